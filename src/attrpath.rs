@@ -0,0 +1,293 @@
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+use crate::cache::PathOrigin;
+use crate::digest::sha256_hex;
+use crate::nix::{list_out_paths, nixpkgs, out_path, store_path_root};
+
+lazy_static! {
+    static ref PINNED: Mutex<AttrPaths> = Mutex::new(AttrPaths::load());
+}
+
+/// Where a store path comes from in nixpkgs, e.g. `python3Packages.cffi` and `dev`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AttrPath {
+    pub attr: String,
+    pub output: String,
+}
+
+impl fmt::Display for AttrPath {
+    /// Like `outputs::installable`, `zlib.dev` or `zlib`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.output == "out" {
+            write!(f, "{}", self.attr)
+        } else {
+            write!(f, "{}.{}", self.attr, self.output)
+        }
+    }
+}
+
+/// Every package of nixpkgs, as listed by `nix-env -qaP --out-path`.
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct Listing {
+    /// Store path hash -> its attribute path
+    by_hash: HashMap<String, AttrPath>,
+    /// Package name, e.g. `zlib-1.3` -> attributes of that name
+    by_name: HashMap<String, Vec<String>>,
+}
+
+/// Columns of `nix-env -qaP --out-path` lines, e.g.
+/// `zlib  zlib-1.3  /nix/store/…-zlib-1.3;dev=/nix/store/…-zlib-1.3-dev`:
+/// the attribute, the package name and the output paths, the default output has no name.
+fn listing_rows(listing: &str) -> impl Iterator<Item = (&str, &str, Vec<(&str, &str)>)> {
+    listing.lines().filter_map(|line| {
+        let mut columns = line.split_whitespace();
+        let (attr, name, out_paths) = (columns.next()?, columns.next()?, columns.next()?);
+        if !out_paths.contains('/') {
+            return None;
+        }
+        let out_paths = out_paths
+            .split(';')
+            .map(|out_path| out_path.split_once('=').unwrap_or(("out", out_path)))
+            .collect();
+
+        Some((attr, name, out_paths))
+    })
+}
+
+fn parse_listing(listing: &str) -> Listing {
+    let mut parsed = Listing::default();
+    for (attr, name, out_paths) in listing_rows(listing) {
+        for (output, out_path) in out_paths {
+            if let Some((hash, _)) = split_store_path(out_path) {
+                parsed.by_hash.insert(
+                    hash.to_string(),
+                    AttrPath {
+                        attr: attr.to_string(),
+                        output: output.to_string(),
+                    },
+                );
+            }
+        }
+        parsed
+            .by_name
+            .entry(name.to_string())
+            .or_default()
+            .push(attr.to_string());
+    }
+
+    parsed
+}
+
+/// Output paths listed by `nix-env -qaP --out-path`, with the attribute path building them.
+pub fn listed_out_paths(listing: &str) -> Vec<(AttrPath, String)> {
+    listing_rows(listing)
+        .flat_map(|(attr, _, out_paths)| {
+            out_paths.into_iter().map(move |(output, out_path)| {
+                (
+                    AttrPath {
+                        attr: attr.to_string(),
+                        output: output.to_string(),
+                    },
+                    out_path.to_string(),
+                )
+            })
+        })
+        .collect()
+}
+
+/// Hash and name of a store path, or of the store path containing a file.
+fn split_store_path(path: &str) -> Option<(&str, &str)> {
+    let root = store_path_root(path);
+    let base = root.rsplit_once('/').map_or(root, |(_, base)| base);
+    base.split_once('-')
+}
+
+/// Maps store paths back to their nixpkgs attribute at the revision the index was built from,
+/// rather than trusting the origin the index recorded, e.g. for `buildxyz export shell`.
+///
+/// The attribute the index tells is checked first, evaluating its output path.
+/// Otherwise every package of nixpkgs is listed, once, and kept in `$XDG_CACHE_HOME/buildxyz/attr-paths`.
+pub struct AttrPaths {
+    /// Store path hash -> attribute path, as checked during this run
+    checked: HashMap<String, Option<AttrPath>>,
+    listing: Option<Listing>,
+    cache_filepath: Option<PathBuf>,
+}
+
+impl AttrPaths {
+    /// Attribute paths at the pinned nixpkgs, shared by the whole process.
+    pub fn pinned() -> MutexGuard<'static, AttrPaths> {
+        PINNED.lock().unwrap()
+    }
+
+    fn load() -> Self {
+        // The nixpkgs source is a store path too, its path identifies the revision.
+        let cache_filepath = xdg::BaseDirectories::with_prefix("buildxyz")
+            .ok()
+            .and_then(|dirs| {
+                dirs.place_cache_file(Path::new("attr-paths").join(format!(
+                    "{}.json",
+                    sha256_hex(nixpkgs().as_bytes())
+                )))
+                .ok()
+            });
+        let listing = cache_filepath
+            .as_ref()
+            .and_then(|filepath| std::fs::read(filepath).ok())
+            .and_then(|data| serde_json::from_slice(&data).ok());
+
+        AttrPaths {
+            checked: HashMap::new(),
+            listing,
+            cache_filepath,
+        }
+    }
+
+    /// Whether `installable` evaluates to the store path of this hash.
+    fn evaluates_to(&self, installable: &str, hash: &str) -> bool {
+        match out_path(installable) {
+            Ok(path) => split_store_path(&path).map_or(false, |(evaluated, _)| evaluated == hash),
+            Err(err) => {
+                debug!("{}", err);
+                false
+            }
+        }
+    }
+
+    /// List every package of nixpkgs unless it already is, this takes a while.
+    fn listing(&mut self) -> Option<&Listing> {
+        if self.listing.is_none() {
+            info!("Listing the packages of nixpkgs to find attribute paths, this takes a while");
+            match list_out_paths() {
+                Ok(listing) => {
+                    let listing = parse_listing(&listing);
+                    if let Some(filepath) = &self.cache_filepath {
+                        if let Err(err) =
+                            std::fs::write(filepath, serde_json::to_vec(&listing).unwrap())
+                        {
+                            warn!(
+                                "Failed to cache the attribute paths in {}: {}",
+                                filepath.display(),
+                                err
+                            );
+                        }
+                    }
+                    self.listing = Some(listing);
+                }
+                Err(err) => warn!("{}", err),
+            }
+        }
+
+        self.listing.as_ref()
+    }
+
+    /// Attribute path of a store path, of the store path containing a file, or of a store path hash.
+    /// `origin` is what the index tells, it is checked first.
+    /// Unless `list` is set, nothing but `origin` is checked, to answer quickly.
+    pub fn resolve(
+        &mut self,
+        path: &str,
+        origin: Option<&PathOrigin>,
+        list: bool,
+    ) -> Option<AttrPath> {
+        let (hash, name) = split_store_path(path).unwrap_or((path, ""));
+        if let Some(attr_path) = self.checked.get(hash) {
+            return attr_path.clone();
+        }
+        if let Some(attr_path) = self
+            .listing
+            .as_ref()
+            .and_then(|listing| listing.by_hash.get(hash))
+        {
+            return Some(attr_path.clone());
+        }
+
+        let from_origin = origin
+            .map(|origin| AttrPath {
+                attr: origin.attr.clone(),
+                output: origin.output.clone(),
+            })
+            .filter(|attr_path| self.evaluates_to(&attr_path.to_string(), hash));
+        if from_origin.is_some() {
+            self.checked.insert(hash.to_string(), from_origin.clone());
+            return from_origin;
+        }
+        if !list {
+            return None;
+        }
+
+        let listing = self.listing()?;
+        if let Some(attr_path) = listing.by_hash.get(hash) {
+            return Some(attr_path.clone());
+        }
+        // Only the default outputs are listed, the others are named after them, e.g. `zlib-1.3-dev`.
+        let candidates: Vec<AttrPath> = listing
+            .by_name
+            .iter()
+            .filter_map(|(package, attrs)| {
+                let output = name
+                    .strip_prefix(package.as_str())?
+                    .strip_prefix('-')
+                    .filter(|output| !output.contains('-'))?;
+                Some(attrs.iter().map(move |attr| AttrPath {
+                    attr: attr.clone(),
+                    output: output.to_string(),
+                }))
+            })
+            .flatten()
+            .collect();
+        let found = candidates
+            .into_iter()
+            .find(|attr_path| self.evaluates_to(&attr_path.to_string(), hash));
+        self.checked.insert(hash.to_string(), found.clone());
+
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_listing() {
+        let output = "zlib                     zlib-1.3          /nix/store/zy4xhzc6x6ldrfzqzfa4x3cw8ds1mgcn-zlib-1.3;dev=/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-zlib-1.3-dev\n\
+             python3Packages.cffi     python3.11-cffi-1.16.0  /nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-python3.11-cffi-1.16.0\n\
+             error: evaluation aborted\n";
+        let listing = parse_listing(output);
+
+        assert_eq!(
+            listing.by_hash["aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"].to_string(),
+            "zlib.dev"
+        );
+        assert_eq!(
+            listing.by_hash["zy4xhzc6x6ldrfzqzfa4x3cw8ds1mgcn"].to_string(),
+            "zlib"
+        );
+        assert_eq!(
+            listing.by_hash["bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb"].attr,
+            "python3Packages.cffi"
+        );
+        assert_eq!(listing.by_name["zlib-1.3"], ["zlib"]);
+        assert_eq!(listing.by_hash.len(), 3);
+        let out_paths = listed_out_paths(output);
+        assert_eq!(out_paths.len(), 3);
+        assert_eq!(out_paths[1].0.to_string(), "zlib.dev");
+        assert_eq!(
+            out_paths[1].1,
+            "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-zlib-1.3-dev"
+        );
+        assert_eq!(
+            split_store_path(
+                "/nix/store/zy4xhzc6x6ldrfzqzfa4x3cw8ds1mgcn-zlib-1.3-dev/include/zlib.h"
+            ),
+            Some(("zy4xhzc6x6ldrfzqzfa4x3cw8ds1mgcn", "zlib-1.3-dev"))
+        );
+    }
+}
@@ -0,0 +1,189 @@
+use log::error;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::interactive::UserRequest;
+use crate::outputs;
+use crate::process::ProcessInfo;
+
+/// A suggestion accepted by `--automatic` once its decision is recorded.
+#[derive(Serialize, Clone)]
+pub struct AuditEntry {
+    /// Seconds since the UNIX epoch
+    pub timestamp: f64,
+    pub path: PathBuf,
+    pub installable: String,
+    pub store_path: String,
+    /// Why it was the best ranked candidate, see `Ranking`
+    pub ranking: String,
+    /// Whether it only matched a relaxed pattern
+    pub fuzzy: bool,
+    pub requested_by: ProcessInfo,
+}
+
+impl fmt::Display for AuditEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} → {} ({}{}), requested by {}",
+            self.path.display(),
+            self.installable,
+            if self.fuzzy { "fuzzy, " } else { "" },
+            self.ranking,
+            self.requested_by
+        )
+    }
+}
+
+/// Follows the suggestions accepted by `--automatic` until their decision is recorded,
+/// the prompt does not tell the decision but `UserRequest::Decided` does.
+/// Every entry is appended to the `--audit-log` file as a JSON line if any, e.g. to follow them with `tail -f`.
+#[derive(Default)]
+pub struct AuditTrail {
+    accepted: HashMap<u64, AuditEntry>,
+    log: Option<(PathBuf, LineWriter<File>)>,
+}
+
+impl AuditTrail {
+    /// Audit trail appending to this file too, see `--audit-log`.
+    pub fn open(filepath: &Path) -> io::Result<Self> {
+        let file = File::options().create(true).append(true).open(filepath)?;
+        Ok(AuditTrail {
+            accepted: HashMap::new(),
+            log: Some((filepath.to_owned(), LineWriter::new(file))),
+        })
+    }
+
+    /// Remember a suggestion accepted automatically.
+    pub fn accepted(&mut self, request: &UserRequest) {
+        if let UserRequest::InteractiveSearch(
+            request_id,
+            _,
+            rankings,
+            suggested,
+            requester,
+            fuzzy,
+            ..,
+            requested_path,
+        ) = request
+        {
+            self.accepted.insert(
+                *request_id,
+                AuditEntry {
+                    timestamp: 0.0,
+                    path: requested_path.clone(),
+                    installable: outputs::installable(&suggested.0.origin()),
+                    store_path: suggested.0.as_str().to_string(),
+                    ranking: rankings.first().map_or(String::new(), ToString::to_string),
+                    fuzzy: *fuzzy,
+                    requested_by: requester.clone(),
+                },
+            );
+        }
+    }
+
+    /// Keep track of a request, returns the automatic decision it records if any.
+    pub fn update(&mut self, request: &UserRequest) -> Option<AuditEntry> {
+        let UserRequest::Decided(request_id, requested_path, _) = request else {
+            return None;
+        };
+        let mut entry = self.accepted.remove(request_id)?;
+        entry.path = requested_path.clone();
+        entry.timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+
+        if let Some((filepath, writer)) = &mut self.log {
+            let written = serde_json::to_writer(&mut *writer, &entry)
+                .map_err(io::Error::from)
+                .and_then(|()| writer.write_all(b"\n"));
+            if let Err(err) = written {
+                error!(
+                    "Failed to write to the audit log {}: {}",
+                    filepath.display(),
+                    err
+                );
+            }
+        }
+
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::database::write_index;
+    use crate::cache::PathOrigin;
+    use crate::ranking::Ranking;
+
+    #[test]
+    fn test_automatic_decisions_are_audited_once_recorded() {
+        let origin = PathOrigin {
+            attr: "zlib".to_string(),
+            output: "dev".to_string(),
+            toplevel: true,
+            system: None,
+            index: None,
+        };
+        let index = tempfile::NamedTempFile::new().unwrap();
+        let (store_path, entry) = write_index(
+            index.path(),
+            &[(
+                origin,
+                "/nix/store/zy4xhzc6x6ldrfzqzfa4x3cw8ds1mgcn-zlib-1.3-dev",
+                vec!["include/zlib.h"],
+            )],
+        )
+        .remove(0);
+        let ranking = Ranking {
+            path_match: (0, 0),
+            must_build: false,
+            output: 0,
+            inverted_popularity: -3,
+            propagated: false,
+        };
+        let requester = ProcessInfo {
+            pid: 42,
+            comm: Some("cc".to_string()),
+            exe: None,
+            cwd: None,
+        };
+        let search = UserRequest::InteractiveSearch(
+            7,
+            vec![(store_path.clone(), entry.clone())],
+            vec![ranking],
+            (store_path, entry),
+            requester,
+            false,
+            false,
+            None,
+            PathBuf::from("include/zlib.h"),
+        );
+        let decided = UserRequest::Decided(
+            7,
+            PathBuf::from("include/zlib.h"),
+            "provided by zlib.dev".to_string(),
+        );
+
+        let mut trail = AuditTrail::default();
+        assert!(
+            trail.update(&decided).is_none(),
+            "prompts answered by hand are not audited"
+        );
+
+        trail.accepted(&search);
+        let entry = trail.update(&decided).unwrap();
+        assert_eq!(
+            entry.to_string(),
+            "include/zlib.h → zlib.dev (exact path, expected output, used by 3 packages), requested by cc (pid 42)"
+        );
+        assert!(trail.update(&decided).is_none());
+    }
+}
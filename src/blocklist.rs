@@ -0,0 +1,99 @@
+use glob::Pattern;
+use regex::Regex;
+
+/// Paths that are never worth resolving: VCS metadata, package manager
+/// trees, bytecode caches and editor backup files.
+const DEFAULT_BLOCKED_PATHS: &[&str] = &[
+    r"(^|/)\.git(/|$)",
+    r"(^|/)node_modules(/|$)",
+    r"(^|/)__pycache__(/|$)",
+    r"~$",
+    r"\.sw[op]$",
+    r"(^|/)#[^/]*#$",
+    r"\.(bak|orig)$",
+];
+
+/// Requested paths matching one of these patterns
+/// are answered with ENOENT without any index query or prompt.
+#[derive(Default, Debug, Clone)]
+pub struct PathBlocklist {
+    regexes: Vec<Regex>,
+    globs: Vec<Pattern>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum BlocklistError {
+    #[error("invalid regex `{0}`: {1}")]
+    Regex(String, regex::Error),
+    #[error("invalid glob `{0}`: {1}")]
+    Glob(String, glob::PatternError),
+}
+
+impl PathBlocklist {
+    pub fn new(
+        regexes: &[String],
+        globs: &[String],
+        with_defaults: bool,
+    ) -> Result<Self, BlocklistError> {
+        let defaults = if with_defaults {
+            DEFAULT_BLOCKED_PATHS
+        } else {
+            &[]
+        };
+
+        Ok(PathBlocklist {
+            regexes: defaults
+                .iter()
+                .map(|r| r.to_string())
+                .chain(regexes.iter().cloned())
+                .map(|r| Regex::new(&r).map_err(|err| BlocklistError::Regex(r, err)))
+                .collect::<Result<Vec<Regex>, BlocklistError>>()?,
+            globs: globs
+                .iter()
+                .map(|g| Pattern::new(g).map_err(|err| BlocklistError::Glob(g.clone(), err)))
+                .collect::<Result<Vec<Pattern>, BlocklistError>>()?,
+        })
+    }
+
+    /// Whether this requested path (relative to the mountpoint) is blocked.
+    pub fn is_blocked(&self, requested_path: &str) -> bool {
+        self.regexes.iter().any(|r| r.is_match(requested_path))
+            || self.globs.iter().any(|g| g.matches(requested_path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocklist_defaults_and_patterns() {
+        let blocklist = PathBlocklist::new(
+            &[r"^share/doc/".to_string()],
+            &["include/*.gch".to_string()],
+            true,
+        )
+        .unwrap();
+
+        assert!(blocklist.is_blocked(".git/HEAD"));
+        assert!(blocklist.is_blocked("lib/node_modules/foo/package.json"));
+        assert!(blocklist.is_blocked("lib/python3.11/__pycache__"));
+        assert!(blocklist.is_blocked("include/zlib.h~"));
+        assert!(blocklist.is_blocked("include/.zlib.h.swp"));
+        assert!(blocklist.is_blocked("share/doc/zlib/README"));
+        assert!(blocklist.is_blocked("include/stdio.gch"));
+        assert!(!blocklist.is_blocked("include/zlib.h"));
+        assert!(!blocklist.is_blocked("lib/gitlib.so"));
+
+        let without_defaults = PathBlocklist::new(&[], &[], false).unwrap();
+        assert!(!without_defaults.is_blocked(".git/HEAD"));
+        assert!(matches!(
+            PathBlocklist::new(&["(".to_string()], &[], false),
+            Err(BlocklistError::Regex(..))
+        ));
+        assert!(matches!(
+            PathBlocklist::new(&[], &["[".to_string()], false),
+            Err(BlocklistError::Glob(..))
+        ));
+    }
+}
@@ -0,0 +1,173 @@
+use log::warn;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::interactive::human_size;
+use crate::nix::closure_size;
+use crate::policy::parse_size;
+
+/// What happens to a provide exceeding the closure-size budget, see `--over-budget`.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverBudget {
+    /// ENOENT the lookup without recording anything
+    #[default]
+    Refuse,
+    /// Provide it anyway, with a warning
+    Warn,
+}
+
+/// Parse a size argument such as `500 MiB`.
+pub fn parse_size_arg(size: &str) -> Result<usize, String> {
+    parse_size(size).ok_or_else(|| format!("invalid size `{}`, expected e.g. `500 MiB`", size))
+}
+
+#[derive(Default)]
+struct Usage {
+    /// Store path -> closure size of the packages provided during this session
+    provided: HashMap<String, usize>,
+    /// Store path -> closure size, `None` if unknown, of every package asked about
+    sizes: HashMap<String, Option<usize>>,
+}
+
+/// Closure-size limits of the packages provided during a session, see `--max-closure-size`.
+/// The session usage sums the closures of the provided packages, shared dependencies are counted once per package.
+#[derive(Default)]
+pub struct ClosureBudget {
+    /// Largest closure of a single provided package
+    max_closure_size: Option<usize>,
+    /// Largest sum of the closures of the provided packages
+    max_session_size: Option<usize>,
+    over_budget: OverBudget,
+    usage: Mutex<Usage>,
+}
+
+impl ClosureBudget {
+    pub fn new(
+        max_closure_size: Option<usize>,
+        max_session_size: Option<usize>,
+        over_budget: OverBudget,
+    ) -> Self {
+        ClosureBudget {
+            max_closure_size,
+            max_session_size,
+            over_budget,
+            usage: Default::default(),
+        }
+    }
+
+    fn is_limited(&self) -> bool {
+        self.max_closure_size.is_some() || self.max_session_size.is_some()
+    }
+
+    /// Closure size of a store path, asked to the store once.
+    fn size_of(usage: &mut Usage, store_path: &str) -> Option<usize> {
+        *usage
+            .sizes
+            .entry(store_path.to_string())
+            .or_insert_with(|| closure_size(store_path))
+    }
+
+    /// Why providing this closure size would exceed the budget, `None` if it fits.
+    fn excess(&self, usage: &Usage, store_path: &str, size: usize) -> Option<String> {
+        if let Some(max_closure_size) = self.max_closure_size.filter(|max| size > *max) {
+            return Some(format!(
+                "the closure of {} is {}, more than {}",
+                store_path,
+                human_size(size),
+                human_size(max_closure_size)
+            ));
+        }
+        let session_size: usize = usage.provided.values().sum();
+        match self.max_session_size {
+            Some(max_session_size) if session_size + size > max_session_size => Some(format!(
+                "the closure of {} is {}, {} are already provided this session, more than {} with it",
+                store_path,
+                human_size(size),
+                human_size(session_size),
+                human_size(max_session_size)
+            )),
+            _ => None,
+        }
+    }
+
+    /// Whether a suggested package may be provided, it is counted in the session usage if so.
+    /// Packages whose closure size is unknown are provided with a warning.
+    pub fn admit(&self, store_path: &str) -> bool {
+        if !self.is_limited() {
+            return true;
+        }
+        let mut usage = self.usage.lock().unwrap();
+        if usage.provided.contains_key(store_path) {
+            return true;
+        }
+        let Some(size) = Self::size_of(&mut usage, store_path) else {
+            warn!(
+                "The closure size of {} is unknown, it is not counted in the budget",
+                store_path
+            );
+            usage.provided.insert(store_path.to_string(), 0);
+            return true;
+        };
+        if let Some(excess) = self.excess(&usage, store_path, size) {
+            match self.over_budget {
+                OverBudget::Refuse => {
+                    warn!("Refusing to provide {}: {}", store_path, excess);
+                    return false;
+                }
+                OverBudget::Warn => warn!("Over the closure-size budget: {}", excess),
+            }
+        }
+        usage.provided.insert(store_path.to_string(), size);
+
+        true
+    }
+
+    /// Count a package provided by a recorded resolution in the session usage, it is never refused.
+    pub fn account(&self, store_path: &str) {
+        if !self.is_limited() {
+            return;
+        }
+        let mut usage = self.usage.lock().unwrap();
+        if !usage.provided.contains_key(store_path) {
+            let size = Self::size_of(&mut usage, store_path).unwrap_or(0);
+            usage.provided.insert(store_path.to_string(), size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_budget_excess() {
+        let budget = ClosureBudget::new(Some(100 << 20), Some(150 << 20), OverBudget::Refuse);
+        let mut usage = Usage::default();
+        assert_eq!(
+            budget.excess(&usage, "/nix/store/…-zlib-1.3-dev", 1 << 20),
+            None
+        );
+        assert_eq!(
+            budget
+                .excess(&usage, "/nix/store/…-qt-full-5.15", 2 << 30)
+                .unwrap(),
+            "the closure of /nix/store/…-qt-full-5.15 is 2.0 GiB, more than 100.0 MiB"
+        );
+
+        usage
+            .provided
+            .insert("/nix/store/…-openssl-3.0-dev".into(), 90 << 20);
+        assert!(budget
+            .excess(&usage, "/nix/store/…-curl-8.4-dev", 80 << 20)
+            .unwrap()
+            .contains("90.0 MiB are already provided this session"));
+        assert_eq!(
+            budget.excess(&usage, "/nix/store/…-zlib-1.3-dev", 1 << 20),
+            None
+        );
+
+        assert!(ClosureBudget::default().admit("/nix/store/…-qt-full-5.15"));
+        assert_eq!(parse_size_arg("500 MiB"), Ok(500 << 20));
+        assert!(parse_size_arg("huge").is_err());
+    }
+}
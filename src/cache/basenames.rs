@@ -0,0 +1,153 @@
+//! A lookup of the packages having files of a given name in a nix-index database,
+//! so that exact paths are found without decoding the whole database.
+//!
+//! All integers are little endian. The lookup is laid out as:
+//!
+//! * the magic `NIXB`, the format version, the number of packages and the number of names (`u64`)
+//! * the offsets of the packages in the database (`u64`), followed by the length of the database
+//! * for each name, sorted: the offsets of its start and end and of its packages in the lookup (`u64`)
+//! * the names, then the packages of each name: their number and their indices (`u32`)
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+const FILE_MAGIC: &[u8] = b"NIXB";
+const FORMAT_VERSION: u64 = 1;
+const HEADER_LEN: usize = 32;
+/// Offsets of the start and end of a name and of its packages.
+const NAME_ENTRY_LEN: usize = 24;
+
+/// Name of the file of an entry of the database, e.g. `zlib.pc` for `/lib/pkgconfig/zlib.pc`.
+pub fn basename(path: &[u8]) -> &[u8] {
+    path.rsplit(|c| *c == b'/').next().unwrap_or(path)
+}
+
+/// Lays the lookup out from the offsets of the packages, followed by the length of the database,
+/// and the indices of the packages having each name, in increasing order.
+pub fn write(package_starts: &[usize], names: &BTreeMap<Vec<u8>, Vec<u32>>) -> Vec<u8> {
+    let table_start = HEADER_LEN + package_starts.len() * 8;
+    let names_start = table_start + names.len() * NAME_ENTRY_LEN;
+    let names_len: usize = names.keys().map(|name| name.len()).sum();
+    let postings_len: usize = names.values().map(|packages| 4 + packages.len() * 4).sum();
+
+    let mut data = vec![0; names_start + names_len + postings_len];
+    data[..4].copy_from_slice(FILE_MAGIC);
+    LittleEndian::write_u64(&mut data[4..12], FORMAT_VERSION);
+    LittleEndian::write_u64(&mut data[12..20], (package_starts.len() - 1) as u64);
+    LittleEndian::write_u64(&mut data[20..28], names.len() as u64);
+    for (i, start) in package_starts.iter().enumerate() {
+        let at = HEADER_LEN + i * 8;
+        LittleEndian::write_u64(&mut data[at..at + 8], *start as u64);
+    }
+
+    let mut name_pos = names_start;
+    let mut postings_pos = names_start + names_len;
+    for (i, (name, packages)) in names.iter().enumerate() {
+        let at = table_start + i * NAME_ENTRY_LEN;
+        LittleEndian::write_u64(&mut data[at..at + 8], name_pos as u64);
+        LittleEndian::write_u64(&mut data[at + 8..at + 16], (name_pos + name.len()) as u64);
+        LittleEndian::write_u64(&mut data[at + 16..at + 24], postings_pos as u64);
+        data[name_pos..name_pos + name.len()].copy_from_slice(name);
+        name_pos += name.len();
+
+        LittleEndian::write_u32(
+            &mut data[postings_pos..postings_pos + 4],
+            packages.len() as u32,
+        );
+        postings_pos += 4;
+        for package in packages {
+            LittleEndian::write_u32(&mut data[postings_pos..postings_pos + 4], *package);
+            postings_pos += 4;
+        }
+    }
+
+    data
+}
+
+/// A lookup laid out by `write`, read in place.
+pub struct Basenames<'a> {
+    data: &'a [u8],
+    packages: usize,
+    names: usize,
+    database_len: usize,
+}
+
+impl<'a> Basenames<'a> {
+    /// The lookup of a database of `database_len` bytes, `None` if it is not one, e.g. of another database.
+    pub fn new(data: &'a [u8], database_len: usize) -> Option<Basenames<'a>> {
+        if data.len() < HEADER_LEN
+            || &data[..4] != FILE_MAGIC
+            || LittleEndian::read_u64(&data[4..12]) != FORMAT_VERSION
+        {
+            return None;
+        }
+        let packages = LittleEndian::read_u64(&data[12..20]) as usize;
+        let names = LittleEndian::read_u64(&data[20..28]) as usize;
+        let basenames = Basenames {
+            data,
+            packages,
+            names,
+            database_len,
+        };
+        let table_end = HEADER_LEN
+            .checked_add(packages.checked_add(1)?.checked_mul(8)?)?
+            .checked_add(names.checked_mul(NAME_ENTRY_LEN)?)?;
+        if table_end > data.len() || basenames.package_start(packages) != database_len {
+            return None;
+        }
+
+        Some(basenames)
+    }
+
+    fn read(&self, at: usize) -> usize {
+        LittleEndian::read_u64(&self.data[at..at + 8]) as usize
+    }
+
+    fn package_start(&self, package: usize) -> usize {
+        self.read(HEADER_LEN + package * 8)
+    }
+
+    fn name_entry(&self, i: usize) -> usize {
+        HEADER_LEN + (self.packages + 1) * 8 + i * NAME_ENTRY_LEN
+    }
+
+    fn name(&self, i: usize) -> &'a [u8] {
+        let at = self.name_entry(i);
+        self.data
+            .get(self.read(at)..self.read(at + 8))
+            .unwrap_or_default()
+    }
+
+    /// Ranges of the database holding the packages which have files named `name`, in the order of the database.
+    pub fn packages(&self, name: &[u8]) -> Vec<Range<usize>> {
+        let (mut low, mut high) = (0, self.names);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            match self.name(middle).cmp(name) {
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Greater => high = middle,
+                std::cmp::Ordering::Equal => return self.postings(middle),
+            }
+        }
+        Vec::new()
+    }
+
+    fn postings(&self, i: usize) -> Vec<Range<usize>> {
+        let at = self.read(self.name_entry(i) + 16);
+        let Some(count) = self.data.get(at..at + 4).map(LittleEndian::read_u32) else {
+            return Vec::new();
+        };
+        let Some(postings) = self.data.get(at + 4..at + 4 + count as usize * 4) else {
+            return Vec::new();
+        };
+
+        postings
+            .chunks_exact(4)
+            .map(|package| LittleEndian::read_u32(package) as usize)
+            .filter(|package| *package < self.packages)
+            .map(|package| self.package_start(package)..self.package_start(package + 1))
+            .filter(|range| range.start < range.end && range.end <= self.database_len)
+            .collect()
+    }
+}
@@ -1,24 +1,32 @@
+use std::collections::BTreeMap;
 use std::fs::File;
-use std::io::Cursor;
 /// Creating and searching file databases.
 ///
 /// This module implements an abstraction for creating an index of files with meta information
 /// and searching that index for paths matching a specific pattern.
-use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::path::Path;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::{Deref, Range};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use error_chain::error_chain;
 use grep::matcher::{LineMatchKind, Match, Matcher, NoError};
 use grep::{self};
+use log::{debug, warn};
 use memchr::{memchr, memrchr};
+use memmap2::Mmap;
+use once_cell::sync::OnceCell;
+use rayon::prelude::*;
 use regex::bytes::Regex;
 use regex_syntax::ast::{
     Alternation, Assertion, AssertionKind, Ast, Concat, Group, Literal, Repetition,
 };
+use regex_syntax::hir::{self, HirKind, Look};
 use serde_json;
 use zstd;
 
+use crate::cache::basenames::{self, Basenames};
 use crate::cache::files::{FileTree, FileTreeEntry};
 use crate::cache::frcode;
 use crate::cache::package::StorePath;
@@ -98,6 +106,72 @@ impl Writer {
     }
 }
 
+/// Writes a database of these packages for tests, by origin, store path and regular files, e.g. `include/zlib.h`.
+/// Returns the entries of the files with their store path, as a search finds them.
+#[cfg(test)]
+pub(crate) fn write_index<S: AsRef<str>>(
+    path: &Path,
+    packages: &[(crate::cache::PathOrigin, S, Vec<S>)],
+) -> Vec<(StorePath, FileTreeEntry)> {
+    use crate::cache::FileNode;
+    use serde_bytes::ByteBuf;
+
+    let mut writer = Writer::create(path, 1).unwrap();
+    let mut entries = Vec::new();
+    for (origin, store_path, files) in packages {
+        let store_path = StorePath::parse(origin.clone(), store_path.as_ref()).unwrap();
+        let tree = FileTree::directory(
+            files
+                .iter()
+                .map(|file| (ByteBuf::from(file.as_ref().as_bytes()), FileTree::regular(1, false)))
+                .collect(),
+        );
+        entries.extend(files.iter().map(|file| {
+            let entry = FileTreeEntry {
+                path: format!("/{}", file.as_ref()).into_bytes(),
+                node: FileNode::Regular {
+                    size: 1,
+                    executable: false,
+                },
+            };
+            (store_path.clone(), entry)
+        }));
+        writer.add(store_path, tree, b"").unwrap();
+    }
+    writer.finish().unwrap();
+
+    entries
+}
+
+/// The entry of a regular file of a package, as a search of the index finds it, for tests
+/// that don't need the database itself, e.g. `/include/zlib.h` of the `dev` output of `zlib`.
+#[cfg(test)]
+pub(crate) fn candidate(
+    attr: &str,
+    output: &str,
+    toplevel: bool,
+    store_path: &str,
+    path: &str,
+) -> (StorePath, FileTreeEntry) {
+    use crate::cache::{FileNode, PathOrigin};
+
+    let origin = PathOrigin {
+        attr: attr.to_string(),
+        output: output.to_string(),
+        toplevel,
+        system: None,
+        index: None,
+    };
+    let entry = FileTreeEntry {
+        path: path.as_bytes().to_vec(),
+        node: FileNode::Regular {
+            size: 1,
+            executable: false,
+        },
+    };
+    (StorePath::parse(origin, store_path).unwrap(), entry)
+}
+
 error_chain! {
     errors {
         UnsupportedFileType(found: Vec<u8>) {
@@ -138,16 +212,87 @@ impl From<frcode::Error> for Error {
     }
 }
 
+/// Databases smaller than this are searched by a single thread.
+const MIN_CHUNK_SIZE: usize = 4 << 20;
+/// Exact paths are searched in the whole database, in parallel, rather than in the packages having files
+/// of the same name when those make more than `1 / MAX_LOOKUP_SHARE` of it.
+const MAX_LOOKUP_SHARE: usize = 8;
+
+/// Ranges of whole packages of a decompressed database, read one after the other,
+/// so that parts of it can be decoded in parallel or on their own.
+struct Packages {
+    buffer: SharedBuffer,
+    /// Ranges left to read, the one being read is the last.
+    ranges: Vec<Range<usize>>,
+}
+
+impl Packages {
+    fn new(buffer: SharedBuffer, mut ranges: Vec<Range<usize>>) -> Packages {
+        ranges.reverse();
+        Packages { buffer, ranges }
+    }
+}
+
+impl Read for Packages {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let len = {
+            let data = self.fill_buf()?;
+            let len = data.len().min(out.len());
+            out[..len].copy_from_slice(&data[..len]);
+            len
+        };
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl BufRead for Packages {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        while self.ranges.last().map_or(false, |range| range.is_empty()) {
+            self.ranges.pop();
+        }
+        Ok(match self.ranges.last() {
+            Some(range) => &self.buffer[range.clone()],
+            None => &[],
+        })
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if let Some(range) = self.ranges.last_mut() {
+            range.start += amt;
+        }
+    }
+}
+
 /// A Reader allows fast querying of a nix-index database.
 pub struct Reader {
-    decoder: frcode::Decoder<Cursor<Vec<u8>>>, // BufReader<zstd::Decoder<'static, BufReader<File>>>>,
+    decoder: frcode::Decoder<Packages>, // BufReader<zstd::Decoder<'static, BufReader<File>>>>,
 }
 
+#[cfg(test)]
 pub fn read_from_path<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
     read_raw_buffer(File::open(path)?)
 }
 
-pub fn read_raw_buffer<Reader: std::io::Read>(mut reader: Reader) -> Result<Vec<u8>> {
+pub fn read_raw_buffer<Reader: std::io::Read>(reader: Reader) -> Result<Vec<u8>> {
+    let mut buffer: Vec<u8> = Vec::new();
+    decompress(reader)?.read_to_end(&mut buffer)?;
+
+    Ok(buffer)
+}
+
+/// Decompresses a database into `writer`, e.g. a file to map, without holding it in memory.
+/// Returns the size of the decompressed database.
+pub fn decompress_into<Reader: std::io::Read, W: Write>(
+    reader: Reader,
+    writer: &mut W,
+) -> Result<u64> {
+    Ok(io::copy(&mut decompress(reader)?, writer)?)
+}
+
+fn decompress<Reader: std::io::Read>(
+    mut reader: Reader,
+) -> Result<zstd::Decoder<'static, BufReader<Reader>>> {
     let mut magic = [0u8; 4];
     reader.read_exact(&mut magic)?;
 
@@ -160,25 +305,65 @@ pub fn read_raw_buffer<Reader: std::io::Read>(mut reader: Reader) -> Result<Vec<
         return Err(ErrorKind::UnsupportedVersion(version).into());
     }
 
-    let mut decoder = zstd::Decoder::new(reader)?;
-    let mut buffer: Vec<u8> = Vec::new();
-    decoder.read_to_end(&mut buffer)?;
+    Ok(zstd::Decoder::new(reader)?)
+}
 
-    Ok(buffer)
+/// A decompressed database, in memory or mapped from a file, shared without copying it.
+#[derive(Clone)]
+pub struct SharedBuffer {
+    data: Arc<dyn AsRef<[u8]> + Send + Sync>,
+    /// File it is mapped from
+    path: Option<PathBuf>,
+}
+
+impl SharedBuffer {
+    /// Maps a file which is never written to once it exists, e.g. renamed into place once complete.
+    pub fn map(path: &Path) -> io::Result<SharedBuffer> {
+        let file = File::open(path)?;
+        // Safe as long as nobody writes to the file, which is only ever replaced.
+        let map = unsafe { Mmap::map(&file)? };
+
+        Ok(SharedBuffer {
+            data: Arc::new(map),
+            path: Some(path.to_owned()),
+        })
+    }
+
+    /// File it is mapped from, `None` if it is in memory.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+}
+
+impl Deref for SharedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        (*self.data).as_ref()
+    }
+}
+
+impl From<Vec<u8>> for SharedBuffer {
+    fn from(buffer: Vec<u8>) -> Self {
+        SharedBuffer {
+            data: Arc::new(buffer),
+            path: None,
+        }
+    }
 }
 
 impl Reader {
-    /// Opens a nix-index database located at the given path.
-    ///
-    /// If the path does not exist or is not a valid database, an error is returned.
-    pub fn open<P: AsRef<Path>>(path: P) -> Result<Reader> {
-        Reader::from_buffer(read_from_path(path)?)
+    /// Opens a nix-index database from an already decompressed buffer,
+    /// which can be shared by many readers without copying it.
+    pub fn from_shared_buffer(buffer: SharedBuffer) -> Result<Reader> {
+        let range = 0..buffer.len();
+        Ok(Reader::from_packages(buffer, vec![range]))
     }
 
-    pub fn from_buffer(buffer: Vec<u8>) -> Result<Reader> {
-        Ok(Reader {
-            decoder: frcode::Decoder::new(Cursor::new(buffer)),
-        })
+    fn from_packages(buffer: SharedBuffer, ranges: Vec<Range<usize>>) -> Reader {
+        Reader {
+            decoder: frcode::Decoder::new(Packages::new(buffer, ranges)),
+        }
     }
 
     /// Builds a query to find all entries in the database that have a filename matching the given pattern.
@@ -210,6 +395,237 @@ impl Reader {
     }
 }
 
+/// Start of the package following `from`, the buffer length if there is none.
+///
+/// Each package is encoded on its own and ends with its package entry, `p\0<differential><store path>\n`,
+/// which resets the shared prefix: the database can be decoded from there without what comes before.
+/// Paths and metadata contain neither newlines nor NUL bytes, so `\np\0` only occurs before a package entry.
+fn next_package_start(buffer: &[u8], from: usize) -> usize {
+    let mut pos = from;
+    while let Some(newline) = memchr(b'\n', &buffer[pos..]) {
+        let entry = pos + newline + 1;
+        if buffer[entry..].starts_with(b"p\0") {
+            return package_entry_end(buffer, entry);
+        }
+        pos = entry;
+    }
+    buffer.len()
+}
+
+/// End of the package entry at `entry`.
+fn package_entry_end(buffer: &[u8], entry: usize) -> usize {
+    // the differential takes 3 bytes when it is escaped by 0x80
+    let path = match buffer.get(entry + 2) {
+        Some(0x80) => entry + 5,
+        _ => entry + 3,
+    };
+    match buffer.get(path..).and_then(|rest| memchr(b'\n', rest)) {
+        Some(end) => path + end + 1,
+        None => buffer.len(),
+    }
+}
+
+/// End of the package starting at `start`, which may have no file entries.
+fn package_end(buffer: &[u8], start: usize) -> usize {
+    if buffer[start..].starts_with(b"p\0") {
+        package_entry_end(buffer, start)
+    } else {
+        next_package_start(buffer, start)
+    }
+}
+
+/// The path an exactly anchored regex matches, e.g. `/lib/pkgconfig/zlib.pc` for `^/lib/pkgconfig/zlib\.pc$`.
+fn exact_path(regex: &Regex) -> Option<Vec<u8>> {
+    let hir = regex_syntax::Parser::new().parse(regex.as_str()).ok()?;
+    let HirKind::Concat(parts) = hir.kind() else {
+        return None;
+    };
+    match parts
+        .iter()
+        .map(|part| part.kind())
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        [HirKind::Look(Look::Start), HirKind::Literal(hir::Literal(path)), HirKind::Look(Look::End)] => {
+            Some(path.to_vec())
+        }
+        _ => None,
+    }
+}
+
+/// Lookup of the packages by the names of their files, see `basenames`.
+fn build_basenames(buffer: &SharedBuffer) -> Result<Vec<u8>> {
+    let mut package_starts = vec![0];
+    while package_starts[package_starts.len() - 1] < buffer.len() {
+        let start = package_starts[package_starts.len() - 1];
+        package_starts.push(package_end(buffer, start));
+    }
+
+    let mut names: BTreeMap<Vec<u8>, Vec<u32>> = BTreeMap::new();
+    let mut package: u32 = 0;
+    let mut reader = Reader::from_shared_buffer(buffer.clone())?;
+    loop {
+        let block = reader.decoder.decode()?;
+        if block.is_empty() {
+            break;
+        }
+        for entry in block
+            .split(|c| *c == b'\n')
+            .filter(|entry| !entry.is_empty())
+        {
+            if entry.starts_with(b"p\0") {
+                package += 1;
+                continue;
+            }
+            let Some(sep) = memchr(b'\0', entry) else {
+                return Err(ErrorKind::EntryParse(entry.to_vec()).into());
+            };
+            let packages = names
+                .entry(basenames::basename(&entry[sep + 1..]).to_vec())
+                .or_default();
+            if packages.last() != Some(&package) {
+                packages.push(package);
+            }
+        }
+    }
+    if package as usize != package_starts.len() - 1 {
+        return Err(ErrorKind::MissingPackageEntry.into());
+    }
+
+    Ok(basenames::write(&package_starts, &names))
+}
+
+/// A nix-index database shared by all the lookups of a session, see `SharedReader::search`.
+///
+/// Unlike a `Reader`, which decodes the whole database on a single thread and is consumed by its query,
+/// it splits the database at package boundaries and searches the parts in parallel.
+/// Exact paths are only searched in the packages having files of the same name.
+pub struct SharedReader {
+    buffer: SharedBuffer,
+    /// Ranges of packages searched in parallel on the global thread pool, split on first use.
+    chunks: OnceCell<Vec<Range<usize>>>,
+    /// Lookup of the packages by file name, built on the first search of an exact path,
+    /// `None` if it could not be.
+    basenames: OnceCell<Option<SharedBuffer>>,
+}
+
+impl SharedReader {
+    /// Shares an already decompressed database, which is not copied.
+    pub fn new(buffer: SharedBuffer) -> SharedReader {
+        SharedReader {
+            buffer,
+            chunks: OnceCell::new(),
+            basenames: OnceCell::new(),
+        }
+    }
+
+    fn chunks(&self) -> &[Range<usize>] {
+        self.chunks.get_or_init(|| {
+            let len = self.buffer.len();
+            let count = rayon::current_num_threads().min(len / MIN_CHUNK_SIZE).max(1);
+            split(&self.buffer, count)
+        })
+    }
+
+    fn basenames(&self) -> Option<Basenames<'_>> {
+        let lookup = self.basenames.get_or_init(|| {
+            self.load_basenames()
+                .map_err(|err| warn!("Failed to look packages up by file name: {}", err))
+                .ok()
+        });
+        lookup
+            .as_ref()
+            .and_then(|lookup| Basenames::new(lookup, self.buffer.len()))
+    }
+
+    /// Lookup of the packages by file name, kept next to a mapped database as `<database>.basenames`
+    /// for the next sessions.
+    fn load_basenames(&self) -> Result<SharedBuffer> {
+        let Some(database) = self.buffer.path() else {
+            return Ok(build_basenames(&self.buffer)?.into());
+        };
+        let mut path = database.as_os_str().to_owned();
+        path.push(".basenames");
+        let path = PathBuf::from(path);
+        if let Ok(lookup) = SharedBuffer::map(&path) {
+            if Basenames::new(&lookup, self.buffer.len()).is_some() {
+                return Ok(lookup);
+            }
+        }
+
+        debug!("looking packages up by file name in {}", path.display());
+        let directory = path.parent().unwrap_or(Path::new("."));
+        let mut lookup = tempfile::NamedTempFile::new_in(directory)?;
+        lookup.write_all(&build_basenames(&self.buffer)?)?;
+        lookup.persist(&path).map_err(|err| err.error)?;
+
+        Ok(SharedBuffer::map(&path)?)
+    }
+
+    /// Entries with a filename matching `exact_regex`, only those of the package with the given hash if `Some`.
+    /// The entries are in the same order from one search to the next.
+    pub fn search(
+        &self,
+        exact_regex: &Regex,
+        hash: Option<String>,
+    ) -> Result<Vec<(StorePath, FileTreeEntry)>> {
+        let search_packages = |ranges: Vec<Range<usize>>| -> Result<Vec<_>> {
+            if ranges.is_empty() {
+                return Ok(Vec::new());
+            }
+            Reader::from_packages(self.buffer.clone(), ranges)
+                .query(exact_regex)
+                .hash(hash.clone())
+                .run()?
+                .collect()
+        };
+
+        // names found in many packages, e.g. `README`, are searched like patterns
+        if let Some(path) = exact_path(exact_regex) {
+            if let Some(basenames) = self.basenames() {
+                let packages = basenames.packages(basenames::basename(&path));
+                let len: usize = packages.iter().map(|range| range.len()).sum();
+                if len <= self.buffer.len() / MAX_LOOKUP_SHARE {
+                    return search_packages(packages);
+                }
+            }
+        }
+
+        match self.chunks() {
+            [range] => search_packages(vec![range.clone()]),
+            // On the threads of the global pool, shared by the concurrent searches.
+            chunks => {
+                let found: Vec<Vec<_>> = chunks
+                    .par_iter()
+                    .map(|range| search_packages(vec![range.clone()]))
+                    .collect::<Result<_>>()?;
+                Ok(found.into_iter().flatten().collect())
+            }
+        }
+    }
+}
+
+/// Splits a database into `count` ranges of whole packages, of about the same size.
+fn split(buffer: &[u8], count: usize) -> Vec<Range<usize>> {
+    let mut chunks = Vec::with_capacity(count);
+    let mut start = 0;
+    for i in 1..count {
+        let target = buffer.len() / count * i;
+        if target <= start {
+            continue;
+        }
+        let end = next_package_start(buffer, target);
+        if end >= buffer.len() {
+            break;
+        }
+        chunks.push(start..end);
+        start = end;
+    }
+    chunks.push(start..buffer.len());
+
+    chunks
+}
+
 /// A builder for a `ReaderIter` to iterate over entries in the database matching a given pattern.
 pub struct Query<'a, 'b> {
     /// The underlying reader from which we read input.
@@ -518,4 +934,166 @@ ANOTHER LINE
         let mat = next_matching_line(matcher, buffer, 0);
         assert_eq!(mat, Some(Match::new(11, 17)));
     }
+
+    /// A database of 40 packages with long shared prefixes, which escape the differentials,
+    /// some of which are newlines.
+    fn write_database(path: &Path) {
+        use crate::cache::package::PathOrigin;
+
+        let long = "d".repeat(200);
+        let packages: Vec<(PathOrigin, String, Vec<String>)> = (0..40)
+            .map(|i| {
+                let origin = PathOrigin {
+                    attr: format!("zlib{}", i),
+                    output: "out".into(),
+                    toplevel: true,
+                    system: None,
+                    index: None,
+                };
+                let files = vec![
+                    format!("include/lib{}.h", i),
+                    format!("{}/file{}", long, i),
+                    format!("{}/file{}x", long, i),
+                    format!("lib/libz{}.so", i),
+                    format!("lib/libz{}.so.0123456789", i),
+                ];
+                (origin, format!("/nix/store/{:032}-zlib-{}", i, i), files)
+            })
+            .collect();
+        write_index(path, &packages);
+    }
+
+    /// Store paths and paths of the entries matching the pattern, sorted.
+    fn found(results: Vec<(StorePath, FileTreeEntry)>) -> Vec<(String, Vec<u8>)> {
+        let mut found: Vec<_> = results
+            .into_iter()
+            .map(|(path, entry)| (path.as_str().into_owned(), entry.path))
+            .collect();
+        found.sort();
+        found
+    }
+
+    fn search_whole(buffer: &SharedBuffer, pattern: &Regex) -> Vec<(String, Vec<u8>)> {
+        found(
+            Reader::from_shared_buffer(buffer.clone())
+                .unwrap()
+                .query(pattern)
+                .run()
+                .unwrap()
+                .collect::<Result<_>>()
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_chunks_are_searched_like_the_whole_database() {
+        let database = tempfile::NamedTempFile::new().unwrap();
+        write_database(database.path());
+        let buffer: SharedBuffer = read_from_path(database.path()).unwrap().into();
+
+        let chunks = split(&buffer, 7);
+        assert_eq!(chunks.len(), 7);
+        assert_eq!(chunks[0].start, 0);
+        assert_eq!(chunks[6].end, buffer.len());
+        assert!(chunks.windows(2).all(|pair| pair[0].end == pair[1].start));
+
+        let shared = SharedReader {
+            buffer: buffer.clone(),
+            chunks: OnceCell::from(chunks),
+            basenames: OnceCell::new(),
+        };
+        for pattern in ["^/lib/libz1.*", "file3", "^/include/"] {
+            let pattern = Regex::new(pattern).unwrap();
+            let whole = search_whole(&buffer, &pattern);
+            assert!(!whole.is_empty());
+            assert_eq!(whole, found(shared.search(&pattern, None).unwrap()));
+        }
+        assert!(shared.basenames.get().is_none());
+
+        // packages apart from each other are decoded one after the other
+        let mut starts = vec![0];
+        while starts.len() <= 31 {
+            starts.push(package_end(&buffer, starts[starts.len() - 1]));
+        }
+        let pattern = Regex::new("^/include/").unwrap();
+        let apart = Reader::from_packages(
+            buffer.clone(),
+            vec![starts[3]..starts[4], starts[30]..starts[31]],
+        );
+        assert_eq!(
+            found(
+                apart
+                    .query(&pattern)
+                    .run()
+                    .unwrap()
+                    .collect::<Result<_>>()
+                    .unwrap()
+            ),
+            [
+                (
+                    format!("/nix/store/{:032}-zlib-3", 3),
+                    b"/include/lib3.h".to_vec()
+                ),
+                (
+                    format!("/nix/store/{:032}-zlib-30", 30),
+                    b"/include/lib30.h".to_vec()
+                )
+            ]
+        );
+        let hash = Some(format!("{:032}", 12));
+        let pattern = Regex::new("^/include/").unwrap();
+        assert_eq!(shared.search(&pattern, hash).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_exact_paths_are_looked_up_by_file_name() {
+        let exact = |pattern: &str| exact_path(&Regex::new(pattern).unwrap());
+        assert_eq!(
+            exact(r"^/lib/pkgconfig/zlib\.pc$").as_deref(),
+            Some(&b"/lib/pkgconfig/zlib.pc"[..])
+        );
+        assert_eq!(exact(r"^/lib/libz\.so.*"), None);
+        assert_eq!(exact(r"(?i)^/include/zlib\.h$"), None);
+
+        let directory = tempfile::tempdir().unwrap();
+        let database = directory.path().join("files");
+        write_database(&database);
+        let decompressed = directory.path().join("decompressed");
+        std::fs::write(&decompressed, read_from_path(&database).unwrap()).unwrap();
+        let buffer = SharedBuffer::map(&decompressed).unwrap();
+        let in_memory: SharedBuffer = read_from_path(&database).unwrap().into();
+
+        let long = "d".repeat(200);
+        let patterns = [
+            r"^/lib/libz12\.so$".to_string(),
+            r"^/include/lib3\.h$".to_string(),
+            format!(r"^/{}/file7x$", long),
+            r"^/lib/libnone\.so$".to_string(),
+        ];
+        for buffer in [&buffer, &in_memory] {
+            let shared = SharedReader::new(buffer.clone());
+            for pattern in &patterns {
+                let pattern = Regex::new(pattern).unwrap();
+                let whole = search_whole(buffer, &pattern);
+                assert_eq!(whole, found(shared.search(&pattern, None).unwrap()));
+            }
+            assert!(matches!(shared.basenames.get(), Some(Some(_))));
+        }
+        assert!(directory.path().join("decompressed.basenames").exists());
+
+        // the next sessions map the lookup
+        let shared = SharedReader::new(buffer.clone());
+        let pattern = Regex::new(r"^/lib/libz12\.so$").unwrap();
+        assert_eq!(found(shared.search(&pattern, None).unwrap()).len(), 1);
+        let lookup = shared.basenames.get().unwrap().as_ref().unwrap();
+        assert!(lookup.path().is_some());
+        assert_eq!(
+            Basenames::new(lookup, buffer.len())
+                .unwrap()
+                .packages(b"file7x")
+                .len(),
+            1
+        );
+        assert!(Basenames::new(lookup, buffer.len() + 1).is_none());
+    }
 }
@@ -1,12 +1,13 @@
 use std::ffi::OsStr;
 
+mod basenames;
 pub mod database;
 mod files;
 mod frcode;
 mod package;
 
-pub use files::{FileNode, FileTreeEntry};
-pub use package::StorePath;
+pub use files::{FileNode, FileTree, FileTreeEntry};
+pub use package::{PathOrigin, StorePath};
 
 pub fn cache_dir() -> &'static OsStr {
     let base = xdg::BaseDirectories::with_prefix("nix-index").unwrap();
@@ -45,6 +45,11 @@ pub struct PathOrigin {
 
     /// Target system
     pub system: Option<String>,
+
+    /// Name of the index this path was found in, when several are searched, see `IndexSet`.
+    /// Not stored in the index itself.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<String>,
 }
 
 impl PathOrigin {
@@ -104,6 +109,7 @@ impl PathOrigin {
                             output: output,
                             toplevel: toplevel,
                             system: None,
+                            index: None,
                         })
                     })
             })
@@ -0,0 +1,976 @@
+use clap::Subcommand;
+use fuser::FileAttr;
+use regex::bytes::Regex;
+use log::{info, warn};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::attrpath::AttrPaths;
+use crate::cache::{self, FileTreeEntry, PathOrigin, StorePath};
+use crate::diff;
+use crate::export;
+use crate::filesource::{BinaryCacheSource, FileSource};
+use crate::import;
+use crate::index;
+use crate::interactive::human_size;
+use crate::nix::{self, ShellSource};
+use crate::outputs;
+use crate::pin::{self, IndexPin, CURRENT_PIN};
+use crate::popcount::Popcount;
+use crate::querycache;
+use crate::resolution::{
+    diagnose_resolution_db, insert_resolution, read_pinned_resolution_file, update_resolution_db,
+    write_resolution_db, DbFormat, Decision, Provenance, ProvideData, Resolution, ResolutionDB, ResolutionData,
+};
+use crate::signature::{self, SignatureCheck};
+use crate::usage;
+
+/// Subcommands working on recorded resolutions, without running anything.
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Manage resolution databases
+    #[command(subcommand)]
+    Res(ResCommand),
+    /// Export the packages provided in a resolution database
+    #[command(subcommand)]
+    Export(ExportCommand),
+    /// Find the nixpkgs attribute of a store path, a file in it or its hash, at the nixpkgs the index was built from
+    AttrPath {
+        /// e.g. `/nix/store/<hash>-zlib-1.3-dev`
+        path: String,
+    },
+    /// Manage the nix-index databases searched
+    #[command(subcommand)]
+    Index(IndexCommand),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum IndexCommand {
+    /// Download a prebuilt index, searched by the next sessions instead of the embedded one
+    Fetch {
+        /// Channel it indexes, only `nixpkgs-unstable` is published at the default URL
+        #[arg(long, default_value = index::DEFAULT_CHANNEL)]
+        channel: String,
+        /// Nix system, the one of this machine by default
+        #[arg(long)]
+        system: Option<String>,
+        /// Where to download it from, `{channel}` and `{system}` are replaced
+        #[arg(long, default_value = index::DEFAULT_INDEX_URL)]
+        url: String,
+    },
+    /// Build the index of a nixpkgs from the file listings of the binary cache, like nix-index, this takes a long while
+    Build {
+        /// nixpkgs revision or flake reference, e.g. `nixos-23.11` branches
+        #[arg(long)]
+        nixpkgs: String,
+        /// Nix system, the one of this machine by default
+        #[arg(long)]
+        system: Option<String>,
+        /// Binary cache serving the `.ls` listings of the packages
+        #[arg(long = "binary-cache", default_value = index::DEFAULT_BINARY_CACHE)]
+        binary_cache: String,
+        /// Listings fetched at once
+        #[arg(long, default_value_t = index::DEFAULT_BUILD_JOBS)]
+        jobs: usize,
+        /// Directory of the index, searched by the next sessions when it is the most recent one
+        #[arg(long = "db", default_value_os = cache::cache_dir())]
+        database: PathBuf,
+    },
+    /// Index the packages of a flake, e.g. internal tools, searched by the next sessions after the other indexes
+    AddFlake {
+        /// e.g. `github:acme/tools` or `.`
+        flakeref: String,
+        /// Nix system, the one of this machine by default
+        #[arg(long)]
+        system: Option<String>,
+        /// Leave out the outputs neither in the store nor on a binary cache, rather than building them
+        #[arg(long = "no-build")]
+        no_build: bool,
+    },
+    /// Manage the candidates of requested paths remembered across sessions, by indexes searched
+    #[command(subcommand)]
+    Cache(QueryCacheCommand),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum QueryCacheCommand {
+    /// Show how many requested paths are remembered and the space they take, for each set of indexes
+    Stats,
+    /// Forget them all, e.g. to reclaim the space taken by indexes no longer searched
+    Clear,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ResCommand {
+    /// Convert a resolution database, formats are chosen by file extension (`.toml` or `.json`)
+    Convert { input: PathBuf, output: PathBuf },
+    /// Point the provided store paths to the current index, keeping their attributes
+    Repin {
+        resolutions: PathBuf,
+        /// Rewritten in place otherwise
+        #[arg(long = "output", short = 'o')]
+        output: Option<PathBuf>,
+    },
+    /// Provide a path from a nixpkgs attribute, found in the index or else listed by the binary caches
+    Add {
+        /// Requested path, e.g. `include/zlib.h`
+        requested_path: String,
+        /// nixpkgs attribute providing it, e.g. `zlib`
+        #[arg(long = "pkg")]
+        attr: String,
+        /// Output of the attribute, any of them by default
+        #[arg(long)]
+        output: Option<String>,
+        #[command(flatten)]
+        target: EditTarget,
+    },
+    /// Always ENOENT a path
+    Ignore {
+        requested_path: String,
+        /// And everything under it
+        #[arg(long)]
+        tree: bool,
+        #[command(flatten)]
+        target: EditTarget,
+    },
+    /// Redirect a path to another one, e.g. a tool installed outside of Nix
+    Redirect {
+        requested_path: String,
+        /// Absolute, or relative to the directory buildxyz runs from
+        host_path: PathBuf,
+        #[command(flatten)]
+        target: EditTarget,
+    },
+    /// Remove the resolution of a path
+    Rm {
+        requested_path: String,
+        #[command(flatten)]
+        target: EditTarget,
+    },
+    /// Provide the `bin/`, `include/` and `lib/` entries of the packages of a development shell
+    #[command(group(clap::ArgGroup::new("shell").required(true).args(["from_flake", "from_file"])))]
+    Import {
+        /// Flake installable of the shell, e.g. `.#devShells.x86_64-linux.default`
+        #[arg(long = "from-flake")]
+        from_flake: Option<String>,
+        /// Nix file of the shell, e.g. `shell.nix`
+        #[arg(long = "from-file")]
+        from_file: Option<PathBuf>,
+        /// Resolution file, created if missing
+        #[arg(long = "file", short = 'f', default_value = "buildxyz.toml")]
+        file: PathBuf,
+    },
+    /// List the resolutions
+    List {
+        /// Only those whose requested path matches this regex
+        #[arg(long)]
+        filter: Option<String>,
+        /// Resolution file
+        #[arg(long = "file", short = 'f', default_value = "buildxyz.toml")]
+        file: PathBuf,
+    },
+    /// Compare two resolution databases, e.g. to review an update of a committed one
+    Diff {
+        before: PathBuf,
+        after: PathBuf,
+        /// Print the changes as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Sign a resolution file with minisign, for `--require-signed`
+    Sign {
+        resolutions: PathBuf,
+        /// minisign secret key, the default minisign one otherwise
+        #[arg(long = "secret-key", short = 's')]
+        secret_key: Option<PathBuf>,
+    },
+    /// Check that a trusted key signed a resolution file
+    Verify {
+        resolutions: PathBuf,
+        /// minisign public key, as a file or base64
+        #[arg(long = "key", short = 'k', required = true)]
+        keys: Vec<String>,
+    },
+    /// Report every resolution which does not load, e.g. in CI, failing if there is any
+    Lint {
+        #[arg(required = true)]
+        resolutions: Vec<PathBuf>,
+    },
+    /// Remove the resolutions which were not used by buildxyz for a while
+    Gc {
+        /// Days without a use, counted from the first run which loaded the resolution if never used
+        #[arg(long = "unused-since")]
+        unused_since: u64,
+        /// Only list the resolutions which would be removed
+        #[arg(long)]
+        dry_run: bool,
+        /// Resolution file
+        #[arg(long = "file", short = 'f', default_value = "buildxyz.toml")]
+        file: PathBuf,
+    },
+}
+
+/// Resolution file and phase edited by a `res` subcommand.
+#[derive(clap::Args, Debug)]
+pub struct EditTarget {
+    /// Resolution file, created if missing
+    #[arg(long = "file", short = 'f', default_value = "buildxyz.toml")]
+    file: PathBuf,
+    /// Only in this build phase
+    #[arg(long)]
+    phase: Option<String>,
+    #[arg(long, default_value_t = 0)]
+    priority: i64,
+    /// Why this resolution, recorded in its provenance
+    #[arg(long)]
+    comment: Option<String>,
+    /// Ask to confirm the resolution once per run, even with `--automatic`
+    #[arg(long)]
+    reconfirm: bool,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ExportCommand {
+    /// `nativeBuildInputs` and `buildInputs` lists of the provided nixpkgs attributes
+    Nix {
+        resolutions: PathBuf,
+        /// Use the attributes recorded in the index rather than finding them in nixpkgs
+        #[arg(long)]
+        trust_index: bool,
+        /// Written to stdout otherwise
+        #[arg(long = "output", short = 'o')]
+        output: Option<PathBuf>,
+    },
+    /// `shell.nix` providing the nixpkgs attributes, e.g. to rerun the build without buildxyz
+    Shell {
+        resolutions: PathBuf,
+        /// Generate a `flake.nix` with a `devShell` instead
+        #[arg(long)]
+        flake: bool,
+        /// nixpkgs revision the index was built from, defaults to the one the resolutions are pinned to
+        #[arg(long)]
+        nixpkgs_rev: Option<String>,
+        /// Use the attributes recorded in the index rather than finding them in nixpkgs
+        #[arg(long)]
+        trust_index: bool,
+        /// Written to stdout otherwise
+        #[arg(long = "output", short = 'o')]
+        output: Option<PathBuf>,
+    },
+    /// Draft `package.nix` building the project with `stdenv.mkDerivation` and the provided nixpkgs attributes
+    Derivation {
+        resolutions: PathBuf,
+        /// Source tree of the project, its build system is guessed from it
+        #[arg(long, default_value = ".")]
+        source: PathBuf,
+        /// Package name, defaults to the name of the source directory
+        #[arg(long)]
+        pname: Option<String>,
+        /// nixpkgs revision the index was built from, defaults to the one the resolutions are pinned to
+        #[arg(long)]
+        nixpkgs_rev: Option<String>,
+        /// Use the attributes recorded in the index rather than finding them in nixpkgs
+        #[arg(long)]
+        trust_index: bool,
+        /// Written to stdout otherwise
+        #[arg(long = "output", short = 'o')]
+        output: Option<PathBuf>,
+    },
+}
+
+fn read_db(filepath: &Path) -> Result<(ResolutionDB, Option<IndexPin>), io::Error> {
+    read_pinned_resolution_file(filepath)?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not a valid resolution database", filepath.display()),
+        )
+    })
+}
+
+/// An empty database for a file to be created.
+fn read_or_create_db(filepath: &Path) -> Result<(ResolutionDB, Option<IndexPin>), io::Error> {
+    if filepath.exists() {
+        read_db(filepath)
+    } else {
+        Ok(Default::default())
+    }
+}
+
+/// Requested paths are relative to the mountpoint.
+fn normalize_requested_path(requested_path: &str) -> String {
+    requested_path.trim_start_matches('/').to_string()
+}
+
+/// Store path of `attr` providing `requested_path` in the index, top-level ones first,
+/// then the output expected to provide it unless one is given.
+fn find_in_index(
+    requested_path: &str,
+    attr: &str,
+    output: Option<&str>,
+) -> Result<(StorePath, FileTreeEntry), io::Error> {
+    let pattern = Regex::new(&format!("^/{}$", regex::escape(requested_path)))
+        .expect("An escaped path is a valid regex");
+
+    pin::query_embedded_index(&pattern)
+        .into_iter()
+        .filter(|(store_path, _)| {
+            let origin = store_path.origin();
+            origin.attr == attr && output.map_or(true, |output| origin.output == output)
+        })
+        .min_by_key(|(store_path, _)| {
+            let origin = store_path.origin();
+            (
+                !origin.toplevel,
+                outputs::output_rank(requested_path, &origin.output),
+            )
+        })
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("`{}` does not provide {} in the index", attr, requested_path),
+            )
+        })
+}
+
+/// Output of a nixpkgs attribute providing the requested path, as listed by the binary caches,
+/// e.g. for an attribute more recent than the index. Outputs are tried in the expected order.
+fn find_in_binary_cache(
+    requested_path: &str,
+    attr: &str,
+    output: Option<&str>,
+) -> Result<(StorePath, FileTreeEntry), io::Error> {
+    let pattern = Regex::new(&format!("^/{}$", regex::escape(requested_path)))
+        .expect("An escaped path is a valid regex");
+    let binary_cache = BinaryCacheSource::new(nix::substituters());
+    let outputs = match output {
+        Some(output) => vec![output],
+        None => outputs::preferred_outputs(requested_path).to_vec(),
+    };
+
+    for output in outputs {
+        let origin = PathOrigin {
+            attr: attr.to_string(),
+            output: output.to_string(),
+            toplevel: true,
+            system: None,
+            index: None,
+        };
+        let out_path = match nix::out_path(&outputs::installable(&origin)) {
+            Ok(out_path) => out_path,
+            Err(err) => {
+                warn!("{}", err);
+                continue;
+            }
+        };
+        let Some(store_path) = StorePath::parse(origin, &out_path) else {
+            continue;
+        };
+        let entry = binary_cache
+            .files_of(&store_path, &pattern)
+            .and_then(|files| files.into_iter().next());
+        if let Some(entry) = entry {
+            return Ok((store_path, entry));
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!(
+            "`{}` does not provide {} in the index nor in the binary caches",
+            attr, requested_path
+        ),
+    ))
+}
+
+fn edit_db(
+    target: &EditTarget,
+    edit: impl FnOnce(&mut ResolutionDB) -> Result<(), io::Error>,
+) -> Result<(), io::Error> {
+    let (_, pin) = read_or_create_db(&target.file)?;
+
+    update_resolution_db(&target.file, pin.as_ref(), edit)
+}
+
+fn write_output(output: Option<&PathBuf>, contents: &str) -> Result<(), io::Error> {
+    match output {
+        Some(filepath) => std::fs::write(filepath, contents),
+        None => io::stdout().write_all(contents.as_bytes()),
+    }
+}
+
+/// Attribute of a provided store path in the pinned nixpkgs, the one the index recorded if it is not there.
+fn attr_in_nixpkgs(attr_paths: &mut AttrPaths, store_path: &StorePath) -> String {
+    let origin = store_path.origin();
+    match attr_paths.resolve(&store_path.as_str(), Some(&origin), true) {
+        Some(attr_path) => {
+            if attr_path.attr != origin.attr {
+                info!(
+                    "{} is {} in nixpkgs, not {} as the index recorded",
+                    store_path.as_str(),
+                    attr_path.attr,
+                    origin.attr
+                );
+            }
+            attr_path.attr
+        }
+        None => {
+            warn!(
+                "{} is not in the nixpkgs the index was built from, exported as {}",
+                store_path.as_str(),
+                origin.attr
+            );
+            origin.attr.clone()
+        }
+    }
+}
+
+/// Inputs of the exported database, by their attribute in the pinned nixpkgs unless `trust_index`.
+fn export_inputs(db: &ResolutionDB, trust_index: bool) -> export::Inputs {
+    if trust_index {
+        return export::collect_inputs(db);
+    }
+
+    let mut attr_paths = AttrPaths::pinned();
+    export::collect_inputs_with(db, |store_path| attr_in_nixpkgs(&mut attr_paths, store_path))
+}
+
+/// Like `export_inputs`, with the kind of input told by `is_native`, see `export::collect_inputs_classified`.
+fn export_inputs_classified(
+    db: &ResolutionDB,
+    trust_index: bool,
+    is_native: impl FnMut(&StorePath, &str) -> bool,
+) -> export::Inputs {
+    if trust_index {
+        return export::collect_inputs_classified(
+            db,
+            |store_path| store_path.origin().attr.clone(),
+            is_native,
+        );
+    }
+
+    let mut attr_paths = AttrPaths::pinned();
+    export::collect_inputs_classified(
+        db,
+        |store_path| attr_in_nixpkgs(&mut attr_paths, store_path),
+        is_native,
+    )
+}
+
+pub fn run(command: &Command) -> Result<(), io::Error> {
+    match command {
+        Command::Res(ResCommand::Convert { input, output }) => {
+            let (db, pin) = read_db(input)?;
+            // The output is replaced, not merged into.
+            update_resolution_db(output, pin.as_ref(), |converted| {
+                *converted = db.clone();
+                Ok(())
+            })?;
+            info!(
+                "Converted {} resolutions from {} to {}",
+                db.len(),
+                input.display(),
+                output.display()
+            );
+
+            Ok(())
+        }
+        Command::Res(ResCommand::Repin {
+            resolutions,
+            output,
+        }) => {
+            let (mut db, _) = read_db(resolutions)?;
+            let report = pin::repin(&mut db);
+            for requested_path in &report.missing {
+                warn!(
+                    "{} is not provided by the same attribute in the current index anymore, left as is",
+                    requested_path
+                );
+            }
+            write_resolution_db(output.as_ref().unwrap_or(resolutions), &db, Some(&CURRENT_PIN))?;
+            info!(
+                "Repinned {} resolutions to {}",
+                report.updated.len(),
+                *CURRENT_PIN
+            );
+
+            Ok(())
+        }
+        Command::Res(ResCommand::Add {
+            requested_path,
+            attr,
+            output,
+            target,
+        }) => {
+            let requested_path = normalize_requested_path(requested_path);
+            let (store_path, entry) = find_in_index(&requested_path, attr, output.as_deref())
+                .or_else(|err| {
+                    info!("{}, asking the binary caches", err);
+                    find_in_binary_cache(&requested_path, attr, output.as_deref())
+                })?;
+            let attribute: FileAttr = entry.node.into();
+            let (mut db, pin) = read_or_create_db(&target.file)?;
+            let data = ResolutionData {
+                requested_path: requested_path.clone(),
+                decision: Decision::Provide(Box::new(ProvideData {
+                    kind: attribute.kind,
+                    file_entry_name: String::from_utf8_lossy(&entry.path).to_string(),
+                    store_path,
+                    link_mode: None,
+                    flake: None,
+                })),
+                requested_by: None,
+                priority: target.priority,
+                source: None,
+                provenance: Provenance::now(None, target.comment.clone()),
+                reconfirm: target.reconfirm,
+            };
+            insert_resolution(&mut db, data, target.phase.as_deref());
+            // The store path comes from the current index.
+            write_resolution_db(&target.file, &db, Some(pin.as_ref().unwrap_or(&CURRENT_PIN)))?;
+            info!("{}: {}", requested_path, db[&requested_path].describe());
+
+            Ok(())
+        }
+        Command::Res(ResCommand::Ignore {
+            requested_path,
+            tree,
+            target,
+        }) => edit_db(target, |db| {
+            let data = ResolutionData {
+                requested_path: normalize_requested_path(requested_path),
+                decision: if *tree {
+                    Decision::IgnoreTree
+                } else {
+                    Decision::Ignore
+                },
+                requested_by: None,
+                priority: target.priority,
+                source: None,
+                provenance: Provenance::now(None, target.comment.clone()),
+                reconfirm: target.reconfirm,
+            };
+            insert_resolution(db, data, target.phase.as_deref());
+
+            Ok(())
+        }),
+        Command::Res(ResCommand::Redirect {
+            requested_path,
+            host_path,
+            target,
+        }) => edit_db(target, |db| {
+            let data = ResolutionData {
+                requested_path: normalize_requested_path(requested_path),
+                decision: Decision::Redirect {
+                    target: host_path.clone(),
+                },
+                requested_by: None,
+                priority: target.priority,
+                source: None,
+                provenance: Provenance::now(None, target.comment.clone()),
+                reconfirm: target.reconfirm,
+            };
+            insert_resolution(db, data, target.phase.as_deref());
+
+            Ok(())
+        }),
+        Command::Res(ResCommand::Rm {
+            requested_path,
+            target,
+        }) => edit_db(target, |db| {
+            let requested_path = normalize_requested_path(requested_path);
+            let not_found = || {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("No resolution for {}", requested_path),
+                )
+            };
+
+            match (&target.phase, db.get_mut(&requested_path)) {
+                (None, Some(_)) => {
+                    db.remove(&requested_path);
+                }
+                (Some(phase), Some(Resolution::PhaseResolution(phase_data))) => {
+                    phase_data.phases.remove(phase).ok_or_else(not_found)?;
+                    if phase_data.phases.is_empty() {
+                        db.remove(&requested_path);
+                    }
+                }
+                _ => return Err(not_found()),
+            }
+
+            Ok(())
+        }),
+        Command::Res(ResCommand::Import {
+            from_flake,
+            from_file,
+            file,
+        }) => {
+            let source = match (from_flake, from_file) {
+                (Some(installable), _) => ShellSource::Flake(installable),
+                (None, Some(filepath)) => ShellSource::File(filepath),
+                (None, None) => unreachable!("clap requires a shell"),
+            };
+            let nix_error = |err: nix::Error| io::Error::new(io::ErrorKind::Other, err.to_string());
+            let inputs = nix::shell_inputs(&source).map_err(nix_error)?;
+            info!("Building the {} inputs of the shell...", inputs.len());
+            nix::realize_shell_inputs(&source).map_err(nix_error)?;
+
+            let (mut db, pin) = read_or_create_db(file)?;
+            let comment = match &source {
+                ShellSource::Flake(installable) => format!("imported from {}", installable),
+                ShellSource::File(filepath) => format!("imported from {}", filepath.display()),
+            };
+            let added =
+                import::import_shell_inputs(&mut db, inputs, &Provenance::now(None, Some(comment)));
+            // The store paths come from the nixpkgs of the shell, not from the index.
+            write_resolution_db(file, &db, pin.as_ref())?;
+            info!("Imported {} resolutions into {}", added, file.display());
+
+            Ok(())
+        }
+        Command::Res(ResCommand::List { filter, file }) => {
+            let filter = filter
+                .as_deref()
+                .map(regex::Regex::new)
+                .transpose()
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            let (db, _) = read_db(file)?;
+            for (requested_path, resolution) in &db {
+                if filter.as_ref().map_or(true, |filter| filter.is_match(requested_path)) {
+                    let comments: Vec<&str> = resolution
+                        .all_data()
+                        .into_iter()
+                        .filter_map(|data| data.provenance.comment.as_deref())
+                        .collect();
+                    if comments.is_empty() {
+                        println!("{}: {}", requested_path, resolution.describe());
+                    } else {
+                        println!(
+                            "{}: {} ({})",
+                            requested_path,
+                            resolution.describe(),
+                            comments.join(", ")
+                        );
+                    }
+                }
+            }
+
+            Ok(())
+        }
+        Command::Res(ResCommand::Diff {
+            before,
+            after,
+            json,
+        }) => {
+            let changes = diff::diff(&read_db(before)?.0, &read_db(after)?.0);
+            if *json {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&changes).expect("Failed to serialize the changes")
+                );
+            } else {
+                for change in &changes {
+                    println!("{}", change);
+                }
+            }
+
+            Ok(())
+        }
+        Command::Res(ResCommand::Sign {
+            resolutions,
+            secret_key,
+        }) => {
+            signature::sign(resolutions, secret_key.as_deref())?;
+            info!(
+                "Signed {} in {}",
+                resolutions.display(),
+                signature::signature_location(&resolutions.to_string_lossy())
+            );
+
+            Ok(())
+        }
+        Command::Res(ResCommand::Verify { resolutions, keys }) => {
+            let signature_path = signature::signature_location(&resolutions.to_string_lossy());
+            SignatureCheck::new(keys.clone())
+                .verify(&std::fs::read(resolutions)?, &std::fs::read(signature_path)?)?;
+            info!("{} is signed by a trusted key", resolutions.display());
+
+            Ok(())
+        }
+        Command::Res(ResCommand::Lint { resolutions }) => {
+            let mut problems = 0;
+            for filepath in resolutions {
+                let data = std::fs::read_to_string(filepath)?;
+                let (_, diagnostics) =
+                    diagnose_resolution_db(&data, DbFormat::from_path(filepath));
+                for diagnostic in &diagnostics {
+                    println!("{}: {}", filepath.display(), diagnostic);
+                }
+                problems += diagnostics.len();
+            }
+
+            if problems > 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} invalid resolutions", problems),
+                ));
+            }
+            Ok(())
+        }
+        Command::Res(ResCommand::Gc {
+            unused_since,
+            dry_run,
+            file,
+        }) => {
+            let (db, pin) = read_db(file)?;
+            let canonical_file = std::fs::canonicalize(file)?;
+            let stats_filepath = usage::default_stats_filepath();
+            let stats = usage::read_stats(&stats_filepath)?;
+            let cutoff = usage::days_ago(*unused_since);
+            // Resolutions buildxyz never loaded are kept, nothing is known about them.
+            let unused: Vec<String> = stats
+                .get(&canonical_file)
+                .map(|usages| {
+                    db.keys()
+                        .filter(|requested_path| {
+                            usages
+                                .get(*requested_path)
+                                .map_or(false, |usage| usage.unused_since(cutoff))
+                        })
+                        .cloned()
+                        .collect()
+                })
+                .unwrap_or_default();
+
+            if *dry_run {
+                for requested_path in &unused {
+                    println!("{}: {}", requested_path, db[requested_path].describe());
+                }
+                return Ok(());
+            }
+
+            update_resolution_db(file, pin.as_ref(), |db| {
+                for requested_path in &unused {
+                    db.remove(requested_path);
+                }
+                Ok(())
+            })?;
+            usage::update_stats(&stats_filepath, |stats| {
+                if let Some(usages) = stats.get_mut(&canonical_file) {
+                    for requested_path in &unused {
+                        usages.remove(requested_path);
+                    }
+                }
+            })?;
+            info!(
+                "Removed {} resolutions unused for {} days from {}",
+                unused.len(),
+                unused_since,
+                file.display()
+            );
+
+            Ok(())
+        }
+        Command::Export(ExportCommand::Nix {
+            resolutions,
+            trust_index,
+            output,
+        }) => {
+            let inputs = export_inputs(&read_db(resolutions)?.0, *trust_index);
+            write_output(output.as_ref(), &export::render_nix_inputs(&inputs))
+        }
+        Command::Export(ExportCommand::Shell {
+            resolutions,
+            flake,
+            nixpkgs_rev,
+            trust_index,
+            output,
+        }) => {
+            let (db, pin) = read_db(resolutions)?;
+            let inputs = export_inputs(&db, *trust_index);
+            let nixpkgs_rev = nixpkgs_rev
+                .clone()
+                .or_else(|| pin.and_then(|pin| pin.nixpkgs_rev));
+            if nixpkgs_rev.is_none() {
+                warn!("No nixpkgs revision given, the shell will use whatever nixpkgs is available when entered");
+            }
+            let rendered = if *flake {
+                export::render_flake(&inputs, nixpkgs_rev.as_deref())
+            } else {
+                export::render_shell_nix(&inputs, nixpkgs_rev.as_deref())
+            };
+            write_output(output.as_ref(), &rendered)
+        }
+        Command::Export(ExportCommand::Derivation {
+            resolutions,
+            source,
+            pname,
+            nixpkgs_rev,
+            trust_index,
+            output,
+        }) => {
+            let (db, pin) = read_db(resolutions)?;
+            // nixpkgs tells whether a package is mostly a tool or a library, the requested path otherwise.
+            let popcount = Popcount::embedded();
+            let inputs = export_inputs_classified(&db, *trust_index, |store_path, requested_path| {
+                popcount
+                    .is_mostly_native(&store_path.as_str())
+                    .unwrap_or_else(|| export::is_native_path(requested_path))
+            });
+            let nixpkgs_rev = nixpkgs_rev
+                .clone()
+                .or_else(|| pin.and_then(|pin| pin.nixpkgs_rev));
+            let pname = match pname {
+                Some(pname) => pname.clone(),
+                None => source
+                    .canonicalize()?
+                    .file_name()
+                    .map_or_else(|| "package".to_string(), |name| name.to_string_lossy().to_string()),
+            };
+            let build_system = export::BuildSystem::detect(source);
+            info!("{} is built with {:?}", source.display(), build_system);
+            let rendered = export::render_derivation(
+                &pname,
+                build_system,
+                &inputs,
+                export::recorded_command(&db).as_deref(),
+                nixpkgs_rev.as_deref(),
+            );
+            write_output(output.as_ref(), &rendered)
+        }
+        Command::AttrPath { path } => {
+            let attr_path = AttrPaths::pinned().resolve(path, None, true).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} is not in the nixpkgs the index was built from", path),
+                )
+            })?;
+            println!("{}", attr_path);
+
+            Ok(())
+        }
+        Command::Index(IndexCommand::Fetch {
+            channel,
+            system,
+            url,
+        }) => {
+            let system = system.clone().unwrap_or_else(index::current_system);
+            index::fetch_index(channel, &system, url)?;
+
+            Ok(())
+        }
+        Command::Index(IndexCommand::Build {
+            nixpkgs,
+            system,
+            binary_cache,
+            jobs,
+            database,
+        }) => {
+            let system = system.clone().unwrap_or_else(index::current_system);
+            index::build_index(nixpkgs, &system, binary_cache, *jobs, database)?;
+
+            Ok(())
+        }
+        Command::Index(IndexCommand::AddFlake {
+            flakeref,
+            system,
+            no_build,
+        }) => {
+            let system = system.clone().unwrap_or_else(index::current_system);
+            index::add_flake(flakeref, &system, !no_build)?;
+
+            Ok(())
+        }
+        Command::Index(IndexCommand::Cache(QueryCacheCommand::Stats)) => {
+            for cache in querycache::stats(&querycache::queries_dir()?)? {
+                println!(
+                    "{}: {} requested paths, {}",
+                    cache.directory.display(),
+                    cache.entries,
+                    human_size(cache.bytes as usize)
+                );
+                for line in cache.key.lines() {
+                    println!("  {}", line);
+                }
+            }
+
+            Ok(())
+        }
+        Command::Index(IndexCommand::Cache(QueryCacheCommand::Clear)) => {
+            let cleared = querycache::clear(&querycache::queries_dir()?)?;
+            info!(
+                "Forgot {} requested paths, {}",
+                cleared.iter().map(|cache| cache.entries).sum::<usize>(),
+                human_size(cleared.iter().map(|cache| cache.bytes as usize).sum())
+            );
+
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(file: &Path) -> EditTarget {
+        EditTarget {
+            file: file.to_owned(),
+            phase: None,
+            priority: 0,
+            comment: None,
+            reconfirm: false,
+        }
+    }
+
+    #[test]
+    fn test_res_add_ignore_and_rm() {
+        let directory = tempfile::tempdir().unwrap();
+        let file = directory.path().join("buildxyz.toml");
+
+        run(&Command::Res(ResCommand::Add {
+            requested_path: "/bin/hello".into(),
+            attr: "hello".into(),
+            output: None,
+            target: target(&file),
+        }))
+        .unwrap();
+        run(&Command::Res(ResCommand::Ignore {
+            requested_path: "include/zlib.h".into(),
+            tree: false,
+            target: EditTarget {
+                phase: Some("configure".into()),
+                comment: Some("vendored".into()),
+                ..target(&file)
+            },
+        }))
+        .unwrap();
+
+        let (db, pin) = read_db(&file).unwrap();
+        assert_eq!(pin.as_ref(), Some(&*CURRENT_PIN));
+        let Some(Decision::Provide(provide)) = db["bin/hello"].data_for_phase(None).map(|data| &data.decision) else {
+            panic!("bin/hello should be provided");
+        };
+        assert_eq!(provide.file_entry_name, "/bin/hello");
+        assert_eq!(provide.store_path.origin().attr, "hello");
+        assert!(db["include/zlib.h"].data_for_phase(None).is_none());
+        let ignored = db["include/zlib.h"].data_for_phase(Some("configure")).unwrap();
+        assert_eq!(ignored.decision, Decision::Ignore);
+        assert_eq!(ignored.provenance.comment.as_deref(), Some("vendored"));
+
+        let rm = |requested_path: &str, phase: Option<&str>| {
+            run(&Command::Res(ResCommand::Rm {
+                requested_path: requested_path.into(),
+                target: EditTarget {
+                    phase: phase.map(String::from),
+                    ..target(&file)
+                },
+            }))
+        };
+        assert_eq!(rm("include/zlib.h", Some("build")).unwrap_err().kind(), io::ErrorKind::NotFound);
+        rm("include/zlib.h", Some("configure")).unwrap();
+        rm("/bin/hello", None).unwrap();
+        assert_eq!(rm("bin/hello", None).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(read_db(&file).unwrap().0.is_empty());
+    }
+}
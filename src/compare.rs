@@ -0,0 +1,130 @@
+use log::debug;
+use std::path::Path;
+
+use crate::attrpath::AttrPaths;
+use crate::cache::{FileTreeEntry, StorePath};
+use crate::interactive::human_size;
+use crate::nix::{closure_size, count_files, package_metadata, PackageMetadata};
+use crate::outputs;
+
+/// What tells a candidate apart from another one providing the same path,
+/// e.g. `openssl_1_1.dev` and `openssl_3.dev` for `include/openssl/ssl.h`.
+#[derive(Clone)]
+pub struct CandidateDetails {
+    pub installable: String,
+    /// Installable evaluating to the candidate in nixpkgs, `None` if the one of the index does not
+    pub attr_path: Option<String>,
+    pub store_name: String,
+    /// `None` if nixpkgs could not be evaluated
+    pub metadata: Option<PackageMetadata>,
+    pub closure_size: Option<usize>,
+    /// Directory of the matched file, e.g. `include/openssl/`
+    pub prefix: String,
+    /// Files of the candidate under `prefix`
+    pub matching_files: Option<usize>,
+}
+
+/// Gather the details of a candidate, this evaluates nixpkgs and asks the store so it takes a while.
+pub fn candidate_details(store_path: &StorePath, entry: &FileTreeEntry) -> CandidateDetails {
+    let origin = store_path.origin();
+    let metadata = package_metadata(&origin.attr)
+        .map_err(|err| debug!("no metadata for {}: {}", origin.attr, err))
+        .ok();
+    let matched_file = String::from_utf8_lossy(&entry.path).into_owned();
+    let prefix = Path::new(matched_file.trim_start_matches('/'))
+        .parent()
+        .map(|parent| parent.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    CandidateDetails {
+        installable: outputs::installable(&origin),
+        attr_path: AttrPaths::pinned()
+            .resolve(&store_path.as_str(), Some(&origin), false)
+            .map(|attr_path| attr_path.to_string()),
+        store_name: store_path.name().into_owned(),
+        metadata,
+        closure_size: closure_size(store_path.as_str().as_ref()),
+        matching_files: count_files(&store_path.join(prefix.as_str().into())),
+        prefix: if prefix.is_empty() {
+            prefix
+        } else {
+            format!("{}/", prefix)
+        },
+    }
+}
+
+/// Rows of a side by side comparison: what is compared, then its value for each candidate.
+pub fn comparison(left: &CandidateDetails, right: &CandidateDetails) -> Vec<[String; 3]> {
+    let row = |label: &str, value: fn(&CandidateDetails) -> Option<String>| {
+        let value = |details| value(details).unwrap_or_else(|| "?".to_string());
+        [label.to_string(), value(left), value(right)]
+    };
+
+    vec![
+        row("package", |details| Some(details.installable.clone())),
+        // The store path name tells the version too, less clearly.
+        row("version", |details| {
+            Some(details.metadata.as_ref().map_or_else(
+                || details.store_name.clone(),
+                |metadata| metadata.version.clone(),
+            ))
+        }),
+        row("outputs", |details| {
+            Some(details.metadata.as_ref()?.outputs.join(", "))
+        }),
+        row("closure size", |details| {
+            details.closure_size.map(human_size)
+        }),
+        row("matching files", |details| {
+            details
+                .matching_files
+                .map(|files| format!("{} under /{}", files, details.prefix))
+        }),
+        row("license", |details| {
+            Some(details.metadata.as_ref()?.licenses.join(", "))
+                .filter(|licenses| !licenses.is_empty())
+        }),
+        row("description", |details| {
+            details.metadata.as_ref()?.description.clone()
+        }),
+        row("in nixpkgs", |details| {
+            Some(details.attr_path.clone().unwrap_or_else(|| "unverified".to_string()))
+        }),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn details(installable: &str, version: &str, closure_size: Option<usize>) -> CandidateDetails {
+        CandidateDetails {
+            installable: installable.to_string(),
+            attr_path: Some(installable.to_string()),
+            store_name: format!("openssl-{}-dev", version),
+            metadata: Some(PackageMetadata {
+                version: version.to_string(),
+                outputs: vec!["bin".into(), "dev".into(), "out".into()],
+                description: None,
+                licenses: vec!["Apache-2.0".into()],
+            }),
+            closure_size,
+            prefix: "include/openssl/".into(),
+            matching_files: Some(120),
+        }
+    }
+
+    #[test]
+    fn test_comparison_is_side_by_side() {
+        let rows = comparison(
+            &details("openssl_1_1.dev", "1.1.1w", Some(2048)),
+            &details("openssl_3.dev", "3.0.12", None),
+        );
+        assert_eq!(rows[0], ["package", "openssl_1_1.dev", "openssl_3.dev"]);
+        assert_eq!(rows[1], ["version", "1.1.1w", "3.0.12"]);
+        assert_eq!(rows[3], ["closure size", "2.0 KiB", "?"]);
+        assert_eq!(rows[4][1], "120 under /include/openssl/");
+        assert_eq!(rows[6], ["description", "?", "?"]);
+        assert_eq!(rows[7], ["in nixpkgs", "openssl_1_1.dev", "openssl_3.dev"]);
+    }
+}
@@ -0,0 +1,528 @@
+use log::{debug, trace, warn};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::nix::{self, CommandStore, ErrorKind, StoreBackend};
+
+const WORKER_MAGIC_1: u64 = 0x6e697863;
+const WORKER_MAGIC_2: u64 = 0x6478696f;
+/// 1.35, later versions negotiate features we do not need.
+const PROTOCOL_VERSION: u64 = 1 << 8 | 35;
+
+const STDERR_NEXT: u64 = 0x6f6c6d67;
+const STDERR_READ: u64 = 0x64617461;
+const STDERR_WRITE: u64 = 0x64617416;
+const STDERR_LAST: u64 = 0x616c7473;
+const STDERR_ERROR: u64 = 0x63787470;
+const STDERR_START_ACTIVITY: u64 = 0x53545254;
+const STDERR_STOP_ACTIVITY: u64 = 0x53544f50;
+const STDERR_RESULT: u64 = 0x52534c54;
+
+const OP_IS_VALID_PATH: u64 = 1;
+const OP_ENSURE_PATH: u64 = 10;
+const OP_QUERY_PATH_INFO: u64 = 26;
+
+/// Activity copying a store path, its progress is in bytes.
+const ACT_COPY_PATH: u64 = 100;
+const RES_PROGRESS: u64 = 105;
+
+const DEFAULT_SOCKET_PATH: &str = "/nix/var/nix/daemon-socket/socket";
+
+#[derive(thiserror::Error, Debug)]
+pub enum DaemonError {
+    #[error("failed to talk to the Nix daemon: {0}")]
+    Io(#[from] io::Error),
+    #[error("unexpected message from the Nix daemon: {0}")]
+    Protocol(String),
+    /// Reported by the daemon, e.g. a path which cannot be substituted
+    #[error("{0}")]
+    Nix(String),
+}
+
+/// What we use of the path info of a valid store path.
+#[derive(Debug, PartialEq)]
+pub struct PathInfo {
+    pub references: Vec<String>,
+    pub nar_size: u64,
+}
+
+fn write_u64(out: &mut Vec<u8>, n: u64) {
+    out.extend_from_slice(&n.to_le_bytes());
+}
+
+/// Strings are prefixed by their length and padded to 8 bytes.
+fn write_string(out: &mut Vec<u8>, s: &str) {
+    write_u64(out, s.len() as u64);
+    out.extend_from_slice(s.as_bytes());
+    out.resize(out.len() + (8 - s.len() % 8) % 8, 0);
+}
+
+/// A connection to the daemon speaking the worker protocol.
+pub struct Connection<R, W> {
+    reader: BufReader<R>,
+    writer: W,
+    /// Minor version of the protocol both sides speak
+    minor: u64,
+}
+
+impl<R: Read, W: Write> Connection<R, W> {
+    /// Handshake with the daemon, it is ready for operations then.
+    pub fn handshake(reader: R, writer: W) -> Result<Self, DaemonError> {
+        let mut conn = Connection {
+            reader: BufReader::new(reader),
+            writer,
+            minor: 0,
+        };
+        conn.send(|out| write_u64(out, WORKER_MAGIC_1))?;
+        if conn.read_u64()? != WORKER_MAGIC_2 {
+            return Err(DaemonError::Protocol("this is not a Nix daemon".into()));
+        }
+        let daemon_version = conn.read_u64()?;
+        if daemon_version >> 8 != 1 || daemon_version & 0xff < 10 {
+            return Err(DaemonError::Protocol(format!(
+                "unsupported protocol version {}.{}",
+                daemon_version >> 8,
+                daemon_version & 0xff
+            )));
+        }
+        conn.minor = (daemon_version & 0xff).min(PROTOCOL_VERSION & 0xff);
+
+        let minor = conn.minor;
+        conn.send(|out| {
+            write_u64(out, PROTOCOL_VERSION);
+            // No CPU affinity, no space to reserve.
+            if minor >= 14 {
+                write_u64(out, 0);
+            }
+            if minor >= 11 {
+                write_u64(out, 0);
+            }
+        })?;
+        if minor >= 33 {
+            let version = conn.read_string()?;
+            trace!("Nix daemon version {}", version);
+        }
+        if minor >= 35 {
+            // Whether we are trusted, it does not matter to us.
+            conn.read_u64()?;
+        }
+        conn.process_stderr()?;
+
+        Ok(conn)
+    }
+
+    fn send(&mut self, message: impl FnOnce(&mut Vec<u8>)) -> io::Result<()> {
+        let mut out = Vec::new();
+        message(&mut out);
+        self.writer.write_all(&out)?;
+        self.writer.flush()
+    }
+
+    fn read_u64(&mut self) -> io::Result<u64> {
+        let mut buf = [0; 8];
+        self.reader.read_exact(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn read_string(&mut self) -> io::Result<String> {
+        let len = self.read_u64()? as usize;
+        let mut buf = vec![0; len + (8 - len % 8) % 8];
+        self.reader.read_exact(&mut buf)?;
+        buf.truncate(len);
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    }
+
+    fn read_strings(&mut self) -> io::Result<Vec<String>> {
+        let count = self.read_u64()?;
+        (0..count).map(|_| self.read_string()).collect()
+    }
+
+    /// The integer fields of an activity or a result, the strings are skipped.
+    fn read_fields(&mut self) -> Result<Vec<u64>, DaemonError> {
+        let mut ints = Vec::new();
+        for _ in 0..self.read_u64()? {
+            match self.read_u64()? {
+                0 => ints.push(self.read_u64()?),
+                1 => {
+                    self.read_string()?;
+                }
+                other => {
+                    return Err(DaemonError::Protocol(format!(
+                        "unknown field type {}",
+                        other
+                    )))
+                }
+            }
+        }
+        Ok(ints)
+    }
+
+    fn process_stderr(&mut self) -> Result<(), DaemonError> {
+        self.process_stderr_with(&mut |_, _| {})
+    }
+
+    /// Read the logs the daemon sends before the result of an operation, or the error it fails with.
+    /// `on_progress` is told the bytes copied so far and expected when a store path is copied.
+    fn process_stderr_with(&mut self, on_progress: &mut dyn FnMut(u64, u64)) -> Result<(), DaemonError> {
+        // Activity -> its type
+        let mut activities = HashMap::new();
+        loop {
+            match self.read_u64()? {
+                STDERR_LAST => return Ok(()),
+                STDERR_NEXT | STDERR_WRITE => {
+                    // Read even if not logged.
+                    let line = self.read_string()?;
+                    trace!("nix daemon: {}", line.trim_end());
+                }
+                STDERR_START_ACTIVITY => {
+                    let activity = self.read_u64()?;
+                    // Level.
+                    self.read_u64()?;
+                    activities.insert(activity, self.read_u64()?);
+                    let text = self.read_string()?;
+                    self.read_fields()?;
+                    // Parent activity.
+                    self.read_u64()?;
+                    if !text.is_empty() {
+                        debug!("nix daemon: {}", text);
+                    }
+                }
+                STDERR_STOP_ACTIVITY => {
+                    self.read_u64()?;
+                }
+                STDERR_RESULT => {
+                    let (activity, result) = (self.read_u64()?, self.read_u64()?);
+                    let fields = self.read_fields()?;
+                    if let (RES_PROGRESS, Some(&ACT_COPY_PATH), [done, expected, ..]) =
+                        (result, activities.get(&activity), fields.as_slice())
+                    {
+                        on_progress(*done, *expected);
+                    }
+                }
+                STDERR_ERROR => return Err(self.read_error()?),
+                STDERR_READ => {
+                    return Err(DaemonError::Protocol("the daemon asked for data".into()))
+                }
+                other => {
+                    return Err(DaemonError::Protocol(format!(
+                        "unknown message {:#x}",
+                        other
+                    )))
+                }
+            }
+        }
+    }
+
+    fn read_error(&mut self) -> Result<DaemonError, DaemonError> {
+        if self.minor < 26 {
+            let message = self.read_string()?;
+            // Exit status.
+            self.read_u64()?;
+            return Ok(DaemonError::Nix(message));
+        }
+        // Type, level and name.
+        self.read_string()?;
+        self.read_u64()?;
+        self.read_string()?;
+        let message = self.read_string()?;
+        if self.read_u64()? != 0 {
+            return Err(DaemonError::Protocol(
+                "error positions are not supported".into(),
+            ));
+        }
+        for _ in 0..self.read_u64()? {
+            if self.read_u64()? != 0 {
+                return Err(DaemonError::Protocol(
+                    "error positions are not supported".into(),
+                ));
+            }
+            self.read_string()?;
+        }
+
+        Ok(DaemonError::Nix(message))
+    }
+
+    /// Send an operation on a store path, the daemon logs until its result comes.
+    fn operation(&mut self, op: u64, path: &str) -> Result<(), DaemonError> {
+        self.operation_with(op, path, &mut |_, _| {})
+    }
+
+    fn operation_with(
+        &mut self,
+        op: u64,
+        path: &str,
+        on_progress: &mut dyn FnMut(u64, u64),
+    ) -> Result<(), DaemonError> {
+        self.send(|out| {
+            write_u64(out, op);
+            write_string(out, path);
+        })?;
+        self.process_stderr_with(on_progress)
+    }
+
+    pub fn is_valid_path(&mut self, path: &str) -> Result<bool, DaemonError> {
+        self.operation(OP_IS_VALID_PATH, path)?;
+        Ok(self.read_u64()? != 0)
+    }
+
+    /// Substitute the path if it is not valid, telling the bytes copied so far and expected.
+    pub fn ensure_path(
+        &mut self,
+        path: &str,
+        on_progress: &mut dyn FnMut(u64, u64),
+    ) -> Result<(), DaemonError> {
+        self.operation_with(OP_ENSURE_PATH, path, on_progress)?;
+        self.read_u64()?;
+        Ok(())
+    }
+
+    /// `None` if the path is not valid.
+    pub fn query_path_info(&mut self, path: &str) -> Result<Option<PathInfo>, DaemonError> {
+        self.operation(OP_QUERY_PATH_INFO, path)?;
+        if self.minor >= 17 && self.read_u64()? == 0 {
+            return Ok(None);
+        }
+        // Deriver and NAR hash.
+        self.read_string()?;
+        self.read_string()?;
+        let references = self.read_strings()?;
+        // Registration time.
+        self.read_u64()?;
+        let nar_size = self.read_u64()?;
+        if self.minor >= 16 {
+            // Ultimate, signatures and content address.
+            self.read_u64()?;
+            self.read_strings()?;
+            self.read_string()?;
+        }
+
+        Ok(Some(PathInfo {
+            references,
+            nar_size,
+        }))
+    }
+
+    /// Sum of the NAR sizes of the path and everything it references, `None` if it is not valid.
+    pub fn closure_size(&mut self, path: &str) -> Result<Option<u64>, DaemonError> {
+        let mut seen = HashSet::from([path.to_string()]);
+        let mut queue = VecDeque::from([path.to_string()]);
+        let mut size = 0;
+        while let Some(path) = queue.pop_front() {
+            let info = match self.query_path_info(&path)? {
+                Some(info) => info,
+                None => return Ok(None),
+            };
+            size += info.nar_size;
+            for reference in info.references {
+                if seen.insert(reference.clone()) {
+                    queue.push_back(reference);
+                }
+            }
+        }
+
+        Ok(Some(size))
+    }
+}
+
+/// Talks to the store through the daemon socket, a connection per call as realizations can take long.
+/// The commands are run instead if the daemon cannot be talked to, e.g. on single user installations.
+pub struct DaemonStore {
+    socket_path: PathBuf,
+    /// Set once connecting failed, not to try every time
+    unreachable: AtomicBool,
+}
+
+/// Socket of a store URI the daemon protocol talks to, `daemon` or `unix://<socket>`, `None` for other stores.
+pub fn socket_of_store(uri: &str) -> Option<PathBuf> {
+    // Store settings such as `?trusted=1` do not change the socket.
+    let uri = uri.split_once('?').map_or(uri, |(uri, _)| uri);
+    match uri {
+        "daemon" | "unix://" => Some(
+            std::env::var_os("NIX_DAEMON_SOCKET_PATH")
+                .map_or_else(|| DEFAULT_SOCKET_PATH.into(), PathBuf::from),
+        ),
+        uri => uri.strip_prefix("unix://").map(PathBuf::from),
+    }
+}
+
+impl DaemonStore {
+    pub fn new() -> Self {
+        DaemonStore::at(socket_of_store("daemon").expect("The daemon has a socket"))
+    }
+
+    pub fn at(socket_path: PathBuf) -> Self {
+        DaemonStore {
+            socket_path,
+            unreachable: AtomicBool::new(false),
+        }
+    }
+
+    /// Run `operation` on a new connection, `None` if the daemon could not be talked to.
+    fn with_daemon<T>(
+        &self,
+        operation: impl FnOnce(&mut Connection<UnixStream, UnixStream>) -> Result<T, DaemonError>,
+    ) -> Option<Result<T, DaemonError>> {
+        if self.unreachable.load(Ordering::Relaxed) {
+            return None;
+        }
+        let connected = UnixStream::connect(&self.socket_path)
+            .map_err(DaemonError::from)
+            .and_then(|stream| Connection::handshake(stream.try_clone()?, stream));
+        let mut conn = match connected {
+            Ok(conn) => conn,
+            Err(err) => {
+                if !self.unreachable.swap(true, Ordering::Relaxed) {
+                    warn!(
+                        "Cannot use the Nix daemon at {}, running nix commands instead: {}",
+                        self.socket_path.display(),
+                        err
+                    );
+                }
+                return None;
+            }
+        };
+
+        match operation(&mut conn) {
+            Err(err @ (DaemonError::Io(_) | DaemonError::Protocol(_))) => {
+                debug!("{}, running nix commands instead", err);
+                None
+            }
+            result => Some(result),
+        }
+    }
+}
+
+impl StoreBackend for DaemonStore {
+    fn is_valid(&self, path: &str) -> nix::Result<bool> {
+        match self.with_daemon(|conn| conn.is_valid_path(path)) {
+            Some(result) => result.map_err(|err| ErrorKind::Daemon(err.to_string()).into()),
+            None => CommandStore::local().is_valid(path),
+        }
+    }
+
+    fn realize(&self, path: &str, on_progress: &mut dyn FnMut(u64, u64)) -> nix::Result<()> {
+        match self.with_daemon(|conn| conn.ensure_path(path, &mut *on_progress)) {
+            Some(result) => result.map_err(|err| ErrorKind::Daemon(err.to_string()).into()),
+            None => CommandStore::local().realize(path, on_progress),
+        }
+    }
+
+    fn closure_size(&self, path: &str) -> Option<usize> {
+        match self.with_daemon(|conn| conn.closure_size(path)) {
+            Some(result) => result.ok().flatten().map(|size| size as usize),
+            None => CommandStore::local().closure_size(path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_socket_of_store() {
+        assert_eq!(
+            socket_of_store("unix:///run/nix/socket?trusted=1"),
+            Some(PathBuf::from("/run/nix/socket"))
+        );
+        assert!(socket_of_store("daemon").is_some());
+        assert_eq!(socket_of_store("ssh-ng://builder"), None);
+    }
+
+    /// What a newer daemon answers to the handshake, it speaks 1.35 with us.
+    fn handshake_reply(out: &mut Vec<u8>) {
+        write_u64(out, WORKER_MAGIC_2);
+        write_u64(out, 1 << 8 | 37);
+        write_string(out, "2.18.1");
+        write_u64(out, 1);
+        write_u64(out, STDERR_LAST);
+    }
+
+    #[test]
+    fn test_path_info_and_errors_are_read() {
+        let mut daemon = Vec::new();
+        handshake_reply(&mut daemon);
+        // The path info of zlib, after some logs.
+        write_u64(&mut daemon, STDERR_NEXT);
+        write_string(&mut daemon, "querying info");
+        write_u64(&mut daemon, STDERR_START_ACTIVITY);
+        for n in [1, 3, 100] {
+            write_u64(&mut daemon, n);
+        }
+        write_string(&mut daemon, "copying path");
+        write_u64(&mut daemon, 1);
+        write_u64(&mut daemon, 1);
+        write_string(&mut daemon, "field");
+        write_u64(&mut daemon, 0);
+        write_u64(&mut daemon, STDERR_STOP_ACTIVITY);
+        write_u64(&mut daemon, 1);
+        write_u64(&mut daemon, STDERR_LAST);
+        write_u64(&mut daemon, 1);
+        write_string(&mut daemon, "");
+        write_string(&mut daemon, "sha256:abc");
+        write_u64(&mut daemon, 2);
+        write_string(&mut daemon, "/nix/store/aaa-glibc");
+        write_string(&mut daemon, "/nix/store/bbb-zlib");
+        write_u64(&mut daemon, 1700000000);
+        write_u64(&mut daemon, 4096);
+        write_u64(&mut daemon, 0);
+        write_u64(&mut daemon, 0);
+        write_string(&mut daemon, "");
+        // An invalid path.
+        write_u64(&mut daemon, STDERR_LAST);
+        write_u64(&mut daemon, 0);
+        // A substitution failing halfway.
+        write_u64(&mut daemon, STDERR_START_ACTIVITY);
+        for n in [2, 3, ACT_COPY_PATH] {
+            write_u64(&mut daemon, n);
+        }
+        write_string(&mut daemon, "copying path");
+        write_u64(&mut daemon, 0);
+        write_u64(&mut daemon, 0);
+        write_u64(&mut daemon, STDERR_RESULT);
+        write_u64(&mut daemon, 2);
+        write_u64(&mut daemon, RES_PROGRESS);
+        write_u64(&mut daemon, 4);
+        for n in [1024, 4096, 1, 0] {
+            write_u64(&mut daemon, 0);
+            write_u64(&mut daemon, n);
+        }
+        write_u64(&mut daemon, STDERR_ERROR);
+        write_string(&mut daemon, "Error");
+        write_u64(&mut daemon, 0);
+        write_string(&mut daemon, "Error");
+        write_string(&mut daemon, "path is not valid");
+        write_u64(&mut daemon, 0);
+        write_u64(&mut daemon, 0);
+
+        let mut conn = Connection::handshake(Cursor::new(daemon), Vec::new()).unwrap();
+        assert_eq!(conn.minor, 35);
+        assert_eq!(
+            conn.query_path_info("/nix/store/bbb-zlib").unwrap(),
+            Some(PathInfo {
+                references: vec!["/nix/store/aaa-glibc".into(), "/nix/store/bbb-zlib".into()],
+                nar_size: 4096,
+            })
+        );
+        assert_eq!(conn.query_path_info("/nix/store/ccc-gone").unwrap(), None);
+        let mut progress = Vec::new();
+        assert!(matches!(
+            conn.ensure_path("/nix/store/ccc-gone", &mut |done, expected| progress.push((done, expected))),
+            Err(DaemonError::Nix(message)) if message == "path is not valid"
+        ));
+        assert_eq!(progress, [(1024, 4096)]);
+
+        let mut sent = Vec::new();
+        write_u64(&mut sent, WORKER_MAGIC_1);
+        write_u64(&mut sent, PROTOCOL_VERSION);
+        write_u64(&mut sent, 0);
+        write_u64(&mut sent, 0);
+        write_u64(&mut sent, OP_QUERY_PATH_INFO);
+        write_string(&mut sent, "/nix/store/bbb-zlib");
+        assert_eq!(conn.writer[..sent.len()], sent[..]);
+    }
+}
@@ -0,0 +1,276 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::resolution::{Decision, Resolution, ResolutionDB};
+
+/// A difference between two resolution databases, for one requested path and phase.
+/// Decisions are described as in `res list`.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+#[serde(tag = "change", rename_all = "kebab-case")]
+pub enum Change {
+    Added {
+        requested_path: String,
+        phase: Option<String>,
+        decision: String,
+    },
+    Removed {
+        requested_path: String,
+        phase: Option<String>,
+        decision: String,
+    },
+    /// Another decision, or another package
+    Decision {
+        requested_path: String,
+        phase: Option<String>,
+        before: String,
+        after: String,
+    },
+    /// The same attribute and output in another version
+    Version {
+        requested_path: String,
+        phase: Option<String>,
+        installable: String,
+        before: String,
+        after: String,
+    },
+    /// The same attribute, output and version with another store path, e.g. after a nixpkgs update
+    Rebuild {
+        requested_path: String,
+        phase: Option<String>,
+        installable: String,
+        before: String,
+        after: String,
+    },
+}
+
+/// `requested_path`, or `requested_path in phase build`.
+fn location(requested_path: &str, phase: &Option<String>) -> String {
+    match phase {
+        Some(phase) => format!("{} in phase {}", requested_path, phase),
+        None => requested_path.to_string(),
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Added {
+                requested_path,
+                phase,
+                decision,
+            } => write!(f, "+ {}: {}", location(requested_path, phase), decision),
+            Self::Removed {
+                requested_path,
+                phase,
+                decision,
+            } => write!(f, "- {}: {}", location(requested_path, phase), decision),
+            Self::Decision {
+                requested_path,
+                phase,
+                before,
+                after,
+            } => write!(
+                f,
+                "~ {}: {} -> {}",
+                location(requested_path, phase),
+                before,
+                after
+            ),
+            Self::Version {
+                requested_path,
+                phase,
+                installable,
+                before,
+                after,
+            } => write!(
+                f,
+                "~ {}: {} {} -> {}",
+                location(requested_path, phase),
+                installable,
+                before,
+                after
+            ),
+            Self::Rebuild {
+                requested_path,
+                phase,
+                installable,
+                before,
+                after,
+            } => write!(
+                f,
+                "~ {}: {} rebuilt, {} -> {}",
+                location(requested_path, phase),
+                installable,
+                before,
+                after
+            ),
+        }
+    }
+}
+
+/// Version of a store path name, following `builtins.parseDrvName`:
+/// it starts after the first dash not followed by a letter.
+fn version_of(name: &str) -> &str {
+    name.match_indices('-')
+        .find(|(index, _)| {
+            name[index + 1..]
+                .chars()
+                .next()
+                .map_or(false, |c| !c.is_ascii_alphabetic())
+        })
+        .map_or("", |(index, _)| &name[index + 1..])
+}
+
+fn decisions_by_phase(resolution: &Resolution) -> BTreeMap<Option<String>, &Decision> {
+    resolution
+        .decisions()
+        .into_iter()
+        .map(|(phase, decision)| (phase.map(String::from), decision))
+        .collect()
+}
+
+fn compare(
+    requested_path: &str,
+    phase: Option<String>,
+    before: &Decision,
+    after: &Decision,
+) -> Option<Change> {
+    if before == after {
+        return None;
+    }
+    let requested_path = requested_path.to_string();
+
+    if let (Decision::Provide(before), Decision::Provide(after)) = (before, after) {
+        let installable = before.installable();
+        let same_package = installable == after.installable()
+            && before.file_entry_name == after.file_entry_name
+            && before.kind == after.kind
+            && before.link_mode == after.link_mode;
+        if same_package {
+            let (before_name, after_name) = (before.store_path.name(), after.store_path.name());
+            if before.store_path.hash() == after.store_path.hash() {
+                // Only the origin metadata changed, e.g. the system.
+                return None;
+            }
+            return Some(if before_name == after_name {
+                Change::Rebuild {
+                    requested_path,
+                    phase,
+                    installable,
+                    before: before.store_path.as_str().to_string(),
+                    after: after.store_path.as_str().to_string(),
+                }
+            } else {
+                Change::Version {
+                    requested_path,
+                    phase,
+                    installable,
+                    before: version_of(&before_name).to_string(),
+                    after: version_of(&after_name).to_string(),
+                }
+            });
+        }
+    }
+
+    Some(Change::Decision {
+        requested_path,
+        phase,
+        before: before.to_string(),
+        after: after.to_string(),
+    })
+}
+
+/// Changes from `before` to `after`, by requested path then phase.
+/// Only decisions are compared, not their priority nor provenance.
+pub fn diff(before: &ResolutionDB, after: &ResolutionDB) -> Vec<Change> {
+    let requested_paths: BTreeSet<&String> = before.keys().chain(after.keys()).collect();
+    let mut changes = Vec::new();
+
+    for requested_path in requested_paths {
+        let before = before
+            .get(requested_path)
+            .map(decisions_by_phase)
+            .unwrap_or_default();
+        let after = after
+            .get(requested_path)
+            .map(decisions_by_phase)
+            .unwrap_or_default();
+        let phases: BTreeSet<&Option<String>> = before.keys().chain(after.keys()).collect();
+
+        for phase in phases {
+            let change = match (before.get(phase), after.get(phase)) {
+                (Some(before), Some(after)) => {
+                    compare(requested_path, phase.clone(), before, after)
+                }
+                (Some(before), None) => Some(Change::Removed {
+                    requested_path: requested_path.clone(),
+                    phase: phase.clone(),
+                    decision: before.to_string(),
+                }),
+                (None, Some(after)) => Some(Change::Added {
+                    requested_path: requested_path.clone(),
+                    phase: phase.clone(),
+                    decision: after.to_string(),
+                }),
+                (None, None) => None,
+            };
+            changes.extend(change);
+        }
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resolution::read_resolution_db;
+
+    fn provide_zlib(hash: &str, name: &str) -> ResolutionDB {
+        read_resolution_db(&format!(
+            r#"
+["include/zlib.h"]
+resolution = "constant"
+decision = "provide"
+file_entry_name = "/include/zlib.h"
+kind = "regular-file"
+
+["include/zlib.h".store_path]
+hash = "{}"
+name = "{}"
+store_dir = "/nix/store"
+
+["include/zlib.h".store_path.origin]
+attr = "zlib"
+output = "dev"
+toplevel = true
+
+["bin/yacc"]
+resolution = "constant"
+decision = "ignore"
+"#,
+            hash, name
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_version_only_changes_are_told_apart() {
+        let before = provide_zlib("0znwn1lw0268i7pj0rylhz9dnzbwijjn", "zlib-1.2.13-dev");
+        let after = provide_zlib("1znwn1lw0268i7pj0rylhz9dnzbwijjn", "zlib-1.3-dev");
+
+        assert_eq!(diff(&before, &before), vec![]);
+        assert_eq!(
+            diff(&before, &after),
+            vec![Change::Version {
+                requested_path: "include/zlib.h".into(),
+                phase: None,
+                installable: "zlib.dev".into(),
+                before: "1.2.13-dev".into(),
+                after: "1.3-dev".into(),
+            }]
+        );
+        assert_eq!(version_of("gtk+3-3.24.36-dev"), "3.24.36-dev");
+    }
+}
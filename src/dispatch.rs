@@ -0,0 +1,334 @@
+use crossbeam_channel::{unbounded, Sender as WorkSender};
+use fuser::Filesystem;
+use log::debug;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+use crate::fs::{BuildXYZ, FsEventMessage, LinkTarget, LookupReply, PendingLookup, RequestInfo};
+
+/// Routes the UI answers to the lookup waiting for them.
+#[derive(Clone, Default)]
+pub struct AnswerRouter {
+    /// request id -> waiting lookup
+    waiting: Arc<Mutex<HashMap<u64, Sender<FsEventMessage>>>>,
+    /// Filesystems undoing decisions, they only know theirs
+    undoing: Arc<Mutex<Vec<Sender<u64>>>>,
+    /// Filesystems following the build phase
+    phases: Arc<Mutex<Vec<Sender<Option<String>>>>>,
+}
+
+impl AnswerRouter {
+    /// Route all events received on `recv_fs_event` until the UI goes away.
+    pub fn spawn(recv_fs_event: Receiver<FsEventMessage>) -> Self {
+        let router = AnswerRouter::default();
+        let waiting = router.waiting.clone();
+        let undoing = router.undoing.clone();
+        let phases = router.phases.clone();
+
+        thread::spawn(move || {
+            for event in recv_fs_event {
+                let waiting = waiting.lock().unwrap();
+                match &event {
+                    FsEventMessage::PackageSuggestion(id, _)
+                    | FsEventMessage::SubtreeSuggestion(id, _)
+                    | FsEventMessage::IgnoreRequest(id)
+                    | FsEventMessage::SkipForNow(id)
+                    | FsEventMessage::Confirm(id)
+                    | FsEventMessage::ManualSearch(id, _)
+                    | FsEventMessage::Explain(id) => {
+                        match waiting.get(id) {
+                            Some(sender) => {
+                                let _ = sender.send(event.clone());
+                            }
+                            None => debug!("discarding an answer to request {}, nobody waits for it", id),
+                        }
+                    }
+                    FsEventMessage::Undo(id) => {
+                        for sender in undoing.lock().unwrap().iter() {
+                            let _ = sender.send(*id);
+                        }
+                    }
+                    FsEventMessage::SetPhase(phase) => {
+                        for sender in phases.lock().unwrap().iter() {
+                            let _ = sender.send(phase.clone());
+                        }
+                    }
+                    FsEventMessage::IgnorePendingRequests => {
+                        for sender in waiting.values() {
+                            let _ = sender.send(event.clone());
+                        }
+                    }
+                }
+            }
+            // Dropping the senders disconnects all waiting lookups.
+            waiting.lock().unwrap().clear();
+        });
+
+        router
+    }
+
+    /// Receiver of the answers to this request, until it is unregistered.
+    pub fn register(&self, request_id: u64) -> Receiver<FsEventMessage> {
+        let (send, recv) = channel();
+        self.waiting.lock().unwrap().insert(request_id, send);
+
+        recv
+    }
+
+    pub fn unregister(&self, request_id: u64) {
+        self.waiting.lock().unwrap().remove(&request_id);
+    }
+
+    /// Whether a lookup waits for the answers to this request, answers sent before are discarded.
+    #[cfg(test)]
+    pub fn is_waiting(&self, request_id: u64) -> bool {
+        self.waiting.lock().unwrap().contains_key(&request_id)
+    }
+
+    /// Receiver of the decisions to undo, for a filesystem.
+    pub fn undo_requests(&self) -> Receiver<u64> {
+        let (send, recv) = channel();
+        self.undoing.lock().unwrap().push(send);
+
+        recv
+    }
+
+    /// Receiver of the build phases to switch to, for a filesystem.
+    pub fn phase_requests(&self) -> Receiver<Option<String>> {
+        let (send, recv) = channel();
+        self.phases.lock().unwrap().push(send);
+
+        recv
+    }
+}
+
+/// What the dispatch loop hands over to the workers.
+enum Work {
+    Lookup(Box<PendingLookup>),
+    /// The store path a symlink points to is realized again before answering
+    Readlink(Vec<u8>, fuser::ReplyData),
+}
+
+/// Serves a filesystem from the FUSE dispatch loop while index searches,
+/// realizations and user prompts run on a pool of workers.
+///
+/// The dispatch loop only holds the filesystem for the fast paths,
+/// so that one lookup waiting for the user or a realization does not stall all others.
+/// Workers only hold it to install what they found and reply.
+pub struct Dispatcher {
+    fs: Arc<Mutex<BuildXYZ>>,
+    send_work: WorkSender<Work>,
+}
+
+impl Dispatcher {
+    pub fn new(fs: BuildXYZ, workers: usize) -> Self {
+        let search = fs.search_handles();
+        let fs = Arc::new(Mutex::new(fs));
+        let (send_work, recv_work) = unbounded::<Work>();
+
+        for _ in 0..workers.max(1) {
+            let fs = fs.clone();
+            let search = search.clone();
+            let recv_work = recv_work.clone();
+            thread::spawn(move || {
+                for work in recv_work {
+                    match work {
+                        Work::Lookup(pending) => {
+                            // A completed lookup may come back to be searched again, e.g. once confirmed,
+                            // with the lookups of the same path that waited for it.
+                            let mut pending = vec![*pending];
+                            while let Some(searched) = pending.pop() {
+                                let outcome = search.search(&searched);
+                                pending.extend(fs.lock().unwrap().complete_lookup(searched, outcome));
+                            }
+                        }
+                        Work::Readlink(nix_path, reply) => match search.realized_link_target(&nix_path) {
+                            Ok(target) => reply.data(target.as_bytes()),
+                            Err(err) => reply.error(err),
+                        },
+                    }
+                }
+            });
+        }
+
+        Dispatcher { fs, send_work }
+    }
+
+    fn send(&self, work: Work) {
+        self.send_work
+            .send(work)
+            .expect("Workers should outlive the dispatch loop");
+    }
+
+    /// Answer this lookup from the fast paths, or hand it over to a worker.
+    fn dispatch_lookup(&self, req: RequestInfo, parent: u64, name: &OsStr, reply: LookupReply) {
+        let pending = self.lock().lookup_fast(req, parent, name, reply);
+        if let Some(pending) = pending {
+            self.send(Work::Lookup(Box::new(pending)));
+        }
+    }
+
+    /// The filesystem, to reach it once it is unmounted.
+    pub fn fs(&self) -> Arc<Mutex<BuildXYZ>> {
+        self.fs.clone()
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, BuildXYZ> {
+        self.fs.lock().unwrap()
+    }
+}
+
+impl Filesystem for Dispatcher {
+    fn init(
+        &mut self,
+        req: &fuser::Request<'_>,
+        config: &mut fuser::KernelConfig,
+    ) -> Result<(), i32> {
+        self.lock().init(req, config)
+    }
+
+    fn destroy(&mut self) {
+        self.lock().destroy()
+    }
+
+    fn lookup(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        reply: fuser::ReplyEntry,
+    ) {
+        self.dispatch_lookup(req.into(), parent, name, reply.into())
+    }
+
+    fn forget(&mut self, req: &fuser::Request<'_>, ino: u64, nlookup: u64) {
+        self.lock().forget(req, ino, nlookup)
+    }
+
+    fn readdir(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        reply: fuser::ReplyDirectory,
+    ) {
+        self.lock().readdir(req, ino, fh, offset, reply)
+    }
+
+    fn statfs(&mut self, req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyStatfs) {
+        self.lock().statfs(req, ino, reply)
+    }
+
+    fn getxattr(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: fuser::ReplyXattr,
+    ) {
+        self.lock().getxattr(req, ino, name, size, reply)
+    }
+
+    fn listxattr(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        size: u32,
+        reply: fuser::ReplyXattr,
+    ) {
+        self.lock().listxattr(req, ino, size, reply)
+    }
+
+    fn readlink(&mut self, _req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyData) {
+        let target = self.lock().link_target(ino);
+        match target {
+            Some(LinkTarget::Store(nix_path)) => self.send(Work::Readlink(nix_path, reply)),
+            Some(LinkTarget::Host(redirection_path)) => reply.data(&redirection_path),
+            None => reply.error(nix::errno::Errno::ENOENT as i32),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{PathOrigin, StorePath};
+    use crate::realize::{Realizer, RetryPolicy};
+    use crate::resolution::{insert_resolution, Decision, ProvideData, ResolutionData};
+    use fuser::FileType;
+    use std::time::Duration;
+
+    fn request(unique: u64) -> RequestInfo {
+        RequestInfo {
+            unique,
+            pid: std::process::id(),
+            uid: 0,
+            gid: 0,
+        }
+    }
+
+    #[test]
+    fn test_lookups_are_answered_while_a_realization_stalls() {
+        let (release, stalled) = channel::<()>();
+        let stalled = Mutex::new(stalled);
+        let realizer = Realizer::new(1, RetryPolicy::default(), None).with_realize(move |_, _| {
+            let _ = stalled.lock().unwrap().recv();
+            Ok(())
+        });
+        let fast_working_tree = tempfile::tempdir().unwrap();
+        std::fs::create_dir(fast_working_tree.path().join("include")).unwrap();
+        std::fs::write(fast_working_tree.path().join("include/zlib.h"), "").unwrap();
+        let mut fs = BuildXYZ {
+            fast_working_tree: fast_working_tree.path().to_owned(),
+            realizer,
+            ..Default::default()
+        };
+        let origin = PathOrigin {
+            attr: "hello".into(),
+            output: "out".into(),
+            toplevel: true,
+            system: None,
+            index: None,
+        };
+        let data = ResolutionData {
+            requested_path: "bin/hello".into(),
+            decision: Decision::Provide(Box::new(ProvideData {
+                kind: FileType::Symlink,
+                file_entry_name: "/bin/hello".into(),
+                store_path: StorePath::parse(origin, "/nix/store/0c4d9ykb8ya8d2ysx6x7i5hmd3j9anv7-hello-2.12")
+                    .unwrap(),
+                link_mode: None,
+                flake: None,
+            })),
+            requested_by: None,
+            priority: 0,
+            source: None,
+            provenance: Default::default(),
+            reconfirm: false,
+        };
+        insert_resolution(&mut fs.resolution_db, data, None);
+        fs.mkdir_fhs_directories();
+        let (bin, include) = (fs.inodes.get("bin").unwrap(), fs.inodes.get("include").unwrap());
+        let dispatcher = Dispatcher::new(fs, 2);
+
+        let (send_hello, recv_hello) = channel();
+        dispatcher.dispatch_lookup(request(1), bin, OsStr::new("hello"), LookupReply::Test(send_hello));
+        let (send_zlib, recv_zlib) = channel();
+        dispatcher.dispatch_lookup(request(2), include, OsStr::new("zlib.h"), LookupReply::Test(send_zlib));
+        let (_, zlib) = recv_zlib.recv_timeout(Duration::from_secs(5)).unwrap().unwrap();
+        assert_eq!(zlib.kind, FileType::Symlink);
+        // The filesystem is not held during the realization either.
+        assert!(dispatcher.lock().inodes.get("bin/hello").is_none());
+        assert!(recv_hello.try_recv().is_err());
+
+        release.send(()).unwrap();
+        let (_, hello) = recv_hello.recv_timeout(Duration::from_secs(5)).unwrap().unwrap();
+        assert_eq!(hello.kind, FileType::Symlink);
+        assert_eq!(dispatcher.lock().inodes.get("bin/hello"), Some(hello.ino));
+    }
+}
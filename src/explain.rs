@@ -0,0 +1,156 @@
+use lru::LruCache;
+use std::collections::VecDeque;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+
+use crate::process::{read_cmdline, ProcessInfo};
+use crate::trace::AccessOutcome;
+
+/// Lookups remembered per process.
+const LOOKUPS_PER_PROCESS: usize = 8;
+/// Processes remembered, builds spawn many short lived ones.
+const PROCESSES: usize = 256;
+
+/// The latest lookups of each process, to tell what it was doing when it requested a path.
+pub struct RecentLookups(LruCache<u32, VecDeque<(PathBuf, AccessOutcome)>>);
+
+impl Default for RecentLookups {
+    fn default() -> Self {
+        RecentLookups(LruCache::new(NonZeroUsize::new(PROCESSES).unwrap()))
+    }
+}
+
+impl RecentLookups {
+    pub fn record(&mut self, pid: u32, path: PathBuf, outcome: AccessOutcome) {
+        let lookups = self.0.get_or_insert_mut(pid, VecDeque::new);
+        if lookups.len() == LOOKUPS_PER_PROCESS {
+            lookups.pop_front();
+        }
+        lookups.push_back((path, outcome));
+    }
+
+    /// Lookups of this process, oldest first.
+    pub fn of(&self, pid: u32) -> Vec<(PathBuf, AccessOutcome)> {
+        self.0
+            .peek(&pid)
+            .map_or(Vec::new(), |lookups| lookups.iter().cloned().collect())
+    }
+}
+
+/// Why a path is requested, asked from its prompt.
+#[derive(Clone, Debug)]
+pub struct Explanation {
+    pub requested_path: PathBuf,
+    pub requester: ProcessInfo,
+    /// `None` if the process already exited
+    pub cmdline: Option<Vec<String>>,
+    /// The FUSE request as the kernel sent it
+    pub operation: String,
+    /// Other lookups of the process, oldest first
+    pub recent_lookups: Vec<(PathBuf, AccessOutcome)>,
+}
+
+impl Explanation {
+    pub fn new(
+        requested_path: PathBuf,
+        requester: ProcessInfo,
+        operation: String,
+        recent_lookups: Vec<(PathBuf, AccessOutcome)>,
+    ) -> Self {
+        Explanation {
+            cmdline: read_cmdline(requester.pid),
+            requested_path,
+            requester,
+            operation,
+            recent_lookups,
+        }
+    }
+
+    /// What the frontends show, line by line.
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![
+            format!(
+                "{} was requested by {}",
+                self.requested_path.display(),
+                self.requester
+            ),
+            format!(
+                "command line: {}",
+                self.cmdline
+                    .as_ref()
+                    .map_or("<exited>".to_string(), |cmdline| cmdline.join(" "))
+            ),
+            format!(
+                "executable: {}",
+                self.requester
+                    .exe
+                    .as_ref()
+                    .map_or("<unknown>".to_string(), |exe| exe.display().to_string())
+            ),
+            format!("operation: {}", self.operation),
+        ];
+        if self.recent_lookups.is_empty() {
+            lines.push("no other lookup by this process".to_string());
+        } else {
+            lines.push("its latest lookups:".to_string());
+            lines.extend(
+                self.recent_lookups
+                    .iter()
+                    .map(|(path, outcome)| format!("  {} ({})", path.display(), outcome)),
+            );
+        }
+
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recent_lookups_are_bounded_per_process() {
+        let mut recent = RecentLookups::default();
+        for index in 0..LOOKUPS_PER_PROCESS + 2 {
+            recent.record(
+                1,
+                PathBuf::from(format!("lib/lib{}.so", index)),
+                AccessOutcome::NotFound,
+            );
+        }
+        recent.record(2, PathBuf::from("bin/sh"), AccessOutcome::Provided);
+
+        let lookups = recent.of(1);
+        assert_eq!(lookups.len(), LOOKUPS_PER_PROCESS);
+        assert_eq!(lookups[0].0, PathBuf::from("lib/lib2.so"));
+        assert_eq!(
+            recent.of(2),
+            vec![(PathBuf::from("bin/sh"), AccessOutcome::Provided)]
+        );
+        assert!(recent.of(3).is_empty());
+
+        let explanation = Explanation {
+            requested_path: PathBuf::from("include/zlib.h"),
+            requester: ProcessInfo {
+                pid: 2,
+                comm: Some("cc".to_string()),
+                exe: None,
+                cwd: None,
+            },
+            cmdline: Some(vec!["cc".into(), "-c".into(), "foo.c".into()]),
+            operation: "LOOKUP parent 1, name \"zlib.h\"".to_string(),
+            recent_lookups: recent.of(2),
+        };
+        assert_eq!(
+            explanation.lines(),
+            [
+                "include/zlib.h was requested by cc (pid 2)",
+                "command line: cc -c foo.c",
+                "executable: <unknown>",
+                "operation: LOOKUP parent 1, name \"zlib.h\"",
+                "its latest lookups:",
+                "  bin/sh (provided)",
+            ]
+        );
+    }
+}
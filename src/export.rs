@@ -0,0 +1,409 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::fmt::Write;
+
+use crate::cache::StorePath;
+use crate::resolution::{Decision, ResolutionDB};
+
+/// Paths only needed on the build machine, e.g. tools and their autoconf macros.
+const NATIVE_PREFIXES: [&str; 4] = ["bin/", "sbin/", "libexec/", "share/aclocal/"];
+
+/// nixpkgs attributes of the provided store paths, by kind of input.
+#[derive(Default, Debug, PartialEq, Eq)]
+pub struct Inputs {
+    pub native_build_inputs: BTreeSet<String>,
+    pub build_inputs: BTreeSet<String>,
+}
+
+/// Every provided store path of the database, whatever the phase, by the attribute the index recorded.
+pub fn collect_inputs(db: &ResolutionDB) -> Inputs {
+    collect_inputs_with(db, |store_path| store_path.origin().attr.clone())
+}
+
+/// Like `collect_inputs`, with the attribute of each store path told by `attr_of`, e.g. `attrpath::AttrPaths`.
+pub fn collect_inputs_with(db: &ResolutionDB, attr_of: impl FnMut(&StorePath) -> String) -> Inputs {
+    collect_inputs_classified(db, attr_of, |_, requested_path| is_native_path(requested_path))
+}
+
+/// Whether a provided path is only needed on the build machine, from the path alone.
+pub fn is_native_path(requested_path: &str) -> bool {
+    NATIVE_PREFIXES
+        .iter()
+        .any(|prefix| requested_path.starts_with(prefix))
+}
+
+/// Like `collect_inputs_with`, with the kind of input told by `is_native` from the store path and the requested path.
+pub fn collect_inputs_classified(
+    db: &ResolutionDB,
+    mut attr_of: impl FnMut(&StorePath) -> String,
+    mut is_native: impl FnMut(&StorePath, &str) -> bool,
+) -> Inputs {
+    let mut inputs = Inputs::default();
+
+    for resolution in db.values() {
+        for data in resolution.all_data() {
+            if let Decision::Provide(provide_data) = &data.decision {
+                let attr = attr_of(&provide_data.store_path);
+                if is_native(&provide_data.store_path, &data.requested_path) {
+                    inputs.native_build_inputs.insert(attr);
+                } else {
+                    inputs.build_inputs.insert(attr);
+                }
+            }
+        }
+    }
+
+    inputs
+}
+
+/// Command the resolutions were recorded while building, the most frequent one if several.
+pub fn recorded_command(db: &ResolutionDB) -> Option<String> {
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for data in db.values().flat_map(|resolution| resolution.all_data()) {
+        if let Some(command) = &data.provenance.command {
+            *counts.entry(command.as_str()).or_default() += 1;
+        }
+    }
+
+    counts
+        .into_iter()
+        .max_by_key(|(_, count)| *count)
+        .map(|(command, _)| command.to_string())
+}
+
+/// Whether an attribute name can be written without quotes, e.g. `gtk3` but not `gtk+3`.
+pub fn is_nix_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .map_or(false, |c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || "_'-".contains(c))
+}
+
+/// `python3Packages.cffi` as is, attributes which are not identifiers are quoted,
+/// e.g. `pkgs."gtk+3"`.
+pub fn nix_attr_path(attr: &str) -> String {
+    if attr.split('.').all(is_nix_identifier) {
+        return attr.to_string();
+    }
+
+    let mut path = String::from("pkgs");
+    for name in attr.split('.') {
+        path.push('.');
+        if is_nix_identifier(name) {
+            path.push_str(name);
+        } else {
+            path.push_str(&format!("{:?}", name));
+        }
+    }
+
+    path
+}
+
+/// A Nix list of attributes, one per line, indented by `indent` spaces.
+pub fn render_list(attrs: &BTreeSet<String>, indent: usize) -> String {
+    if attrs.is_empty() {
+        return "[ ]".into();
+    }
+
+    let mut list = String::from("[\n");
+    for attr in attrs {
+        let _ = writeln!(list, "{:indent$}  {}", "", nix_attr_path(attr), indent = indent);
+    }
+    let _ = write!(list, "{:indent$}]", "", indent = indent);
+
+    list
+}
+
+/// `nativeBuildInputs` and `buildInputs` ready to paste in a derivation using `with pkgs;`.
+pub fn render_nix_inputs(inputs: &Inputs) -> String {
+    format!(
+        "nativeBuildInputs = {};\nbuildInputs = {};\n",
+        render_list(&inputs.native_build_inputs, 0),
+        render_list(&inputs.build_inputs, 0)
+    )
+}
+
+/// `mkShell` with the inputs, the body indented by `indent` spaces.
+fn render_mk_shell(inputs: &Inputs, indent: usize) -> String {
+    format!(
+        "pkgs.mkShell {{\n{pad}  nativeBuildInputs = with pkgs; {};\n{pad}  buildInputs = with pkgs; {};\n{pad}}}",
+        render_list(&inputs.native_build_inputs, indent + 2),
+        render_list(&inputs.build_inputs, indent + 2),
+        pad = " ".repeat(indent)
+    )
+}
+
+/// A `shell.nix` importing nixpkgs at `nixpkgs_rev`, or `<nixpkgs>` when unknown.
+pub fn render_shell_nix(inputs: &Inputs, nixpkgs_rev: Option<&str>) -> String {
+    let nixpkgs = match nixpkgs_rev {
+        Some(rev) => format!(
+            "(fetchTarball \"https://github.com/NixOS/nixpkgs/archive/{}.tar.gz\")",
+            rev
+        ),
+        None => "<nixpkgs>".into(),
+    };
+
+    format!(
+        "{{ pkgs ? import {} {{ }} }}:\n\n{}\n",
+        nixpkgs,
+        render_mk_shell(inputs, 0)
+    )
+}
+
+/// How a project is built, guessed from the files at the root of its source tree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BuildSystem {
+    /// `configure` script, generated first by `autoreconfHook` if only `configure.ac` is there
+    Autotools { autoreconf: bool },
+    Cmake,
+    Cargo,
+    /// Anything else, the recorded command builds it
+    Unknown,
+}
+
+impl BuildSystem {
+    pub fn detect(source: &Path) -> Self {
+        let has = |name: &str| source.join(name).exists();
+        if has("Cargo.toml") {
+            Self::Cargo
+        } else if has("CMakeLists.txt") {
+            Self::Cmake
+        } else if has("configure") {
+            Self::Autotools { autoreconf: false }
+        } else if has("configure.ac") || has("configure.in") {
+            Self::Autotools { autoreconf: true }
+        } else {
+            Self::Unknown
+        }
+    }
+
+    /// Native build inputs setting up its phases in stdenv.
+    fn hooks(&self) -> &'static [&'static str] {
+        match self {
+            Self::Autotools { autoreconf: true } => &["autoreconfHook"],
+            Self::Autotools { autoreconf: false } | Self::Unknown => &[],
+            Self::Cmake => &["cmake"],
+            Self::Cargo => &["cargo", "rustPlatform.cargoSetupHook", "rustc"],
+        }
+    }
+}
+
+/// A draft `package.nix` building the project with `stdenv.mkDerivation` and the inputs,
+/// see `render_shell_nix` for `nixpkgs_rev`. `command` is the one the resolutions were recorded with,
+/// it becomes the build phase when the build system is unknown.
+pub fn render_derivation(
+    pname: &str,
+    build_system: BuildSystem,
+    inputs: &Inputs,
+    command: Option<&str>,
+    nixpkgs_rev: Option<&str>,
+) -> String {
+    let nixpkgs = match nixpkgs_rev {
+        Some(rev) => format!(
+            "(fetchTarball \"https://github.com/NixOS/nixpkgs/archive/{}.tar.gz\")",
+            rev
+        ),
+        None => "<nixpkgs>".into(),
+    };
+    let mut native_build_inputs = inputs.native_build_inputs.clone();
+    native_build_inputs.extend(build_system.hooks().iter().map(|hook| hook.to_string()));
+
+    let mut body = String::new();
+    let _ = writeln!(body, "  pname = {:?};", pname);
+    let _ = writeln!(body, "  version = \"unstable\";\n");
+    let _ = writeln!(body, "  src = ./.;\n");
+    if build_system == BuildSystem::Cargo {
+        let _ = writeln!(
+            body,
+            "  cargoDeps = pkgs.rustPlatform.importCargoLock {{\n    lockFile = ./Cargo.lock;\n  }};\n"
+        );
+    }
+    let _ = writeln!(
+        body,
+        "  nativeBuildInputs = with pkgs; {};",
+        render_list(&native_build_inputs, 2)
+    );
+    let _ = writeln!(
+        body,
+        "  buildInputs = with pkgs; {};\n",
+        render_list(&inputs.build_inputs, 2)
+    );
+    match (build_system, command) {
+        (BuildSystem::Unknown, Some(command)) => {
+            let _ = writeln!(
+                body,
+                "  buildPhase = ''\n    runHook preBuild\n    {}\n    runHook postBuild\n  '';\n",
+                command
+            );
+        }
+        (_, Some(command)) => {
+            let _ = writeln!(body, "  # Recorded while running `{}`", command);
+        }
+        (_, None) => {}
+    }
+    let _ = writeln!(body, "  meta = {{\n    description = \"TODO\";\n  }};");
+
+    format!(
+        "{{ pkgs ? import {} {{ }} }}:\n\npkgs.stdenv.mkDerivation {{\n{}}}\n",
+        nixpkgs, body
+    )
+}
+
+/// A `flake.nix` exposing the inputs as the default `devShell` of every system,
+/// with nixpkgs locked at `nixpkgs_rev` or following the default branch when unknown.
+pub fn render_flake(inputs: &Inputs, nixpkgs_rev: Option<&str>) -> String {
+    let nixpkgs_url = match nixpkgs_rev {
+        Some(rev) => format!("github:NixOS/nixpkgs/{}", rev),
+        None => "github:NixOS/nixpkgs".into(),
+    };
+
+    format!(
+        r#"{{
+  description = "Development shell generated by buildxyz";
+
+  inputs.nixpkgs.url = "{}";
+
+  outputs = {{ self, nixpkgs }}:
+    let
+      forAllSystems = nixpkgs.lib.genAttrs nixpkgs.lib.systems.flakeExposed;
+    in
+    {{
+      devShells = forAllSystems (system:
+        let
+          pkgs = nixpkgs.legacyPackages.${{system}};
+        in
+        {{
+          default = {};
+        }});
+    }};
+}}
+"#,
+        nixpkgs_url,
+        render_mk_shell(inputs, 10)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::popcount::Popcount;
+    use crate::resolution::read_resolution_db;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_provided_paths_are_exported_as_inputs() {
+        let db = read_resolution_db(
+            r#"
+["bin/pkg-config"]
+resolution = "constant"
+decision = "provide"
+file_entry_name = "/bin/pkg-config"
+kind = "symlink"
+
+["bin/pkg-config".store_path]
+hash = "0znwn1lw0268i7pj0rylhz9dnzbwijjn"
+name = "pkg-config-wrapper-0.29.2"
+store_dir = "/nix/store"
+
+["bin/pkg-config".store_path.origin]
+attr = "pkg-config"
+output = "out"
+toplevel = true
+
+["include/gtk/gtk.h"]
+resolution = "constant"
+decision = "provide"
+file_entry_name = "/include/gtk/gtk.h"
+kind = "symlink"
+
+["include/gtk/gtk.h".store_path]
+hash = "1znwn1lw0268i7pj0rylhz9dnzbwijjn"
+name = "gtk+3-3.24.36-dev"
+store_dir = "/nix/store"
+
+["include/gtk/gtk.h".store_path.origin]
+attr = "gtk+3"
+output = "dev"
+toplevel = true
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            render_nix_inputs(&collect_inputs(&db)),
+            "nativeBuildInputs = [\n  pkg-config\n];\nbuildInputs = [\n  pkgs.\"gtk+3\"\n];\n"
+        );
+    }
+
+    #[test]
+    fn test_derivation_skeleton_follows_the_build_system() {
+        let popcount = Popcount {
+            build_inputs: HashMap::from([("/nix/store/b-zlib-1.3-dev".to_string(), 900)]),
+            propagated_build_inputs: HashMap::new(),
+            native_build_inputs: HashMap::from([
+                ("/nix/store/a-pkg-config-wrapper-0.29.2".to_string(), 1200),
+                ("/nix/store/b-zlib-1.3-dev".to_string(), 12),
+            ]),
+            propagated_native_build_inputs: HashMap::new(),
+        };
+        assert_eq!(popcount.is_mostly_native("/nix/store/a-pkg-config-wrapper-0.29.2"), Some(true));
+        assert_eq!(popcount.is_mostly_native("/nix/store/b-zlib-1.3-dev"), Some(false));
+        assert_eq!(popcount.is_mostly_native("/nix/store/c-hello-2.12"), None);
+
+        let inputs = Inputs {
+            native_build_inputs: BTreeSet::from(["pkg-config".to_string()]),
+            build_inputs: BTreeSet::from(["zlib".to_string()]),
+        };
+        assert_eq!(
+            render_derivation("hello", BuildSystem::Cmake, &inputs, Some("make -j4"), None),
+            r#"{ pkgs ? import <nixpkgs> { } }:
+
+pkgs.stdenv.mkDerivation {
+  pname = "hello";
+  version = "unstable";
+
+  src = ./.;
+
+  nativeBuildInputs = with pkgs; [
+    cmake
+    pkg-config
+  ];
+  buildInputs = with pkgs; [
+    zlib
+  ];
+
+  # Recorded while running `make -j4`
+  meta = {
+    description = "TODO";
+  };
+}
+"#
+        );
+        assert!(
+            render_derivation("hello", BuildSystem::Unknown, &inputs, Some("./build.sh"), None)
+                .contains("    runHook preBuild\n    ./build.sh\n    runHook postBuild\n")
+        );
+    }
+
+    #[test]
+    fn test_shell_nix_is_pinned_to_the_nixpkgs_revision() {
+        let inputs = Inputs {
+            native_build_inputs: BTreeSet::from(["pkg-config".to_string()]),
+            build_inputs: BTreeSet::new(),
+        };
+
+        assert_eq!(
+            render_shell_nix(&inputs, Some("8a3e1cf40a6eaeb122c8321b97a0518cfa6ed779")),
+            r#"{ pkgs ? import (fetchTarball "https://github.com/NixOS/nixpkgs/archive/8a3e1cf40a6eaeb122c8321b97a0518cfa6ed779.tar.gz") { } }:
+
+pkgs.mkShell {
+  nativeBuildInputs = with pkgs; [
+    pkg-config
+  ];
+  buildInputs = with pkgs; [ ];
+}
+"#
+        );
+    }
+}
@@ -0,0 +1,131 @@
+use log::{debug, warn};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::{Command, Output};
+
+use crate::digest::sha256_hex;
+
+/// Whether a resolution location is remote rather than a local path.
+/// Plain HTTP ones are only read when they are signed, see `include::read_remote_resolutions`.
+pub fn is_url(location: &str) -> bool {
+    location.starts_with("https://") || location.starts_with("http://")
+}
+
+fn curl_error(url: &str, output: &Output) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        format!(
+            "Failed to fetch {}: {}",
+            url,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+    )
+}
+
+/// Download a remote file with `curl`, following redirections.
+pub fn fetch(url: &str) -> io::Result<Vec<u8>> {
+    let output = Command::new("curl")
+        .args(["--fail", "--silent", "--show-error", "--location", url])
+        .output()?;
+    if !output.status.success() {
+        return Err(curl_error(url, &output));
+    }
+
+    Ok(output.stdout)
+}
+
+/// Download a remote file with `curl` to `destination`, whose modification time becomes
+/// the `Last-Modified` date of the server, if it tells one.
+pub fn fetch_file(url: &str, destination: &Path) -> io::Result<()> {
+    let output = Command::new("curl")
+        .args(["--fail", "--silent", "--show-error", "--location", "--remote-time"])
+        .arg("--output")
+        .arg(destination)
+        .arg(url)
+        .output()?;
+    if !output.status.success() {
+        return Err(curl_error(url, &output));
+    }
+
+    Ok(())
+}
+
+/// Last `ETag` of dumped response headers, redirections included.
+fn etag_of(headers: &str) -> Option<String> {
+    headers
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .filter(|(name, _)| name.trim().eq_ignore_ascii_case("etag"))
+        .map(|(_, value)| value.trim().to_string())
+        .next_back()
+}
+
+/// Download a remote file into `$XDG_CACHE_HOME/buildxyz/remote`, revalidating the cached copy with its `ETag`.
+/// The cached copy is used when the server cannot be reached.
+pub fn fetch_cached(url: &str) -> io::Result<Vec<u8>> {
+    let cached = xdg::BaseDirectories::with_prefix("buildxyz")
+        .expect("Failed to find the XDG directories")
+        .place_cache_file(Path::new("remote").join(sha256_hex(url.as_bytes())))?;
+    let etag_path = cached.with_extension("etag");
+    let etag = fs::read_to_string(&etag_path)
+        .ok()
+        .filter(|_| cached.exists());
+
+    let directory = cached.parent().unwrap_or_else(|| Path::new("."));
+    let headers = tempfile::NamedTempFile::new_in(directory)?;
+    let body = tempfile::NamedTempFile::new_in(directory)?;
+    let mut command = Command::new("curl");
+    command
+        .args(["--fail", "--silent", "--show-error", "--location"])
+        .args(["--write-out", "%{http_code}"])
+        .arg("--dump-header")
+        .arg(headers.path())
+        .arg("--output")
+        .arg(body.path());
+    if let Some(etag) = &etag {
+        command.arg("--header").arg(format!("If-None-Match: {}", etag));
+    }
+    let fetched = command.arg(url).output().and_then(|output| {
+        if output.status.success() {
+            Ok(output)
+        } else {
+            Err(curl_error(url, &output))
+        }
+    });
+
+    match fetched {
+        Ok(output) if output.stdout == b"304" => {
+            debug!("{} is not modified since it was cached", url);
+        }
+        Ok(_) => {
+            let etag = etag_of(&fs::read_to_string(headers.path())?);
+            body.persist(&cached).map_err(|err| err.error)?;
+            match etag {
+                Some(etag) => fs::write(&etag_path, etag)?,
+                None => {
+                    let _ = fs::remove_file(&etag_path);
+                }
+            }
+            debug!("{} cached in {}", url, cached.display());
+        }
+        Err(err) if cached.exists() => {
+            warn!("{}, using the cached copy", err);
+        }
+        Err(err) => return Err(err),
+    }
+
+    fs::read(&cached)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_etag_of_the_last_response_is_kept() {
+        let headers = "HTTP/2 302\r\netag: \"moved\"\r\nlocation: /core.toml\r\n\r\nHTTP/2 200\r\nETag: W/\"1a2b\"\r\ncontent-type: text/plain\r\n\r\n";
+        assert_eq!(etag_of(headers).as_deref(), Some("W/\"1a2b\""));
+        assert_eq!(etag_of("HTTP/2 200\r\n\r\n"), None);
+    }
+}
@@ -0,0 +1,502 @@
+use log::{debug, trace, warn};
+use lru::LruCache;
+use regex::bytes::Regex;
+use serde_bytes::ByteBuf;
+use std::num::NonZeroUsize;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+
+use crate::cache::database::{SharedBuffer, SharedReader};
+use crate::cache::{FileNode, FileTree, FileTreeEntry, PathOrigin, StorePath};
+
+/// Listings of store paths remembered, they never change.
+const KNOWN_LISTINGS: usize = 256;
+/// Seconds given to a binary cache to answer, it is asked while a lookup waits.
+const TIMEOUT: &str = "10";
+
+/// Where the files of the store paths are looked up.
+pub trait FileSource {
+    /// Files matching the pattern in any store path this source knows about.
+    fn search(&self, pattern: &Regex) -> Vec<(StorePath, FileTreeEntry)>;
+
+    /// Files of a single store path matching the pattern,
+    /// `None` if this source cannot tell which files the store path has.
+    fn files_of(&self, store_path: &StorePath, pattern: &Regex) -> Option<Vec<FileTreeEntry>>;
+}
+
+/// The nix-index database of the session, see `--nixpkgs`.
+/// Its clones share the same reader, lookups of all threads search it concurrently.
+#[derive(Clone)]
+pub struct IndexSource {
+    reader: Arc<SharedReader>,
+    /// Of the compressed index, `None` if it was not mapped from the cache directory
+    fingerprint: Option<String>,
+}
+
+impl IndexSource {
+    pub fn new(buffer: SharedBuffer) -> Self {
+        // Mapped indexes are named after their fingerprint, see `index::load_index`.
+        let fingerprint = buffer
+            .path()
+            .and_then(|path| path.file_name())
+            .map(|name| name.to_string_lossy().to_string());
+        IndexSource {
+            reader: Arc::new(SharedReader::new(buffer)),
+            fingerprint,
+        }
+    }
+}
+
+impl FileSource for IndexSource {
+    fn search(&self, pattern: &Regex) -> Vec<(StorePath, FileTreeEntry)> {
+        self.reader
+            .search(pattern, None)
+            .expect("Failed to query the database")
+    }
+
+    /// Store paths are not told apart from those which are not indexed: `None` if nothing matches.
+    fn files_of(&self, store_path: &StorePath, pattern: &Regex) -> Option<Vec<FileTreeEntry>> {
+        let files: Vec<FileTreeEntry> = self
+            .reader
+            .search(pattern, Some(store_path.hash().into_owned()))
+            .expect("Failed to query the database")
+            .into_iter()
+            .map(|(_, entry)| entry)
+            .collect();
+
+        (!files.is_empty()).then_some(files)
+    }
+}
+
+/// Name of the index of the session among several, see `IndexSet`.
+pub const DEFAULT_INDEX_NAME: &str = "default";
+
+/// Several nix-index databases searched in priority order, e.g. of stable and unstable nixpkgs
+/// and of a private flake, see `--index`: the first one with matches answers.
+/// When there are several, candidates carry the name of the index they were found in.
+#[derive(Clone)]
+pub struct IndexSet {
+    indexes: Vec<(String, IndexSource)>,
+}
+
+impl IndexSet {
+    /// Only the index of the session.
+    pub fn new(buffer: SharedBuffer) -> Self {
+        IndexSet {
+            indexes: vec![(DEFAULT_INDEX_NAME.to_string(), IndexSource::new(buffer))],
+        }
+    }
+
+    /// Search this index too, after the ones added before.
+    pub fn with(mut self, name: &str, buffer: SharedBuffer) -> Self {
+        self.indexes
+            .push((name.to_string(), IndexSource::new(buffer)));
+        self
+    }
+
+    /// Names and fingerprints of the indexes, in priority order,
+    /// `None` if one of them was decompressed in memory and cannot be told apart from another.
+    pub fn fingerprints(&self) -> Option<String> {
+        let fingerprints: Option<Vec<String>> = self
+            .indexes
+            .iter()
+            .map(|(name, index)| Some(format!("{} {}", name, index.fingerprint.as_ref()?)))
+            .collect();
+        fingerprints.map(|fingerprints| fingerprints.join("\n"))
+    }
+
+    fn tag(&self, name: &str, store_path: StorePath) -> StorePath {
+        if self.indexes.len() < 2 {
+            return store_path;
+        }
+        let origin = PathOrigin {
+            index: Some(name.to_string()),
+            ..store_path.origin().into_owned()
+        };
+        StorePath::parse(origin, &store_path.as_str()).unwrap_or(store_path)
+    }
+}
+
+impl FileSource for IndexSet {
+    fn search(&self, pattern: &Regex) -> Vec<(StorePath, FileTreeEntry)> {
+        for (name, index) in &self.indexes {
+            let candidates = index.search(pattern);
+            if !candidates.is_empty() {
+                debug!("{} candidates in the {} index", candidates.len(), name);
+                return candidates
+                    .into_iter()
+                    .map(|(store_path, entry)| (self.tag(name, store_path), entry))
+                    .collect();
+            }
+        }
+
+        Vec::new()
+    }
+
+    /// Asks the index the store path was found in, or else each of them in order.
+    fn files_of(&self, store_path: &StorePath, pattern: &Regex) -> Option<Vec<FileTreeEntry>> {
+        let found_in = store_path.origin().index.clone();
+        self.indexes
+            .iter()
+            .filter(|(name, _)| found_in.as_ref().map_or(true, |found_in| found_in == name))
+            .find_map(|(_, index)| index.files_of(store_path, pattern))
+    }
+}
+
+/// Fields of a `.narinfo` used to find the listing of a store path.
+#[derive(Debug, PartialEq, Eq)]
+pub struct NarInfo {
+    pub store_path: String,
+    pub nar_size: Option<u64>,
+    /// Store paths it references, by base name, e.g. `<hash>-glibc-2.38`
+    pub references: Vec<String>,
+}
+
+/// Parse the `Key: value` lines of a `.narinfo`, `None` if it has no `StorePath`.
+fn parse_narinfo(contents: &str) -> Option<NarInfo> {
+    let field = |key: &str| {
+        contents
+            .lines()
+            .filter_map(|line| line.split_once(": "))
+            .find(|(name, _)| *name == key)
+            .map(|(_, value)| value.trim())
+    };
+
+    Some(NarInfo {
+        store_path: field("StorePath")?.to_string(),
+        nar_size: field("NarSize").and_then(|size| size.parse().ok()),
+        references: field("References")
+            .map(|references| references.split_whitespace().map(String::from).collect())
+            .unwrap_or_default(),
+    })
+}
+
+/// Flatten a node of a `.ls` listing into the entries under `path`, directories included.
+fn flatten_listing(path: &str, node: &serde_json::Value, entries: &mut Vec<FileTreeEntry>) {
+    let node_of = |node: &serde_json::Value| -> Option<FileNode<()>> {
+        match node["type"].as_str()? {
+            "regular" => Some(FileNode::Regular {
+                size: node["size"].as_u64().unwrap_or(0),
+                executable: node["executable"].as_bool().unwrap_or(false),
+            }),
+            "symlink" => Some(FileNode::Symlink {
+                target: ByteBuf::from(node["target"].as_str()?.as_bytes()),
+            }),
+            "directory" => Some(FileNode::Directory {
+                size: node["entries"]
+                    .as_object()
+                    .map_or(0, |entries| entries.len() as u64),
+                contents: (),
+            }),
+            _ => None,
+        }
+    };
+
+    let Some(file_node) = node_of(node) else {
+        return;
+    };
+    // The store path itself is not a file in it.
+    if !path.is_empty() {
+        entries.push(FileTreeEntry {
+            path: path.as_bytes().to_vec(),
+            node: file_node,
+        });
+    }
+    if let Some(children) = node["entries"].as_object() {
+        for (name, child) in children {
+            flatten_listing(&format!("{}/{}", path, name), child, entries);
+        }
+    }
+}
+
+/// Files of a store path from its `.ls` listing, as served by the binary caches, e.g. `/lib/libz.so`.
+fn parse_listing(contents: &[u8]) -> Option<Vec<FileTreeEntry>> {
+    let listing: serde_json::Value = serde_json::from_slice(contents).ok()?;
+    let mut entries = Vec::new();
+    flatten_listing("", listing.get("root")?, &mut entries);
+
+    Some(entries)
+}
+
+/// Tree of a node of a `.ls` listing, as the index stores it.
+fn tree_of(node: &serde_json::Value) -> Option<FileTree> {
+    match node["type"].as_str()? {
+        "regular" => Some(FileTree::regular(
+            node["size"].as_u64().unwrap_or(0),
+            node["executable"].as_bool().unwrap_or(false),
+        )),
+        "symlink" => Some(FileTree::symlink(ByteBuf::from(
+            node["target"].as_str()?.as_bytes(),
+        ))),
+        "directory" => Some(FileTree::directory(
+            node["entries"]
+                .as_object()
+                .into_iter()
+                .flatten()
+                .filter_map(|(name, child)| Some((ByteBuf::from(name.as_bytes()), tree_of(child)?)))
+                .collect(),
+        )),
+        _ => None,
+    }
+}
+
+/// Body of a binary cache URL, `None` if it does not have it or cannot be reached.
+/// Listings are served compressed, e.g. by `cache.nixos.org`.
+fn get(url: &str) -> Option<Vec<u8>> {
+    let output = Command::new("curl")
+        .args([
+            "--fail",
+            "--silent",
+            "--location",
+            "--compressed",
+            "--max-time",
+            TIMEOUT,
+        ])
+        .arg(url)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|err| warn!("Failed to ask {}: {}", url, err))
+        .ok()?;
+
+    output.status.success().then_some(output.stdout)
+}
+
+/// Files of the store paths missing from the index, e.g. built from a more recent nixpkgs,
+/// as listed by the binary caches which have them, see `--binary-cache-listings`.
+/// Only store paths known to the session are searched, a binary cache cannot be searched by file.
+pub struct BinaryCacheSource {
+    /// Nothing is asked if empty
+    caches: Vec<String>,
+    /// Store paths searched, e.g. those of the loaded resolutions
+    tracked: Mutex<Vec<StorePath>>,
+    /// Store path hash -> its files, `None` if no binary cache lists it
+    listings: Mutex<LruCache<String, Option<Arc<Vec<FileTreeEntry>>>>>,
+}
+
+impl Default for BinaryCacheSource {
+    fn default() -> Self {
+        BinaryCacheSource::new(Vec::new())
+    }
+}
+
+impl BinaryCacheSource {
+    /// Binary caches over HTTP among these substituters, the others cannot be asked for listings.
+    pub fn new(substituters: Vec<String>) -> Self {
+        BinaryCacheSource {
+            caches: substituters
+                .into_iter()
+                .filter(|substituter| {
+                    substituter.starts_with("https://") || substituter.starts_with("http://")
+                })
+                .map(|substituter| substituter.trim_end_matches('/').to_string())
+                .collect(),
+            tracked: Mutex::new(Vec::new()),
+            listings: Mutex::new(LruCache::new(NonZeroUsize::new(KNOWN_LISTINGS).unwrap())),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.caches.is_empty()
+    }
+
+    /// Search the files of this store path too, from now on.
+    pub fn track(&self, store_path: &StorePath) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut tracked = self.tracked.lock().unwrap();
+        if !tracked
+            .iter()
+            .any(|known| known.hash() == store_path.hash())
+        {
+            tracked.push(store_path.clone());
+        }
+    }
+
+    /// `.narinfo` of a store path on the first binary cache having it, with that binary cache.
+    fn narinfo(&self, store_path: &StorePath) -> Option<(&str, NarInfo)> {
+        self.caches.iter().find_map(|cache| {
+            let contents = get(&format!("{}/{}.narinfo", cache, store_path.hash()))?;
+            let narinfo = parse_narinfo(&String::from_utf8_lossy(&contents))?;
+            Some((cache.as_str(), narinfo))
+        })
+    }
+
+    /// Files of a store path, from the `.ls` next to its `.narinfo`, asked once.
+    fn listing(&self, store_path: &StorePath) -> Option<Arc<Vec<FileTreeEntry>>> {
+        let hash = store_path.hash().into_owned();
+        if let Some(listing) = self.listings.lock().unwrap().get(&hash) {
+            return listing.clone();
+        }
+
+        let listing = self.narinfo(store_path).and_then(|(cache, narinfo)| {
+            debug!(
+                "{} is on {}, {} bytes",
+                narinfo.store_path,
+                cache,
+                narinfo.nar_size.unwrap_or(0)
+            );
+            let entries = parse_listing(&get(&format!("{}/{}.ls", cache, hash))?);
+            if entries.is_none() {
+                warn!(
+                    "{} serves an invalid listing of {}",
+                    cache, narinfo.store_path
+                );
+            }
+            entries.map(Arc::new)
+        });
+        trace!("listing of {}: {:?}", store_path.as_str(), listing);
+        self.listings.lock().unwrap().put(hash, listing.clone());
+
+        listing
+    }
+}
+
+/// `.narinfo` of a store path on a binary cache and its files, from the `.ls` next to it,
+/// `None` if the binary cache does not have both. Used to build an index, see `index::build_index`.
+pub fn fetch_file_tree(cache: &str, hash: &str) -> Option<(NarInfo, FileTree)> {
+    let cache = cache.trim_end_matches('/');
+    let narinfo = parse_narinfo(&String::from_utf8_lossy(&get(&format!(
+        "{}/{}.narinfo",
+        cache, hash
+    ))?))?;
+    let listing: serde_json::Value =
+        serde_json::from_slice(&get(&format!("{}/{}.ls", cache, hash))?).ok()?;
+    let tree = tree_of(listing.get("root")?);
+    if tree.is_none() {
+        warn!(
+            "{} serves an invalid listing of {}",
+            cache, narinfo.store_path
+        );
+    }
+
+    Some((narinfo, tree?))
+}
+
+impl FileSource for BinaryCacheSource {
+    fn search(&self, pattern: &Regex) -> Vec<(StorePath, FileTreeEntry)> {
+        let tracked = self.tracked.lock().unwrap().clone();
+        tracked
+            .iter()
+            .flat_map(|store_path| {
+                self.files_of(store_path, pattern)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(move |entry| (store_path.clone(), entry))
+            })
+            .collect()
+    }
+
+    fn files_of(&self, store_path: &StorePath, pattern: &Regex) -> Option<Vec<FileTreeEntry>> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let listing = self.listing(store_path)?;
+
+        Some(
+            listing
+                .iter()
+                .filter(|entry| pattern.is_match(&entry.path))
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_binary_cache_listings() {
+        let narinfo = parse_narinfo(
+            "StorePath: /nix/store/zy4xhzc6x6ldrfzqzfa4x3cw8ds1mgcn-zlib-1.3\n\
+             URL: nar/0a1b.nar.xz\n\
+             Compression: xz\n\
+             NarSize: 125352\n\
+             References: zy4xhzc6x6ldrfzqzfa4x3cw8ds1mgcn-zlib-1.3\n",
+        );
+        assert_eq!(
+            narinfo,
+            Some(NarInfo {
+                store_path: "/nix/store/zy4xhzc6x6ldrfzqzfa4x3cw8ds1mgcn-zlib-1.3".into(),
+                nar_size: Some(125352),
+                references: vec!["zy4xhzc6x6ldrfzqzfa4x3cw8ds1mgcn-zlib-1.3".into()],
+            })
+        );
+        assert_eq!(parse_narinfo("404 Not Found"), None);
+
+        let listing = br#"{"version": 1, "root": {"type": "directory", "entries": {
+                "lib": {"type": "directory", "entries": {
+                    "libz.so": {"type": "symlink", "target": "libz.so.1.3"},
+                    "libz.so.1.3": {"type": "regular", "size": 121000, "executable": true, "narOffset": 400}
+                }}
+            }}}"#;
+        let entries = parse_listing(listing).unwrap();
+        let paths: Vec<&[u8]> = entries.iter().map(|entry| entry.path.as_slice()).collect();
+        assert_eq!(paths, [&b"/lib"[..], b"/lib/libz.so", b"/lib/libz.so.1.3"]);
+        assert_eq!(
+            entries[1].node,
+            FileNode::Symlink {
+                target: ByteBuf::from(&b"libz.so.1.3"[..])
+            }
+        );
+        assert!(parse_listing(b"\x1b\x8b").is_none());
+
+        let listing: serde_json::Value = serde_json::from_slice(listing).unwrap();
+        let mut indexed: Vec<Vec<u8>> = tree_of(&listing["root"])
+            .unwrap()
+            .to_list(b"")
+            .into_iter()
+            .map(|entry| entry.path)
+            .collect();
+        indexed.sort();
+        assert_eq!(
+            indexed[1..],
+            paths.iter().map(|path| path.to_vec()).collect::<Vec<_>>()
+        );
+    }
+
+    /// An index of a single store path having these files.
+    fn index_of(store_path: &str, files: &[&str]) -> SharedBuffer {
+        let database = tempfile::NamedTempFile::new().unwrap();
+        let origin = PathOrigin {
+            attr: "zlib".into(),
+            output: "out".into(),
+            toplevel: true,
+            system: None,
+            index: None,
+        };
+        crate::cache::database::write_index(database.path(), &[(origin, store_path, files.to_vec())]);
+
+        crate::cache::database::read_from_path(database.path())
+            .unwrap()
+            .into()
+    }
+
+    #[test]
+    fn test_indexes_are_searched_in_order() {
+        let stable = "/nix/store/zy4xhzc6x6ldrfzqzfa4x3cw8ds1mgcn-zlib-1.2";
+        let unstable = "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-zlib-1.3";
+        let zlib_h = Regex::new("^/zlib.h$").unwrap();
+        let zconf_h = Regex::new("^/zconf.h$").unwrap();
+
+        let single = IndexSet::new(index_of(stable, &["zlib.h"]));
+        assert_eq!(single.search(&zlib_h)[0].0.origin().index, None);
+
+        let indexes = IndexSet::new(index_of(stable, &["zlib.h"]))
+            .with("unstable", index_of(unstable, &["zlib.h", "zconf.h"]));
+        let candidates = indexes.search(&zlib_h);
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].0.as_str(), stable);
+        assert_eq!(
+            candidates[0].0.origin().index.as_deref(),
+            Some(DEFAULT_INDEX_NAME)
+        );
+        let candidates = indexes.search(&zconf_h);
+        assert_eq!(candidates[0].0.as_str(), unstable);
+        assert_eq!(candidates[0].0.origin().index.as_deref(), Some("unstable"));
+        assert!(indexes.files_of(&candidates[0].0, &zlib_h).is_some());
+        assert!(indexes.search(&Regex::new("^/png.h$").unwrap()).is_empty());
+    }
+}
@@ -0,0 +1,208 @@
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+use crate::cache::{PathOrigin, StorePath};
+use crate::nix::{
+    flake_metadata, is_valid_path, real_path, realize_installable, store_dir, store_path_root,
+    FlakeMetadata,
+};
+
+lazy_static! {
+    static ref OUTPUTS: Mutex<FlakeOutputs> = Mutex::new(FlakeOutputs::load());
+}
+
+/// Whether a candidate or a resolution target is a flake installable, e.g. `github:acme/tools#mytool`,
+/// rather than a store path.
+pub fn is_installable(target: &str) -> bool {
+    !target.starts_with(&format!("{}/", store_dir())) && target.contains('#')
+}
+
+/// Flake installable a store path was built from, if it is not from the index, see `origin_of`.
+pub fn installable_of(store_path: &StorePath) -> Option<String> {
+    let attr = &store_path.origin().attr;
+    is_installable(attr).then(|| attr.clone())
+}
+
+/// Origin of the outputs of a flake installable, the installable stands for the attribute.
+pub fn origin_of(installable: &str) -> PathOrigin {
+    PathOrigin {
+        attr: installable.to_string(),
+        output: "out".to_string(),
+        toplevel: true,
+        system: None,
+        index: None,
+    }
+}
+
+/// The locked flake and the attribute, the same installable builds something else once its lock file changes.
+/// Sources without a revision, e.g. dirty Git trees, are told apart by their hash.
+fn cache_key(metadata: &FlakeMetadata, attr: &str) -> String {
+    match (&metadata.locked.rev, &metadata.locked.nar_hash) {
+        (None, Some(nar_hash)) => format!("{}?narHash={}#{}", metadata.url, nar_hash, attr),
+        _ => format!("{}#{}", metadata.url, attr),
+    }
+}
+
+/// Output paths of the flake installables provided by resolutions and prompts, e.g. tools only packaged as flakes.
+///
+/// They are kept in `$XDG_CACHE_HOME/buildxyz/flake-outputs.json` by locked flake,
+/// so that an installable is only built again when its lock file changes or its outputs were collected.
+pub struct FlakeOutputs {
+    /// Locked installable -> its output paths
+    built: HashMap<String, Vec<String>>,
+    /// Output path -> installable it was built from, to build it again when it is not in the store anymore
+    installables: HashMap<String, String>,
+    cache_filepath: Option<PathBuf>,
+}
+
+impl FlakeOutputs {
+    /// Output paths shared by the whole process.
+    pub fn shared() -> MutexGuard<'static, FlakeOutputs> {
+        OUTPUTS.lock().unwrap()
+    }
+
+    fn load() -> Self {
+        let cache_filepath = xdg::BaseDirectories::with_prefix("buildxyz")
+            .ok()
+            .and_then(|dirs| dirs.place_cache_file("flake-outputs.json").ok());
+        let built = cache_filepath
+            .as_ref()
+            .and_then(|filepath| std::fs::read(filepath).ok())
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+
+        FlakeOutputs {
+            built,
+            installables: HashMap::new(),
+            cache_filepath,
+        }
+    }
+
+    fn save(&self) {
+        if let Some(filepath) = &self.cache_filepath {
+            if let Err(err) = std::fs::write(filepath, serde_json::to_vec(&self.built).unwrap()) {
+                warn!(
+                    "Failed to cache the flake outputs in {}: {}",
+                    filepath.display(),
+                    err
+                );
+            }
+        }
+    }
+
+    /// Output paths of a flake installable, built unless a build of the same lock file is still in the store.
+    pub fn out_paths(&mut self, installable: &str) -> Result<Vec<String>, String> {
+        let (flakeref, attr) = installable
+            .split_once('#')
+            .ok_or_else(|| format!("{} is not a flake installable", installable))?;
+        let metadata = flake_metadata(flakeref).map_err(|err| err.to_string())?;
+        let key = cache_key(&metadata, attr);
+
+        let out_paths = match self.built.get(&key) {
+            Some(out_paths) if out_paths.iter().all(|out_path| is_valid_path(out_path)) => {
+                debug!("{} is built as {}", installable, out_paths.join(", "));
+                out_paths.clone()
+            }
+            _ => {
+                info!("Building {}", installable);
+                let out_paths = realize_installable(&format!("{}#{}", metadata.url, attr))
+                    .map_err(|err| err.to_string())?;
+                self.built.insert(key, out_paths.clone());
+                self.save();
+                out_paths
+            }
+        };
+        self.remember(installable, out_paths.iter().map(String::as_str));
+
+        Ok(out_paths)
+    }
+
+    /// Remember that these store paths are built by a flake installable, e.g. as recorded in a resolution.
+    pub fn remember<'a>(
+        &mut self,
+        installable: &str,
+        out_paths: impl IntoIterator<Item = &'a str>,
+    ) {
+        for out_path in out_paths {
+            self.installables.insert(
+                store_path_root(out_path).to_string(),
+                installable.to_string(),
+            );
+        }
+    }
+
+    /// Build the flake installable a store path comes from again, `None` if it is not a known flake output.
+    pub fn rebuild(&mut self, path: &str) -> Option<Result<(), String>> {
+        let root = store_path_root(path);
+        let installable = self.installables.get(root)?.clone();
+        Some(self.out_paths(&installable).and_then(|out_paths| {
+            if out_paths.iter().any(|out_path| out_path == root) {
+                Ok(())
+            } else {
+                Err(format!(
+                    "{} now builds {} rather than {}",
+                    installable,
+                    out_paths.join(", "),
+                    root
+                ))
+            }
+        }))
+    }
+}
+
+/// Output of a flake installable having `file_entry_name`, its first output if none has it.
+pub fn store_path_of(installable: &str, file_entry_name: &str) -> Result<StorePath, String> {
+    let out_paths = FlakeOutputs::shared().out_paths(installable)?;
+    let root = out_paths
+        .iter()
+        .find(|out_path| {
+            Path::new(&real_path(out_path))
+                .join(file_entry_name.trim_start_matches('/'))
+                .symlink_metadata()
+                .is_ok()
+        })
+        .or(out_paths.first())
+        .ok_or_else(|| format!("{} has no output", installable))?;
+
+    StorePath::parse(origin_of(installable), root)
+        .ok_or_else(|| format!("{} is not a store path", root))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nix::LockedFlake;
+
+    #[test]
+    fn test_flake_outputs_follow_the_lock() {
+        assert!(is_installable("github:acme/tools#mytool"));
+        assert!(is_installable("nixpkgs#zlib.dev"));
+        assert!(!is_installable(
+            "/nix/store/zy4xhzc6x6ldrfzqzfa4x3cw8ds1mgcn-zlib-1.3"
+        ));
+        assert!(!is_installable("zlib.dev"));
+
+        let mut metadata = FlakeMetadata {
+            path: "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-source".into(),
+            url: "github:acme/tools/057f9aecfb71c4437d2b27d3323df7f93c010b7e".into(),
+            locked: LockedFlake {
+                rev: Some("057f9aecfb71c4437d2b27d3323df7f93c010b7e".into()),
+                nar_hash: Some("sha256-AAAA".into()),
+            },
+        };
+        assert_eq!(
+            cache_key(&metadata, "mytool"),
+            "github:acme/tools/057f9aecfb71c4437d2b27d3323df7f93c010b7e#mytool"
+        );
+
+        metadata.url = "git+file:///home/me/tools".into();
+        metadata.locked.rev = None;
+        assert_eq!(
+            cache_key(&metadata, "mytool"),
+            "git+file:///home/me/tools?narHash=sha256-AAAA#mytool"
+        );
+    }
+}
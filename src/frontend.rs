@@ -0,0 +1,217 @@
+use serde::Deserialize;
+use serde_json::json;
+use std::io;
+use std::sync::mpsc::{channel, Sender};
+use std::thread::{self, JoinHandle};
+
+use crate::fs::FsEventMessage;
+use crate::interactive::{prompt_title, send_answer, PromptAnswer, TimeoutDecision, UserRequest};
+use crate::outputs;
+
+/// A way to show the prompts to the user, e.g. the TUI, `--remote-listen` or `--rpc-listen`.
+/// Several frontends can run at once, see `multiplex`.
+pub trait Frontend: Send {
+    /// Start showing the requests sent on the returned channel, answers go to `reply_fs`.
+    /// Returns the thread to wait for before exiting, if any.
+    fn spawn(
+        self: Box<Self>,
+        reply_fs: Sender<FsEventMessage>,
+    ) -> io::Result<(Option<JoinHandle<()>>, Sender<UserRequest>)>;
+}
+
+/// Share the prompts between frontends: each one gets every request and answers on its own channel,
+/// the first answer to a prompt wins and the other frontends drop it.
+pub fn multiplex(
+    frontends: Vec<Box<dyn Frontend>>,
+    reply_fs: Sender<FsEventMessage>,
+) -> io::Result<(Vec<JoinHandle<()>>, Sender<UserRequest>)> {
+    let mut join_handles = Vec::new();
+    let mut senders = Vec::new();
+    let mut answers = Vec::new();
+    for frontend in frontends {
+        let (send_answer, recv_answer) = channel();
+        let (join_handle, sender) = frontend.spawn(send_answer)?;
+        join_handles.extend(join_handle);
+        senders.push(sender);
+        answers.push(recv_answer);
+    }
+
+    for (index, answers) in answers.into_iter().enumerate() {
+        let (senders, reply_fs) = (senders.clone(), reply_fs.clone());
+        thread::spawn(move || {
+            for answer in answers {
+                if let Some(request_id) = answer.answered_request() {
+                    for (_, sender) in senders
+                        .iter()
+                        .enumerate()
+                        .filter(|(other, _)| *other != index)
+                    {
+                        let _ = sender.send(UserRequest::Cancel(request_id));
+                    }
+                }
+                if reply_fs.send(answer).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    let (send, recv) = channel::<UserRequest>();
+    thread::spawn(move || {
+        for request in recv {
+            for sender in &senders {
+                let _ = sender.send(request.clone());
+            }
+        }
+    });
+
+    Ok((join_handles, send))
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum AnswerError {
+    #[error("request {0} is not waiting for an answer")]
+    NotPending(u64),
+    #[error("this answer does not fit the prompt")]
+    Invalid,
+}
+
+/// An answer of a frontend speaking JSON, e.g. `{"choice": 0}`, `{"choice": 2, "subtree": true}`,
+/// `{"search": "zlib"}`, `{"skip": true}` or `{"later": true}` to skip it this time only.
+/// Resolutions to reconfirm are confirmed by `{"choice": 0}`.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct JsonAnswer {
+    choice: Option<usize>,
+    subtree: bool,
+    search: Option<String>,
+    skip: bool,
+    later: bool,
+}
+
+impl JsonAnswer {
+    /// What the local UI would answer, `None` if the answer does not fit the prompt.
+    fn prompt_answer(self, request: &UserRequest) -> Option<PromptAnswer> {
+        let choices = match request {
+            UserRequest::InteractiveSearch(_, candidates, ..) => candidates.len(),
+            _ => 1,
+        };
+
+        match self {
+            JsonAnswer {
+                search: Some(query),
+                ..
+            } if matches!(request, UserRequest::InteractiveSearch(..)) => {
+                Some(PromptAnswer::Search(query))
+            }
+            JsonAnswer {
+                choice: Some(index),
+                subtree: true,
+                ..
+            } if index < choices => Some(PromptAnswer::SubtreeChoice(index)),
+            JsonAnswer {
+                choice: Some(index),
+                ..
+            } if index < choices => Some(PromptAnswer::Choice(index)),
+            JsonAnswer { skip: true, .. } => Some(PromptAnswer::Skip),
+            JsonAnswer { later: true, .. } => Some(PromptAnswer::SkipForNow),
+            _ => None,
+        }
+    }
+}
+
+/// The prompt as frontends speaking JSON show it, `None` for other requests.
+pub fn prompt_json(request: &UserRequest) -> Option<serde_json::Value> {
+    match request {
+        UserRequest::InteractiveSearch(
+            request_id,
+            candidates,
+            rankings,
+            _,
+            requester,
+            fuzzy,
+            deferred,
+            subtree,
+            requested_path,
+        ) => Some(json!({
+            "id": request_id,
+            "kind": "search",
+            "title": prompt_title(request),
+            "path": requested_path.display().to_string(),
+            "requester": requester.to_string(),
+            "fuzzy": fuzzy,
+            "deferred": deferred,
+            "subtree": subtree.as_ref().map(|subtree| subtree.display().to_string()),
+            "candidates": candidates
+                .iter()
+                .zip(rankings)
+                .map(|((store_path, entry), ranking)| json!({
+                    "installable": outputs::installable(&store_path.origin()),
+                    "store_path": store_path.as_str().to_string(),
+                    "file": String::from_utf8_lossy(&entry.path),
+                    "ranking": ranking.to_string(),
+                }))
+                .collect::<Vec<_>>(),
+        })),
+        UserRequest::Reconfirm(request_id, requested_path, description, requester) => Some(json!({
+            "id": request_id,
+            "kind": "reconfirm",
+            "title": prompt_title(request),
+            "requester": requester.to_string(),
+            "path": requested_path.display().to_string(),
+            "description": description,
+        })),
+        _ => None,
+    }
+}
+
+/// Prompts waiting for an answer, in the order they came, for frontends listing them.
+#[derive(Default)]
+pub struct PendingPrompts(Vec<UserRequest>);
+
+impl PendingPrompts {
+    /// Keep track of a request, whether the pending prompts changed.
+    pub fn update(&mut self, request: &UserRequest) -> bool {
+        match request {
+            UserRequest::Cancel(request_id) => {
+                let pending = self.0.len();
+                self.0
+                    .retain(|prompt| prompt.prompt_id() != Some(*request_id));
+                self.0.len() != pending
+            }
+            request if request.prompt_id().is_some() => {
+                // The results of a manual search replace the prompt they answer.
+                self.0
+                    .retain(|prompt| prompt.prompt_id() != request.prompt_id());
+                self.0.push(request.clone());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Reply to the filesystem like the local UI would, the prompt is not pending anymore.
+    pub fn answer(
+        &mut self,
+        request_id: u64,
+        answer: JsonAnswer,
+        reply_fs: &Sender<FsEventMessage>,
+    ) -> Result<(), AnswerError> {
+        let position = self
+            .0
+            .iter()
+            .position(|prompt| prompt.prompt_id() == Some(request_id))
+            .ok_or(AnswerError::NotPending(request_id))?;
+        let answer = answer
+            .prompt_answer(&self.0[position])
+            .ok_or(AnswerError::Invalid)?;
+
+        let request = self.0.remove(position);
+        send_answer(reply_fs, &request, answer, None, TimeoutDecision::Ignore);
+
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::Value::Array(self.0.iter().filter_map(prompt_json).collect())
+    }
+}
@@ -1,91 +1,468 @@
 use std::collections::{HashMap, HashSet};
 use std::io;
+use std::iter;
 use std::path::{Path, PathBuf};
 use std::time::{Duration, Instant, SystemTime};
 
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::num::NonZeroUsize;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
 
 // TODO: is it Linux-specific?
-use std::cell::RefCell;
 use std::ffi::{OsStr, OsString};
 
 use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::PermissionsExt;
 
 use fuser::{FileAttr, FileType, Filesystem};
 
-use log::{debug, info, trace, warn};
+use log::{debug, error, info, trace, warn};
+use lru::LruCache;
 
 use regex::bytes::Regex;
 use walkdir::WalkDir;
 
-use crate::cache::database::Reader;
-use crate::cache::{FileNode, FileTreeEntry, StorePath};
-use crate::interactive::UserRequest;
-use crate::nix::realize_path;
+use crate::blocklist::PathBlocklist;
+use crate::budget::ClosureBudget;
+use crate::dispatch::AnswerRouter;
+use crate::explain::{Explanation, RecentLookups};
+use crate::filesource::{BinaryCacheSource, FileSource, IndexSet};
+use crate::flake::{self, FlakeOutputs};
+use crate::fuzzy;
+use crate::metrics::Metrics;
+use crate::cache::{FileNode, FileTreeEntry, PathOrigin, StorePath};
+use crate::inode::InodeTable;
+use crate::interpreter;
+use crate::interactive::{ReviewItem, ReviewVerdict, UserRequest};
+use crate::library;
+use crate::outputs;
+use crate::overlay::{self, MissingPackage, MissingPath};
+use crate::pkgconfig;
+use crate::nix::{real_path, realize_path, store_dir, store_is_relocated, store_path_root};
 use crate::popcount::Popcount;
-
-use crate::read_raw_buffer;
-use crate::resolution::{db_to_human_toml, Decision, ProvideData, Resolution, ResolutionDB};
+use crate::process::{self, ProcessFilter, ProcessInfo};
+use crate::querycache::QueryCache;
+use crate::ranking::Ranking;
+use crate::realize::Realizer;
+use crate::substituters::SubstituterCheck;
+use crate::trace::{AccessOutcome, AccessTrace};
+use crate::usage::UsageCounter;
+use crate::vdir::VirtualDirectories;
+
+use crate::pin::{current_pin, session_index};
+use crate::resolution::{
+    insert_resolution, merge_resolution_db, remove_resolution, update_resolution_db, Decision, LinkMode, PatternResolutionData, PatternResolutions,
+    PatternSyntax, Provenance, ProvideData,
+    Resolution,
+    ResolutionData, ResolutionDB,
+};
 
 const UNIX_EPOCH: SystemTime = SystemTime::UNIX_EPOCH;
 
+#[derive(Clone)]
 pub enum FsEventMessage {
     /// Flush all current pending filesystem access to ENOENT
     IgnorePendingRequests,
-    /// A package suggestion as a reply to a user interactive search
-    PackageSuggestion((StorePath, FileTreeEntry)),
+    /// ENOENT the given interactive search
+    IgnoreRequest(u64),
+    /// ENOENT the given interactive search this time only, it is asked again on the next lookup
+    SkipForNow(u64),
+    /// A package suggestion as a reply to the given interactive search
+    PackageSuggestion(u64, (StorePath, FileTreeEntry)),
+    /// A package suggestion for all the lookups under the subtree of the given interactive search
+    SubtreeSuggestion(u64, (StorePath, FileTreeEntry)),
+    /// The resolution of the given request is confirmed
+    Confirm(u64),
+    /// Undo the decision taken for the given request
+    Undo(u64),
+    /// None of the candidates of the given interactive search fits, look this query up instead
+    ManualSearch(u64, String),
+    /// Tell why the path of the given interactive search is requested, see `UserRequest::Explanation`
+    Explain(u64),
+    /// Switch the session to this build phase, see `BuildXYZ::set_phase`
+    SetPhase(Option<String>),
+}
+
+impl FsEventMessage {
+    /// Request whose prompt this message answers, if it is an answer.
+    pub fn answered_request(&self) -> Option<u64> {
+        match self {
+            Self::PackageSuggestion(request_id, _)
+            | Self::SubtreeSuggestion(request_id, _)
+            | Self::IgnoreRequest(request_id)
+            | Self::SkipForNow(request_id)
+            | Self::Confirm(request_id)
+            | Self::ManualSearch(request_id, _) => Some(*request_id),
+            Self::IgnorePendingRequests | Self::Undo(_) | Self::Explain(_) | Self::SetPhase(_) => {
+                None
+            }
+        }
+    }
+}
+
+/// Outcome of an interactive search from the filesystem point of view.
+enum PromptOutcome {
+    /// Also for the lookups under the subtree of the requested path if set
+    Provide(Box<StorePath>, FileTreeEntry, bool),
+    Ignore,
+    SkipForNow,
+    /// The resolution to reconfirm is confirmed
+    Confirm,
+    /// The user looks for other candidates with this query
+    Search(String),
+    /// The requesting process went away while waiting
+    Interrupted,
+}
+
+/// What we keep of a `fuser::Request` once the dispatch loop moved on.
+#[derive(Clone, Copy, Debug)]
+pub struct RequestInfo {
+    pub unique: u64,
+    pub pid: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl From<&fuser::Request<'_>> for RequestInfo {
+    fn from(req: &fuser::Request<'_>) -> Self {
+        RequestInfo {
+            unique: req.unique(),
+            pid: req.pid(),
+            uid: req.uid(),
+            gid: req.gid(),
+        }
+    }
+}
+
+/// Where the answer to a lookup goes: the kernel, or a test observing it.
+pub enum LookupReply {
+    Kernel(fuser::ReplyEntry),
+    #[cfg(test)]
+    Test(Sender<Result<(Duration, FileAttr), i32>>),
+}
+
+impl LookupReply {
+    fn entry(self, ttl: &Duration, attr: &FileAttr, generation: u64) {
+        match self {
+            LookupReply::Kernel(reply) => reply.entry(ttl, attr, generation),
+            #[cfg(test)]
+            LookupReply::Test(send) => {
+                let _ = send.send(Ok((*ttl, *attr)));
+            }
+        }
+    }
+
+    fn error(self, err: i32) {
+        match self {
+            LookupReply::Kernel(reply) => reply.error(err),
+            #[cfg(test)]
+            LookupReply::Test(send) => {
+                let _ = send.send(Err(err));
+            }
+        }
+    }
+}
+
+impl From<fuser::ReplyEntry> for LookupReply {
+    fn from(reply: fuser::ReplyEntry) -> Self {
+        LookupReply::Kernel(reply)
+    }
+}
+
+/// What a served symlink points to.
+pub enum LinkTarget {
+    /// A store path, which must be realized before answering, see `SearchHandles::realized_link_target`
+    Store(Vec<u8>),
+    /// A path of the host or of the fast working tree
+    Host(Vec<u8>),
+}
+
+/// A lookup of a path already searched for by a pending lookup, it waits for its answer.
+pub struct ParkedLookup {
+    req: RequestInfo,
+    parent: u64,
+    name: OsString,
+    reply: LookupReply,
+}
+
+/// A lookup which none of the fast paths could answer,
+/// it needs the index and possibly the user, or the realization of the package resolving it.
+pub struct PendingLookup {
+    req: RequestInfo,
+    parent: u64,
+    name: OsString,
+    target_path: PathBuf,
+    requester: ProcessInfo,
+    phase: Option<String>,
+    /// The resolution defers this path to the user
+    deferred: bool,
+    /// The resolution of this path, described, must be confirmed by the user first
+    reconfirm: Option<String>,
+    /// The resolution of this path provides it from this package, which only needs to be realized
+    resolved: Option<ProvideData>,
+    reply: LookupReply,
+}
+
+impl PendingLookup {
+    /// Lookups with the same key wait for this one, see `BuildXYZ::lookup_fast`.
+    fn coalescing_key(&self) -> (String, Option<String>) {
+        (self.target_path.to_string_lossy().to_string(), self.phase.clone())
+    }
+}
+
+/// The candidate chosen for a pending lookup, realized and shadowed in the fast working tree.
+pub struct ProvidedCandidate {
+    store_path: StorePath,
+    entry: FileTreeEntry,
+    attribute: FileAttr,
+    /// Also provides the lookups under this subtree
+    subtree: Option<PathBuf>,
+    /// Candidates it was chosen among
+    candidates: Vec<(StorePath, FileTreeEntry)>,
+}
+
+/// Result of the index search of a pending lookup.
+pub enum SearchOutcome {
+    Provide(Box<ProvidedCandidate>),
+    /// The package of the resolution is realized, it serves this store path of this kind
+    Resolved(Vec<u8>, FileType),
+    /// The package of the resolution does not have the requested path or cannot be realized
+    Unavailable,
+    Ignore,
+    /// ENOENT without recording anything, the path is asked again on its next lookup
+    SkipForNow,
+    /// The resolution to reconfirm is confirmed, the lookup is answered by it
+    Confirmed,
+    Interrupted,
+    NotFound,
+}
+
+/// Requested path with the casing of the index entry providing it, e.g. `Include/Foo.h`
+/// provided by `/include/foo.h` is recorded as `include/foo.h`.
+/// Entries which are not the same path up to case, e.g. another library version, are left alone.
+fn canonical_case(requested_path: &Path, entry_path: &[u8], case_insensitive: bool) -> PathBuf {
+    let entry_path = String::from_utf8_lossy(entry_path);
+    let entry_path = entry_path.trim_start_matches('/');
+    if case_insensitive && entry_path.to_lowercase() == requested_path.to_string_lossy().to_lowercase() {
+        PathBuf::from(entry_path)
+    } else {
+        requested_path.to_owned()
+    }
+}
+
+/// Fuzzy searches can match many unrelated packages, e.g. any `config.h`.
+const MAX_FUZZY_CANDIDATES: usize = 20;
+
+/// Whether a query is a store path rather than an attribute name or a pattern.
+fn is_store_path_query(query: &str) -> bool {
+    query.starts_with(&format!("{}/", store_dir()))
 }
 
+/// Where `--fallback-host` looks for paths missing from the index, in order.
+const HOST_PREFIXES: &[&str] = &["/usr", "/"];
+
+/// Environment variable through which build processes announce their phase,
+/// e.g. `export BUILDXYZ_PHASE=configure` in a phase hook.
+pub const PHASE_VARIABLE: &str = "BUILDXYZ_PHASE";
+
+/// How often a pending lookup checks whether its requester is still alive.
+const INTERRUPT_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// How long the kernel may cache our answers.
+#[derive(Clone, Copy, Debug)]
+pub struct CacheTtls {
+    /// Served files and directories
+    pub entry: Duration,
+    /// ENOENTs, zero means they are never cached
+    pub negative: Duration,
+    /// Global FHS directories
+    pub directory: Duration,
+}
+
+impl Default for CacheTtls {
+    fn default() -> Self {
+        CacheTtls {
+            entry: Duration::from_secs(60 * 20),
+            negative: Duration::ZERO,
+            directory: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+impl CacheTtls {
+    /// Every answer is asked again to the filesystem.
+    pub fn disabled() -> Self {
+        CacheTtls {
+            entry: Duration::ZERO,
+            negative: Duration::ZERO,
+            directory: Duration::ZERO,
+        }
+    }
+}
+
+/// Index candidates by requested path, shared by the lookup workers.
+pub type CandidatesCache = Arc<Mutex<LruCache<String, Vec<(StorePath, FileTreeEntry)>>>>;
+
 pub struct BuildXYZ {
-    pub index_buffer: Vec<u8>,
-    pub popcount_buffer: Popcount,
+    /// indexes searched, the session one first, see `--index`
+    pub index: IndexSet,
+    /// requested path -> index candidates
+    pub query_cache: CandidatesCache,
+    /// requested path -> index candidates, found by earlier sessions
+    pub disk_query_cache: Arc<QueryCache>,
+    pub popcount_buffer: Arc<Popcount>,
     /// resolution information for this instance
     pub resolution_db: ResolutionDB,
+    /// pattern resolutions of the database, compiled on init
+    pub pattern_resolutions: PatternResolutions,
+    /// lowercase requested path -> key in the database, for case insensitive lookups
+    pub lowercase_resolutions: HashMap<String, String>,
     /// where to write this instance resolutions
     pub resolution_record_filepath: Option<PathBuf>,
+    /// provenance of the decisions of this instance
+    pub provenance: Provenance,
+    /// hits of the resolutions during this instance
+    pub usage: UsageCounter,
+    /// where to merge the hits of this instance, if anywhere
+    pub usage_stats_filepath: Option<PathBuf>,
+    /// answers to the resolutions to reconfirm during this instance, by requested path
+    pub reconfirmed: HashMap<String, bool>,
     /// recorded ENOENTs
-    pub recorded_enoent: HashSet<(u64, String)>,
+    pub recorded_enoent: HashSet<(Option<String>, u64, String)>,
+    /// (canonical path, phase) of the pending lookups -> lookups of the same path waiting for them
+    pub in_flight_lookups: HashMap<(String, Option<String>), Vec<ParkedLookup>>,
     pub global_dirs: HashMap<String, u64>,
-    /// "global path" -> inode
-    pub parent_prefixes: HashMap<u64, String>,
+    /// inode <-> "global path"
+    pub inodes: InodeTable,
+    /// directories derived from the provided paths
+    pub virtual_dirs: VirtualDirectories,
     /// inode -> "virtual paths"
     pub nix_paths: HashMap<u64, Vec<u8>>,
     /// inode -> "virtual foreign paths" (on another filesystem)
     pub redirections: HashMap<u64, Vec<u8>>,
     /// fast working tree for subgraph extraction
     pub fast_working_tree: PathBuf,
-    /// inode -> nix store paths
-    pub last_inode: RefCell<u64>,
-    /// Receiver channel for commands
-    pub recv_fs_event: Receiver<FsEventMessage>,
+    /// store paths shadowed in the fast working tree
+    pub fast_working_tree_store_paths: HashSet<String>,
+    /// rewrite served pkg-config files to point at the fast working tree
+    pub rewrite_pkgconfig: bool,
+    /// how leaves are materialized in the fast working tree
+    pub link_mode: LinkMode,
+    /// kernel cache durations for lookups
+    pub ttls: CacheTtls,
+    /// provide interpreters under `bin/` without prompting
+    pub interpreter_shims: bool,
+    /// redirect paths missing from the index to the host filesystem
+    pub fallback_host: bool,
+    /// match requested paths against the index and resolutions regardless of case
+    pub case_insensitive: bool,
+    /// retry paths without candidates with relaxed patterns before giving up on them
+    pub fuzzy_search: bool,
+    /// build phase of processes which do not announce one
+    pub phase: Option<String>,
+    /// where to record every lookup in order
+    pub access_trace: Option<AccessTrace>,
+    /// latest lookups of each process, to explain the prompts
+    pub recent_lookups: Arc<Mutex<RecentLookups>>,
+    /// realizes the served store paths, once for all the lookups waiting for them
+    pub realizer: Realizer,
+    /// tells the candidates which would be built locally, they are ranked last
+    pub substituters: Arc<SubstituterCheck>,
+    /// lists the files of store paths missing from the index
+    pub binary_cache: Arc<BinaryCacheSource>,
+    /// closure sizes the provided packages may add up to
+    pub budget: Arc<ClosureBudget>,
+    /// Routes the UI answers to the pending lookups
+    pub answers: AnswerRouter,
+    pub metrics: Arc<Metrics>,
     /// Sender channel for UI requests
     pub send_ui_event: Sender<UserRequest>,
+    /// Processes whose lookups are always ENOENT'd
+    pub ignored_processes: ProcessFilter,
+    /// Requested paths which are always ENOENT'd
+    pub blocklist: PathBlocklist,
+    /// decisions taken during this instance which can be undone, by request
+    pub session_decisions: HashMap<u64, SessionDecision>,
+    /// resolutions undone during this instance, removed from the record file on the next write
+    pub undone_resolutions: HashSet<String>,
+    /// decisions to undo, by request
+    pub undo_requests: Option<Receiver<u64>>,
+    /// build phases the session switches to, see `set_phase`
+    pub phase_requests: Option<Receiver<Option<String>>>,
+    /// decisions are only written to the record file once reviewed or confirmed, see `write_deferred_record`
+    pub defer_record: bool,
+    /// lookups no candidate answered during this instance, see `offer_missing_overlays`
+    pub missing_paths: Vec<MissingPath>,
+}
+
+/// What to revert to undo a decision taken during this instance.
+pub struct SessionDecision {
+    requested_path: String,
+    phase: Option<String>,
+    /// Package shadowed in the fast working tree for it, if provided
+    store_path: Option<String>,
+    /// Pattern resolution recorded along, see `record_subtree_resolution`
+    subtree_pattern: Option<String>,
+    /// Recorded ENOENT, if ignored
+    enoent_key: Option<(Option<String>, u64, String)>,
+    description: String,
+    requested_by: ProcessInfo,
+    /// Candidates the provided package was chosen among, to change it during the review
+    candidates: Vec<(StorePath, FileTreeEntry)>,
 }
 
 impl Default for BuildXYZ {
     fn default() -> Self {
-        // Those are useless channels.
-        let (_send, recv) = channel();
+        // This is a useless channel.
         let (send, _recv) = channel();
 
         BuildXYZ {
-            popcount_buffer: serde_json::from_slice(include_bytes!("../popcount-graph.json"))
-                .expect("Failed to deserialize the popcount graph"),
-            index_buffer: read_raw_buffer(std::io::Cursor::new(include_bytes!(
-                "../nix-index-files"
-            )))
-            .expect("Failed to deserialize the index buffer"),
+            popcount_buffer: Arc::new(Popcount::embedded()),
+            index: IndexSet::new(session_index()),
+            query_cache: Arc::new(Mutex::new(LruCache::new(NonZeroUsize::new(1024).unwrap()))),
+            disk_query_cache: Arc::new(QueryCache::disabled()),
             resolution_db: Default::default(),
+            pattern_resolutions: Default::default(),
+            lowercase_resolutions: Default::default(),
             resolution_record_filepath: Default::default(),
+            provenance: Default::default(),
+            usage: Default::default(),
+            usage_stats_filepath: None,
+            reconfirmed: HashMap::new(),
             recorded_enoent: HashSet::new(),
+            in_flight_lookups: HashMap::new(),
             global_dirs: HashMap::new(),
-            parent_prefixes: HashMap::new(),
+            inodes: Default::default(),
+            virtual_dirs: Default::default(),
             fast_working_tree: String::new().into(),
+            fast_working_tree_store_paths: HashSet::new(),
+            rewrite_pkgconfig: false,
+            link_mode: LinkMode::Symlink,
+            ttls: Default::default(),
+            interpreter_shims: false,
+            fallback_host: false,
+            case_insensitive: false,
+            fuzzy_search: true,
+            phase: None,
+            access_trace: None,
+            recent_lookups: Default::default(),
+            realizer: Default::default(),
+            substituters: Default::default(),
+            binary_cache: Default::default(),
+            budget: Default::default(),
             nix_paths: HashMap::new(),
             redirections: HashMap::new(),
-            last_inode: 2.into(),
-            recv_fs_event: recv,
+            answers: Default::default(),
+            metrics: Default::default(),
             send_ui_event: send,
+            ignored_processes: Default::default(),
+            blocklist: Default::default(),
+            session_decisions: HashMap::new(),
+            undone_resolutions: HashSet::new(),
+            undo_requests: None,
+            phase_requests: None,
+            defer_record: false,
+            missing_paths: Vec::new(),
         }
     }
 }
@@ -109,8 +486,19 @@ fn prompt_user(prompt: String) -> bool {
     }
 }
 
+/// Permissions of an entry of this kind whose target is unknown:
+/// the filesystem is read-only, directories must be traversable
+/// and symlink permissions are never checked.
+fn default_perm(kind: FileType) -> u16 {
+    match kind {
+        FileType::Directory => 0o555,
+        FileType::Symlink => 0o777,
+        _ => 0o444,
+    }
+}
+
 #[inline]
-fn build_fake_fattr(ino: u64, kind: FileType) -> FileAttr {
+pub fn build_fake_fattr(ino: u64, kind: FileType) -> FileAttr {
     fuser::FileAttr {
         kind,
         ino,
@@ -122,11 +510,41 @@ fn build_fake_fattr(ino: u64, kind: FileType) -> FileAttr {
         crtime: UNIX_EPOCH,
         ctime: UNIX_EPOCH,
         flags: 0,
-        uid: 0,
-        gid: 0,
+        uid: nix::unistd::getuid().as_raw(),
+        gid: nix::unistd::getgid().as_raw(),
         nlink: 1,
         rdev: 0,
-        perm: 777,
+        perm: default_perm(kind),
+    }
+}
+
+/// Attributes owned by the requester, so that permission checks of the build pass.
+pub fn owned_by(attribute: FileAttr, req: &RequestInfo) -> FileAttr {
+    FileAttr {
+        uid: req.uid,
+        gid: req.gid,
+        ..attribute
+    }
+}
+
+/// Attributes of what is actually served for this target.
+/// Anything but a directory is a symlink to it, whose own mode the kernel checks, e.g. its exec bits;
+/// directories mirror its permissions.
+fn served_attribute(attribute: FileAttr, target: &Path) -> FileAttr {
+    if attribute.kind != FileType::Directory {
+        return FileAttr {
+            kind: FileType::Symlink,
+            perm: default_perm(FileType::Symlink),
+            ..attribute
+        };
+    }
+
+    match std::fs::symlink_metadata(target) {
+        Ok(metadata) => FileAttr {
+            perm: (metadata.permissions().mode() & 0o7777) as u16,
+            ..attribute
+        },
+        Err(_) => attribute,
     }
 }
 
@@ -138,6 +556,15 @@ fn is_file_or_symlink<T>(n: &FileNode<T>) -> bool {
     }
 }
 
+/// Whether `candidate` can be served in place of `served`: directories only replace directories,
+/// files only files, symlinks either.
+fn same_kind<T, U>(served: &FileNode<T>, candidate: &FileNode<U>) -> bool {
+    match served {
+        FileNode::Directory { .. } => is_dir(candidate),
+        _ => is_file_or_symlink(candidate),
+    }
+}
+
 fn is_dir<T>(n: &FileNode<T>) -> bool {
     // FIXME
     // investigate interaction with symlinkJoin
@@ -166,25 +593,26 @@ impl<T> Into<fuser::FileAttr> for FileNode<T> {
 /// according to the sort function order
 /// and return the best
 /// It will perform some debug asserts on the list.
-fn extract_optimal_path<F>(
+fn extract_optimal_path<F, K>(
     candidates: &mut Vec<(StorePath, FileTreeEntry)>,
     sort_key_function: F,
 ) -> (&StorePath, &FileTreeEntry)
 where
-    F: FnMut(&(StorePath, FileTreeEntry)) -> i32,
+    F: FnMut(&(StorePath, FileTreeEntry)) -> K,
+    K: Ord,
 {
-    // 1. There cannot be a folder and a file at the same time in `candidates`
-    debug_assert!(
-        candidates
-            .into_iter()
-            .all(|(_, c)| is_file_or_symlink(&c.node))
-            || candidates.into_iter().all(|(_, c)| is_dir(&c.node)),
-        "either candidates are all directories, either all files, not in-between."
-    );
-
     // FIXME: is it enough for the ranking algorithm?
     candidates.sort_by_cached_key(sort_key_function);
 
+    // 1. There cannot be a folder and a file at the same time in `candidates`,
+    // the best one decides which kind is served.
+    if !candidates.iter().all(|(_, c)| is_file_or_symlink(&c.node))
+        && !candidates.iter().all(|(_, c)| is_dir(&c.node))
+    {
+        let best = candidates[0].1.node.clone();
+        candidates.retain(|(_, c)| same_kind(&best, &c.node));
+    }
+
     let (store_path, ft_entry) = candidates.first().unwrap();
 
     (store_path, ft_entry)
@@ -204,14 +632,112 @@ where
     (store_path, fattr, nix_path.as_os_str().as_bytes().to_vec())*/
 }
 
-/// This will create all the directories and symlink only the leaves.
+/// Maximum number of nested symlinked directories followed by `shadow_symlink_leaves`.
+const MAX_SHADOW_DEPTH: usize = 32;
+/// Maximum length of a symlink chain, mirroring the kernel's ELOOP limit.
+const MAX_SYMLINK_HOPS: usize = 40;
+
+#[derive(thiserror::Error, Debug)]
+pub enum ShadowSymlinkError {
+    #[error("I/O error while shadowing {0}: {1}")]
+    Io(PathBuf, std::io::Error),
+    #[error("symlinked directories nested deeper than {1} levels at {0}")]
+    TooDeep(PathBuf, usize),
+    #[error("symlink chain longer than {1} hops starting at {0}")]
+    SymlinkLoop(PathBuf, usize),
+}
+
+/// Follow a symlink chain until a non-symlink is reached.
+/// Relative links are resolved against the directory of the link.
+fn resolve_symlink_chain(link: &Path) -> Result<PathBuf, ShadowSymlinkError> {
+    let mut current = link.to_path_buf();
+
+    for _ in 0..MAX_SYMLINK_HOPS {
+        if !current.is_symlink() {
+            return Ok(current);
+        }
+
+        let target = std::fs::read_link(&current)
+            .map_err(|err| ShadowSymlinkError::Io(current.clone(), err))?;
+        trace!("--> {}", target.display());
+        current = if target.is_relative() {
+            current
+                .parent()
+                .expect("Expected a symlink parented by at least /")
+                .join(target)
+        } else {
+            // Links to other store paths name them in the store directory, wherever it is.
+            PathBuf::from(real_path(&target.to_string_lossy()))
+        };
+    }
+
+    Err(ShadowSymlinkError::SymlinkLoop(link.to_path_buf(), MAX_SYMLINK_HOPS))
+}
+
+/// Shadow the leaves of a store path in the fast working tree, see `shadow_symlink_leaves`.
+/// Lookup workers may shadow packages sharing leaves at the same time, the first link wins.
+fn shadow_store_path(fast_working_tree: &Path, store_path: &StorePath, link_mode: LinkMode) {
+    let npath = PathBuf::from(real_path(&store_path.as_str()));
+    debug!("Shadow symlinking all the leaves {} -> {}", npath.display(), fast_working_tree.display());
+    // We do not want to symlink nix-support
+    if let Err(err) = shadow_symlink_leaves(&npath, fast_working_tree, &vec![
+        "nix-support"
+    ], &mut HashSet::new(), 0, link_mode) {
+        warn!(
+            "Failed to shadow symlink {} inside the fast working tree, potential incompatibility: {}",
+            npath.display(),
+            err
+        );
+    }
+}
+
+/// Materialize a leaf of a store path in the fast working tree.
+fn link_leaf(src: &Path, target: &Path, link_mode: LinkMode) -> std::io::Result<()> {
+    let linked = match link_mode {
+        LinkMode::Symlink => std::os::unix::fs::symlink(src, target),
+        LinkMode::Hardlink => std::fs::hard_link(src, target).or_else(|err| {
+            if err.raw_os_error() == Some(nix::errno::Errno::EXDEV as i32) {
+                trace!("{} lives on another filesystem, copying it instead", src.display());
+                std::fs::copy(src, target).map(|_| ())
+            } else {
+                Err(err)
+            }
+        }),
+        LinkMode::Copy => std::fs::copy(src, target).map(|_| ()),
+    };
+    // Another package, shadowed at the same time, got there first.
+    match linked {
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => Ok(()),
+        linked => linked,
+    }
+}
+
+/// This will create all the directories and link only the leaves.
 /// It will fail in case of incompatibility.
-fn shadow_symlink_leaves(src_dir: &Path, target_dir: &Path, excluded_dirs: &Vec<&str>, already_seen: &mut HashSet<PathBuf>) -> std::io::Result<()> {
+/// Symlinked directories are recursed into at most `MAX_SHADOW_DEPTH` levels deep
+/// and never twice, so cyclic symlinks cannot loop forever.
+fn shadow_symlink_leaves(
+    src_dir: &Path,
+    target_dir: &Path,
+    excluded_dirs: &Vec<&str>,
+    already_seen: &mut HashSet<PathBuf>,
+    depth: usize,
+    link_mode: LinkMode,
+) -> Result<(), ShadowSymlinkError> {
+    if depth > MAX_SHADOW_DEPTH {
+        return Err(ShadowSymlinkError::TooDeep(src_dir.to_path_buf(), MAX_SHADOW_DEPTH));
+    }
+
+    let io_error = |path: &Path| {
+        let path = path.to_path_buf();
+        move |err| ShadowSymlinkError::Io(path, err)
+    };
+
     // Do not follow symlinks
     // Otherwise, you will get an entry.path() which does not share a base prefix with src_dir
     // Therefore, you don't know where to send it.
     // Symlink compression should be done only at the end as an optimization if needed.
-    already_seen.insert(src_dir.canonicalize().expect("Failed to canonicalize the source path for cycle detection").into());
+    already_seen.insert(src_dir.canonicalize().map_err(io_error(src_dir))?);
     trace!("shadow symlinking {} -> {}...", src_dir.display(), target_dir.display());
     for entry in WalkDir::new(src_dir).follow_links(false).into_iter().filter_map(|e| e.ok()) {
         // ensure target_dir.join(entry modulo src_dir) is a directory
@@ -236,34 +762,22 @@ fn shadow_symlink_leaves(src_dir: &Path, target_dir: &Path, excluded_dirs: &Vec<
 
         if ft.is_dir() {
             trace!("mkdir -p {} based on {}", target_path.display(), entry.path().display());
-            std::fs::create_dir_all(target_path)?;
+            std::fs::create_dir_all(&target_path).map_err(io_error(&target_path))?;
         } else if ft.is_file() {
-            trace!("symlink {} -> {}", entry.path().display(), target_path.display());
-            std::os::unix::fs::symlink(entry.path(), target_path)?;
+            trace!("{} {} -> {}", link_mode.as_str(), entry.path().display(), target_path.display());
+            link_leaf(entry.path(), &target_path, link_mode).map_err(io_error(&target_path))?;
         } else if ft.is_symlink() {
             // Two things has to be done
             // 1. Resolve completely the entry into resolved_target
             // 2. Recurse on resolved_target -> target_path
             // 2. Symlink target_path -> resolved_target
-            let mut resolved_target = std::fs::read_link(entry.path())?;
-            trace!("resolve {} -> {}", entry.path().display(), resolved_target.display());
-            while resolved_target.is_symlink() {
-                resolved_target = std::fs::read_link(resolved_target.as_path())?;
-                trace!("--> {}", resolved_target.display());
-            }
-            // Now, `resolved_target` is completely resolved.
-            // Either, it's relative, either it's absolute.
-            // If it's relative, we correct it to an absolute link, by concatenating
-            // $src_dir/$resolved_target.
-            // If it's absolute, we proceed to recurse into it.
-            if resolved_target.is_relative() {
-                resolved_target = entry.path().parent().expect("Expected a symlink parented by at least /").join(resolved_target);
-            }
+            trace!("resolve {}", entry.path().display());
+            let resolved_target = resolve_symlink_chain(entry.path())?;
             trace!("encountered an internal symlink: {} -> {}, symlinking or recursing depending on file type", entry.path().display(), resolved_target.display());
             // If it's a dir, recurse the symlinkage
             if resolved_target.is_dir() {
                 trace!("recursing into the symlink {} -> {} for directory symlinkage", entry.path().display(), resolved_target.display());
-                if already_seen.contains(&resolved_target.canonicalize().expect("Failed to canonicalize the resolved target")) {
+                if already_seen.contains(&resolved_target.canonicalize().map_err(io_error(&resolved_target))?) {
                     trace!("… but this source path {} was already seen, skipping.", entry.path().display());
                     continue;
                 }
@@ -272,12 +786,20 @@ fn shadow_symlink_leaves(src_dir: &Path, target_dir: &Path, excluded_dirs: &Vec<
                     &resolved_target,
                     &target_path,
                     excluded_dirs,
-                    already_seen
+                    already_seen,
+                    depth + 1,
+                    link_mode,
                 )?;
             }
             else if resolved_target.is_file() {
-                trace!("symlink ({} ->) {} -> {}", entry.path().display(), resolved_target.display(), target_path.display());
-                std::os::unix::fs::symlink(entry.path(), target_path)?;
+                trace!("{} ({} ->) {} -> {}", link_mode.as_str(), entry.path().display(), resolved_target.display(), target_path.display());
+                // Symlinks keep pointing at the original link, other modes need the actual file,
+                // and so do relocated stores where the link may name the store directory.
+                let src = match link_mode {
+                    LinkMode::Symlink if !store_is_relocated() => entry.path(),
+                    _ => &resolved_target,
+                };
+                link_leaf(src, &target_path, link_mode).map_err(io_error(&target_path))?;
             }
         }
     }
@@ -285,384 +807,3099 @@ fn shadow_symlink_leaves(src_dir: &Path, target_dir: &Path, excluded_dirs: &Vec<
     Ok(())
 }
 
-impl BuildXYZ {
-    fn allocate_inode(&self) -> u64 {
-        *self.last_inode.borrow_mut() += 1;
-        *self.last_inode.borrow() - 1
+/// Answer an extended attribute request, `size` 0 asks for the size only.
+fn reply_xattr(reply: fuser::ReplyXattr, size: u32, data: &[u8]) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if data.len() > size as usize {
+        reply.error(nix::errno::Errno::ERANGE as i32);
+    } else {
+        reply.data(data);
     }
+}
 
-    fn build_in_construction_path(&self, parent: u64, name: &OsStr) -> PathBuf {
-        let prefix = Path::new(
-            self.parent_prefixes
-                .get(&parent)
-                .expect("Unknown parent inode!"),
-        );
-
-        prefix.join(name)
-    }
+/// Path of the host filesystem providing this requested path, if any.
+fn find_on_host(requested_path: &Path) -> Option<PathBuf> {
+    HOST_PREFIXES
+        .iter()
+        .map(|prefix| Path::new(prefix).join(requested_path))
+        .find(|host_path| host_path.exists())
+}
 
-    fn record_resolution(&mut self, parent: u64, name: &OsStr, decision: Decision) {
-        let current_path = self
-            .build_in_construction_path(parent, name)
-            .to_string_lossy()
-            .to_string();
-        trace!("Recording {} for {:?}", current_path, decision);
-        self.resolution_db.insert(
-            current_path.clone(),
-            Resolution::ConstantResolution(crate::resolution::ResolutionData {
-                requested_path: current_path,
-                decision,
-            }),
-        );
+/// Kind of the file at `file_entry_name` in a store path, realized first. `None` if it has no such file.
+fn kind_in_store_path(store_path: &StorePath, file_entry_name: &str) -> Option<FileType> {
+    if let Err(err) = realize_path(store_path.as_str().to_string()) {
+        warn!("Failed to realize {}: {}", store_path.as_str(), err);
+        return None;
     }
+    let path = Path::new(&real_path(&store_path.as_str())).join(file_entry_name.trim_start_matches('/'));
+    let file_type = std::fs::symlink_metadata(path).ok()?.file_type();
+
+    Some(if file_type.is_symlink() {
+        FileType::Symlink
+    } else if file_type.is_dir() {
+        FileType::Directory
+    } else {
+        FileType::RegularFile
+    })
+}
 
-    fn get_resolution(&self, parent: u64, name: &OsStr) -> Option<&Resolution> {
-        let current_path = self
-            .build_in_construction_path(parent, name)
-            .to_string_lossy()
-            .to_string();
-        self.resolution_db.get(&current_path)
-    }
+/// Directory whose lookups a prompt can answer at once, e.g. `include/boost` for `include/boost/version.hpp`:
+/// the first two components of paths at least three deep, `include/` itself is too broad.
+fn subtree_of(requested_path: &Path) -> Option<PathBuf> {
+    let components: Vec<_> = requested_path.components().collect();
+    (components.len() >= 3).then(|| components[..2].iter().collect())
+}
 
-    fn get_decision(&self, parent: u64, name: &OsStr) -> Option<&Decision> {
-        match self.get_resolution(parent, name) {
-            Some(Resolution::ConstantResolution(data)) => Some(&data.decision),
-            _ => None,
+/// Realize a store path or a flake installable typed by the user
+/// and make a candidate of its output providing `requested_path`, if any.
+fn realized_candidate(
+    requested_path: &Path,
+    query: &str,
+    binary_cache: &BinaryCacheSource,
+) -> Option<(StorePath, FileTreeEntry)> {
+    let (attr, roots) = if is_store_path_query(query) {
+        // Only the store path itself, not a file in it.
+        let root = store_path_root(query).to_string();
+        let name = root.split_once('-').map_or(root.as_str(), |(_, name)| name);
+        // Not realized for nothing when a binary cache lists its files.
+        let pattern = Regex::new(&format!(r"^/{}$", regex::escape(&requested_path.to_string_lossy())))
+            .expect("An escaped path is a valid regex");
+        let listed = StorePath::parse(flake::origin_of(name), &root)
+            .and_then(|store_path| binary_cache.files_of(&store_path, &pattern));
+        if listed.map_or(false, |files| files.is_empty()) {
+            warn!("{} does not provide {}, as listed by the binary cache", root, requested_path.display());
+            return None;
         }
+        if let Err(err) = realize_path(root.clone()) {
+            warn!("Failed to realize {}: {}", root, err);
+            return None;
+        }
+        (name.to_string(), vec![root.clone()])
+    } else {
+        match FlakeOutputs::shared().out_paths(query) {
+            Ok(roots) => (query.to_string(), roots),
+            Err(err) => {
+                warn!("Failed to build {}: {}", query, err);
+                return None;
+            }
+        }
+    };
+
+    for root in roots {
+        let provided = Path::new(&real_path(&root)).join(requested_path);
+        let node = match std::fs::symlink_metadata(&provided) {
+            Ok(metadata) if metadata.file_type().is_symlink() => FileNode::Symlink {
+                target: std::fs::read_link(&provided)
+                    .ok()?
+                    .into_os_string()
+                    .into_vec()
+                    .into(),
+            },
+            Ok(metadata) if metadata.is_dir() => FileNode::Directory {
+                size: std::fs::read_dir(&provided).map_or(0, |entries| entries.count() as u64),
+                contents: (),
+            },
+            Ok(metadata) => FileNode::Regular {
+                size: metadata.len(),
+                executable: metadata.permissions().mode() & 0o111 != 0,
+            },
+            Err(_) => continue,
+        };
+        let origin = PathOrigin {
+            attr: attr.clone(),
+            output: "out".to_string(),
+            toplevel: true,
+            system: None,
+            index: None,
+        };
+        let store_path = StorePath::parse(origin, &root)?;
+
+        return Some((
+            store_path,
+            FileTreeEntry {
+                path: format!("/{}", requested_path.display()).into_bytes(),
+                node,
+            },
+        ));
     }
-    
-    // Shadow symlink in the fast working tree
-    // this Nix path
-    fn extend_fast_working_tree(
-        &mut self,
-        store_path: &StorePath
-    ) {
-        let npath: PathBuf = OsString::from_vec(store_path.as_str().as_bytes().to_vec()).into();
-        debug!("Shadow symlinking all the leaves {} -> {}", npath.display(), self.fast_working_tree.display());
-        // We do not want to symlink nix-support
-        shadow_symlink_leaves(&npath, &self.fast_working_tree, &vec![
-            "nix-support"
-        ], &mut HashSet::new())
-            .expect("Failed to shadow symlink the Nix path inside the fast working tree, potential incompatibility");
-    }
-
-    /// Serve the path as an answer to the filesystem
-    /// It realizes the Nix path if it's not already.
-    fn serve_path(
-        &mut self,
-        nix_path: Vec<u8>,
-        requested_path: PathBuf,
-        attribute: fuser::FileAttr,
-        reply: fuser::ReplyEntry,
-    ) {
-        let nix_path_as_str = String::from_utf8_lossy(&nix_path);
-        trace!("{}: {:?}", nix_path_as_str, attribute);
-        self.parent_prefixes
-            .insert(attribute.ino, requested_path.to_string_lossy().to_string());
-
-        realize_path(nix_path_as_str.into())
-            .expect("Nix path should be realized, database seems incoherent with Nix store.");
-
-        self.nix_paths.insert(attribute.ino, nix_path);
 
-        reply.entry(&Duration::from_secs(60 * 20), &attribute, attribute.ino);
-    }
+    warn!("{} does not provide {}", query, requested_path.display());
+    None
+}
 
-    /// Redirect to a filesystem file
-    /// via symlink
-    fn redirect_to_fs(
-        &mut self,
-        reply: fuser::ReplyEntry,
-        onfs_path: PathBuf
-    ) {
-        trace!("redirecting to {} on another filesystem", onfs_path.display());
+/// Everything needed to search the index and prompt the user
+/// without holding the filesystem, cheap to clone.
+#[derive(Clone)]
+pub struct SearchHandles {
+    index: IndexSet,
+    query_cache: CandidatesCache,
+    disk_query_cache: Arc<QueryCache>,
+    popcount_buffer: Arc<Popcount>,
+    send_ui_event: Sender<UserRequest>,
+    answers: AnswerRouter,
+    recent_lookups: Arc<Mutex<RecentLookups>>,
+    realizer: Realizer,
+    substituters: Arc<SubstituterCheck>,
+    binary_cache: Arc<BinaryCacheSource>,
+    budget: Arc<ClosureBudget>,
+    metrics: Arc<Metrics>,
+    fast_working_tree: PathBuf,
+    link_mode: LinkMode,
+    interpreter_shims: bool,
+    case_insensitive: bool,
+    fuzzy_search: bool,
+}
 
-        let ft_attribute = build_fake_fattr(self.allocate_inode(),
-            fuser::FileType::Symlink);
-        self.redirections.insert(ft_attribute.ino, onfs_path.to_string_lossy().as_bytes().to_vec());
-        reply.entry(&Duration::from_secs(60 * 20), &ft_attribute, ft_attribute.ino);
+impl SearchHandles {
+    fn compile(&self, pattern: &str) -> Regex {
+        if self.case_insensitive {
+            Regex::new(&format!("(?i){}", pattern)).unwrap()
+        } else {
+            Regex::new(pattern).unwrap()
+        }
     }
 
     /// Runs a query using our index
-    fn search_in_index(&self, requested_path: &PathBuf) -> Vec<(StorePath, FileTreeEntry)> {
-        let escaped_path = regex::escape(&requested_path.to_string_lossy());
-        debug!(
-            "looking for: `{}$` in Nix database",
-            requested_path.to_string_lossy(),
-        );
+    /// Propagated outputs are only considered if `toplevel_only` is false.
+    fn query_index(&self, pattern: &str, toplevel_only: bool) -> Vec<(StorePath, FileTreeEntry)> {
+        debug!("looking for: `{}` in Nix database", pattern);
         let now = Instant::now();
-        // TODO: put me behind Arc
-        let db = Reader::from_buffer(self.index_buffer.clone()).expect("Failed to open database");
 
-        let candidates: Vec<(StorePath, FileTreeEntry)> = db
-            .query(&Regex::new(format!(r"^/{}$", escaped_path).as_str()).unwrap())
-            .run()
-            .expect("Failed to query the database")
+        let candidates: Vec<(StorePath, FileTreeEntry)> = self
+            .index
+            .search(&self.compile(pattern))
             .into_iter()
-            .map(|result| result.expect("Failed to obtain candidate"))
-            .filter(|(spath, _)| spath.origin().toplevel) // It must be a top-level path, otherwise
+            .filter(|(spath, _)| !toplevel_only || spath.origin().toplevel) // It must be a top-level path, otherwise
             // it is propagated, so not to consider.
             .collect();
         trace!("{:?}", candidates);
         debug!("search took {:.2?}", now.elapsed());
+        self.metrics.index_query_latency.observe(now.elapsed());
 
         candidates
     }
 
-    /// Register known "FHS" structure
-    /// Assume parents are already created.
-    fn mkdir_fhs_directory(&mut self, path: &str) {
-        let inode = self.allocate_inode();
-        self.parent_prefixes.insert(inode, path.to_string());
-        self.global_dirs.insert(path.to_string(), inode);
-    }
-}
-
-// Allow parallel calls to lookup() as it should be fine.
-const FUSE_CAP_PARALLEL_DIROPS: u32 = 1 << 18;
-// Cache the symlinks we provide in the page cache.
-const FUSE_CAP_CACHE_SYMLINKS: u32 = 1 << 23;
-
-impl Filesystem for BuildXYZ {
-    fn init(
-        &mut self,
-        _req: &fuser::Request<'_>,
-        config: &mut fuser::KernelConfig,
-    ) -> Result<(), i32> {
-        // https://www.kernel.org/doc/html/latest/filesystems/fuse.html
-        // https://libfuse.github.io/doxygen/fuse__common_8h.html
-        config
-            .add_capabilities(FUSE_CAP_PARALLEL_DIROPS)
-            .map_err(|err| -(err as i32))?;
-        self.parent_prefixes.insert(1, "".to_string());
-        // Create bin, lib, include, pkg-config inodes
-        // TODO: Keep this list synchronized with created search paths in runner.rs?
-        [
-            "bin",
-            "include",
-            "perl",
-            "aclocal",
-            "cmake",
-            "lib",
-            "lib/pkgconfig",
-        ]
-        .into_iter()
-        .for_each(|c| self.mkdir_fhs_directory(c));
-
-        info!(
-            "Loaded {} resolutions from the database.",
-            self.resolution_db.len()
-        );
+    /// Search candidates for this requested path.
+    /// Shared libraries are also matched against their other versions,
+    /// e.g. `lib/libfoo.so` can be provided by `lib/libfoo.so.1.2.3`.
+    /// Results are cached, the index never changes during a session.
+    /// The ones of the index are also kept on disk for the next sessions searching the same indexes.
+    fn search_in_index(&self, requested_path: &Path) -> Vec<(StorePath, FileTreeEntry)> {
+        let requested_path = requested_path.to_string_lossy().to_string();
+        let requested_path = if self.case_insensitive {
+            requested_path.to_lowercase()
+        } else {
+            requested_path
+        };
+        if let Some(candidates) = self.query_cache.lock().unwrap().get(&requested_path) {
+            trace!("query cache hit for {}", requested_path);
+            return candidates.clone();
+        }
 
-        let store_paths = self.resolution_db
-            .values()
-            .filter_map(|resolution| {
-                debug!("store path: {:?}", resolution);
-                match resolution {
-                    Resolution::ConstantResolution(data) => {
-                        if let Decision::Provide(provide_data) = &data.decision {
-                            return Some(provide_data.store_path.clone());
-                        }
+        let exact_pattern = format!(r"^/{}$", regex::escape(&requested_path));
+        let mut candidates = match self.disk_query_cache.get(&requested_path) {
+            Some(candidates) => candidates,
+            None => {
+                let mut candidates = self.query_index(&exact_pattern, true);
+                if candidates.is_empty() {
+                    if let Some(pattern) = library::any_version_pattern(&requested_path) {
+                        debug!("no exact match for the shared library, trying other versions");
+                        candidates = self.query_index(&pattern, true);
                     }
                 }
+                // The binary caches may know more store paths next time, their answers are not kept.
+                self.disk_query_cache.put(&requested_path, &candidates);
+                candidates
+            }
+        };
 
-                None
-            })
-        .collect::<Vec<StorePath>>();
+        if candidates.is_empty() && self.binary_cache.is_enabled() {
+            debug!("not in the index, asking the binary caches about the known store paths");
+            candidates = self.binary_cache.search(&self.compile(&exact_pattern));
+        }
 
-        info!(
-            "Will fast extend {} store paths.",
-            store_paths.len()
-        );
+        self.query_cache
+            .lock()
+            .unwrap()
+            .put(requested_path, candidates.clone());
+        candidates
+    }
 
-        for spath in store_paths {
-            debug!("{} being extended in the working tree", spath.as_str());
-            self.extend_fast_working_tree(&spath);
+    /// Retry a path without candidates with relaxed patterns, from the most to the least specific:
+    /// any version in place of the requested ones, the exact path in propagated outputs,
+    /// the same file name in any directory. Only the best ranked candidates are kept.
+    fn fuzzy_search_in_index(&self, target_path: &Path) -> Vec<(StorePath, FileTreeEntry)> {
+        let requested_path = target_path.to_string_lossy();
+        let searches = fuzzy::versionless_pattern(&requested_path)
+            .map(|pattern| (pattern, true))
+            .into_iter()
+            .chain(iter::once((format!(r"^/{}$", regex::escape(&requested_path)), false)))
+            .chain(fuzzy::basename_pattern(&requested_path).map(|pattern| (pattern, true)));
+
+        for (pattern, toplevel_only) in searches {
+            let mut candidates = self.query_index(&pattern, toplevel_only);
+            if !candidates.is_empty() {
+                debug!("{} fuzzy candidates for {}", candidates.len(), requested_path);
+                candidates.sort_by_cached_key(|candidate| self.rank(target_path, candidate));
+                candidates.truncate(MAX_FUZZY_CANDIDATES);
+                return candidates;
+            }
         }
 
-        info!(
-            "Fast working tree ready based on the resolutions."
-        );
+        Vec::new()
+    }
 
-        Ok(())
+    /// Candidates for a query typed by the user when none of the proposed ones fits:
+    /// a store path or a flake installable (`nixpkgs#zlib`) is realized and offered if it has the requested path,
+    /// a query with a `/` is a pattern over the paths of the index,
+    /// anything else an attribute name, matched by prefix, having the same file name in any directory.
+    fn manual_search(&self, requested_path: &Path, query: &str) -> Vec<(StorePath, FileTreeEntry)> {
+        info!("Looking up `{}` for {}", query, requested_path.display());
+        if is_store_path_query(query) || query.contains('#') {
+            let candidate = realized_candidate(requested_path, query, &self.binary_cache);
+            if let Some((store_path, _)) = &candidate {
+                self.binary_cache.track(store_path);
+            }
+            return candidate.into_iter().collect();
+        }
+
+        let mut candidates = if query.contains('/') {
+            if let Err(err) = Regex::new(query) {
+                warn!("`{}` is not a valid pattern: {}", query, err);
+                return Vec::new();
+            }
+            self.query_index(query, true)
+        } else {
+            let requested_path = requested_path.to_string_lossy();
+            let pattern = fuzzy::basename_pattern(&requested_path)
+                .unwrap_or_else(|| format!(r"^/{}$", regex::escape(&requested_path)));
+            self.query_index(&pattern, true)
+                .into_iter()
+                .filter(|(store_path, _)| store_path.origin().attr.starts_with(query))
+                .collect()
+        };
+        candidates.sort_by_key(|(store_path, _)| -self.popularity(store_path));
+        candidates.truncate(MAX_FUZZY_CANDIDATES);
+
+        candidates
     }
 
-    fn destroy(&mut self) {
-        if let Some(filepath) = &self.resolution_record_filepath {
-            debug!(
-                "Writing {} resolutions on disk...",
-                self.resolution_db.len()
-            );
-            // Write this resolution on disk.
-            std::fs::write(
-                filepath,
-                toml::to_string_pretty(&db_to_human_toml(&self.resolution_db))
-                    .expect("Failed to serialize in a human-way the resolution database"),
-            )
-            .expect("Failed to write resolution data");
+    /// Where a candidate for this requested path comes in prompts, the first one is suggested.
+    fn rank(&self, requested_path: &Path, (store_path, ft_entry): &(StorePath, FileTreeEntry)) -> Ranking {
+        // Exact matches come first, then the closest shared library versions.
+        let (requested, candidate) = (
+            requested_path.to_string_lossy(),
+            String::from_utf8_lossy(&ft_entry.path),
+        );
+        let path_match = if self.case_insensitive {
+            library::match_rank(&requested.to_lowercase(), &candidate.to_lowercase())
+        } else {
+            library::match_rank(&requested, &candidate)
+        };
+        trace!(
+            "extracting pop for {}: {}",
+            store_path.as_str(),
+            store_path.origin().attr
+        );
+        // Split packages provide the path from several outputs, prefer the expected one.
+        let output = outputs::output_rank(&requested, &store_path.origin().output);
+        // Highest popularity comes first, so inverted popularity works here.
+        let inverted_popularity = -self.popularity(store_path);
+        trace!("pop: {inverted_popularity}");
+
+        Ranking {
+            path_match,
+            must_build: self.substituters.must_build(store_path),
+            output,
+            inverted_popularity,
+            propagated: !store_path.origin().toplevel,
         }
     }
 
-    fn lookup(
-        &mut self,
-        _req: &fuser::Request<'_>,
-        parent: u64,
-        name: &OsStr,
-        reply: fuser::ReplyEntry,
-    ) {
-        let target_path = self.build_in_construction_path(parent, name);
+    fn popularity(&self, store_path: &StorePath) -> i32 {
+        *self
+            .popcount_buffer
+            .native_build_inputs
+            .get(&store_path.as_str().to_string())
+            .unwrap_or(&0) as i32
+    }
 
-        // global directory
-        if let Some(inode) = self
-            .global_dirs
-            .get(&target_path.to_string_lossy().to_string())
-        {
-            trace!(
-                "global directory hit: {}",
-                &target_path.to_string_lossy().to_string()
-            );
-            reply.entry(
-                &Duration::from_secs(60 * 60),
-                &build_fake_fattr(*inode, FileType::Directory),
-                *inode,
-            );
-            return;
+    /// Why this lookup is prompted, with the FUSE request behind it.
+    fn explain(&self, pending: &PendingLookup) -> Explanation {
+        let req = &pending.req;
+        Explanation::new(
+            pending.target_path.clone(),
+            pending.requester.clone(),
+            format!(
+                "LOOKUP parent {}, name {:?} (unique {}, pid {}, uid {}, gid {})",
+                pending.parent, pending.name, req.unique, req.pid, req.uid, req.gid
+            ),
+            self.recent_lookups
+                .lock()
+                .unwrap()
+                .of(pending.requester.pid),
+        )
+    }
+
+    /// Wait for the UI to answer the prompt of this lookup, explaining it when asked.
+    /// FUSE interrupts are not forwarded to us, so we watch the requesting process instead:
+    /// if it dies, or gets a signal which makes the kernel interrupt its request,
+    /// the prompt is cancelled and the lookup interrupted.
+    fn wait_for_answer(&self, pending: &PendingLookup) -> PromptOutcome {
+        let (request_id, requester) = (pending.req.unique, &pending.requester);
+        let answers = self.answers.register(request_id);
+        let now = Instant::now();
+        let outcome = loop {
+            match answers.recv_timeout(INTERRUPT_POLL_INTERVAL) {
+                Ok(FsEventMessage::PackageSuggestion(id, (pkg, ft_entry))) if id == request_id => {
+                    break PromptOutcome::Provide(Box::new(pkg), ft_entry, false)
+                }
+                Ok(FsEventMessage::SubtreeSuggestion(id, (pkg, ft_entry))) if id == request_id => {
+                    break PromptOutcome::Provide(Box::new(pkg), ft_entry, true)
+                }
+                Ok(FsEventMessage::IgnoreRequest(id)) if id == request_id => {
+                    break PromptOutcome::Ignore
+                }
+                Ok(FsEventMessage::SkipForNow(id)) if id == request_id => break PromptOutcome::SkipForNow,
+                Ok(FsEventMessage::Confirm(id)) if id == request_id => break PromptOutcome::Confirm,
+                Ok(FsEventMessage::ManualSearch(id, query)) if id == request_id => {
+                    break PromptOutcome::Search(query)
+                }
+                Ok(FsEventMessage::Explain(id)) if id == request_id => {
+                    let _ = self
+                        .send_ui_event
+                        .send(UserRequest::Explanation(request_id, self.explain(pending)));
+                }
+                Ok(FsEventMessage::IgnorePendingRequests) => break PromptOutcome::Ignore,
+                Ok(_) => trace!("discarding an answer to another request"),
+                Err(RecvTimeoutError::Timeout) => {
+                    if !requester.is_alive() || requester.has_pending_signal() {
+                        debug!("{} went away, interrupting request {}", requester, request_id);
+                        let _ = self.send_ui_event.send(UserRequest::Cancel(request_id));
+                        break PromptOutcome::Interrupted;
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => break PromptOutcome::Ignore,
+            }
+        };
+        self.answers.unregister(request_id);
+        self.metrics.prompt_wait.observe(now.elapsed());
+
+        outcome
+    }
+
+    /// Search the index for this lookup, ask the user which candidate to provide
+    /// and realize it. This is the slow part of a lookup.
+    pub fn search(&self, pending: &PendingLookup) -> SearchOutcome {
+        let target_path = &pending.target_path;
+        let requester = &pending.requester;
+
+        debug!(
+            "{} requested by {}",
+            target_path.display(),
+            requester
+        );
+
+        if let Some(data) = &pending.resolved {
+            return self.realize_resolved(&pending.target_path, data);
         }
 
-        // No other global directories.
-        if parent == 1 {
-            return reply.error(nix::errno::Errno::ENOENT as i32);
+        if let Some(description) = &pending.reconfirm {
+            let request_id = pending.req.unique;
+            self.send_ui_event
+                .send(UserRequest::Reconfirm(
+                    request_id,
+                    target_path.clone(),
+                    description.clone(),
+                    requester.clone(),
+                ))
+                .expect("Failed to send UI thread a message");
+
+            return match self.wait_for_answer(pending) {
+                PromptOutcome::Confirm => SearchOutcome::Confirmed,
+                PromptOutcome::Interrupted => SearchOutcome::Interrupted,
+                PromptOutcome::SkipForNow => SearchOutcome::SkipForNow,
+                PromptOutcome::Provide(..) | PromptOutcome::Ignore | PromptOutcome::Search(_) => {
+                    SearchOutcome::Ignore
+                }
+            };
         }
 
-        // Fast path: ignore temporarily recorded ENOENTs.
-        if self
-            .recorded_enoent
-            .contains(&(parent, name.to_string_lossy().to_string()))
-        {
-            return reply.error(nix::errno::Errno::ENOENT as i32);
+        let mut candidates = self.search_in_index(target_path);
+        let fuzzy = candidates.is_empty() && self.fuzzy_search;
+        if fuzzy {
+            candidates = self.fuzzy_search_in_index(target_path);
         }
 
-        // Fast path: fast working tree
-        // Rebase the target path based on the working tree structure
-        if self.fast_working_tree.join(&target_path).exists() {
-            trace!("FAST PATH — Path already exist in the fast working tree");
-            return self.redirect_to_fs(reply, self.fast_working_tree.join(target_path));
+        if candidates.is_empty() {
+            return SearchOutcome::NotFound;
         }
 
-        // Fast path: general resolutions
-        let path_provide_data: Option<&ProvideData> = match self.get_decision(parent, name) {
-            Some(Decision::Provide(data)) => Some(data),
-            Some(Decision::Ignore) => return reply.error(nix::errno::Errno::ENOENT as i32),
-            _ => None,
+        self.substituters.check(candidates.iter().map(|(store_path, _)| store_path));
+        let (store_path, ft_entry) =
+            extract_optimal_path(&mut candidates, |candidate| self.rank(target_path, candidate));
+
+        let outcome = if !fuzzy && !pending.deferred && self.interpreter_shims && interpreter::is_interpreter_shim(target_path) {
+            // Interpreters are needed to even run the build, do not bother the user.
+            debug!("serving interpreter shim {}", target_path.display());
+            PromptOutcome::Provide(Box::new(store_path.clone()), ft_entry.clone(), false)
+        } else {
+            // Ask the user if he want to provide this dependency?
+            let mut suggestion = (store_path.clone(), ft_entry.clone());
+            let mut fuzzy = fuzzy;
+            let request_id = pending.req.unique;
+            loop {
+                let rankings = candidates
+                    .iter()
+                    .map(|candidate| self.rank(target_path, candidate))
+                    .collect();
+                self.send_ui_event
+                    .send(UserRequest::InteractiveSearch(
+                        request_id,
+                        candidates.clone(),
+                        rankings,
+                        suggestion.clone(),
+                        requester.clone(),
+                        fuzzy,
+                        pending.deferred,
+                        // Fuzzy candidates provide another path, deferred answers are not recorded.
+                        subtree_of(target_path).filter(|_| !fuzzy && !pending.deferred),
+                        target_path.clone(),
+                    ))
+                    .expect("Failed to send UI thread a message");
+
+                match self.wait_for_answer(pending) {
+                    PromptOutcome::Search(query) => {
+                        // The prompt is asked again with what was found instead.
+                        let mut found = self.manual_search(target_path, query.trim());
+                        // Served in place of the suggestion, they must be of the same kind.
+                        found.retain(|(_, entry)| same_kind(&suggestion.1.node, &entry.node));
+                        if found.is_empty() {
+                            warn!("Nothing found for `{}`", query.trim());
+                            continue;
+                        }
+                        let requested_entry = format!("/{}", target_path.display());
+                        fuzzy = found
+                            .iter()
+                            .any(|(_, entry)| entry.path != requested_entry.as_bytes());
+                        self.substituters.check(found.iter().map(|(store_path, _)| store_path));
+                        found.sort_by_cached_key(|candidate| self.rank(target_path, candidate));
+                        suggestion = found[0].clone();
+                        candidates = found;
+                    }
+                    outcome => break outcome,
+                }
+            }
         };
 
-        if let Some(data) = path_provide_data {
-            trace!("FAST PATH - Decision already exist in current database");
-            let nix_path = data
-                .store_path
-                .join(data.file_entry_name.clone().into())
-                .into_owned()
-                .as_str()
-                .as_bytes()
-                .to_vec();
-            let ft_attribute = build_fake_fattr(self.allocate_inode(), data.kind);
-            return self.serve_path(nix_path, target_path, ft_attribute, reply);
-        }
-
-
-        let mut candidates = self.search_in_index(&target_path);
-
-        if !candidates.is_empty() {
-            let (store_path, ft_entry) =
-                extract_optimal_path(&mut candidates, |(store_path, _)| {
-                    trace!(
-                        "extracting pop for {}: {}",
-                        store_path.as_str(),
-                        store_path.origin().attr
-                    );
-                    // Highest popularity comes first, so inverted popularity works here.
-                    let pop = -(*self
-                        .popcount_buffer
-                        .native_build_inputs
-                        .get(&store_path.as_str().to_string())
-                        .unwrap_or(&0) as i32);
-                    trace!("pop: {pop}");
-                    pop
-                });
+        match outcome {
+            PromptOutcome::Provide(pkg, _, _) if !self.budget.admit(&pkg.as_str()) => SearchOutcome::SkipForNow,
+            PromptOutcome::Provide(pkg, ft_entry, for_subtree) => {
+                debug!("prompt reply: {:?}", pkg);
+                let nix_path = pkg.join_entry(ft_entry.clone()).into_owned();
+                self.realizer.remember(&pkg);
+                if let Err(err) = self.realize(nix_path.as_str().as_ref()) {
+                    error!("Failed to provide {}, {} cannot be realized: {}", target_path.display(), pkg.as_str(), err);
+                    return SearchOutcome::SkipForNow;
+                }
 
-            // Ask the user if he want to provide this dependency?
-            let mut ft_attribute: fuser::FileAttr = ft_entry.node.clone().into();
-            let suggestion = (store_path.clone(), ft_entry.clone());
-            self.send_ui_event
-                .send(UserRequest::InteractiveSearch(candidates.clone(), suggestion))
-                .expect("Failed to send UI thread a message");
+                // Now, we want to extract the whole subgraph
+                // Instead of trying to figure out that subgraph
+                // We can grab the Nix path and extend the fast working tree with it
+                // à la lndir.
+                shadow_store_path(&self.fast_working_tree, &pkg, self.link_mode);
+                let subtree = subtree_of(target_path).filter(|_| for_subtree);
+                // The chosen candidate, which may not be the suggested one.
+                let attribute = ft_entry.node.clone().into();
+                SearchOutcome::Provide(Box::new(ProvidedCandidate {
+                    store_path: *pkg,
+                    entry: ft_entry,
+                    attribute,
+                    subtree,
+                    candidates,
+                }))
+            }
+            PromptOutcome::Ignore | PromptOutcome::Confirm | PromptOutcome::Search(_) => {
+                SearchOutcome::Ignore
+            }
+            PromptOutcome::SkipForNow => SearchOutcome::SkipForNow,
+            PromptOutcome::Interrupted => SearchOutcome::Interrupted,
+        }
+    }
 
+    /// Realize the store path of a served path, waiting for it.
+    fn realize(&self, nix_path: &str) -> Result<(), String> {
+        let now = Instant::now();
+        let realized = self.realizer.realize(nix_path).wait();
+        self.metrics.realizations.observe(now.elapsed());
 
-            // FIXME: timeouts?
-            match self.recv_fs_event.recv() {
-                Ok(FsEventMessage::PackageSuggestion((pkg, ft_entry))) => {
-                    debug!("prompt reply: {:?}", pkg);
-                    // Allocate a file attribute for this file entry.
-                    ft_attribute.ino = self.allocate_inode();
-                    self.record_resolution(
-                        parent,
-                        name,
-                        Decision::Provide(ProvideData {
-                            file_entry_name: String::from_utf8_lossy(&ft_entry.path).to_string(),
-                            kind: ft_attribute.kind,
-                            store_path: pkg.clone(),
-                        }),
-                    );
-                    let nix_path = pkg.join_entry(ft_entry.clone()).into_owned().as_str().as_bytes().to_vec();
-                    let nix_path_as_str = String::from_utf8_lossy(&nix_path);
-                    realize_path(nix_path_as_str.into())
-                        .expect("Nix path should be realized, database seems incoherent with Nix store.");
-
-                    // Now, we want to extract the whole subgraph
-                    // Instead of trying to figure out that subgraph
-                    // We can grab the Nix path and extend the fast working tree with it
-                    // à la lndir.
-                    self.extend_fast_working_tree(&pkg);
-                    return self.serve_path(nix_path, target_path, ft_attribute, reply);
+        realized
+    }
+
+    /// Realize the package a resolution provides the requested path from.
+    fn realize_resolved(&self, requested_path: &Path, data: &ProvideData) -> SearchOutcome {
+        // Pattern resolutions provide the requested path itself by default, if their package has it.
+        let (file_entry_name, kind) = if data.file_entry_name.is_empty() {
+            let file_entry_name = format!("/{}", requested_path.display());
+            match kind_in_store_path(&data.store_path, &file_entry_name) {
+                Some(kind) => (file_entry_name, kind),
+                None => {
+                    trace!("{} does not have {}", data.store_path.as_str(), requested_path.display());
+                    return SearchOutcome::Unavailable;
+                }
+            }
+        } else {
+            (data.file_entry_name.clone(), data.kind)
+        };
+        self.budget.account(&data.store_path.as_str());
+        let nix_path = data
+            .store_path
+            .join(file_entry_name.into())
+            .into_owned()
+            .as_str()
+            .to_string();
+        if let Err(err) = self.realize(&nix_path) {
+            error!("Failed to serve {}, {} cannot be realized: {}", requested_path.display(), nix_path, err);
+            return SearchOutcome::Unavailable;
+        }
+
+        SearchOutcome::Resolved(nix_path.into_bytes(), kind)
+    }
+
+    /// Target of a symlink to a store path, realized again as it may have been collected since its lookup.
+    pub fn realized_link_target(&self, nix_path: &[u8]) -> Result<String, i32> {
+        let nix_path = String::from_utf8_lossy(nix_path);
+        match self.realize(&nix_path) {
+            Ok(()) => Ok(real_path(&nix_path)),
+            Err(err) => {
+                warn!(
+                    "Failed to realize {} during readlink, it was supposed to be realizable: {}",
+                    nix_path, err
+                );
+                Err(nix::errno::Errno::ENOENT as i32)
+            }
+        }
+    }
+}
+
+impl BuildXYZ {
+    /// The same requested path keeps its inode until the kernel forgets it.
+    fn allocate_inode(&mut self, requested_path: &Path) -> u64 {
+        self.inodes.lookup(&requested_path.to_string_lossy())
+    }
+
+    fn build_in_construction_path(&self, parent: u64, name: &OsStr) -> PathBuf {
+        let prefix = Path::new(
+            self.inodes
+                .path(parent)
+                .expect("Unknown parent inode!"),
+        );
+
+        prefix.join(name)
+    }
+
+    /// Requested path as recorded in resolutions,
+    /// multilib directories are folded onto `lib/`.
+    fn build_canonical_path(&self, parent: u64, name: &OsStr) -> PathBuf {
+        library::normalize_multilib_path(&self.build_in_construction_path(parent, name))
+    }
+
+    /// `requested_path` must be canonical, see `build_canonical_path`.
+    fn record_resolution(
+        &mut self,
+        requested_path: &Path,
+        decision: Decision,
+        requested_by: &ProcessInfo,
+        phase: Option<&str>,
+    ) {
+        let current_path = requested_path.to_string_lossy().to_string();
+        trace!(
+            "Recording {} for {:?} (requested by {} in phase {:?})",
+            current_path,
+            decision,
+            requested_by,
+            phase
+        );
+        let data = ResolutionData {
+            requested_path: current_path,
+            decision,
+            requested_by: Some(requested_by.clone()),
+            priority: 0,
+            source: None,
+            provenance: self.provenance.renewed(),
+            reconfirm: false,
+        };
+        // A constant resolution would have answered this lookup already.
+        insert_resolution(&mut self.resolution_db, data, phase);
+        self.index_resolutions();
+        // Persist every decision right away so that a crash does not lose the session.
+        self.persist_resolutions();
+    }
+
+    /// Provide the lookups under `subtree` from this package from now on, with a pattern resolution.
+    /// Pattern resolutions do not depend on the phase.
+    /// Returns the pattern.
+    fn record_subtree_resolution(
+        &mut self,
+        subtree: &Path,
+        store_path: &StorePath,
+        requested_by: &ProcessInfo,
+    ) -> String {
+        let pattern = format!("{}/**", glob::Pattern::escape(&subtree.to_string_lossy()));
+        info!("Providing {} from {} from now on", pattern, store_path.as_str());
+        let data = ResolutionData {
+            requested_path: pattern.clone(),
+            decision: Decision::Provide(Box::new(ProvideData {
+                kind: FileType::RegularFile,
+                // The requested path itself.
+                file_entry_name: String::new(),
+                store_path: store_path.clone(),
+                link_mode: None,
+                flake: flake::installable_of(store_path),
+            })),
+            requested_by: Some(requested_by.clone()),
+            priority: 0,
+            source: None,
+            provenance: self.provenance.renewed(),
+            reconfirm: false,
+        };
+        self.resolution_db.insert(
+            pattern.clone(),
+            Resolution::PatternResolution(PatternResolutionData {
+                syntax: PatternSyntax::Glob,
+                data,
+            }),
+        );
+        self.index_resolutions();
+        self.persist_resolutions();
+
+        pattern
+    }
+
+    /// Keep what is needed to undo a decision just recorded, and list it in the UI.
+    fn remember_decision(&mut self, id: u64, decision: SessionDecision) {
+        let _ = self.send_ui_event.send(UserRequest::Decided(
+            id,
+            PathBuf::from(&decision.requested_path),
+            decision.description.clone(),
+        ));
+        self.session_decisions.insert(id, decision);
+    }
+
+    /// Undo the decisions the UI asked for, their paths are searched again on their next lookup.
+    fn undo_decisions(&mut self) {
+        let ids: Vec<u64> = match &self.undo_requests {
+            Some(undo_requests) => undo_requests.try_iter().collect(),
+            None => return,
+        };
+
+        for id in ids {
+            if self.undo_decision(id).is_some() {
+                self.persist_resolutions();
+            }
+        }
+    }
+
+    /// Forget a decision of this instance, if it is known, and revert what it did.
+    fn undo_decision(&mut self, id: u64) -> Option<SessionDecision> {
+        let decision = self.session_decisions.remove(&id)?;
+        info!("Undoing the decision for {}", decision.requested_path);
+        remove_resolution(
+            &mut self.resolution_db,
+            &decision.requested_path,
+            decision.phase.as_deref(),
+        );
+        if let Some(pattern) = &decision.subtree_pattern {
+            self.resolution_db.remove(pattern);
+            self.undone_resolutions.insert(pattern.clone());
+        }
+        self.index_resolutions();
+        if !self.resolution_db.contains_key(&decision.requested_path) {
+            self.undone_resolutions.insert(decision.requested_path.clone());
+        }
+        if let Some(key) = &decision.enoent_key {
+            self.recorded_enoent.remove(key);
+        }
+        // Inodes the kernel still caches do not resolve anymore.
+        if let Some(ino) = self.inodes.get(&decision.requested_path) {
+            self.nix_paths.remove(&ino);
+            self.redirections.remove(&ino);
+        }
+        if let Some(store_path) = &decision.store_path {
+            self.unshadow_store_path(store_path);
+        }
+
+        Some(decision)
+    }
+
+    /// Decisions of this instance, in the order they were taken.
+    fn session_items(&self) -> Vec<ReviewItem> {
+        let mut items: Vec<ReviewItem> = self
+            .session_decisions
+            .iter()
+            .map(|(id, decision)| ReviewItem {
+                request_id: *id,
+                requested_path: PathBuf::from(&decision.requested_path),
+                description: decision.description.clone(),
+                chosen: decision.candidates.iter().position(|(store_path, _)| {
+                    decision.store_path.as_deref() == Some(store_path.as_str().as_ref())
+                }),
+                candidates: decision.candidates.clone(),
+            })
+            .collect();
+        items.sort_by_key(|item| item.request_id);
+
+        items
+    }
+
+    /// Ask `review` about the decisions of this instance and apply its verdicts,
+    /// see `write_deferred_record`.
+    pub fn review_decisions<R>(&mut self, review: R)
+    where
+        R: FnOnce(&[ReviewItem]) -> Vec<(u64, ReviewVerdict)>,
+    {
+        let items = self.session_items();
+        for (id, verdict) in review(&items) {
+            let index = match verdict {
+                ReviewVerdict::Keep => continue,
+                ReviewVerdict::Drop => {
+                    self.undo_decision(id);
+                    continue;
                 }
-                Ok(FsEventMessage::IgnorePendingRequests) | _ => {
-                    debug!("ENOENT received from user");
-                    self.record_resolution(parent, name, Decision::Ignore);
-                    self.recorded_enoent
-                        .insert((parent, name.to_string_lossy().to_string()));
-                    return reply.error(nix::errno::Errno::ENOENT as i32);
+                ReviewVerdict::Change(index) => index,
+            };
+            let decision = match self.undo_decision(id) {
+                Some(decision) => decision,
+                None => continue,
+            };
+            let (store_path, entry) = decision.candidates[index].clone();
+            let requested_path = PathBuf::from(&decision.requested_path);
+            if decision.subtree_pattern.is_some() {
+                if let Some(subtree) = subtree_of(&requested_path) {
+                    self.record_subtree_resolution(&subtree, &store_path, &decision.requested_by);
                 }
+            }
+            let attribute: fuser::FileAttr = entry.node.clone().into();
+            self.record_resolution(
+                &requested_path,
+                Decision::Provide(Box::new(ProvideData {
+                    file_entry_name: String::from_utf8_lossy(&entry.path).to_string(),
+                    kind: attribute.kind,
+                    flake: flake::installable_of(&store_path),
+                    store_path,
+                    link_mode: None,
+                })),
+                &decision.requested_by,
+                decision.phase.as_deref(),
+            );
+        }
+    }
+
+    /// Ask `confirm` which decisions of this instance go to the record file, the ones it returns are undone.
+    /// It also gets how many resolutions loaded beforehand come from each source, they are written as they are.
+    pub fn confirm_record<C>(&mut self, confirm: C)
+    where
+        C: FnOnce(&[ReviewItem], &[(String, usize)]) -> Vec<u64>,
+    {
+        let items = self.session_items();
+        if items.is_empty() {
+            return;
+        }
+        let mut existing: Vec<(String, usize)> = Vec::new();
+        for resolution in self.resolution_db.values() {
+            // Decisions of this session have no source.
+            let source = match resolution.all_data().into_iter().find_map(|data| data.source.as_deref()) {
+                Some(source) => source,
+                None => continue,
             };
-        } else {
-            // This file potentially don't exist at all
-            // But it is also possible we just do not have the package for it yet.
-            // FIXME: provide proper heuristics for this.
-            debug!("not found in database, recording this ENOENT.");
-            self.recorded_enoent
-                .insert((parent, name.to_string_lossy().to_string()));
-            return reply.error(nix::errno::Errno::ENOENT as i32);
+            match existing.iter_mut().find(|(known, _)| known == source) {
+                Some((_, count)) => *count += 1,
+                None => existing.push((source.to_string(), 1)),
+            }
+        }
+
+        for id in confirm(&items, &existing) {
+            self.undo_decision(id);
         }
     }
 
-    fn readlink(&mut self, _req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyData) {
-        if let Some(nix_path) = self.nix_paths.get(&ino) {
-            // Ensure the path is realized, it could have been gc'd between the lookup and the
-            // readlink.
-            if realize_path(String::from_utf8_lossy(&nix_path).into()).is_err() {
+    /// Ask `offer` which of the packages missing during this instance get a stub overlay in `overlays_dir`,
+    /// by their index, to help packaging them.
+    pub fn offer_missing_overlays<O>(&self, overlays_dir: &Path, offer: O)
+    where
+        O: FnOnce(&[MissingPackage]) -> Vec<usize>,
+    {
+        let packages = overlay::group(&self.missing_paths);
+        if packages.is_empty() {
+            return;
+        }
+        let chosen: Vec<&MissingPackage> = offer(&packages)
+            .into_iter()
+            .filter_map(|index| packages.get(index))
+            .collect();
+        if chosen.is_empty() {
+            return;
+        }
+
+        if let Err(err) = overlay::write_stubs(
+            overlays_dir,
+            &chosen,
+            &current_pin(),
+            self.provenance.command.as_deref(),
+        ) {
+            warn!("Failed to write the stub overlays to {}: {}", overlays_dir.display(), err);
+        }
+    }
+
+    /// Write the record file held back until the decisions were reviewed or confirmed.
+    pub fn write_deferred_record(&mut self) {
+        if std::mem::take(&mut self.defer_record) {
+            self.persist_resolutions();
+        }
+    }
+
+    /// Remove the leaves of a store path from the fast working tree, unless a resolution still provides from it.
+    /// Only symlinked leaves are found, hardlinked or copied ones stay.
+    fn unshadow_store_path(&mut self, store_path: &str) {
+        let still_provided = self.resolution_db.values().any(|resolution| {
+            resolution.all_data().iter().any(|data| match &data.decision {
+                Decision::Provide(provide_data) => provide_data.store_path.as_str() == store_path,
+                _ => false,
+            })
+        });
+        if still_provided {
+            return;
+        }
+
+        self.remove_leaves_of(store_path);
+    }
+
+    /// Remove the symlinked leaves of a store path from the fast working tree.
+    fn remove_leaves_of(&mut self, store_path: &str) {
+        debug!("Removing the leaves of {} from the fast working tree", store_path);
+        for entry in WalkDir::new(&self.fast_working_tree)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path_is_symlink())
+        {
+            let points_into_store_path = std::fs::read_link(entry.path())
+                .map_or(false, |target| target.starts_with(store_path));
+            if points_into_store_path {
+                if let Err(err) = std::fs::remove_file(entry.path()) {
+                    warn!("Failed to remove {}: {}", entry.path().display(), err);
+                }
+            }
+        }
+        self.fast_working_tree_store_paths.remove(store_path);
+    }
+
+    /// Write this instance resolutions on disk, if a record file was requested.
+    fn persist_resolutions(&self) {
+        if self.defer_record {
+            return;
+        }
+        if let Some(filepath) = &self.resolution_record_filepath {
+            // Other instances may record in the same file meanwhile, their resolutions are kept.
+            let written = update_resolution_db(filepath, Some(&current_pin()), |on_disk| {
+                on_disk.retain(|requested_path, _| !self.undone_resolutions.contains(requested_path));
+                *on_disk = merge_resolution_db(std::mem::take(on_disk), self.resolution_db.clone());
+                Ok(())
+            });
+            if let Err(err) = written {
                 warn!(
-                    "Failed to realize {} during readlink, it was supposed to be realizable!",
-                    String::from_utf8_lossy(&nix_path)
+                    "Failed to write resolution data to {}: {}",
+                    filepath.display(),
+                    err
                 );
-                reply.error(nix::errno::Errno::ENOENT as i32);
-            } else {
-                reply.data(nix_path);
             }
         }
-        else if let Some(redirection_path) = self.redirections.get(&ino) {
-            reply.data(redirection_path);
-        } else {
-            warn!("Attempt to read a non-existent Nix path, ino={}", ino);
-            reply.error(nix::errno::Errno::ENOENT as i32);
+    }
+
+    /// Count the resolution of `current_path` answering in this phase, if any.
+    fn count_hit(&mut self, current_path: &str, phase: Option<&str>) {
+        let hit = self
+            .get_resolution_of_path(current_path)
+            .filter(|resolution| resolution.data_for_phase(phase).is_some())
+            .map(|resolution| (resolution.source().to_string(), resolution.requested_path().clone()));
+        if let Some((source, requested_path)) = hit {
+            self.usage.hit(&source, &requested_path);
+        }
+    }
+
+    fn get_resolution(&self, parent: u64, name: &OsStr) -> Option<&Resolution> {
+        self.get_resolution_of_path(&self.build_canonical_path(parent, name).to_string_lossy())
+    }
+
+    /// Compile the pattern resolutions and index the others by lowercase path,
+    /// whenever the resolution database changes.
+    fn index_resolutions(&mut self) {
+        self.pattern_resolutions =
+            PatternResolutions::compile(&self.resolution_db, self.case_insensitive);
+        self.lowercase_resolutions.clear();
+        if self.case_insensitive {
+            for (requested_path, resolution) in &self.resolution_db {
+                if !resolution.is_pattern() {
+                    self.lowercase_resolutions
+                        .entry(requested_path.to_lowercase())
+                        .or_insert_with(|| requested_path.clone());
+                }
+            }
         }
     }
+
+    /// `current_path` must be canonical, see `build_canonical_path`.
+    fn get_resolution_of_path(&self, current_path: &str) -> Option<&Resolution> {
+        match self.resolution_db.get(current_path) {
+            Some(resolution) => Some(resolution),
+            None => {
+                // Resolutions are recorded with the casing found in the index.
+                self.lowercase_resolutions
+                    .get(&current_path.to_lowercase())
+                    .and_then(|requested_path| self.resolution_db.get(requested_path))
+                    .or_else(|| {
+                        self.pattern_resolutions
+                            .find(current_path)
+                            .and_then(|pattern| self.resolution_db.get(pattern))
+                    })
+            }
+        }
+    }
+
+    /// Key of this lookup in the recorded ENOENTs.
+    fn enoent_key(
+        &self,
+        phase: &Option<String>,
+        parent: u64,
+        name: &OsStr,
+    ) -> (Option<String>, u64, String) {
+        let name = name.to_string_lossy().to_string();
+        (
+            phase.clone(),
+            parent,
+            if self.case_insensitive { name.to_lowercase() } else { name },
+        )
+    }
+
+    fn get_decision(&self, parent: u64, name: &OsStr, phase: Option<&str>) -> Option<&Decision> {
+        self.get_resolution(parent, name)
+            .and_then(|resolution| resolution.data_for_phase(phase))
+            .map(|data| &data.decision)
+    }
+
+    /// Ancestor of the requested path whose whole tree is ignored, if any.
+    fn ignored_tree_of(&self, requested_path: &Path, phase: Option<&str>) -> Option<PathBuf> {
+        requested_path
+            .ancestors()
+            .skip(1)
+            .filter(|ancestor| !ancestor.as_os_str().is_empty())
+            .find(|ancestor| {
+                matches!(
+                    self.get_resolution_of_path(&ancestor.to_string_lossy())
+                        .and_then(|resolution| resolution.data_for_phase(phase))
+                        .map(|data| &data.decision),
+                    Some(Decision::IgnoreTree)
+                )
+            })
+            .map(Path::to_path_buf)
+    }
+
+    /// Build phase of the requesting process, as exported in its `BUILDXYZ_PHASE`,
+    /// defaulting to the session phase.
+    fn phase_of(&self, requester: &ProcessInfo) -> Option<String> {
+        process::read_environ_var(requester.pid, PHASE_VARIABLE).or_else(|| self.phase.clone())
+    }
+    
+    // Shadow symlink in the fast working tree
+    // this Nix path
+    /// Store paths provided in the session phase, with their link mode.
+    fn store_paths_of_phase(&self) -> Vec<(StorePath, LinkMode)> {
+        self.resolution_db
+            .values()
+            .filter_map(|resolution| resolution.data_for_phase(self.phase.as_deref()))
+            .filter_map(|data| match &data.decision {
+                Decision::Provide(provide_data) => Some((
+                    provide_data.store_path.clone(),
+                    provide_data.link_mode.unwrap_or(self.link_mode),
+                )),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Shadow the store paths provided in the session phase in the fast working tree,
+    /// the resolutions of other phases are only served once their phase comes, see `set_phase`.
+    fn populate_fast_working_tree(&mut self) {
+        let store_paths = self.store_paths_of_phase();
+        info!(
+            "Will fast extend {} store paths.",
+            store_paths.len()
+        );
+
+        for (spath, link_mode) in store_paths {
+            debug!("{} being extended in the working tree", spath.as_str());
+            self.extend_fast_working_tree(&spath, link_mode);
+        }
+
+        if self.rewrite_pkgconfig {
+            let pc_paths: Vec<PathBuf> = self
+                .resolution_db
+                .iter()
+                .filter(|(_, resolution)| !resolution.is_pattern())
+                .filter(|(_, resolution)| resolution.data_for_phase(self.phase.as_deref()).is_some())
+                .map(|(requested_path, _)| PathBuf::from(requested_path))
+                .filter(|path| pkgconfig::is_pc_file(path))
+                .collect();
+
+            for pc_path in pc_paths {
+                if let Err(err) = self.rewrite_pc_in_fast_working_tree(&pc_path) {
+                    warn!("Failed to rewrite {}: {}", pc_path.display(), err);
+                }
+            }
+        }
+    }
+
+    /// Switch the session to another build phase: the store paths only provided in the former one
+    /// leave the fast working tree, those of the new one are shadowed in it.
+    pub fn set_phase(&mut self, phase: Option<String>) {
+        if phase == self.phase {
+            return;
+        }
+        info!("Entering phase {:?}, was {:?}", phase, self.phase);
+        self.phase = phase;
+
+        let provided: HashSet<String> = self
+            .store_paths_of_phase()
+            .iter()
+            .map(|(store_path, _)| store_path.as_str().into_owned())
+            .collect();
+        let stale: Vec<String> = self
+            .fast_working_tree_store_paths
+            .iter()
+            .filter(|store_path| !provided.contains(*store_path))
+            .cloned()
+            .collect();
+        for store_path in stale {
+            self.remove_leaves_of(&store_path);
+        }
+        self.populate_fast_working_tree();
+    }
+
+    /// Apply the phase changes sent by the UI, the latest one wins.
+    fn apply_phase_requests(&mut self) {
+        let latest = match &self.phase_requests {
+            Some(phase_requests) => phase_requests.try_iter().last(),
+            None => return,
+        };
+        if let Some(phase) = latest {
+            self.set_phase(phase);
+        }
+    }
+
+    fn extend_fast_working_tree(
+        &mut self,
+        store_path: &StorePath,
+        link_mode: LinkMode,
+    ) {
+        shadow_store_path(&self.fast_working_tree, store_path, link_mode);
+        self.fast_working_tree_store_paths.insert(store_path.as_str().into_owned());
+    }
+
+    /// Replace the shadow symlink of this pkg-config file in the fast working tree
+    /// by a copy whose prefix, libdir and includedir point to the fast working tree.
+    fn rewrite_pc_in_fast_working_tree(&self, requested_path: &Path) -> std::io::Result<PathBuf> {
+        let pc_path = self.fast_working_tree.join(requested_path);
+        let contents = std::fs::read_to_string(&pc_path)?;
+        let store_paths: Vec<String> = self.fast_working_tree_store_paths.iter().cloned().collect();
+        let rewritten = pkgconfig::rewrite_pc_file(&contents, &store_paths, &self.fast_working_tree);
+
+        trace!("rewriting {}:\n{}", pc_path.display(), rewritten);
+        std::fs::remove_file(&pc_path)?;
+        std::fs::write(&pc_path, rewritten)?;
+
+        Ok(pc_path)
+    }
+
+    /// Serve the path as an answer to the filesystem
+    /// The Nix path must be realized already, see `SearchHandles::realize`.
+    fn serve_path(
+        &mut self,
+        req: &RequestInfo,
+        nix_path: Vec<u8>,
+        requested_path: PathBuf,
+        attribute: fuser::FileAttr,
+        reply: LookupReply,
+    ) {
+        let nix_path_as_str = String::from_utf8_lossy(&nix_path);
+        let attribute = owned_by(
+            served_attribute(attribute, Path::new(&real_path(&nix_path_as_str))),
+            req,
+        );
+        trace!("{} -> {}: {:?}", requested_path.display(), nix_path_as_str, attribute);
+
+        self.redirections.remove(&attribute.ino);
+        self.nix_paths.insert(attribute.ino, nix_path);
+        self.virtual_dirs.insert(&requested_path, attribute.kind);
+
+        reply.entry(&self.ttls.entry, &attribute, self.inodes.generation(attribute.ino));
+    }
+
+    /// Redirect to a filesystem file
+    /// via symlink
+    fn redirect_to_fs(
+        &mut self,
+        req: &RequestInfo,
+        reply: LookupReply,
+        requested_path: &Path,
+        onfs_path: PathBuf
+    ) {
+        trace!("redirecting {} to {} on another filesystem", requested_path.display(), onfs_path.display());
+
+        let ft_attribute = owned_by(
+            build_fake_fattr(self.allocate_inode(requested_path), fuser::FileType::Symlink),
+            req,
+        );
+        self.nix_paths.remove(&ft_attribute.ino);
+        self.redirections.insert(ft_attribute.ino, onfs_path.to_string_lossy().as_bytes().to_vec());
+        self.virtual_dirs.insert(requested_path, ft_attribute.kind);
+        reply.entry(&self.ttls.entry, &ft_attribute, self.inodes.generation(ft_attribute.ino));
+    }
+
+    /// Generate a stub in the fast working tree, which serves it from now on.
+    fn write_stub(&self, requested_path: &Path, contents: &str) -> std::io::Result<PathBuf> {
+        let onfs_path = self.fast_working_tree.join(requested_path);
+        if let Some(parent) = onfs_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(
+            &onfs_path,
+            contents.replace("@prefix@", &self.fast_working_tree.to_string_lossy()),
+        )?;
+
+        Ok(onfs_path)
+    }
+
+    /// Append this lookup to the access trace, if any, and to the latest lookups of its process.
+    fn trace_access(
+        &mut self,
+        path: &Path,
+        outcome: AccessOutcome,
+        requester: Option<&ProcessInfo>,
+    ) {
+        if let Some(requester) = requester {
+            self.recent_lookups
+                .lock()
+                .unwrap()
+                .record(requester.pid, path.to_owned(), outcome);
+        }
+        if let Some(access_trace) = &mut self.access_trace {
+            if let Err(err) = access_trace.record(path, outcome, requester) {
+                warn!("Failed to record {} in the access trace: {}", path.display(), err);
+            }
+        }
+    }
+
+    /// Answer ENOENT to a lookup, as a cached negative entry if requested.
+    fn reply_enoent(&self, reply: LookupReply) {
+        if self.ttls.negative.is_zero() {
+            reply.error(nix::errno::Errno::ENOENT as i32);
+        } else {
+            // A zero inode is understood by the kernel as a negative entry.
+            reply.entry(
+                &self.ttls.negative,
+                &build_fake_fattr(0, FileType::RegularFile),
+                0,
+            );
+        }
+    }
+
+    /// Answer this lookup right away if possible: global directories, blocked paths,
+    /// the fast working tree, existing resolutions, etc.
+    /// Otherwise, the returned lookup has to be searched in the index then completed.
+    /// Lookups of a path already pending wait for it instead, they are resumed by `complete_lookup`.
+    pub fn lookup_fast(
+        &mut self,
+        req: RequestInfo,
+        parent: u64,
+        name: &OsStr,
+        reply: LookupReply,
+    ) -> Option<PendingLookup> {
+        let pending = self.lookup_fast_or_park(req, parent, name, reply)?;
+        self.in_flight_lookups.insert(pending.coalescing_key(), Vec::new());
+
+        Some(pending)
+    }
+
+    fn lookup_fast_or_park(
+        &mut self,
+        req: RequestInfo,
+        parent: u64,
+        name: &OsStr,
+        reply: LookupReply,
+    ) -> Option<PendingLookup> {
+        self.metrics.lookups.inc();
+        self.undo_decisions();
+        self.apply_phase_requests();
+        let target_path = self.build_in_construction_path(parent, name);
+
+        // global directory
+        let global_dir = target_path.to_string_lossy().to_string();
+        let global_dir = if self.case_insensitive {
+            global_dir.to_lowercase()
+        } else {
+            global_dir
+        };
+        if let Some(inode) = self.global_dirs.get(&global_dir) {
+            trace!(
+                "global directory hit: {}",
+                &target_path.to_string_lossy().to_string()
+            );
+            let inode = *inode;
+            self.trace_access(&target_path, AccessOutcome::GlobalDirectory, None);
+            reply.entry(
+                &self.ttls.directory,
+                &owned_by(build_fake_fattr(inode, FileType::Directory), &req),
+                inode,
+            );
+            return None;
+        }
+
+        // No other global directories.
+        if parent == self.inodes.root() {
+            self.trace_access(&target_path, AccessOutcome::NotFound, None);
+            self.reply_enoent(reply);
+            return None;
+        }
+
+        // From now on, only reason about the canonical `lib/` layout.
+        let target_path = library::normalize_multilib_path(&target_path);
+
+        if self.blocklist.is_blocked(&target_path.to_string_lossy()) {
+            trace!("{} is blocklisted", target_path.display());
+            self.trace_access(&target_path, AccessOutcome::Blocked, None);
+            self.reply_enoent(reply);
+            return None;
+        }
+
+        let requester = ProcessInfo::from_pid(req.pid);
+        let phase = self.phase_of(&requester);
+        if self.ignored_processes.is_ignored(&requester) {
+            trace!(
+                "ignoring {} as requested by filtered process {}",
+                target_path.display(),
+                requester
+            );
+            self.trace_access(&target_path, AccessOutcome::FilteredProcess, Some(&requester));
+            self.reply_enoent(reply);
+            return None;
+        }
+
+        // Fast path: ignore temporarily recorded ENOENTs.
+        if self.recorded_enoent.contains(&self.enoent_key(&phase, parent, name)) {
+            self.trace_access(&target_path, AccessOutcome::RecordedEnoent, Some(&requester));
+            self.reply_enoent(reply);
+            return None;
+        }
+
+        // The same path is searched, realized or asked once at a time, e.g. by a parallel build.
+        let current_path = self.build_canonical_path(parent, name).to_string_lossy().to_string();
+        if let Some(parked) = self.in_flight_lookups.get_mut(&(current_path.clone(), phase.clone())) {
+            trace!("{} is already pending, waiting for it", target_path.display());
+            parked.push(ParkedLookup {
+                req,
+                parent,
+                name: name.to_os_string(),
+                reply,
+            });
+            return None;
+        }
+
+        // Resolutions to reconfirm are asked once per run, even in automatic mode.
+        let to_reconfirm = self
+            .get_resolution(parent, name)
+            .and_then(|resolution| resolution.data_for_phase(phase.as_deref()))
+            .filter(|data| data.reconfirm && data.decision != Decision::Defer)
+            .map(|data| data.decision.to_string());
+        if let Some(description) = to_reconfirm {
+            match self.reconfirmed.get(&current_path) {
+                Some(true) => {}
+                Some(false) => {
+                    trace!("{} was not confirmed in this run", target_path.display());
+                    self.trace_access(&target_path, AccessOutcome::Ignored, Some(&requester));
+                    self.reply_enoent(reply);
+                    return None;
+                }
+                None => {
+                    return Some(PendingLookup {
+                        req,
+                        parent,
+                        name: name.to_os_string(),
+                        target_path,
+                        requester,
+                        phase,
+                        deferred: false,
+                        reconfirm: Some(description),
+                        resolved: None,
+                        reply,
+                    })
+                }
+            }
+        }
+
+        // Deferred paths are always asked, even if another package already shadows them.
+        let deferred = matches!(
+            self.get_decision(parent, name, phase.as_deref()),
+            Some(Decision::Defer)
+        );
+
+        // Resolutions of other phases may have shadowed this path in the fast working tree.
+        let other_phase = self
+            .get_resolution(parent, name)
+            .map_or(false, |resolution| resolution.data_for_phase(phase.as_deref()).is_none());
+
+        // Fast path: fast working tree
+        // Rebase the target path based on the working tree structure
+        if !deferred && !other_phase && self.fast_working_tree.join(&target_path).exists() {
+            trace!("FAST PATH — Path already exist in the fast working tree");
+            self.trace_access(&target_path, AccessOutcome::FastWorkingTree, Some(&requester));
+            let onfs_path = self.fast_working_tree.join(&target_path);
+            self.redirect_to_fs(&req, reply, &target_path, onfs_path);
+            return None;
+        }
+
+        // Fast path: general resolutions
+        if !deferred {
+            self.count_hit(&current_path, phase.as_deref());
+        }
+        let path_provide_data: Option<ProvideData> = match self.get_decision(parent, name, phase.as_deref()) {
+            Some(Decision::Provide(data)) => Some(data.as_ref().clone()),
+            Some(Decision::Ignore | Decision::IgnoreTree) => {
+                self.trace_access(&target_path, AccessOutcome::ResolvedIgnore, Some(&requester));
+                self.reply_enoent(reply);
+                return None;
+            }
+            Some(decision @ (Decision::HostFallback { host_path } | Decision::Redirect { target: host_path })) => {
+                let outcome = match decision {
+                    Decision::Redirect { .. } => AccessOutcome::ResolvedRedirect,
+                    _ => AccessOutcome::ResolvedHostFallback,
+                };
+                // The redirection is a symlink, read from other working directories.
+                let host_path = std::env::current_dir()
+                    .map(|cwd| cwd.join(host_path))
+                    .unwrap_or_else(|_| host_path.clone());
+                if !host_path.exists() {
+                    warn!("{} is gone from the host, ENOENT'ing {}", host_path.display(), target_path.display());
+                    self.trace_access(&target_path, AccessOutcome::NotFound, Some(&requester));
+                    self.reply_enoent(reply);
+                    return None;
+                }
+                self.trace_access(&target_path, outcome, Some(&requester));
+                self.redirect_to_fs(&req, reply, &target_path, host_path);
+                return None;
+            }
+            Some(Decision::Stub { contents }) => {
+                let contents = contents.clone();
+                match self.write_stub(&target_path, &contents) {
+                    Ok(onfs_path) => {
+                        self.trace_access(&target_path, AccessOutcome::ResolvedStub, Some(&requester));
+                        self.redirect_to_fs(&req, reply, &target_path, onfs_path);
+                    }
+                    Err(err) => {
+                        warn!("Failed to write the stub of {}: {}", target_path.display(), err);
+                        self.trace_access(&target_path, AccessOutcome::NotFound, Some(&requester));
+                        self.reply_enoent(reply);
+                    }
+                }
+                return None;
+            }
+            _ => None,
+        };
+
+        if let Some(data) = path_provide_data {
+            trace!("FAST PATH - Decision already exist in current database");
+            // Its package is realized by a worker, other lookups are answered meanwhile.
+            return Some(PendingLookup {
+                req,
+                parent,
+                name: name.to_os_string(),
+                target_path,
+                requester,
+                phase,
+                deferred: false,
+                reconfirm: None,
+                resolved: Some(data),
+                reply,
+            });
+        }
+
+
+        // Fast path: ignored trees, the closest resolutions above were consulted first.
+        if let Some(ignored_tree) = self.ignored_tree_of(
+            &library::normalize_multilib_path(&target_path),
+            phase.as_deref(),
+        ) {
+            trace!("{} is under the ignored tree {}", target_path.display(), ignored_tree.display());
+            self.count_hit(&ignored_tree.to_string_lossy(), phase.as_deref());
+            self.trace_access(&target_path, AccessOutcome::ResolvedIgnore, Some(&requester));
+            self.reply_enoent(reply);
+            return None;
+        }
+
+        // Fast path: directories above paths we already provided
+        if self.virtual_dirs.is_dir(&target_path.to_string_lossy()) {
+            trace!("FAST PATH - Synthesized directory");
+            self.trace_access(&target_path, AccessOutcome::SynthesizedDirectory, Some(&requester));
+            let inode = self.allocate_inode(&target_path);
+            reply.entry(
+                &self.ttls.directory,
+                &owned_by(build_fake_fattr(inode, FileType::Directory), &req),
+                self.inodes.generation(inode),
+            );
+            return None;
+        }
+
+        self.metrics.index_searches.inc();
+        Some(PendingLookup {
+            req,
+            parent,
+            name: name.to_os_string(),
+            target_path,
+            requester,
+            phase,
+            deferred,
+            reconfirm: None,
+            resolved: None,
+            reply,
+        })
+    }
+
+    /// Answer a pending lookup once its index search is over, then the lookups of the same path waiting for it.
+    /// A confirmed resolution may need a search of its own, as may the lookups waiting,
+    /// the lookups returned have to be searched then completed too.
+    pub fn complete_lookup(&mut self, pending: PendingLookup, outcome: SearchOutcome) -> Vec<PendingLookup> {
+        let parked = self
+            .in_flight_lookups
+            .remove(&pending.coalescing_key())
+            .unwrap_or_default();
+        let mut resumed: Vec<PendingLookup> = self.answer_lookup(pending, outcome).into_iter().collect();
+        // Mostly answered by the resolution recorded meanwhile now.
+        resumed.extend(parked.into_iter().filter_map(|parked| {
+            self.lookup_fast(parked.req, parked.parent, &parked.name, parked.reply)
+        }));
+
+        resumed
+    }
+
+    fn answer_lookup(&mut self, pending: PendingLookup, outcome: SearchOutcome) -> Option<PendingLookup> {
+        let PendingLookup {
+            req,
+            parent,
+            name,
+            target_path,
+            requester,
+            phase,
+            deferred,
+            reconfirm,
+            resolved: _,
+            reply,
+        } = pending;
+        let name = name.as_os_str();
+
+        match outcome {
+            SearchOutcome::Provide(provided) => {
+                let ProvidedCandidate {
+                    store_path: pkg,
+                    entry: ft_entry,
+                    attribute: mut ft_attribute,
+                    subtree,
+                    candidates,
+                } = *provided;
+                if !deferred {
+                    let subtree_pattern = subtree
+                        .map(|subtree| self.record_subtree_resolution(&subtree, &pkg, &requester));
+                    let requested_path = canonical_case(&target_path, &ft_entry.path, self.case_insensitive);
+                    let decision = Decision::Provide(Box::new(ProvideData {
+                        file_entry_name: String::from_utf8_lossy(&ft_entry.path).to_string(),
+                        kind: ft_attribute.kind,
+                        store_path: pkg.clone(),
+                        link_mode: None,
+                        flake: flake::installable_of(&pkg),
+                    }));
+                    let mut description = decision.to_string();
+                    if let Some(pattern) = &subtree_pattern {
+                        description.push_str(&format!(", and {}", pattern));
+                    }
+                    self.record_resolution(&requested_path, decision, &requester, phase.as_deref());
+                    self.remember_decision(
+                        req.unique,
+                        SessionDecision {
+                            requested_path: requested_path.to_string_lossy().to_string(),
+                            phase: phase.clone(),
+                            store_path: Some(pkg.as_str().to_string()),
+                            subtree_pattern,
+                            enoent_key: None,
+                            description,
+                            requested_by: requester.clone(),
+                            candidates,
+                        },
+                    );
+                }
+                let nix_path = pkg.join_entry(ft_entry.clone()).into_owned().as_bytes().to_vec();
+
+                // Shadowed in the fast working tree by the worker.
+                self.fast_working_tree_store_paths.insert(pkg.as_str().into_owned());
+                self.trace_access(&target_path, AccessOutcome::Provided, Some(&requester));
+
+                if self.rewrite_pkgconfig && pkgconfig::is_pc_file(&target_path) {
+                    match self.rewrite_pc_in_fast_working_tree(&target_path) {
+                        Ok(pc_path) => {
+                            self.redirect_to_fs(&req, reply, &target_path, pc_path);
+                            return None;
+                        }
+                        Err(err) => warn!(
+                            "Failed to rewrite {}, serving it as is: {}",
+                            target_path.display(),
+                            err
+                        ),
+                    }
+                }
+
+                // Allocate a file attribute for this file entry.
+                ft_attribute.ino = self.allocate_inode(&target_path);
+                self.serve_path(&req, nix_path, target_path, ft_attribute, reply);
+            }
+            SearchOutcome::Resolved(nix_path, kind) => {
+                self.trace_access(&target_path, AccessOutcome::ResolvedProvide, Some(&requester));
+                let ft_attribute = build_fake_fattr(self.allocate_inode(&target_path), kind);
+                self.serve_path(&req, nix_path, target_path, ft_attribute, reply);
+            }
+            SearchOutcome::Unavailable => {
+                self.trace_access(&target_path, AccessOutcome::NotFound, Some(&requester));
+                self.reply_enoent(reply);
+            }
+            SearchOutcome::Confirmed => {
+                let current_path = self.build_canonical_path(parent, name).to_string_lossy().to_string();
+                debug!("{} is confirmed for this run", current_path);
+                self.reconfirmed.insert(current_path, true);
+                // Answered by the resolution now, unless it does not answer lookups by itself.
+                return self.lookup_fast(req, parent, name, reply);
+            }
+            SearchOutcome::Interrupted => {
+                self.trace_access(&target_path, AccessOutcome::Interrupted, Some(&requester));
+                reply.error(nix::errno::Errno::EINTR as i32);
+            }
+            SearchOutcome::SkipForNow => {
+                debug!("{} is skipped for now", target_path.display());
+                self.trace_access(&target_path, AccessOutcome::Ignored, Some(&requester));
+                self.reply_enoent(reply);
+            }
+            SearchOutcome::Ignore if reconfirm.is_some() => {
+                debug!("{} is not confirmed for this run", target_path.display());
+                self.trace_access(&target_path, AccessOutcome::Ignored, Some(&requester));
+                self.reconfirmed.insert(
+                    self.build_canonical_path(parent, name).to_string_lossy().to_string(),
+                    false,
+                );
+                self.reply_enoent(reply);
+            }
+            SearchOutcome::Ignore => {
+                debug!("ENOENT received from user");
+                self.trace_access(&target_path, AccessOutcome::Ignored, Some(&requester));
+                if !deferred {
+                    self.record_resolution(&target_path, Decision::Ignore, &requester, phase.as_deref());
+                    let enoent_key = self.enoent_key(&phase, parent, name);
+                    self.recorded_enoent.insert(enoent_key.clone());
+                    self.remember_decision(
+                        req.unique,
+                        SessionDecision {
+                            requested_path: target_path.to_string_lossy().to_string(),
+                            phase: phase.clone(),
+                            store_path: None,
+                            subtree_pattern: None,
+                            enoent_key: Some(enoent_key),
+                            description: Decision::Ignore.to_string(),
+                            requested_by: requester.clone(),
+                            candidates: Vec::new(),
+                        },
+                    );
+                }
+                self.reply_enoent(reply);
+            }
+            SearchOutcome::NotFound => {
+                if let Some(host_path) = self.fallback_host.then(|| find_on_host(&target_path)).flatten() {
+                    debug!("not found in database, falling back to {}", host_path.display());
+                    self.trace_access(&target_path, AccessOutcome::HostFallback, Some(&requester));
+                    if !deferred {
+                        let decision = Decision::HostFallback { host_path: host_path.clone() };
+                        let description = decision.to_string();
+                        self.record_resolution(&target_path, decision, &requester, phase.as_deref());
+                        self.remember_decision(
+                            req.unique,
+                            SessionDecision {
+                                requested_path: target_path.to_string_lossy().to_string(),
+                                phase: phase.clone(),
+                                store_path: None,
+                                subtree_pattern: None,
+                                enoent_key: None,
+                                description,
+                                requested_by: requester.clone(),
+                                candidates: Vec::new(),
+                            },
+                        );
+                    }
+                    self.redirect_to_fs(&req, reply, &target_path, host_path);
+                    return None;
+                }
+
+                // This file potentially don't exist at all
+                // But it is also possible we just do not have the package for it yet.
+                // FIXME: provide proper heuristics for this.
+                debug!("not found in database, recording this ENOENT.");
+                self.trace_access(&target_path, AccessOutcome::NotFound, Some(&requester));
+                if !deferred {
+                    self.missing_paths.push(MissingPath {
+                        requested_path: target_path.to_string_lossy().to_string(),
+                        phase: phase.clone(),
+                        requested_by: requester,
+                    });
+                }
+                self.recorded_enoent.insert(self.enoent_key(&phase, parent, name));
+                self.reply_enoent(reply);
+            }
+        }
+
+        None
+    }
+
+    /// Handles to search the index for pending lookups.
+    pub fn search_handles(&self) -> SearchHandles {
+        SearchHandles {
+            index: self.index.clone(),
+            query_cache: self.query_cache.clone(),
+            disk_query_cache: self.disk_query_cache.clone(),
+            popcount_buffer: self.popcount_buffer.clone(),
+            send_ui_event: self.send_ui_event.clone(),
+            answers: self.answers.clone(),
+            recent_lookups: self.recent_lookups.clone(),
+            realizer: self.realizer.clone(),
+            substituters: self.substituters.clone(),
+            binary_cache: self.binary_cache.clone(),
+            budget: self.budget.clone(),
+            metrics: self.metrics.clone(),
+            fast_working_tree: self.fast_working_tree.clone(),
+            link_mode: self.link_mode,
+            interpreter_shims: self.interpreter_shims,
+            case_insensitive: self.case_insensitive,
+            fuzzy_search: self.fuzzy_search,
+        }
+    }
+
+    /// What the symlink served at `ino` points to.
+    pub fn link_target(&self, ino: u64) -> Option<LinkTarget> {
+        if let Some(nix_path) = self.nix_paths.get(&ino) {
+            Some(LinkTarget::Store(nix_path.clone()))
+        } else if let Some(redirection_path) = self.redirections.get(&ino) {
+            Some(LinkTarget::Host(redirection_path.clone()))
+        } else {
+            warn!("Attempt to read a non-existent Nix path, ino={}", ino);
+            None
+        }
+    }
+
+    /// Path whose extended attributes are served for this inode, i.e. what it points to.
+    fn xattr_target(&self, ino: u64) -> Option<PathBuf> {
+        self.nix_paths
+            .get(&ino)
+            .map(|path| real_path(&String::from_utf8_lossy(path)).into())
+            .or_else(|| {
+                self.redirections
+                    .get(&ino)
+                    .map(|path| OsString::from_vec(path.clone()).into())
+            })
+    }
+
+    /// Value of an extended attribute of the path served at `ino`, `None` for ENODATA.
+    fn xattr_value(&self, ino: u64, name: &OsStr) -> Option<Vec<u8>> {
+        self.xattr_target(ino)
+            .and_then(|target| xattr::get_deref(target, name).ok().flatten())
+    }
+
+    /// Extended attribute names of the path served at `ino`, NUL-terminated as `listxattr` replies them.
+    fn xattr_names(&self, ino: u64) -> Vec<u8> {
+        let mut names: Vec<u8> = Vec::new();
+        if let Some(Ok(attributes)) = self.xattr_target(ino).map(xattr::list_deref) {
+            for attribute in attributes {
+                names.extend(attribute.into_vec());
+                names.push(0);
+            }
+        }
+
+        names
+    }
+
+    /// Register known "FHS" structure
+    /// Assume parents are already created.
+    fn mkdir_fhs_directory(&mut self, path: &str) {
+        let inode = self.inodes.pin(path);
+        self.global_dirs.insert(path.to_string(), inode);
+        self.virtual_dirs.insert(Path::new(path), FileType::Directory);
+    }
+
+    /// Entries of a directory after `offset`, with the offset of the next one, as `readdir` lists them.
+    fn directory_entries(&self, ino: u64, offset: i64) -> Result<Vec<(u64, i64, FileType, String)>, i32> {
+        let path = self.inodes.path(ino).ok_or(nix::errno::Errno::ENOENT as i32)?;
+        let children = self
+            .virtual_dirs
+            .children(path)
+            .ok_or(nix::errno::Errno::ENOTDIR as i32)?;
+        let parent_ino = self.inodes.parent(ino).unwrap_or(UNKNOWN_INODE);
+
+        let entries = [(".".to_string(), ino, FileType::Directory), ("..".to_string(), parent_ino, FileType::Directory)]
+            .into_iter()
+            .chain(children.iter().map(|(name, kind)| {
+                let child_ino = self
+                    .inodes
+                    .get(&Path::new(path).join(name).to_string_lossy())
+                    .unwrap_or(UNKNOWN_INODE);
+                (name.clone(), child_ino, *kind)
+            }));
+
+        Ok(entries
+            .enumerate()
+            .skip(offset as usize)
+            .map(|(index, (name, child_ino, kind))| (child_ino, (index + 1) as i64, kind, name))
+            .collect())
+    }
+
+    pub(crate) fn mkdir_fhs_directories(&mut self) {
+        // Create bin, lib, include, pkg-config inodes
+        // TODO: Keep this list synchronized with created search paths in runner.rs?
+        [
+            "bin",
+            "include",
+            "perl",
+            "aclocal",
+            "cmake",
+            "lib",
+            "lib/pkgconfig",
+        ]
+        .into_iter()
+        .for_each(|c| self.mkdir_fhs_directory(c));
+        // Multilib directories are served as aliases of `lib`.
+        for dir in library::multilib_dirs() {
+            self.mkdir_fhs_directory(&dir);
+            self.mkdir_fhs_directory(&format!("{}/pkgconfig", dir));
+        }
+    }
+}
+
+// Allow parallel calls to lookup() as it should be fine.
+const FUSE_CAP_PARALLEL_DIROPS: u32 = 1 << 18;
+// Cache the symlinks we provide in the page cache.
+const FUSE_CAP_CACHE_SYMLINKS: u32 = 1 << 23;
+// Inode reported in readdir for entries which were not looked up yet, as libfuse does.
+const UNKNOWN_INODE: u64 = 0xffff_ffff;
+
+impl Filesystem for BuildXYZ {
+    fn init(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        config: &mut fuser::KernelConfig,
+    ) -> Result<(), i32> {
+        // https://www.kernel.org/doc/html/latest/filesystems/fuse.html
+        // https://libfuse.github.io/doxygen/fuse__common_8h.html
+        config
+            .add_capabilities(FUSE_CAP_PARALLEL_DIROPS)
+            .map_err(|err| -(err as i32))?;
+        self.mkdir_fhs_directories();
+
+        info!(
+            "Loaded {} resolutions from the database.",
+            self.resolution_db.len()
+        );
+
+        self.index_resolutions();
+        info!(
+            "Compiled {} pattern resolutions.",
+            self.pattern_resolutions.len()
+        );
+
+        for (requested_path, resolution) in &self.resolution_db {
+            if resolution.is_pattern() {
+                continue;
+            }
+            for data in resolution.all_data() {
+                if let Decision::Provide(provide_data) = &data.decision {
+                    self.virtual_dirs
+                        .insert(Path::new(requested_path), provide_data.kind);
+                }
+            }
+        }
+
+        self.populate_fast_working_tree();
+
+        info!(
+            "Fast working tree ready based on the resolutions."
+        );
+
+        Ok(())
+    }
+
+    fn destroy(&mut self) {
+        debug!(
+            "Writing {} resolutions on disk...",
+            self.resolution_db.len()
+        );
+        self.persist_resolutions();
+        if let Some(filepath) = &self.usage_stats_filepath {
+            self.usage.persist(filepath, &self.resolution_db);
+        }
+    }
+
+    fn lookup(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        reply: fuser::ReplyEntry,
+    ) {
+        let mut pending: Vec<PendingLookup> = self
+            .lookup_fast(req.into(), parent, name, reply.into())
+            .into_iter()
+            .collect();
+        while let Some(searched) = pending.pop() {
+            let outcome = self.search_handles().search(&searched);
+            pending.extend(self.complete_lookup(searched, outcome));
+        }
+    }
+
+    fn forget(&mut self, _req: &fuser::Request<'_>, ino: u64, nlookup: u64) {
+        if self.inodes.forget(ino, nlookup) {
+            trace!("reclaimed inode {}, {} inodes left", ino, self.inodes.len());
+            self.nix_paths.remove(&ino);
+            self.redirections.remove(&ino);
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: fuser::ReplyDirectory,
+    ) {
+        match self.directory_entries(ino, offset) {
+            Ok(entries) => {
+                for (child_ino, next_offset, kind, name) in entries {
+                    if reply.add(child_ino, next_offset, kind, name) {
+                        break;
+                    }
+                }
+                reply.ok();
+            }
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn statfs(&mut self, _req: &fuser::Request<'_>, _ino: u64, reply: fuser::ReplyStatfs) {
+        // Provided files end up in the fast working tree, report its filesystem
+        // so that free space checks of installers and configure scripts pass.
+        match nix::sys::statvfs::statvfs(&self.fast_working_tree) {
+            Ok(stat) => {
+                // Sizes the kernel takes in 32 bits, saturated rather than truncated.
+                let saturated = |size| u32::try_from(size).unwrap_or(u32::MAX);
+                reply.statfs(
+                    stat.blocks(),
+                    stat.blocks_free(),
+                    stat.blocks_available(),
+                    stat.files(),
+                    stat.files_free(),
+                    saturated(stat.block_size()),
+                    saturated(stat.name_max()),
+                    saturated(stat.fragment_size()),
+                )
+            }
+            Err(err) => {
+                warn!(
+                    "Failed to statvfs {}: {}",
+                    self.fast_working_tree.display(),
+                    err
+                );
+                reply.error(err as i32);
+            }
+        }
+    }
+
+    fn getxattr(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: fuser::ReplyXattr,
+    ) {
+        match self.xattr_value(ino, name) {
+            Some(value) => reply_xattr(reply, size, &value),
+            None => reply.error(nix::errno::Errno::ENODATA as i32),
+        }
+    }
+
+    fn listxattr(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        size: u32,
+        reply: fuser::ReplyXattr,
+    ) {
+        reply_xattr(reply, size, &self.xattr_names(ino));
+    }
+
+    fn readlink(&mut self, _req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyData) {
+        match self.link_target(ino) {
+            Some(LinkTarget::Store(nix_path)) => {
+                match self.search_handles().realized_link_target(&nix_path) {
+                    Ok(target) => reply.data(target.as_bytes()),
+                    Err(err) => reply.error(err),
+                }
+            }
+            Some(LinkTarget::Host(redirection_path)) => reply.data(&redirection_path),
+            None => reply.error(nix::errno::Errno::ENOENT as i32),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::database::candidate;
+    use std::os::unix::fs::MetadataExt;
+    use std::thread;
+
+    /// What the kernel would be answered for this lookup, `None` if it needs a search.
+    fn lookup(fs: &mut BuildXYZ, parent: u64, name: &str) -> Option<Result<(Duration, FileAttr), i32>> {
+        let (send, recv) = channel();
+        let req = RequestInfo {
+            unique: 1,
+            pid: std::process::id(),
+            uid: 0,
+            gid: 0,
+        };
+        fs.lookup_fast(req, parent, OsStr::new(name), LookupReply::Test(send))
+            .is_none()
+            .then(|| recv.try_recv().unwrap())
+    }
+
+    #[test]
+    fn test_entry_and_negative_ttls() {
+        let fast_working_tree = tempfile::tempdir().unwrap();
+        std::fs::create_dir(fast_working_tree.path().join("include")).unwrap();
+        std::fs::write(fast_working_tree.path().join("include/zlib.h"), "").unwrap();
+        let mut fs = BuildXYZ {
+            fast_working_tree: fast_working_tree.path().to_owned(),
+            ttls: CacheTtls {
+                entry: Duration::from_secs(5),
+                negative: Duration::from_secs(7),
+                directory: Duration::from_secs(11),
+            },
+            ..Default::default()
+        };
+        fs.mkdir_fhs_directories();
+        let root = fs.inodes.root();
+
+        let (ttl, include) = lookup(&mut fs, root, "include").unwrap().unwrap();
+        assert_eq!((ttl, include.kind), (Duration::from_secs(11), FileType::Directory));
+        let (ttl, zlib) = lookup(&mut fs, include.ino, "zlib.h").unwrap().unwrap();
+        assert_eq!((ttl, zlib.kind), (Duration::from_secs(5), FileType::Symlink));
+        // A negative entry is a zero inode.
+        let (ttl, etc) = lookup(&mut fs, root, "etc").unwrap().unwrap();
+        assert_eq!((ttl, etc.ino), (Duration::from_secs(7), 0));
+
+        fs.ttls = CacheTtls::disabled();
+        assert_eq!(lookup(&mut fs, root, "etc"), Some(Err(nix::errno::Errno::ENOENT as i32)));
+        let (ttl, _) = lookup(&mut fs, include.ino, "zlib.h").unwrap().unwrap();
+        assert_eq!(ttl, Duration::ZERO);
+    }
+
+    #[test]
+    fn test_shadow_symlink_leaves_survives_cycles() {
+        let store_path = tempfile::tempdir().unwrap();
+        let fast_working_tree = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(store_path.path().join("include/zlib")).unwrap();
+        std::fs::write(store_path.path().join("include/zlib/zlib.h"), "").unwrap();
+        // `include/zlib/parent` -> `include`, walked once.
+        std::os::unix::fs::symlink("..", store_path.path().join("include/zlib/parent")).unwrap();
+        std::os::unix::fs::symlink("zlib", store_path.path().join("include/alias")).unwrap();
+
+        shadow_symlink_leaves(
+            store_path.path(),
+            fast_working_tree.path(),
+            &vec!["nix-support"],
+            &mut HashSet::new(),
+            0,
+            LinkMode::Symlink,
+        )
+        .unwrap();
+        assert!(fast_working_tree.path().join("include/zlib/zlib.h").is_file());
+        assert!(fast_working_tree.path().join("include/alias/zlib.h").is_file());
+
+        // A link to itself is a structured error, not a hang.
+        std::os::unix::fs::symlink("loop", store_path.path().join("include/loop")).unwrap();
+        let err = shadow_symlink_leaves(
+            store_path.path(),
+            tempfile::tempdir().unwrap().path(),
+            &vec!["nix-support"],
+            &mut HashSet::new(),
+            0,
+            LinkMode::Symlink,
+        )
+        .unwrap_err();
+        // Reached through `include/zlib/parent/loop` as well, whichever is walked first.
+        assert!(matches!(
+            err,
+            ShadowSymlinkError::SymlinkLoop(path, MAX_SYMLINK_HOPS) if path.ends_with("loop")
+        ));
+    }
+    #[test]
+    fn test_fast_working_tree_link_modes() {
+        let store_path = tempfile::tempdir().unwrap();
+        std::fs::create_dir(store_path.path().join("include")).unwrap();
+        std::fs::write(store_path.path().join("include/zlib.h"), "#define ZLIB_VERSION").unwrap();
+
+        for link_mode in [LinkMode::Symlink, LinkMode::Hardlink, LinkMode::Copy] {
+            let fast_working_tree = tempfile::tempdir().unwrap();
+            shadow_symlink_leaves(
+                store_path.path(),
+                fast_working_tree.path(),
+                &vec!["nix-support"],
+                &mut HashSet::new(),
+                0,
+                link_mode,
+            )
+            .unwrap();
+
+            let leaf = fast_working_tree.path().join("include/zlib.h");
+            let original = std::fs::metadata(store_path.path().join("include/zlib.h")).unwrap();
+            let materialized = std::fs::symlink_metadata(&leaf).unwrap();
+            assert_eq!(std::fs::read_to_string(&leaf).unwrap(), "#define ZLIB_VERSION");
+            assert_eq!(materialized.is_symlink(), link_mode == LinkMode::Symlink);
+            assert_eq!(
+                materialized.ino() == original.ino(),
+                link_mode == LinkMode::Hardlink,
+                "{}",
+                link_mode.as_str()
+            );
+        }
+    }
+    #[test]
+    fn test_prompts_of_killed_requesters_are_cancelled() {
+        let (send_ui_event, recv_ui_event) = channel();
+        let fs = BuildXYZ {
+            send_ui_event,
+            ..Default::default()
+        };
+        let mut requester = std::process::Command::new("sleep").arg("60").spawn().unwrap();
+        let (send_reply, _recv_reply) = channel();
+        let pending = PendingLookup {
+            req: RequestInfo {
+                unique: 42,
+                pid: requester.id(),
+                uid: 0,
+                gid: 0,
+            },
+            parent: fs.inodes.root(),
+            name: "zlib.h".into(),
+            target_path: "include/zlib.h".into(),
+            requester: ProcessInfo::from_pid(requester.id()),
+            phase: None,
+            deferred: false,
+            reconfirm: None,
+            resolved: None,
+            reply: LookupReply::Test(send_reply),
+        };
+
+        let killer = thread::spawn(move || {
+            thread::sleep(INTERRUPT_POLL_INTERVAL);
+            requester.kill().unwrap();
+            requester.wait().unwrap();
+        });
+        assert!(matches!(
+            fs.search_handles().wait_for_answer(&pending),
+            PromptOutcome::Interrupted
+        ));
+        killer.join().unwrap();
+        assert!(matches!(recv_ui_event.try_recv(), Ok(UserRequest::Cancel(42))));
+    }
+    #[test]
+    fn test_every_lookup_is_traced_in_order() {
+        let fast_working_tree = tempfile::tempdir().unwrap();
+        std::fs::create_dir(fast_working_tree.path().join("include")).unwrap();
+        std::fs::write(fast_working_tree.path().join("include/zlib.h"), "").unwrap();
+        let trace_path = fast_working_tree.path().join("trace.jsonl");
+        let mut fs = BuildXYZ {
+            fast_working_tree: fast_working_tree.path().to_owned(),
+            access_trace: Some(AccessTrace::create(&trace_path).unwrap()),
+            blocklist: PathBlocklist::new(&[], &[], true).unwrap(),
+            ..Default::default()
+        };
+        fs.mkdir_fhs_directories();
+        let root = fs.inodes.root();
+
+        let (_, include) = lookup(&mut fs, root, "include").unwrap().unwrap();
+        lookup(&mut fs, include.ino, "zlib.h").unwrap().unwrap();
+        lookup(&mut fs, include.ino, "zlib.h~").unwrap().unwrap_err();
+        lookup(&mut fs, root, "etc").unwrap().unwrap_err();
+        drop(fs);
+
+        let events: Vec<serde_json::Value> = std::fs::read_to_string(&trace_path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let accesses: Vec<(&str, &str)> = events
+            .iter()
+            .map(|event| (event["path"].as_str().unwrap(), event["outcome"].as_str().unwrap()))
+            .collect();
+        assert_eq!(
+            accesses,
+            vec![
+                ("include", "global-directory"),
+                ("include/zlib.h", "fast-working-tree"),
+                ("include/zlib.h~", "blocked"),
+                ("etc", "not-found"),
+            ]
+        );
+        assert_eq!(
+            events[1]["requested_by"]["pid"].as_u64(),
+            Some(std::process::id() as u64)
+        );
+        assert!(events.windows(2).all(|pair| pair[0]["elapsed_ms"].as_u64() <= pair[1]["elapsed_ms"].as_u64()));
+    }
+
+    #[test]
+    fn test_index_searches_are_cached() {
+        let fs = BuildXYZ {
+            case_insensitive: true,
+            ..Default::default()
+        };
+        let search = fs.search_handles();
+
+        // Misses are cached too.
+        assert!(search.search_in_index(Path::new("include/zlib.h")).is_empty());
+        assert_eq!(
+            fs.query_cache.lock().unwrap().peek("include/zlib.h"),
+            Some(&Vec::new())
+        );
+
+        let zlib = candidate(
+            "zlib",
+            "out",
+            true,
+            "/nix/store/8vvhlnnbjb7sdmgdcb9ixyw4k7nqp56w-zlib-1.3-dev",
+            "/include/zlib.h",
+        );
+        fs.query_cache
+            .lock()
+            .unwrap()
+            .put("include/zlib.h".into(), vec![zlib.clone()]);
+        // Shared by every worker, whatever the case of the requested path.
+        assert_eq!(
+            fs.search_handles().search_in_index(Path::new("include/ZLIB.h")),
+            vec![zlib]
+        );
+    }
+    #[test]
+    fn test_readdir_entries() {
+        let fast_working_tree = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(fast_working_tree.path().join("lib/pkgconfig")).unwrap();
+        std::fs::write(fast_working_tree.path().join("lib/pkgconfig/zlib.pc"), "").unwrap();
+        let mut fs = BuildXYZ {
+            fast_working_tree: fast_working_tree.path().to_owned(),
+            ..Default::default()
+        };
+        fs.mkdir_fhs_directories();
+        let root = fs.inodes.root();
+        let (_, lib) = lookup(&mut fs, root, "lib").unwrap().unwrap();
+        let (_, pkgconfig) = lookup(&mut fs, lib.ino, "pkgconfig").unwrap().unwrap();
+        let (_, zlib) = lookup(&mut fs, pkgconfig.ino, "zlib.pc").unwrap().unwrap();
+
+        assert_eq!(
+            fs.directory_entries(pkgconfig.ino, 0),
+            Ok(vec![
+                (pkgconfig.ino, 1, FileType::Directory, ".".to_string()),
+                (lib.ino, 2, FileType::Directory, "..".to_string()),
+                (zlib.ino, 3, FileType::Symlink, "zlib.pc".to_string()),
+            ])
+        );
+        let entries = fs.directory_entries(root, 0).unwrap();
+        assert_eq!(
+            entries[..2],
+            [
+                (root, 1, FileType::Directory, ".".to_string()),
+                (root, 2, FileType::Directory, "..".to_string()),
+            ]
+        );
+        // Listing resumes after the offset of the last entry returned.
+        assert_eq!(fs.directory_entries(root, 2).unwrap(), entries[2..]);
+        assert_eq!(fs.directory_entries(pkgconfig.ino, 3), Ok(Vec::new()));
+        assert_eq!(fs.directory_entries(zlib.ino, 0), Err(nix::errno::Errno::ENOTDIR as i32));
+        assert_eq!(fs.directory_entries(12345, 0), Err(nix::errno::Errno::ENOENT as i32));
+    }
+    /// Lookup the index has no candidate for, completed as if it was searched.
+    fn lookup_not_found(fs: &mut BuildXYZ, parent: u64, name: &str) -> Result<(Duration, FileAttr), i32> {
+        let (send, recv) = channel();
+        let req = RequestInfo {
+            unique: 2,
+            pid: std::process::id(),
+            uid: 0,
+            gid: 0,
+        };
+        let pending = fs
+            .lookup_fast(req, parent, OsStr::new(name), LookupReply::Test(send))
+            .expect("Expected the lookup to need a search");
+        fs.complete_lookup(pending, SearchOutcome::NotFound);
+        recv.try_recv().unwrap()
+    }
+
+    #[test]
+    fn test_host_fallback() {
+        let fast_working_tree = tempfile::tempdir().unwrap();
+        let mut fs = BuildXYZ {
+            fast_working_tree: fast_working_tree.path().to_owned(),
+            fallback_host: true,
+            ..Default::default()
+        };
+        fs.mkdir_fhs_directories();
+        let bin = fs.inodes.get("bin").unwrap();
+        let host_sh = find_on_host(Path::new("bin/sh")).unwrap();
+
+        let (_, sh) = lookup_not_found(&mut fs, bin, "sh").unwrap();
+        assert_eq!(sh.kind, FileType::Symlink);
+        assert_eq!(fs.redirections[&sh.ino], host_sh.to_string_lossy().as_bytes());
+        assert_eq!(
+            fs.get_decision(bin, OsStr::new("sh"), None),
+            Some(&Decision::HostFallback { host_path: host_sh })
+        );
+        // Recorded, it answers the next lookups right away.
+        let (_, again) = lookup(&mut fs, bin, "sh").unwrap().unwrap();
+        assert_eq!(again.ino, sh.ino);
+
+        assert_eq!(
+            lookup_not_found(&mut fs, bin, "buildxyz-missing-tool"),
+            Err(nix::errno::Errno::ENOENT as i32)
+        );
+        fs.fallback_host = false;
+        assert_eq!(
+            lookup_not_found(&mut fs, bin, "env"),
+            Err(nix::errno::Errno::ENOENT as i32)
+        );
+    }
+
+    #[test]
+    fn test_redirections_are_relative_to_the_working_directory() {
+        let mut fs = BuildXYZ::default();
+        fs.mkdir_fhs_directories();
+        for (requested_path, target) in [("bin/tool", "src/main.rs"), ("bin/gone", "buildxyz-missing-tool")] {
+            let data = ResolutionData {
+                requested_path: requested_path.into(),
+                decision: Decision::Redirect {
+                    target: target.into(),
+                },
+                requested_by: None,
+                priority: 0,
+                source: None,
+                provenance: Default::default(),
+                reconfirm: false,
+            };
+            insert_resolution(&mut fs.resolution_db, data, None);
+        }
+        let bin = fs.inodes.get("bin").unwrap();
+
+        let (_, tool) = lookup(&mut fs, bin, "tool").unwrap().unwrap();
+        assert_eq!(tool.kind, FileType::Symlink);
+        let Some(LinkTarget::Host(target)) = fs.link_target(tool.ino) else {
+            panic!("bin/tool should point to the host");
+        };
+        assert_eq!(PathBuf::from(OsString::from_vec(target)), std::env::current_dir().unwrap().join("src/main.rs"));
+        assert_eq!(lookup(&mut fs, bin, "gone"), Some(Err(nix::errno::Errno::ENOENT as i32)));
+    }
+
+    #[test]
+    fn test_deferred_paths_are_always_searched() {
+        let fast_working_tree = tempfile::tempdir().unwrap();
+        std::fs::create_dir(fast_working_tree.path().join("bin")).unwrap();
+        // As shadowed by another package.
+        std::fs::write(fast_working_tree.path().join("bin/cc"), "").unwrap();
+        let mut fs = BuildXYZ {
+            fast_working_tree: fast_working_tree.path().to_owned(),
+            ..Default::default()
+        };
+        fs.mkdir_fhs_directories();
+        let data = ResolutionData {
+            requested_path: "bin/cc".into(),
+            decision: Decision::Defer,
+            requested_by: None,
+            priority: 0,
+            source: None,
+            provenance: Default::default(),
+            reconfirm: false,
+        };
+        insert_resolution(&mut fs.resolution_db, data, None);
+        let bin = fs.inodes.get("bin").unwrap();
+        let req = RequestInfo {
+            unique: 6,
+            pid: std::process::id(),
+            uid: 0,
+            gid: 0,
+        };
+
+        for _ in 0..2 {
+            let (send, recv) = channel();
+            let pending = fs
+                .lookup_fast(req, bin, OsStr::new("cc"), LookupReply::Test(send))
+                .expect("a deferred path is not answered from the fast working tree");
+            assert!(pending.deferred);
+            assert!(fs.complete_lookup(pending, SearchOutcome::Ignore).is_empty());
+            assert_eq!(recv.try_recv().unwrap(), Err(nix::errno::Errno::ENOENT as i32));
+            // The answer is not recorded, the next lookup asks again.
+            assert_eq!(
+                fs.get_decision(bin, OsStr::new("cc"), None),
+                Some(&Decision::Defer)
+            );
+        }
+    }
+
+    #[test]
+    fn test_ignored_trees_cover_their_descendants() {
+        let mut fs = BuildXYZ::default();
+        fs.mkdir_fhs_directories();
+        let data = ResolutionData {
+            requested_path: "include/boost".into(),
+            decision: Decision::IgnoreTree,
+            requested_by: None,
+            priority: 0,
+            source: None,
+            provenance: Default::default(),
+            reconfirm: false,
+        };
+        insert_resolution(&mut fs.resolution_db, data, None);
+        let include = fs.inodes.get("include").unwrap();
+        // As looked up by a process which knew it before.
+        let boost = fs.inodes.lookup("include/boost");
+        let detail = fs.inodes.lookup("include/boost/detail");
+        let enoent = Some(Err(nix::errno::Errno::ENOENT as i32));
+
+        assert_eq!(lookup(&mut fs, include, "boost"), enoent);
+        assert_eq!(lookup(&mut fs, boost, "any.hpp"), enoent);
+        assert_eq!(lookup(&mut fs, detail, "workaround.hpp"), enoent);
+        // Only under the tree.
+        assert_eq!(lookup(&mut fs, include, "boost.h"), None);
+    }
+
+    #[test]
+    fn test_phase_resolutions_are_only_served_in_their_phase() {
+        let fast_working_tree = tempfile::tempdir().unwrap();
+        std::fs::create_dir(fast_working_tree.path().join("bin")).unwrap();
+        // As shadowed by a former session in the configure phase.
+        std::fs::write(fast_working_tree.path().join("bin/hello"), "").unwrap();
+        let (send_phase, recv_phase) = channel();
+        let mut fs = BuildXYZ {
+            fast_working_tree: fast_working_tree.path().to_owned(),
+            phase: Some("build".into()),
+            phase_requests: Some(recv_phase),
+            ..Default::default()
+        };
+        fs.mkdir_fhs_directories();
+        let (store_path, _) = candidate(
+            "hello",
+            "out",
+            true,
+            "/nix/store/0c4d9ykb8ya8d2ysx6x7i5hmd3j9anv7-hello-2.12",
+            "/bin/hello",
+        );
+        let data = ResolutionData {
+            requested_path: "bin/hello".into(),
+            decision: Decision::Provide(Box::new(ProvideData {
+                kind: FileType::RegularFile,
+                file_entry_name: "/bin/hello".into(),
+                store_path: store_path.clone(),
+                link_mode: None,
+                flake: None,
+            })),
+            requested_by: None,
+            priority: 0,
+            source: None,
+            provenance: Default::default(),
+            reconfirm: false,
+        };
+        insert_resolution(&mut fs.resolution_db, data, Some("configure"));
+        let bin = fs.inodes.get("bin").unwrap();
+
+        assert!(fs.store_paths_of_phase().is_empty());
+        assert_eq!(lookup(&mut fs, bin, "hello"), None);
+
+        send_phase.send(Some("configure".into())).unwrap();
+        let (_, hello) = lookup(&mut fs, bin, "hello").unwrap().unwrap();
+        assert_eq!(hello.kind, FileType::Symlink);
+        assert_eq!(fs.phase.as_deref(), Some("configure"));
+        assert!(fs.fast_working_tree_store_paths.contains(store_path.as_str().as_ref()));
+
+        send_phase.send(Some("test".into())).unwrap();
+        assert_eq!(lookup(&mut fs, bin, "hello"), None);
+        assert!(fs.fast_working_tree_store_paths.is_empty());
+    }
+
+    /// Answer a prompt as the user would, once its lookup waits for it.
+    fn answer(answers: &AnswerRouter, send_answer: &Sender<FsEventMessage>, event: FsEventMessage) {
+        let request_id = event.answered_request().unwrap();
+        while !answers.is_waiting(request_id) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        send_answer.send(event).unwrap();
+    }
+
+    #[test]
+    fn test_the_chosen_candidate_is_provided() {
+        let fast_working_tree = tempfile::tempdir().unwrap();
+        let (send_ui_event, recv_ui_event) = channel();
+        let (send_answer, recv_answer) = channel();
+        let answers = AnswerRouter::spawn(recv_answer);
+        let mut fs = BuildXYZ {
+            fast_working_tree: fast_working_tree.path().to_owned(),
+            send_ui_event,
+            answers: answers.clone(),
+            realizer: Realizer::default().with_realize(|_, _| Ok(())),
+            ..Default::default()
+        };
+        fs.mkdir_fhs_directories();
+        let hello = candidate(
+            "hello",
+            "out",
+            true,
+            "/nix/store/0c4d9ykb8ya8d2ysx6x7i5hmd3j9anv7-hello-2.12",
+            "/bin/hello",
+        );
+        let (wrapper_path, _) = candidate(
+            "hello-wrapper",
+            "out",
+            true,
+            "/nix/store/1c4d9ykb8ya8d2ysx6x7i5hmd3j9anv7-hello-wrapper-1.0",
+            "/bin/hello",
+        );
+        let wrapper = (
+            wrapper_path,
+            FileTreeEntry {
+                path: b"/bin/hello".to_vec(),
+                node: FileNode::Symlink {
+                    target: b"hello-2.12".to_vec().into(),
+                },
+            },
+        );
+        let (directory_path, _) = candidate(
+            "hello-docs",
+            "out",
+            true,
+            "/nix/store/2c4d9ykb8ya8d2ysx6x7i5hmd3j9anv7-hello-docs-1.0",
+            "/bin/hello",
+        );
+        let directory = (
+            directory_path,
+            FileTreeEntry {
+                path: b"/bin/hello".to_vec(),
+                node: FileNode::Directory {
+                    size: 0,
+                    contents: (),
+                },
+            },
+        );
+        fs.query_cache.lock().unwrap().put(
+            "bin/hello".into(),
+            vec![hello.clone(), wrapper.clone(), directory],
+        );
+        let bin = fs.inodes.get("bin").unwrap();
+        let (send, _recv) = channel();
+        let req = RequestInfo {
+            unique: 4,
+            pid: std::process::id(),
+            uid: 0,
+            gid: 0,
+        };
+        let pending = fs
+            .lookup_fast(req, bin, OsStr::new("hello"), LookupReply::Test(send))
+            .unwrap();
+
+        let search = fs.search_handles();
+        let searched = thread::spawn(move || search.search(&pending));
+        let Ok(UserRequest::InteractiveSearch(id, candidates, _, suggestion, ..)) =
+            recv_ui_event.recv_timeout(Duration::from_secs(5))
+        else {
+            panic!("the candidates should be prompted");
+        };
+        // The directory cannot be served along with the files.
+        assert_eq!(candidates, vec![hello.clone(), wrapper.clone()]);
+        assert_eq!(suggestion, hello);
+        answer(&answers, &send_answer, FsEventMessage::PackageSuggestion(id, wrapper.clone()));
+
+        let SearchOutcome::Provide(provided) = searched.join().unwrap() else {
+            panic!("the chosen candidate should be provided");
+        };
+        assert_eq!(provided.entry, wrapper.1);
+        assert_eq!(provided.attribute.kind, FileType::Symlink);
+    }
+
+    #[test]
+    fn test_manual_searches_only_offer_the_same_kind() {
+        let (send_ui_event, recv_ui_event) = channel();
+        let (send_answer, recv_answer) = channel();
+        let answers = AnswerRouter::spawn(recv_answer);
+        let mut fs = BuildXYZ {
+            send_ui_event,
+            answers: answers.clone(),
+            ..Default::default()
+        };
+        fs.mkdir_fhs_directories();
+        let bin = fs.inodes.get("bin").unwrap();
+        let (send, _recv) = channel();
+        let req = RequestInfo {
+            unique: 5,
+            pid: std::process::id(),
+            uid: 0,
+            gid: 0,
+        };
+        let pending = fs
+            .lookup_fast(req, bin, OsStr::new("hello"), LookupReply::Test(send))
+            .unwrap();
+        let search = fs.search_handles();
+        let searched = thread::spawn(move || search.search(&pending));
+
+        let prompted = || match recv_ui_event.recv_timeout(Duration::from_secs(5)) {
+            Ok(UserRequest::InteractiveSearch(id, candidates, ..)) => (id, candidates),
+            _ => panic!("the candidates should be prompted"),
+        };
+        let (id, candidates) = prompted();
+        assert!(candidates.iter().all(|(_, entry)| !is_dir(&entry.node)));
+        // Matches `/bin` as well as `/bin/hello`.
+        answer(&answers, &send_answer, FsEventMessage::ManualSearch(id, "^/bin".into()));
+        let (id, found) = prompted();
+        assert_eq!(
+            found.iter().map(|(_, entry)| entry.path.clone()).collect::<Vec<_>>(),
+            vec![b"/bin/hello".to_vec()]
+        );
+        answer(&answers, &send_answer, FsEventMessage::IgnoreRequest(id));
+        assert!(matches!(searched.join().unwrap(), SearchOutcome::Ignore));
+    }
+
+    #[test]
+    fn test_resolutions_are_found_regardless_of_case() {
+        let mut fs = BuildXYZ {
+            case_insensitive: true,
+            ..Default::default()
+        };
+        let data = ResolutionData {
+            requested_path: "include/Zlib.h".into(),
+            decision: Decision::Ignore,
+            requested_by: None,
+            priority: 0,
+            source: None,
+            provenance: Default::default(),
+            reconfirm: false,
+        };
+        insert_resolution(&mut fs.resolution_db, data, None);
+        fs.index_resolutions();
+
+        assert!(fs.get_resolution_of_path("include/zlib.h").is_some());
+        assert!(fs.get_resolution_of_path("INCLUDE/ZLIB.H").is_some());
+        assert!(fs.get_resolution_of_path("include/zlib.hpp").is_none());
+
+        fs.resolution_db.clear();
+        fs.index_resolutions();
+        assert!(fs.get_resolution_of_path("include/zlib.h").is_none());
+    }
+
+    #[test]
+    fn test_xattrs_of_served_paths() {
+        let host = tempfile::NamedTempFile::new_in(std::env::current_dir().unwrap()).unwrap();
+        let mut fs = BuildXYZ::default();
+        fs.redirections
+            .insert(42, host.path().as_os_str().to_os_string().into_vec());
+
+        assert_eq!(fs.xattr_value(42, OsStr::new("user.buildxyz")), None);
+        assert_eq!(fs.xattr_value(43, OsStr::new("user.buildxyz")), None);
+        assert!(fs.xattr_names(43).is_empty());
+        // Not every filesystem has user attributes.
+        if xattr::set(host.path(), "user.buildxyz", b"provided").is_err() {
+            return;
+        }
+        assert_eq!(
+            fs.xattr_value(42, OsStr::new("user.buildxyz")),
+            Some(b"provided".to_vec())
+        );
+        assert!(fs
+            .xattr_names(42)
+            .split(|byte| *byte == 0)
+            .any(|name| name == b"user.buildxyz"));
+    }
+
+    #[test]
+    fn test_served_executables_keep_their_mode() {
+        let fast_working_tree = tempfile::tempdir().unwrap();
+        let tool = fast_working_tree.path().join("bin/tool");
+        std::fs::create_dir_all(tool.parent().unwrap()).unwrap();
+        std::fs::write(&tool, "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&tool, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let mut fs = BuildXYZ {
+            fast_working_tree: fast_working_tree.path().to_owned(),
+            ..Default::default()
+        };
+        fs.mkdir_fhs_directories();
+        let bin = fs.inodes.get("bin").unwrap();
+
+        // Served as a symlink, `test -x` follows it to the executable.
+        let (_, served) = lookup(&mut fs, bin, "tool").unwrap().unwrap();
+        assert_eq!(served.kind, FileType::Symlink);
+        let target = PathBuf::from(OsString::from_vec(fs.redirections[&served.ino].clone()));
+        assert_eq!(std::fs::metadata(target).unwrap().mode() & 0o777, 0o755);
+
+        let attribute = served_attribute(build_fake_fattr(42, FileType::RegularFile), &tool);
+        assert_eq!((attribute.kind, attribute.perm), (FileType::Symlink, 0o777));
+        std::fs::set_permissions(fast_working_tree.path(), std::fs::Permissions::from_mode(0o750)).unwrap();
+        let attribute = served_attribute(
+            build_fake_fattr(43, FileType::Directory),
+            fast_working_tree.path(),
+        );
+        assert_eq!((attribute.kind, attribute.perm), (FileType::Directory, 0o750));
+    }
+
+    #[test]
+    fn test_confirmed_lookups_are_searched_again() {
+        let fast_working_tree = tempfile::tempdir().unwrap();
+        let mut fs = BuildXYZ {
+            fast_working_tree: fast_working_tree.path().to_owned(),
+            ..Default::default()
+        };
+        fs.mkdir_fhs_directories();
+        let (store_path, _) = candidate(
+            "hello",
+            "out",
+            true,
+            "/nix/store/0c4d9ykb8ya8d2ysx6x7i5hmd3j9anv7-hello-2.12",
+            "/bin/hello",
+        );
+        let data = ResolutionData {
+            requested_path: "bin/hello".into(),
+            decision: Decision::Provide(Box::new(ProvideData {
+                kind: FileType::Symlink,
+                file_entry_name: "/bin/hello".into(),
+                store_path,
+                link_mode: None,
+                flake: None,
+            })),
+            requested_by: None,
+            priority: 0,
+            source: None,
+            provenance: Default::default(),
+            reconfirm: true,
+        };
+        insert_resolution(&mut fs.resolution_db, data, None);
+        let bin = fs.inodes.get("bin").unwrap();
+        let (send, recv) = channel();
+        let req = RequestInfo {
+            unique: 3,
+            pid: std::process::id(),
+            uid: 0,
+            gid: 0,
+        };
+
+        let pending = fs
+            .lookup_fast(req, bin, OsStr::new("hello"), LookupReply::Test(send))
+            .unwrap();
+        assert!(pending.reconfirm.is_some());
+        // The package of the confirmed resolution is realized by the caller, not under the filesystem.
+        let pending = fs.complete_lookup(pending, SearchOutcome::Confirmed).pop().unwrap();
+        assert!(pending.resolved.is_some() && pending.reconfirm.is_none());
+        assert!(recv.try_recv().is_err());
+        assert_eq!(fs.reconfirmed.get("bin/hello"), Some(&true));
+
+        let nix_path = b"/nix/store/0c4d9ykb8ya8d2ysx6x7i5hmd3j9anv7-hello-2.12/bin/hello".to_vec();
+        assert!(fs
+            .complete_lookup(pending, SearchOutcome::Resolved(nix_path, FileType::Symlink))
+            .is_empty());
+        let (_, hello) = recv.try_recv().unwrap().unwrap();
+        assert_eq!(hello.kind, FileType::Symlink);
+    }
+
+    #[test]
+    fn test_lookups_of_a_pending_path_wait_for_it() {
+        let fast_working_tree = tempfile::tempdir().unwrap();
+        let mut fs = BuildXYZ {
+            fast_working_tree: fast_working_tree.path().to_owned(),
+            ..Default::default()
+        };
+        fs.mkdir_fhs_directories();
+        let bin = fs.inodes.get("bin").unwrap();
+        let request = |unique| RequestInfo {
+            unique,
+            pid: std::process::id(),
+            uid: 0,
+            gid: 0,
+        };
+
+        let (send, recv) = channel();
+        let pending = fs
+            .lookup_fast(request(1), bin, OsStr::new("hello"), LookupReply::Test(send))
+            .unwrap();
+        assert!(pending.resolved.is_none());
+        // Another process looks it up while it is searched, it is neither searched nor answered.
+        let (send, recv_parked) = channel();
+        assert!(fs
+            .lookup_fast(request(2), bin, OsStr::new("hello"), LookupReply::Test(send))
+            .is_none());
+        assert!(recv_parked.try_recv().is_err());
+
+        let (store_path, entry) = candidate(
+            "hello",
+            "out",
+            true,
+            "/nix/store/0c4d9ykb8ya8d2ysx6x7i5hmd3j9anv7-hello-2.12",
+            "/bin/hello",
+        );
+        let outcome = SearchOutcome::Provide(Box::new(ProvidedCandidate {
+            store_path,
+            entry,
+            attribute: build_fake_fattr(0, FileType::Symlink),
+            subtree: None,
+            candidates: Vec::new(),
+        }));
+        let mut resumed = fs.complete_lookup(pending, outcome);
+        assert!(recv.try_recv().unwrap().is_ok());
+        // The waiting lookup is resumed with the resolution recorded meanwhile, only its package is realized.
+        let pending = resumed.pop().unwrap();
+        assert!(resumed.is_empty() && pending.resolved.is_some());
+
+        let nix_path = b"/nix/store/0c4d9ykb8ya8d2ysx6x7i5hmd3j9anv7-hello-2.12/bin/hello".to_vec();
+        assert!(fs
+            .complete_lookup(pending, SearchOutcome::Resolved(nix_path, FileType::Symlink))
+            .is_empty());
+        assert!(recv_parked.try_recv().unwrap().is_ok());
+        assert!(fs.in_flight_lookups.is_empty());
+    }
+
+    #[test]
+    fn test_undone_decisions_are_searched_again() {
+        let fast_working_tree = tempfile::tempdir().unwrap();
+        let mut fs = BuildXYZ {
+            fast_working_tree: fast_working_tree.path().to_owned(),
+            ..Default::default()
+        };
+        fs.mkdir_fhs_directories();
+        let hello = "/nix/store/0c4d9ykb8ya8d2ysx6x7i5hmd3j9anv7-hello-2.12";
+        let (store_path, _) = candidate("hello", "out", true, hello, "/bin/hello");
+        let data = ResolutionData {
+            requested_path: "bin/hello".into(),
+            decision: Decision::Provide(Box::new(ProvideData {
+                kind: FileType::Symlink,
+                file_entry_name: "/bin/hello".into(),
+                store_path,
+                link_mode: None,
+                flake: None,
+            })),
+            requested_by: None,
+            priority: 0,
+            source: None,
+            provenance: Default::default(),
+            reconfirm: false,
+        };
+        insert_resolution(&mut fs.resolution_db, data, None);
+        fs.remember_decision(
+            7,
+            SessionDecision {
+                requested_path: "bin/hello".into(),
+                phase: None,
+                store_path: Some(hello.into()),
+                subtree_pattern: None,
+                enoent_key: None,
+                description: format!("provide from {}", hello),
+                requested_by: ProcessInfo::from_pid(std::process::id()),
+                candidates: Vec::new(),
+            },
+        );
+        // Shadowed leaves of the package, and of another one.
+        let (shadowed, other) = (
+            fast_working_tree.path().join("share/hello/hello.info"),
+            fast_working_tree.path().join("share/zlib/zlib.3"),
+        );
+        std::fs::create_dir_all(shadowed.parent().unwrap()).unwrap();
+        std::fs::create_dir_all(other.parent().unwrap()).unwrap();
+        std::os::unix::fs::symlink(format!("{}/share/hello/hello.info", hello), &shadowed).unwrap();
+        std::os::unix::fs::symlink(
+            "/nix/store/8vvhlnnbjb7sdmgdcb9ixyw4k7nqp56w-zlib-1.3/share/zlib/zlib.3",
+            &other,
+        )
+        .unwrap();
+        let bin = fs.inodes.get("bin").unwrap();
+        let request = |unique| RequestInfo {
+            unique,
+            pid: std::process::id(),
+            uid: 0,
+            gid: 0,
+        };
+
+        let (send, _recv) = channel();
+        let pending = fs
+            .lookup_fast(request(1), bin, OsStr::new("hello"), LookupReply::Test(send))
+            .unwrap();
+        assert!(pending.resolved.is_some());
+        // Its realization is over before the path is looked up again.
+        fs.complete_lookup(pending, SearchOutcome::Interrupted);
+
+        assert!(fs.undo_decision(7).is_some());
+        assert!(!fs.resolution_db.contains_key("bin/hello"));
+        assert!(fs.undone_resolutions.contains("bin/hello"));
+        assert!(shadowed.symlink_metadata().is_err());
+        assert!(other.symlink_metadata().is_ok());
+        // Nothing is left to undo, the next lookup searches the path again.
+        assert!(fs.undo_decision(7).is_none());
+        let (send, _recv) = channel();
+        let pending = fs
+            .lookup_fast(request(2), bin, OsStr::new("hello"), LookupReply::Test(send))
+            .unwrap();
+        assert!(pending.resolved.is_none());
+    }
+
+    #[test]
+    fn test_subtree_answers_provide_the_whole_subtree() {
+        let fast_working_tree = tempfile::tempdir().unwrap();
+        let (send_ui_event, recv_ui_event) = channel();
+        let (send_answer, recv_answer) = channel();
+        let answers = AnswerRouter::spawn(recv_answer);
+        let mut fs = BuildXYZ {
+            fast_working_tree: fast_working_tree.path().to_owned(),
+            send_ui_event,
+            answers: answers.clone(),
+            realizer: Realizer::default().with_realize(|_, _| Ok(())),
+            ..Default::default()
+        };
+        fs.mkdir_fhs_directories();
+        fs.mkdir_fhs_directory("include/boost");
+        let boost = candidate(
+            "boost",
+            "out",
+            true,
+            "/nix/store/3c4d9ykb8ya8d2ysx6x7i5hmd3j9anv7-boost-1.81.0-dev",
+            "/include/boost/version.hpp",
+        );
+        fs.query_cache
+            .lock()
+            .unwrap()
+            .put("include/boost/version.hpp".into(), vec![boost.clone()]);
+        let include_boost = fs.inodes.get("include/boost").unwrap();
+        let (send, recv) = channel();
+        let req = RequestInfo {
+            unique: 5,
+            pid: std::process::id(),
+            uid: 0,
+            gid: 0,
+        };
+        let pending = fs
+            .lookup_fast(req, include_boost, OsStr::new("version.hpp"), LookupReply::Test(send))
+            .unwrap();
+
+        let search = fs.search_handles();
+        let searched = thread::spawn(move || {
+            let outcome = search.search(&pending);
+            (pending, outcome)
+        });
+        let Ok(UserRequest::InteractiveSearch(id, .., subtree, _)) =
+            recv_ui_event.recv_timeout(Duration::from_secs(5))
+        else {
+            panic!("the candidates should be prompted");
+        };
+        assert_eq!(subtree, Some(PathBuf::from("include/boost")));
+        answer(&answers, &send_answer, FsEventMessage::SubtreeSuggestion(id, boost));
+
+        let (pending, outcome) = searched.join().unwrap();
+        assert!(fs.complete_lookup(pending, outcome).is_empty());
+        assert!(recv.try_recv().unwrap().is_ok());
+        assert!(fs.resolution_db.contains_key("include/boost/version.hpp"));
+        assert!(matches!(
+            fs.resolution_db.get("include/boost/**"),
+            Some(Resolution::PatternResolution(_))
+        ));
+        assert!(fs.session_decisions[&5].description.ends_with(", and include/boost/**"));
+    }
+
+    #[test]
+    fn test_reviewed_decisions_are_recorded_once_reviewed() {
+        let record = tempfile::tempdir().unwrap();
+        let record_filepath = record.path().join("buildxyz.toml");
+        let mut fs = BuildXYZ {
+            resolution_record_filepath: Some(record_filepath.clone()),
+            defer_record: true,
+            ..Default::default()
+        };
+        let zlib = candidate(
+            "zlib",
+            "out",
+            true,
+            "/nix/store/8vvhlnnbjb7sdmgdcb9ixyw4k7nqp56w-zlib-1.3-dev",
+            "/include/zlib.h",
+        );
+        let zlib_ng = candidate(
+            "zlib-ng",
+            "out",
+            true,
+            "/nix/store/2zmbsy7nfyd8q7ss8vc8nlmq7q9ygnl4-zlib-ng-2.1-dev",
+            "/include/zlib.h",
+        );
+        let requester = ProcessInfo::from_pid(std::process::id());
+        for (id, requested_path, (store_path, entry)) in [
+            (1, "include/zlib.h", zlib.clone()),
+            (2, "include/zconf.h", zlib.clone()),
+        ] {
+            let decision = Decision::Provide(Box::new(ProvideData {
+                kind: FileType::RegularFile,
+                file_entry_name: String::from_utf8_lossy(&entry.path).to_string(),
+                store_path: store_path.clone(),
+                link_mode: None,
+                flake: None,
+            }));
+            fs.record_resolution(Path::new(requested_path), decision, &requester, None);
+            fs.remember_decision(
+                id,
+                SessionDecision {
+                    requested_path: requested_path.into(),
+                    phase: None,
+                    store_path: Some(store_path.as_str().to_string()),
+                    subtree_pattern: None,
+                    enoent_key: None,
+                    description: format!("provide from {}", store_path.as_str()),
+                    requested_by: requester.clone(),
+                    candidates: vec![zlib.clone(), zlib_ng.clone()],
+                },
+            );
+        }
+        // Automatically accepted decisions wait for the review.
+        assert!(!record_filepath.exists());
+
+        fs.review_decisions(|items| {
+            assert_eq!(items.len(), 2);
+            assert_eq!((items[0].request_id, items[0].chosen), (1, Some(0)));
+            vec![(1, ReviewVerdict::Change(1)), (2, ReviewVerdict::Drop)]
+        });
+        assert!(!record_filepath.exists());
+        fs.write_deferred_record();
+
+        let recorded = crate::resolution::read_resolution_db(&std::fs::read_to_string(&record_filepath).unwrap())
+            .unwrap();
+        assert_eq!(recorded.len(), 1);
+        let Decision::Provide(data) = &recorded["include/zlib.h"].all_data()[0].decision else {
+            panic!("the changed candidate should be provided");
+        };
+        assert_eq!(data.store_path, zlib_ng.0);
+    }
+
+    #[test]
+    fn test_skipped_prompts_are_asked_again() {
+        let (send_ui_event, recv_ui_event) = channel();
+        let (send_answer, recv_answer) = channel();
+        let answers = AnswerRouter::spawn(recv_answer);
+        let mut fs = BuildXYZ {
+            send_ui_event,
+            answers: answers.clone(),
+            ..Default::default()
+        };
+        fs.mkdir_fhs_directories();
+        let zlib = candidate(
+            "zlib",
+            "out",
+            true,
+            "/nix/store/8vvhlnnbjb7sdmgdcb9ixyw4k7nqp56w-zlib-1.3-dev",
+            "/include/zlib.h",
+        );
+        fs.query_cache.lock().unwrap().put("include/zlib.h".into(), vec![zlib]);
+        let include = fs.inodes.get("include").unwrap();
+
+        for unique in [6, 7] {
+            let (send, recv) = channel();
+            let req = RequestInfo {
+                unique,
+                pid: std::process::id(),
+                uid: 0,
+                gid: 0,
+            };
+            let pending = fs
+                .lookup_fast(req, include, OsStr::new("zlib.h"), LookupReply::Test(send))
+                .unwrap();
+            let search = fs.search_handles();
+            let searched = thread::spawn(move || {
+                let outcome = search.search(&pending);
+                (pending, outcome)
+            });
+            let Ok(UserRequest::InteractiveSearch(id, ..)) = recv_ui_event.recv_timeout(Duration::from_secs(5))
+            else {
+                panic!("the candidates should be prompted on every lookup");
+            };
+            answer(&answers, &send_answer, FsEventMessage::SkipForNow(id));
+
+            let (pending, outcome) = searched.join().unwrap();
+            assert!(matches!(outcome, SearchOutcome::SkipForNow));
+            assert!(fs.complete_lookup(pending, outcome).is_empty());
+            assert_eq!(recv.try_recv().unwrap().unwrap_err(), nix::errno::Errno::ENOENT as i32);
+            // Nothing is recorded, unlike an ignored path.
+            assert!(fs.resolution_db.is_empty() && fs.session_decisions.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_excluded_decisions_are_not_recorded() {
+        let record = tempfile::tempdir().unwrap();
+        let record_filepath = record.path().join("buildxyz.toml");
+        std::fs::write(&record_filepath, "[\"bin/yacc\"]\ndecision = \"ignore\"\n").unwrap();
+        let mut fs = BuildXYZ {
+            resolution_record_filepath: Some(record_filepath.clone()),
+            defer_record: true,
+            ..Default::default()
+        };
+        let data = ResolutionData {
+            requested_path: "bin/yacc".into(),
+            decision: Decision::Ignore,
+            requested_by: None,
+            priority: 0,
+            source: Some(record_filepath.to_string_lossy().to_string()),
+            provenance: Default::default(),
+            reconfirm: false,
+        };
+        insert_resolution(&mut fs.resolution_db, data, None);
+        let requester = ProcessInfo::from_pid(std::process::id());
+        for (id, requested_path) in [(1, "bin/lex"), (2, "bin/m4")] {
+            fs.record_resolution(Path::new(requested_path), Decision::Ignore, &requester, None);
+            fs.remember_decision(
+                id,
+                SessionDecision {
+                    requested_path: requested_path.into(),
+                    phase: None,
+                    store_path: None,
+                    subtree_pattern: None,
+                    enoent_key: None,
+                    description: "ignore".into(),
+                    requested_by: requester.clone(),
+                    candidates: Vec::new(),
+                },
+            );
+        }
+
+        fs.confirm_record(|items, existing| {
+            let paths: Vec<&Path> = items.iter().map(|item| item.requested_path.as_path()).collect();
+            assert_eq!(paths, vec![Path::new("bin/lex"), Path::new("bin/m4")]);
+            assert_eq!(existing, &[(record_filepath.to_string_lossy().to_string(), 1)]);
+            vec![2]
+        });
+        fs.write_deferred_record();
+
+        let recorded = crate::resolution::read_resolution_db(&std::fs::read_to_string(&record_filepath).unwrap())
+            .unwrap();
+        let mut paths: Vec<&String> = recorded.keys().collect();
+        paths.sort();
+        assert_eq!(paths, vec!["bin/lex", "bin/yacc"]);
+    }
 }
@@ -0,0 +1,92 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    /// Version suffixes in path components, e.g. `-3.0` in `gtk-3.0` or `2` in `python2`.
+    static ref VERSION: Regex = Regex::new(r"[-_.]?v?[0-9]+(\.[0-9]+)*").unwrap();
+}
+
+/// Any version, or none, wherever the requested path has one:
+/// `include/gtk-3.0/gtk/gtk.h` matches `/include/gtk-4.0/gtk/gtk.h` and `/include/gtk/gtk/gtk.h`.
+pub fn versionless_pattern(requested_path: &str) -> Option<String> {
+    if !VERSION.is_match(requested_path) {
+        return None;
+    }
+
+    let mut pattern = String::from("^/");
+    let mut last = 0;
+    for version in VERSION.find_iter(requested_path) {
+        pattern.push_str(&regex::escape(&requested_path[last..version.start()]));
+        pattern.push_str(r"([-_.]?v?[0-9]+(\.[0-9]+)*)?");
+        last = version.end();
+    }
+    pattern.push_str(&regex::escape(&requested_path[last..]));
+    pattern.push('$');
+
+    Some(pattern)
+}
+
+/// The same file name in any directory: `include/foo/bar.h` matches `/include/bar.h`.
+pub fn basename_pattern(requested_path: &str) -> Option<String> {
+    let (_, basename) = requested_path.rsplit_once('/')?;
+
+    Some(format!(r"^/(.*/)?{}$", regex::escape(basename)))
+}
+
+/// Score of a candidate `text` for a query typed to filter a prompt, e.g. `py311` for `python311.out`.
+/// `None` unless the characters of the query appear in order in `text`, regardless of case.
+/// Consecutive characters and characters starting a word score higher, gaps between them lower.
+pub fn filter_score(query: &str, text: &str) -> Option<i64> {
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    let mut score = 0;
+    let mut previous: Option<usize> = None;
+
+    for c in query.to_lowercase().chars().filter(|c| !c.is_whitespace()) {
+        let start = previous.map_or(0, |previous| previous + 1);
+        let index = start + text.get(start..)?.iter().position(|t| *t == c)?;
+        score += 1;
+        if previous.is_some() {
+            if index == start {
+                score += 5;
+            } else {
+                score -= (index - start) as i64;
+            }
+        }
+        if index == 0 || !text[index - 1].is_alphanumeric() {
+            score += 3;
+        }
+        previous = Some(index);
+    }
+
+    Some(score)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relaxed_patterns() {
+        let versionless = Regex::new(&versionless_pattern("include/gtk-3.0/gtk/gtk.h").unwrap()).unwrap();
+        assert!(versionless.is_match("/include/gtk-4.0/gtk/gtk.h"));
+        assert!(versionless.is_match("/include/gtk/gtk/gtk.h"));
+        assert!(!versionless.is_match("/include/gdk-3.0/gtk/gtk.h"));
+        assert_eq!(versionless_pattern("include/zlib.h"), None);
+
+        let basename = Regex::new(&basename_pattern("include/foo/bar.h").unwrap()).unwrap();
+        assert!(basename.is_match("/include/bar.h"));
+        assert!(!basename.is_match("/include/foobar.h"));
+        assert_eq!(basename_pattern("bar.h"), None);
+    }
+
+    #[test]
+    fn test_filter_scores_consecutive_matches_higher() {
+        assert_eq!(filter_score("py3", "ruby.out ruby-3.1.4"), None);
+        assert_eq!(filter_score("", "python311.out"), Some(0));
+
+        let python = filter_score("py311", "python311.out python3-3.11.4").unwrap();
+        let pypy = filter_score("py311", "pypy3.out pypy3-7.3.11").unwrap();
+        assert!(python > pypy);
+        assert!(filter_score("PY", "python3.out").is_some());
+    }
+}
@@ -0,0 +1,135 @@
+use fuser::FileType;
+use log::{debug, warn};
+use std::path::Path;
+use walkdir::WalkDir;
+
+use crate::cache::{PathOrigin, StorePath};
+use crate::nix::{real_path, ShellInput};
+use crate::resolution::{
+    Decision, Provenance, ProvideData, Resolution, ResolutionDB, ResolutionData,
+};
+
+/// Directories of a package whose entries are imported, and how deep.
+const IMPORTED_DIRECTORIES: [(&str, usize); 4] = [
+    ("bin", 1),
+    ("include", usize::MAX),
+    ("lib", 1),
+    ("lib/pkgconfig", 1),
+];
+
+/// Files and symlinks of `root` under the imported directories, relative to it.
+pub fn provided_entries(root: &Path) -> Vec<(String, FileType)> {
+    IMPORTED_DIRECTORIES
+        .iter()
+        .flat_map(|(directory, depth)| {
+            WalkDir::new(root.join(directory))
+                .min_depth(1)
+                .max_depth(*depth)
+                .sort_by_file_name()
+                .into_iter()
+                .filter_map(|entry| entry.ok())
+        })
+        .filter_map(|entry| {
+            let kind = if entry.file_type().is_symlink() {
+                FileType::Symlink
+            } else if entry.file_type().is_file() {
+                FileType::RegularFile
+            } else {
+                return None;
+            };
+            let relative = entry.path().strip_prefix(root).ok()?;
+
+            Some((relative.to_string_lossy().to_string(), kind))
+        })
+        .collect()
+}
+
+/// Provide the entries of the shell inputs, earlier inputs first.
+/// Paths which already have a resolution are left untouched. Returns the number of added resolutions.
+pub fn import_shell_inputs(
+    db: &mut ResolutionDB,
+    inputs: Vec<ShellInput>,
+    provenance: &Provenance,
+) -> usize {
+    let mut added = 0;
+
+    for input in inputs {
+        let origin = PathOrigin {
+            attr: input.attr,
+            output: input.output,
+            toplevel: true,
+            system: input.system,
+            index: None,
+        };
+        let store_path = match StorePath::parse(origin, &input.path) {
+            Some(store_path) => store_path,
+            None => {
+                warn!("{} is not a store path, skipping it", input.path);
+                continue;
+            }
+        };
+
+        for (requested_path, kind) in provided_entries(Path::new(&real_path(&input.path))) {
+            if db.contains_key(&requested_path) {
+                debug!("{} is already resolved, skipping it", requested_path);
+                continue;
+            }
+
+            let data = ResolutionData {
+                requested_path: requested_path.clone(),
+                decision: Decision::Provide(Box::new(ProvideData {
+                    kind,
+                    file_entry_name: format!("/{}", requested_path),
+                    store_path: store_path.clone(),
+                    link_mode: None,
+                    flake: None,
+                })),
+                requested_by: None,
+                priority: 0,
+                source: None,
+                provenance: provenance.clone(),
+                reconfirm: false,
+            };
+            db.insert(requested_path, Resolution::ConstantResolution(data));
+            added += 1;
+        }
+    }
+
+    added
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_provided_entries_are_limited_to_imported_directories() {
+        let root = tempfile::tempdir().unwrap();
+        for directory in ["bin", "include/gtk", "lib/pkgconfig", "lib/python3.11", "share"] {
+            fs::create_dir_all(root.path().join(directory)).unwrap();
+        }
+        for file in [
+            "bin/gtk-builder-tool",
+            "include/gtk/gtk.h",
+            "lib/libgtk-3.so.0",
+            "lib/pkgconfig/gtk+-3.0.pc",
+            "lib/python3.11/gi.py",
+            "share/gtk.svg",
+        ] {
+            fs::write(root.path().join(file), "").unwrap();
+        }
+        std::os::unix::fs::symlink("libgtk-3.so.0", root.path().join("lib/libgtk-3.so")).unwrap();
+
+        assert_eq!(
+            provided_entries(root.path()),
+            vec![
+                ("bin/gtk-builder-tool".to_string(), FileType::RegularFile),
+                ("include/gtk/gtk.h".to_string(), FileType::RegularFile),
+                ("lib/libgtk-3.so".to_string(), FileType::Symlink),
+                ("lib/libgtk-3.so.0".to_string(), FileType::RegularFile),
+                ("lib/pkgconfig/gtk+-3.0.pc".to_string(), FileType::RegularFile),
+            ]
+        );
+    }
+}
@@ -0,0 +1,399 @@
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::digest::sha256_hex;
+use crate::fetch;
+use crate::pin::PinCheck;
+use crate::resolution::{
+    deserialize_includes, deserialize_pin, deserialize_resolution_db, merge_resolution_db,
+    with_source, DbFormat, ResolutionDB,
+};
+use crate::signature::{signature_location, SignatureCheck};
+
+/// A resolution file layered under the one including it, e.g. organizational defaults.
+/// Paths are relative to the including file, remote files must be pinned by their SHA-256.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Include {
+    Location(String),
+    Pinned { url: String, sha256: String },
+}
+
+/// Where a resolution file is read from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Location {
+    File(PathBuf),
+    Url(String),
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(filepath) => write!(f, "{}", filepath.display()),
+            Self::Url(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+impl Location {
+    /// `location` as written in an include of this file.
+    fn resolve(&self, location: &str) -> Location {
+        if fetch::is_url(location) {
+            return Self::Url(location.to_string());
+        }
+
+        match self {
+            Self::File(filepath) => Self::File(
+                filepath
+                    .parent()
+                    .unwrap_or_else(|| Path::new("."))
+                    .join(location),
+            ),
+            Self::Url(url) => Self::Url(format!(
+                "{}/{}",
+                url.rsplit_once('/').map_or(url.as_str(), |(base, _)| base),
+                location
+            )),
+        }
+    }
+
+    /// Identity of the file, to detect cycles.
+    fn key(&self) -> String {
+        match self {
+            Self::File(filepath) => fs::canonicalize(filepath)
+                .unwrap_or_else(|_| filepath.clone())
+                .to_string_lossy()
+                .to_string(),
+            Self::Url(url) => url.clone(),
+        }
+    }
+
+    fn format(&self) -> DbFormat {
+        match self {
+            Self::File(filepath) => DbFormat::from_path(filepath),
+            Self::Url(url) => DbFormat::from_path(Path::new(url)),
+        }
+    }
+
+    fn read_signature(&self) -> io::Result<Vec<u8>> {
+        match self {
+            Self::File(filepath) => fs::read(signature_location(&filepath.to_string_lossy())),
+            Self::Url(url) => fetch::fetch_cached(&signature_location(url)),
+        }
+    }
+
+    fn read(&self, sha256: Option<&str>) -> io::Result<String> {
+        let url = match self {
+            Self::File(filepath) => return fs::read_to_string(filepath),
+            Self::Url(url) => url,
+        };
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+
+        let sha256 = match sha256 {
+            Some(sha256) => sha256,
+            None => {
+                return Err(invalid(format!(
+                "remote includes must be pinned, include it as {{ url = \"{}\", sha256 = \"{}\" }}",
+                url,
+                sha256_hex(&fetch::fetch(url)?)
+            )))
+            }
+        };
+        // Cached by hash, the pin is the cache key.
+        let cached = xdg::BaseDirectories::with_prefix("buildxyz")
+            .expect("Failed to find the XDG directories")
+            .place_cache_file(Path::new("includes").join(sha256))?;
+        let data = match fs::read(&cached) {
+            Ok(data) if sha256_hex(&data) == sha256 => {
+                debug!("{} is cached in {}", url, cached.display());
+                data
+            }
+            _ => {
+                let data = fetch::fetch(url)?;
+                let actual = sha256_hex(&data);
+                if actual != sha256 {
+                    return Err(invalid(format!(
+                        "its SHA-256 is {} but {} is pinned",
+                        actual, sha256
+                    )));
+                }
+                fs::write(&cached, &data)?;
+                data
+            }
+        };
+
+        String::from_utf8(data).map_err(|err| invalid(err.to_string()))
+    }
+}
+
+/// Read a resolution file with its includes layered underneath, recursively:
+/// later includes override earlier ones and the file overrides all of them, priorities aside.
+/// Pins of every file are checked, includes which cannot be read or form a cycle are skipped.
+/// `None` if the file itself does not parse, the reason is logged.
+pub fn read_resolution_file_with_includes(
+    filepath: &Path,
+    pins: &mut PinCheck,
+    signatures: &SignatureCheck,
+) -> io::Result<Option<ResolutionDB>> {
+    let data = fs::read_to_string(filepath)?;
+
+    Ok(load(
+        &Location::File(filepath.to_owned()),
+        &data,
+        pins,
+        signatures,
+        &mut Vec::new(),
+    ))
+}
+
+/// Read a remote resolution file like `read_resolution_file_with_includes`,
+/// it is cached and revalidated with its `ETag` rather than pinned.
+/// Over plain HTTP, anyone on the way could rewrite it: it is only read when signatures are required.
+pub fn read_remote_resolutions(
+    url: &str,
+    pins: &mut PinCheck,
+    signatures: &SignatureCheck,
+) -> io::Result<Option<ResolutionDB>> {
+    if url.starts_with("http://") && !signatures.required() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "plain HTTP is refused unless signatures are required, use https:// or --require-signed",
+        ));
+    }
+    let data = String::from_utf8(fetch::fetch_cached(url)?)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+    Ok(load(
+        &Location::Url(url.to_string()),
+        &data,
+        pins,
+        signatures,
+        &mut Vec::new(),
+    ))
+}
+
+/// `including` are the files including this one, outermost first.
+fn load(
+    location: &Location,
+    data: &str,
+    pins: &mut PinCheck,
+    signatures: &SignatureCheck,
+    including: &mut Vec<String>,
+) -> Option<ResolutionDB> {
+    if signatures.required() {
+        if let Err(err) = location
+            .read_signature()
+            .and_then(|signature| signatures.verify(data.as_bytes(), &signature))
+        {
+            error!("Refusing the resolutions of {}: {}", location, err);
+            return None;
+        }
+    }
+    let format = location.format();
+    let db = with_source(
+        deserialize_resolution_db(data, format, &location.to_string())?,
+        &location.to_string(),
+    );
+    pins.check(
+        Path::new(&location.to_string()),
+        deserialize_pin(data, format).as_ref(),
+    );
+
+    including.push(location.key());
+    let mut included: Option<ResolutionDB> = None;
+    for include in deserialize_includes(data, format) {
+        let (included_location, sha256) = match &include {
+            Include::Location(included_location) => (location.resolve(included_location), None),
+            Include::Pinned { url, sha256 } => (location.resolve(url), Some(sha256.as_str())),
+        };
+        if including.contains(&included_location.key()) {
+            warn!(
+                "{} includes {} which includes it back, skipping it",
+                location, included_location
+            );
+            continue;
+        }
+
+        debug!("{} includes {}", location, included_location);
+        let included_data = match included_location.read(sha256) {
+            Ok(included_data) => included_data,
+            Err(err) => {
+                warn!(
+                    "Failed to include {} in {}: {}",
+                    included_location, location, err
+                );
+                continue;
+            }
+        };
+        if let Some(included_db) = load(
+            &included_location,
+            &included_data,
+            pins,
+            signatures,
+            including,
+        ) {
+            included = Some(match included {
+                Some(included) => merge_resolution_db(included, included_db),
+                None => included_db,
+            });
+        }
+    }
+    including.pop();
+
+    Some(match included {
+        Some(included) => merge_resolution_db(included, db),
+        None => db,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_includes_are_layered_underneath_and_cycles_skipped() {
+        let directory = tempfile::tempdir().unwrap();
+        let org = directory.path().join("org-defaults.toml");
+        let project = directory.path().join("project").join("buildxyz.toml");
+        fs::create_dir(project.parent().unwrap()).unwrap();
+        fs::write(
+            &org,
+            r#"
+include = ["project/buildxyz.toml"]
+
+["bin/yacc"]
+decision = "ignore"
+
+["bin/lex"]
+decision = "ignore"
+"#,
+        )
+        .unwrap();
+        fs::write(
+            &project,
+            r#"
+include = ["../org-defaults.toml"]
+
+["bin/lex"]
+decision = "defer"
+"#,
+        )
+        .unwrap();
+
+        let db = read_resolution_file_with_includes(
+            &project,
+            &mut PinCheck::default(),
+            &SignatureCheck::default(),
+        )
+            .unwrap()
+            .unwrap();
+        assert_eq!(db.len(), 2);
+        assert_eq!(db["bin/yacc"].describe(), "ignore");
+        assert_eq!(db["bin/lex"].describe(), "always ask");
+    }
+
+    #[test]
+    fn test_unsigned_resolutions_are_refused_when_signatures_are_required() {
+        let directory = tempfile::tempdir().unwrap();
+        let filepath = directory.path().join("buildxyz.toml");
+        fs::write(
+            &filepath,
+            r#"
+["bin/cc"]
+decision = "ignore"
+"#,
+        )
+        .unwrap();
+        let signatures = SignatureCheck::new(vec![
+            "RWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3".into(),
+        ]);
+
+        let db = read_resolution_file_with_includes(&filepath, &mut PinCheck::default(), &signatures);
+        assert!(db.unwrap().is_none());
+        // Nor is a signature which does not match.
+        fs::write(
+            signature_location(&filepath.to_string_lossy()),
+            "untrusted comment: tampered\n",
+        )
+        .unwrap();
+        let db = read_resolution_file_with_includes(&filepath, &mut PinCheck::default(), &signatures);
+        assert!(db.unwrap().is_none());
+        // Signatures are only checked when required.
+        let db = read_resolution_file_with_includes(
+            &filepath,
+            &mut PinCheck::default(),
+            &SignatureCheck::default(),
+        );
+        assert_eq!(db.unwrap().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_plain_http_resolutions_are_refused_unless_signed() {
+        let err = read_remote_resolutions(
+            "http://example.org/buildxyz.toml",
+            &mut PinCheck::default(),
+            &SignatureCheck::default(),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_signed_resolutions_are_loaded_when_signatures_are_required() {
+        let directory = tempfile::tempdir().unwrap();
+        let filepath = directory.path().join("buildxyz.toml");
+        fs::write(
+            &filepath,
+            r#"
+["bin/cc"]
+decision = "ignore"
+"#,
+        )
+        .unwrap();
+        // Signed by minisign with a throwaway key.
+        fs::write(
+            signature_location(&filepath.to_string_lossy()),
+            "untrusted comment: signature from minisign secret key
+RUSKgCBwYR823UGePB+93siQR82Th4D5BIFX6nrkCpTMcjiAHFtYc34+Isb20Awwo/Q3DHN/mybVvs/mpijSvmGm5TQSjqThuQs=
+trusted comment: timestamp:1688169600\tfile:buildxyz.toml\thashed
+zonOT7whlRrpiSO8YEFq+TIg9ysMzaAyY9rfEPPcVKyehr+2WeZcWn4bVfbB6iVJwPSQ02F1QRGPg30fwuXwDA==
+",
+        )
+        .unwrap();
+        let public_key = directory.path().join("minisign.pub");
+        fs::write(
+            &public_key,
+            "untrusted comment: minisign public key 8A80207061...
+RWSKgCBwYR823b1cE82+Wqlg1M6VHLarFS7Z54p3nvItsvv+fowJl4rq
+",
+        )
+        .unwrap();
+
+        for key in [
+            public_key.to_string_lossy().to_string(),
+            "RWSKgCBwYR823b1cE82+Wqlg1M6VHLarFS7Z54p3nvItsvv+fowJl4rq".to_string(),
+        ] {
+            let signatures = SignatureCheck::new(vec![
+                "RWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3".into(),
+                key,
+            ]);
+            let db = read_resolution_file_with_includes(&filepath, &mut PinCheck::default(), &signatures);
+            assert_eq!(db.unwrap().unwrap().len(), 1);
+        }
+        // Other keys did not sign it.
+        let signatures = SignatureCheck::new(vec![
+            "RWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3".into(),
+        ]);
+        let db = read_resolution_file_with_includes(&filepath, &mut PinCheck::default(), &signatures);
+        assert!(db.unwrap().is_none());
+        // Editing the file invalidates the signature.
+        let signatures = SignatureCheck::new(vec![public_key.to_string_lossy().to_string()]);
+        fs::write(&filepath, "[\"bin/cc\"]\ndecision = \"ignore\"\n").unwrap();
+        let db = read_resolution_file_with_includes(&filepath, &mut PinCheck::default(), &signatures);
+        assert!(db.unwrap().is_none());
+    }
+}
@@ -0,0 +1,850 @@
+use log::{debug, info, warn};
+use nix::sys::stat::{utimensat, UtimensatFlags};
+use nix::sys::time::TimeSpec;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::attrpath::listed_out_paths;
+use crate::cache::database::{decompress_into, read_raw_buffer, SharedBuffer, Writer};
+use crate::cache::{FileTree, PathOrigin, StorePath};
+use crate::fetch::fetch_file;
+use crate::filesource::{fetch_file_tree, NarInfo};
+use crate::flake::{origin_of, FlakeOutputs};
+use crate::nix::{
+    flake_metadata, flake_package_outputs, is_valid_path, list_out_paths_of, real_path,
+    substituters,
+};
+use crate::pin::{fingerprint, flakeref_of};
+
+/// Where `buildxyz index fetch` downloads the index from by default, `{system}` is replaced by the system.
+pub const DEFAULT_INDEX_URL: &str =
+    "https://github.com/nix-community/nix-index-database/releases/latest/download/index-{system}";
+/// The only channel indexed at `DEFAULT_INDEX_URL`.
+pub const DEFAULT_CHANNEL: &str = "nixpkgs-unstable";
+/// Days after which the index searched is reported as stale, see `--index-max-age`.
+pub const DEFAULT_MAX_AGE_DAYS: u64 = 30;
+
+/// Binary cache `buildxyz index build` fetches the file listings from by default.
+pub const DEFAULT_BINARY_CACHE: &str = "https://cache.nixos.org";
+/// Listings `buildxyz index build` fetches at once by default.
+pub const DEFAULT_BUILD_JOBS: usize = 16;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+/// Store paths whose listings are held in memory before being written to the index.
+const BUILD_BATCH: usize = 1024;
+/// zstd level of the built indexes, the one of nix-index.
+const COMPRESSION_LEVEL: i32 = 22;
+/// Days after which a decompressed index no session mapped is deleted.
+const MAPPED_MAX_AGE_DAYS: u64 = 7;
+
+/// What is known about a downloaded or built index, next to it in `manifest.json`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct IndexMetadata {
+    /// Channel, or the `--nixpkgs` it was built from
+    pub channel: String,
+    pub system: String,
+    /// Where it was downloaded from, or the binary cache listing the files of the packages
+    pub url: String,
+    /// Seconds since the UNIX epoch, when the server published it, or else when it was downloaded or built
+    pub built_at: u64,
+    /// nixpkgs revision, when it was built here
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nixpkgs_rev: Option<String>,
+}
+
+fn seconds_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Nix system of this machine, e.g. `x86_64-linux` or `aarch64-darwin`.
+pub fn current_system() -> String {
+    let os = match std::env::consts::OS {
+        "macos" => "darwin",
+        os => os,
+    };
+
+    format!("{}-{}", std::env::consts::ARCH, os)
+}
+
+/// URL of the index of a channel for a system, from a template with `{channel}` and `{system}`.
+fn index_url(template: &str, channel: &str, system: &str) -> io::Result<String> {
+    if template == DEFAULT_INDEX_URL && channel != DEFAULT_CHANNEL {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "nix-index-database only indexes {}, pass `--url` to fetch an index of {}",
+                DEFAULT_CHANNEL, channel
+            ),
+        ));
+    }
+
+    Ok(template
+        .replace("{channel}", channel)
+        .replace("{system}", system))
+}
+
+/// Directory of the downloaded indexes, `$XDG_CACHE_HOME/buildxyz/indexes/fetched`.
+fn fetched_dir() -> io::Result<PathBuf> {
+    let dirs = xdg::BaseDirectories::with_prefix("buildxyz")?;
+    Ok(dirs.get_cache_home().join("indexes").join("fetched"))
+}
+
+/// Download the prebuilt index of a channel for a system, replacing the previous download once it is read.
+pub fn fetch_index(channel: &str, system: &str, url_template: &str) -> io::Result<IndexMetadata> {
+    let url = index_url(url_template, channel, system)?;
+    let directory = fetched_dir()?.join(channel).join(system);
+    std::fs::create_dir_all(&directory)?;
+
+    info!(
+        "Fetching the index of {} for {} from {}",
+        channel, system, url
+    );
+    let download = tempfile::NamedTempFile::new_in(&directory)?;
+    fetch_file(&url, download.path())?;
+    let data = std::fs::read(download.path())?;
+    // Decompressed for the sessions to map it right away.
+    load_index(&data).map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not an index this build can read: {}", url, err),
+        )
+    })?;
+
+    // A server telling no date leaves the modification time of the download to now.
+    let built_at = download.as_file().metadata()?.modified()?;
+    let metadata = IndexMetadata {
+        channel: channel.to_string(),
+        system: system.to_string(),
+        url,
+        built_at: seconds_since_epoch(built_at),
+        nixpkgs_rev: None,
+    };
+    download
+        .persist(directory.join("files"))
+        .map_err(|err| err.error)?;
+    write_manifest(&directory, &metadata)?;
+    info!(
+        "Fetched {} bytes, built {} days ago",
+        data.len(),
+        age_in_days(&metadata, SystemTime::now())
+    );
+
+    Ok(metadata)
+}
+
+fn write_manifest(directory: &Path, metadata: &IndexMetadata) -> io::Result<()> {
+    std::fs::write(
+        directory.join("manifest.json"),
+        serde_json::to_vec_pretty(metadata)?,
+    )
+}
+
+fn read_manifest(directory: &Path) -> Option<IndexMetadata> {
+    let manifest = std::fs::read(directory.join("manifest.json")).ok()?;
+    serde_json::from_slice(&manifest)
+        .map_err(|err| {
+            warn!(
+                "Invalid {}: {}",
+                directory.join("manifest.json").display(),
+                err
+            )
+        })
+        .ok()
+}
+
+/// Files of the store paths of a batch on the binary cache, fetched by `jobs` threads,
+/// `None` for those it does not have.
+fn fetch_file_trees(
+    binary_cache: &str,
+    store_paths: &[StorePath],
+    jobs: usize,
+) -> Vec<Option<(NarInfo, FileTree)>> {
+    let jobs = jobs.max(1);
+    let per_job = ((store_paths.len() + jobs - 1) / jobs).max(1);
+    thread::scope(|scope| {
+        let fetching: Vec<_> = store_paths
+            .chunks(per_job)
+            .map(|store_paths| {
+                scope.spawn(move || {
+                    store_paths
+                        .iter()
+                        .map(|store_path| fetch_file_tree(binary_cache, &store_path.hash()))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        fetching
+            .into_iter()
+            .flat_map(|fetching| fetching.join().expect("Failed to fetch listings"))
+            .collect()
+    })
+}
+
+/// Build the index of a nixpkgs revision or flake reference for a system as `files` in `directory`,
+/// like nix-index does: every package of nixpkgs is listed, then the files of the packages
+/// and of their references are fetched from the listings served by the binary cache.
+/// Packages the binary cache does not have, e.g. unfree ones, are left out.
+pub fn build_index(
+    nixpkgs: &str,
+    system: &str,
+    binary_cache: &str,
+    jobs: usize,
+    directory: &Path,
+) -> io::Result<IndexMetadata> {
+    let flakeref = flakeref_of(nixpkgs);
+    let flake = flake_metadata(&flakeref)
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+    info!("Listing the packages of {} for {}", flakeref, system);
+    let listing = list_out_paths_of(&flake.path, Some(system))
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+
+    let mut seen = HashSet::new();
+    let mut frontier: Vec<StorePath> = listed_out_paths(&listing)
+        .into_iter()
+        .filter_map(|(attr_path, out_path)| {
+            let origin = PathOrigin {
+                attr: attr_path.attr,
+                output: attr_path.output,
+                toplevel: true,
+                system: Some(system.to_string()),
+                index: None,
+            };
+            StorePath::parse(origin, &out_path)
+        })
+        .filter(|store_path| seen.insert(store_path.hash().into_owned()))
+        .collect();
+
+    std::fs::create_dir_all(directory)?;
+    let partial = directory.join("files.partial");
+    let mut writer = Writer::create(&partial, COMPRESSION_LEVEL)?;
+    let (mut indexed, mut missing) = (0, 0);
+    while !frontier.is_empty() {
+        info!(
+            "Fetching the listings of {} store paths from {}",
+            frontier.len(),
+            binary_cache
+        );
+        let mut references = Vec::new();
+        for batch in frontier.chunks(BUILD_BATCH) {
+            let fetched = fetch_file_trees(binary_cache, batch, jobs);
+            for (store_path, fetched) in batch.iter().zip(fetched) {
+                let Some((narinfo, tree)) = fetched else {
+                    debug!("{} is not on {}", store_path.as_str(), binary_cache);
+                    missing += 1;
+                    continue;
+                };
+                // References are reached through the package, under its attribute.
+                let origin = PathOrigin {
+                    toplevel: false,
+                    ..store_path.origin().into_owned()
+                };
+                for reference in &narinfo.references {
+                    let reference = format!("{}/{}", store_path.store_dir(), reference);
+                    if let Some(reference) = StorePath::parse(origin.clone(), &reference) {
+                        if seen.insert(reference.hash().into_owned()) {
+                            references.push(reference);
+                        }
+                    }
+                }
+                writer.add(store_path.clone(), tree, b"")?;
+                indexed += 1;
+            }
+            info!(
+                "Indexed {} store paths, {} are not on the binary cache",
+                indexed, missing
+            );
+        }
+        frontier = references;
+    }
+    let size = writer.finish()?;
+    std::fs::rename(&partial, directory.join("files"))?;
+
+    let metadata = IndexMetadata {
+        channel: nixpkgs.to_string(),
+        system: system.to_string(),
+        url: binary_cache.to_string(),
+        built_at: seconds_since_epoch(SystemTime::now()),
+        nixpkgs_rev: flake.locked.rev,
+    };
+    write_manifest(directory, &metadata)?;
+    info!(
+        "Wrote the index of {} store paths to {}, {} bytes",
+        indexed,
+        directory.join("files").display(),
+        size
+    );
+
+    Ok(metadata)
+}
+
+/// The most recent index downloaded by `buildxyz index fetch` or built by `buildxyz index build` in `database`
+/// for this system, and its compressed data.
+pub fn latest_index(system: &str, database: &Path) -> Option<(IndexMetadata, Vec<u8>)> {
+    let fetched = fetched_dir()
+        .ok()
+        .and_then(|fetched_dir| std::fs::read_dir(fetched_dir).ok())
+        .into_iter()
+        .flatten()
+        .filter_map(|channel| channel.ok())
+        .map(|channel| channel.path().join(system));
+
+    most_recent_index(fetched.chain([database.to_owned()]), system)
+}
+
+/// The most recent index of these directories for this system, by their manifests, and its compressed data.
+fn most_recent_index(
+    directories: impl Iterator<Item = PathBuf>,
+    system: &str,
+) -> Option<(IndexMetadata, Vec<u8>)> {
+    let (directory, metadata) = directories
+        .filter_map(|directory| Some((directory.clone(), read_manifest(&directory)?)))
+        .filter(|(_, metadata)| metadata.system == system)
+        .max_by_key(|(_, metadata)| metadata.built_at)?;
+    debug!("{} has the most recent index", directory.display());
+
+    let data = std::fs::read(directory.join("files"))
+        .map_err(|err| {
+            warn!(
+                "Failed to read the index in {}: {}",
+                directory.display(),
+                err
+            )
+        })
+        .ok()?;
+    Some((metadata, data))
+}
+
+/// Directory of the decompressed indexes sessions map, `$XDG_CACHE_HOME/buildxyz/indexes/mapped`.
+fn mapped_dir() -> io::Result<PathBuf> {
+    let dirs = xdg::BaseDirectories::with_prefix("buildxyz")?;
+    Ok(dirs.get_cache_home().join("indexes").join("mapped"))
+}
+
+/// Map the decompressed index in `directory`, named after its fingerprint,
+/// decompressing it there first if no session did yet.
+fn map_index(directory: &Path, fingerprint: &str, data: &[u8]) -> io::Result<SharedBuffer> {
+    let path = directory.join(fingerprint.replace(':', "-"));
+    if !path.exists() {
+        std::fs::create_dir_all(directory)?;
+        let mut decompressed = tempfile::NamedTempFile::new_in(directory)?;
+        decompress_into(io::Cursor::new(data), &mut decompressed)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+        decompressed.persist(&path).map_err(|err| err.error)?;
+    } else if let Err(err) = set_modified(&path, SystemTime::now()) {
+        debug!("Failed to mark {} as used: {}", path.display(), err);
+    }
+
+    let buffer = SharedBuffer::map(&path)?;
+    evict_mapped_indexes(
+        directory,
+        Duration::from_secs(MAPPED_MAX_AGE_DAYS * SECONDS_PER_DAY),
+    );
+    Ok(buffer)
+}
+
+/// Sets both the access and modification times of `path`.
+fn set_modified(path: &Path, modified: SystemTime) -> io::Result<()> {
+    let time = TimeSpec::from(
+        modified
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default(),
+    );
+    utimensat(None, path, &time, &time, UtimensatFlags::FollowSymlink)?;
+
+    Ok(())
+}
+
+/// Delete the decompressed indexes in `directory` no session mapped for `max_age`,
+/// with their lookups by file name, e.g. older releases of a fetched index.
+/// Sessions still mapping one keep reading it until they unmap it.
+fn evict_mapped_indexes(directory: &Path, max_age: Duration) {
+    let entries = match std::fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(err) => {
+            debug!("Failed to list {}: {}", directory.display(), err);
+            return;
+        }
+    };
+    let now = SystemTime::now();
+    let is_stale = |path: &Path| {
+        std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .map_or(false, |modified| {
+                now.duration_since(modified)
+                    .map_or(false, |age| age > max_age)
+            })
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        // A lookup by file name goes with its index, which may still be used.
+        let index = match path.to_str().and_then(|path| path.strip_suffix(".basenames")) {
+            Some(index) if Path::new(index).exists() => PathBuf::from(index),
+            _ => path.clone(),
+        };
+        if !is_stale(&index) {
+            continue;
+        }
+        debug!("Deleting the unused index {}", path.display());
+        if let Err(err) = std::fs::remove_file(&path) {
+            warn!("Failed to delete the unused index {}: {}", path.display(), err);
+        }
+    }
+}
+
+/// Decompressed index from its compressed `data`, e.g. the embedded one.
+/// It is decompressed once into `$XDG_CACHE_HOME/buildxyz/indexes/mapped` and mapped from there,
+/// so that it stays in the page cache and concurrent sessions share its memory.
+/// It is decompressed in memory when it cannot be mapped, e.g. with a read-only cache.
+pub fn load_index(data: &[u8]) -> io::Result<SharedBuffer> {
+    let fingerprint = fingerprint(data);
+    match mapped_dir().and_then(|directory| map_index(&directory, &fingerprint, data)) {
+        Ok(buffer) => Ok(buffer),
+        Err(err) if err.kind() == io::ErrorKind::InvalidData => Err(err),
+        Err(err) => {
+            warn!(
+                "Failed to map the index {}, decompressing it in memory: {}",
+                fingerprint, err
+            );
+            read_raw_buffer(io::Cursor::new(data))
+                .map(SharedBuffer::from)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))
+        }
+    }
+}
+
+/// Name and decompressed data of an index given to `--index` as `NAME=PATH`,
+/// PATH being a nix-index database or a directory with one as `files`, e.g. `--db`.
+pub fn read_named_index(spec: &str) -> io::Result<(String, SharedBuffer)> {
+    let (name, path) = spec.split_once('=').ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("expected NAME=PATH, got {}", spec),
+        )
+    })?;
+    let path = Path::new(path);
+    let path = if path.is_dir() {
+        path.join("files")
+    } else {
+        path.to_owned()
+    };
+    let data = std::fs::read(&path)
+        .and_then(|data| load_index(&data))
+        .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))?;
+
+    Ok((name.to_string(), data))
+}
+
+/// Directory of the indexes of flakes, `$XDG_CACHE_HOME/buildxyz/indexes/flakes`.
+fn flakes_dir() -> io::Result<PathBuf> {
+    let dirs = xdg::BaseDirectories::with_prefix("buildxyz")?;
+    Ok(dirs.get_cache_home().join("indexes").join("flakes"))
+}
+
+/// Directory name of the index of a flake reference, e.g. `github_acme_tools` for `github:acme/tools`.
+fn flake_dir_name(flakeref: &str) -> String {
+    flakeref
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Installable of an output of a flake package, the default output is built by the package itself.
+fn flake_installable(flakeref: &str, package: &str, output: &str) -> String {
+    if output == "out" {
+        format!("{}#{}", flakeref, package)
+    } else {
+        format!("{}#{}.{}", flakeref, package, output)
+    }
+}
+
+/// Files under a path of the store, as the index stores them.
+fn tree_of_path(path: &Path) -> io::Result<FileTree> {
+    let metadata = path.symlink_metadata()?;
+    if metadata.file_type().is_symlink() {
+        let target = std::fs::read_link(path)?.into_os_string().into_vec();
+        Ok(FileTree::symlink(ByteBuf::from(target)))
+    } else if metadata.is_dir() {
+        let entries = std::fs::read_dir(path)?
+            .map(|entry| {
+                let entry = entry?;
+                let name = ByteBuf::from(entry.file_name().into_vec());
+                Ok((name, tree_of_path(&entry.path())?))
+            })
+            .collect::<io::Result<HashMap<_, _>>>()?;
+        Ok(FileTree::directory(entries))
+    } else {
+        Ok(FileTree::regular(
+            metadata.len(),
+            metadata.permissions().mode() & 0o111 != 0,
+        ))
+    }
+}
+
+/// Files of an output of a flake: those in the store if it is there, or else as listed by a binary cache,
+/// or else once built if `build` is set. `None` if none of them has it.
+fn files_of_flake_output(
+    installable: &str,
+    store_path: &StorePath,
+    binary_caches: &[String],
+    build: bool,
+) -> Option<FileTree> {
+    let out_path = store_path.as_str();
+    if !is_valid_path(&out_path) {
+        let listed = binary_caches
+            .iter()
+            .find_map(|cache| fetch_file_tree(cache, &store_path.hash()));
+        if let Some((_, tree)) = listed {
+            return Some(tree);
+        }
+        if !build {
+            return None;
+        }
+        info!("Building {}", installable);
+        if let Err(err) = FlakeOutputs::shared().out_paths(installable) {
+            warn!("Failed to build {}: {}", installable, err);
+            return None;
+        }
+    }
+
+    tree_of_path(Path::new(&real_path(&out_path)))
+        .map_err(|err| warn!("Failed to list the files of {}: {}", out_path, err))
+        .ok()
+}
+
+/// Index the outputs of the packages of a flake for a system, e.g. internal tools nixpkgs does not have,
+/// into `$XDG_CACHE_HOME/buildxyz/indexes/flakes/`. Later sessions search it after the other indexes,
+/// and its candidates are flake installables, built again when they are not in the store.
+/// Adding the same flake again indexes its current packages.
+pub fn add_flake(flakeref: &str, system: &str, build: bool) -> io::Result<IndexMetadata> {
+    let flake = flake_metadata(flakeref)
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+    info!("Evaluating the packages of {} for {}", flakeref, system);
+    let packages = flake_package_outputs(&flake.url, system)
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+    let binary_caches: Vec<String> = substituters()
+        .into_iter()
+        .filter(|substituter| {
+            substituter.starts_with("https://") || substituter.starts_with("http://")
+        })
+        .collect();
+
+    let directory = flakes_dir()?.join(flake_dir_name(flakeref));
+    std::fs::create_dir_all(&directory)?;
+    let partial = directory.join("files.partial");
+    let mut writer = Writer::create(&partial, COMPRESSION_LEVEL)?;
+    let (mut indexed, mut missing) = (0, 0);
+    for (package, outputs) in &packages {
+        for (output, out_path) in outputs {
+            let installable = flake_installable(flakeref, package, output);
+            let Some(store_path) = StorePath::parse(origin_of(&installable), out_path) else {
+                continue;
+            };
+            match files_of_flake_output(&installable, &store_path, &binary_caches, build) {
+                Some(tree) => {
+                    writer.add(store_path, tree, b"")?;
+                    indexed += 1;
+                }
+                None => {
+                    warn!(
+                        "Left {} out, it is neither in the store nor on a binary cache",
+                        installable
+                    );
+                    missing += 1;
+                }
+            }
+        }
+    }
+    writer.finish()?;
+    std::fs::rename(&partial, directory.join("files"))?;
+
+    let metadata = IndexMetadata {
+        channel: flakeref.to_string(),
+        system: system.to_string(),
+        url: flake.url,
+        built_at: seconds_since_epoch(SystemTime::now()),
+        nixpkgs_rev: None,
+    };
+    write_manifest(&directory, &metadata)?;
+    info!(
+        "Indexed {} outputs of {}, {} left out, in {}",
+        indexed,
+        flakeref,
+        missing,
+        directory.display()
+    );
+
+    Ok(metadata)
+}
+
+/// Indexes of the flakes added with `buildxyz index add-flake` for this system, decompressed, by flake reference.
+pub fn flake_indexes(system: &str) -> Vec<(IndexMetadata, SharedBuffer)> {
+    let Ok(flakes) = flakes_dir().and_then(std::fs::read_dir) else {
+        return Vec::new();
+    };
+    let mut indexes: Vec<(IndexMetadata, SharedBuffer)> = flakes
+        .filter_map(|flake| flake.ok())
+        .filter_map(|flake| {
+            let directory = flake.path();
+            let metadata =
+                read_manifest(&directory).filter(|metadata| metadata.system == system)?;
+            let data = std::fs::read(directory.join("files"))
+                .and_then(|data| load_index(&data))
+                .map_err(|err| {
+                    warn!(
+                        "Failed to read the index in {}: {}",
+                        directory.display(),
+                        err
+                    )
+                })
+                .ok()?;
+            Some((metadata, data))
+        })
+        .collect();
+    indexes.sort_by(|(a, _), (b, _)| a.channel.cmp(&b.channel));
+
+    indexes
+}
+
+/// Whole days since the index was built.
+pub fn age_in_days(metadata: &IndexMetadata, now: SystemTime) -> u64 {
+    seconds_since_epoch(now).saturating_sub(metadata.built_at) / SECONDS_PER_DAY
+}
+
+/// Warn when the index searched is older than `max_age_days`, it misses the recent packages. Zero never warns.
+pub fn check_freshness(metadata: &IndexMetadata, max_age_days: u64, now: SystemTime) -> bool {
+    let age = age_in_days(metadata, now);
+    if max_age_days == 0 || age <= max_age_days {
+        return true;
+    }
+
+    warn!(
+        "The index of {} is {} days old, `buildxyz index fetch` downloads a recent one",
+        metadata.channel, age
+    );
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::database::write_index;
+
+    #[test]
+    fn test_fetched_indexes_are_checked_for_freshness() {
+        assert_eq!(
+            index_url(DEFAULT_INDEX_URL, DEFAULT_CHANNEL, "x86_64-linux").unwrap(),
+            "https://github.com/nix-community/nix-index-database/releases/latest/download/index-x86_64-linux"
+        );
+        assert!(index_url(DEFAULT_INDEX_URL, "nixos-24.05", "x86_64-linux").is_err());
+        assert_eq!(
+            index_url(
+                "https://indexes.example.org/{channel}/{system}/files",
+                "nixos-24.05",
+                "aarch64-darwin"
+            )
+            .unwrap(),
+            "https://indexes.example.org/nixos-24.05/aarch64-darwin/files"
+        );
+        assert!(current_system().contains('-'));
+
+        let metadata = IndexMetadata {
+            channel: DEFAULT_CHANNEL.into(),
+            system: "x86_64-linux".into(),
+            url: "https://indexes.example.org/index-x86_64-linux".into(),
+            built_at: 1_700_000_000,
+            nixpkgs_rev: None,
+        };
+        let now =
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000 + 45 * SECONDS_PER_DAY + 60);
+        assert_eq!(age_in_days(&metadata, now), 45);
+        assert!(!check_freshness(&metadata, 30, now));
+        assert!(check_freshness(&metadata, 60, now));
+        assert!(check_freshness(&metadata, 0, now));
+    }
+
+    #[test]
+    fn test_flake_outputs_are_indexed() {
+        assert_eq!(flake_dir_name("github:acme/tools"), "github_acme_tools");
+        assert_eq!(
+            flake_installable("github:acme/tools", "mytool", "dev"),
+            "github:acme/tools#mytool.dev"
+        );
+        assert_eq!(
+            flake_installable("github:acme/tools", "mytool", "out"),
+            "github:acme/tools#mytool"
+        );
+
+        let output = tempfile::tempdir().unwrap();
+        std::fs::create_dir(output.path().join("bin")).unwrap();
+        std::fs::write(output.path().join("bin/mytool"), "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(
+            output.path().join("bin/mytool"),
+            std::fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+        std::os::unix::fs::symlink("bin", output.path().join("sbin")).unwrap();
+        let mut files: Vec<(String, bool)> = tree_of_path(output.path())
+            .unwrap()
+            .to_list(b"")
+            .into_iter()
+            .map(|entry| {
+                let executable = matches!(
+                    entry.node,
+                    crate::cache::FileNode::Regular {
+                        executable: true,
+                        ..
+                    }
+                );
+                (String::from_utf8(entry.path).unwrap(), executable)
+            })
+            .collect();
+        files.sort();
+        assert_eq!(
+            files,
+            [
+                ("".to_string(), false),
+                ("/bin".to_string(), false),
+                ("/bin/mytool".to_string(), true),
+                ("/sbin".to_string(), false)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_indexes_are_decompressed_once_and_mapped() {
+        let directory = tempfile::tempdir().unwrap();
+        let database = directory.path().join("files");
+        let origin = PathOrigin {
+            attr: "zlib".into(),
+            output: "out".into(),
+            toplevel: true,
+            system: None,
+            index: None,
+        };
+        let store_path = "/nix/store/zy4xhzc6x6ldrfzqzfa4x3cw8ds1mgcn-zlib-1.3";
+        write_index(&database, &[(origin, store_path, vec!["zlib.h"])]);
+        let data = std::fs::read(&database).unwrap();
+
+        let mapped = directory.path().join("mapped");
+        let fingerprint = fingerprint(&data);
+        let map = map_index(&mapped, &fingerprint, &data).unwrap();
+        assert_eq!(map[..], read_raw_buffer(io::Cursor::new(&data)).unwrap());
+        let again = map_index(&mapped, &fingerprint, &data).unwrap();
+        assert_eq!(again[..], map[..]);
+        assert_eq!(std::fs::read_dir(&mapped).unwrap().count(), 1);
+
+        let error = map_index(&mapped, "fnv1a64:invalid", b"not an index").err();
+        assert_eq!(
+            error.map(|err| err.kind()),
+            Some(io::ErrorKind::InvalidData)
+        );
+        assert_eq!(std::fs::read_dir(&mapped).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_unused_mapped_indexes_are_evicted() {
+        let directory = tempfile::tempdir().unwrap();
+        let database = directory.path().join("files");
+        let origin = PathOrigin {
+            attr: "zlib".into(),
+            output: "out".into(),
+            toplevel: true,
+            system: None,
+            index: None,
+        };
+        let store_path = "/nix/store/zy4xhzc6x6ldrfzqzfa4x3cw8ds1mgcn-zlib-1.3";
+        write_index(&database, &[(origin, store_path, vec!["zlib.h"])]);
+        let data = std::fs::read(&database).unwrap();
+
+        let mapped = directory.path().join("mapped");
+        let current = fingerprint(&data).replace(':', "-");
+        let last_week = SystemTime::now() - Duration::from_secs(8 * SECONDS_PER_DAY);
+        let touch = |name: &str, modified: SystemTime| {
+            std::fs::File::options()
+                .create(true)
+                .append(true)
+                .open(mapped.join(name))
+                .unwrap();
+            set_modified(&mapped.join(name), modified).unwrap();
+        };
+        // Another session mapped the current index last week, and an older release.
+        map_index(&mapped, &fingerprint(&data), &data).unwrap();
+        touch(&current, last_week);
+        touch(&format!("{}.basenames", current), last_week);
+        touch("fnv1a64-old", last_week);
+        touch("fnv1a64-old.basenames", SystemTime::now());
+        // Still used by another session.
+        touch("fnv1a64-recent", SystemTime::now());
+
+        map_index(&mapped, &fingerprint(&data), &data).unwrap();
+        let mut names: Vec<String> = std::fs::read_dir(&mapped)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        let mut expected = vec![
+            current.clone(),
+            format!("{}.basenames", current),
+            "fnv1a64-recent".to_string(),
+        ];
+        expected.sort();
+        assert_eq!(names, expected);
+    }
+
+    #[test]
+    fn test_the_most_recent_index_is_searched() {
+        let directory = tempfile::tempdir().unwrap();
+        let origin = PathOrigin {
+            attr: "zlib".into(),
+            output: "out".into(),
+            toplevel: true,
+            system: None,
+            index: None,
+        };
+        let store_path = "/nix/store/zy4xhzc6x6ldrfzqzfa4x3cw8ds1mgcn-zlib-1.3";
+        // Fetched, built here, and for another system.
+        let indexes = [
+            ("fetched", "x86_64-linux", 1_700_000_000),
+            ("built", "x86_64-linux", 1_700_000_100),
+            ("other", "aarch64-linux", 1_700_000_200),
+        ];
+        for (name, system, built_at) in indexes {
+            let index_dir = directory.path().join(name);
+            std::fs::create_dir(&index_dir).unwrap();
+            write_index(&index_dir.join("files"), &[(origin.clone(), store_path, vec![name])]);
+            let metadata = IndexMetadata {
+                channel: DEFAULT_CHANNEL.into(),
+                system: system.into(),
+                url: DEFAULT_BINARY_CACHE.into(),
+                built_at,
+                nixpkgs_rev: None,
+            };
+            write_manifest(&index_dir, &metadata).unwrap();
+        }
+        let directories = || indexes.iter().map(|(name, ..)| directory.path().join(name));
+
+        let (metadata, data) = most_recent_index(directories(), "x86_64-linux").unwrap();
+        assert_eq!(metadata.built_at, 1_700_000_100);
+        assert_eq!(data, std::fs::read(directory.path().join("built/files")).unwrap());
+        // Without a manifest, it is not known when the index was built.
+        std::fs::remove_file(directory.path().join("built/manifest.json")).unwrap();
+        let (metadata, _) = most_recent_index(directories(), "x86_64-linux").unwrap();
+        assert_eq!(metadata.built_at, 1_700_000_000);
+        assert!(most_recent_index(directories(), "x86_64-darwin").is_none());
+    }
+}
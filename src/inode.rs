@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+
+/// The root inode, as mandated by FUSE.
+pub const ROOT_INODE: u64 = 1;
+
+struct InodeEntry {
+    path: String,
+    generation: u64,
+    /// Number of times the kernel was handed this inode and did not forget it yet.
+    lookups: u64,
+    /// Pinned inodes are never reclaimed, e.g. the global FHS directories.
+    pinned: bool,
+}
+
+/// Inode table keyed by the canonical requested path.
+///
+/// Looking up the same path twice yields the same inode as long as the kernel did not
+/// `forget` it. Forgotten inodes are reclaimed and their number is reused with a new
+/// generation, so that long sessions do not grow without bound.
+pub struct InodeTable {
+    root: u64,
+    /// Directory holding the root, the root itself unless the table is mounted below another one
+    root_parent: u64,
+    entries: HashMap<u64, InodeEntry>,
+    by_path: HashMap<String, u64>,
+    free: Vec<u64>,
+    next_inode: u64,
+    next_generation: u64,
+}
+
+impl Default for InodeTable {
+    fn default() -> Self {
+        InodeTable::with_root(ROOT_INODE)
+    }
+}
+
+impl InodeTable {
+    /// Table whose root is `root`, further inodes are allocated right after it.
+    pub fn with_root(root: u64) -> Self {
+        InodeTable::with_root_under(root, root)
+    }
+
+    /// Same as `with_root`, for a table whose root is the `parent` directory child, e.g. a namespace.
+    pub fn with_root_under(root: u64, parent: u64) -> Self {
+        let mut table = InodeTable {
+            root,
+            root_parent: parent,
+            entries: HashMap::new(),
+            by_path: HashMap::new(),
+            free: Vec::new(),
+            next_inode: root,
+            next_generation: 0,
+        };
+        table.pin("");
+
+        table
+    }
+
+    pub fn root(&self) -> u64 {
+        self.root
+    }
+
+    fn allocate(&mut self, path: &str, pinned: bool) -> u64 {
+        let ino = self.free.pop().unwrap_or_else(|| {
+            self.next_inode += 1;
+            self.next_inode - 1
+        });
+        self.next_generation += 1;
+        self.entries.insert(
+            ino,
+            InodeEntry {
+                path: path.to_string(),
+                generation: self.next_generation,
+                lookups: 0,
+                pinned,
+            },
+        );
+        self.by_path.insert(path.to_string(), ino);
+
+        ino
+    }
+
+    /// Register a path which lives as long as the filesystem.
+    pub fn pin(&mut self, path: &str) -> u64 {
+        match self.by_path.get(path) {
+            Some(&ino) => {
+                self.entries.get_mut(&ino).unwrap().pinned = true;
+                ino
+            }
+            None => self.allocate(path, true),
+        }
+    }
+
+    /// Inode for this path, allocated if needed.
+    /// Counts as one more lookup handed to the kernel.
+    pub fn lookup(&mut self, path: &str) -> u64 {
+        let ino = match self.by_path.get(path) {
+            Some(&ino) => ino,
+            None => self.allocate(path, false),
+        };
+        self.entries.get_mut(&ino).unwrap().lookups += 1;
+
+        ino
+    }
+
+    pub fn get(&self, path: &str) -> Option<u64> {
+        self.by_path.get(path).copied()
+    }
+
+    pub fn path(&self, ino: u64) -> Option<&str> {
+        self.entries.get(&ino).map(|entry| entry.path.as_str())
+    }
+
+    /// Inode of the directory holding this one, the root is its own parent unless mounted below another table.
+    pub fn parent(&self, ino: u64) -> Option<u64> {
+        if ino == self.root {
+            return Some(self.root_parent);
+        }
+        let path = self.path(ino)?;
+        self.get(path.rsplit_once('/').map_or("", |(parent, _)| parent))
+    }
+
+    pub fn generation(&self, ino: u64) -> u64 {
+        self.entries.get(&ino).map_or(0, |entry| entry.generation)
+    }
+
+    /// The kernel dropped `nlookup` references to this inode.
+    /// Returns `true` if the inode was reclaimed.
+    pub fn forget(&mut self, ino: u64, nlookup: u64) -> bool {
+        let reclaim = match self.entries.get_mut(&ino) {
+            Some(entry) => {
+                entry.lookups = entry.lookups.saturating_sub(nlookup);
+                entry.lookups == 0 && !entry.pinned
+            }
+            None => false,
+        };
+
+        if reclaim {
+            let entry = self.entries.remove(&ino).unwrap();
+            self.by_path.remove(&entry.path);
+            self.free.push(ino);
+        }
+
+        reclaim
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forgotten_inodes_are_reused_with_a_new_generation() {
+        let mut table = InodeTable::default();
+        let lib = table.pin("lib");
+        let ino = table.lookup("lib/libz.so");
+        assert_eq!(table.lookup("lib/libz.so"), ino);
+        let generation = table.generation(ino);
+
+        assert!(!table.forget(ino, 1));
+        assert!(table.forget(ino, 1));
+        assert!(!table.forget(lib, 1));
+        assert_eq!(table.path(ino), None);
+
+        assert_eq!(table.lookup("lib/libc.so"), ino);
+        assert_ne!(table.generation(ino), generation);
+        assert_eq!(table.path(lib), Some("lib"));
+    }
+
+    #[test]
+    fn test_parents() {
+        let mut table = InodeTable::with_root(7);
+        let lib = table.pin("lib");
+        let pkgconfig = table.lookup("lib/pkgconfig");
+
+        assert_eq!(table.parent(7), Some(7));
+        assert_eq!(table.parent(lib), Some(7));
+        assert_eq!(table.parent(pkgconfig), Some(lib));
+        assert_eq!(table.parent(42), None);
+
+        let mut table = InodeTable::with_root_under(7, ROOT_INODE);
+        let lib = table.pin("lib");
+        assert_eq!(table.parent(7), Some(ROOT_INODE));
+        assert_eq!(table.parent(lib), Some(7));
+    }
+}
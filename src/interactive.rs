@@ -1,98 +1,1164 @@
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
 use std::thread;
+use std::time::{Duration, Instant};
 use std::{
-    sync::mpsc::{channel, Sender},
+    sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+    sync::Mutex,
     thread::JoinHandle,
 };
 
+use crossterm::tty::IsTty;
+use lazy_static::lazy_static;
 use log::{debug, info, warn};
+use nix::sys::signal::{raise, Signal};
+use rustyline::error::ReadlineError;
+use regex::Regex;
+use rustyline::DefaultEditor;
 
+use crate::audit::AuditTrail;
 use crate::cache::{FileTreeEntry, StorePath};
+use crate::compare::{candidate_details, comparison};
+use crate::explain::Explanation;
+use crate::frontend::Frontend;
 use crate::fs::FsEventMessage;
+use crate::fuzzy;
+use crate::nix::closure_size;
+use crate::outputs;
+use crate::overlay::MissingPackage;
+use crate::policy::{AutomaticPolicy, PolicyFallback};
+use crate::process::ProcessInfo;
+use crate::ranking::Ranking;
+use crate::realize::RealizationProgress;
+use crate::resolution::Decision;
 
 /// Request types between FUSE thread and UI thread
+#[derive(Clone)]
 pub enum UserRequest {
     /// Order the thread to stop listen for events
     Quit,
     /// An interactive search request for the given path to the UI thread
-    /// with a preferred candidate.
-    InteractiveSearch(Vec<(StorePath, FileTreeEntry)>, (StorePath, FileTreeEntry)),
+    /// with the ranking of each candidate, a preferred candidate and the process which requested it.
+    /// The first field identifies the request, replies carry it back.
+    /// The sixth one is set if the candidates only match a relaxed pattern,
+    /// the next one if the path is deferred to the user, even in automatic mode.
+    /// The next one is the subtree of the path an answer can be applied to, if any,
+    /// the last one the requested path.
+    InteractiveSearch(
+        u64,
+        Vec<(StorePath, FileTreeEntry)>,
+        Vec<Ranking>,
+        (StorePath, FileTreeEntry),
+        ProcessInfo,
+        bool,
+        bool,
+        Option<PathBuf>,
+        PathBuf,
+    ),
+    /// Confirm the resolution, described, of the given path for this run,
+    /// with the request it answers and the process which requested it.
+    Reconfirm(u64, PathBuf, String, ProcessInfo),
+    /// The filesystem request was interrupted, or answered from another frontend, drop its prompt
+    Cancel(u64),
+    /// A decision, described, was recorded for the given path,
+    /// the first field identifies the request it answered to undo it
+    Decided(u64, PathBuf, String),
+    /// Why the path of the given prompt is requested, as asked by `FsEventMessage::Explain`
+    Explanation(u64, Explanation),
+    /// Where the realizations of store paths stand, see `Realizer`
+    Realizing(RealizationProgress),
 }
 
-pub fn prompt_among_choices(
+impl UserRequest {
+    /// Request the replies to this prompt carry back, if it is one.
+    pub fn prompt_id(&self) -> Option<u64> {
+        match self {
+            Self::InteractiveSearch(request_id, ..) | Self::Reconfirm(request_id, ..) => {
+                Some(*request_id)
+            }
+            _ => None,
+        }
+    }
+
+    /// Path this prompt is about, if it is one.
+    pub fn requested_path(&self) -> Option<&Path> {
+        match self {
+            Self::InteractiveSearch(.., requested_path) | Self::Reconfirm(_, requested_path, ..) => {
+                Some(requested_path)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// How prompts are shown to the user.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Ui {
+    /// Full screen, with the candidates, their details and the build log
+    Tui,
+    /// Line by line on the standard input and output, e.g. when they are not a terminal
+    Plain,
+}
+
+impl Ui {
+    /// The TUI when running in a terminal.
+    pub fn detect() -> Self {
+        if std::io::stdin().is_tty() && std::io::stdout().is_tty() {
+            Self::Tui
+        } else {
+            Self::Plain
+        }
+    }
+}
+
+/// How often a pending prompt checks whether it was cancelled.
+const CANCELLATION_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// What to do when the user does not answer a prompt in time.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeoutDecision {
+    /// Record the path as ignored (ENOENT).
+    Ignore,
+    /// Accept the top-ranked suggestion.
+    Accept,
+}
+
+/// `nix path-info` run at once to compute closure sizes.
+pub const CLOSURE_SIZE_WORKERS: usize = 4;
+
+/// Outcome of a prompt among choices.
+pub enum PromptAnswer {
+    Choice(usize),
+    /// The choice also provides the lookups under the subtree of the requested path
+    SubtreeChoice(usize),
+    /// Look for other candidates with this query, see `FsEventMessage::ManualSearch`
+    Search(String),
+    /// A session command such as `:undo 2`, the prompt is asked again after it ran
+    Command(String),
+    Skip,
+    /// ENOENT this time only, the path is asked again on its next lookup
+    SkipForNow,
+    TimedOut,
+    Cancelled,
+}
+
+/// A decision of the session, reviewed once the command exits, see `--review`.
+pub struct ReviewItem {
+    pub request_id: u64,
+    pub requested_path: PathBuf,
+    pub description: String,
+    /// Candidates the provided package was chosen among, empty for other decisions
+    pub candidates: Vec<(StorePath, FileTreeEntry)>,
+    pub chosen: Option<usize>,
+}
+
+/// What the review does with a decision.
+pub enum ReviewVerdict {
+    Keep,
+    /// Provide this candidate instead
+    Change(usize),
+    /// Forget it, the path is asked again next time
+    Drop,
+}
+
+/// Human readable size, in binary units.
+pub fn human_size(bytes: usize) -> String {
+    let mut size = bytes as f64;
+    for unit in ["B", "KiB", "MiB", "GiB"] {
+        if size < 1024.0 {
+            return format!("{:.1} {}", size, unit);
+        }
+        size /= 1024.0;
+    }
+    format!("{:.1} TiB", size)
+}
+
+/// Closure sizes of the candidates, a few at once since `nix path-info` takes a while for remote paths.
+fn closure_sizes(candidates: &[(StorePath, FileTreeEntry)]) -> Vec<Option<usize>> {
+    candidates
+        .chunks(CLOSURE_SIZE_WORKERS)
+        .flat_map(|chunk| {
+            thread::scope(|scope| {
+                let workers: Vec<_> = chunk
+                    .iter()
+                    .map(|(store_path, _)| scope.spawn(move || closure_size(store_path.as_str().as_ref())))
+                    .collect();
+                workers
+                    .into_iter()
+                    .map(|worker| worker.join().unwrap_or(None))
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect()
+}
+
+/// Lines of stdin, the reader is shared by the UI thread, the merge conflicts and the review.
+static STDIN_LINES: Mutex<Option<Receiver<String>>> = Mutex::new(None);
+
+/// Answers of previous sessions, recalled with the arrow keys.
+fn history_filepath() -> Option<PathBuf> {
+    xdg::BaseDirectories::with_prefix("buildxyz")
+        .ok()?
+        .place_data_file("history")
+        .ok()
+}
+
+/// Reads stdin line by line in a dedicated thread
+/// so that prompts can wait for an answer with a timeout.
+/// Lines are edited with readline key bindings and kept in the history across sessions.
+fn spawn_stdin_reader() -> Receiver<String> {
+    let (send, recv) = channel();
+
+    thread::spawn(move || {
+        let mut editor = match DefaultEditor::new() {
+            Ok(editor) => editor,
+            Err(err) => {
+                warn!("Failed to set up line editing, reading stdin as is: {}", err);
+                return read_stdin_lines(send);
+            }
+        };
+        let history = history_filepath();
+        if let Some(history) = &history {
+            let _ = editor.load_history(history);
+        }
+
+        loop {
+            match editor.readline("> ") {
+                Ok(line) => {
+                    let line = strip_control_sequences(&line);
+                    if !line.trim().is_empty() {
+                        let _ = editor.add_history_entry(line.trim());
+                        if let Some(history) = &history {
+                            if let Err(err) = editor.append_history(history) {
+                                debug!("Failed to save the history to {}: {}", history.display(), err);
+                            }
+                        }
+                    }
+                    if send.send(line).is_err() {
+                        break;
+                    }
+                }
+                // The terminal does not turn Ctrl-C into a signal while a line is edited.
+                Err(ReadlineError::Interrupted) => {
+                    let _ = raise(Signal::SIGINT);
+                }
+                Err(_) => break,
+            }
+        }
+    });
+
+    recv
+}
+
+/// Read the answers as they come, without line editing nor its redrawn prompt, see `--plain`.
+/// Must be called before anything is asked.
+pub fn disable_line_editing() {
+    let (send, recv) = channel();
+    thread::spawn(move || read_stdin_lines(send));
+    *STDIN_LINES.lock().unwrap() = Some(recv);
+}
+
+lazy_static! {
+    /// CSI sequences, e.g. the bracketed paste markers `ESC [200~` and `ESC [201~`.
+    static ref ESCAPE_SEQUENCE: Regex = Regex::new(r"\x1b\[[0-?]*[ -/]*[@-~]").unwrap();
+}
+
+/// Pasted escape sequences and control characters are not part of the answer.
+fn strip_control_sequences(line: &str) -> String {
+    ESCAPE_SEQUENCE
+        .replace_all(line, "")
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect()
+}
+
+fn read_stdin_lines(send: Sender<String>) {
+    loop {
+        let mut line = String::new();
+        match std::io::stdin().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {
+                if send.send(line).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Next line of stdin, `None` once it is closed.
+fn read_answer() -> Option<String> {
+    let mut stdin_lines = STDIN_LINES.lock().unwrap();
+    stdin_lines.get_or_insert_with(spawn_stdin_reader).recv().ok()
+}
+
+/// Prompt the user to pick among choices.
+/// If `searchable`, a query matching none of them, or starting with `?`, is looked up instead.
+/// If `discard_typed_ahead`, lines received before the prompt was shown are not taken as answers,
+/// unlike lines piped in advance by a script.
+/// `is_cancelled` is polled while waiting so that the prompt can be dropped
+/// when the underlying filesystem request goes away.
+pub fn prompt_among_choices<C>(
     prompt: &str,
-    choices: Vec<String>
-) -> Option<usize> {
+    choices: Vec<String>,
+    searchable: bool,
+    answers: &Receiver<String>,
+    discard_typed_ahead: bool,
+    timeout: Option<Duration>,
+    mut is_cancelled: C,
+) -> PromptAnswer
+where
+    C: FnMut() -> bool,
+{
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    if discard_typed_ahead {
+        let stale = discard_stale_answers(answers);
+        if stale > 0 {
+            warn!("Ignoring {} line(s) typed before this prompt", stale);
+        }
+    }
+    // Indices of the choices listed, narrowed by typing something else than a number.
+    let mut shown: Vec<usize> = (0..choices.len()).collect();
     loop {
-        let mut answer = String::new();
         info!("{}", prompt);
-        for (index, choice) in choices.iter().enumerate() {
-            info!("{}. {}", index + 1, choice);
+        for index in &shown {
+            info!("{}. {}", index + 1, choices[*index]);
         }
-        // TODO: make this non-blocking and interruptible
-        std::io::stdin()
-            .read_line(&mut answer)
-            .ok()
-            .expect("Failed to read line");
+        let answer = loop {
+            let wait = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(remaining) => remaining.min(CANCELLATION_POLL_INTERVAL),
+                    None => return PromptAnswer::TimedOut,
+                },
+                None => CANCELLATION_POLL_INTERVAL,
+            };
+            match answers.recv_timeout(wait) {
+                Ok(answer) => break answer,
+                Err(RecvTimeoutError::Timeout) => {
+                    if is_cancelled() {
+                        return PromptAnswer::Cancelled;
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => return PromptAnswer::Skip,
+            }
+        };
 
+        if answer.trim().starts_with(':') {
+            return PromptAnswer::Command(answer.trim().to_string());
+        }
+        if let Some(query) = answer.trim().strip_prefix('?').filter(|_| searchable) {
+            return PromptAnswer::Search(query.trim().to_string());
+        }
         if answer.trim().to_lowercase() == "n" || answer.trim().to_lowercase() == "no" || answer.trim() == "" {
-            return None;
+            return PromptAnswer::Skip;
+        }
+        if answer.trim().to_lowercase() == "later" {
+            return PromptAnswer::SkipForNow;
         }
 
+        // `3*` picks the third choice for the whole subtree.
+        if let Some(Ok(k)) = answer.trim().strip_suffix('*').map(str::parse::<usize>) {
+            if k >= 1 && k <= choices.len() {
+                return PromptAnswer::SubtreeChoice(k - 1);
+            }
+        }
         match answer.trim().parse::<usize>() {
             Ok(k) if k >= 1 && k <= choices.len() => {
-                return Some(k - 1);
+                return PromptAnswer::Choice(k - 1);
+            }
+            Err(_) => {
+                let mut scored: Vec<(i64, usize)> = (0..choices.len())
+                    .filter_map(|index| {
+                        // Choices start with what they are, the rest explains them.
+                        let name = choices[index].split_whitespace().next().unwrap_or_default();
+                        fuzzy::filter_score(answer.trim(), name)
+                            .map(|score| (score, index))
+                    })
+                    .collect();
+                if scored.is_empty() && searchable {
+                    return PromptAnswer::Search(answer.trim().to_string());
+                }
+                if scored.is_empty() {
+                    warn!("No choice matches `{}`", answer.trim());
+                    continue;
+                }
+                scored.sort_by_key(|(score, index)| (-score, *index));
+                shown = scored.into_iter().map(|(_, index)| index).collect();
+                continue;
             }
             _ => {
-                warn!("Enter a valid choice between 1 and {} or `no`/`n`/press enter for skipping this choice", choices.len());
+                warn!("Enter a valid choice between 1 and {}, `no`/`n`/press enter for skipping this choice or `later` for skipping it this time only", choices.len());
                 continue;
             }
         }
     }
 }
 
+/// Drop the lines typed while no prompt was shown, they do not answer the next one.
+fn discard_stale_answers(answers: &Receiver<String>) -> usize {
+    let mut stale = 0;
+    while answers.try_recv().is_ok() {
+        stale += 1;
+    }
+    stale
+}
+
+/// Whether the prompt of `request_id` was cancelled in the meantime.
+/// Other requests received meanwhile are kept in `pending`, cancelled ones are dropped.
+fn poll_cancellation(
+    recv: &Receiver<UserRequest>,
+    pending: &mut VecDeque<UserRequest>,
+    request_id: u64,
+) -> bool {
+    let mut cancelled = false;
+    while let Ok(message) = recv.try_recv() {
+        match message {
+            UserRequest::Cancel(id) if id == request_id => cancelled = true,
+            UserRequest::Cancel(id) => pending.retain(|request| request.prompt_id() != Some(id)),
+            UserRequest::Quit => {
+                cancelled = true;
+                pending.push_front(UserRequest::Quit);
+            }
+            request => pending.push_back(request),
+        }
+    }
+    cancelled
+}
+
+/// The results of a manual search come back as a new prompt for the same request, it is asked first.
+/// Other requests received meanwhile are kept in `pending`.
+fn wait_for_search_results(
+    recv: &Receiver<UserRequest>,
+    pending: &mut VecDeque<UserRequest>,
+    request_id: u64,
+) {
+    info!("Looking it up, this may take a while");
+    while let Ok(message) = recv.recv() {
+        match message {
+            UserRequest::Cancel(id) if id == request_id => break,
+            UserRequest::Cancel(id) => pending.retain(|request| request.prompt_id() != Some(id)),
+            UserRequest::Quit => {
+                pending.push_front(UserRequest::Quit);
+                break;
+            }
+            request if request.prompt_id() == Some(request_id) => {
+                pending.push_front(request);
+                break;
+            }
+            request => pending.push_back(request),
+        }
+    }
+}
+
+/// Show why the path of this prompt is requested once the filesystem tells, whether the prompt still waits.
+/// Other requests received meanwhile are kept in `pending`.
+fn wait_for_explanation(
+    recv: &Receiver<UserRequest>,
+    pending: &mut VecDeque<UserRequest>,
+    request_id: u64,
+) -> bool {
+    while let Ok(message) = recv.recv() {
+        match message {
+            UserRequest::Explanation(id, explanation) if id == request_id => {
+                for line in explanation.lines() {
+                    info!("{}", line);
+                }
+                return true;
+            }
+            UserRequest::Cancel(id) if id == request_id => return false,
+            UserRequest::Cancel(id) => pending.retain(|request| request.prompt_id() != Some(id)),
+            UserRequest::Quit => {
+                pending.push_front(UserRequest::Quit);
+                return false;
+            }
+            request => pending.push_back(request),
+        }
+    }
+    false
+}
+
+/// Ask which of the conflicting resolutions of this path to keep, `None` to let priorities decide.
+/// Only used before the UI thread starts.
+pub fn prompt_merge_conflict(requested_path: &str, choices: &[String]) -> Option<usize> {
+    loop {
+        info!("Conflicting resolutions for {}, pick the one to keep", requested_path);
+        for (index, choice) in choices.iter().enumerate() {
+            info!("{}. {}", index + 1, choice);
+        }
+
+        let answer = read_answer()?;
+        if answer.trim().is_empty() {
+            return None;
+        }
+
+        match answer.trim().parse::<usize>() {
+            Ok(k) if k >= 1 && k <= choices.len() => return Some(k - 1),
+            _ => warn!(
+                "Enter a valid choice between 1 and {} or press enter to keep the highest priority",
+                choices.len()
+            ),
+        }
+    }
+}
+
+/// Ask what to do with each decision of the session, in order.
+/// Only used once the UI thread is done, decisions left when stdin closes are kept.
+pub fn review_decisions(items: &[ReviewItem]) -> Vec<(u64, ReviewVerdict)> {
+    let mut verdicts = Vec::new();
+    for (position, item) in items.iter().enumerate() {
+        info!(
+            "Decision {} of {}: {} was {}",
+            position + 1,
+            items.len(),
+            item.requested_path.display(),
+            item.description
+        );
+        for (index, (store_path, _)) in item.candidates.iter().enumerate() {
+            info!(
+                "{}. {}{}",
+                index + 1,
+                outputs::label(&store_path.origin()),
+                if item.chosen == Some(index) { " (chosen)" } else { "" }
+            );
+        }
+        let verdict = loop {
+            info!("Press enter to keep it, pick another candidate, `d` to drop it or `k` to keep all the remaining ones");
+            let answer = match read_answer() {
+                Some(answer) => answer,
+                None => return verdicts,
+            };
+            match answer.trim() {
+                "" => break ReviewVerdict::Keep,
+                "d" => break ReviewVerdict::Drop,
+                "k" => return verdicts,
+                answer => match answer.parse::<usize>() {
+                    Ok(k) if item.chosen == Some(k.wrapping_sub(1)) => break ReviewVerdict::Keep,
+                    Ok(k) if k >= 1 && k <= item.candidates.len() => break ReviewVerdict::Change(k - 1),
+                    _ => warn!("`{}` is not an answer", answer),
+                },
+            }
+        };
+        verdicts.push((item.request_id, verdict));
+    }
+
+    verdicts
+}
+
+/// Ask which decisions of the session are written to the `--record-to` file, returns those left out.
+/// `existing` counts the resolutions loaded beforehand by source, they are written as they are.
+pub fn confirm_record(items: &[ReviewItem], existing: &[(String, usize)]) -> Vec<u64> {
+    let existing_count: usize = existing.iter().map(|(_, count)| count).sum();
+    info!(
+        "{} new decisions are about to be written to the record file, along with {} existing resolutions{}",
+        items.len(),
+        existing_count,
+        if existing.is_empty() {
+            String::new()
+        } else {
+            format!(
+                " ({})",
+                existing
+                    .iter()
+                    .map(|(source, count)| format!("{} from {}", count, source))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        }
+    );
+    for (position, item) in items.iter().enumerate() {
+        info!("{}. {}: {}", position + 1, item.requested_path.display(), item.description);
+    }
+
+    loop {
+        info!("Press enter to write them all, list those to leave out (e.g. `2 4-6`), `ignored` to leave out the ignored paths or `none`");
+        let answer = match read_answer() {
+            Some(answer) => answer,
+            None => return Vec::new(),
+        };
+        let left_out: Option<Vec<&ReviewItem>> = match answer.trim() {
+            "" => Some(Vec::new()),
+            "none" => Some(items.iter().collect()),
+            "ignored" => Some(
+                items
+                    .iter()
+                    .filter(|item| item.description == Decision::Ignore.to_string())
+                    .collect(),
+            ),
+            answer => parse_ranges(answer, items.len())
+                .map(|indices| indices.into_iter().map(|index| &items[index]).collect()),
+        };
+        match left_out {
+            Some(left_out) => {
+                if !left_out.is_empty() {
+                    info!("Leaving out {} decisions", left_out.len());
+                }
+                return left_out.iter().map(|item| item.request_id).collect();
+            }
+            None => warn!("`{}` is not an answer", answer.trim()),
+        }
+    }
+}
+
+/// Indices of the items an answer like `2 4-6` picks, numbered from 1, `None` if it is not one.
+fn parse_ranges(answer: &str, count: usize) -> Option<Vec<usize>> {
+    answer
+        .split_whitespace()
+        .map(|range| {
+            let (first, last) = range.split_once('-').unwrap_or((range, range));
+            match (first.parse::<usize>(), last.parse::<usize>()) {
+                (Ok(first), Ok(last)) if 1 <= first && first <= last && last <= count => {
+                    Some(first - 1..last)
+                }
+                _ => None,
+            }
+        })
+        .collect::<Option<Vec<_>>>()
+        .map(|ranges| ranges.into_iter().flatten().collect())
+}
+
+/// Ask in the terminal which of the packages missing during the session get a stub overlay, by their index.
+pub fn offer_missing_overlays(packages: &[MissingPackage]) -> Vec<usize> {
+    info!("No package provides the following paths, a stub overlay can be written for each missing package:");
+    for (position, package) in packages.iter().enumerate() {
+        let paths: Vec<&str> = package.paths.iter().map(|path| path.requested_path.as_str()).collect();
+        info!("{}. {}: {}", position + 1, package.name, paths.join(", "));
+    }
+
+    loop {
+        info!("Press enter to write them all, list those to write (e.g. `2 4-6`) or `none`");
+        let answer = match read_answer() {
+            Some(answer) => answer,
+            None => return Vec::new(),
+        };
+        let chosen = match answer.trim() {
+            "" => Some((0..packages.len()).collect()),
+            "none" => Some(Vec::new()),
+            answer => parse_ranges(answer, packages.len()),
+        };
+        match chosen {
+            Some(chosen) => return chosen,
+            None => warn!("`{}` is not an answer", answer.trim()),
+        }
+    }
+}
+
+/// Answer the prompts which need no user in automatic mode, whether it did.
+/// Suggestions failing the policy are asked or ignored, accepted ones are followed by `audit`.
+pub fn answer_automatically(
+    reply_fs: &Sender<FsEventMessage>,
+    request: &UserRequest,
+    automatic: Option<&AutomaticPolicy>,
+    audit: &mut AuditTrail,
+) -> bool {
+    let (request_id, rankings, suggested, requester, fuzzy, policy) = match (request, automatic) {
+        (
+            UserRequest::InteractiveSearch(request_id, _, rankings, suggested, requester, fuzzy, false, ..),
+            Some(policy),
+        ) => (request_id, rankings, suggested, requester, fuzzy, policy),
+        _ => return false,
+    };
+
+    if let Some(violation) = policy.violation(suggested, rankings.first()) {
+        return match policy.otherwise {
+            PolicyFallback::Prompt => {
+                warn!("not providing automatically: {}, asking instead", violation);
+                false
+            }
+            PolicyFallback::Ignore => {
+                warn!("not providing automatically: {}, ignoring the path", violation);
+                reply_fs
+                    .send(FsEventMessage::IgnoreRequest(*request_id))
+                    .expect("Failed to send message to FS thread");
+                true
+            }
+        };
+    }
+    // The suggestion is the best ranked candidate, tell why for the record.
+    info!(
+        "automatically providing {} as a {}path requested by {}: {}",
+        outputs::label(&suggested.0.origin()),
+        if *fuzzy { "fuzzy " } else { "" },
+        requester,
+        rankings.first().map_or(String::new(), Ranking::to_string)
+    );
+    audit.accepted(request);
+    reply_fs
+        .send(FsEventMessage::PackageSuggestion(*request_id, suggested.clone()))
+        .expect("Failed to send message to FS thread");
+    true
+}
+
+/// What the user is asked for this prompt.
+pub fn prompt_title(request: &UserRequest) -> String {
+    match request {
+        UserRequest::InteractiveSearch(_, _, _, _, requester, _, true, _, requested_path) => format!(
+            "{}, which must be chosen by hand, was requested by {}, pick a choice",
+            requested_path.display(),
+            requester
+        ),
+        UserRequest::InteractiveSearch(_, _, _, _, requester, true, _, _, requested_path) => format!(
+            "{}, with no exact match, was requested by {}, pick a fuzzy choice",
+            requested_path.display(),
+            requester
+        ),
+        UserRequest::InteractiveSearch(_, _, _, _, requester, _, _, _, requested_path) => format!(
+            "{}, not found in your search paths, was requested by {}, pick a choice",
+            requested_path.display(),
+            requester
+        ),
+        UserRequest::Reconfirm(_, requested_path, _, requester) => format!(
+            "{} was requested by {}, its resolution must be confirmed for this run",
+            requested_path.display(),
+            requester
+        ),
+        UserRequest::Quit
+        | UserRequest::Cancel(_)
+        | UserRequest::Decided(..)
+        | UserRequest::Explanation(..)
+        | UserRequest::Realizing(_) => String::new(),
+    }
+}
+
+/// Reply to the filesystem with the answer to this prompt,
+/// timed out prompts get `timeout_decision`.
+pub fn send_answer(
+    reply_fs: &Sender<FsEventMessage>,
+    request: &UserRequest,
+    answer: PromptAnswer,
+    prompt_timeout: Option<Duration>,
+    timeout_decision: TimeoutDecision,
+) {
+    if let PromptAnswer::TimedOut = answer {
+        warn!(
+            "No answer within {:?}, applying the default decision: {:?}",
+            prompt_timeout.unwrap_or_default(),
+            timeout_decision
+        );
+    }
+    let accepted = matches!(answer, PromptAnswer::TimedOut) && timeout_decision == TimeoutDecision::Accept;
+
+    let reply = match (request, answer) {
+        (_, PromptAnswer::Cancelled) => {
+            info!("The request was interrupted, dropping the prompt");
+            return;
+        }
+        (_, PromptAnswer::Command(_)) => return,
+        (
+            UserRequest::InteractiveSearch(request_id, candidates, _, _, _, _, _, Some(_), _),
+            PromptAnswer::SubtreeChoice(index),
+        ) => FsEventMessage::SubtreeSuggestion(*request_id, candidates[index].clone()),
+        (
+            UserRequest::InteractiveSearch(request_id, candidates, ..),
+            PromptAnswer::Choice(index) | PromptAnswer::SubtreeChoice(index),
+        ) => FsEventMessage::PackageSuggestion(*request_id, candidates[index].clone()),
+        (UserRequest::InteractiveSearch(request_id, ..), PromptAnswer::Search(query)) => {
+            FsEventMessage::ManualSearch(*request_id, query)
+        }
+        (request, PromptAnswer::SkipForNow) => match request.prompt_id() {
+            Some(request_id) => FsEventMessage::SkipForNow(request_id),
+            None => return,
+        },
+        (UserRequest::InteractiveSearch(request_id, _, _, suggested, ..), _) if accepted => {
+            FsEventMessage::PackageSuggestion(*request_id, suggested.clone())
+        }
+        (UserRequest::Reconfirm(request_id, ..), PromptAnswer::Choice(_) | PromptAnswer::SubtreeChoice(_)) => FsEventMessage::Confirm(*request_id),
+        (UserRequest::Reconfirm(request_id, ..), _) if accepted => FsEventMessage::Confirm(*request_id),
+        (request, _) => match request.prompt_id() {
+            Some(request_id) => FsEventMessage::IgnoreRequest(request_id),
+            None => return,
+        },
+    };
+
+    reply_fs
+        .send(reply)
+        .expect("Failed to send message to FS thread");
+}
+
+/// How to answer a prompt among `choices`, spelled out for `--plain`.
+fn answer_hint(request: &UserRequest, choices: usize) -> String {
+    match request {
+        UserRequest::Reconfirm(..) => {
+            ". Answer 1 to confirm, n to skip, later to skip it for now, or :explain to tell why it is requested"
+                .to_string()
+        }
+        UserRequest::InteractiveSearch(.., subtree, _) => format!(
+            ". Answer a number from 1 to {}{}, n to skip, later to skip it for now, :explain to tell why it is requested, or a question mark followed by what to look up instead",
+            choices,
+            if subtree.is_some() {
+                ", followed by a star for the whole directory"
+            } else {
+                ""
+            }
+        ),
+        _ => String::new(),
+    }
+}
+
+/// Run a session command typed in place of an answer:
+/// `:decisions` lists the decisions made so far, `:undo N` undoes the N-th one
+/// and `:compare A B` compares two of the `candidates` of the shown prompt.
+fn run_session_command(
+    line: &str,
+    decisions: &mut Vec<(u64, PathBuf, String)>,
+    candidates: &[(StorePath, FileTreeEntry)],
+    reply_fs: &Sender<FsEventMessage>,
+) {
+    let mut words = line.trim().trim_start_matches(':').split_whitespace();
+    let command = words.next();
+    let numbers: Vec<_> = words.map(str::parse::<usize>).collect();
+    match (command, numbers.as_slice()) {
+        (Some("decisions"), []) if decisions.is_empty() => {
+            info!("No decision was made during this session")
+        }
+        (Some("decisions"), []) => {
+            for (index, (_, requested_path, description)) in decisions.iter().enumerate() {
+                info!("{}. {}: {}", index + 1, requested_path.display(), description);
+            }
+        }
+        (Some("undo"), [Ok(k)]) if *k >= 1 && *k <= decisions.len() => {
+            let (request_id, requested_path, description) = decisions.remove(k - 1);
+            info!("Undoing {}: {}", requested_path.display(), description);
+            reply_fs
+                .send(FsEventMessage::Undo(request_id))
+                .expect("Failed to send message to FS thread");
+        }
+        (Some("compare"), [Ok(a), Ok(b)])
+            if a != b && (1..=candidates.len()).contains(a) && (1..=candidates.len()).contains(b) =>
+        {
+            info!("Comparing the candidates {} and {}, this may take a while", a, b);
+            let (left, right) = thread::scope(|scope| {
+                let details = |k: usize| {
+                    let (store_path, entry) = &candidates[k - 1];
+                    scope.spawn(move || candidate_details(store_path, entry))
+                };
+                let (left, right) = (details(*a), details(*b));
+                (left.join().unwrap(), right.join().unwrap())
+            });
+            let rows = comparison(&left, &right);
+            let width = rows.iter().map(|[_, value, _]| value.chars().count()).max().unwrap_or(0);
+            for [label, left, right] in rows {
+                info!("{:>14}  {:<width$}  {}", label, left, right, width = width);
+            }
+        }
+        (Some("compare"), _) if candidates.len() < 2 => {
+            warn!("`:compare A B` needs a prompt with at least two candidates")
+        }
+        _ => warn!(
+            "Unknown command `{}`, use `:decisions`, `:undo N` with N between 1 and {}, `:compare A B`, `:explain`, `:queue` or `:next`",
+            line.trim(),
+            decisions.len()
+        ),
+    }
+}
+
+/// Prompts line by line on the standard input and output, see `--ui plain`.
+pub struct PlainFrontend {
+    /// Tell how to answer each prompt, see `--plain`
+    pub answer_hints: bool,
+    /// Policy accepting the suggestions, `None` unless `--automatic`
+    pub automatic: Option<AutomaticPolicy>,
+    /// Follows the accepted suggestions, see `--audit-log`
+    pub audit: AuditTrail,
+    pub prompt_timeout: Option<Duration>,
+    pub timeout_decision: TimeoutDecision,
+}
+
+impl Frontend for PlainFrontend {
+    fn spawn(
+        self: Box<Self>,
+        reply_fs: Sender<FsEventMessage>,
+    ) -> std::io::Result<(Option<JoinHandle<()>>, Sender<UserRequest>)> {
+        let (join_handle, send) = spawn_ui(
+            reply_fs,
+            self.automatic,
+            self.audit,
+            self.prompt_timeout,
+            self.timeout_decision,
+            self.answer_hints,
+        );
+
+        Ok((Some(join_handle), send))
+    }
+}
+
 pub fn spawn_ui(
     reply_fs: Sender<FsEventMessage>,
-    automatic: bool,
+    automatic: Option<AutomaticPolicy>,
+    mut audit: AuditTrail,
+    prompt_timeout: Option<Duration>,
+    timeout_decision: TimeoutDecision,
+    answer_hints: bool,
 ) -> (JoinHandle<()>, Sender<UserRequest>) {
     let (send, recv) = channel();
 
     let join_handle = thread::spawn(move || {
         info!("UI thread spawned and listening for events");
-        loop {
-            if let Ok(message) = recv.recv() {
-                match message {
-                    UserRequest::Quit => {
-                        break;
+        let mut stdin_lines = STDIN_LINES.lock().unwrap();
+        let answers = stdin_lines.get_or_insert_with(spawn_stdin_reader);
+        // Only someone at a terminal types ahead of the prompts, scripts pipe their answers in advance.
+        let typed_ahead_is_stale = std::io::stdin().is_tty();
+        // Requests received while a prompt was pending.
+        let mut pending: VecDeque<UserRequest> = VecDeque::new();
+        // Decisions of this session which can be undone, see `run_session_command`.
+        let mut decisions: Vec<(u64, PathBuf, String)> = Vec::new();
+        'ui: loop {
+            let message = match pending.pop_front() {
+                Some(message) => message,
+                // Commands can be typed while no prompt is pending.
+                None => loop {
+                    match recv.recv_timeout(CANCELLATION_POLL_INTERVAL) {
+                        Ok(message) => break message,
+                        Err(RecvTimeoutError::Disconnected) => break 'ui,
+                        Err(RecvTimeoutError::Timeout) => {}
+                    }
+                    if !typed_ahead_is_stale {
+                        continue;
                     }
-                    UserRequest::InteractiveSearch(candidates, suggested) => {
-                        if automatic {
-                            reply_fs
-                                .send(FsEventMessage::PackageSuggestion(suggested))
-                                .expect("Failed to send message to FS thread");
-                            continue;
+                    while let Ok(line) = answers.try_recv() {
+                        if line.trim().starts_with(':') {
+                            run_session_command(&line, &mut decisions, &[], &reply_fs);
+                        } else if !line.trim().is_empty() {
+                            warn!("No prompt is pending, use `:decisions` or `:undo N`");
                         }
+                    }
+                },
+            };
 
-                        let choices: Vec<String> = candidates.iter().map(|(c, _)| c.origin().as_ref().clone().attr).collect();
-                        let potential_index = prompt_among_choices(
-                            "A dependency not found in your search paths was requested, pick a choice",
-                            choices
-                        );
+            if let Some(entry) = audit.update(&message) {
+                info!("Automatically decided {}", entry);
+            }
+            let choices: Vec<String> = match &message {
+                UserRequest::Quit => {
+                    break;
+                }
+                UserRequest::Cancel(request_id) => {
+                    debug!("request {} was cancelled before being prompted", request_id);
+                    continue;
+                }
+                UserRequest::Decided(request_id, requested_path, description) => {
+                    decisions.push((*request_id, requested_path.clone(), description.clone()));
+                    continue;
+                }
+                // Its prompt was answered meanwhile.
+                UserRequest::Explanation(..) => continue,
+                UserRequest::Realizing(progress) => {
+                    match &progress.finished {
+                        Some((store_path, true)) => info!("Realized {} ({})", store_path, progress),
+                        Some((store_path, false)) => warn!("Failed to realize {} ({})", store_path, progress),
+                        None => debug!("{}", progress),
+                    }
+                    continue;
+                }
+                request if answer_automatically(&reply_fs, request, automatic.as_ref(), &mut audit) => continue,
+                UserRequest::InteractiveSearch(_, candidates, rankings, _, _, fuzzy, ..) => {
+                    info!("Computing the closure size of {} candidates", candidates.len());
+                    candidates
+                        .iter()
+                        .zip(rankings)
+                        .zip(closure_sizes(candidates))
+                        .map(|(((c, entry), ranking), closure_size)| {
+                            // Outputs of the same package are distinct choices.
+                            let label = outputs::label(&c.origin());
+                            let closure_size = closure_size.map_or("?".to_string(), human_size);
+                            if *fuzzy {
+                                format!(
+                                    "{} [{}] ({}, fuzzy: {})",
+                                    label,
+                                    closure_size,
+                                    ranking,
+                                    String::from_utf8_lossy(&entry.path)
+                                )
+                            } else {
+                                format!("{} [{}] ({})", label, closure_size, ranking)
+                            }
+                        })
+                        .collect()
+                }
+                // Asked even in automatic mode, that is the point.
+                UserRequest::Reconfirm(_, _, description, _) => vec![description.clone()],
+            };
+            let request_id = message.prompt_id().expect("Only prompts are left");
+            let mut prompt = prompt_title(&message);
+            if let UserRequest::InteractiveSearch(.., Some(subtree), _) = &message {
+                prompt.push_str(&format!(
+                    ", `N*` provides everything under {}/ from it",
+                    subtree.display()
+                ));
+            }
+            let queued = pending.iter().filter(|request| request.prompt_id().is_some()).count();
+            if queued > 0 {
+                prompt.push_str(&format!(" ({} more pending, `:queue` lists them, `:next` answers them first)", queued));
+            }
+            if answer_hints {
+                prompt.push_str(&answer_hint(&message, choices.len()));
+            }
 
-                        match potential_index {
-                            Some(index) => reply_fs.send(FsEventMessage::PackageSuggestion(candidates[index].clone())),
-                            None => reply_fs.send(FsEventMessage::IgnorePendingRequests),
+            let answer = prompt_among_choices(
+                &prompt,
+                choices,
+                matches!(message, UserRequest::InteractiveSearch(..)),
+                answers,
+                typed_ahead_is_stale,
+                prompt_timeout,
+                || poll_cancellation(&recv, &mut pending, request_id),
+            );
+            if let PromptAnswer::Command(line) = &answer {
+                // Decisions made while the prompt was shown can be undone too.
+                pending.retain(|request| match request {
+                    UserRequest::Decided(request_id, requested_path, description) => {
+                        if let Some(entry) = audit.update(request) {
+                            info!("Automatically decided {}", entry);
                         }
-                        .expect("Failed to send message to FS thread");
-
-                        // list all the candidates with numbers
-                        // provide ENOENT option
-
-                        // ENOENT
+                        decisions.push((*request_id, requested_path.clone(), description.clone()));
+                        false
+                    }
+                    _ => true,
+                });
+                let queued: Vec<&Path> = pending.iter().filter_map(UserRequest::requested_path).collect();
+                match line.as_str() {
+                    ":queue" => {
+                        info!("> {}", message.requested_path().unwrap_or(Path::new("")).display());
+                        for requested_path in queued {
+                            info!("  {}", requested_path.display());
+                        }
+                        pending.push_front(message);
+                    }
+                    // Answered once the other pending prompts are.
+                    ":next" if !queued.is_empty() => pending.push_back(message),
+                    ":next" => {
+                        warn!("No other prompt is pending");
+                        pending.push_front(message);
+                    }
+                    ":explain" => {
+                        reply_fs
+                            .send(FsEventMessage::Explain(request_id))
+                            .expect("Failed to send message to FS thread");
+                        if wait_for_explanation(&recv, &mut pending, request_id) {
+                            pending.push_front(message);
+                        }
+                    }
+                    _ => {
+                        let candidates = match &message {
+                            UserRequest::InteractiveSearch(_, candidates, ..) => candidates.as_slice(),
+                            _ => &[],
+                        };
+                        run_session_command(line, &mut decisions, candidates, &reply_fs);
+                        pending.push_front(message);
                     }
                 }
+                continue;
+            }
+            let searched = matches!(answer, PromptAnswer::Search(_));
+            send_answer(&reply_fs, &message, answer, prompt_timeout, timeout_decision);
+            if searched {
+                wait_for_search_results(&recv, &mut pending, request_id);
             }
         }
     });
 
     (join_handle, send)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stale_answers_do_not_answer_the_prompt() {
+        let (send, answers) = channel();
+        // Typed ahead, e.g. while a realization was running.
+        send.send("1".to_string()).unwrap();
+        send.send("later".to_string()).unwrap();
+
+        // Polled once the prompt waits for an answer, the stale ones are discarded by then.
+        let (send_waiting, waiting) = channel();
+        let answer = thread::spawn(move || {
+            let answer = prompt_among_choices(
+                "Which one?",
+                vec!["zlib.dev".to_string(), "zlib-ng.dev".to_string()],
+                false,
+                &answers,
+                true,
+                Some(Duration::from_secs(10)),
+                || {
+                    let _ = send_waiting.send(());
+                    false
+                },
+            );
+            (answer, answers)
+        });
+        waiting.recv().unwrap();
+        send.send("2".to_string()).unwrap();
+        let (answer, answers) = answer.join().unwrap();
+
+        assert!(matches!(answer, PromptAnswer::Choice(1)));
+        assert_eq!(discard_stale_answers(&answers), 0);
+    }
+
+    #[test]
+    fn test_pasted_control_sequences_are_stripped() {
+        assert_eq!(strip_control_sequences("\x1b[200~2\x1b[201~"), "2");
+        assert_eq!(strip_control_sequences("zlib\x1b[D\x1b[1;5Cng\t"), "zlibng");
+        assert_eq!(strip_control_sequences("?zlib [dev]"), "?zlib [dev]");
+    }
+
+    /// Answer a prompt among two choices as a script piping its answers would, in advance.
+    fn answer_plain_prompt(line: &str) -> PromptAnswer {
+        let (send, answers) = channel();
+        send.send(format!("{}\n", line)).unwrap();
+
+        prompt_among_choices(
+            "Which one?",
+            vec!["zlib.dev".to_string(), "zlib-ng.dev".to_string()],
+            true,
+            &answers,
+            false,
+            Some(Duration::from_secs(10)),
+            || false,
+        )
+    }
+
+    #[test]
+    fn test_plain_prompts_tell_how_they_are_answered() {
+        let entry = FileTreeEntry {
+            path: b"/include/boost/version.hpp".to_vec(),
+            node: crate::cache::FileNode::Regular {
+                size: 42,
+                executable: false,
+            },
+        };
+        let origin = crate::cache::PathOrigin {
+            attr: "boost".into(),
+            output: "dev".into(),
+            toplevel: true,
+            system: None,
+            index: None,
+        };
+        let store_path =
+            StorePath::parse(origin, "/nix/store/3c4d9ykb8ya8d2ysx6x7i5hmd3j9anv7-boost-1.81.0-dev").unwrap();
+        let request = UserRequest::InteractiveSearch(
+            1,
+            vec![(store_path.clone(), entry.clone())],
+            Vec::new(),
+            (store_path, entry),
+            ProcessInfo::from_pid(std::process::id()),
+            false,
+            false,
+            Some(PathBuf::from("include/boost")),
+            PathBuf::from("include/boost/version.hpp"),
+        );
+        let prompt = format!("{}{}", prompt_title(&request), answer_hint(&request, 2));
+        assert!(prompt.starts_with("include/boost/version.hpp, not found in your search paths"));
+        assert!(prompt.contains("from 1 to 2, followed by a star for the whole directory"));
+        // Screen readers and braille terminals get no control sequence.
+        assert!(!prompt.chars().any(char::is_control));
+
+        // Every answer the hint tells about is understood.
+        assert!(matches!(answer_plain_prompt("2"), PromptAnswer::Choice(1)));
+        assert!(matches!(answer_plain_prompt("2*"), PromptAnswer::SubtreeChoice(1)));
+        assert!(matches!(answer_plain_prompt("n"), PromptAnswer::Skip));
+        assert!(matches!(answer_plain_prompt("later"), PromptAnswer::SkipForNow));
+        assert!(matches!(answer_plain_prompt(":explain"), PromptAnswer::Command(command) if command == ":explain"));
+        assert!(matches!(answer_plain_prompt("?libboost"), PromptAnswer::Search(query) if query == "libboost"));
+    }
+}
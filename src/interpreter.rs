@@ -0,0 +1,180 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Bytes of a script the kernel reads to find its shebang, see `BINPRM_BUF_SIZE`.
+const SHEBANG_MAX_LEN: u64 = 256;
+
+lazy_static! {
+    /// Interpreters commonly found in shebangs, served under `bin/` without prompting.
+    static ref INTERPRETER: Regex = Regex::new(
+        r"^bin/(env|sh|bash|dash|zsh|python[0-9.]*|perl[0-9.]*|ruby|node|lua[0-9.]*|tclsh[0-9.]*)$"
+    )
+    .unwrap();
+}
+
+/// Whether this requested path (relative to the mountpoint) is an interpreter shim.
+pub fn is_interpreter_shim(requested_path: &Path) -> bool {
+    INTERPRETER.is_match(&requested_path.to_string_lossy())
+}
+
+/// Reads `#!interpreter args` out of a script.
+/// Like the kernel, only its first bytes are read: a longer shebang line is ignored.
+fn read_shebang(script: &Path) -> Option<(String, Vec<String>)> {
+    let mut head = Vec::new();
+    File::open(script)
+        .ok()?
+        .take(SHEBANG_MAX_LEN)
+        .read_to_end(&mut head)
+        .ok()?;
+    let line = match head.iter().position(|b| *b == b'\n') {
+        Some(end) => &head[..end],
+        None if (head.len() as u64) < SHEBANG_MAX_LEN => &head[..],
+        None => return None,
+    };
+
+    let mut words = std::str::from_utf8(line)
+        .ok()?
+        .strip_prefix("#!")?
+        .split_whitespace();
+    let interpreter = words.next()?.to_string();
+
+    Some((interpreter, words.map(|w| w.to_string()).collect()))
+}
+
+/// Looks `cmd` up like `execvp` would, in the `PATH` given.
+fn find_in_path(cmd: &str, path: Option<&str>) -> Option<PathBuf> {
+    if cmd.contains('/') {
+        return Some(PathBuf::from(cmd));
+    }
+
+    path?
+        .split(':')
+        .map(|dir| Path::new(dir).join(cmd))
+        .find(|candidate| candidate.is_file())
+}
+
+/// If `cmd` is a script whose interpreter does not exist on this system,
+/// e.g. `#!/usr/bin/env python3` in a Nix sandbox or `#!/usr/bin/python3` on NixOS,
+/// returns the invocation running it through the shim served in `mountpoint`.
+pub fn shim_invocation(
+    cmd: &str,
+    args: &[String],
+    path: Option<&str>,
+    mountpoint: &Path,
+) -> Option<(String, Vec<String>)> {
+    let script = find_in_path(cmd, path)?;
+    let (interpreter, mut interpreter_args) = read_shebang(&script)?;
+
+    if Path::new(&interpreter).exists() {
+        return None;
+    }
+
+    let name = Path::new(&interpreter).file_name()?.to_string_lossy().to_string();
+    let shim = if name == "env" && !interpreter_args.is_empty() {
+        // `env` only looks up the program in PATH, which already contains our `bin/`.
+        mountpoint.join("bin").join(interpreter_args.remove(0))
+    } else {
+        mountpoint.join("bin").join(name)
+    };
+
+    interpreter_args.push(script.to_string_lossy().to_string());
+    interpreter_args.extend(args.iter().cloned());
+
+    Some((shim.to_string_lossy().to_string(), interpreter_args))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script(dir: &Path, name: &str, contents: &str) -> PathBuf {
+        let script = dir.join(name);
+        std::fs::write(&script, contents).unwrap();
+        script
+    }
+
+    #[test]
+    fn test_read_shebang() {
+        let dir = tempfile::tempdir().unwrap();
+
+        assert_eq!(
+            read_shebang(&script(
+                dir.path(),
+                "env",
+                "#!/usr/bin/env python3\nprint()\n"
+            )),
+            Some(("/usr/bin/env".into(), vec!["python3".into()]))
+        );
+        assert_eq!(
+            read_shebang(&script(dir.path(), "args", "#! /usr/bin/perl -w -T\n")),
+            Some(("/usr/bin/perl".into(), vec!["-w".into(), "-T".into()]))
+        );
+        assert_eq!(
+            read_shebang(&script(dir.path(), "no-newline", "#!/bin/sh")),
+            Some(("/bin/sh".into(), vec![]))
+        );
+        assert_eq!(
+            read_shebang(&script(dir.path(), "missing", "print()\n#!/bin/sh\n")),
+            None
+        );
+        assert_eq!(read_shebang(&script(dir.path(), "empty", "")), None);
+        let too_long = format!("#!/bin/sh {}\n", "-x ".repeat(100));
+        assert_eq!(
+            read_shebang(&script(dir.path(), "too-long", &too_long)),
+            None
+        );
+        assert_eq!(read_shebang(&dir.path().join("absent")), None);
+    }
+
+    #[test]
+    fn test_shim_invocation() {
+        let dir = tempfile::tempdir().unwrap();
+        let mountpoint = Path::new("/tmp/buildxyz");
+        let path = dir.path().to_string_lossy().to_string();
+        script(
+            dir.path(),
+            "configure-env",
+            "#!/nonexistent/bin/env python3 -u\n",
+        );
+        script(dir.path(), "configure-perl", "#!/nonexistent/bin/perl -w\n");
+        script(dir.path(), "configure-sh", "#!/bin/sh\n");
+        let args = vec!["--prefix=/usr".to_string()];
+        let script_path = |name: &str| dir.path().join(name).to_string_lossy().to_string();
+
+        // `env` runs the interpreter it is given.
+        assert_eq!(
+            shim_invocation("configure-env", &args, Some(&path), mountpoint),
+            Some((
+                "/tmp/buildxyz/bin/python3".into(),
+                vec![
+                    "-u".into(),
+                    script_path("configure-env"),
+                    "--prefix=/usr".into()
+                ]
+            ))
+        );
+        assert_eq!(
+            shim_invocation(&script_path("configure-perl"), &args, None, mountpoint),
+            Some((
+                "/tmp/buildxyz/bin/perl".into(),
+                vec![
+                    "-w".into(),
+                    script_path("configure-perl"),
+                    "--prefix=/usr".into()
+                ]
+            ))
+        );
+        // Existing interpreters are run as is.
+        assert_eq!(
+            shim_invocation("configure-sh", &args, Some(&path), mountpoint),
+            None
+        );
+        assert_eq!(
+            shim_invocation("configure-env", &args, None, mountpoint),
+            None
+        );
+    }
+}
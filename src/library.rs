@@ -0,0 +1,164 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::path::{Path, PathBuf};
+
+/// Debian-style multiarch triplets found under `lib/`.
+pub const MULTIARCH_TRIPLETS: &[&str] = &[
+    "x86_64-linux-gnu",
+    "i386-linux-gnu",
+    "aarch64-linux-gnu",
+    "arm-linux-gnueabihf",
+    "powerpc64le-linux-gnu",
+    "riscv64-linux-gnu",
+    "s390x-linux-gnu",
+];
+
+/// Fedora-style multilib directories living next to `lib/`.
+pub const MULTILIB_DIRS: &[&str] = &["lib64", "lib32"];
+
+lazy_static! {
+    static ref SHARED_LIBRARY: Regex = Regex::new(r"^(.*\.so)((?:\.[0-9]+)*)$").unwrap();
+}
+
+/// All multilib and multiarch library directories, relative to the mountpoint.
+pub fn multilib_dirs() -> Vec<String> {
+    MULTILIB_DIRS
+        .iter()
+        .map(|dir| dir.to_string())
+        .chain(
+            MULTIARCH_TRIPLETS
+                .iter()
+                .map(|triplet| format!("lib/{}", triplet)),
+        )
+        .collect()
+}
+
+/// Maps multilib and multiarch library directories onto `lib/`,
+/// the only library directory in Nix store paths,
+/// e.g. `lib64/libz.so` and `lib/x86_64-linux-gnu/libz.so` become `lib/libz.so`.
+pub fn normalize_multilib_path(path: &Path) -> PathBuf {
+    for dir in MULTILIB_DIRS {
+        if let Ok(rest) = path.strip_prefix(dir) {
+            return Path::new("lib").join(rest);
+        }
+    }
+
+    if let Ok(rest) = path.strip_prefix("lib") {
+        for triplet in MULTIARCH_TRIPLETS {
+            if let Ok(rest) = rest.strip_prefix(triplet) {
+                return Path::new("lib").join(rest);
+            }
+        }
+    }
+
+    path.to_path_buf()
+}
+
+/// Splits `lib/libfoo.so.1.2.3` into `("lib/libfoo.so", ["1", "2", "3"])`.
+/// Returns `None` if the path does not look like a shared library.
+pub fn split_shared_library(path: &str) -> Option<(&str, Vec<&str>)> {
+    let captures = SHARED_LIBRARY.captures(path)?;
+    let stem = captures.get(1)?.as_str();
+    let versions = captures
+        .get(2)
+        .map(|v| v.as_str())
+        .unwrap_or("")
+        .split('.')
+        .filter(|v| !v.is_empty())
+        .collect();
+
+    Some((stem, versions))
+}
+
+/// Regex matching any version of the shared library requested,
+/// e.g. `lib/libfoo.so`, `lib/libfoo.so.1` and `lib/libfoo.so.1.2.3` for `lib/libfoo.so.1`.
+pub fn any_version_pattern(requested_path: &str) -> Option<String> {
+    split_shared_library(requested_path)
+        .map(|(stem, _)| format!(r"^/{}(\.[0-9]+)*$", regex::escape(stem)))
+}
+
+/// Ranks a candidate path for a requested shared library, lower is better:
+/// exact matches first, then more specific versions of the requested SONAME
+/// (e.g. `libfoo.so.1.2.3` for `libfoo.so.1`), then less specific ones, then anything else.
+/// Ties are broken by preferring the closest version.
+pub fn match_rank(requested_path: &str, candidate_path: &str) -> (u8, usize) {
+    let requested_path = requested_path.trim_start_matches('/');
+    let candidate_path = candidate_path.trim_start_matches('/');
+
+    if requested_path == candidate_path {
+        return (0, 0);
+    }
+
+    match (
+        split_shared_library(requested_path),
+        split_shared_library(candidate_path),
+    ) {
+        (Some((req_stem, req_versions)), Some((cand_stem, cand_versions)))
+            if req_stem == cand_stem =>
+        {
+            if cand_versions.starts_with(&req_versions) {
+                (1, cand_versions.len() - req_versions.len())
+            } else if req_versions.starts_with(&cand_versions) {
+                (2, req_versions.len() - cand_versions.len())
+            } else {
+                (3, 0)
+            }
+        }
+        _ => (3, 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_multilib_paths_are_served_from_lib() {
+        for (requested, normalized) in [
+            ("lib64/libz.so.1", "lib/libz.so.1"),
+            ("lib32/libz.so", "lib/libz.so"),
+            ("lib/x86_64-linux-gnu/libz.so", "lib/libz.so"),
+            (
+                "lib/aarch64-linux-gnu/pkgconfig/zlib.pc",
+                "lib/pkgconfig/zlib.pc",
+            ),
+            ("lib64", "lib"),
+            ("lib/libz.so", "lib/libz.so"),
+            (
+                "lib/x86_64-linux-musl/libz.so",
+                "lib/x86_64-linux-musl/libz.so",
+            ),
+            ("lib64x/libz.so", "lib64x/libz.so"),
+            ("include/zlib.h", "include/zlib.h"),
+        ] {
+            assert_eq!(
+                normalize_multilib_path(Path::new(requested)),
+                Path::new(normalized),
+                "{}",
+                requested
+            );
+        }
+        assert!(multilib_dirs().contains(&"lib/x86_64-linux-gnu".to_string()));
+    }
+
+    #[test]
+    fn test_match_rank_prefers_closest_soname() {
+        let mut candidates = vec![
+            "/lib/libfoo.so.2",
+            "/lib/libfoo.so.1.2.3",
+            "/lib/libfoo.so.1",
+            "/lib/libfoo.so",
+        ];
+        candidates.sort_by_key(|c| match_rank("lib/libfoo.so.1", c));
+
+        assert_eq!(
+            candidates,
+            vec![
+                "/lib/libfoo.so.1",
+                "/lib/libfoo.so.1.2.3",
+                "/lib/libfoo.so",
+                "/lib/libfoo.so.2"
+            ]
+        );
+    }
+}
@@ -1,34 +1,87 @@
 use ::nix::sys::signal::Signal::{SIGINT, SIGKILL, SIGTERM};
 use ::nix::unistd::Pid;
-use cache::database::read_raw_buffer;
 use clap::Parser;
-use fuser::spawn_mount2;
+use crossterm::tty::IsTty;
+use fuser::{spawn_mount2, MountOption};
 use lazy_static::lazy_static;
-use log::{debug, info, warn};
+use log::{debug, error, info, warn};
+use lru::LruCache;
+use std::collections::HashMap;
 use std::io;
 use std::iter;
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
 use std::os::unix::ffi::OsStringExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
-use std::sync::mpsc::channel;
-use std::sync::Arc;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 use include_dir::{include_dir, Dir};
 
 use crate::cache::StorePath;
-use crate::nix::realize_path;
+use crate::inode::{InodeTable, ROOT_INODE};
 use crate::resolution::{
-    load_resolution_db, merge_resolution_db, read_resolution_db, ResolutionDB, Resolution, Decision,
+    drop_tombstones, load_resolution_db, merge_resolution_db_with, read_resolution_db,
+    with_source, update_resolution_db, ConflictPolicy, Decision, MergeConflicts, ResolutionDB,
 };
 
 // mod instrument;
+mod attrpath;
+mod audit;
+mod blocklist;
+mod budget;
 mod cache;
+mod commands;
+mod compare;
+mod daemon;
+mod diff;
+mod digest;
+mod dispatch;
+mod explain;
+mod export;
+mod fetch;
+mod filesource;
+mod flake;
+mod frontend;
 mod fs;
+mod fuzzy;
+mod import;
+mod include;
+mod index;
+mod inode;
 mod interactive;
+mod interpreter;
+mod library;
+mod metrics;
+mod mount;
+mod namespace;
 mod nix;
+mod nixcli;
+mod nixld;
+mod outputs;
+mod overlay;
+mod packs;
+mod pin;
+mod pkgconfig;
+mod policy;
 mod popcount;
+mod process;
+mod querycache;
+mod ranking;
+mod realize;
+mod remote;
 mod resolution;
+mod rpc;
 mod runner;
+mod signature;
+mod substituters;
+mod trace;
+mod tui;
+mod usage;
+mod vdir;
+mod verify;
 
 pub enum EventMessage {
     Stop,
@@ -41,18 +94,49 @@ pub enum EventMessage {
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    cmd: String,
+    #[command(subcommand)]
+    command: Option<commands::Command>,
+    /// Command to run in the instrumented environment
+    #[arg(required = true)]
+    cmd: Option<String>,
     /// Say yes to everything except if it is recorded as ENOENT.
     #[arg(long = "automatic", default_value_t = false)]
     automatic: bool,
+    /// Accept the top-ranked suggestions like `--automatic`, then review every decision
+    /// once the command exits, before the `--record-to` file is written
+    #[arg(long = "review", default_value_t = false, requires = "resolution_record_filepath")]
+    review: bool,
+    /// Append every automatic decision to this file as JSON lines, while the command runs
+    #[arg(long = "audit-log")]
+    audit_filepath: Option<PathBuf>,
+    /// Only accept the suggestions allowed by this TOML policy automatically, the other ones are
+    /// asked or ignored, e.g. to run unattended in CI, see `policy::AutomaticPolicy`
+    #[arg(long = "automatic-policy")]
+    automatic_policy_filepath: Option<PathBuf>,
     /// No core resolution
     #[arg(long = "naked", default_value_t = false)]
     naked: bool,
+    /// Only load these packs of core resolutions, e.g. `python,autotools`, all of them otherwise
+    #[arg(long, value_delimiter = ',', conflicts_with = "naked")]
+    packs: Option<Vec<String>>,
+    /// List the packs of core resolutions
+    #[arg(long, default_value_t = false)]
+    list_packs: bool,
+    /// Directory of the index written by `buildxyz index build`, searched when it is the most recent one
     #[arg(long = "db", default_value_os = cache::cache_dir())]
     database: PathBuf,
     #[arg(long = "record-to")]
     resolution_record_filepath: Option<PathBuf>,
+    /// Write every decision to the `--record-to` file as it is taken, rather than asking in a terminal
+    /// which ones to write once the command exits
+    #[arg(long = "no-confirm-record", default_value_t = false)]
+    no_confirm_record: bool,
+    /// Once the command exits, write a stub overlay `missing-<name>.nix` under `.buildxyz/overlays/`
+    /// for each package no candidate was found for, with the paths it was requested for
+    #[arg(long = "missing-overlays", value_enum, default_value_t = overlay::MissingOverlays::Ask)]
+    missing_overlays: overlay::MissingOverlays,
     #[arg(long = "resolutions-from")]
     custom_resolutions_filepath: Option<PathBuf>,
     /// In case of failures, retry automatically the invocation
@@ -60,7 +144,330 @@ struct Args {
     retry: bool,
     /// Print ignored paths
     #[arg(long = "print-ignored-paths", default_value_t = false)]
-    print_ignored_paths: bool
+    print_ignored_paths: bool,
+    /// What to do when loaded resolution files take different decisions for the same path
+    #[arg(long = "merge-conflicts", value_enum, default_value_t = ConflictPolicy::Report)]
+    merge_conflicts: ConflictPolicy,
+    /// Ask which resolution to keep for every conflict, the choices are recorded in the `--record-to` file
+    #[arg(long = "interactive-merge", default_value_t = false)]
+    interactive_merge: bool,
+    /// Refuse resolution files recorded against another index instead of warning about them
+    #[arg(long = "strict-pins", default_value_t = false)]
+    strict_pins: bool,
+    /// Refuse resolution files which are not signed by a trusted key, the embedded core ones aside
+    #[arg(long = "require-signed", default_value_t = false)]
+    require_signed: bool,
+    /// minisign public key trusted by `--require-signed`, as a file or base64
+    #[arg(long = "trusted-key")]
+    trusted_keys: Vec<String>,
+    /// What happens to the store paths of loaded resolution files which are not in any store or
+    /// not signed by one of the `trusted-public-keys` of Nix, the embedded core ones aside
+    #[arg(long = "unverified-store-paths", value_enum, default_value_t = verify::UnverifiedPaths::Warn)]
+    unverified_store_paths: verify::UnverifiedPaths,
+    /// Print where every merged resolution comes from and its priority
+    #[arg(long = "print-resolution-sources", default_value_t = false)]
+    print_resolution_sources: bool,
+    /// Seconds to wait for an answer to a prompt before applying the default decision
+    #[arg(long = "prompt-timeout")]
+    prompt_timeout: Option<u64>,
+    /// Decision applied when a prompt times out
+    #[arg(long = "prompt-timeout-default", value_enum, default_value_t = interactive::TimeoutDecision::Ignore)]
+    prompt_timeout_default: interactive::TimeoutDecision,
+    /// How prompts are shown, the TUI when running in a terminal
+    #[arg(long, value_enum)]
+    ui: Option<interactive::Ui>,
+    /// Line by line prompts for screen readers, braille terminals and expect-style automation:
+    /// `--ui plain` without colors nor line editing, and a hint on how to answer each prompt
+    #[arg(long, default_value_t = false, conflicts_with = "ui")]
+    plain: bool,
+    /// Also serve the prompts over HTTP on this address, e.g. `127.0.0.1:8080`, to answer them from a browser or a script.
+    /// Anyone reaching it can answer them, keep it local and forward it over SSH
+    #[arg(long = "remote-listen")]
+    remote_address: Option<SocketAddr>,
+    /// Also serve the prompts over JSON-RPC on this Unix socket, e.g. for editor integrations
+    #[arg(long = "rpc-listen")]
+    rpc_socket: Option<PathBuf>,
+    /// Ignore lookups issued by processes whose name or executable path matches this regex
+    #[arg(long = "ignore-process")]
+    ignored_processes: Vec<String>,
+    /// ENOENT requested paths matching this regex without querying the index
+    #[arg(long = "block-path")]
+    blocked_path_regexes: Vec<String>,
+    /// ENOENT requested paths matching this glob without querying the index
+    #[arg(long = "block-glob")]
+    blocked_path_globs: Vec<String>,
+    /// Do not block `.git/`, `node_modules/`, `__pycache__/` and editor backup files by default
+    #[arg(long = "no-default-blocklist", default_value_t = false)]
+    no_default_blocklist: bool,
+    /// Rewrite provided pkg-config files so that their prefix points to the fast working tree
+    #[arg(long = "rewrite-pkgconfig", default_value_t = false)]
+    rewrite_pkgconfig: bool,
+    /// How provided files are materialized in the fast working tree
+    #[arg(long = "link-mode", value_enum, default_value_t = resolution::LinkMode::Symlink)]
+    link_mode: resolution::LinkMode,
+    /// Seconds the kernel may cache a provided path
+    #[arg(long = "entry-ttl", default_value_t = 60 * 20)]
+    entry_ttl: u64,
+    /// Seconds the kernel may cache an ENOENT, 0 disables negative caching
+    #[arg(long = "negative-ttl", default_value_t = 0)]
+    negative_ttl: u64,
+    /// Disable all kernel caching, e.g. to iterate on resolution files without remounting
+    #[arg(long = "no-kernel-cache", default_value_t = false)]
+    no_kernel_cache: bool,
+    /// Allow other users to access the mount, e.g. builds dropping privileges (needs `user_allow_other` in /etc/fuse.conf)
+    #[arg(long = "allow-other", default_value_t = false, group = "access", conflicts_with = "allow_root")]
+    allow_other: bool,
+    /// Allow root to access the mount
+    #[arg(long = "allow-root", default_value_t = false, group = "access")]
+    allow_root: bool,
+    /// Filesystem name shown in the mount table
+    #[arg(long = "fsname", default_value = "buildxyz")]
+    fsname: String,
+    /// Let the kernel unmount the filesystem if buildxyz crashes (requires --allow-other or --allow-root)
+    #[arg(long = "auto-unmount", default_value_t = false, requires = "access")]
+    auto_unmount: bool,
+    /// Record every lookup, in order and with timestamps, as JSON lines in this file
+    #[arg(long = "trace-to")]
+    trace_filepath: Option<PathBuf>,
+    /// Number of requested paths whose index candidates are kept in memory
+    #[arg(long = "query-cache-size", default_value = "1024")]
+    query_cache_size: NonZeroUsize,
+    /// Serve `bin/env`, `bin/sh`, `bin/python3`, etc. without prompting
+    /// and run the command through them if its shebang interpreter is missing
+    #[arg(long = "interpreter-shims", default_value_t = false)]
+    interpreter_shims: bool,
+    /// Redirect paths missing from the index to `/usr` or `/` on the host, if they exist there
+    #[arg(long = "fallback-host", default_value_t = false)]
+    fallback_host: bool,
+    /// Match requested paths regardless of case, e.g. `Include/Foo.h` for `include/foo.h`.
+    /// Resolutions are recorded with the casing found in the index
+    #[arg(long = "case-insensitive", default_value_t = false)]
+    case_insensitive: bool,
+    /// Do not retry paths without candidates with relaxed patterns (other versions, other directories)
+    #[arg(long = "no-fuzzy-search", default_value_t = false)]
+    no_fuzzy_search: bool,
+    /// Build phase of lookups whose process does not export `BUILDXYZ_PHASE`.
+    /// Decisions taken in a phase are only reused in that phase,
+    /// lower `--entry-ttl` so that the kernel does not keep answers across phases.
+    /// The `phase` method of `--rpc-socket` switches it during the session.
+    #[arg(long = "phase")]
+    phase: Option<String>,
+    /// Serve an independent environment under `<mountpoint>/NAME/`, with its own fast working tree.
+    /// `NAME=FILE` adds the resolutions of FILE on top of the session ones for this namespace only.
+    /// The command runs in the first namespace.
+    #[arg(long = "namespace")]
+    namespaces: Vec<String>,
+    /// Number of threads searching the index and waiting for answers, defaults to the number of CPUs
+    #[arg(long = "lookup-workers")]
+    lookup_workers: Option<usize>,
+    /// Serve Prometheus metrics of the filesystem over HTTP on this address, e.g. `127.0.0.1:9090`
+    #[arg(long = "metrics-listen")]
+    metrics_address: Option<SocketAddr>,
+    /// Write Prometheus metrics of the filesystem to this file on exit
+    #[arg(long = "metrics-to")]
+    metrics_filepath: Option<PathBuf>,
+    /// Do not count how often the loaded resolutions are used, see `buildxyz res gc`
+    #[arg(long = "no-usage-stats", default_value_t = false)]
+    no_usage_stats: bool,
+    /// How to realize store paths and ask about them
+    #[arg(long = "store-backend", value_enum, default_value_t = nix::StoreBackendKind::Daemon)]
+    store_backend: nix::StoreBackendKind,
+    /// Nix store to realize and ask about store paths, e.g. `daemon` or `ssh-ng://builder`, the local one by default.
+    /// Provided paths must be readable where the build runs, those of chroot stores such as `~/.local/nix` are served from there.
+    #[arg(long = "store")]
+    store: Option<String>,
+    /// nixpkgs revision or flake reference to pin the session to, e.g. `nixos-23.11` branches,
+    /// the one the embedded index was built from by default.
+    /// Packages are evaluated from it and searched in an index built for it, recorded in the resolutions.
+    #[arg(long = "nixpkgs")]
+    nixpkgs: Option<String>,
+    /// URL of the index of a `--nixpkgs` revision, `{rev}` is replaced by the revision.
+    /// The index is built with nix-index when there is none, this takes a long while.
+    #[arg(long = "index-url", requires = "nixpkgs")]
+    index_url: Option<String>,
+    /// Search the index embedded in this build even when one was fetched with `buildxyz index fetch`
+    /// or built with `buildxyz index build`
+    #[arg(long = "embedded-index", conflicts_with = "nixpkgs")]
+    embedded_index: bool,
+    /// Warn when the fetched or built index searched is older than this many days, 0 never warns
+    #[arg(long = "index-max-age", default_value_t = index::DEFAULT_MAX_AGE_DAYS)]
+    index_max_age: u64,
+    /// Search this nix-index database too when the ones before have no match, as `NAME=PATH`
+    /// where PATH is a database or a directory with one, e.g. `unstable=~/indexes/unstable`.
+    /// Candidates tell the name of the index they come from.
+    #[arg(long = "index")]
+    indexes: Vec<String>,
+    /// Run the command in the development shell of this flake installable, e.g. `.` or `.#devShells.x86_64-linux.ci`,
+    /// as `nix develop` would: only what the shell lacks is looked up
+    #[arg(long = "develop", conflicts_with = "develop_file")]
+    develop: Option<String>,
+    /// Run the command in the development shell of this Nix file, e.g. `shell.nix`, like `--develop`
+    #[arg(long = "develop-file")]
+    develop_file: Option<PathBuf>,
+    /// Let the built programs find the provided shared libraries when they run in the session, through nix-ld
+    /// (`NIX_LD_LIBRARY_PATH`) or the `buildxyz-run` wrapper
+    #[arg(long = "nix-ld", default_value_t = false)]
+    nix_ld: bool,
+    /// Largest closure of a package provided from a prompt or automatically, e.g. `500 MiB`
+    #[arg(long = "max-closure-size", value_parser = budget::parse_size_arg)]
+    max_closure_size: Option<usize>,
+    /// Largest sum of the closures of the packages provided during the session, e.g. `2 GiB`
+    #[arg(long = "max-session-closure-size", value_parser = budget::parse_size_arg)]
+    max_session_closure_size: Option<usize>,
+    /// What happens to a package exceeding `--max-closure-size` or `--max-session-closure-size`
+    #[arg(long = "over-budget", value_enum, default_value_t = budget::OverBudget::Refuse)]
+    over_budget: budget::OverBudget,
+    /// Do not ask the substituters which candidates they have before prompting,
+    /// candidates which would be built locally are ranked last otherwise
+    #[arg(long = "no-substituter-check", default_value_t = false)]
+    no_substituter_check: bool,
+    /// Ask the binary caches for the files of the resolved store paths missing from the index,
+    /// e.g. recorded at a more recent nixpkgs, and check typed store paths before realizing them
+    #[arg(long = "binary-cache-listings", default_value_t = false)]
+    binary_cache_listings: bool,
+    /// Number of store paths realized at once, e.g. the resolutions loaded at startup
+    #[arg(long = "realize-jobs", default_value_t = realize::DEFAULT_JOBS)]
+    realize_jobs: usize,
+    /// Times a failed substitution is tried again, e.g. during a binary cache outage, waiting longer each time
+    #[arg(long = "realize-retries", default_value_t = realize::DEFAULT_RETRIES)]
+    realize_retries: u32,
+    /// Fail the lookup when a store path cannot be substituted
+    /// rather than building it locally from its nixpkgs attribute, with `--fallback`
+    #[arg(long = "no-build-fallback", default_value_t = false)]
+    no_build_fallback: bool,
+    /// Seconds the validity and closure size of a store path are remembered across prompts and sessions,
+    /// validities are asked again once the store changed anyway, 0 always asks
+    #[arg(long = "path-info-ttl", default_value_t = nix::DEFAULT_PATH_INFO_TTL)]
+    path_info_ttl: u64,
+}
+
+/// What the filesystems of a session share, each namespace gets a clone.
+#[derive(Clone)]
+struct SharedServices {
+    answers: dispatch::AnswerRouter,
+    metrics: Arc<metrics::Metrics>,
+    send_ui_event: Sender<interactive::UserRequest>,
+    realizer: realize::Realizer,
+    substituters: Arc<substituters::SubstituterCheck>,
+    index: filesource::IndexSet,
+    binary_cache: Arc<filesource::BinaryCacheSource>,
+    budget: Arc<budget::ClosureBudget>,
+}
+
+impl Args {
+    /// Whether to ask which decisions go to the record file once the command exits, when someone can answer.
+    fn confirms_record(&self) -> bool {
+        self.resolution_record_filepath.is_some()
+            && !self.automatic
+            && !self.review
+            && !self.no_confirm_record
+            && io::stdin().is_tty()
+    }
+
+    /// Whether to write stub overlays for the missing packages, asking only when someone can answer.
+    fn missing_overlays(&self) -> overlay::MissingOverlays {
+        match self.missing_overlays {
+            overlay::MissingOverlays::Ask if self.automatic || !io::stdin().is_tty() => {
+                overlay::MissingOverlays::Never
+            }
+            missing_overlays => missing_overlays,
+        }
+    }
+
+    /// Filesystem for the whole mount, or for the `index`-th namespace.
+    fn build_fs(
+        &self,
+        resolution_db: ResolutionDB,
+        fast_working_tree: PathBuf,
+        shared: SharedServices,
+        namespace: Option<(usize, &str)>,
+    ) -> fs::BuildXYZ {
+        // Namespaces record to their own files.
+        let per_namespace = |filepath: &PathBuf| match namespace {
+            Some((_, name)) => namespace::namespaced_path(filepath, name),
+            None => filepath.clone(),
+        };
+        let SharedServices {
+            answers,
+            metrics,
+            send_ui_event,
+            realizer,
+            substituters,
+            index,
+            binary_cache,
+            budget,
+        } = shared;
+        let disk_query_cache = Arc::new(querycache::QueryCache::open(&index, self.case_insensitive));
+
+        fs::BuildXYZ {
+            undo_requests: Some(answers.undo_requests()),
+            phase_requests: Some(answers.phase_requests()),
+            defer_record: self.review || self.confirms_record(),
+            answers,
+            metrics,
+            send_ui_event,
+            realizer,
+            substituters,
+            index,
+            binary_cache,
+            budget,
+            resolution_record_filepath: self.resolution_record_filepath.as_ref().map(per_namespace),
+            provenance: resolution::Provenance::now(self.cmd.clone(), None),
+            usage_stats_filepath: (!self.no_usage_stats).then(usage::default_stats_filepath),
+            resolution_db,
+            inodes: match namespace {
+                Some((index, _)) => InodeTable::with_root_under(namespace::namespace_root(index), ROOT_INODE),
+                None => Default::default(),
+            },
+            fast_working_tree,
+            rewrite_pkgconfig: self.rewrite_pkgconfig,
+            link_mode: self.link_mode,
+            interpreter_shims: self.interpreter_shims,
+            fallback_host: self.fallback_host,
+            case_insensitive: self.case_insensitive,
+            fuzzy_search: !self.no_fuzzy_search,
+            phase: self.phase.clone(),
+            query_cache: Arc::new(Mutex::new(LruCache::new(self.query_cache_size))),
+            disk_query_cache,
+            access_trace: self.trace_filepath.as_ref().map(per_namespace).map(|filepath| {
+                trace::AccessTrace::create(&filepath).expect("Failed to create the access trace file")
+            }),
+            ttls: if self.no_kernel_cache {
+                fs::CacheTtls::disabled()
+            } else {
+                fs::CacheTtls {
+                    entry: Duration::from_secs(self.entry_ttl),
+                    negative: Duration::from_secs(self.negative_ttl),
+                    ..Default::default()
+                }
+            },
+            ignored_processes: process::ProcessFilter::new(&self.ignored_processes)
+                .expect("Failed to compile the process filters"),
+            blocklist: blocklist::PathBlocklist::new(
+                &self.blocked_path_regexes,
+                &self.blocked_path_globs,
+                !self.no_default_blocklist,
+            )
+            .expect("Failed to compile the path blocklist"),
+            ..Default::default()
+        }
+    }
+
+    fn mount_options(&self) -> Vec<MountOption> {
+        let mut options = vec![MountOption::FSName(self.fsname.clone())];
+
+        if self.allow_other {
+            options.push(MountOption::AllowOther);
+        }
+        if self.allow_root {
+            options.push(MountOption::AllowRoot);
+        }
+        if self.auto_unmount {
+            options.push(MountOption::AutoUnmount);
+        }
+
+        options
+    }
 }
 
 fn get_git_root() -> Option<std::path::PathBuf> {
@@ -71,8 +478,13 @@ fn get_git_root() -> Option<std::path::PathBuf> {
         .ok()?;
 
     if output.status.success() {
+        let mut stdout = output.stdout;
+        // Without the trailing newline.
+        if stdout.last() == Some(&b'\n') {
+            stdout.pop();
+        }
         Some(
-            std::ffi::OsString::from_vec(output.stdout)
+            std::ffi::OsString::from_vec(stdout)
                 .as_os_str()
                 .into(),
         )
@@ -81,6 +493,45 @@ fn get_git_root() -> Option<std::path::PathBuf> {
     }
 }
 
+/// `.buildxyz` directory of the project, at the Git root or in the current directory outside of Git.
+fn project_dir() -> PathBuf {
+    get_git_root()
+        .or_else(|| std::env::current_dir().ok())
+        .unwrap_or_default()
+        .join(".buildxyz")
+}
+
+/// Record the resolutions picked to solve merge conflicts in the `--record-to` file,
+/// the merged files are not: they are read again next time.
+fn record_merge_choices(filepath: Option<PathBuf>, conflicts: &mut MergeConflicts) {
+    let answered = std::mem::take(&mut conflicts.answered);
+    if answered.is_empty() {
+        return;
+    }
+    match filepath {
+        Some(filepath) => update_resolution_db(&filepath, Some(&pin::current_pin()), |recorded| {
+            recorded.extend(answered);
+            Ok(())
+        })
+        .expect("Failed to write the merge choices"),
+        None => warn!("The merge choices are not kept without `--record-to`"),
+    }
+}
+
+/// Refuse to run with the conflicts the `error` policy collected.
+fn exit_on_unresolved_conflicts(conflicts: &MergeConflicts) {
+    if conflicts.unresolved.is_empty() {
+        return;
+    }
+    for conflict in &conflicts.unresolved {
+        error!("{}", conflict);
+    }
+    error!(
+        "{} conflicting resolutions, set a `priority` or pass `--merge-conflicts report`",
+        conflicts.unresolved.len()
+    );
+    std::process::exit(1);
+}
 
 static CORE_RESOLUTIONS: Dir = include_dir!("$BUILDXYZ_CORE_RESOLUTIONS");
 lazy_static! {
@@ -108,19 +559,126 @@ lazy_static! {
 
 fn main() -> Result<(), io::Error> {
     let args = Args::parse();
+    let ui = if args.plain {
+        interactive::Ui::Plain
+    } else {
+        args.ui.unwrap_or_else(interactive::Ui::detect)
+    };
 
-    stderrlog::new()
-        //.module(module_path!())
-        .verbosity(4)
-        .init()
-        .unwrap();
+    if args.command.is_none() && ui == interactive::Ui::Tui {
+        tui::init_logger();
+    } else {
+        stderrlog::new()
+            //.module(module_path!())
+            .verbosity(4)
+            .color(if args.plain {
+                stderrlog::ColorChoice::Never
+            } else {
+                stderrlog::ColorChoice::Auto
+            })
+            .init()
+            .unwrap();
+    }
+    if args.plain {
+        interactive::disable_line_editing();
+    }
+
+    nix::set_store_backend(args.store_backend, args.store.as_deref());
+    nix::set_path_info_ttl(Duration::from_secs(args.path_info_ttl));
+
+    if let Some(command) = &args.command {
+        return commands::run(command);
+    }
+    if let Err(err) = nixcli::cli().check() {
+        error!("{}", err);
+        std::process::exit(1);
+    }
+    if let Some(nixpkgs) = &args.nixpkgs {
+        pin::pin_nixpkgs(nixpkgs, args.index_url.as_deref()).expect("Failed to pin nixpkgs");
+        info!("Pinned to {}", pin::current_pin());
+    } else if !args.embedded_index {
+        if let Some((metadata, data)) = index::latest_index(&index::current_system(), &args.database) {
+            match pin::pin_index(metadata.nixpkgs_rev.clone(), &data) {
+                Ok(()) => {
+                    info!(
+                        "Searching the index of {} for {} from {}",
+                        metadata.channel, metadata.system, metadata.url
+                    );
+                    index::check_freshness(&metadata, args.index_max_age, SystemTime::now());
+                }
+                Err(err) => warn!(
+                    "Failed to read the index, searching the embedded one: {}",
+                    err
+                ),
+            }
+        }
+    }
+    let cmd = args.cmd.clone().unwrap_or_default();
+    let shell_source = match (&args.develop, &args.develop_file) {
+        (Some(installable), _) => Some(nix::ShellSource::Flake(installable)),
+        (None, Some(filepath)) => Some(nix::ShellSource::File(filepath)),
+        (None, None) => None,
+    };
+    let mut env: HashMap<String, String> = match &shell_source {
+        Some(source) => {
+            info!("Entering the development shell, building its inputs if needed");
+            let dev_env = nix::dev_env(source).unwrap_or_else(|err| {
+                error!("Failed to enter the development shell: {}", err);
+                std::process::exit(1);
+            });
+            runner::layer_dev_env(std::env::vars().collect(), dev_env)
+        }
+        None => std::env::vars().collect(),
+    };
 
     // Signal to stop the current program
     // If sent twice, uses SIGKILL
     let (send_event, recv_event) = channel::<EventMessage>();
     let (send_fs_event, recv_fs_event) = channel();
-    let (ui_join_handle, send_ui_event) =
-        interactive::spawn_ui(send_fs_event.clone(), args.automatic);
+    // Build output goes to the log pane of the TUI.
+    let (send_build_output, recv_build_output) = channel();
+    let automatic = (args.automatic || args.review).then(|| {
+        args.automatic_policy_filepath
+            .as_deref()
+            .map(|filepath| {
+                policy::AutomaticPolicy::load(filepath).expect("Failed to load the automatic policy")
+            })
+            .unwrap_or_default()
+    });
+    let audit = match &args.audit_filepath {
+        Some(filepath) => audit::AuditTrail::open(filepath).expect("Failed to open the audit log"),
+        None => audit::AuditTrail::default(),
+    };
+    let mut frontends: Vec<Box<dyn frontend::Frontend>> = vec![match ui {
+        interactive::Ui::Tui => Box::new(tui::TuiFrontend {
+            send_to_main: send_event.clone(),
+            log_pane: (send_build_output.clone(), recv_build_output),
+            automatic,
+            audit,
+            prompt_timeout: args.prompt_timeout.map(Duration::from_secs),
+            timeout_decision: args.prompt_timeout_default,
+        }),
+        interactive::Ui::Plain => Box::new(interactive::PlainFrontend {
+            answer_hints: args.plain,
+            automatic,
+            audit,
+            prompt_timeout: args.prompt_timeout.map(Duration::from_secs),
+            timeout_decision: args.prompt_timeout_default,
+        }),
+    }];
+    if let Some(address) = args.remote_address {
+        let token =
+            remote::random_token().expect("Failed to generate the token of the remote prompts");
+        let remote = remote::RemoteFrontend { address, token };
+        info!("Prompts can be answered on {}", remote.url());
+        frontends.push(Box::new(remote));
+    }
+    if let Some(socket_path) = &args.rpc_socket {
+        frontends.push(Box::new(rpc::RpcFrontend { socket_path: socket_path.clone() }));
+        info!("Prompts can be answered over JSON-RPC on {}", socket_path.display());
+    }
+    let (ui_join_handles, send_ui_event) =
+        frontend::multiplex(frontends, send_fs_event.clone()).expect("Failed to start the UI frontends");
     let mut stop_count = 0;
 
     let ctrlc_event = send_event.clone();
@@ -134,52 +692,141 @@ fn main() -> Result<(), io::Error> {
     .expect("Failed to set Ctrl-C handler");
     // FIXME: register SIGTERM too.
 
+    mount::cleanup_stale_mounts(&args.fsname);
+
     info!("Mounting the FUSE filesystem in the background...");
 
     let fuse_tmpdir = tempfile::tempdir().expect("Failed to create a temporary directory for the FUSE mountpoint");
     let fast_tmpdir = tempfile::tempdir().expect("Failed to create a temporary directory for the fast working tree");
 
+    let mut conflicts = MergeConflicts::new(if args.interactive_merge {
+        ConflictPolicy::Interactive
+    } else {
+        args.merge_conflicts
+    });
+
+    let mut pins = pin::PinCheck::new(args.strict_pins);
+    if args.require_signed && args.trusted_keys.is_empty() {
+        error!("`--require-signed` needs at least one `--trusted-key`");
+        std::process::exit(1);
+    }
+    let signatures = signature::SignatureCheck::new(if args.require_signed {
+        args.trusted_keys.clone()
+    } else {
+        Vec::new()
+    });
+
     // Load all resolution databases in memory.
     // Reduce them by merging them in the provided priority order.
     // Load *core* resolutions first
+    let packs_manifest = packs::read_manifest(
+        CORE_RESOLUTIONS
+            .get_file(packs::MANIFEST_FILENAME)
+            .and_then(|file| file.contents_utf8())
+            .expect("Failed to find the manifest of the core resolution packs inside the binary, corrupted binary?"),
+    );
+    if args.list_packs {
+        println!("List of core resolution packs:");
+        for (name, pack) in &packs_manifest {
+            println!("\t{}: {}", name, pack.description);
+        }
+
+        return Ok(());
+    }
+    if let Some(selected) = &args.packs {
+        let unknown = packs::unknown_packs(&packs_manifest, selected);
+        if !unknown.is_empty() {
+            error!(
+                "Unknown core resolution packs: {}, see `--list-packs`",
+                unknown.join(", ")
+            );
+            std::process::exit(1);
+        }
+    }
+
     let core_resolution_db = if !args.naked { CORE_RESOLUTIONS.find("**/*.toml").unwrap()
         .into_iter()
+        .filter(|entry| entry.path() != Path::new(packs::MANIFEST_FILENAME))
+        .filter(|entry| match &args.packs {
+            Some(selected) => packs::pack_of(&packs_manifest, entry.path())
+                .map_or(false, |pack| selected.iter().any(|name| name == pack)),
+            None => true,
+        })
         .map(|entry| CORE_RESOLUTIONS.get_file(entry.path()).expect("Failed to find a core resolution file inside the binary, corrupted binary?"))
-        .filter_map(|file| read_resolution_db(file.contents_utf8().unwrap()))
-        .fold(ResolutionDB::new(), |left, right| merge_resolution_db(left, right))
+        .map(|file| {
+            let db = read_resolution_db(file.contents_utf8().unwrap())
+                .expect("Failed to load a core resolution file inside the binary, corrupted binary?");
+            with_source(db, &format!("core:{}", file.path().display()))
+        })
+        .fold(ResolutionDB::new(), |left, right| merge_resolution_db_with(left, right, &mut conflicts))
     } else { ResolutionDB::new() };
 
-    let mut resolution_db = std::env::var("BUILDXYZ_RESOLUTION_PATH")
-        .unwrap_or(String::new())
-        .split(":")
-        .into_iter()
-        .map(PathBuf::from)
+    let resolution_path = std::env::var("BUILDXYZ_RESOLUTION_PATH").unwrap_or_default();
+    let mut resolution_db = DEFAULT_RESOLUTION_PATHS
+        .iter()
+        .map(|searchpath| searchpath.to_string_lossy().to_string())
         // Default resolution paths are lowest priority.
-        .chain(DEFAULT_RESOLUTION_PATHS.iter().cloned())
-        .map(|searchpath| load_resolution_db(searchpath))
-        .flatten() // Filter out all Nones.
+        .chain(resolution::split_resolution_path(&resolution_path))
+        .filter_map(|searchpath| {
+            if fetch::is_url(&searchpath) {
+                include::read_remote_resolutions(&searchpath, &mut pins, &signatures).unwrap_or_else(|err| {
+                    warn!("Failed to read resolutions from {}: {}", searchpath, err);
+                    None
+                })
+            } else {
+                load_resolution_db(searchpath.clone().into(), &mut conflicts, &mut pins, &signatures)
+                    .unwrap_or_else(|err| {
+                        error!("Failed to load the resolutions of {}: {}", searchpath, err);
+                        std::process::exit(1);
+                    })
+            }
+        })
+        .collect::<Vec<ResolutionDB>>()
+        .into_iter()
         .fold(core_resolution_db, |left, right| {
-            merge_resolution_db(left, right)
+            merge_resolution_db_with(left, right, &mut conflicts)
         });
 
-    if let Some(custom_resolutions_filepath) = args.custom_resolutions_filepath {
-        if let Some(custom_resolutions) = read_resolution_db(
-            &std::fs::read_to_string(custom_resolutions_filepath).expect("Failed to read from custom resolution file")
-        )
+    if let Some(custom_resolutions_filepath) = &args.custom_resolutions_filepath {
+        if let Some(custom_resolutions) = include::read_resolution_file_with_includes(custom_resolutions_filepath, &mut pins, &signatures)
+            .expect("Failed to read from custom resolution file")
         {
-            resolution_db = merge_resolution_db(resolution_db, custom_resolutions);
+            resolution_db = merge_resolution_db_with(resolution_db, custom_resolutions, &mut conflicts);
+        }
+    }
+
+    exit_on_unresolved_conflicts(&conflicts);
+
+    record_merge_choices(args.resolution_record_filepath.clone(), &mut conflicts);
+    let resolution_db = drop_tombstones(resolution_db);
+
+    if args.print_resolution_sources {
+        println!("List of resolutions with their source:");
+        for (requested_path, resolution) in &resolution_db {
+            println!(
+                "\t{} (priority {}) from {}",
+                requested_path,
+                resolution.priority(),
+                resolution.source()
+            );
         }
+
+        return Ok(());
     }
 
     if args.print_ignored_paths {
         println!("List of ignored paths:");
         for resolution in resolution_db.values() {
-            let resolution::Resolution::ConstantResolution(data) = resolution;
-            match data.decision {
-                resolution::Decision::Ignore => {
-                    println!("\t{}", data.requested_path);
-                },
-                _ => {}
+            for data in resolution.all_data() {
+                match data.decision {
+                    resolution::Decision::Ignore => {
+                        println!("\t{}", data.requested_path);
+                    },
+                    resolution::Decision::IgnoreTree => {
+                        println!("\t{}/**", data.requested_path);
+                    },
+                    _ => {}
+                }
             }
         }
         
@@ -187,53 +834,222 @@ fn main() -> Result<(), io::Error> {
     }
 
 
-    let store_paths = resolution_db
-        .values()
-        .filter_map(|resolution| {
-            debug!("store path: {:?}", resolution);
-            match resolution {
-                Resolution::ConstantResolution(data) => {
-                    if let Decision::Provide(provide_data) = &data.decision {
-                        return Some(provide_data.store_path.clone());
+    // Every namespace starts from the session resolutions.
+    let namespaces: Vec<(String, ResolutionDB)> = args
+        .namespaces
+        .iter()
+        .map(|spec| match spec.split_once('=') {
+            Some((name, filepath)) => {
+                let namespace_db = match include::read_resolution_file_with_includes(Path::new(filepath), &mut pins, &signatures) {
+                    Ok(Some(namespace_db)) => namespace_db,
+                    // The reason was logged while parsing.
+                    Ok(None) => {
+                        error!("Failed to parse the resolutions of namespace `{}` in {}", name, filepath);
+                        std::process::exit(1);
                     }
-                }
+                    Err(err) => {
+                        error!("Failed to read the resolutions of namespace `{}` from {}: {}", name, filepath, err);
+                        std::process::exit(1);
+                    }
+                };
+
+                let namespace_db = merge_resolution_db_with(resolution_db.clone(), namespace_db, &mut conflicts);
+                record_merge_choices(
+                    args.resolution_record_filepath.as_ref().map(|filepath| namespace::namespaced_path(filepath, name)),
+                    &mut conflicts,
+                );
+
+                (name.to_string(), drop_tombstones(namespace_db))
+            }
+            None => (spec.clone(), resolution_db.clone()),
+        })
+        .collect();
+    exit_on_unresolved_conflicts(&conflicts);
+    if pins.strict && !pins.stale.is_empty() {
+        for filepath in &pins.stale {
+            error!(
+                "{} was recorded against another index, run `buildxyz res repin {}`",
+                filepath.display(),
+                filepath.display()
+            );
+        }
+        std::process::exit(1);
+    }
+
+    // Shared resolution files could point to any store path, check them before realizing anything.
+    let mut verification = verify::PathVerification::new(args.unverified_store_paths);
+    let resolution_db = verification.apply(resolution_db);
+    let namespaces: Vec<(String, ResolutionDB)> = namespaces
+        .into_iter()
+        .map(|(name, namespace_db)| (name, verification.apply(namespace_db)))
+        .collect();
+
+    // The command runs in the first namespace, if any.
+    let runner_namespace = namespaces.first().map(|(name, _)| name.clone());
+
+    let store_paths = iter::once(&resolution_db)
+        .chain(namespaces.iter().map(|(_, namespace_db)| namespace_db))
+        .flat_map(|db| db.values())
+        .flat_map(|resolution| {
+            debug!("store path: {:?}", resolution);
+            resolution.all_data()
+        })
+        .filter_map(|data| {
+            if let Decision::Provide(provide_data) = &data.decision {
+                return Some(provide_data.store_path.clone());
             }
 
             None
         })
     .collect::<Vec<StorePath>>();
 
-    for spath in store_paths {
-        debug!("Ensuring that resolution {} is available in the Nix store", spath.as_str());
-        if realize_path(spath.as_str().to_string()).is_err() {
-            warn!("Failed to realize it, BuildXYZ may fail");
+    let library_dirs = if args.nix_ld {
+        nixld::library_dirs(namespaces.first().map_or(&resolution_db, |(_, namespace_db)| namespace_db))
+    } else {
+        Vec::new()
+    };
+
+    // The other indexes are searched after the session one, in the order given, then those of the added flakes.
+    let mut index_set = filesource::IndexSet::new(pin::session_index());
+    for spec in &args.indexes {
+        match index::read_named_index(spec) {
+            Ok((name, data)) => {
+                info!("Searching the {} index too", name);
+                index_set = index_set.with(&name, data);
+            }
+            Err(err) => {
+                error!("Failed to read the index {}: {}", spec, err);
+                std::process::exit(1);
+            }
         }
     }
-
-    let session = spawn_mount2(
-        fs::BuildXYZ {
-            recv_fs_event,
-            send_ui_event: send_ui_event.clone(),
-            resolution_record_filepath: args.resolution_record_filepath,
-            resolution_db,
-            fast_working_tree: fast_tmpdir.path().to_owned(),
+    for (metadata, data) in index::flake_indexes(&index::current_system()) {
+        debug!("Searching the packages of {} too", metadata.channel);
+        index_set = index_set.with(&metadata.channel, data);
+    }
+    // The resolved store paths may be missing from the index, e.g. recorded at a more recent nixpkgs.
+    let binary_cache = Arc::new(if args.binary_cache_listings {
+        filesource::BinaryCacheSource::new(nix::substituters())
+    } else {
+        Default::default()
+    });
+    // Realized in the background, lookups of a path wait for its realization only.
+    let realizer = realize::Realizer::new(
+        args.realize_jobs,
+        realize::RetryPolicy {
+            retries: args.realize_retries,
+            build_fallback: !args.no_build_fallback,
             ..Default::default()
         },
-        fuse_tmpdir
-            .path()
-            .to_str()
-            .expect("Failed to convert the path to a string"),
-        &[]
+        Some(send_ui_event.clone()),
+    );
+    for spath in store_paths {
+        debug!("Ensuring that resolution {} is available in the Nix store", spath.as_str());
+        realizer.remember(&spath);
+        realizer.realize(spath.as_str().as_ref());
+        binary_cache.track(&spath);
+    }
+
+    let mount_options = args.mount_options();
+    debug!("Mount options: {:?}", mount_options);
+    let answers = dispatch::AnswerRouter::spawn(recv_fs_event);
+    let metrics = Arc::new(metrics::Metrics::default());
+    if let Some(address) = args.metrics_address {
+        let address = metrics.serve(address).expect("Failed to serve the metrics");
+        info!("Metrics served on http://{}/metrics", address);
+    }
+    let lookup_workers = args.lookup_workers.unwrap_or_else(num_cpus::get);
+    let substituters = Arc::new(if args.no_substituter_check {
+        Default::default()
+    } else {
+        substituters::SubstituterCheck::new(nix::substituters())
+    });
+    // Shared by the namespaces, they fill the same disk.
+    let budget = Arc::new(budget::ClosureBudget::new(
+        args.max_closure_size,
+        args.max_session_closure_size,
+        args.over_budget,
+    ));
+    // Kept to review or confirm their decisions once unmounted.
+    let mut filesystems = Vec::new();
+    let shared = SharedServices {
+        answers,
+        metrics: metrics.clone(),
+        send_ui_event: send_ui_event.clone(),
+        realizer: realizer.clone(),
+        substituters,
+        index: index_set.clone(),
+        binary_cache: binary_cache.clone(),
+        budget,
+    };
+    let session = if namespaces.is_empty() {
+        let dispatcher = dispatch::Dispatcher::new(
+            args.build_fs(resolution_db, fast_tmpdir.path().to_owned(), shared, None),
+            lookup_workers,
+        );
+        filesystems.push(dispatcher.fs());
+        spawn_mount2(
+            dispatcher,
+            fuse_tmpdir
+                .path()
+                .to_str()
+                .expect("Failed to convert the path to a string"),
+            &mount_options,
+        )
+    } else {
+        let namespaces = namespaces
+            .into_iter()
+            .enumerate()
+            .map(|(index, (name, namespace_db))| {
+                let fast_working_tree = fast_tmpdir.path().join(&name);
+                std::fs::create_dir(&fast_working_tree)
+                    .expect("Failed to create the namespace fast working tree");
+                info!(
+                    "Namespace `{}` served in {}",
+                    name,
+                    fuse_tmpdir.path().join(&name).display()
+                );
+                let namespace_fs = args.build_fs(
+                    namespace_db,
+                    fast_working_tree,
+                    shared.clone(),
+                    Some((index, &name)),
+                );
+
+                let dispatcher = dispatch::Dispatcher::new(namespace_fs, lookup_workers);
+                filesystems.push(dispatcher.fs());
 
-    )
+                (name, dispatcher)
+            })
+            .collect();
+
+        spawn_mount2(
+            namespace::Namespaces::new(namespaces),
+            fuse_tmpdir
+                .path()
+                .to_str()
+                .expect("Failed to convert the path to a string"),
+            &mount_options,
+        )
+    }
     .expect("Error spawning the FUSE filesystem in the background");
 
-    info!("Running `{}`", args.cmd);
+    info!("Running `{}`", cmd);
 
     let retry = Arc::new(AtomicBool::new(args.retry));
     // FIXME uninitialized values are bad.
     let current_child_pid = Arc::new(AtomicU32::new(0));
-    if let [cmd, cmd_args @ ..] = &args.cmd.split_ascii_whitespace().collect::<Vec<&str>>()[..] {
+    let mountpoint = runner_namespace
+        .as_ref()
+        .map_or_else(|| fuse_tmpdir.path().to_owned(), |name| fuse_tmpdir.path().join(name));
+    let fast_working_root = runner_namespace
+        .as_ref()
+        .map_or_else(|| fast_tmpdir.path().to_owned(), |name| fast_tmpdir.path().join(name));
+    if args.nix_ld {
+        nixld::inject(&mut env, &library_dirs, &fast_working_root, &mountpoint)
+            .expect("Failed to set up nix-ld");
+    }
+    if let [cmd, cmd_args @ ..] = &cmd.split_ascii_whitespace().collect::<Vec<&str>>()[..] {
         let run_join_handle = runner::spawn_instrumented_program(
             cmd.to_string(),
             // FIXME: ugh ugly
@@ -242,12 +1058,16 @@ fn main() -> Result<(), io::Error> {
                 .into_iter()
                 .map(|s| s.to_string())
                 .collect(),
-            std::env::vars().collect(),
+            env,
             current_child_pid.clone(),
             retry.clone(),
             send_event.clone(),
-            fuse_tmpdir.path(),
-            fast_tmpdir.path()
+            runner::RunnerOptions {
+                mountpoint: &mountpoint,
+                fast_working_root: &fast_working_root,
+                interpreter_shims: args.interpreter_shims,
+                send_output: (ui == interactive::Ui::Tui).then_some(send_build_output),
+            },
         );
 
         // Main event loop
@@ -290,11 +1110,40 @@ fn main() -> Result<(), io::Error> {
                     let status_code = run_join_handle
                         .join()
                         .expect("Failed to wait for the runner thread");
-                    ui_join_handle
-                        .join()
-                        .expect("Failed to wait for the UI thread");
+                    for ui_join_handle in ui_join_handles {
+                        ui_join_handle
+                            .join()
+                            .expect("Failed to wait for the UI thread");
+                    }
                     info!("Unmounting the filesystem...");
-                    session.join();
+                    mount::teardown(session, fuse_tmpdir.path());
+
+                    for fs in &filesystems {
+                        let mut fs = fs.lock().unwrap();
+                        if args.review {
+                            fs.review_decisions(interactive::review_decisions);
+                        }
+                        if args.confirms_record() {
+                            fs.confirm_record(interactive::confirm_record);
+                        }
+                        fs.write_deferred_record();
+                        let overlays_dir = project_dir().join("overlays");
+                        match args.missing_overlays() {
+                            overlay::MissingOverlays::Ask => {
+                                fs.offer_missing_overlays(&overlays_dir, interactive::offer_missing_overlays)
+                            }
+                            overlay::MissingOverlays::Write => {
+                                fs.offer_missing_overlays(&overlays_dir, |packages| (0..packages.len()).collect())
+                            }
+                            overlay::MissingOverlays::Never => {}
+                        }
+                    }
+
+                    if let Some(filepath) = &args.metrics_filepath {
+                        if let Err(err) = metrics.dump(filepath) {
+                            warn!("Failed to write the metrics to {}: {}", filepath.display(), err);
+                        }
+                    }
 
                     if let Some(code) = status_code {
                         if code != 0 && args.automatic {
@@ -314,3 +1163,37 @@ fn main() -> Result<(), io::Error> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mount_options() {
+        let args = Args::try_parse_from(["buildxyz", "make"]).unwrap();
+        assert_eq!(args.mount_options(), vec![MountOption::FSName("buildxyz".into())]);
+
+        let args = Args::try_parse_from([
+            "buildxyz",
+            "--allow-root",
+            "--auto-unmount",
+            "--fsname",
+            "buildxyz-zlib",
+            "make",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.mount_options(),
+            vec![
+                MountOption::FSName("buildxyz-zlib".into()),
+                MountOption::AllowRoot,
+                MountOption::AutoUnmount,
+            ]
+        );
+
+        // Refused by FUSE when mounting otherwise.
+        assert!(Args::try_parse_from(["buildxyz", "--auto-unmount", "make"]).is_err());
+        assert!(Args::try_parse_from(["buildxyz", "--allow-other", "--allow-root", "make"]).is_err());
+        assert!(Args::try_parse_from(["buildxyz", "--allow-other", "--auto-unmount", "make"]).is_ok());
+    }
+}
@@ -0,0 +1,231 @@
+use log::{debug, warn};
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// How long a metrics client may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Upper bounds of the histogram buckets, in seconds.
+const BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0, 30.0, 120.0,
+];
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Cumulative histogram of durations.
+#[derive(Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    /// In microseconds, to stay atomic.
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (bound, bucket) in BUCKETS.iter().zip(&self.buckets) {
+            if seconds <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        for (bound, bucket) in BUCKETS.iter().zip(&self.buckets) {
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{le=\"{bound}\"}} {}",
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+        let _ = writeln!(
+            out,
+            "{name}_sum {}",
+            self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6
+        );
+        let _ = writeln!(out, "{name}_count {count}");
+    }
+}
+
+/// Counters and histograms of the filesystem layer, shared by all namespaces and workers.
+#[derive(Default)]
+pub struct Metrics {
+    pub lookups: Counter,
+    /// Lookups which needed an index search, the others took a fast path.
+    pub index_searches: Counter,
+    /// Index queries which missed the query cache.
+    pub index_query_latency: Histogram,
+    pub prompt_wait: Histogram,
+    pub realizations: Histogram,
+}
+
+fn render_counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} counter");
+    let _ = writeln!(out, "{name} {value}");
+}
+
+impl Metrics {
+    /// Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let lookups = self.lookups.get();
+        let index_searches = self.index_searches.get();
+
+        render_counter(
+            &mut out,
+            "buildxyz_lookups_total",
+            "Lookups received from the kernel.",
+            lookups,
+        );
+        render_counter(
+            &mut out,
+            "buildxyz_index_searches_total",
+            "Lookups which needed an index search.",
+            index_searches,
+        );
+        let _ = writeln!(
+            out,
+            "# HELP buildxyz_fast_path_ratio Share of lookups answered without an index search."
+        );
+        let _ = writeln!(out, "# TYPE buildxyz_fast_path_ratio gauge");
+        let _ = writeln!(
+            out,
+            "buildxyz_fast_path_ratio {}",
+            if lookups == 0 {
+                0.0
+            } else {
+                lookups.saturating_sub(index_searches) as f64 / lookups as f64
+            }
+        );
+        self.index_query_latency.render(
+            &mut out,
+            "buildxyz_index_query_seconds",
+            "Latency of the index queries missing the query cache.",
+        );
+        self.prompt_wait.render(
+            &mut out,
+            "buildxyz_prompt_wait_seconds",
+            "Time spent waiting for the user to answer a prompt.",
+        );
+        self.realizations.render(
+            &mut out,
+            "buildxyz_realization_seconds",
+            "Time spent realizing the provided store paths.",
+        );
+
+        out
+    }
+
+    /// Serve the metrics over HTTP on `address`, whatever the request path.
+    /// Returns the address actually listened on, e.g. the port picked for port 0.
+    pub fn serve(self: &Arc<Self>, address: SocketAddr) -> std::io::Result<SocketAddr> {
+        let listener = TcpListener::bind(address)?;
+        let address = listener.local_addr()?;
+        let metrics = self.clone();
+        debug!("serving metrics on http://{}/metrics", address);
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        warn!("Failed to accept a metrics connection: {}", err);
+                        continue;
+                    }
+                };
+                // A client which never finishes its request does not hold the next scrapes.
+                let metrics = metrics.clone();
+                thread::spawn(move || {
+                    if let Err(err) = metrics.reply(stream) {
+                        debug!("Failed to reply to a metrics connection: {}", err);
+                    }
+                });
+            }
+        });
+
+        Ok(address)
+    }
+
+    fn reply(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        // Skip the request line and headers.
+        let mut reader = BufReader::new(&stream);
+        let mut line = String::new();
+        while reader.read_line(&mut line).map_or(false, |read| read > 2) {
+            line.clear();
+        }
+
+        let body = self.render();
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    }
+
+    pub fn dump(&self, filepath: &Path) -> std::io::Result<()> {
+        std::fs::write(filepath, self.render())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_buckets_are_cumulative() {
+        let metrics = Metrics::default();
+        metrics.prompt_wait.observe(Duration::from_millis(3));
+        metrics.prompt_wait.observe(Duration::from_secs(2));
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("buildxyz_prompt_wait_seconds_bucket{le=\"0.001\"} 0\n"));
+        assert!(rendered.contains("buildxyz_prompt_wait_seconds_bucket{le=\"0.005\"} 1\n"));
+        assert!(rendered.contains("buildxyz_prompt_wait_seconds_bucket{le=\"5\"} 2\n"));
+        assert!(rendered.contains("buildxyz_prompt_wait_seconds_count 2\n"));
+    }
+
+    #[test]
+    fn test_stalled_clients_do_not_hold_scrapes() {
+        let metrics = Arc::new(Metrics::default());
+        metrics.lookups.inc();
+        let address = metrics.serve("127.0.0.1:0".parse().unwrap()).unwrap();
+
+        // Connected, never sending its request.
+        let _stalled = TcpStream::connect(address).unwrap();
+        let mut scrape = TcpStream::connect(address).unwrap();
+        scrape.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        scrape.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        std::io::Read::read_to_string(&mut scrape, &mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("buildxyz_lookups_total 1\n"));
+    }
+}
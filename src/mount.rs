@@ -0,0 +1,169 @@
+use fuser::BackgroundSession;
+use log::{debug, info, warn};
+use nix::errno::Errno;
+use nix::mount::{umount2, MntFlags};
+use std::ffi::OsString;
+use std::fs;
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+/// How long we wait for the FUSE session to stop once unmounted.
+const SESSION_JOIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// `/proc/mounts` escapes spaces, tabs, newlines and backslashes as octal.
+fn unescape_mount_field(field: &str) -> OsString {
+    let field = field.as_bytes();
+    let mut unescaped = Vec::with_capacity(field.len());
+    let mut i = 0;
+    while i < field.len() {
+        let octal = field.get(i + 1..i + 4).filter(|_| field[i] == b'\\');
+        match octal.and_then(|octal| u8::from_str_radix(std::str::from_utf8(octal).ok()?, 8).ok()) {
+            Some(byte) => {
+                unescaped.push(byte);
+                i += 4;
+            }
+            None => {
+                unescaped.push(field[i]);
+                i += 1;
+            }
+        }
+    }
+
+    OsString::from_vec(unescaped)
+}
+
+/// Mountpoints of FUSE filesystems named `fsname` in a `/proc/mounts`-formatted table.
+fn fuse_mountpoints(mounts: &str, fsname: &str) -> Vec<PathBuf> {
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            let source = unescape_mount_field(fields.next()?);
+            let mountpoint = unescape_mount_field(fields.next()?);
+            let fstype = fields.next()?;
+
+            (source == fsname && (fstype == "fuse" || fstype.starts_with("fuse.")))
+                .then(|| PathBuf::from(mountpoint))
+        })
+        .collect()
+}
+
+/// Unmount through the setuid helper, which is the only way for unprivileged users.
+fn fusermount(mountpoint: &Path, lazy: bool) -> bool {
+    ["fusermount3", "fusermount"].into_iter().any(|helper| {
+        Command::new(helper)
+            .arg(if lazy { "-uz" } else { "-u" })
+            .arg(mountpoint)
+            .status()
+            .map_or(false, |status| status.success())
+    })
+}
+
+/// Unmount `mountpoint`, detaching it lazily if the kernel still has it busy,
+/// e.g. a stray child process whose working directory lives there.
+pub fn unmount(mountpoint: &Path) -> Result<(), Errno> {
+    match umount2(mountpoint, MntFlags::empty()) {
+        Ok(()) => return Ok(()),
+        Err(Errno::EPERM) if fusermount(mountpoint, false) => return Ok(()),
+        Err(Errno::EINVAL) => return Err(Errno::EINVAL),
+        Err(err) => debug!("failed to unmount {}: {}", mountpoint.display(), err),
+    }
+
+    warn!("{} is still busy, detaching it lazily", mountpoint.display());
+    match umount2(mountpoint, MntFlags::MNT_DETACH) {
+        Ok(()) => Ok(()),
+        Err(Errno::EPERM) if fusermount(mountpoint, true) => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Unmount the filesystem and wait for its session to stop,
+/// without hanging forever if the kernel does not let go of it.
+pub fn teardown(session: BackgroundSession, mountpoint: &Path) {
+    match unmount(mountpoint) {
+        Ok(()) => debug!("unmounted {}", mountpoint.display()),
+        // Not mounted anymore.
+        Err(Errno::EINVAL) => {}
+        Err(err) => warn!("Failed to unmount {}: {}", mountpoint.display(), err),
+    }
+
+    let (send_done, recv_done) = channel();
+    thread::spawn(move || {
+        session.join();
+        let _ = send_done.send(());
+    });
+    if recv_done.recv_timeout(SESSION_JOIN_TIMEOUT).is_err() {
+        warn!(
+            "The FUSE session did not stop after {:?}, leaving it behind",
+            SESSION_JOIN_TIMEOUT
+        );
+    }
+}
+
+/// Unmount and remove the mountpoints left behind by crashed buildxyz instances.
+///
+/// Only mounts of `fsname` in the temporary directory whose daemon is gone
+/// (`ENOTCONN`) are considered abandoned, live sessions are left alone.
+pub fn cleanup_stale_mounts(fsname: &str) {
+    let mounts = match fs::read_to_string("/proc/self/mounts") {
+        Ok(mounts) => mounts,
+        Err(err) => return debug!("cannot read the mount table: {}", err),
+    };
+    let tmpdir = std::env::temp_dir();
+
+    for mountpoint in fuse_mountpoints(&mounts, fsname) {
+        if !mountpoint.starts_with(&tmpdir) {
+            continue;
+        }
+        match fs::metadata(&mountpoint) {
+            Err(err) if err.raw_os_error() == Some(Errno::ENOTCONN as i32) => {}
+            _ => continue,
+        }
+
+        info!("Cleaning up the stale mount {}", mountpoint.display());
+        match unmount(&mountpoint) {
+            Ok(()) => {
+                if let Err(err) = fs::remove_dir(&mountpoint) {
+                    debug!("failed to remove {}: {}", mountpoint.display(), err);
+                }
+            }
+            Err(err) => warn!(
+                "Failed to clean up the stale mount {}: {}",
+                mountpoint.display(),
+                err
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fuse_mountpoints_are_unescaped_and_filtered_by_name() {
+        let mounts = "\
+buildxyz /tmp/.tmpAbC fuse rw,nosuid,nodev 0 0
+buildxyz /tmp/with\\040space fuse.buildxyz rw 0 0
+other /tmp/.tmpDeF fuse rw 0 0
+buildxyz /mnt ext4 rw 0 0
+";
+        assert_eq!(
+            fuse_mountpoints(mounts, "buildxyz"),
+            vec![PathBuf::from("/tmp/.tmpAbC"), PathBuf::from("/tmp/with space")]
+        );
+    }
+
+    #[test]
+    fn test_escaped_bytes_are_not_reencoded() {
+        // A Latin-1 `é`, which is not valid UTF-8 on its own.
+        assert_eq!(
+            unescape_mount_field("/tmp/caf\\351\\134x\\9"),
+            OsString::from_vec(b"/tmp/caf\xe9\\x\\9".to_vec())
+        );
+    }
+}
@@ -0,0 +1,243 @@
+use fuser::{FileType, Filesystem};
+use log::{debug, warn};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+use crate::dispatch::Dispatcher;
+use crate::fs::{build_fake_fattr, owned_by, RequestInfo};
+use crate::inode::ROOT_INODE;
+
+/// Each namespace owns the inodes whose high bits are its index (plus one),
+/// so that requests can be routed without any lookup table.
+const NAMESPACE_INODE_SHIFT: u32 = 48;
+
+/// Root inode of the `index`-th namespace.
+pub fn namespace_root(index: usize) -> u64 {
+    (index as u64 + 1) << NAMESPACE_INODE_SHIFT
+}
+
+/// `resolutions.toml` becomes `resolutions.<namespace>.toml`.
+pub fn namespaced_path(path: &Path, namespace: &str) -> PathBuf {
+    let mut file_name = path.file_stem().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(namespace);
+    if let Some(extension) = path.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+
+    path.with_file_name(file_name)
+}
+
+/// Several independent environments served from one mount,
+/// each under `<mountpoint>/<name>/` with its own resolutions and fast working tree.
+pub struct Namespaces {
+    namespaces: Vec<(String, Dispatcher)>,
+}
+
+impl Namespaces {
+    /// `namespaces` inode tables must be rooted at `namespace_root(index)`.
+    pub fn new(namespaces: Vec<(String, Dispatcher)>) -> Self {
+        for (index, (name, namespace)) in namespaces.iter().enumerate() {
+            assert_eq!(
+                namespace.lock().inodes.root(),
+                namespace_root(index),
+                "namespace {} has a misplaced inode table",
+                name
+            );
+        }
+
+        Namespaces { namespaces }
+    }
+
+    fn route(&mut self, ino: u64) -> Option<&mut Dispatcher> {
+        let index = (ino >> NAMESPACE_INODE_SHIFT).checked_sub(1)?;
+        self.namespaces
+            .get_mut(index as usize)
+            .map(|(_, namespace)| namespace)
+    }
+}
+
+impl Filesystem for Namespaces {
+    fn init(
+        &mut self,
+        req: &fuser::Request<'_>,
+        config: &mut fuser::KernelConfig,
+    ) -> Result<(), i32> {
+        for (name, namespace) in &mut self.namespaces {
+            debug!("initializing namespace {}", name);
+            namespace.init(req, config)?;
+        }
+
+        Ok(())
+    }
+
+    fn destroy(&mut self) {
+        for (_, namespace) in &mut self.namespaces {
+            namespace.destroy();
+        }
+    }
+
+    fn lookup(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        reply: fuser::ReplyEntry,
+    ) {
+        if parent == ROOT_INODE {
+            return match self
+                .namespaces
+                .iter()
+                .position(|(namespace, _)| OsStr::new(namespace) == name)
+            {
+                Some(index) => reply.entry(
+                    &self.namespaces[index].1.lock().ttls.directory,
+                    &owned_by(
+                        build_fake_fattr(namespace_root(index), FileType::Directory),
+                        &RequestInfo::from(req),
+                    ),
+                    0,
+                ),
+                None => reply.error(nix::errno::Errno::ENOENT as i32),
+            };
+        }
+
+        match self.route(parent) {
+            Some(namespace) => namespace.lookup(req, parent, name, reply),
+            None => reply.error(nix::errno::Errno::ENOENT as i32),
+        }
+    }
+
+    fn forget(&mut self, req: &fuser::Request<'_>, ino: u64, nlookup: u64) {
+        if let Some(namespace) = self.route(ino) {
+            namespace.forget(req, ino, nlookup);
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: fuser::ReplyDirectory,
+    ) {
+        if ino == ROOT_INODE {
+            let entries = [(".", ROOT_INODE), ("..", ROOT_INODE)]
+                .into_iter()
+                .chain(
+                    self.namespaces
+                        .iter()
+                        .enumerate()
+                        .map(|(index, (name, _))| (name.as_str(), namespace_root(index))),
+                );
+            for (index, (name, child_ino)) in entries.enumerate().skip(offset as usize) {
+                if reply.add(child_ino, (index + 1) as i64, FileType::Directory, name) {
+                    break;
+                }
+            }
+            return reply.ok();
+        }
+
+        match self.route(ino) {
+            Some(namespace) => namespace.readdir(req, ino, fh, offset, reply),
+            None => reply.error(nix::errno::Errno::ENOENT as i32),
+        }
+    }
+
+    fn statfs(&mut self, req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyStatfs) {
+        // All fast working trees live on the same filesystem, the root reports the first one.
+        let index = (ino >> NAMESPACE_INODE_SHIFT).saturating_sub(1) as usize;
+        match self.namespaces.get_mut(index) {
+            Some((_, namespace)) => namespace.statfs(req, ino, reply),
+            None => reply.error(nix::errno::Errno::ENOENT as i32),
+        }
+    }
+
+    fn getxattr(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: fuser::ReplyXattr,
+    ) {
+        match self.route(ino) {
+            Some(namespace) => namespace.getxattr(req, ino, name, size, reply),
+            None => reply.error(nix::errno::Errno::ENODATA as i32),
+        }
+    }
+
+    fn listxattr(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        size: u32,
+        reply: fuser::ReplyXattr,
+    ) {
+        match self.route(ino) {
+            Some(namespace) => namespace.listxattr(req, ino, size, reply),
+            None if size == 0 => reply.size(0),
+            None => reply.data(&[]),
+        }
+    }
+
+    fn readlink(&mut self, req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyData) {
+        match self.route(ino) {
+            Some(namespace) => namespace.readlink(req, ino, reply),
+            None => {
+                warn!("Attempt to read a link outside of any namespace, ino={}", ino);
+                reply.error(nix::errno::Errno::ENOENT as i32)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::BuildXYZ;
+    use crate::inode::InodeTable;
+
+    #[test]
+    fn test_namespaced_path() {
+        assert_eq!(
+            namespaced_path(Path::new(".buildxyz/resolutions.toml"), "tests"),
+            Path::new(".buildxyz/resolutions.tests.toml")
+        );
+        assert_eq!(
+            namespaced_path(Path::new("/tmp/trace"), "docs"),
+            Path::new("/tmp/trace.docs")
+        );
+    }
+
+    #[test]
+    fn test_requests_are_routed_by_inode() {
+        let mut namespaces = Namespaces::new(
+            ["build", "tests"]
+                .into_iter()
+                .enumerate()
+                .map(|(index, name)| {
+                    let fs = BuildXYZ {
+                        inodes: InodeTable::with_root_under(namespace_root(index), ROOT_INODE),
+                        ..Default::default()
+                    };
+                    (name.to_string(), Dispatcher::new(fs, 1))
+                })
+                .collect(),
+        );
+        let tests_lib = namespaces.namespaces[1].1.lock().inodes.pin("lib");
+
+        assert!(namespaces.route(ROOT_INODE).is_none());
+        assert_eq!(
+            namespaces.route(namespace_root(0)).unwrap().lock().inodes.root(),
+            namespace_root(0)
+        );
+        let routed = namespaces.route(tests_lib).unwrap().lock();
+        assert_eq!(routed.inodes.path(tests_lib), Some("lib"));
+        assert_eq!(routed.inodes.parent(namespace_root(1)), Some(ROOT_INODE));
+        drop(routed);
+        assert!(namespaces.route(namespace_root(2)).is_none());
+    }
+}
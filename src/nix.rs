@@ -1,49 +1,910 @@
-use log::trace;
-use serde::Deserialize;
+use lazy_static::lazy_static;
+use log::{debug, trace, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
 
 use error_chain::{bail, error_chain};
 
+use crate::daemon::{socket_of_store, DaemonStore};
+use crate::nixcli::cli;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum StoreKind {
     Local,
     Remote(String),
 }
 
+/// Store directory of the paths in the index, and of most stores.
+pub const DEFAULT_STORE_DIR: &str = "/nix/store";
+
+/// Where the store paths are: the directory they are named after, and where it actually is on this machine,
+/// e.g. in `~/.local/nix/nix/store` for a chroot store the build cannot see `/nix/store` from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StoreLocation {
+    pub store_dir: String,
+    pub real_store_dir: String,
+}
+
+impl Default for StoreLocation {
+    fn default() -> Self {
+        StoreLocation {
+            store_dir: DEFAULT_STORE_DIR.to_string(),
+            real_store_dir: DEFAULT_STORE_DIR.to_string(),
+        }
+    }
+}
+
+impl StoreLocation {
+    /// Location of the store at this Nix store URI, relocated for chroot stores,
+    /// e.g. `/home/me/.local/nix` or `local?root=/home/me/.local/nix`, and for the `store` and `real` settings of local stores.
+    pub fn of(uri: &str) -> Self {
+        let (scheme, params) = uri.split_once('?').unwrap_or((uri, ""));
+        let params: HashMap<&str, &str> = params
+            .split('&')
+            .filter_map(|param| param.split_once('='))
+            .collect();
+        let root = if scheme.starts_with('/') {
+            Some(scheme)
+        } else if scheme == "local" || scheme == "auto" {
+            params.get("root").copied()
+        } else {
+            None
+        };
+        let store_dir = params
+            .get("store")
+            .map_or(DEFAULT_STORE_DIR, |store_dir| store_dir.trim_end_matches('/'));
+        let real_store_dir = match (params.get("real"), root) {
+            (Some(real), _) => real.trim_end_matches('/').to_string(),
+            (None, Some(root)) => format!("{}{}", root.trim_end_matches('/'), store_dir),
+            (None, None) => store_dir.to_string(),
+        };
+
+        StoreLocation {
+            store_dir: store_dir.to_string(),
+            real_store_dir,
+        }
+    }
+
+    fn is_relocated(&self) -> bool {
+        self.store_dir != self.real_store_dir
+    }
+
+    /// Where a path of the store is on this machine.
+    fn real_path(&self, path: &str) -> String {
+        match path.strip_prefix(&self.store_dir) {
+            Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+                format!("{}{}", self.real_store_dir, rest)
+            }
+            _ => path.to_string(),
+        }
+    }
+}
+
 error_chain! {
-    errors { InvalidPath }
+    errors {
+        InvalidPath
+        ShellEvaluation(stderr: String) {
+            description("failed to evaluate the development shell")
+            display("failed to evaluate the development shell: {}", stderr)
+        }
+        Build(installable: String, stderr: String) {
+            description("failed to build an installable")
+            display("failed to build {}: {}", installable, stderr)
+        }
+        Metadata(attr: String, stderr: String) {
+            description("failed to evaluate the metadata of a package")
+            display("failed to evaluate the metadata of {}: {}", attr, stderr)
+        }
+        OutPath(installable: String, stderr: String) {
+            description("failed to evaluate the output path of an installable")
+            display("failed to evaluate the output path of {}: {}", installable, stderr)
+        }
+        Flake(flakeref: String, stderr: String) {
+            description("failed to fetch a flake")
+            display("failed to fetch {}: {}", flakeref, stderr)
+        }
+        Index(stderr: String) {
+            description("failed to build the index of nixpkgs")
+            display("failed to build the index of nixpkgs: {}", stderr)
+        }
+        Listing(stderr: String) {
+            description("failed to list the packages of nixpkgs")
+            display("failed to list the packages of nixpkgs: {}", stderr)
+        }
+        Daemon(message: String) {
+            description("the Nix daemon failed")
+            display("the Nix daemon failed: {}", message)
+        }
+        Unsupported(message: String) {
+            description("the installed Nix cannot do this")
+            display("{}", message)
+        }
+    }
 }
 
-/// Ask the store to realize the provided path.
-pub fn realize_path(path: String) -> Result<()> {
-    let nixpkgs_path = env!("BUILDXYZ_NIXPKGS");
-    // TODO: send back this information to the meta-panel of the TUI
-    let output = Command::new("nix-store")
-        .arg("--realize")
-        .arg(path)
-        .env("NIX_PATH", format!("nixpkgs={}", nixpkgs_path))
+/// How BuildXYZ talks to the Nix store, see `--store-backend`.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StoreBackendKind {
+    /// The daemon protocol, falling back to the commands if the daemon is not reachable
+    Daemon,
+    /// `nix-store` and `nix` commands, one per call
+    Command,
+}
+
+/// Realization, validity and path-info queries of store paths.
+pub trait StoreBackend: Send + Sync {
+    fn is_valid(&self, path: &str) -> Result<bool>;
+    /// Substitute a store path, it cannot be built without its derivation.
+    /// `on_progress` is told the bytes copied so far and expected, when the backend knows.
+    fn realize(&self, path: &str, on_progress: &mut dyn FnMut(u64, u64)) -> Result<()>;
+    /// Closure size of a valid store path, `None` if it is not valid.
+    fn closure_size(&self, path: &str) -> Option<usize>;
+}
+
+lazy_static! {
+    static ref STORE_BACKEND: RwLock<Arc<dyn StoreBackend>> = RwLock::new(Arc::new(CommandStore::local()));
+    /// Store the `nix` commands use, see `--store`
+    static ref STORE: RwLock<StoreKind> = RwLock::new(StoreKind::Local);
+    /// Where the paths of the store are, see `StoreLocation`
+    static ref STORE_LOCATION: RwLock<StoreLocation> = RwLock::new(StoreLocation::default());
+    /// nixpkgs packages are evaluated from, see `--nixpkgs`
+    static ref NIXPKGS: RwLock<String> = RwLock::new(env!("BUILDXYZ_NIXPKGS").to_string());
+    /// Validity and closure sizes asked before, see `PathInfoCache`
+    static ref PATH_INFO: Mutex<PathInfoCache> =
+        Mutex::new(PathInfoCache::load(Duration::from_secs(DEFAULT_PATH_INFO_TTL)));
+}
+
+/// Talk to the store this way from now on, `store` is a Nix store URI, e.g. `daemon` or `ssh-ng://builder`.
+/// Only `daemon` and `unix://` stores speak the daemon protocol, the commands are run for the others.
+pub fn set_store_backend(kind: StoreBackendKind, store: Option<&str>) {
+    let store = match store {
+        None | Some("auto") | Some("local") => StoreKind::Local,
+        Some(uri) => StoreKind::Remote(uri.to_string()),
+    };
+    *STORE_BACKEND.write().unwrap() = match (kind, &store) {
+        (StoreBackendKind::Daemon, StoreKind::Local) => Arc::new(DaemonStore::new()),
+        (StoreBackendKind::Daemon, StoreKind::Remote(uri)) => match socket_of_store(uri) {
+            Some(socket_path) => Arc::new(DaemonStore::at(socket_path)),
+            None => Arc::new(CommandStore(store.clone())),
+        },
+        (StoreBackendKind::Command, _) => Arc::new(CommandStore(store.clone())),
+    };
+    *STORE_LOCATION.write().unwrap() = match &store {
+        StoreKind::Local => StoreLocation::default(),
+        StoreKind::Remote(uri) => StoreLocation::of(uri),
+    };
+    *STORE.write().unwrap() = store;
+}
+
+/// Directory the store paths are named after, `/nix/store` unless the store says otherwise.
+pub fn store_dir() -> String {
+    STORE_LOCATION.read().unwrap().store_dir.clone()
+}
+
+/// Whether the store directory is somewhere else on this machine, see `real_path`.
+pub fn store_is_relocated() -> bool {
+    STORE_LOCATION.read().unwrap().is_relocated()
+}
+
+/// Where a store path, or a file in it, is on this machine, the same path unless the store is relocated.
+/// Anything read from the store or served to the build goes through it.
+pub fn real_path(path: &str) -> String {
+    STORE_LOCATION.read().unwrap().real_path(path)
+}
+
+/// `--store` arguments of the commands for the configured store, none for the local one.
+fn store_args() -> Vec<String> {
+    match &*STORE.read().unwrap() {
+        StoreKind::Local => Vec::new(),
+        StoreKind::Remote(uri) => vec!["--store".into(), uri.clone()],
+    }
+}
+
+/// Evaluate packages from this nixpkgs source from now on rather than the one the build embeds.
+pub fn set_nixpkgs(source: &str) {
+    *NIXPKGS.write().unwrap() = source.to_string();
+}
+
+/// nixpkgs source packages are evaluated from.
+pub fn nixpkgs() -> String {
+    NIXPKGS.read().unwrap().clone()
+}
+
+fn store_backend() -> Arc<dyn StoreBackend> {
+    STORE_BACKEND.read().unwrap().clone()
+}
+
+/// Store path containing `path`, e.g. `/nix/store/<hash>-zlib-1.3-dev` for `/nix/store/<hash>-zlib-1.3-dev/include/zlib.h`.
+pub fn store_path_root(path: &str) -> &str {
+    let depth = STORE_LOCATION.read().unwrap().store_dir.matches('/').count() + 1;
+    path.match_indices('/').nth(depth).map_or(path, |(index, _)| &path[..index])
+}
+
+/// Where a development shell is defined.
+pub enum ShellSource<'a> {
+    /// Flake installable, e.g. `.#devShells.x86_64-linux.default`
+    Flake(&'a str),
+    /// Nix file evaluating to the shell, e.g. `shell.nix`
+    File(&'a Path),
+}
+
+impl ShellSource<'_> {
+    /// `nix` arguments selecting `attr` of the shell, or the shell itself.
+    fn installable_args(&self, attr: Option<&str>) -> Vec<String> {
+        match self {
+            Self::Flake(installable) => vec![match attr {
+                Some(attr) => format!("{}.{}", installable, attr),
+                None => installable.to_string(),
+            }],
+            Self::File(filepath) => ["--file".to_string(), filepath.display().to_string()]
+                .into_iter()
+                .chain(attr.map(String::from))
+                .collect(),
+        }
+    }
+}
+
+/// A package the development shell brings in.
+#[derive(Deserialize, Debug)]
+pub struct ShellInput {
+    /// `pname` of the package, the closest we get to its nixpkgs attribute
+    pub attr: String,
+    pub output: String,
+    pub path: String,
+    pub system: Option<String>,
+}
+
+/// Maps a `mkShell` derivation to its inputs, `packages` end up in `nativeBuildInputs`.
+const SHELL_INPUTS_EXPR: &str = r#"shell: map (p: {
+  attr = p.pname or (builtins.parseDrvName p.name).name;
+  output = p.outputName or "out";
+  path = p.outPath;
+  system = p.system or null;
+}) (builtins.filter (p: p ? outPath) (shell.nativeBuildInputs ++ shell.buildInputs
+  ++ shell.propagatedNativeBuildInputs ++ shell.propagatedBuildInputs))"#;
+
+/// What nixpkgs says about a package, see `package_metadata`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct PackageMetadata {
+    pub version: String,
+    pub outputs: Vec<String>,
+    pub description: Option<String>,
+    /// SPDX identifiers when known, e.g. `Apache-2.0`
+    pub licenses: Vec<String>,
+}
+
+/// Maps a package to its `PackageMetadata`, `meta.license` is a license, a list of them or a free form string.
+const PACKAGE_METADATA_EXPR: &str = r#"p: let license = p.meta.license or [ ]; in {
+  version = p.version or (builtins.parseDrvName p.name).version;
+  outputs = p.outputs or [ "out" ];
+  description = p.meta.description or null;
+  licenses = map (l: if builtins.isAttrs l then l.spdxId or l.shortName or l.fullName or "unknown" else l)
+    (if builtins.isList license then license else [ license ]);
+}"#;
+
+/// `nix` with the experimental features used enabled, when this Nix has them.
+fn nix_command() -> Command {
+    let mut command = Command::new("nix");
+    command.args(cli().feature_args());
+    command
+}
+
+/// Standard output of a command, its standard error on failure.
+fn run(command: &mut Command) -> std::result::Result<Vec<u8>, String> {
+    let output = command
         .stdin(Stdio::null())
         .output()
-        .expect("Failed to realize store based on nix-store --realize");
+        .map_err(|err| format!("failed to run {:?}: {}", command.get_program(), err))?;
 
     if output.status.success() {
-        Ok(())
+        Ok(output.stdout)
+    } else {
+        Err(String::from_utf8_lossy(&output.stderr).trim().to_string())
+    }
+}
+
+/// Standard output of `nix`, its standard error on failure, or why this Nix cannot run it.
+fn run_nix(args: &[String]) -> std::result::Result<Vec<u8>, String> {
+    cli().require_nix_command(&format!("`nix {}`", args.first().map_or("", String::as_str)))?;
+
+    run(nix_command().args(store_args()).args(args))
+}
+
+/// Evaluate an attribute of the nixpkgs packages are evaluated from as JSON, applying `apply` to it first if any.
+/// `nix-instantiate` evaluates it when `nix eval` is not there.
+fn eval_nixpkgs(attr: &str, apply: Option<&str>) -> std::result::Result<Vec<u8>, String> {
+    let source = nixpkgs();
+    if !cli().has_nix_command() {
+        let expr = format!(
+            "{{ nixpkgs }}: ({}) (import nixpkgs {{ }}).{}",
+            apply.unwrap_or("x: x"),
+            attr
+        );
+        return run(Command::new("nix-instantiate")
+            .args(["--eval", "--strict", "--json", "--expr", &expr, "--argstr", "nixpkgs"])
+            .arg(&source));
+    }
+
+    let nixpkgs = ShellSource::File(Path::new(&source));
+    let mut args: Vec<String> = vec!["eval".into(), "--json".into()];
+    args.extend(nixpkgs.installable_args(Some(attr)));
+    if let Some(apply) = apply {
+        args.extend(["--apply".into(), apply.into()]);
+    }
+    run_nix(&args)
+}
+
+/// A string evaluated by `eval_nixpkgs`.
+fn eval_nixpkgs_string(attr: &str) -> std::result::Result<String, String> {
+    let stdout = eval_nixpkgs(attr, None)?;
+    serde_json::from_slice(&stdout).map_err(|err| format!("{} is not a string: {}", attr, err))
+}
+
+/// Evaluate the inputs of a development shell, in order.
+pub fn shell_inputs(source: &ShellSource) -> Result<Vec<ShellInput>> {
+    let mut args: Vec<String> = vec!["eval".into(), "--json".into()];
+    args.extend(source.installable_args(None));
+    args.extend(["--apply".into(), SHELL_INPUTS_EXPR.into()]);
+    let stdout = run_nix(&args).map_err(ErrorKind::ShellEvaluation)?;
+    trace!("shell inputs: {}", String::from_utf8_lossy(&stdout));
+
+    Ok(serde_json::from_slice(&stdout).expect("Valid JSON from nix eval --json"))
+}
+
+/// Variables `nix develop` leaves as they are, see `ignoreVars` in Nix.
+const IGNORED_DEV_VARIABLES: [&str; 17] = [
+    "BASHOPTS", "HOME", "NIX_BUILD_TOP", "NIX_ENFORCE_PURITY", "NIX_LOG_FD", "NIX_REMOTE", "PPID", "SHELL",
+    "SHELLOPTS", "SSL_CERT_FILE", "TEMP", "TEMPDIR", "TERM", "TMP", "TMPDIR", "TZ", "UID",
+];
+
+/// Exported variables of `nix print-dev-env --json`, but those `nix develop` ignores.
+fn exported_variables(dev_env: &[u8]) -> HashMap<String, String> {
+    #[derive(Deserialize)]
+    struct Variable {
+        #[serde(rename = "type")]
+        kind: String,
+        value: serde_json::Value,
+    }
+    #[derive(Deserialize)]
+    struct DevEnv {
+        variables: HashMap<String, Variable>,
+    }
+
+    let dev_env: DevEnv = serde_json::from_slice(dev_env).expect("Valid JSON from nix print-dev-env --json");
+    dev_env
+        .variables
+        .into_iter()
+        .filter(|(name, variable)| variable.kind == "exported" && !IGNORED_DEV_VARIABLES.contains(&name.as_str()))
+        .filter_map(|(name, variable)| Some((name, variable.value.as_str()?.to_string())))
+        .collect()
+}
+
+/// Environment of a development shell as `nix develop` enters it, building its inputs.
+pub fn dev_env(source: &ShellSource) -> Result<HashMap<String, String>> {
+    let mut args: Vec<String> = vec!["print-dev-env".into(), "--json".into()];
+    args.extend(source.installable_args(None));
+    let stdout = run_nix(&args).map_err(ErrorKind::ShellEvaluation)?;
+
+    Ok(exported_variables(&stdout))
+}
+
+/// Build or substitute all the inputs of a development shell.
+pub fn realize_shell_inputs(source: &ShellSource) -> Result<()> {
+    let mut args: Vec<String> = vec!["build".into(), "--no-link".into()];
+    args.extend(source.installable_args(Some("inputDerivation")));
+    run_nix(&args).map_err(ErrorKind::ShellEvaluation)?;
+
+    Ok(())
+}
+
+/// Build or substitute a flake installable, e.g. `nixpkgs#zlib.dev`, and return its output paths.
+pub fn realize_installable(installable: &str) -> Result<Vec<String>> {
+    let args: Vec<String> = vec![
+        "build".into(),
+        "--no-link".into(),
+        "--print-out-paths".into(),
+        installable.into(),
+    ];
+    let stdout = run_nix(&args).map_err(|stderr| ErrorKind::Build(installable.to_string(), stderr))?;
+
+    Ok(String::from_utf8_lossy(&stdout)
+        .lines()
+        .map(String::from)
+        .collect())
+}
+
+/// Lines at the end of a failed build log kept in its error.
+const BUILD_LOG_TAIL: usize = 20;
+
+/// Build a package of nixpkgs locally, e.g. `zlib.dev`, when it cannot be substituted, and return its output paths.
+/// Each line of the build log is handed to `on_line` as it comes.
+pub fn build_attr(attr: &str, on_line: &mut dyn FnMut(&str)) -> Result<Vec<String>> {
+    let source = nixpkgs();
+    let mut command = if cli().has_print_out_paths() {
+        let nixpkgs = ShellSource::File(Path::new(&source));
+        let mut command = nix_command();
+        command
+            .args(store_args())
+            .args(["build", "--fallback", "--no-link", "--print-out-paths", "--print-build-logs"])
+            .args(nixpkgs.installable_args(Some(attr)));
+        command
     } else {
-        // TODO: more precise errors.
-        bail!(ErrorKind::InvalidPath)
+        // It prints the output paths too, and the build log as it comes.
+        let mut command = Command::new("nix-build");
+        command
+            .args(store_args())
+            .args(["--fallback", "--no-out-link"])
+            .arg(&source)
+            .args(["--attr", attr]);
+        command
+    };
+    let mut child = match command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(err) => bail!(ErrorKind::Build(
+            attr.to_string(),
+            format!("failed to run {:?}: {}", command.get_program(), err)
+        )),
+    };
+
+    let mut tail = VecDeque::new();
+    let stderr = child.stderr.take().expect("Piped stderr");
+    for line in BufReader::new(stderr).lines().map_while(std::result::Result::ok) {
+        on_line(&line);
+        tail.push_back(line);
+        if tail.len() > BUILD_LOG_TAIL {
+            tail.pop_front();
+        }
+    }
+    let output = child
+        .wait_with_output()
+        .chain_err(|| ErrorKind::Build(attr.to_string(), "failed to wait for the build".into()))?;
+    if !output.status.success() {
+        bail!(ErrorKind::Build(attr.to_string(), Vec::from(tail).join("\n")));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(String::from)
+        .collect())
+}
+
+/// Metadata of a package of the nixpkgs the index was built from, e.g. `openssl_3`.
+pub fn package_metadata(attr: &str) -> Result<PackageMetadata> {
+    let stdout = eval_nixpkgs(attr, Some(PACKAGE_METADATA_EXPR))
+        .map_err(|stderr| ErrorKind::Metadata(attr.to_string(), stderr))?;
+
+    Ok(serde_json::from_slice(&stdout).expect("Valid JSON from nix eval --json"))
+}
+
+/// Store path of an output of the nixpkgs the index was built from, e.g. `zlib.dev`, without building it.
+pub fn out_path(installable: &str) -> Result<String> {
+    let out_path = eval_nixpkgs_string(&format!("{}.outPath", installable))
+        .map_err(|stderr| ErrorKind::OutPath(installable.to_string(), stderr))?;
+
+    Ok(out_path)
+}
+
+/// Dynamic linker the packages of nixpkgs are linked against, e.g. for nix-ld.
+pub fn dynamic_linker() -> Result<String> {
+    const ATTR: &str = "stdenv.cc.bintools.dynamicLinker";
+    let dynamic_linker =
+        eval_nixpkgs_string(ATTR).map_err(|stderr| ErrorKind::OutPath(ATTR.to_string(), stderr))?;
+
+    Ok(dynamic_linker)
+}
+
+/// `nix-env -qaP --out-path` of the nixpkgs the index was built from, this evaluates all of it.
+pub fn list_out_paths() -> Result<String> {
+    list_out_paths_of(&nixpkgs(), None)
+}
+
+/// `nix-env -qaP --out-path` of a nixpkgs source, for another system than this one if given.
+/// Aliases are left out, they list the same packages again.
+pub fn list_out_paths_of(source: &str, system: Option<&str>) -> Result<String> {
+    let mut command = Command::new("nix-env");
+    command
+        .args(["--query", "--available", "--attr-path", "--out-path", "--file"])
+        .arg(source)
+        .args(["--arg", "config", "{ allowAliases = false; }"]);
+    if let Some(system) = system {
+        command.args(["--argstr", "system", system]);
+    }
+    let stdout = run(&mut command).map_err(ErrorKind::Listing)?;
+
+    Ok(String::from_utf8_lossy(&stdout).into_owned())
+}
+
+/// What `nix flake metadata` says about a flake reference, see `flake_metadata`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct FlakeMetadata {
+    /// Store path of the flake source
+    pub path: String,
+    /// Locked flake reference
+    pub url: String,
+    pub locked: LockedFlake,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct LockedFlake {
+    /// Local sources without one, e.g. dirty Git trees, are identified by their hash
+    pub rev: Option<String>,
+    #[serde(rename = "narHash")]
+    pub nar_hash: Option<String>,
+}
+
+/// Lock a flake reference, e.g. `github:NixOS/nixpkgs/nixos-23.11`, fetching its source if needed.
+pub fn flake_metadata(flakeref: &str) -> Result<FlakeMetadata> {
+    let args: Vec<String> = vec!["flake".into(), "metadata".into(), "--json".into(), flakeref.into()];
+    let stdout = run_nix(&args).map_err(|stderr| ErrorKind::Flake(flakeref.to_string(), stderr))?;
+
+    Ok(serde_json::from_slice(&stdout).expect("Valid JSON from nix flake metadata --json"))
+}
+
+/// Maps the packages of a flake to the output paths of their outputs, without building them.
+const FLAKE_OUTPUTS_EXPR: &str = r#"packages: builtins.mapAttrs (name: package:
+  builtins.listToAttrs (map (output: { name = output; value = package.${output}.outPath; })
+    (package.outputs or [ "out" ]))) packages"#;
+
+/// Output paths of every package of a flake for a system, by package then output,
+/// e.g. `mytool` -> `out` -> `/nix/store/…-mytool-1.0`.
+pub fn flake_package_outputs(
+    flakeref: &str,
+    system: &str,
+) -> Result<BTreeMap<String, BTreeMap<String, String>>> {
+    let installable = format!("{}#packages.{}", flakeref, system);
+    let args: Vec<String> = vec![
+        "eval".into(),
+        "--json".into(),
+        installable.clone(),
+        "--apply".into(),
+        FLAKE_OUTPUTS_EXPR.into(),
+    ];
+    let stdout = run_nix(&args).map_err(|stderr| ErrorKind::OutPath(installable, stderr))?;
+
+    Ok(serde_json::from_slice(&stdout).expect("Valid JSON from nix eval --json"))
+}
+
+/// Build the nix-index database of a nixpkgs source as `files` in `directory`,
+/// this fetches the file listing of every package and takes a long while.
+pub fn build_index(source: &str, directory: &Path) -> Result<()> {
+    run(Command::new("nix-index")
+        .arg("--nixpkgs")
+        .arg(source)
+        .arg("--db")
+        .arg(directory))
+    .map_err(ErrorKind::Index)?;
+
+    Ok(())
+}
+
+/// Seconds a validity or a closure size is remembered, see `--path-info-ttl`.
+pub const DEFAULT_PATH_INFO_TTL: u64 = 24 * 60 * 60;
+
+fn seconds_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Last change of the database of the store, e.g. by a garbage collection or a build,
+/// `None` if the store is not on this machine.
+fn store_generation() -> Option<u64> {
+    let on_this_machine = *STORE.read().unwrap() == StoreKind::Local || store_is_relocated();
+    if !on_this_machine {
+        return None;
+    }
+    let real_store_dir = STORE_LOCATION.read().unwrap().real_store_dir.clone();
+    let db_dir = Path::new(&real_store_dir).parent()?.join("var/nix/db");
+
+    // Writes go to the write-ahead log first.
+    ["db.sqlite", "db.sqlite-wal"]
+        .iter()
+        .filter_map(|name| db_dir.join(name).metadata().ok()?.modified().ok())
+        .map(seconds_since_epoch)
+        .max()
+}
+
+/// A value and when it was asked, in seconds since the UNIX epoch.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+struct Checked<T> {
+    value: T,
+    at: u64,
+}
+
+/// Validity and closure sizes of store paths, kept in `$XDG_CACHE_HOME/buildxyz/path-info.json`
+/// so that prompts and later sessions do not ask the store or the binary cache again, even offline.
+/// Validities are forgotten once the store database changed, e.g. after a garbage collection,
+/// and everything once it is older than the TTL. A zero TTL disables it.
+#[derive(Serialize, Deserialize, Default)]
+pub struct PathInfoCache {
+    /// Store, `local` or its URI -> store path -> whether it is valid there
+    validity: HashMap<String, HashMap<String, Checked<bool>>>,
+    /// Store path -> closure size, the same in every store
+    closure_sizes: HashMap<String, Checked<usize>>,
+    #[serde(skip)]
+    ttl: u64,
+    #[serde(skip)]
+    cache_filepath: Option<PathBuf>,
+}
+
+impl PathInfoCache {
+    fn load(ttl: Duration) -> Self {
+        let cache_filepath = xdg::BaseDirectories::with_prefix("buildxyz")
+            .ok()
+            .and_then(|dirs| dirs.place_cache_file("path-info.json").ok());
+        let mut cache: PathInfoCache = cache_filepath
+            .as_ref()
+            .and_then(|filepath| std::fs::read(filepath).ok())
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+        cache.cache_filepath = cache_filepath;
+        cache.set_ttl(ttl);
+
+        cache
+    }
+
+    /// Forget what is older than `ttl` from now on.
+    fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = ttl.as_secs();
+        let expiry = seconds_since_epoch(SystemTime::now()).saturating_sub(self.ttl);
+        for paths in self.validity.values_mut() {
+            paths.retain(|_, checked| checked.at > expiry);
+        }
+        self.validity.retain(|_, paths| !paths.is_empty());
+        self.closure_sizes.retain(|_, checked| checked.at > expiry);
+    }
+
+    fn save(&self) {
+        let Some(filepath) = &self.cache_filepath else {
+            return;
+        };
+        // Written aside first, another session may be reading it.
+        let partial = filepath.with_extension("json.partial");
+        let written = std::fs::write(&partial, serde_json::to_vec(self).unwrap())
+            .and_then(|_| std::fs::rename(&partial, filepath));
+        if let Err(err) = written {
+            warn!(
+                "Failed to cache the path info in {}: {}",
+                filepath.display(),
+                err
+            );
+        }
+    }
+
+    fn is_fresh(&self, at: u64, now: u64) -> bool {
+        now < at.saturating_add(self.ttl)
+    }
+
+    /// Whether the store path is valid in `store`, if asked since the last change of the store `generation`.
+    fn validity(&self, store: &str, path: &str, generation: Option<u64>, now: u64) -> Option<bool> {
+        let checked = self.validity.get(store)?.get(path)?;
+        // Changes within the same second as the check are not told apart.
+        let unchanged = generation.map_or(true, |generation| generation < checked.at);
+
+        (unchanged && self.is_fresh(checked.at, now)).then_some(checked.value)
+    }
+
+    fn closure_size(&self, path: &str, now: u64) -> Option<usize> {
+        let checked = self.closure_sizes.get(path)?;
+        self.is_fresh(checked.at, now).then_some(checked.value)
+    }
+
+    fn remember_validity(&mut self, store: &str, path: &str, valid: bool, now: u64) {
+        if self.ttl == 0 {
+            return;
+        }
+        self.validity
+            .entry(store.to_string())
+            .or_default()
+            .insert(path.to_string(), Checked { value: valid, at: now });
+        self.save();
+    }
+
+    fn remember_closure_size(&mut self, path: &str, size: usize, now: u64) {
+        if self.ttl == 0 {
+            return;
+        }
+        self.closure_sizes
+            .insert(path.to_string(), Checked { value: size, at: now });
+        self.save();
+    }
+}
+
+/// Remember validities and closure sizes this long, zero to always ask again.
+pub fn set_path_info_ttl(ttl: Duration) {
+    PATH_INFO.lock().unwrap().set_ttl(ttl);
+}
+
+/// Key of the configured store in the `PathInfoCache`.
+fn store_key() -> String {
+    match &*STORE.read().unwrap() {
+        StoreKind::Local => "local".into(),
+        StoreKind::Remote(uri) => uri.clone(),
+    }
+}
+
+/// Ask the store to realize the provided path, or the store path containing it.
+pub fn realize_path(path: String) -> Result<()> {
+    realize_path_reporting(&path, &mut |_, _| {})
+}
+
+/// Realize a path like `realize_path`, telling the bytes copied so far and expected.
+pub fn realize_path_reporting(path: &str, on_progress: &mut dyn FnMut(u64, u64)) -> Result<()> {
+    let root = store_path_root(path);
+    // Most paths are there already, which is much cheaper to ask.
+    if is_valid_path(root) {
+        return Ok(());
+    }
+
+    store_backend().realize(root, on_progress)?;
+    PATH_INFO.lock().unwrap().remember_validity(
+        &store_key(),
+        root,
+        true,
+        seconds_since_epoch(SystemTime::now()),
+    );
+
+    Ok(())
+}
+
+/// Talks to the store with `nix-store` and `nix` commands.
+pub struct CommandStore(pub StoreKind);
+
+impl CommandStore {
+    pub fn local() -> Self {
+        CommandStore(StoreKind::Local)
+    }
+
+    fn nix_store(&self) -> Command {
+        let mut command = Command::new("nix-store");
+        if let StoreKind::Remote(uri) = &self.0 {
+            command.args(["--store", uri]);
+        }
+        command
+    }
+}
+
+impl StoreBackend for CommandStore {
+    fn is_valid(&self, path: &str) -> Result<bool> {
+        let status = self
+            .nix_store()
+            .args(["--check-validity", "--quiet", path])
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .chain_err(|| "failed to run nix-store --check-validity")?;
+
+        Ok(status.success())
     }
+
+    fn realize(&self, path: &str, _on_progress: &mut dyn FnMut(u64, u64)) -> Result<()> {
+        let nixpkgs_path = nixpkgs();
+        // TODO: send back this information to the meta-panel of the TUI
+        let output = self
+            .nix_store()
+            .arg("--realize")
+            .arg(path)
+            .env("NIX_PATH", format!("nixpkgs={}", nixpkgs_path))
+            .stdin(Stdio::null())
+            .output()
+            .chain_err(|| "failed to run nix-store --realize")?;
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            // TODO: more precise errors.
+            bail!(ErrorKind::InvalidPath)
+        }
+    }
+
+    fn closure_size(&self, path: &str) -> Option<usize> {
+        get_path_size(path, self.0.clone())
+    }
+}
+
+/// Binary cache asked for the closure size of paths which are not in the local store.
+pub const BINARY_CACHE: &str = "https://cache.nixos.org";
+
+/// Whether the store path is in the local store, nothing is substituted.
+/// The answer is remembered in the `PathInfoCache`.
+pub fn is_valid_path(path: &str) -> bool {
+    let store = store_key();
+    let now = seconds_since_epoch(SystemTime::now());
+    if let Some(valid) = PATH_INFO
+        .lock()
+        .unwrap()
+        .validity(&store, path, store_generation(), now)
+    {
+        trace!("{} is cached as valid: {}", path, valid);
+        return valid;
+    }
+
+    let valid = store_backend().is_valid(path).unwrap_or(false);
+    PATH_INFO
+        .lock()
+        .unwrap()
+        .remember_validity(&store, path, valid, now);
+
+    valid
+}
+
+/// A list setting of the Nix configuration, `None` if it cannot be read.
+fn config_list(name: &str) -> Option<Vec<String>> {
+    // Earlier than `nix-command`.
+    let config = run(nix_command().args(store_args()).args(["show-config", "--json"]))
+        .map_err(|stderr| debug!("failed to read the Nix configuration: {}", stderr))
+        .ok()
+        .and_then(|stdout| serde_json::from_slice::<serde_json::Value>(&stdout).ok());
+
+    config
+        .as_ref()
+        .and_then(|config| config.get(name)?.get("value")?.as_array())
+        .map(|values| {
+            values
+                .iter()
+                .filter_map(|value| value.as_str().map(String::from))
+                .collect()
+        })
+}
+
+/// `substituters` of the Nix configuration, the binary cache if it cannot be read.
+pub fn substituters() -> Vec<String> {
+    config_list("substituters").unwrap_or_else(|| vec![BINARY_CACHE.to_string()])
+}
+
+/// `trusted-public-keys` of the Nix configuration, e.g. `cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=`.
+pub fn trusted_public_keys() -> Vec<String> {
+    config_list("trusted-public-keys").unwrap_or_default()
 }
 
-#[derive(Deserialize)]
-struct PathInfo {
-    #[serde(rename = "closureSize")]
-    closure_size: Option<usize>,
+/// `nix path-info --json` of these store paths on a substituter, or else on the store the `nix` commands use.
+/// The output is kept when some of them are not valid, they are reported as such.
+pub fn path_info(paths: &[String], substituter: Option<&str>) -> Vec<u8> {
+    let mut command = nix_command();
+    command.args(["path-info", "--json"]);
+    match substituter {
+        Some(substituter) => command.args(["--store", substituter]),
+        None => command.args(store_args()),
+    };
+    let output = match command.args(paths).stdin(Stdio::null()).output() {
+        Ok(output) => output,
+        Err(err) => {
+            debug!("failed to run nix path-info: {}", err);
+            return Vec::new();
+        }
+    };
+    if !output.status.success() {
+        debug!(
+            "nix path-info: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    output.stdout
 }
 
 /// Returns `nix path-info -S <path> --store <store> if there's any remote store.
 /// If the path is invalid, None is returned.
 /// This returns the closure size.
 pub fn get_path_size(path: &str, store: StoreKind) -> Option<usize> {
-    let mut cmd0 = Command::new("nix");
+    if cli().nix.is_none() {
+        return match store {
+            StoreKind::Local => legacy_closure_size(path),
+            // `nix-store` cannot ask binary caches.
+            StoreKind::Remote(_) => None,
+        };
+    }
+    let mut cmd0 = nix_command();
     let mut cmd = cmd0.arg("path-info").arg("--json").arg("-S").arg(path);
 
     cmd = match store {
@@ -51,18 +912,190 @@ pub fn get_path_size(path: &str, store: StoreKind) -> Option<usize> {
         StoreKind::Remote(remote_store) => cmd.arg("--store").arg(remote_store),
     };
 
-    let output = cmd.output().expect("Failed to extract path information");
+    let output = cmd.output().ok()?;
 
     trace!(
         "nix path-info output: {}",
         String::from_utf8_lossy(&output.stdout)
     );
 
-    if output.status.success() {
-        let pinfos: Vec<PathInfo> =
-            serde_json::from_slice(&output.stdout).expect("Valid JSON from nix path-info --json");
-        pinfos.first().expect("At least one path-info").closure_size
-    } else {
-        None
+    if !output.status.success() {
+        return None;
+    }
+    closure_size_of(&output.stdout)
+}
+
+/// Closure size of a valid store path with `nix-store`, when `nix` is not there.
+fn legacy_closure_size(path: &str) -> Option<usize> {
+    let requisites = run(Command::new("nix-store").args(["--query", "--requisites", path])).ok()?;
+    let requisites = String::from_utf8_lossy(&requisites);
+    let sizes = run(Command::new("nix-store")
+        .args(["--query", "--size"])
+        .args(requisites.lines()))
+    .ok()?;
+
+    String::from_utf8_lossy(&sizes)
+        .lines()
+        .map(|size| size.trim().parse::<usize>().ok())
+        .sum()
+}
+
+/// Closure size in the output of `nix path-info --json -S`.
+fn closure_size_of(stdout: &[u8]) -> Option<usize> {
+    let pinfos: serde_json::Value =
+        serde_json::from_slice(stdout).expect("Valid JSON from nix path-info --json");
+    // Older Nix print a list of path infos, newer ones an object keyed by store path.
+    let pinfo = match &pinfos {
+        serde_json::Value::Array(pinfos) => pinfos.first(),
+        serde_json::Value::Object(pinfos) => pinfos.values().next(),
+        _ => None,
+    }?;
+
+    pinfo
+        .get("closureSize")
+        .and_then(serde_json::Value::as_u64)
+        .map(|size| size as usize)
+}
+
+/// Closure size of a store path, from the local store or else the binary cache.
+pub fn closure_size(path: &str) -> Option<usize> {
+    let now = seconds_since_epoch(SystemTime::now());
+    if let Some(size) = PATH_INFO.lock().unwrap().closure_size(path, now) {
+        return Some(size);
+    }
+
+    let size = store_backend()
+        .closure_size(path)
+        .or_else(|| get_path_size(path, StoreKind::Remote(BINARY_CACHE.into())))?;
+    PATH_INFO
+        .lock()
+        .unwrap()
+        .remember_closure_size(path, size, now);
+
+    Some(size)
+}
+
+/// Number of files under a path, from the local store or else the binary cache.
+/// If the path is in neither, None is returned.
+pub fn count_files(path: &str) -> Option<usize> {
+    let args: Vec<String> = vec!["store".into(), "ls".into(), "--json".into(), "-R".into(), path.into()];
+    let stdout = run_nix(&args)
+        .or_else(|_| {
+            let mut args = args.clone();
+            args.extend(["--store".into(), BINARY_CACHE.into()]);
+            run_nix(&args)
+        })
+        .ok()?;
+    trace!("nix store ls output: {}", String::from_utf8_lossy(&stdout));
+
+    let listing: serde_json::Value =
+        serde_json::from_slice(&stdout).expect("Valid JSON from nix store ls --json");
+    Some(files_in_listing(&listing))
+}
+
+/// Regular files and symlinks in the output of `nix store ls --json -R`.
+fn files_in_listing(listing: &serde_json::Value) -> usize {
+    match listing.get("entries").and_then(serde_json::Value::as_object) {
+        Some(entries) => entries.values().map(files_in_listing).sum(),
+        None if listing["type"] == "directory" => 0,
+        None => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_store_path_root() {
+        let root = "/nix/store/zy4xhzc6x6ldrfzqzfa4x3cw8ds1mgcn-zlib-1.3-dev";
+        assert_eq!(store_path_root(&format!("{}/include/zlib.h", root)), root);
+        assert_eq!(store_path_root(root), root);
+    }
+
+    #[test]
+    fn test_relocated_stores() {
+        let root = "/nix/store/zy4xhzc6x6ldrfzqzfa4x3cw8ds1mgcn-zlib-1.3-dev";
+        assert_eq!(StoreLocation::of("ssh-ng://builder"), StoreLocation::default());
+
+        let chroot = StoreLocation::of("/home/me/.local/nix");
+        assert_eq!(chroot.store_dir, "/nix/store");
+        assert_eq!(
+            chroot.real_path(&format!("{}/include/zlib.h", root)),
+            "/home/me/.local/nix/nix/store/zy4xhzc6x6ldrfzqzfa4x3cw8ds1mgcn-zlib-1.3-dev/include/zlib.h"
+        );
+        assert_eq!(chroot.real_path("/usr/include/zlib.h"), "/usr/include/zlib.h");
+        assert_eq!(chroot.real_path("/nix/storefront"), "/nix/storefront");
+        assert_eq!(StoreLocation::of("local?root=/home/me/.local/nix/"), chroot);
+
+        let relocated = StoreLocation::of("local?store=/home/me/nix/store&real=/scratch/store");
+        assert_eq!(relocated.store_dir, "/home/me/nix/store");
+        assert_eq!(
+            relocated.real_path("/home/me/nix/store/zy4x-zlib-1.3"),
+            "/scratch/store/zy4x-zlib-1.3"
+        );
+    }
+
+    #[test]
+    fn test_closure_size_of_any_path_info_format() {
+        let list = br#"[{"path":"/nix/store/zy4x-zlib-1.3","closureSize":162640}]"#;
+        let object = br#"{"/nix/store/zy4x-zlib-1.3":{"closureSize":162640}}"#;
+        assert_eq!(closure_size_of(list), Some(162640));
+        assert_eq!(closure_size_of(object), Some(162640));
+        assert_eq!(closure_size_of(b"[]"), None);
+    }
+
+    #[test]
+    fn test_only_exported_dev_variables_are_kept() {
+        let variables = exported_variables(
+            br#"{"variables":{
+                "PATH":{"type":"exported","value":"/nix/store/zy4x-cmake-3.27/bin"},
+                "HOME":{"type":"exported","value":"/homeless-shelter"},
+                "buildPhase":{"type":"var","value":"make"},
+                "outputs":{"type":"array","value":["out"]}
+            }}"#,
+        );
+        assert_eq!(
+            variables,
+            HashMap::from([("PATH".to_string(), "/nix/store/zy4x-cmake-3.27/bin".to_string())])
+        );
+    }
+
+    #[test]
+    fn test_files_in_listing_are_counted_recursively() {
+        let listing: serde_json::Value = serde_json::from_str(
+            r#"{"type":"directory","entries":{
+                "ssl.h":{"type":"regular","size":1},
+                "empty":{"type":"directory","entries":{}},
+                "internal":{"type":"directory","entries":{"a.h":{"type":"regular"},"b.h":{"type":"symlink","target":"a.h"}}}
+            }}"#,
+        )
+        .unwrap();
+        assert_eq!(files_in_listing(&listing), 3);
+        assert_eq!(files_in_listing(&serde_json::json!({"type": "regular"})), 1);
+    }
+
+    #[test]
+    fn test_path_info_is_remembered_until_the_store_changes() {
+        let zlib = "/nix/store/zy4xhzc6x6ldrfzqzfa4x3cw8ds1mgcn-zlib-1.3";
+        let mut cache = PathInfoCache {
+            ttl: 100,
+            ..Default::default()
+        };
+        cache.remember_validity("local", zlib, true, 1000);
+        cache.remember_closure_size(zlib, 125352, 1000);
+
+        assert_eq!(cache.validity("local", zlib, Some(999), 1050), Some(true));
+        assert_eq!(cache.validity("local", zlib, None, 1050), Some(true));
+        // Garbage collected since, maybe.
+        assert_eq!(cache.validity("local", zlib, Some(1000), 1050), None);
+        assert_eq!(cache.validity("ssh-ng://builder", zlib, None, 1050), None);
+        assert_eq!(cache.validity("local", zlib, None, 1100), None);
+        assert_eq!(cache.closure_size(zlib, 1099), Some(125352));
+        assert_eq!(cache.closure_size(zlib, 1100), None);
+
+        let mut disabled = PathInfoCache::default();
+        disabled.remember_closure_size(zlib, 125352, 1000);
+        assert_eq!(disabled.closure_size(zlib, 1000), None);
     }
 }
@@ -0,0 +1,223 @@
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use std::fmt;
+use std::process::{Command, Stdio};
+
+lazy_static! {
+    /// What the installed Nix understands, detected on first use.
+    static ref CLI: NixCli = NixCli::detect();
+}
+
+/// Earliest Nix whose `nix-store`, `nix-build` and `nix-instantiate` do everything BuildXYZ needs.
+const MIN_LEGACY: NixVersion = NixVersion(2, 3, 0);
+/// Earliest Nix taking `--extra-experimental-features`, with the `nix-command` subcommands used and flakes.
+const MIN_NIX_COMMAND: NixVersion = NixVersion(2, 4, 0);
+/// Earliest Nix with `nix build --print-out-paths`.
+const MIN_PRINT_OUT_PATHS: NixVersion = NixVersion(2, 8, 0);
+
+/// Version of a Nix command, Lix and other distributions of Nix follow its numbering.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct NixVersion(pub u32, pub u32, pub u32);
+
+impl NixVersion {
+    /// From the output of `--version`, e.g. `nix (Nix) 2.18.1`, `nix-store (Nix) 2.3.16`
+    /// or `nix (Lix, like Nix) 2.90.0`. Suffixes like `pre20240101_dirty` are left out.
+    pub fn parse(output: &str) -> Option<Self> {
+        let version = output.split_whitespace().last()?;
+        let mut numbers = version.split('.').map(|number| {
+            let digits = number
+                .find(|c: char| !c.is_ascii_digit())
+                .map_or(number, |end| &number[..end]);
+            digits.parse::<u32>().ok()
+        });
+        let major = numbers.next()??;
+        let minor = numbers.next()??;
+        let patch = numbers.next().flatten().unwrap_or(0);
+
+        Some(NixVersion(major, minor, patch))
+    }
+}
+
+impl fmt::Display for NixVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+/// Version of a command from its `--version`, `None` if it is not installed or does not run.
+fn version_of(program: &str) -> Option<NixVersion> {
+    let output = Command::new(program)
+        .arg("--version")
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|err| debug!("failed to run {}: {}", program, err))
+        .ok()?;
+
+    output
+        .status
+        .success()
+        .then(|| NixVersion::parse(&String::from_utf8_lossy(&output.stdout)))
+        .flatten()
+}
+
+/// What the installed Nix understands, to pick the commands `nix.rs` runs.
+/// `nix` and the legacy commands are told apart: they may come from different installations.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NixCli {
+    /// Version of `nix`, `None` if it does not run
+    pub nix: Option<NixVersion>,
+    /// Version of `nix-store`, standing for `nix-build` and `nix-instantiate`
+    pub nix_store: Option<NixVersion>,
+}
+
+impl NixCli {
+    fn detect() -> Self {
+        let cli = NixCli {
+            nix: version_of("nix"),
+            nix_store: version_of("nix-store"),
+        };
+        debug!("nix is {:?}, nix-store is {:?}", cli.nix, cli.nix_store);
+
+        cli
+    }
+
+    /// Whether `nix` has the subcommands BuildXYZ uses, e.g. `nix eval --apply`, and flakes.
+    /// They are enabled with `--extra-experimental-features`, whatever the Nix configuration says.
+    pub fn has_nix_command(&self) -> bool {
+        self.nix.map_or(false, |version| version >= MIN_NIX_COMMAND)
+    }
+
+    /// Whether `nix build` prints the output paths it built, `nix-build` is run otherwise.
+    pub fn has_print_out_paths(&self) -> bool {
+        self.nix
+            .map_or(false, |version| version >= MIN_PRINT_OUT_PATHS)
+    }
+
+    /// Arguments enabling the experimental features used, before any `nix` subcommand.
+    /// Earlier versions reject them.
+    pub fn feature_args(&self) -> &'static [&'static str] {
+        if self.has_nix_command() {
+            &["--extra-experimental-features", "nix-command flakes"]
+        } else {
+            &[]
+        }
+    }
+
+    /// Why something needing `nix` with `nix-command` and flakes, e.g. `nix flake metadata`, cannot be done.
+    pub fn require_nix_command(&self, what: &str) -> Result<(), String> {
+        match self.nix {
+            _ if self.has_nix_command() => Ok(()),
+            Some(version) => Err(format!(
+                "{} needs Nix {} or later, `nix` is {}",
+                what, MIN_NIX_COMMAND, version
+            )),
+            None => Err(format!(
+                "{} needs `nix` {} or later, it is not in the PATH",
+                what, MIN_NIX_COMMAND
+            )),
+        }
+    }
+
+    /// Report what BuildXYZ falls back on with this Nix, an error if nothing compatible is installed.
+    pub fn check(&self) -> Result<(), String> {
+        let legacy = match self.nix_store {
+            None => {
+                return Err(format!(
+                    "`nix-store` is not in the PATH, BuildXYZ needs Nix {} or later",
+                    MIN_LEGACY
+                ))
+            }
+            Some(version) if version < MIN_LEGACY => {
+                return Err(format!(
+                    "`nix-store` is {}, BuildXYZ needs Nix {} or later",
+                    version, MIN_LEGACY
+                ))
+            }
+            Some(version) => version,
+        };
+
+        match self.nix {
+            Some(version) if version != legacy => warn!(
+                "`nix` is {} but `nix-store` is {}, they come from different Nix installations: check the PATH",
+                version, legacy
+            ),
+            _ => {}
+        }
+        match self.nix {
+            _ if self.has_nix_command() => {
+                if !self.has_print_out_paths() {
+                    info!("Nix {} builds with `nix-build`", self.nix.unwrap());
+                }
+            }
+            Some(version) => warn!(
+                "`nix` is {}, evaluating with `nix-instantiate` and building with `nix-build`: \
+                 development shells, flake installables and file counts need Nix {} or later",
+                version, MIN_NIX_COMMAND
+            ),
+            None => warn!(
+                "`nix` is not in the PATH, evaluating with `nix-instantiate` and building with `nix-build`: \
+                 development shells, flake installables and file counts need it"
+            ),
+        }
+
+        Ok(())
+    }
+}
+
+/// What the installed Nix understands.
+pub fn cli() -> &'static NixCli {
+    &CLI
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commands_follow_the_nix_version() {
+        assert_eq!(
+            NixVersion::parse("nix (Nix) 2.18.1\n"),
+            Some(NixVersion(2, 18, 1))
+        );
+        assert_eq!(
+            NixVersion::parse("nix (Lix, like Nix) 2.90.0"),
+            Some(NixVersion(2, 90, 0))
+        );
+        assert_eq!(
+            NixVersion::parse("nix (Nix) 2.24.0pre20240101_dirty"),
+            Some(NixVersion(2, 24, 0))
+        );
+        assert_eq!(
+            NixVersion::parse("nix (Nix) 2.4"),
+            Some(NixVersion(2, 4, 0))
+        );
+        assert_eq!(NixVersion::parse("command not found"), None);
+
+        let mixed = NixCli {
+            nix: Some(NixVersion(2, 3, 16)),
+            nix_store: Some(NixVersion(2, 18, 1)),
+        };
+        assert!(!mixed.has_nix_command());
+        assert!(mixed.feature_args().is_empty());
+        assert_eq!(
+            mixed.require_nix_command("`--develop`"),
+            Err("`--develop` needs Nix 2.4.0 or later, `nix` is 2.3.16".to_string())
+        );
+        assert_eq!(mixed.check(), Ok(()));
+
+        let recent = NixCli {
+            nix: Some(NixVersion(2, 18, 1)),
+            nix_store: Some(NixVersion(2, 18, 1)),
+        };
+        assert!(recent.has_nix_command() && recent.has_print_out_paths());
+        assert_eq!(recent.feature_args().len(), 2);
+
+        let missing = NixCli {
+            nix: None,
+            nix_store: None,
+        };
+        assert!(missing.check().is_err());
+        assert!(missing.require_nix_command("flakes").is_err());
+    }
+}
@@ -0,0 +1,159 @@
+use log::{info, warn};
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::nix::{dynamic_linker, real_path};
+use crate::resolution::{Decision, ResolutionDB};
+
+/// Runs a program with the libraries of the session through the dynamic linker, without nix-ld.
+const LD_WRAPPER: &str = r#"#!/bin/sh
+# Generated by buildxyz: run a dynamically linked program with the libraries provided during the session.
+exec "${NIX_LD:?}" --library-path "$NIX_LD_LIBRARY_PATH" "$@"
+"#;
+
+/// Name of the wrapper in the `bin/` of the fast working tree, see `--nix-ld`.
+pub const LD_WRAPPER_NAME: &str = "buildxyz-run";
+
+/// Whether a requested path is a shared library, e.g. `lib/libz.so` or `lib/libz.so.1.3`.
+fn is_shared_library(requested_path: &str) -> bool {
+    let Some(file_name) = requested_path.strip_prefix("lib/") else {
+        return false;
+    };
+    let file_name = file_name.rsplit('/').next().unwrap_or(file_name);
+
+    file_name.ends_with(".so") || file_name.contains(".so.")
+}
+
+/// Directories of the shared libraries provided by the resolutions, e.g. `/nix/store/…-zlib-1.3/lib`,
+/// each once.
+pub fn library_dirs(db: &ResolutionDB) -> Vec<PathBuf> {
+    let mut dirs = BTreeSet::new();
+    for data in db.values().flat_map(|resolution| resolution.all_data()) {
+        let Decision::Provide(provide_data) = &data.decision else {
+            continue;
+        };
+        if !is_shared_library(&data.requested_path) {
+            continue;
+        }
+        // Pattern resolutions serve the requested path itself.
+        let file_entry_name = if provide_data.file_entry_name.is_empty() {
+            data.requested_path.as_str()
+        } else {
+            provide_data.file_entry_name.trim_start_matches('/')
+        };
+        let provided =
+            Path::new(&real_path(&provide_data.store_path.as_str())).join(file_entry_name);
+        if let Some(dir) = provided.parent() {
+            dirs.insert(dir.to_owned());
+        }
+    }
+
+    dirs.into_iter().collect()
+}
+
+/// Let nix-ld find the libraries of the session when the built programs run:
+/// those of the loaded resolutions first, then the ones provided during the session
+/// through the fast working tree, and those which are not provided yet through the mount.
+/// The programs can also be run with the `buildxyz-run` wrapper written in the fast working tree.
+pub fn inject(
+    env: &mut HashMap<String, String>,
+    library_dirs: &[PathBuf],
+    fast_working_root: &Path,
+    mountpoint: &Path,
+) -> io::Result<()> {
+    let mut search_path: Vec<String> = library_dirs
+        .iter()
+        .chain([&fast_working_root.join("lib"), &mountpoint.join("lib")])
+        .map(|dir| dir.display().to_string())
+        .collect();
+    if let Some(existing) = env
+        .get("NIX_LD_LIBRARY_PATH")
+        .filter(|existing| !existing.is_empty())
+    {
+        search_path.push(existing.clone());
+    }
+    env.insert("NIX_LD_LIBRARY_PATH".into(), search_path.join(":"));
+
+    if !env.contains_key("NIX_LD") {
+        match dynamic_linker() {
+            Ok(linker) => {
+                env.insert("NIX_LD".into(), linker);
+            }
+            Err(err) => warn!(
+                "NIX_LD is not set and the dynamic linker of nixpkgs is unknown: {}",
+                err
+            ),
+        }
+    }
+
+    let bin = fast_working_root.join("bin");
+    std::fs::create_dir_all(&bin)?;
+    let wrapper = bin.join(LD_WRAPPER_NAME);
+    std::fs::write(&wrapper, LD_WRAPPER)?;
+    std::fs::set_permissions(&wrapper, std::fs::Permissions::from_mode(0o755))?;
+    info!(
+        "Built programs find the provided libraries through nix-ld, or run them with `{}`",
+        LD_WRAPPER_NAME
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resolution::read_resolution_db;
+
+    #[test]
+    fn test_library_dirs_of_provided_shared_libraries() {
+        let db = read_resolution_db(
+            r#"
+["lib/libz.so.1"]
+decision = "provide"
+file_entry_name = "/lib/libz.so.1"
+kind = "symlink"
+
+["lib/libz.so.1".store_path]
+hash = "zy4xhzc6x6ldrfzqzfa4x3cw8ds1mgcn"
+name = "zlib-1.3"
+store_dir = "/nix/store"
+
+["lib/libz.so.1".store_path.origin]
+attr = "zlib"
+output = "out"
+toplevel = true
+
+["lib/libz.a"]
+decision = "ignore"
+
+["lib/pkgconfig/zlib.pc"]
+decision = "provide"
+file_entry_name = "/lib/pkgconfig/zlib.pc"
+kind = "regular-file"
+
+["lib/pkgconfig/zlib.pc".store_path]
+hash = "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"
+name = "zlib-1.3-dev"
+store_dir = "/nix/store"
+
+["lib/pkgconfig/zlib.pc".store_path.origin]
+attr = "zlib"
+output = "dev"
+toplevel = true
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            library_dirs(&db),
+            [PathBuf::from(
+                "/nix/store/zy4xhzc6x6ldrfzqzfa4x3cw8ds1mgcn-zlib-1.3/lib"
+            )]
+        );
+        assert!(is_shared_library("lib/x86_64-linux-gnu/libssl.so.3"));
+        assert!(!is_shared_library("lib/libssl.a"));
+        assert!(!is_shared_library("include/libsomething.so.h"));
+    }
+}
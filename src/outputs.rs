@@ -0,0 +1,74 @@
+use crate::cache::PathOrigin;
+
+/// Outputs of split packages usually holding a directory, most likely first.
+/// See the `outputs` conventions of the nixpkgs manual.
+const OUTPUT_CONVENTIONS: [(&str, &[&str]); 9] = [
+    ("include/", &["dev", "out"]),
+    ("lib/pkgconfig/", &["dev", "out"]),
+    ("lib/cmake/", &["dev", "out"]),
+    ("share/aclocal/", &["dev", "out"]),
+    ("share/pkgconfig/", &["dev", "out"]),
+    ("bin/", &["bin", "out", "dev"]),
+    ("sbin/", &["bin", "out"]),
+    ("lib/", &["lib", "out"]),
+    ("share/man/", &["man", "out"]),
+];
+
+/// Outputs expected to provide this requested path, most likely first.
+pub fn preferred_outputs(requested_path: &str) -> &'static [&'static str] {
+    OUTPUT_CONVENTIONS
+        .iter()
+        .find(|(prefix, _)| requested_path.starts_with(prefix))
+        .map_or(&["out"], |(_, outputs)| outputs)
+}
+
+/// Rank of an output for this requested path, lower is better.
+/// Unexpected outputs come last, they may only provide the path through a symlink.
+pub fn output_rank(requested_path: &str, output: &str) -> usize {
+    let outputs = preferred_outputs(requested_path);
+
+    outputs
+        .iter()
+        .position(|preferred| *preferred == output)
+        .unwrap_or(outputs.len())
+}
+
+/// `zlib.dev` for an output other than the default one, `zlib` otherwise.
+pub fn installable(origin: &PathOrigin) -> String {
+    if origin.output == "out" {
+        origin.attr.clone()
+    } else {
+        format!("{}.{}", origin.attr, origin.output)
+    }
+}
+
+/// `installable` with the index it was found in when several are searched, e.g. `zlib.dev (unstable index)`.
+pub fn label(origin: &PathOrigin) -> String {
+    match &origin.index {
+        Some(index) => format!("{} ({} index)", installable(origin), index),
+        None => installable(origin),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_headers_prefer_the_dev_output() {
+        assert!(output_rank("include/zlib.h", "dev") < output_rank("include/zlib.h", "out"));
+        assert!(output_rank("lib/libz.so", "lib") < output_rank("lib/libz.so", "dev"));
+        assert_eq!(preferred_outputs("share/zlib/README"), &["out"]);
+
+        let mut origin = PathOrigin {
+            attr: "zlib".into(),
+            output: "dev".into(),
+            toplevel: true,
+            system: None,
+            index: None,
+        };
+        assert_eq!(label(&origin), "zlib.dev");
+        origin.index = Some("unstable".into());
+        assert_eq!(label(&origin), "zlib.dev (unstable index)");
+    }
+}
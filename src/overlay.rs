@@ -0,0 +1,237 @@
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::export::is_nix_identifier;
+use crate::pin::IndexPin;
+use crate::process::ProcessInfo;
+
+/// What happens to the paths no package provides once the command exits, see `--missing-overlays`.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MissingOverlays {
+    /// Offer to write a stub overlay entry for each missing package, when someone can answer
+    #[default]
+    Ask,
+    /// Write them all without asking, e.g. in CI
+    Write,
+    /// Write none
+    Never,
+}
+
+/// A lookup of this session which no candidate answered, not even on the host.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MissingPath {
+    pub requested_path: String,
+    pub phase: Option<String>,
+    pub requested_by: ProcessInfo,
+}
+
+/// Missing paths which likely belong to the same package, by the name guessed from them.
+#[derive(Debug, PartialEq, Eq)]
+pub struct MissingPackage {
+    pub name: String,
+    pub paths: Vec<MissingPath>,
+}
+
+/// Name of the package a requested path likely comes from, e.g. `foo` for `lib/pkgconfig/foo.pc`,
+/// `include/foo/bar.h` or `lib/libfoo.so.1`.
+fn package_name(requested_path: &str) -> String {
+    let components: Vec<&str> = requested_path
+        .split('/')
+        .filter(|component| !component.is_empty())
+        .collect();
+    let name = match components.as_slice() {
+        [.., "pkgconfig" | "aclocal", file] => file,
+        ["lib" | "lib64" | "share", "cmake", dir, ..] | ["include", dir, _, ..] => dir,
+        ["lib" | "lib64", file] => file.strip_prefix("lib").unwrap_or(file),
+        [.., file] => file,
+        [] => "",
+    };
+    let name: String = name
+        .split('.')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect();
+
+    match name.trim_matches('-') {
+        "" => "unknown".into(),
+        name => name.into(),
+    }
+}
+
+/// Group the missing paths by package, each path once.
+pub fn group(paths: &[MissingPath]) -> Vec<MissingPackage> {
+    let mut packages: BTreeMap<String, Vec<MissingPath>> = BTreeMap::new();
+    for path in paths {
+        let known = packages
+            .entry(package_name(&path.requested_path))
+            .or_default();
+        if !known
+            .iter()
+            .any(|known| known.requested_path == path.requested_path)
+        {
+            known.push(path.clone());
+        }
+    }
+
+    packages
+        .into_iter()
+        .map(|(name, paths)| MissingPackage { name, paths })
+        .collect()
+}
+
+/// An overlay adding a stub of the missing package, with what a packager needs to write it:
+/// the paths it must provide, which processes asked for them and the nixpkgs they are missing from.
+pub fn render_stub(package: &MissingPackage, pin: &IndexPin, command: Option<&str>) -> String {
+    let attr = if is_nix_identifier(&package.name) {
+        package.name.clone()
+    } else {
+        format!("{:?}", package.name)
+    };
+
+    let mut stub = String::new();
+    let _ = writeln!(
+        stub,
+        "# Stub of {}: no package of {} provides the paths it was requested for.",
+        package.name, pin
+    );
+    match command {
+        Some(command) => {
+            let _ = writeln!(
+                stub,
+                "# Generated by buildxyz while running `{}`, complete the TODOs.",
+                command
+            );
+        }
+        None => {
+            let _ = writeln!(stub, "# Generated by buildxyz, complete the TODOs.");
+        }
+    }
+    let _ = writeln!(stub, "final: prev: {{");
+    let _ = writeln!(
+        stub,
+        "  # TODO: check the name, it is guessed from the requested paths"
+    );
+    let _ = writeln!(stub, "  {} = final.stdenv.mkDerivation {{", attr);
+    let _ = writeln!(stub, "    pname = {:?};", package.name);
+    let _ = writeln!(stub, "    # TODO: version and sources");
+    let _ = writeln!(stub, "    version = \"unstable\";");
+    let _ = writeln!(stub, "    src = null;\n");
+    let _ = writeln!(stub, "    # TODO: inputs");
+    let _ = writeln!(stub, "    nativeBuildInputs = [ ];");
+    let _ = writeln!(stub, "    buildInputs = [ ];\n");
+    let _ = writeln!(
+        stub,
+        "    # Requested during the session, it must provide them:"
+    );
+    for path in &package.paths {
+        let _ = write!(
+            stub,
+            "    #   {}, by {}",
+            path.requested_path, path.requested_by
+        );
+        if let Some(phase) = &path.phase {
+            let _ = write!(stub, ", during {}", phase);
+        }
+        stub.push('\n');
+    }
+    let _ = writeln!(
+        stub,
+        "    meta = {{\n      description = \"TODO\";\n    }};"
+    );
+    let _ = writeln!(stub, "  }};\n}}");
+
+    stub
+}
+
+/// Write the stub overlay of each package as `missing-<name>.nix` under `dir`, e.g. `.buildxyz/overlays`.
+/// Existing files are left as they are, a packager may have started on them.
+pub fn write_stubs(
+    dir: &Path,
+    packages: &[&MissingPackage],
+    pin: &IndexPin,
+    command: Option<&str>,
+) -> io::Result<Vec<PathBuf>> {
+    std::fs::create_dir_all(dir)?;
+    let mut written = Vec::new();
+    for package in packages {
+        let filepath = dir.join(format!("missing-{}.nix", package.name));
+        if filepath.exists() {
+            warn!("{} already exists, leaving it as is", filepath.display());
+            continue;
+        }
+        std::fs::write(&filepath, render_stub(package, pin, command))?;
+        info!(
+            "Wrote the stub of {} to {}",
+            package.name,
+            filepath.display()
+        );
+        written.push(filepath);
+    }
+
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_paths_are_grouped_into_stubs() {
+        assert_eq!(package_name("lib/pkgconfig/foo.pc"), "foo");
+        assert_eq!(package_name("include/foo/bar.h"), "foo");
+        assert_eq!(package_name("include/foo.h"), "foo");
+        assert_eq!(package_name("lib/libfoo.so.1"), "foo");
+        assert_eq!(package_name("lib/cmake/Foo/FooConfig.cmake"), "foo");
+        assert_eq!(package_name("share/aclocal/gtk-doc.m4"), "gtk-doc");
+        assert_eq!(package_name("bin/protoc-gen-go"), "protoc-gen-go");
+        assert_eq!(package_name("lib/.."), "unknown");
+
+        let requested_by = ProcessInfo {
+            pid: 42,
+            comm: Some("pkg-config".into()),
+            exe: None,
+            cwd: Some("/src".into()),
+        };
+        let missing = |requested_path: &str| MissingPath {
+            requested_path: requested_path.into(),
+            phase: Some("configure".into()),
+            requested_by: requested_by.clone(),
+        };
+        let packages = group(&[
+            missing("lib/pkgconfig/foo.pc"),
+            missing("include/foo/bar.h"),
+            missing("lib/pkgconfig/foo.pc"),
+            missing("bin/7z"),
+        ]);
+        assert_eq!(
+            packages
+                .iter()
+                .map(|package| (package.name.as_str(), package.paths.len()))
+                .collect::<Vec<_>>(),
+            [("7z", 1), ("foo", 2)]
+        );
+
+        let pin = IndexPin {
+            nixpkgs_rev: None,
+            index: "0123abcd".into(),
+        };
+        let stub = render_stub(&packages[1], &pin, Some("make"));
+        assert!(stub.starts_with("# Stub of foo: no package of index 0123abcd provides"));
+        assert!(stub.contains("  foo = final.stdenv.mkDerivation {\n"));
+        assert!(stub.contains(
+            "    #   include/foo/bar.h, by pkg-config (pid 42) in /src, during configure\n"
+        ));
+        assert!(render_stub(&packages[0], &pin, None).contains("  \"7z\" = final.stdenv"));
+    }
+}
@@ -0,0 +1,75 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Manifest of the packs, next to the core resolutions.
+pub const MANIFEST_FILENAME: &str = "packs.toml";
+
+/// A named set of core resolutions, e.g. for one ecosystem.
+#[derive(Deserialize, Debug)]
+pub struct Pack {
+    pub description: String,
+    /// Directories of the core resolutions of this pack
+    pub directories: Vec<PathBuf>,
+}
+
+/// Pack name -> pack
+pub type Manifest = BTreeMap<String, Pack>;
+
+pub fn read_manifest(data: &str) -> Manifest {
+    toml::from_str(data)
+        .expect("Failed to parse the manifest of the core resolution packs, corrupted binary?")
+}
+
+/// Pack of a core resolution file, by its path relative to the core resolutions.
+pub fn pack_of<'a>(manifest: &'a Manifest, filepath: &Path) -> Option<&'a str> {
+    manifest
+        .iter()
+        .find(|(_, pack)| {
+            pack.directories
+                .iter()
+                .any(|directory| filepath.starts_with(directory))
+        })
+        .map(|(name, _)| name.as_str())
+}
+
+/// Selected packs which are not in the manifest.
+pub fn unknown_packs<'a>(manifest: &Manifest, selected: &'a [String]) -> Vec<&'a str> {
+    selected
+        .iter()
+        .filter(|name| !manifest.contains_key(*name))
+        .map(String::as_str)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use walkdir::WalkDir;
+
+    #[test]
+    fn test_every_core_resolution_is_in_a_pack() {
+        let core = Path::new(env!("CARGO_MANIFEST_DIR")).join("data");
+        let manifest =
+            read_manifest(&std::fs::read_to_string(core.join(MANIFEST_FILENAME)).unwrap());
+
+        for entry in WalkDir::new(&core).into_iter().map(Result::unwrap) {
+            let filepath = entry.path().strip_prefix(&core).unwrap();
+            if entry.file_type().is_file() && filepath != Path::new(MANIFEST_FILENAME) {
+                assert!(
+                    pack_of(&manifest, filepath).is_some(),
+                    "{} is in no pack",
+                    filepath.display()
+                );
+            }
+        }
+        assert_eq!(
+            pack_of(&manifest, Path::new("python/cffi.toml")),
+            Some("python")
+        );
+        assert_eq!(
+            unknown_packs(&manifest, &["python".into(), "cobol".into()]),
+            vec!["cobol"]
+        );
+    }
+}
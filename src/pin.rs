@@ -0,0 +1,339 @@
+use lazy_static::lazy_static;
+use log::{debug, warn};
+use regex::bytes::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use crate::cache::database::{Reader, SharedBuffer};
+use crate::cache::{FileTreeEntry, StorePath};
+use crate::digest::sha256_hex;
+use crate::fetch::fetch;
+use crate::index::load_index;
+use crate::nix::{build_index, flake_metadata, set_nixpkgs};
+use crate::resolution::{Decision, ResolutionDB};
+
+/// The nix-index database this build searches, compressed.
+pub static EMBEDDED_INDEX: &[u8] = include_bytes!("../nix-index-files");
+
+lazy_static! {
+    /// Pin of the index embedded in this build, recorded in every written resolution file.
+    pub static ref CURRENT_PIN: IndexPin = IndexPin {
+        nixpkgs_rev: option_env!("BUILDXYZ_NIXPKGS_REV")
+            .filter(|rev| !rev.is_empty())
+            .map(String::from),
+        index: fingerprint(EMBEDDED_INDEX),
+    };
+    /// Embedded index once decompressed, see `load_index`
+    static ref EMBEDDED_INDEX_BUFFER: SharedBuffer =
+        load_index(EMBEDDED_INDEX).expect("Failed to deserialize the index buffer");
+    /// nixpkgs the session is pinned to, see `pin_nixpkgs`
+    static ref SESSION: RwLock<Option<Arc<PinnedNixpkgs>>> = RwLock::new(None);
+}
+
+/// FNV-1a of the data and its length, stable across builds and platforms.
+pub fn fingerprint(data: &[u8]) -> String {
+    let hash = data.iter().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+
+    format!("fnv1a64:{:016x}-{}", hash, data.len())
+}
+
+/// Index a resolution file was recorded against.
+/// Store paths of the provided packages change between nixpkgs revisions,
+/// so they are only meaningful with the same index.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexPin {
+    /// nixpkgs revision, when known at build time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nixpkgs_rev: Option<String>,
+    /// Fingerprint of the index
+    pub index: String,
+}
+
+impl fmt::Display for IndexPin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.nixpkgs_rev {
+            Some(rev) => write!(f, "nixpkgs {} (index {})", rev, self.index),
+            None => write!(f, "index {}", self.index),
+        }
+    }
+}
+
+/// nixpkgs revision a session is pinned to with `--nixpkgs`, and the index built for it.
+pub struct PinnedNixpkgs {
+    pub pin: IndexPin,
+    /// Decompressed index, like `EMBEDDED_INDEX` once read
+    pub index_buffer: SharedBuffer,
+}
+
+/// Flake reference of `--nixpkgs`, a bare revision is one of NixOS/nixpkgs.
+pub fn flakeref_of(nixpkgs: &str) -> String {
+    if nixpkgs.len() == 40 && nixpkgs.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        format!("github:NixOS/nixpkgs/{}", nixpkgs)
+    } else {
+        nixpkgs.to_string()
+    }
+}
+
+/// Compressed index of a nixpkgs revision, kept in `$XDG_CACHE_HOME/buildxyz/indexes/<rev>/files`.
+/// It is downloaded from `index_url`, where `{rev}` is replaced by the revision, or built with `nix-index` otherwise.
+fn index_of(rev: &str, source: &str, index_url: Option<&str>) -> io::Result<Vec<u8>> {
+    let dirs = xdg::BaseDirectories::with_prefix("buildxyz")?;
+    let cached = dirs.place_cache_file(Path::new("indexes").join(rev).join("files"))?;
+
+    cached_index(&cached, rev, source, index_url)
+}
+
+/// Index kept at `cached`, downloaded or built there first if needed, see `index_of`.
+fn cached_index(cached: &Path, rev: &str, source: &str, index_url: Option<&str>) -> io::Result<Vec<u8>> {
+    if let Ok(data) = std::fs::read(cached) {
+        return Ok(data);
+    }
+
+    let data = match index_url {
+        Some(index_url) => fetch(&index_url.replace("{rev}", rev))?,
+        None => {
+            warn!(
+                "No index of nixpkgs {} is cached, building it with nix-index, this takes a long while",
+                rev
+            );
+            let directory = cached.parent().expect("Cached files are in a directory");
+            build_index(source, directory)
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+            std::fs::read(cached)?
+        }
+    };
+    std::fs::write(cached, &data)?;
+
+    Ok(data)
+}
+
+/// Pin the session to a nixpkgs revision or flake reference, e.g. `nixos-23.11` branches:
+/// packages are evaluated from it and searched in an index built for it, see `index_of`.
+/// The index embedded in this build is used when it is the same revision.
+pub fn pin_nixpkgs(nixpkgs: &str, index_url: Option<&str>) -> io::Result<()> {
+    let flakeref = flakeref_of(nixpkgs);
+    let metadata = flake_metadata(&flakeref)
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+    let (source, rev) = (metadata.path, metadata.locked.rev);
+    set_nixpkgs(&source);
+    if rev.is_some() && rev == CURRENT_PIN.nixpkgs_rev {
+        debug!("{} is the nixpkgs revision of the embedded index", flakeref);
+        return Ok(());
+    }
+
+    // Sources without a revision are identified by their store path.
+    let key = rev.clone().unwrap_or_else(|| sha256_hex(source.as_bytes()));
+    let data = index_of(&key, &source, index_url)?;
+    set_session_index(rev, &data)
+}
+
+/// Search a prebuilt index in this session, e.g. one downloaded by `buildxyz index fetch`,
+/// of a nixpkgs revision if known. Packages are still evaluated from the nixpkgs of `NIX_PATH`.
+pub fn pin_index(nixpkgs_rev: Option<String>, data: &[u8]) -> io::Result<()> {
+    set_session_index(nixpkgs_rev, data)
+}
+
+fn set_session_index(nixpkgs_rev: Option<String>, data: &[u8]) -> io::Result<()> {
+    let index_buffer = load_index(data)?;
+    *SESSION.write().unwrap() = Some(Arc::new(PinnedNixpkgs {
+        pin: IndexPin {
+            nixpkgs_rev,
+            index: fingerprint(data),
+        },
+        index_buffer,
+    }));
+
+    Ok(())
+}
+
+/// Pin of the index the session searches, recorded in the resolution files it writes.
+pub fn current_pin() -> IndexPin {
+    match &*SESSION.read().unwrap() {
+        Some(pinned) => pinned.pin.clone(),
+        None => CURRENT_PIN.clone(),
+    }
+}
+
+/// Decompressed index the session searches.
+pub fn session_index() -> SharedBuffer {
+    match &*SESSION.read().unwrap() {
+        Some(pinned) => pinned.index_buffer.clone(),
+        None => EMBEDDED_INDEX_BUFFER.clone(),
+    }
+}
+
+/// Compares the pins of the loaded resolution files with the current index.
+/// Files without a pin, e.g. written by hand, are trusted.
+#[derive(Default)]
+pub struct PinCheck {
+    /// Refuse stale files rather than warning about them.
+    pub strict: bool,
+    pub stale: Vec<PathBuf>,
+}
+
+impl PinCheck {
+    pub fn new(strict: bool) -> Self {
+        PinCheck {
+            strict,
+            ..Default::default()
+        }
+    }
+
+    pub fn check(&mut self, filepath: &Path, pin: Option<&IndexPin>) {
+        let pin = match pin {
+            Some(pin) => pin,
+            None => return,
+        };
+        let current = current_pin();
+        if pin.index == current.index {
+            return;
+        }
+
+        if !self.strict {
+            warn!(
+                "{} was recorded against {} but buildxyz uses {}, its store paths may be stale, run `buildxyz res repin {}`",
+                filepath.display(),
+                pin,
+                current,
+                filepath.display()
+            );
+        }
+        self.stale.push(filepath.to_owned());
+    }
+}
+
+/// Query the embedded index outside of a session, e.g. from subcommands.
+pub fn query_embedded_index(pattern: &Regex) -> Vec<(StorePath, FileTreeEntry)> {
+    Reader::from_shared_buffer(EMBEDDED_INDEX_BUFFER.clone())
+        .expect("Failed to open database")
+        .query(pattern)
+        .run()
+        .expect("Failed to query the database")
+        .map(|result| result.expect("Failed to obtain candidate"))
+        .collect()
+}
+
+/// Outcome of a repin, by requested path.
+#[derive(Default, Debug)]
+pub struct Repin {
+    pub updated: Vec<String>,
+    /// Attributes which do not provide the file anymore, left untouched.
+    pub missing: Vec<String>,
+}
+
+/// Point the provided store paths to the ones of the same attribute and output in the current index.
+pub fn repin(db: &mut ResolutionDB) -> Repin {
+    let mut report = Repin::default();
+    let file_entry_names: Vec<String> = db
+        .values()
+        .flat_map(|resolution| resolution.all_data())
+        .filter_map(|data| match &data.decision {
+            Decision::Provide(provide_data) if !provide_data.file_entry_name.is_empty() => {
+                Some(regex::escape(&provide_data.file_entry_name))
+            }
+            _ => None,
+        })
+        .collect();
+    if file_entry_names.is_empty() {
+        return report;
+    }
+
+    // One scan of the index for all the provided files.
+    let pattern = Regex::new(&format!("^({})$", file_entry_names.join("|")))
+        .expect("Escaped paths are a valid regex");
+    // (file entry name, attribute, output) -> store path
+    let mut candidates: HashMap<(String, String, String), StorePath> = HashMap::new();
+    for (store_path, entry) in query_embedded_index(&pattern) {
+        let (key, toplevel) = {
+            let origin = store_path.origin();
+            (
+                (
+                    String::from_utf8_lossy(&entry.path).to_string(),
+                    origin.attr.clone(),
+                    origin.output.clone(),
+                ),
+                origin.toplevel,
+            )
+        };
+        // Prefer top-level paths over propagated ones.
+        if toplevel || !candidates.contains_key(&key) {
+            candidates.insert(key, store_path);
+        }
+    }
+
+    for resolution in db.values_mut() {
+        for data in resolution.all_data_mut() {
+            let provide_data = match &mut data.decision {
+                Decision::Provide(provide_data) if !provide_data.file_entry_name.is_empty() => {
+                    provide_data
+                }
+                _ => continue,
+            };
+            let key = {
+                let origin = provide_data.store_path.origin();
+                (
+                    provide_data.file_entry_name.clone(),
+                    origin.attr.clone(),
+                    origin.output.clone(),
+                )
+            };
+
+            match candidates.get(&key) {
+                Some(store_path) if *store_path == provide_data.store_path => {
+                    debug!("{} is up to date", data.requested_path);
+                }
+                Some(store_path) => {
+                    provide_data.store_path = store_path.clone();
+                    report.updated.push(data.requested_path.clone());
+                }
+                None => report.missing.push(data.requested_path.clone()),
+            }
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fingerprint_is_stable() {
+        assert_eq!(fingerprint(b""), "fnv1a64:cbf29ce484222325-0");
+        assert_eq!(fingerprint(b"a"), "fnv1a64:af63dc4c8601ec8c-1");
+        assert_eq!(
+            flakeref_of("057f9aecfb71c4437d2b27d3323df7f93c010b7e"),
+            "github:NixOS/nixpkgs/057f9aecfb71c4437d2b27d3323df7f93c010b7e"
+        );
+        assert_eq!(
+            flakeref_of("github:NixOS/nixpkgs/nixos-23.11"),
+            "github:NixOS/nixpkgs/nixos-23.11"
+        );
+    }
+
+    #[test]
+    fn test_indexes_of_pinned_revisions_are_cached() {
+        let directory = tempfile::tempdir().unwrap();
+        let rev = "057f9aecfb71c4437d2b27d3323df7f93c010b7e";
+        let served = directory.path().join(format!("{}.index", rev));
+        std::fs::write(&served, b"index").unwrap();
+        let index_url = format!("file://{}/{{rev}}.index", directory.path().display());
+        std::fs::create_dir(directory.path().join(rev)).unwrap();
+        let cached = directory.path().join(rev).join("files");
+
+        let data = cached_index(&cached, rev, "/nix/store/nixpkgs", Some(&index_url)).unwrap();
+        assert_eq!(data, b"index");
+        assert_eq!(std::fs::read(&cached).unwrap(), b"index");
+        // Not downloaded again.
+        std::fs::remove_file(&served).unwrap();
+        let data = cached_index(&cached, rev, "/nix/store/nixpkgs", Some(&index_url)).unwrap();
+        assert_eq!(data, b"index");
+    }
+}
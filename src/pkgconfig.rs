@@ -0,0 +1,109 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::path::Path;
+
+/// pkg-config variables which locate the package on disk.
+const LOCATION_VARIABLES: &[&str] = &["prefix", "exec_prefix", "libdir", "includedir"];
+
+lazy_static! {
+    static ref STORE_PATH: Regex = Regex::new(r"/[^\s'\x22]*?/[0-9a-z]{32}-[^/\s'\x22]+").unwrap();
+}
+
+pub fn is_pc_file(requested_path: &Path) -> bool {
+    requested_path.starts_with("lib/pkgconfig")
+        && requested_path.extension().map_or(false, |ext| ext == "pc")
+}
+
+/// Name of the package a store path is an output of, e.g. `zlib-1.3` for `zlib-1.3-dev`.
+fn package_name(store_path: &str) -> (&str, &str) {
+    let (store_dir, name) = store_path.rsplit_once('/').unwrap_or(("", store_path));
+    let name = name.split_once('-').map_or(name, |(_hash, name)| name);
+    match name.rsplit_once('-') {
+        Some((package, output))
+            if !output.is_empty() && output.chars().all(|c| c.is_ascii_lowercase()) =>
+        {
+            (store_dir, package)
+        }
+        _ => (store_dir, name),
+    }
+}
+
+/// Rewrite the location variables of a `.pc` file so that
+/// any of the `store_paths`, or another output of the same package, is replaced by `merged_root`:
+/// the `.pc` file of a split package lives in its `dev` output while its `prefix` is the default one.
+/// Other lines are kept as is, so are store paths of other packages.
+pub fn rewrite_pc_file(contents: &str, store_paths: &[String], merged_root: &Path) -> String {
+    let merged_root = merged_root.to_string_lossy();
+    let packages: Vec<(&str, &str)> = store_paths.iter().map(|p| package_name(p)).collect();
+
+    contents
+        .lines()
+        .map(|line| match line.split_once('=') {
+            Some((key, value)) if LOCATION_VARIABLES.contains(&key.trim()) => {
+                let value = STORE_PATH.replace_all(value, |store_path: &regex::Captures| {
+                    let store_path = &store_path[0];
+                    if packages.contains(&package_name(store_path)) {
+                        merged_root.to_string()
+                    } else {
+                        store_path.to_string()
+                    }
+                });
+                format!("{}={}", key, value)
+            }
+            _ => line.to_string(),
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+        + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rewrite_pc_file_of_split_outputs() {
+        let contents = "\
+prefix=/nix/store/2zmbsy7nfyd8q7ss8vc8nlmq7q9ygnl4-zlib-1.3
+libdir=/nix/store/fx5bd3gqch6asghpv36yyqf6lvq8chpv-zlib-1.3-lib/lib
+includedir=/nix/store/8vvhlnnbjb7sdmgdcb9ixyw4k7nqp56w-zlib-1.3-dev/include
+sharedlibdir=${libdir}
+datadir=/nix/store/ac2w8q5jdxlhlvbfbds0bba5pgp8vgl7-zlib-doc-1.3/share
+
+Name: zlib
+Requires.private: /nix/store/2zmbsy7nfyd8q7ss8vc8nlmq7q9ygnl4-zlib-1.3
+Libs: -L${libdir} -lz
+Cflags: -I${includedir}";
+        let store_paths =
+            vec!["/nix/store/8vvhlnnbjb7sdmgdcb9ixyw4k7nqp56w-zlib-1.3-dev".to_string()];
+
+        assert_eq!(
+            rewrite_pc_file(contents, &store_paths, Path::new("/tmp/fast")),
+            "\
+prefix=/tmp/fast
+libdir=/tmp/fast/lib
+includedir=/tmp/fast/include
+sharedlibdir=${libdir}
+datadir=/nix/store/ac2w8q5jdxlhlvbfbds0bba5pgp8vgl7-zlib-doc-1.3/share
+
+Name: zlib
+Requires.private: /nix/store/2zmbsy7nfyd8q7ss8vc8nlmq7q9ygnl4-zlib-1.3
+Libs: -L${libdir} -lz
+Cflags: -I${includedir}
+"
+        );
+        // Another package is kept as is.
+        let other = "prefix=/nix/store/2zmbsy7nfyd8q7ss8vc8nlmq7q9ygnl4-zlib-ng-2.1\n";
+        assert_eq!(
+            rewrite_pc_file(other, &store_paths, Path::new("/tmp/fast")),
+            other
+        );
+    }
+
+    #[test]
+    fn test_is_pc_file() {
+        assert!(is_pc_file(Path::new("lib/pkgconfig/zlib.pc")));
+        assert!(!is_pc_file(Path::new("share/pkgconfig/zlib.pc")));
+        assert!(!is_pc_file(Path::new("lib/pkgconfig/zlib.pc.in")));
+    }
+}
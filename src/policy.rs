@@ -0,0 +1,242 @@
+use glob::Pattern;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+use crate::cache::{FileTreeEntry, StorePath};
+use crate::interactive::human_size;
+use crate::nix::closure_size;
+use crate::outputs;
+use crate::ranking::Ranking;
+
+/// What happens to a suggestion the policy does not allow.
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PolicyFallback {
+    /// Ask like without `--automatic`
+    #[default]
+    Prompt,
+    /// Answer ENOENT, e.g. in CI where nobody can answer
+    Ignore,
+}
+
+/// Closure sizes are written in bytes or with a unit, e.g. `500 MiB`.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum Size {
+    Bytes(usize),
+    Human(String),
+}
+
+/// The policy file as written, see `AutomaticPolicy`.
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct PolicyFile {
+    max_closure_size: Option<Size>,
+    min_popularity: Option<i32>,
+    allow: Vec<String>,
+    deny: Vec<String>,
+    require_toplevel: bool,
+    otherwise: PolicyFallback,
+}
+
+/// Which suggestions `--automatic` may accept, see `--automatic-policy`, e.g.
+///
+/// ```toml
+/// max_closure_size = "500 MiB"
+/// min_popularity = 10
+/// allow = ["python3Packages.*"]
+/// deny = ["*.debug"]
+/// require_toplevel = true
+/// otherwise = "ignore"
+/// ```
+///
+/// Patterns are globs over the installables, e.g. `zlib.dev`.
+/// The default policy accepts every suggestion.
+#[derive(Default, Debug, Clone)]
+pub struct AutomaticPolicy {
+    max_closure_size: Option<usize>,
+    min_popularity: Option<i32>,
+    allow: Vec<Pattern>,
+    deny: Vec<Pattern>,
+    require_toplevel: bool,
+    pub otherwise: PolicyFallback,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum PolicyError {
+    #[error("failed to read the policy {0}: {1}")]
+    Io(PathBuf, std::io::Error),
+    #[error("invalid policy: {0}")]
+    Toml(#[from] toml::de::Error),
+    #[error("invalid glob `{0}`: {1}")]
+    Glob(String, glob::PatternError),
+    #[error("invalid size `{0}`, expected e.g. `500 MiB`")]
+    Size(String),
+}
+
+/// Parse a size such as `1.5 GiB`, `300MiB` or `4096`, in bytes.
+pub fn parse_size(size: &str) -> Option<usize> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let multiplier: usize = match unit.trim() {
+        "" | "B" => 1,
+        "KiB" => 1 << 10,
+        "MiB" => 1 << 20,
+        "GiB" => 1 << 30,
+        _ => return None,
+    };
+
+    Some((number.parse::<f64>().ok()? * multiplier as f64) as usize)
+}
+
+fn patterns(globs: Vec<String>) -> Result<Vec<Pattern>, PolicyError> {
+    globs
+        .into_iter()
+        .map(|g| Pattern::new(&g).map_err(|err| PolicyError::Glob(g, err)))
+        .collect()
+}
+
+impl AutomaticPolicy {
+    pub fn load(path: &Path) -> Result<Self, PolicyError> {
+        let data =
+            std::fs::read_to_string(path).map_err(|err| PolicyError::Io(path.to_owned(), err))?;
+        Self::parse(&data)
+    }
+
+    fn parse(data: &str) -> Result<Self, PolicyError> {
+        let file: PolicyFile = toml::from_str(data)?;
+
+        Ok(AutomaticPolicy {
+            max_closure_size: match file.max_closure_size {
+                None => None,
+                Some(Size::Bytes(bytes)) => Some(bytes),
+                Some(Size::Human(size)) => Some(parse_size(&size).ok_or(PolicyError::Size(size))?),
+            },
+            min_popularity: file.min_popularity,
+            allow: patterns(file.allow)?,
+            deny: patterns(file.deny)?,
+            require_toplevel: file.require_toplevel,
+            otherwise: file.otherwise,
+        })
+    }
+
+    /// Why the suggestion must not be accepted automatically, `None` if it can.
+    /// The closure size is only asked to the store when the policy limits it, last.
+    pub fn violation(
+        &self,
+        (store_path, _): &(StorePath, FileTreeEntry),
+        ranking: Option<&Ranking>,
+    ) -> Option<String> {
+        let origin = store_path.origin();
+        let installable = outputs::installable(&origin);
+        if let Some(pattern) = self.deny.iter().find(|p| p.matches(&installable)) {
+            return Some(format!("{} is denied by `{}`", installable, pattern));
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|p| p.matches(&installable)) {
+            return Some(format!("{} is not allowed", installable));
+        }
+        if self.require_toplevel && !origin.toplevel {
+            return Some(format!("{} is not a toplevel package", installable));
+        }
+        if let Some(min_popularity) = self.min_popularity {
+            let popularity = ranking.map_or(0, Ranking::popularity);
+            if popularity < min_popularity {
+                return Some(format!(
+                    "{} is used by {} packages, fewer than {}",
+                    installable, popularity, min_popularity
+                ));
+            }
+        }
+        if let Some(max_closure_size) = self.max_closure_size {
+            match closure_size(store_path.as_str().as_ref()) {
+                Some(size) if size <= max_closure_size => {}
+                Some(size) => {
+                    return Some(format!(
+                        "the closure of {} is {}, more than {}",
+                        installable,
+                        human_size(size),
+                        human_size(max_closure_size)
+                    ))
+                }
+                None => return Some(format!("the closure size of {} is unknown", installable)),
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::database;
+
+    fn candidate(attr: &str, output: &str, toplevel: bool) -> (StorePath, FileTreeEntry) {
+        let store_path = format!(
+            "/nix/store/zy4xhzc6x6ldrfzqzfa4x3cw8ds1mgcn-{}-1.0-{}",
+            attr, output
+        );
+        database::candidate(attr, output, toplevel, &store_path, "/include/foo.h")
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert_eq!(parse_size("500 MiB"), Some(500 << 20));
+        assert_eq!(parse_size("1.5GiB"), Some(3 << 29));
+        assert_eq!(parse_size("12 parsecs"), None);
+    }
+
+    #[test]
+    fn test_policy_violations() {
+        let policy = AutomaticPolicy::parse(
+            r#"
+            min_popularity = 10
+            allow = ["zlib.*", "openssl*"]
+            deny = ["openssl_1_1.*"]
+            require_toplevel = true
+            otherwise = "ignore"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(policy.otherwise, PolicyFallback::Ignore);
+        let ranking = |popularity: i32| Ranking {
+            path_match: (0, 0),
+            must_build: false,
+            output: 0,
+            inverted_popularity: -popularity,
+            propagated: false,
+        };
+
+        assert_eq!(
+            policy.violation(&candidate("zlib", "dev", true), Some(&ranking(50))),
+            None
+        );
+        assert_eq!(
+            policy
+                .violation(&candidate("openssl_1_1", "dev", true), Some(&ranking(50)))
+                .unwrap(),
+            "openssl_1_1.dev is denied by `openssl_1_1.*`"
+        );
+        assert!(policy
+            .violation(&candidate("curl", "dev", true), Some(&ranking(50)))
+            .is_some());
+        assert!(policy
+            .violation(&candidate("zlib", "dev", false), Some(&ranking(50)))
+            .is_some());
+        assert!(policy
+            .violation(&candidate("zlib", "dev", true), Some(&ranking(3)))
+            .is_some());
+
+        assert!(AutomaticPolicy::default()
+            .violation(&candidate("curl", "dev", false), None)
+            .is_none());
+        assert!(matches!(
+            AutomaticPolicy::parse("max_closure_size = \"lots\""),
+            Err(PolicyError::Size(_))
+        ));
+    }
+}
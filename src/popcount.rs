@@ -9,3 +9,21 @@ pub struct Popcount {
     pub native_build_inputs: HashMap<String, u32>,
     pub propagated_native_build_inputs: HashMap<String, u32>,
 }
+
+impl Popcount {
+    /// Popcount graph of the nixpkgs the index was built from.
+    pub fn embedded() -> Self {
+        serde_json::from_slice(include_bytes!("../popcount-graph.json"))
+            .expect("Failed to deserialize the popcount graph")
+    }
+
+    /// Whether nixpkgs mostly has this store path as a native build input rather than a build input,
+    /// `None` if no package has it as either.
+    pub fn is_mostly_native(&self, store_path: &str) -> Option<bool> {
+        let count = |inputs: &HashMap<String, u32>| inputs.get(store_path).copied().unwrap_or(0);
+        let native = count(&self.native_build_inputs) + count(&self.propagated_native_build_inputs);
+        let build = count(&self.build_inputs) + count(&self.propagated_build_inputs);
+
+        (native + build > 0).then_some(native > build)
+    }
+}
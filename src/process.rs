@@ -0,0 +1,236 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::PathBuf;
+
+/// Metadata about the process which issued a filesystem request.
+/// Gathered from `/proc/<pid>`, fields are `None` if the process
+/// already exited or we lack the permissions to read them.
+#[derive(Serialize, Deserialize, Eq, Hash, PartialEq, Clone, Debug)]
+pub struct ProcessInfo {
+    pub pid: u32,
+    pub comm: Option<String>,
+    pub exe: Option<PathBuf>,
+    pub cwd: Option<PathBuf>,
+}
+
+impl ProcessInfo {
+    pub fn from_pid(pid: u32) -> Self {
+        let proc_dir = PathBuf::from(format!("/proc/{}", pid));
+
+        ProcessInfo {
+            pid,
+            comm: std::fs::read_to_string(proc_dir.join("comm"))
+                .ok()
+                .map(|comm| comm.trim_end().to_string()),
+            exe: std::fs::read_link(proc_dir.join("exe")).ok(),
+            cwd: std::fs::read_link(proc_dir.join("cwd")).ok(),
+        }
+    }
+}
+
+impl ProcessInfo {
+    /// Whether the process still runs, zombies are considered dead.
+    pub fn is_alive(&self) -> bool {
+        match std::fs::read_to_string(format!("/proc/{}/stat", self.pid)) {
+            // The state follows the parenthesized command name.
+            Ok(stat) => !matches!(
+                stat.rsplit_once(')')
+                    .and_then(|(_, rest)| rest.trim_start().chars().next()),
+                Some('Z') | Some('X')
+            ),
+            Err(_) => false,
+        }
+    }
+}
+
+impl ProcessInfo {
+    /// Whether the process has a signal pending which it does not block:
+    /// the kernel then interrupts its pending FUSE request, which fuser does not tell us.
+    pub fn has_pending_signal(&self) -> bool {
+        std::fs::read_to_string(format!("/proc/{}/status", self.pid))
+            .map_or(false, |status| unblocked_pending_signals(&status) != 0)
+    }
+}
+
+/// Signals pending for the thread or its process which it does not block,
+/// from the contents of `/proc/<pid>/status`.
+fn unblocked_pending_signals(status: &str) -> u64 {
+    let mask = |field: &str| {
+        status
+            .lines()
+            .find_map(|line| u64::from_str_radix(line.strip_prefix(field)?.trim(), 16).ok())
+            .unwrap_or(0)
+    };
+
+    (mask("SigPnd:") | mask("ShdPnd:")) & !mask("SigBlk:")
+}
+
+/// Arguments of a running process, `None` if it already exited.
+pub fn read_cmdline(pid: u32) -> Option<Vec<String>> {
+    let cmdline = std::fs::read(format!("/proc/{}/cmdline", pid)).ok()?;
+
+    Some(
+        cmdline
+            .split(|b| *b == 0)
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| String::from_utf8_lossy(arg).to_string())
+            .collect(),
+    )
+    .filter(|args: &Vec<String>| !args.is_empty())
+}
+
+/// Value of an environment variable of a running process, as it was when it started.
+pub fn read_environ_var(pid: u32, name: &str) -> Option<String> {
+    let environ = std::fs::read(format!("/proc/{}/environ", pid)).ok()?;
+
+    environ.split(|b| *b == 0).find_map(|entry| {
+        let value = entry.strip_prefix(name.as_bytes())?.strip_prefix(b"=")?;
+        Some(String::from_utf8_lossy(value).to_string())
+    })
+}
+
+impl fmt::Display for ProcessInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (pid {})",
+            self.comm.as_deref().unwrap_or("<unknown>"),
+            self.pid
+        )?;
+        if let Some(cwd) = &self.cwd {
+            write!(f, " in {}", cwd.display())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Rules to ignore lookups issued by certain processes,
+/// e.g. `find`, `updatedb` or IDE indexers crawling the search paths.
+///
+/// Each pattern is matched against the process name (`comm`)
+/// and against its executable path.
+#[derive(Default, Debug, Clone)]
+pub struct ProcessFilter {
+    patterns: Vec<Regex>,
+}
+
+impl ProcessFilter {
+    pub fn new(patterns: &[String]) -> Result<Self, regex::Error> {
+        Ok(ProcessFilter {
+            patterns: patterns
+                .iter()
+                .map(|pattern| Regex::new(&format!("^(?:{})$", pattern)))
+                .collect::<Result<Vec<Regex>, regex::Error>>()?,
+        })
+    }
+
+    /// Whether lookups from this process should be ignored.
+    pub fn is_ignored(&self, info: &ProcessInfo) -> bool {
+        self.patterns.iter().any(|pattern| {
+            info.comm
+                .as_deref()
+                .map_or(false, |comm| pattern.is_match(comm))
+                || info
+                    .exe
+                    .as_ref()
+                    .map_or(false, |exe| pattern.is_match(&exe.to_string_lossy()))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_process_info_of_the_requester() {
+        let info = ProcessInfo::from_pid(std::process::id());
+
+        assert_eq!(info.cwd, std::env::current_dir().ok());
+        assert_eq!(info.exe, std::env::current_exe().ok());
+        assert!(info.comm.is_some() && info.is_alive());
+        assert!(info.to_string().starts_with(&format!(
+            "{} (pid {}) in ",
+            info.comm.as_deref().unwrap(),
+            info.pid
+        )));
+
+        let gone = ProcessInfo::from_pid(u32::MAX);
+        assert_eq!(
+            (
+                gone.comm.as_deref(),
+                gone.exe.as_deref(),
+                gone.cwd.as_deref()
+            ),
+            (None, None, None)
+        );
+        assert!(!gone.is_alive());
+        assert_eq!(gone.to_string(), format!("<unknown> (pid {})", u32::MAX));
+    }
+
+    #[test]
+    fn test_unblocked_pending_signals() {
+        let status = |pending: &str, shared: &str, blocked: &str| {
+            format!(
+                "Name:\tcc1\nState:\tS (sleeping)\nSigQ:\t1/62811\nSigPnd:\t{}\nShdPnd:\t{}\nSigBlk:\t{}\nSigIgn:\t0000000000000000\n",
+                pending, shared, blocked
+            )
+        };
+
+        assert_eq!(
+            unblocked_pending_signals(&status(
+                "0000000000000000",
+                "0000000000000000",
+                "0000000000000000"
+            )),
+            0
+        );
+        // SIGINT pending for the process.
+        assert_eq!(
+            unblocked_pending_signals(&status(
+                "0000000000000000",
+                "0000000000000002",
+                "0000000000000000"
+            )),
+            0x2
+        );
+        // SIGTERM pending for the thread, SIGINT blocked.
+        assert_eq!(
+            unblocked_pending_signals(&status(
+                "0000000000004002",
+                "0000000000000000",
+                "0000000000000002"
+            )),
+            0x4000
+        );
+        assert_eq!(unblocked_pending_signals(""), 0);
+        assert!(!ProcessInfo::from_pid(std::process::id()).has_pending_signal());
+    }
+
+    #[test]
+    fn test_process_filter_matches_comm_or_exe() {
+        let filter =
+            ProcessFilter::new(&["find|updatedb".to_string(), ".*/clangd".to_string()]).unwrap();
+        let process = |comm: Option<&str>, exe: Option<&str>| ProcessInfo {
+            pid: 1,
+            comm: comm.map(str::to_string),
+            exe: exe.map(PathBuf::from),
+            cwd: None,
+        };
+
+        assert!(filter.is_ignored(&process(Some("find"), None)));
+        assert!(filter.is_ignored(&process(Some("updatedb"), Some("/usr/bin/updatedb"))));
+        assert!(filter.is_ignored(&process(
+            None,
+            Some("/nix/store/abc-clang-tools/bin/clangd")
+        )));
+        // Patterns are anchored.
+        assert!(!filter.is_ignored(&process(Some("findutils-helper"), None)));
+        assert!(!filter.is_ignored(&process(Some("cc1"), Some("/usr/libexec/cc1"))));
+        assert!(!filter.is_ignored(&process(None, None)));
+        assert!(!ProcessFilter::default().is_ignored(&process(Some("find"), None)));
+        assert!(ProcessFilter::new(&["(".to_string()]).is_err());
+    }
+}
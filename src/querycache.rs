@@ -0,0 +1,239 @@
+use log::{trace, warn};
+use serde::{Deserialize, Serialize};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::cache::{FileTreeEntry, StorePath};
+use crate::digest::sha256_hex;
+use crate::filesource::IndexSet;
+
+/// Bumped when the candidates of a requested path are searched differently.
+const FORMAT_VERSION: u32 = 1;
+/// File of a cache directory naming the indexes it was searched in.
+const KEY_FILENAME: &str = "indexes";
+
+/// Candidates of a requested path, as found in the indexes.
+#[derive(Serialize, Deserialize)]
+struct CachedQuery {
+    requested_path: String,
+    candidates: Vec<(StorePath, FileTreeEntry)>,
+}
+
+/// Candidates of the requested paths found in the indexes by earlier sessions,
+/// kept in `$XDG_CACHE_HOME/buildxyz/queries` so that building the same project again does not search them.
+/// Each set of indexes has its own directory, named after their fingerprints, with a file per requested path:
+/// results are never stale, another index is another directory.
+pub struct QueryCache {
+    directory: Option<PathBuf>,
+    /// Written in the directory, to tell what it caches
+    key: String,
+}
+
+impl QueryCache {
+    /// Searches nothing and remembers nothing.
+    pub fn disabled() -> Self {
+        QueryCache {
+            directory: None,
+            key: String::new(),
+        }
+    }
+
+    /// The results of these indexes, disabled when one of them was not mapped from the cache directory,
+    /// e.g. with a read-only cache.
+    pub fn open(index: &IndexSet, case_insensitive: bool) -> Self {
+        let Some(fingerprints) = index.fingerprints() else {
+            return QueryCache::disabled();
+        };
+        match queries_dir() {
+            Ok(root) => Self::open_in(&root, &fingerprints, case_insensitive),
+            Err(err) => {
+                warn!("Failed to find the query cache: {}", err);
+                QueryCache::disabled()
+            }
+        }
+    }
+
+    fn open_in(root: &Path, fingerprints: &str, case_insensitive: bool) -> Self {
+        let key = format!(
+            "buildxyz {} (format {}){}\n{}",
+            env!("CARGO_PKG_VERSION"),
+            FORMAT_VERSION,
+            if case_insensitive {
+                ", case insensitive"
+            } else {
+                ""
+            },
+            fingerprints
+        );
+
+        QueryCache {
+            directory: Some(root.join(&sha256_hex(key.as_bytes())[..32])),
+            key,
+        }
+    }
+
+    fn entry_path(&self, requested_path: &str) -> Option<PathBuf> {
+        let directory = self.directory.as_ref()?;
+        Some(directory.join(format!("{}.json", sha256_hex(requested_path.as_bytes()))))
+    }
+
+    /// Candidates of the requested path, if an earlier search of the same indexes remembered them.
+    pub fn get(&self, requested_path: &str) -> Option<Vec<(StorePath, FileTreeEntry)>> {
+        let data = std::fs::read(self.entry_path(requested_path)?).ok()?;
+        let cached: CachedQuery = serde_json::from_slice(&data).ok()?;
+        // Hashes of requested paths are not expected to collide, but a corrupt file is not trusted.
+        (cached.requested_path == requested_path).then(|| {
+            trace!("disk query cache hit for {}", requested_path);
+            cached.candidates
+        })
+    }
+
+    /// Remember the candidates of the requested path, for the next sessions.
+    pub fn put(&self, requested_path: &str, candidates: &[(StorePath, FileTreeEntry)]) {
+        let (Some(directory), Some(filepath)) = (&self.directory, self.entry_path(requested_path))
+        else {
+            return;
+        };
+        let cached = CachedQuery {
+            requested_path: requested_path.to_string(),
+            candidates: candidates.to_vec(),
+        };
+        // Created on write, it may have been cleared since the session started.
+        let key_filepath = directory.join(KEY_FILENAME);
+        let written = if key_filepath.exists() {
+            Ok(())
+        } else {
+            std::fs::create_dir_all(directory)
+                .and_then(|_| std::fs::write(&key_filepath, &self.key))
+        }
+        // Written aside first, another session may be reading it.
+        .and_then(|_| tempfile::NamedTempFile::new_in(directory))
+        .and_then(|mut file| {
+            file.write_all(&serde_json::to_vec(&cached).unwrap())?;
+            file.persist(&filepath).map_err(|err| err.error)?;
+            Ok(())
+        });
+        if let Err(err) = written {
+            warn!(
+                "Failed to cache the candidates of {} in {}: {}",
+                requested_path,
+                filepath.display(),
+                err
+            );
+        }
+    }
+}
+
+/// Directory of the query caches, `$XDG_CACHE_HOME/buildxyz/queries`.
+pub fn queries_dir() -> io::Result<PathBuf> {
+    let dirs = xdg::BaseDirectories::with_prefix("buildxyz")?;
+    Ok(dirs.get_cache_home().join("queries"))
+}
+
+/// What the cache of a set of indexes holds.
+#[derive(Debug, PartialEq, Eq)]
+pub struct CacheStats {
+    pub directory: PathBuf,
+    /// Version of buildxyz and fingerprints of the indexes searched
+    pub key: String,
+    pub entries: usize,
+    pub bytes: u64,
+}
+
+/// What the caches under `root` hold, by directory.
+pub fn stats(root: &Path) -> io::Result<Vec<CacheStats>> {
+    let mut stats = Vec::new();
+    let caches = match std::fs::read_dir(root) {
+        Ok(caches) => caches,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(stats),
+        Err(err) => return Err(err),
+    };
+    for cache in caches {
+        let directory = cache?.path();
+        if !directory.is_dir() {
+            continue;
+        }
+        let key = std::fs::read_to_string(directory.join(KEY_FILENAME)).unwrap_or_default();
+        let (mut entries, mut bytes) = (0, 0);
+        for entry in std::fs::read_dir(&directory)? {
+            let entry = entry?;
+            if entry.path().extension().map_or(false, |ext| ext == "json") {
+                entries += 1;
+                bytes += entry.metadata()?.len();
+            }
+        }
+        stats.push(CacheStats {
+            directory,
+            key,
+            entries,
+            bytes,
+        });
+    }
+    stats.sort_by(|a, b| a.directory.cmp(&b.directory));
+
+    Ok(stats)
+}
+
+/// Delete the caches under `root`, returning what they held.
+pub fn clear(root: &Path) -> io::Result<Vec<CacheStats>> {
+    let stats = stats(root)?;
+    for cache in &stats {
+        std::fs::remove_dir_all(&cache.directory)?;
+    }
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::database::write_index;
+    use crate::cache::PathOrigin;
+
+    #[test]
+    fn test_queries_are_cached_by_index() {
+        let root = tempfile::tempdir().unwrap();
+        let origin = PathOrigin {
+            attr: "zlib".into(),
+            output: "dev".into(),
+            toplevel: true,
+            system: None,
+            index: None,
+        };
+        let index = tempfile::NamedTempFile::new().unwrap();
+        let candidates = write_index(
+            index.path(),
+            &[(
+                origin,
+                "/nix/store/8vvhlnnbjb7sdmgdcb9ixyw4k7nqp56w-zlib-1.3-dev",
+                vec!["include/zlib.h"],
+            )],
+        );
+
+        let cache = QueryCache::open_in(root.path(), "default fnv1a64:0123", false);
+        assert_eq!(cache.get("include/zlib.h"), None);
+        cache.put("include/zlib.h", &candidates);
+        cache.put("include/missing.h", &[]);
+
+        // Another process searching the same indexes.
+        let cache = QueryCache::open_in(root.path(), "default fnv1a64:0123", false);
+        assert_eq!(cache.get("include/zlib.h"), Some(candidates));
+        assert_eq!(cache.get("include/missing.h"), Some(Vec::new()));
+        let other = QueryCache::open_in(root.path(), "default fnv1a64:4567", false);
+        assert_eq!(other.get("include/zlib.h"), None);
+        let case_insensitive = QueryCache::open_in(root.path(), "default fnv1a64:0123", true);
+        assert_eq!(case_insensitive.get("include/zlib.h"), None);
+        assert_eq!(QueryCache::disabled().get("include/zlib.h"), None);
+
+        let stats = stats(root.path()).unwrap();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].entries, 2);
+        assert!(stats[0].key.ends_with("\ndefault fnv1a64:0123") && stats[0].bytes > 0);
+
+        assert_eq!(clear(root.path()).unwrap().len(), 1);
+        assert_eq!(cache.get("include/zlib.h"), None);
+        assert!(super::stats(root.path()).unwrap().is_empty());
+        cache.put("include/zlib.h", &[]);
+        assert_eq!(cache.get("include/zlib.h"), Some(Vec::new()));
+    }
+}
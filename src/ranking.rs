@@ -0,0 +1,86 @@
+use std::fmt;
+
+/// Why a candidate comes before another one in a prompt, compared field by field, lower is better.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Ranking {
+    /// How close the candidate path is to the requested one, see `library::match_rank`
+    pub path_match: (u8, usize),
+    /// Whether no substituter has the candidate, accepting it would build it locally,
+    /// see `substituters::SubstituterCheck`
+    pub must_build: bool,
+    /// Position of the output among the expected ones, see `outputs::output_rank`
+    pub output: usize,
+    /// Inverted popularity, the number of packages having it as a native build input
+    pub inverted_popularity: i32,
+    /// Whether the path is only found in the closure of a top-level package
+    pub propagated: bool,
+}
+
+impl Ranking {
+    pub fn popularity(&self) -> i32 {
+        -self.inverted_popularity
+    }
+}
+
+impl fmt::Display for Ranking {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.path_match {
+            (0, _) => write!(f, "exact path")?,
+            (1, _) => write!(f, "more specific version")?,
+            (2, _) => write!(f, "less specific version")?,
+            _ => write!(f, "other path")?,
+        }
+        match self.output {
+            0 => write!(f, ", expected output")?,
+            output => write!(f, ", output preference #{}", output + 1)?,
+        }
+        write!(f, ", used by {} packages", self.popularity())?;
+        if self.propagated {
+            write!(f, ", propagated")?;
+        }
+        if self.must_build {
+            write!(f, ", must be built locally")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rankings_explain_their_order() {
+        let exact = Ranking {
+            path_match: (0, 0),
+            must_build: false,
+            output: 1,
+            inverted_popularity: -3,
+            propagated: false,
+        };
+        let popular = Ranking {
+            path_match: (1, 2),
+            must_build: false,
+            output: 0,
+            inverted_popularity: -1200,
+            propagated: true,
+        };
+        assert!(exact < popular);
+        assert_eq!(
+            exact.to_string(),
+            "exact path, output preference #2, used by 3 packages"
+        );
+        assert_eq!(
+            popular.to_string(),
+            "more specific version, expected output, used by 1200 packages, propagated"
+        );
+
+        let unsubstitutable = Ranking {
+            must_build: true,
+            ..exact.clone()
+        };
+        assert!(exact < unsubstitutable && unsubstitutable < popular);
+        assert!(unsubstitutable.to_string().ends_with(", must be built locally"));
+    }
+}
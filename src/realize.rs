@@ -0,0 +1,465 @@
+use log::{debug, info, warn};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::cache::StorePath;
+use crate::flake::{self, FlakeOutputs};
+use crate::interactive::{human_size, UserRequest};
+use crate::nix::{build_attr, realize_path_reporting, store_path_root};
+use crate::outputs;
+
+/// Store paths realized at once by default, see `--realize-jobs`.
+pub const DEFAULT_JOBS: usize = 4;
+/// Substitutions retried by default before giving up, see `--realize-retries`.
+pub const DEFAULT_RETRIES: u32 = 2;
+/// Wait before the first retry, doubled for each of the next ones.
+const FIRST_BACKOFF: Duration = Duration::from_secs(2);
+/// Byte counts are not reported to the UI more often than this.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Realizes a store path, telling the bytes copied so far and expected.
+type RealizeFn = dyn Fn(&str, &mut dyn FnMut(u64, u64)) -> Result<(), String> + Send + Sync;
+/// Builds a store path locally from the nixpkgs installable it comes from.
+type BuildFn = dyn Fn(&str, &str) -> Result<(), String> + Send + Sync;
+
+/// What is tried when a store path cannot be substituted, e.g. during a binary cache outage.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Substitutions tried again, waiting longer each time
+    pub retries: u32,
+    pub first_backoff: Duration,
+    /// Build the store path locally from its installable once substitution gave up, see `--no-build-fallback`
+    pub build_fallback: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            retries: DEFAULT_RETRIES,
+            first_backoff: FIRST_BACKOFF,
+            build_fallback: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Wait before the `attempt`-th retry, from 1.
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.first_backoff * 2u32.saturating_pow(attempt - 1)
+    }
+}
+
+/// Build a store path from its installable, the build log goes to the UI line by line.
+fn build_locally(path: &str, installable: &str) -> Result<(), String> {
+    let name = path.rsplit_once('/').map_or(path, |(_, name)| name);
+    let out_paths = build_attr(installable, &mut |line| info!("[{}] {}", name, line))
+        .map_err(|err| err.to_string())?;
+    if out_paths.iter().any(|out_path| out_path == path) {
+        Ok(())
+    } else {
+        Err(format!(
+            "{} now builds {} rather than {}",
+            installable,
+            out_paths.join(", "),
+            path
+        ))
+    }
+}
+
+/// Where the realizations stand, as the UI shows them.
+#[derive(Clone, Debug, Default)]
+pub struct RealizationProgress {
+    pub total: usize,
+    pub done: usize,
+    pub failed: usize,
+    /// Store paths being realized, with the bytes copied so far and expected if known
+    pub running: Vec<(String, u64, u64)>,
+    /// Store path whose realization just finished, if that is what changed, with whether it succeeded
+    pub finished: Option<(String, bool)>,
+}
+
+impl RealizationProgress {
+    /// Whether every realization asked is finished.
+    pub fn is_complete(&self) -> bool {
+        self.done + self.failed == self.total
+    }
+}
+
+impl fmt::Display for RealizationProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} of {} store paths realized", self.done, self.total)?;
+        if self.failed > 0 {
+            write!(f, ", {} failed", self.failed)?;
+        }
+        for (path, copied, expected) in &self.running {
+            let name = path
+                .rsplit_once('/')
+                .map_or(path.as_str(), |(_, name)| name);
+            match (copied, expected) {
+                (_, 0) => write!(f, ", {}", name)?,
+                (copied, expected) => write!(
+                    f,
+                    ", {} {} of {}",
+                    name,
+                    human_size(*copied as usize),
+                    human_size(*expected as usize)
+                )?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A realization shared by everything waiting for the same store path.
+#[derive(Default)]
+pub struct Realization {
+    outcome: Mutex<Option<Result<(), String>>>,
+    finished: Condvar,
+}
+
+impl Realization {
+    /// Block until the store path is realized, the error of the store otherwise.
+    pub fn wait(&self) -> Result<(), String> {
+        let outcome = self
+            .finished
+            .wait_while(self.outcome.lock().unwrap(), |outcome| outcome.is_none())
+            .unwrap();
+        outcome.clone().expect("Realization finished")
+    }
+
+    fn finish(&self, outcome: Result<(), String>) {
+        *self.outcome.lock().unwrap() = Some(outcome);
+        self.finished.notify_all();
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// Store path -> its realization, until it is finished
+    realizations: HashMap<String, Arc<Realization>>,
+    queue: VecDeque<String>,
+    /// Threads realizing the queue
+    jobs: usize,
+    progress: RealizationProgress,
+    reported: Option<Instant>,
+}
+
+/// Realizes store paths on a bounded number of threads, each path once for all the lookups waiting for it.
+#[derive(Clone)]
+pub struct Realizer {
+    state: Arc<Mutex<State>>,
+    max_jobs: usize,
+    realize: Arc<RealizeFn>,
+    build: Arc<BuildFn>,
+    retry: RetryPolicy,
+    /// Store path -> nixpkgs installable it can be built from, e.g. `zlib.dev`
+    installables: Arc<Mutex<HashMap<String, String>>>,
+    /// Told about the progress, see `UserRequest::Realizing`
+    send_ui_event: Option<Sender<UserRequest>>,
+}
+
+impl Default for Realizer {
+    fn default() -> Self {
+        Realizer::new(DEFAULT_JOBS, RetryPolicy::default(), None)
+    }
+}
+
+impl Realizer {
+    pub fn new(
+        max_jobs: usize,
+        retry: RetryPolicy,
+        send_ui_event: Option<Sender<UserRequest>>,
+    ) -> Self {
+        Realizer {
+            state: Default::default(),
+            max_jobs: max_jobs.max(1),
+            realize: Arc::new(|path, on_progress| {
+                realize_path_reporting(path, on_progress)
+                    .map_err(|err| err.to_string())
+                    // Flake outputs are seldom substitutable, they are built again once collected.
+                    .or_else(|err| FlakeOutputs::shared().rebuild(path).unwrap_or(Err(err)))
+            }),
+            build: Arc::new(build_locally),
+            retry,
+            installables: Default::default(),
+            send_ui_event,
+        }
+    }
+
+    /// Realizes store paths with `realize` rather than the store, to observe realizations.
+    #[cfg(test)]
+    pub fn with_realize(
+        mut self,
+        realize: impl Fn(&str, &mut dyn FnMut(u64, u64)) -> Result<(), String> + Send + Sync + 'static,
+    ) -> Self {
+        self.realize = Arc::new(realize);
+        self
+    }
+
+    /// Remember the nixpkgs attribute a store path comes from, to build it if it cannot be substituted.
+    /// Outputs of flakes, e.g. from the index of an added flake, are built again from their installable.
+    pub fn remember(&self, store_path: &StorePath) {
+        let origin = store_path.origin();
+        if flake::is_installable(&origin.attr) {
+            FlakeOutputs::shared().remember(&origin.attr, [store_path.as_str().as_ref()]);
+            return;
+        }
+        if origin.attr.is_empty() {
+            return;
+        }
+        self.installables.lock().unwrap().insert(
+            store_path.as_str().into_owned(),
+            outputs::installable(&origin),
+        );
+    }
+
+    /// Realize a store path, retrying with backoff, then building it locally if its installable is known.
+    fn realize_with_retries(
+        &self,
+        path: &str,
+        on_progress: &mut dyn FnMut(u64, u64),
+    ) -> Result<(), String> {
+        let mut outcome = (self.realize)(path, on_progress);
+        for attempt in 1..=self.retry.retries {
+            let Err(err) = &outcome else {
+                break;
+            };
+            let backoff = self.retry.backoff(attempt);
+            warn!(
+                "Failed to realize {}: {}, retrying in {:.0?} ({}/{})",
+                path, err, backoff, attempt, self.retry.retries
+            );
+            thread::sleep(backoff);
+            outcome = (self.realize)(path, on_progress);
+        }
+
+        let installable = self.installables.lock().unwrap().get(path).cloned();
+        match (outcome, installable) {
+            (Err(err), Some(installable)) if self.retry.build_fallback => {
+                warn!(
+                    "Failed to substitute {}: {}, building {} locally",
+                    path, err, installable
+                );
+                (self.build)(path, &installable)
+            }
+            (outcome, _) => outcome,
+        }
+    }
+
+    /// Start realizing the store path containing `path` unless it already is.
+    pub fn realize(&self, path: &str) -> Arc<Realization> {
+        let root = store_path_root(path).to_string();
+        let mut state = self.state.lock().unwrap();
+        if let Some(realization) = state.realizations.get(&root) {
+            return realization.clone();
+        }
+
+        let realization = Arc::new(Realization::default());
+        state.realizations.insert(root.clone(), realization.clone());
+        state.queue.push_back(root);
+        state.progress.total += 1;
+        if state.jobs < self.max_jobs {
+            state.jobs += 1;
+            let realizer = self.clone();
+            thread::spawn(move || realizer.work());
+        }
+
+        realization
+    }
+
+    /// Realize the queued store paths until there is none left.
+    fn work(&self) {
+        loop {
+            let path = {
+                let mut state = self.state.lock().unwrap();
+                match state.queue.pop_front() {
+                    Some(path) => {
+                        state.progress.running.push((path.clone(), 0, 0));
+                        state.progress.finished = None;
+                        self.report(&mut state, true);
+                        path
+                    }
+                    None => {
+                        state.jobs -= 1;
+                        return;
+                    }
+                }
+            };
+
+            debug!("realizing {}", path);
+            let outcome = self.realize_with_retries(&path, &mut |copied, expected| {
+                let mut state = self.state.lock().unwrap();
+                if let Some(running) = state.progress.running.iter_mut().find(|(p, ..)| *p == path)
+                {
+                    running.1 = copied;
+                    running.2 = expected;
+                }
+                self.report(&mut state, false);
+            });
+            if let Err(err) = &outcome {
+                warn!("Failed to realize {}: {}", path, err);
+            }
+
+            let mut state = self.state.lock().unwrap();
+            state.progress.running.retain(|(p, ..)| *p != path);
+            if outcome.is_ok() {
+                state.progress.done += 1;
+            } else {
+                state.progress.failed += 1;
+            }
+            state.progress.finished = Some((path.clone(), outcome.is_ok()));
+            self.report(&mut state, true);
+            if let Some(realization) = state.realizations.remove(&path) {
+                realization.finish(outcome);
+            }
+            // The next batch is counted from scratch.
+            if state.progress.is_complete() {
+                state.progress = RealizationProgress::default();
+            }
+        }
+    }
+
+    /// Tell the UI where the realizations stand, byte counts only once in a while.
+    fn report(&self, state: &mut State, changed: bool) {
+        let send_ui_event = match &self.send_ui_event {
+            Some(send_ui_event) => send_ui_event,
+            None => return,
+        };
+        if !changed
+            && state
+                .reported
+                .map_or(false, |at| at.elapsed() < PROGRESS_INTERVAL)
+        {
+            return;
+        }
+        state.reported = Some(Instant::now());
+        let _ = send_ui_event.send(UserRequest::Realizing(state.progress.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_realizations_are_shared_and_bounded() {
+        let (calls, running, most_running) = (
+            Arc::new(AtomicUsize::new(0)),
+            Arc::new(AtomicUsize::new(0)),
+            Arc::new(AtomicUsize::new(0)),
+        );
+        let (send, recv) = std::sync::mpsc::channel();
+        let mut realizer = Realizer::new(
+            2,
+            RetryPolicy {
+                retries: 0,
+                ..Default::default()
+            },
+            Some(send),
+        );
+        let counters = (calls.clone(), running.clone(), most_running.clone());
+        realizer.realize = Arc::new(move |path, _| {
+            let (calls, running, most_running) = &counters;
+            calls.fetch_add(1, Ordering::SeqCst);
+            most_running.fetch_max(running.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(20));
+            running.fetch_sub(1, Ordering::SeqCst);
+            if path.ends_with("-broken") {
+                Err("no substituter has it".to_string())
+            } else {
+                Ok(())
+            }
+        });
+
+        let zlib = "/nix/store/zy4xhzc6x6ldrfzqzfa4x3cw8ds1mgcn-zlib-1.3-dev";
+        let waiting: Vec<_> = [
+            format!("{}/include/zlib.h", zlib),
+            format!("{}/lib/pkgconfig/zlib.pc", zlib),
+            "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-openssl-3.0".to_string(),
+            "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-curl-8.4".to_string(),
+            "/nix/store/cccccccccccccccccccccccccccccccc-broken".to_string(),
+        ]
+        .iter()
+        .map(|path| realizer.realize(path))
+        .collect();
+        let outcomes: Vec<_> = waiting
+            .iter()
+            .map(|realization| realization.wait())
+            .collect();
+
+        assert!(outcomes[..4].iter().all(Result::is_ok));
+        assert_eq!(outcomes[4], Err("no substituter has it".to_string()));
+        assert_eq!(calls.load(Ordering::SeqCst), 4, "zlib is realized once");
+        assert!(most_running.load(Ordering::SeqCst) <= 2);
+
+        let last = recv
+            .try_iter()
+            .filter_map(|request| match request {
+                UserRequest::Realizing(progress) => Some(progress),
+                _ => None,
+            })
+            .last()
+            .unwrap();
+        assert_eq!((last.total, last.done, last.failed), (4, 3, 1));
+    }
+
+    #[test]
+    fn test_failed_substitutions_are_retried_then_built() {
+        let attempts = Arc::new(Mutex::new(HashMap::<String, usize>::new()));
+        let built = Arc::new(Mutex::new(Vec::new()));
+        let mut realizer = Realizer::new(
+            1,
+            RetryPolicy {
+                retries: 2,
+                first_backoff: Duration::from_millis(1),
+                build_fallback: true,
+            },
+            None,
+        );
+        let counted = attempts.clone();
+        realizer.realize = Arc::new(move |path, _| {
+            let mut attempts = counted.lock().unwrap();
+            let attempt = attempts.entry(path.to_string()).or_default();
+            *attempt += 1;
+            // The cache comes back for zlib on the second attempt only.
+            if path.ends_with("-zlib-1.3") && *attempt == 2 {
+                Ok(())
+            } else {
+                Err("cache.nixos.org is not reachable".to_string())
+            }
+        });
+        let builds = built.clone();
+        realizer.build = Arc::new(move |_, installable| {
+            builds.lock().unwrap().push(installable.to_string());
+            Ok(())
+        });
+
+        let origin = |attr: &str, output: &str| crate::cache::PathOrigin {
+            attr: attr.to_string(),
+            output: output.to_string(),
+            toplevel: true,
+            system: None,
+            index: None,
+        };
+        let zlib = "/nix/store/zy4xhzc6x6ldrfzqzfa4x3cw8ds1mgcn-zlib-1.3";
+        let openssl = "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-openssl-3.0-dev";
+        let unknown = "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-unknown";
+        realizer.remember(&StorePath::parse(origin("openssl_3", "dev"), openssl).unwrap());
+
+        assert_eq!(realizer.realize(zlib).wait(), Ok(()));
+        assert_eq!(realizer.realize(openssl).wait(), Ok(()));
+        assert!(realizer.realize(unknown).wait().is_err());
+
+        let attempts = attempts.lock().unwrap();
+        assert_eq!(attempts[zlib], 2);
+        assert_eq!(attempts[openssl], 3);
+        assert_eq!(attempts[unknown], 3);
+        assert_eq!(*built.lock().unwrap(), ["openssl_3.dev"]);
+    }
+}
@@ -0,0 +1,415 @@
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::digest::{base64, sha1};
+use crate::frontend::{AnswerError, Frontend, JsonAnswer, PendingPrompts};
+use crate::fs::FsEventMessage;
+use crate::interactive::UserRequest;
+
+/// Appended to the key of a WebSocket handshake, see RFC 6455.
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Pages not reading their WebSocket anymore are dropped after this.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Answers are a few bytes of JSON, larger bodies are refused rather than read.
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+const PAGE: &str = include_str!("remote.html");
+
+#[derive(Default)]
+struct State {
+    prompts: PendingPrompts,
+    /// Pages following the prompts over a WebSocket
+    subscribers: Vec<TcpStream>,
+}
+
+impl State {
+    fn snapshot(&self) -> String {
+        self.prompts.to_json().to_string()
+    }
+
+    /// Send the prompts to every page following them.
+    fn broadcast(&mut self) {
+        let frame = text_frame(&self.snapshot());
+        self.subscribers
+            .retain_mut(|subscriber| subscriber.write_all(&frame).is_ok());
+    }
+}
+
+/// Unmasked and unfragmented, as servers send them.
+fn text_frame(text: &str) -> Vec<u8> {
+    let mut frame = vec![0x81];
+    match text.len() {
+        length if length < 126 => frame.push(length as u8),
+        length if length <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(text.as_bytes());
+
+    frame
+}
+
+fn websocket_accept(key: &str) -> String {
+    base64(&sha1(format!("{}{}", key, WEBSOCKET_GUID).as_bytes()))
+}
+
+/// Secret of a session, only those it was given to can see and answer its prompts.
+pub fn random_token() -> io::Result<String> {
+    let mut bytes = [0u8; 16];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Token of the request, from its `Authorization: Bearer` header
+/// or else its `token` query parameter, the only way for a page to give it to a WebSocket.
+fn request_token<'a>(
+    query: Option<&'a str>,
+    headers: &'a HashMap<String, String>,
+) -> Option<&'a str> {
+    headers
+        .get("authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+        .or_else(|| {
+            query?
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("token="))
+        })
+}
+
+/// Compared in constant time, not to tell how much of the token a guess got right.
+fn same_token(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |difference, (given, expected)| {
+                difference | (given ^ expected)
+            })
+            == 0
+}
+
+/// Whether the request comes from our page or a script: browsers tell which site a request comes from,
+/// any page could send one to a server on localhost otherwise.
+fn same_origin(headers: &HashMap<String, String>) -> bool {
+    match headers.get("origin") {
+        None => true,
+        Some(origin) => {
+            let host = origin
+                .strip_prefix("http://")
+                .or_else(|| origin.strip_prefix("https://"));
+            host.is_some() && host == headers.get("host").map(String::as_str)
+        }
+    }
+}
+
+fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &str) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+fn answer(
+    state: &Mutex<State>,
+    reply_fs: &Sender<FsEventMessage>,
+    request_id: u64,
+    body: &[u8],
+) -> Result<(), (&'static str, String)> {
+    let answer: JsonAnswer =
+        serde_json::from_slice(body).map_err(|err| ("400 Bad Request", err.to_string()))?;
+    let mut state = state.lock().unwrap();
+    state
+        .prompts
+        .answer(request_id, answer, reply_fs)
+        .map_err(|err| match err {
+            AnswerError::NotPending(_) => ("404 Not Found", err.to_string()),
+            AnswerError::Invalid => ("400 Bad Request", err.to_string()),
+        })?;
+    state.broadcast();
+
+    Ok(())
+}
+
+fn handle(
+    mut stream: TcpStream,
+    state: &Mutex<State>,
+    reply_fs: &Sender<FsEventMessage>,
+    token: &str,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? <= 2 {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+
+    let mut words = request_line.split_whitespace();
+    let (method, target) = (
+        words.next().unwrap_or_default(),
+        words.next().unwrap_or_default(),
+    );
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (target, None),
+    };
+    if !same_origin(&headers) {
+        return respond(
+            &mut stream,
+            "403 Forbidden",
+            "text/plain",
+            "Cross-origin requests are refused",
+        );
+    }
+    // The page holds no data, it reads the token from its own URL.
+    let authorized = request_token(query, &headers).map_or(false, |given| same_token(given, token));
+    if path != "/" && !authorized {
+        return respond(
+            &mut stream,
+            "401 Unauthorized",
+            "text/plain",
+            "The token printed with the URL is expected",
+        );
+    }
+
+    match (method, path) {
+        ("GET", "/") => respond(&mut stream, "200 OK", "text/html; charset=utf-8", PAGE),
+        ("GET", "/api/prompts") => {
+            let prompts = state.lock().unwrap().snapshot();
+            respond(&mut stream, "200 OK", "application/json", &prompts)
+        }
+        ("GET", "/ws") => match headers.get("sec-websocket-key") {
+            Some(key) => {
+                write!(
+                    stream,
+                    "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                    websocket_accept(key)
+                )?;
+                stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+                let mut state = state.lock().unwrap();
+                stream.write_all(&text_frame(&state.snapshot()))?;
+                state.subscribers.push(stream);
+                Ok(())
+            }
+            None => respond(
+                &mut stream,
+                "400 Bad Request",
+                "text/plain",
+                "WebSocket handshake expected",
+            ),
+        },
+        ("POST", path) => {
+            let request_id = match path
+                .strip_prefix("/api/prompts/")
+                .and_then(|id| id.parse::<u64>().ok())
+            {
+                Some(request_id) => request_id,
+                None => return respond(&mut stream, "404 Not Found", "text/plain", "Not found"),
+            };
+            // Unlike a form, JSON cannot be sent from another site without its consent.
+            let json = headers.get("content-type").map_or(false, |content_type| {
+                let mime = content_type.split(';').next().unwrap_or_default();
+                mime.trim().eq_ignore_ascii_case("application/json")
+            });
+            if !json {
+                return respond(
+                    &mut stream,
+                    "415 Unsupported Media Type",
+                    "text/plain",
+                    "application/json expected",
+                );
+            }
+            let length = headers
+                .get("content-length")
+                .and_then(|length| length.parse().ok())
+                .unwrap_or(0);
+            if length > MAX_BODY_SIZE {
+                return respond(
+                    &mut stream,
+                    "413 Payload Too Large",
+                    "text/plain",
+                    "Answer too large",
+                );
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body)?;
+
+            match answer(state, reply_fs, request_id, &body) {
+                Ok(()) => respond(&mut stream, "204 No Content", "text/plain", ""),
+                Err((status, message)) => respond(&mut stream, status, "text/plain", &message),
+            }
+        }
+        _ => respond(&mut stream, "404 Not Found", "text/plain", "Not found"),
+    }
+}
+
+/// Serves the pending prompts on `address` so that they can be answered from a browser or a script:
+/// a page at `/`, the JSON API under `/api/prompts` and their updates over a WebSocket at `/ws`.
+/// The API and the WebSocket require the `token`, see `url`.
+pub struct RemoteFrontend {
+    pub address: SocketAddr,
+    pub token: String,
+}
+
+impl RemoteFrontend {
+    /// Page of the prompts, with the token it passes to the API.
+    pub fn url(&self) -> String {
+        format!("http://{}/?token={}", self.address, self.token)
+    }
+}
+
+impl Frontend for RemoteFrontend {
+    fn spawn(
+        self: Box<Self>,
+        reply_fs: Sender<FsEventMessage>,
+    ) -> io::Result<(Option<JoinHandle<()>>, Sender<UserRequest>)> {
+        let listener = TcpListener::bind(self.address)?;
+        let (send_request, recv_request) = channel();
+        let state = Arc::new(Mutex::new(State::default()));
+        debug!("serving the prompts on {}", self.url());
+        let token: Arc<str> = self.token.into();
+
+        let prompts = state.clone();
+        thread::spawn(move || {
+            for request in recv_request {
+                if let UserRequest::Quit = request {
+                    break;
+                }
+                let mut state = prompts.lock().unwrap();
+                if state.prompts.update(&request) {
+                    state.broadcast();
+                }
+            }
+        });
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        warn!("Failed to accept a remote connection: {}", err);
+                        continue;
+                    }
+                };
+                let (state, reply_fs, token) = (state.clone(), reply_fs.clone(), token.clone());
+                thread::spawn(move || {
+                    if let Err(err) = handle(stream, &state, &reply_fs, &token) {
+                        debug!("remote connection failed: {}", err);
+                    }
+                });
+            }
+        });
+
+        Ok((None, send_request))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_websocket_handshake_and_frames() {
+        // The example of RFC 6455.
+        assert_eq!(
+            websocket_accept("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+        assert_eq!(text_frame("[]"), b"\x81\x02[]");
+        let long = "x".repeat(300);
+        assert_eq!(&text_frame(&long)[..4], &[0x81, 126, 0x01, 0x2c]);
+    }
+
+    /// Status line of the response to `request`, handled with the token `secret`.
+    fn status_of(request: &str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(request.as_bytes()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let (reply_fs, _recv) = channel();
+        handle(server, &Mutex::new(State::default()), &reply_fs, "secret").unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response.lines().next().unwrap_or_default().to_string()
+    }
+
+    #[test]
+    fn test_requests_are_checked_before_being_served() {
+        let get = |headers: &str| {
+            status_of(&format!(
+                "GET /api/prompts HTTP/1.1\r\nHost: h\r\n{}\r\n",
+                headers
+            ))
+        };
+        assert_eq!(get(""), "HTTP/1.1 401 Unauthorized");
+        assert_eq!(
+            get("Authorization: Bearer guess\r\n"),
+            "HTTP/1.1 401 Unauthorized"
+        );
+        assert_eq!(get("Authorization: Bearer secret\r\n"), "HTTP/1.1 200 OK");
+        assert_eq!(
+            status_of("GET /api/prompts?token=secret HTTP/1.1\r\nHost: h\r\n\r\n"),
+            "HTTP/1.1 200 OK"
+        );
+        assert_eq!(
+            get("Authorization: Bearer secret\r\nOrigin: http://h\r\n"),
+            "HTTP/1.1 200 OK"
+        );
+        assert_eq!(
+            get("Authorization: Bearer secret\r\nOrigin: http://elsewhere\r\n"),
+            "HTTP/1.1 403 Forbidden"
+        );
+        // The page itself carries no data.
+        assert_eq!(
+            status_of("GET / HTTP/1.1\r\nHost: h\r\n\r\n"),
+            "HTTP/1.1 200 OK"
+        );
+
+        let post = |headers: &str, body: &str| {
+            status_of(&format!(
+                "POST /api/prompts/1 HTTP/1.1\r\nHost: h\r\nAuthorization: Bearer secret\r\n{}Content-Length: {}\r\n\r\n{}",
+                headers,
+                body.len(),
+                body
+            ))
+        };
+        assert_eq!(
+            post("Content-Type: text/plain\r\n", r#"{"skip": true}"#),
+            "HTTP/1.1 415 Unsupported Media Type"
+        );
+        assert_eq!(
+            post("Content-Type: application/json\r\n", r#"{"skip": true}"#),
+            "HTTP/1.1 404 Not Found"
+        );
+        assert_eq!(
+            status_of("POST /api/prompts/1 HTTP/1.1\r\nAuthorization: Bearer secret\r\nContent-Type: application/json\r\nContent-Length: 4294967296\r\n\r\n"),
+            "HTTP/1.1 413 Payload Too Large"
+        );
+    }
+}
@@ -1,8 +1,28 @@
+use log::{debug, error, warn};
+use regex::{Regex, RegexBuilder};
 use serde::{Deserialize, Serialize};
-use std::{collections::BTreeMap, fs, path::PathBuf};
+use std::fmt;
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::PathBuf,
+};
 use thiserror::Error;
+use walkdir::WalkDir;
 
 use crate::cache::StorePath;
+use crate::fetch;
+use crate::flake::{self, FlakeOutputs};
+use crate::include::{read_resolution_file_with_includes, Include};
+use crate::interactive;
+use crate::outputs;
+use crate::pin::{IndexPin, PinCheck};
+use crate::process::ProcessInfo;
+use crate::signature::SignatureCheck;
 
 #[derive(Error, Debug)]
 pub enum ParseResolutionError {
@@ -10,10 +30,98 @@ pub enum ParseResolutionError {
     MissingField(String),
     #[error("expected type `{0}` for field `{1}`")]
     UnexpectedType(String, String),
+    #[error("invalid `{0}`: {1}")]
+    InvalidValue(String, String),
+    #[error("schema version {0} is newer than the supported one ({1}), upgrade buildxyz")]
+    UnsupportedSchemaVersion(i64, i64),
 }
 
 type ParseResult<T> = Result<T, ParseResolutionError>;
 
+/// Version of the resolution files written by this build.
+/// Bump it with a new migration whenever older files need one to keep loading.
+pub const SCHEMA_VERSION: i64 = 1;
+
+/// `MIGRATIONS[n]` turns a document of schema version `n` into version `n + 1`.
+const MIGRATIONS: [fn(&mut toml::Table); SCHEMA_VERSION as usize] = [migrate_unversioned];
+
+/// Unversioned files predate phase and pattern resolutions,
+/// their resolutions were all constant and may omit it.
+fn migrate_unversioned(document: &mut toml::Table) {
+    for (_, resolution) in document.iter_mut() {
+        if let toml::Value::Table(resolution) = resolution {
+            resolution
+                .entry("resolution")
+                .or_insert_with(|| "constant".into());
+        }
+    }
+}
+
+/// Bring a document of any supported schema version to the current one.
+fn migrate(document: &mut toml::Table) -> ParseResult<()> {
+    let version = match document.remove("schema_version") {
+        Some(toml::Value::Integer(version)) => version,
+        None => 0,
+        _ => {
+            return Err(ParseResolutionError::UnexpectedType(
+                "integer".into(),
+                "schema_version".into(),
+            ))
+        }
+    };
+    if !(0..=SCHEMA_VERSION).contains(&version) {
+        return Err(ParseResolutionError::UnsupportedSchemaVersion(
+            version,
+            SCHEMA_VERSION,
+        ));
+    }
+
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(document);
+    }
+
+    Ok(())
+}
+
+/// How the leaves of a provided store path are materialized in the fast working tree.
+#[derive(
+    clap::ValueEnum, Clone, Copy, Default, Eq, Hash, PartialEq, Serialize, Deserialize, Debug,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum LinkMode {
+    /// Symlink into the Nix store
+    #[default]
+    Symlink,
+    /// Hardlink from the Nix store, copy if it lives on another filesystem
+    Hardlink,
+    /// Copy out of the Nix store
+    Copy,
+}
+
+impl LinkMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Symlink => "symlink",
+            Self::Hardlink => "hardlink",
+            Self::Copy => "copy",
+        }
+    }
+
+    fn from_str(v: &str) -> ParseResult<Self> {
+        Ok(match v {
+            "symlink" => Self::Symlink,
+            "hardlink" => Self::Hardlink,
+            "copy" => Self::Copy,
+            _ => {
+                return Err(ParseResolutionError::UnexpectedType(
+                    "`symlink`, `hardlink` or `copy`".into(),
+                    "link_mode".into(),
+                ))
+            }
+        })
+    }
+}
+
 /// Resolution is data that enable the tool to automate a situation where
 /// a manual decision has to be taken.
 
@@ -22,6 +130,13 @@ pub struct ProvideData {
     pub kind: fuser::FileType,
     pub file_entry_name: String,
     pub store_path: StorePath,
+    /// Overrides the session link mode for this store path
+    #[serde(default)]
+    pub link_mode: Option<LinkMode>,
+    /// Flake installable the store path is built from, e.g. `github:acme/tools#mytool`,
+    /// it is built again when the store path is not there, see `flake::FlakeOutputs`
+    #[serde(default)]
+    pub flake: Option<String>,
 }
 
 fn parse_filetype_kind(v: &str) -> ParseResult<fuser::FileType> {
@@ -43,6 +158,11 @@ fn parse_filetype_kind(v: &str) -> ParseResult<fuser::FileType> {
 }
 
 impl ProvideData {
+    /// Attribute and output of the provided store path, e.g. `zlib.dev`.
+    pub fn installable(&self) -> String {
+        outputs::installable(&self.store_path.origin())
+    }
+
     pub fn to_human_toml_table(&self) -> toml::Table {
         let mut table = toml::Table::new();
 
@@ -67,11 +187,52 @@ impl ProvideData {
             "store_path".into(),
             toml::Table::try_from(&self.store_path).unwrap().into(),
         );
+        if let Some(link_mode) = self.link_mode {
+            table.insert("link_mode".into(), link_mode.as_str().into());
+        }
+        if let Some(flake) = &self.flake {
+            table.insert("flake".into(), flake.clone().into());
+        }
 
         table
     }
 
+    /// A provide may name a flake installable rather than a store path,
+    /// it is built then, once per lock file, see `flake::FlakeOutputs`.
     pub fn from_toml(mut data: toml::Table) -> ParseResult<Self> {
+        let flake = match data.remove("flake") {
+            Some(toml::Value::String(v)) => Some(v),
+            None => None,
+            _ => {
+                return Err(ParseResolutionError::UnexpectedType(
+                    "string".into(),
+                    "flake".into(),
+                ))
+            }
+        };
+        // use the deserializer here.
+        let file_entry_name = data
+            .remove("file_entry_name")
+            .map(|v| match v {
+                toml::Value::String(v) => Ok(v),
+                _ => Err(ParseResolutionError::UnexpectedType(
+                    "string".into(),
+                    "file_entry_name".into(),
+                )),
+            })
+            .ok_or_else(|| ParseResolutionError::MissingField("file_entry_name".into()))??;
+        let store_path: StorePath = match (data.remove("store_path"), &flake) {
+            (Some(store_path), _) => store_path.try_into().map_err(|err: toml::de::Error| {
+                ParseResolutionError::InvalidValue("store_path".into(), err.message().into())
+            })?,
+            (None, Some(installable)) => flake::store_path_of(installable, &file_entry_name)
+                .map_err(|err| ParseResolutionError::InvalidValue("flake".into(), err))?,
+            (None, None) => return Err(ParseResolutionError::MissingField("store_path".into())),
+        };
+        if let Some(installable) = &flake {
+            FlakeOutputs::shared().remember(installable, [store_path.as_str().as_ref()]);
+        }
+
         Ok(ProvideData {
             kind: match data.get("kind") {
                 Some(toml::Value::String(v)) => parse_filetype_kind(v)?,
@@ -83,19 +244,19 @@ impl ProvideData {
                     ))
                 }
             },
-            // use the deserializer here.
-            file_entry_name: data
-                .remove("file_entry_name")
-                .map(|v| match v {
-                    toml::Value::String(v) => Ok(v),
-                    _ => Err(ParseResolutionError::UnexpectedType(
+            file_entry_name,
+            store_path,
+            link_mode: match data.get("link_mode") {
+                Some(toml::Value::String(v)) => Some(LinkMode::from_str(v)?),
+                None => None,
+                _ => {
+                    return Err(ParseResolutionError::UnexpectedType(
                         "string".into(),
-                        "file_entry_name".into(),
-                    )),
-                })
-                .ok_or_else(|| ParseResolutionError::MissingField("file_entry_name".into()))??,
-            store_path: data.remove("store_path").expect("missing `store_path` field").try_into()
-            .unwrap(),
+                        "link_mode".into(),
+                    ))
+                }
+            },
+            flake,
         })
     }
 }
@@ -104,9 +265,50 @@ impl ProvideData {
 #[serde(tag = "decision")]
 pub enum Decision {
     /// Provide this store path
-    Provide(ProvideData),
+    Provide(Box<ProvideData>),
     /// Returns ENOENT
     Ignore,
+    /// Redirect to this path of the host filesystem
+    HostFallback { host_path: PathBuf },
+    /// Returns ENOENT for this path and everything under it
+    IgnoreTree,
+    /// Never resolve automatically, always ask the user, even in automatic mode.
+    /// Answers are not recorded.
+    Defer,
+    /// Redirect to this path, e.g. a tool installed outside of Nix.
+    /// Relative paths are relative to the directory buildxyz runs from.
+    Redirect { target: PathBuf },
+    /// Serve a generated file, e.g. for probes only checking that a marker file exists.
+    /// `@prefix@` in the contents is replaced by the fast working tree.
+    Stub {
+        #[serde(default)]
+        contents: String,
+    },
+    /// Disable the resolution of lower files for this path, e.g. a core one, whatever its priority.
+    /// Dropped once every file is merged.
+    Tombstone,
+}
+
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Provide(data) => write!(
+                f,
+                "provide {} from {} ({})",
+                data.file_entry_name,
+                data.installable(),
+                data.store_path.as_str()
+            ),
+            Self::Ignore => write!(f, "ignore"),
+            Self::IgnoreTree => write!(f, "ignore the whole tree"),
+            Self::Defer => write!(f, "always ask"),
+            Self::HostFallback { host_path } => write!(f, "fall back to {}", host_path.display()),
+            Self::Redirect { target } => write!(f, "redirect to {}", target.display()),
+            Self::Stub { contents } if contents.is_empty() => write!(f, "empty stub"),
+            Self::Stub { contents } => write!(f, "stub of {} bytes", contents.len()),
+            Self::Tombstone => write!(f, "disable"),
+        }
+    }
 }
 
 impl Decision {
@@ -121,6 +323,32 @@ impl Decision {
             Self::Ignore => {
                 table.insert("decision".into(), "ignore".into());
             }
+            Self::IgnoreTree => {
+                table.insert("decision".into(), "ignore-tree".into());
+            }
+            Self::Defer => {
+                table.insert("decision".into(), "defer".into());
+            }
+            Self::HostFallback { host_path } => {
+                table.insert("decision".into(), "host-fallback".into());
+                table.insert(
+                    "host_path".into(),
+                    host_path.to_string_lossy().to_string().into(),
+                );
+            }
+            Self::Redirect { target } => {
+                table.insert("decision".into(), "redirect".into());
+                table.insert("target".into(), target.to_string_lossy().to_string().into());
+            }
+            Self::Stub { contents } => {
+                table.insert("decision".into(), "stub".into());
+                if !contents.is_empty() {
+                    table.insert("contents".into(), contents.clone().into());
+                }
+            }
+            Self::Tombstone => {
+                table.insert("decision".into(), "tombstone".into());
+            }
         }
 
         table
@@ -130,10 +358,49 @@ impl Decision {
         Ok(match decision.get("decision") {
             Some(toml::Value::String(decision_choice)) => match decision_choice.as_str() {
                 "ignore" => Self::Ignore,
-                "provide" => Self::Provide(ProvideData::from_toml(decision)?),
+                "ignore-tree" => Self::IgnoreTree,
+                "defer" => Self::Defer,
+                "tombstone" => Self::Tombstone,
+                "provide" => Self::Provide(Box::new(ProvideData::from_toml(decision)?)),
+                "host-fallback" => Self::HostFallback {
+                    host_path: match decision.get("host_path") {
+                        Some(toml::Value::String(v)) => PathBuf::from(v),
+                        None => return Err(ParseResolutionError::MissingField("host_path".into())),
+                        _ => {
+                            return Err(ParseResolutionError::UnexpectedType(
+                                "string".into(),
+                                "host_path".into(),
+                            ))
+                        }
+                    },
+                },
+                "redirect" => Self::Redirect {
+                    target: match decision.get("target") {
+                        Some(toml::Value::String(v)) => PathBuf::from(v),
+                        None => return Err(ParseResolutionError::MissingField("target".into())),
+                        _ => {
+                            return Err(ParseResolutionError::UnexpectedType(
+                                "string".into(),
+                                "target".into(),
+                            ))
+                        }
+                    },
+                },
+                "stub" => Self::Stub {
+                    contents: match decision.get("contents") {
+                        Some(toml::Value::String(v)) => v.clone(),
+                        None => String::new(),
+                        _ => {
+                            return Err(ParseResolutionError::UnexpectedType(
+                                "string".into(),
+                                "contents".into(),
+                            ))
+                        }
+                    },
+                },
                 _ => {
                     return Err(ParseResolutionError::UnexpectedType(
-                        "`ignore` or `provide`".into(),
+                        "`ignore`, `ignore-tree`, `defer`, `provide`, `host-fallback`, `redirect`, `stub` or `tombstone`".into(),
                         "decision".into(),
                     ))
                 }
@@ -141,7 +408,7 @@ impl Decision {
             None => return Err(ParseResolutionError::MissingField("decision".into())),
             _ => {
                 return Err(ParseResolutionError::UnexpectedType(
-                    "`ignore` or `provide`".into(),
+                    "`ignore`, `ignore-tree`, `defer`, `provide`, `host-fallback`, `redirect`, `stub` or `tombstone`".into(),
                     "decision".into(),
                 ))
             }
@@ -149,60 +416,299 @@ impl Decision {
     }
 }
 
+// Variant names are the `resolution` tags of serialized databases, renaming them breaks existing files.
+#[allow(clippy::enum_variant_names)]
 #[derive(Serialize, Deserialize, Eq, Hash, PartialEq, Clone, Debug)]
 #[serde(tag = "resolution")]
 #[non_exhaustive]
 pub enum Resolution {
     /// Constant resolution is always issued no matter the context.
     ConstantResolution(ResolutionData),
+    /// Phase resolution is only issued in the build phase it was recorded in.
+    PhaseResolution(PhaseResolutionData),
+    /// Pattern resolution is issued for every requested path matching its glob or regex,
+    /// unless an exact resolution exists.
+    PatternResolution(PatternResolutionData),
 }
 
 impl Resolution {
     pub fn requested_path(&self) -> &String {
         match self {
             Self::ConstantResolution(res_data) => &res_data.requested_path,
+            Self::PhaseResolution(phase_data) => &phase_data.requested_path,
+            Self::PatternResolution(pattern_data) => &pattern_data.data.requested_path,
+        }
+    }
+
+    /// Resolution data issued in this build phase, if any.
+    pub fn data_for_phase(&self, phase: Option<&str>) -> Option<&ResolutionData> {
+        match self {
+            Self::ConstantResolution(res_data) => Some(res_data),
+            Self::PhaseResolution(phase_data) => phase.and_then(|phase| phase_data.phases.get(phase)),
+            Self::PatternResolution(pattern_data) => Some(&pattern_data.data),
+        }
+    }
+
+    /// All resolution data, whatever the phase.
+    pub fn all_data(&self) -> Vec<&ResolutionData> {
+        match self {
+            Self::ConstantResolution(res_data) => vec![res_data],
+            Self::PhaseResolution(phase_data) => phase_data.phases.values().collect(),
+            Self::PatternResolution(pattern_data) => vec![&pattern_data.data],
+        }
+    }
+
+    pub fn all_data_mut(&mut self) -> Vec<&mut ResolutionData> {
+        match self {
+            Self::ConstantResolution(res_data) => vec![res_data],
+            Self::PhaseResolution(phase_data) => phase_data.phases.values_mut().collect(),
+            Self::PatternResolution(pattern_data) => vec![&mut pattern_data.data],
         }
     }
 
+    /// Highest priority among its data.
+    pub fn priority(&self) -> i64 {
+        self.all_data()
+            .into_iter()
+            .map(|data| data.priority)
+            .max()
+            .unwrap_or_default()
+    }
+
+    pub fn source(&self) -> &str {
+        self.all_data()
+            .into_iter()
+            .find_map(|data| data.source.as_deref())
+            .unwrap_or("this session")
+    }
+
+    /// Decisions by phase, `None` for those issued in every phase.
+    pub fn decisions(&self) -> Vec<(Option<&str>, &Decision)> {
+        match self {
+            Self::PhaseResolution(phase_data) => phase_data
+                .phases
+                .iter()
+                .map(|(phase, data)| (Some(phase.as_str()), &data.decision))
+                .collect(),
+            _ => self
+                .all_data()
+                .into_iter()
+                .map(|data| (None, &data.decision))
+                .collect(),
+        }
+    }
+
+    /// Whether both resolutions take the same decisions, wherever they come from.
+    pub fn agrees_with(&self, other: &Resolution) -> bool {
+        self.decisions() == other.decisions()
+    }
+
+    /// Human description of the decisions, e.g. for conflict reports.
+    pub fn describe(&self) -> String {
+        self.decisions()
+            .into_iter()
+            .map(|(phase, decision)| match phase {
+                Some(phase) => format!("{} in phase {}", decision, phase),
+                None => decision.to_string(),
+            })
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+
+    /// Whether this resolution only disables the ones of lower files.
+    pub fn is_tombstone(&self) -> bool {
+        self.all_data()
+            .iter()
+            .all(|data| data.decision == Decision::Tombstone)
+    }
+
+    /// Whether this resolution is keyed by a pattern rather than a requested path.
+    pub fn is_pattern(&self) -> bool {
+        matches!(self, Self::PatternResolution(_))
+    }
+
     pub fn to_human_toml_table(&self) -> toml::Table {
         let mut gtable = toml::Table::new();
 
-        let Self::ConstantResolution(data) = self;
-
-        {
-            let mut table = toml::Table::new();
-            table.insert("resolution".into(), "constant".into());
-            table.extend(data.decision.to_human_toml_table());
-            gtable.insert(data.requested_path.clone(), table.into());
+        match self {
+            Self::ConstantResolution(data) => {
+                let mut table = toml::Table::new();
+                table.insert("resolution".into(), "constant".into());
+                table.extend(data.to_human_toml_table());
+                gtable.insert(data.requested_path.clone(), table.into());
+            }
+            Self::PhaseResolution(phase_data) => {
+                let mut table = toml::Table::new();
+                table.insert("resolution".into(), "phase".into());
+                table.insert(
+                    "phases".into(),
+                    phase_data
+                        .phases
+                        .iter()
+                        .map(|(phase, data)| (phase.clone(), data.to_human_toml_table().into()))
+                        .collect::<toml::Table>()
+                        .into(),
+                );
+                gtable.insert(phase_data.requested_path.clone(), table.into());
+            }
+            Self::PatternResolution(pattern_data) => {
+                let mut table = toml::Table::new();
+                table.insert("resolution".into(), "pattern".into());
+                table.insert("syntax".into(), pattern_data.syntax.as_str().into());
+                table.extend(pattern_data.data.to_human_toml_table());
+                if let Decision::Provide(provide_data) = &pattern_data.data.decision {
+                    if provide_data.file_entry_name.is_empty() {
+                        table.remove("file_entry_name");
+                    }
+                }
+                gtable.insert(pattern_data.data.requested_path.clone(), table.into());
+            }
         }
 
         gtable
     }
 
     pub fn from_toml_item(resolution: (String, toml::Value)) -> ParseResult<(String, Self)> {
-        Ok((
-            resolution.0.clone(),
-            Self::ConstantResolution(ResolutionData {
-                requested_path: resolution.0.clone(),
-                decision: Decision::from_toml(match resolution.1 {
-                    toml::Value::Table(table) => table,
+        let mut table = match resolution.1 {
+            toml::Value::Table(table) => table,
+            _ => {
+                return Err(ParseResolutionError::UnexpectedType(
+                    "a table".into(),
+                    resolution.0,
+                ))
+            }
+        };
+
+        let parsed = match table.get("resolution") {
+            Some(toml::Value::String(kind)) if kind == "phase" => {
+                let phases = match table.remove("phases") {
+                    Some(toml::Value::Table(phases)) => phases,
+                    None => return Err(ParseResolutionError::MissingField("phases".into())),
                     _ => {
                         return Err(ParseResolutionError::UnexpectedType(
                             "a table".into(),
-                            resolution.0,
+                            "phases".into(),
+                        ))
+                    }
+                };
+
+                Self::PhaseResolution(PhaseResolutionData {
+                    requested_path: resolution.0.clone(),
+                    phases: phases
+                        .into_iter()
+                        .map(|(phase, data)| match data {
+                            toml::Value::Table(data) => Ok((
+                                phase,
+                                ResolutionData::from_toml(resolution.0.clone(), data)?,
+                            )),
+                            _ => Err(ParseResolutionError::UnexpectedType(
+                                "a table".into(),
+                                phase,
+                            )),
+                        })
+                        .collect::<ParseResult<BTreeMap<String, ResolutionData>>>()?,
+                })
+            }
+            Some(toml::Value::String(kind)) if kind == "pattern" => {
+                let syntax = match table.remove("syntax") {
+                    Some(toml::Value::String(syntax)) => PatternSyntax::from_str(&syntax)?,
+                    None => PatternSyntax::Glob,
+                    _ => {
+                        return Err(ParseResolutionError::UnexpectedType(
+                            "string".into(),
+                            "syntax".into(),
                         ))
                     }
-                })?,
-            }),
-        ))
+                };
+                // Provided entries default to the requested path, served as a symlink.
+                if table.get("decision").and_then(|v| v.as_str()) == Some("provide") {
+                    table
+                        .entry("file_entry_name")
+                        .or_insert_with(|| String::new().into());
+                    table
+                        .entry("kind")
+                        .or_insert_with(|| "symlink".into());
+                }
+
+                Self::PatternResolution(PatternResolutionData {
+                    syntax,
+                    data: ResolutionData::from_toml(resolution.0.clone(), table)?,
+                })
+            }
+            _ => Self::ConstantResolution(ResolutionData::from_toml(resolution.0.clone(), table)?),
+        };
+
+        Ok((resolution.0, parsed))
     }
 
-    pub fn from_toml(resolutions: toml::Value) -> ParseResult<ResolutionDB> {
+    /// Parse a whole document. Resolutions which do not parse are returned apart, by requested path,
+    /// the others are loaded anyway.
+    pub fn from_toml(
+        resolutions: toml::Value,
+    ) -> ParseResult<(ResolutionDB, Vec<(String, ParseResolutionError)>)> {
         match resolutions {
-            toml::Value::Table(resolutions_map) => Ok(resolutions_map
-                .into_iter()
-                .map(Self::from_toml_item)
-                .collect::<ParseResult<ResolutionDB>>()?),
+            toml::Value::Table(mut resolutions_map) => {
+                // Read separately by `deserialize_pin` and `deserialize_includes`.
+                resolutions_map.remove("pin");
+                resolutions_map.remove("include");
+                let disabled = resolutions_map.remove("disable");
+                migrate(&mut resolutions_map)?;
+                let mut db = ResolutionDB::new();
+                let mut invalid = Vec::new();
+                for item in resolutions_map {
+                    let requested_path = item.0.clone();
+                    match Self::from_toml_item(item) {
+                        Ok((requested_path, resolution)) => {
+                            db.insert(requested_path, resolution);
+                        }
+                        Err(err) => invalid.push((requested_path, err)),
+                    }
+                }
+
+                // `disable = ["bin/yacc"]` is a shorthand for tombstones.
+                let disabled = match disabled {
+                    Some(toml::Value::Array(disabled)) => disabled,
+                    None => Vec::new(),
+                    _ => {
+                        invalid.push((
+                            "disable".into(),
+                            ParseResolutionError::UnexpectedType(
+                                "an array of requested paths".into(),
+                                "disable".into(),
+                            ),
+                        ));
+                        Vec::new()
+                    }
+                };
+                for requested_path in disabled {
+                    let requested_path = match requested_path {
+                        toml::Value::String(requested_path) => requested_path,
+                        _ => {
+                            invalid.push((
+                                "disable".into(),
+                                ParseResolutionError::UnexpectedType(
+                                    "a requested path".into(),
+                                    "disable".into(),
+                                ),
+                            ));
+                            continue;
+                        }
+                    };
+                    db.entry(requested_path.clone()).or_insert_with(|| {
+                        Self::ConstantResolution(ResolutionData {
+                            requested_path,
+                            decision: Decision::Tombstone,
+                            requested_by: None,
+                            priority: 0,
+                            source: None,
+                            provenance: Provenance::default(),
+                            reconfirm: false,
+                        })
+                    });
+                }
+
+                Ok((db, invalid))
+            }
             _ => Err(ParseResolutionError::UnexpectedType(
                 "an array of table".into(),
                 "the whole document".into(),
@@ -211,43 +717,1536 @@ impl Resolution {
     }
 }
 
+#[derive(Serialize, Deserialize, Eq, Hash, PartialEq, Clone, Debug)]
+pub struct PhaseResolutionData {
+    pub requested_path: String,
+    /// build phase -> resolution in this phase
+    pub phases: BTreeMap<String, ResolutionData>,
+}
+
+#[derive(Serialize, Deserialize, Eq, Hash, PartialEq, Clone, Copy, Debug)]
+pub enum PatternSyntax {
+    /// `*` does not cross `/`, e.g. `lib/libboost_*.so`
+    Glob,
+    /// Anchored at both ends, e.g. `include/python3\.[0-9]+`
+    Regex,
+}
+
+impl PatternSyntax {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Glob => "glob",
+            Self::Regex => "regex",
+        }
+    }
+
+    fn from_str(v: &str) -> ParseResult<Self> {
+        Ok(match v {
+            "glob" => Self::Glob,
+            "regex" => Self::Regex,
+            _ => {
+                return Err(ParseResolutionError::UnexpectedType(
+                    "`glob` or `regex`".into(),
+                    "syntax".into(),
+                ))
+            }
+        })
+    }
+}
+
+/// The requested path of the data is the pattern, patterns with a higher priority are consulted first.
+/// A provided `file_entry_name` left empty stands for the requested path itself.
+#[derive(Serialize, Deserialize, Eq, Hash, PartialEq, Clone, Debug)]
+pub struct PatternResolutionData {
+    pub syntax: PatternSyntax,
+    pub data: ResolutionData,
+}
+
+enum Matcher {
+    Glob(glob::Pattern),
+    Regex(Regex),
+}
+
+/// Compiled pattern resolutions of a database, by decreasing priority.
+#[derive(Default)]
+pub struct PatternResolutions {
+    /// matcher -> pattern, the key in the database
+    patterns: Vec<(Matcher, String)>,
+    case_insensitive: bool,
+}
+
+impl PatternResolutions {
+    /// Invalid patterns are skipped with a warning.
+    pub fn compile(db: &ResolutionDB, case_insensitive: bool) -> Self {
+        let mut patterns: Vec<(i64, Matcher, String)> = db
+            .iter()
+            .filter_map(|(key, resolution)| match resolution {
+                Resolution::PatternResolution(pattern_data) => {
+                    let matcher = match pattern_data.syntax {
+                        PatternSyntax::Glob => glob::Pattern::new(key).map(Matcher::Glob).map_err(|err| err.to_string()),
+                        PatternSyntax::Regex => RegexBuilder::new(&format!("^(?:{})$", key))
+                            .case_insensitive(case_insensitive)
+                            .build()
+                            .map(Matcher::Regex)
+                            .map_err(|err| err.to_string()),
+                    };
+                    match matcher {
+                        Ok(matcher) => Some((pattern_data.data.priority, matcher, key.clone())),
+                        Err(err) => {
+                            warn!("Skipping the invalid pattern resolution `{}`: {}", key, err);
+                            None
+                        }
+                    }
+                }
+                _ => None,
+            })
+            .collect();
+        // Stable, so that equal priorities keep the database order.
+        patterns.sort_by_key(|(priority, _, _)| -priority);
+
+        PatternResolutions {
+            patterns: patterns
+                .into_iter()
+                .map(|(_, matcher, key)| (matcher, key))
+                .collect(),
+            case_insensitive,
+        }
+    }
+
+    /// Database key of the first pattern matching this requested path.
+    pub fn find(&self, requested_path: &str) -> Option<&str> {
+        let options = glob::MatchOptions {
+            case_sensitive: !self.case_insensitive,
+            require_literal_separator: true,
+            require_literal_leading_dot: false,
+        };
+
+        self.patterns
+            .iter()
+            .find(|(matcher, _)| match matcher {
+                Matcher::Glob(pattern) => pattern.matches_with(requested_path, options),
+                Matcher::Regex(regex) => regex.is_match(requested_path),
+            })
+            .map(|(_, key)| key.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.patterns.len()
+    }
+}
+
+/// Civil UTC date of a UNIX timestamp, e.g. `2023-05-17T09:41:00Z`.
+fn format_utc(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    let (days, seconds_of_day) = ((seconds / 86400) as i64, seconds % 86400);
+    // Days to civil date, from Howard Hinnant's `civil_from_days`.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        seconds_of_day % 3600 / 60,
+        seconds_of_day % 60
+    )
+}
+
+/// Why a resolution exists, for whoever reviews a shared resolution file later on.
+#[derive(Serialize, Deserialize, Eq, Hash, PartialEq, Clone, Debug, Default)]
+pub struct Provenance {
+    /// UTC date of the decision
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recorded_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub buildxyz_version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// Command being built when the decision was taken
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+}
+
+impl Provenance {
+    /// Provenance of a decision taken right now by the current user.
+    pub fn now(command: Option<String>, comment: Option<String>) -> Self {
+        Provenance {
+            recorded_at: Some(format_utc(SystemTime::now())),
+            buildxyz_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            user: nix::unistd::User::from_uid(nix::unistd::getuid())
+                .ok()
+                .flatten()
+                .map(|user| user.name)
+                .or_else(|| std::env::var("USER").ok()),
+            command,
+            comment,
+        }
+    }
+
+    /// The same provenance, for a decision taken right now.
+    pub fn renewed(&self) -> Self {
+        Provenance {
+            recorded_at: Some(format_utc(SystemTime::now())),
+            ..self.clone()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
 #[derive(Serialize, Deserialize, Eq, Hash, PartialEq, Clone, Debug)]
 pub struct ResolutionData {
     pub requested_path: String,
     pub decision: Decision,
+    /// Process which triggered this resolution, if known
+    #[serde(default)]
+    pub requested_by: Option<ProcessInfo>,
+    /// Merges keep the resolution with the highest priority, the latest one on ties
+    #[serde(default)]
+    pub priority: i64,
+    /// Where this resolution was loaded from, if known
+    #[serde(skip)]
+    pub source: Option<String>,
+    #[serde(default, skip_serializing_if = "Provenance::is_empty")]
+    pub provenance: Provenance,
+    /// Ask the user to confirm this resolution once per run, even in automatic mode
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub reconfirm: bool,
+}
+
+impl ResolutionData {
+    pub fn to_human_toml_table(&self) -> toml::Table {
+        let mut table = self.decision.to_human_toml_table();
+        if self.priority != 0 {
+            table.insert("priority".into(), self.priority.into());
+        }
+        if self.reconfirm {
+            table.insert("reconfirm".into(), true.into());
+        }
+        if let Some(requested_by) = &self.requested_by {
+            table.insert(
+                "requested_by".into(),
+                toml::Table::try_from(requested_by).unwrap().into(),
+            );
+        }
+        if !self.provenance.is_empty() {
+            table.insert(
+                "provenance".into(),
+                toml::Table::try_from(&self.provenance).unwrap().into(),
+            );
+        }
+
+        table
+    }
+
+    pub fn from_toml(requested_path: String, mut table: toml::Table) -> ParseResult<Self> {
+        let requested_by = table
+            .remove("requested_by")
+            .map(|v| {
+                v.try_into().map_err(|_| {
+                    ParseResolutionError::UnexpectedType(
+                        "a process table".into(),
+                        "requested_by".into(),
+                    )
+                })
+            })
+            .transpose()?;
+        let provenance = table
+            .remove("provenance")
+            .map(|v| {
+                v.try_into().map_err(|_| {
+                    ParseResolutionError::UnexpectedType(
+                        "a provenance table".into(),
+                        "provenance".into(),
+                    )
+                })
+            })
+            .transpose()?
+            .unwrap_or_default();
+        let priority = match table.remove("priority") {
+            Some(toml::Value::Integer(priority)) => priority,
+            None => 0,
+            _ => {
+                return Err(ParseResolutionError::UnexpectedType(
+                    "integer".into(),
+                    "priority".into(),
+                ))
+            }
+        };
+        let reconfirm = match table.remove("reconfirm") {
+            Some(toml::Value::Boolean(reconfirm)) => reconfirm,
+            None => false,
+            _ => {
+                return Err(ParseResolutionError::UnexpectedType(
+                    "boolean".into(),
+                    "reconfirm".into(),
+                ))
+            }
+        };
+
+        Ok(ResolutionData {
+            requested_path,
+            decision: Decision::from_toml(table)?,
+            requested_by,
+            priority,
+            source: None,
+            provenance,
+            reconfirm,
+        })
+    }
 }
 
 // TODO: BTreeMap provide O(log n) search, do we need better?
 pub type ResolutionDB = BTreeMap<String, Resolution>;
 
+/// Record `data` for its requested path, only in `phase` if any.
+/// A constant resolution of this path is replaced by a phase one, and conversely.
+pub fn insert_resolution(db: &mut ResolutionDB, data: ResolutionData, phase: Option<&str>) {
+    let requested_path = data.requested_path.clone();
+    match phase {
+        Some(phase) => match db.get_mut(&requested_path) {
+            Some(Resolution::PhaseResolution(phase_data)) => {
+                phase_data.phases.insert(phase.to_string(), data);
+            }
+            _ => {
+                db.insert(
+                    requested_path.clone(),
+                    Resolution::PhaseResolution(PhaseResolutionData {
+                        requested_path,
+                        phases: [(phase.to_string(), data)].into(),
+                    }),
+                );
+            }
+        },
+        None => {
+            db.insert(requested_path, Resolution::ConstantResolution(data));
+        }
+    }
+}
+
+/// Remove what `insert_resolution` recorded for this path in `phase`, other phases are kept.
+pub fn remove_resolution(db: &mut ResolutionDB, requested_path: &str, phase: Option<&str>) {
+    match phase {
+        Some(phase) => {
+            if let Some(Resolution::PhaseResolution(phase_data)) = db.get_mut(requested_path) {
+                phase_data.phases.remove(phase);
+                if phase_data.phases.is_empty() {
+                    db.remove(requested_path);
+                }
+            }
+        }
+        None => {
+            db.remove(requested_path);
+        }
+    }
+}
+
 pub fn db_to_human_toml(db: &ResolutionDB) -> toml::Table {
     let mut table = toml::Table::new();
+    table.insert("schema_version".into(), SCHEMA_VERSION.into());
 
+    let mut disabled = Vec::new();
     for item in db.values() {
-        table.extend(item.to_human_toml_table());
+        match item {
+            Resolution::ConstantResolution(data)
+                if data.decision == Decision::Tombstone
+                    && data.requested_by.is_none()
+                    && data.provenance.is_empty() =>
+            {
+                disabled.push(toml::Value::from(data.requested_path.clone()))
+            }
+            _ => table.extend(item.to_human_toml_table()),
+        }
+    }
+    if !disabled.is_empty() {
+        table.insert("disable".into(), disabled.into());
     }
 
     table
 }
 
-fn locate_resolution_db(search_path: PathBuf) -> Option<PathBuf> {
-    None
+/// Atomically merge the resolution database into the file, in the format of its extension, with its pin if any.
+/// Another writer may have recorded resolutions since this one was read, they are kept,
+/// see `update_resolution_db`.
+pub fn write_resolution_db(
+    filepath: &Path,
+    db: &ResolutionDB,
+    pin: Option<&IndexPin>,
+) -> std::io::Result<()> {
+    update_resolution_db(filepath, pin, |on_disk| {
+        *on_disk = merge_resolution_db(std::mem::take(on_disk), db.clone());
+        Ok(())
+    })
+}
+
+/// Atomically edit the resolutions held by the file, empty if it does not exist yet,
+/// and write them in the format of its extension, with its pin if any.
+/// Concurrent writers are serialized through an exclusive lock on `<filepath>.lock`,
+/// held from the read to the write so that no update is lost;
+/// the data is written to a temporary file which is renamed over `filepath`,
+/// so that a crash never leaves a truncated file behind.
+/// A file which does not parse is left untouched.
+pub fn update_resolution_db(
+    filepath: &Path,
+    pin: Option<&IndexPin>,
+    edit: impl FnOnce(&mut ResolutionDB) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    let mut lock_path = filepath.as_os_str().to_owned();
+    lock_path.push(".lock");
+    let lock_file = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(&lock_path)?;
+    nix::fcntl::flock(lock_file.as_raw_fd(), nix::fcntl::FlockArg::LockExclusive)?;
+
+    let format = DbFormat::from_path(filepath);
+    let existing = match fs::read_to_string(filepath) {
+        Ok(existing) => Some(existing),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err),
+    };
+    let mut db = match &existing {
+        Some(existing) => deserialize_resolution_db(existing, format, &filepath.to_string_lossy())
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{} does not parse, not overwriting it", filepath.display()),
+                )
+            })?,
+        None => ResolutionDB::new(),
+    };
+    edit(&mut db)?;
+
+    let serialized = serialize_resolution_db(&db, format, pin);
+    // Hand-maintained files keep their comments and layout.
+    let serialized = match (format, existing) {
+        (DbFormat::Toml, Some(existing)) => update_human_toml(&existing, &serialized).unwrap_or(serialized),
+        _ => serialized,
+    };
+    let directory = match filepath.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let mut tmp_file = tempfile::NamedTempFile::new_in(directory)?;
+    tmp_file.write_all(serialized.as_bytes())?;
+    tmp_file.as_file().sync_all()?;
+    tmp_file.persist(filepath).map_err(|err| err.error)?;
+
+    // The lock is released when `lock_file` is closed.
+    Ok(())
 }
 
+/// Resolution files in a search path, in merge order (later ones take priority):
+/// `buildxyz.toml`, then `*.resolutions.toml`, then every `*.toml` under `resolutions/`,
+/// each group sorted by path. JSON files are found the same way with a `.json` extension.
+/// A search path can also be a resolution file itself.
+fn locate_resolution_db(search_path: PathBuf) -> Vec<PathBuf> {
+    if search_path.is_file() {
+        return vec![search_path];
+    }
+
+    let mut located: Vec<PathBuf> = ["buildxyz.toml", "buildxyz.json"]
+        .into_iter()
+        .map(|name| search_path.join(name))
+        .filter(|path| path.is_file())
+        .collect();
+
+    if let Ok(entries) = fs::read_dir(&search_path) {
+        let mut resolution_files: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.is_file()
+                    && path
+                        .file_name()
+                        .map_or(false, |name| {
+                            let name = name.to_string_lossy();
+                            name.ends_with(".resolutions.toml") || name.ends_with(".resolutions.json")
+                        })
+            })
+            .collect();
+        resolution_files.sort();
+        located.extend(resolution_files);
+    }
+
+    let mut directory_files: Vec<PathBuf> = WalkDir::new(search_path.join("resolutions"))
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.into_path())
+        .filter(|path| path.extension().map_or(false, |extension| extension == "toml" || extension == "json"))
+        .collect();
+    directory_files.sort();
+    located.extend(directory_files);
+
+    located
+}
+
+/// A problem found while loading a resolution file, see `buildxyz res lint`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// Line of the document, starting at 1, if known
+    pub line: Option<usize>,
+    /// Resolution the problem is in, `None` if it concerns the whole document
+    pub requested_path: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "line {}: ", line)?;
+        }
+        if let Some(requested_path) = &self.requested_path {
+            write!(f, "`{}`: ", requested_path)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Line of a byte offset, starting at 1.
+fn line_of(data: &str, offset: usize) -> usize {
+    data[..offset.min(data.len())].matches('\n').count() + 1
+}
+
+/// Parse a human TOML document, loading the resolutions which parse and diagnosing the others.
+/// The database is `None` if the document as a whole cannot be loaded.
+fn diagnose_human_toml(data: &str) -> (Option<ResolutionDB>, Vec<Diagnostic>) {
+    let document: BTreeMap<toml::Spanned<String>, toml::Value> = match toml::from_str(data) {
+        Ok(document) => document,
+        Err(err) => {
+            return (
+                None,
+                vec![Diagnostic {
+                    line: err.span().map(|span| line_of(data, span.start)),
+                    requested_path: None,
+                    message: err.message().to_string(),
+                }],
+            )
+        }
+    };
+    let lines: HashMap<String, usize> = document
+        .keys()
+        .map(|key| (key.get_ref().clone(), line_of(data, key.span().start)))
+        .collect();
+    let document: toml::Table = document
+        .into_iter()
+        .map(|(key, value)| (key.into_inner(), value))
+        .collect();
+
+    match Resolution::from_toml(document.into()) {
+        Ok((db, invalid)) => (
+            Some(db),
+            invalid
+                .into_iter()
+                .map(|(requested_path, err)| Diagnostic {
+                    line: lines.get(&requested_path).copied(),
+                    requested_path: Some(requested_path),
+                    message: err.to_string(),
+                })
+                .collect(),
+        ),
+        Err(err) => (
+            None,
+            vec![Diagnostic {
+                line: None,
+                requested_path: None,
+                message: err.to_string(),
+            }],
+        ),
+    }
+}
+
+/// Parse a human TOML document, `None` if it cannot be loaded at all.
+/// Resolutions which do not parse are skipped, the reasons are logged.
 pub fn read_resolution_db(data: &str) -> Option<ResolutionDB> {
-    Resolution::from_toml(
-        toml::from_str(data)
-            .expect("Failed to parse the TOML"),
-    )
-    .ok()
+    let (db, diagnostics) = diagnose_human_toml(data);
+    for diagnostic in diagnostics {
+        error!("Failed to load resolutions: {}", diagnostic);
+    }
+
+    db
+}
+
+/// On-disk formats of resolution databases, chosen by file extension.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DbFormat {
+    /// Human TOML, the default
+    Toml,
+    /// Serde representation, for other tooling
+    Json,
+}
+
+impl DbFormat {
+    pub fn from_path(filepath: &Path) -> Self {
+        match filepath.extension() {
+            Some(extension) if extension == "json" => Self::Json,
+            _ => Self::Toml,
+        }
+    }
 }
 
-/// Search in the provided path for a resolution database.
-pub fn load_resolution_db(search_path: PathBuf) -> Option<ResolutionDB> {
-    locate_resolution_db(search_path).and_then(|filename| read_resolution_db(&std::fs::read_to_string(filename).expect("Failed to read resolution DB from file")))
+/// JSON document, the schema version is checked but there is nothing to migrate yet.
+#[derive(Serialize, Deserialize)]
+struct JsonDocument {
+    schema_version: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pin: Option<IndexPin>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    include: Vec<Include>,
+    resolutions: ResolutionDB,
+}
+
+pub fn serialize_resolution_db(db: &ResolutionDB, format: DbFormat, pin: Option<&IndexPin>) -> String {
+    match format {
+        DbFormat::Toml => {
+            let mut document = db_to_human_toml(db);
+            if let Some(pin) = pin {
+                document.insert(
+                    "pin".into(),
+                    toml::Value::try_from(pin).expect("Failed to serialize the index pin"),
+                );
+            }
+
+            toml::to_string_pretty(&document)
+                .expect("Failed to serialize in a human-way the resolution database")
+        }
+        DbFormat::Json => serde_json::to_string_pretty(&JsonDocument {
+            schema_version: SCHEMA_VERSION,
+            pin: pin.cloned(),
+            include: Vec::new(),
+            resolutions: db.clone(),
+        })
+        .expect("Failed to serialize the resolution database"),
+    }
+}
+
+/// Values are compared regardless of their surrounding whitespace and comments.
+fn same_toml_value(left: &toml_edit::Item, right: &toml_edit::Item) -> bool {
+    match (left.as_value(), right.as_value()) {
+        (Some(left), Some(right)) => {
+            let (mut left, mut right) = (left.clone(), right.clone());
+            left.decor_mut().clear();
+            right.decor_mut().clear();
+            left.to_string() == right.to_string()
+        }
+        _ => false,
+    }
+}
+
+/// Move the tables of an inserted item at `position` in the document.
+fn place_toml_item(item: &mut toml_edit::Item, position: usize) {
+    if let Some(table) = item.as_table_mut() {
+        table.set_position(position);
+        for (_, child) in table.iter_mut() {
+            place_toml_item(child, position);
+        }
+    }
+}
+
+fn last_toml_position(table: &toml_edit::Table) -> usize {
+    table
+        .iter()
+        .filter_map(|(_, item)| item.as_table())
+        .map(last_toml_position)
+        .chain(table.position())
+        .max()
+        .unwrap_or_default()
+}
+
+/// Make `existing` hold the entries of `fresh`, in place so that unchanged ones keep their formatting.
+/// New tables go under the closest table above them, at `position`, or at the end of the document.
+fn sync_toml_table(
+    existing: &mut dyn toml_edit::TableLike,
+    fresh: &dyn toml_edit::TableLike,
+    position: Option<usize>,
+    next_position: &mut usize,
+) {
+    let stale: Vec<String> = existing
+        .iter()
+        .map(|(key, _)| key.to_string())
+        .filter(|key| !fresh.contains_key(key))
+        .collect();
+    for key in stale {
+        existing.remove(&key);
+    }
+
+    for (key, fresh_item) in fresh.iter() {
+        match existing.get_mut(key) {
+            Some(item) => {
+                let table_position = item.as_table().and_then(|table| table.position()).or(position);
+                if let (Some(table), Some(fresh_table)) =
+                    (item.as_table_like_mut(), fresh_item.as_table_like())
+                {
+                    sync_toml_table(table, fresh_table, table_position, next_position);
+                } else if !same_toml_value(item, fresh_item) {
+                    let decor = item.as_value().map(|value| value.decor().clone());
+                    *item = fresh_item.clone();
+                    if let (Some(value), Some(decor)) = (item.as_value_mut(), decor) {
+                        *value.decor_mut() = decor;
+                    }
+                    place_toml_item(
+                        item,
+                        table_position.unwrap_or_else(|| {
+                            *next_position += 1;
+                            *next_position
+                        }),
+                    );
+                }
+            }
+            None => {
+                let mut item = fresh_item.clone();
+                let item_position = position.unwrap_or_else(|| {
+                    *next_position += 1;
+                    *next_position
+                });
+                place_toml_item(&mut item, item_position);
+                existing.insert(key, item);
+            }
+        }
+    }
 }
 
-/// Unify two set of resolutions, right taking priority over left.
+/// Rewrite `existing` human TOML to hold the `fresh` one, keeping the comments, order and formatting
+/// of the resolutions which did not change. `None` if `existing` is not valid TOML.
+pub fn update_human_toml(existing: &str, fresh: &str) -> Option<String> {
+    let mut document: toml_edit::Document = existing.parse().ok()?;
+    let mut fresh: toml_edit::Document = fresh
+        .parse()
+        .expect("Serialized resolutions are valid TOML");
+    // Includes are not part of the database.
+    if let Some(includes) = document.get("include") {
+        fresh.insert("include", includes.clone());
+    }
+
+    let mut next_position = last_toml_position(document.as_table());
+    sync_toml_table(
+        document.as_table_mut(),
+        fresh.as_table(),
+        None,
+        &mut next_position,
+    );
+
+    Some(document.to_string())
+}
+
+/// Parse a document in either format, see `diagnose_human_toml`.
+/// JSON documents are loaded as a whole or not at all.
+pub fn diagnose_resolution_db(
+    data: &str,
+    format: DbFormat,
+) -> (Option<ResolutionDB>, Vec<Diagnostic>) {
+    match format {
+        DbFormat::Toml => diagnose_human_toml(data),
+        DbFormat::Json => {
+            let document: JsonDocument = match serde_json::from_str(data) {
+                Ok(document) => document,
+                Err(err) => {
+                    return (
+                        None,
+                        vec![Diagnostic {
+                            line: Some(err.line()),
+                            requested_path: None,
+                            message: err.to_string(),
+                        }],
+                    )
+                }
+            };
+            if !(0..=SCHEMA_VERSION).contains(&document.schema_version) {
+                return (
+                    None,
+                    vec![Diagnostic {
+                        line: None,
+                        requested_path: None,
+                        message: ParseResolutionError::UnsupportedSchemaVersion(
+                            document.schema_version,
+                            SCHEMA_VERSION,
+                        )
+                        .to_string(),
+                    }],
+                );
+            }
+
+            (Some(document.resolutions), Vec::new())
+        }
+    }
+}
+
+/// Parse a document of `source`, `None` if it cannot be loaded at all.
+/// Resolutions which do not parse are skipped, the reasons are logged.
+pub fn deserialize_resolution_db(
+    data: &str,
+    format: DbFormat,
+    source: &str,
+) -> Option<ResolutionDB> {
+    let (db, diagnostics) = diagnose_resolution_db(data, format);
+    for diagnostic in diagnostics {
+        error!("Failed to load resolutions from {}: {}", source, diagnostic);
+    }
+
+    db
+}
+
+/// Index the resolutions were recorded against, `None` for unpinned files.
+pub fn deserialize_pin(data: &str, format: DbFormat) -> Option<IndexPin> {
+    match format {
+        DbFormat::Toml => toml::from_str::<toml::Table>(data)
+            .ok()?
+            .remove("pin")?
+            .try_into()
+            .map_err(|err| warn!("Ignoring an invalid index pin: {}", err))
+            .ok(),
+        DbFormat::Json => {
+            serde_json::from_str::<JsonDocument>(data)
+                .ok()?
+                .pin
+        }
+    }
+}
+
+/// Files included by a resolution file, in order, see `include::read_resolution_file_with_includes`.
+pub fn deserialize_includes(data: &str, format: DbFormat) -> Vec<Include> {
+    let includes = match format {
+        DbFormat::Toml => toml::from_str::<toml::Table>(data)
+            .ok()
+            .and_then(|mut document| document.remove("include"))
+            .map(|includes| includes.try_into().map_err(|err: toml::de::Error| err.to_string())),
+        DbFormat::Json => serde_json::from_str::<JsonDocument>(data)
+            .ok()
+            .map(|document| Ok(document.include)),
+    };
+
+    match includes {
+        Some(Ok(includes)) => includes,
+        Some(Err(err)) => {
+            warn!("Ignoring invalid includes: {}", err);
+            Vec::new()
+        }
+        None => Vec::new(),
+    }
+}
+
+/// Read a resolution file in the format of its extension, tagged with its path as source,
+/// along with its pin. `None` if it does not parse, the reason is logged.
+pub fn read_pinned_resolution_file(
+    filepath: &Path,
+) -> std::io::Result<Option<(ResolutionDB, Option<IndexPin>)>> {
+    let data = fs::read_to_string(filepath)?;
+    let format = DbFormat::from_path(filepath);
+
+    Ok(deserialize_resolution_db(&data, format, &filepath.to_string_lossy())
+        .map(|db| (with_source(db, &filepath.to_string_lossy()), deserialize_pin(&data, format))))
+}
+
+/// Entries of a colon-separated resolution path, e.g. `BUILDXYZ_RESOLUTION_PATH`.
+/// URLs are entries of their own, the colons of their scheme and port do not separate entries.
+pub fn split_resolution_path(resolution_path: &str) -> Vec<String> {
+    let mut entries: Vec<String> = Vec::new();
+    for part in resolution_path.split(':') {
+        match entries.last_mut() {
+            Some(last) if (last == "https" || last == "http") && part.starts_with("//") => {
+                last.push(':');
+                last.push_str(part);
+            }
+            // A port, as long as the URL has no path yet.
+            Some(last)
+                if fetch::is_url(last)
+                    && !last.split_once("//").map_or(false, |(_, rest)| rest.contains('/'))
+                    && part.starts_with(|c: char| c.is_ascii_digit()) =>
+            {
+                last.push(':');
+                last.push_str(part);
+            }
+            _ => entries.push(part.to_string()),
+        }
+    }
+    entries.retain(|entry| !entry.is_empty());
+
+    entries
+}
+
+/// Search in the provided path for resolution databases and merge them,
+/// `None` if there is none.
+/// A resolution file which cannot be read or loaded is an error rather than skipped.
+pub fn load_resolution_db(
+    search_path: PathBuf,
+    conflicts: &mut MergeConflicts,
+    pins: &mut PinCheck,
+    signatures: &SignatureCheck,
+) -> std::io::Result<Option<ResolutionDB>> {
+    let mut loaded: Option<ResolutionDB> = None;
+    for filename in locate_resolution_db(search_path) {
+        debug!("loading resolutions from {}", filename.display());
+        let db = read_resolution_file_with_includes(&filename, pins, signatures)
+            .map_err(|err| std::io::Error::new(err.kind(), format!("{}: {}", filename.display(), err)))?
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{} cannot be loaded, see the errors above", filename.display()),
+                )
+            })?;
+        loaded = Some(match loaded {
+            Some(left) => merge_resolution_db_with(left, db, conflicts),
+            None => db,
+        });
+    }
+
+    Ok(loaded)
+}
+
+/// What to do when merged databases take different decisions for the same path.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Keep the highest priority one silently.
+    Override,
+    /// Keep the highest priority one and warn about the other.
+    #[default]
+    Report,
+    /// Refuse to run until the conflicts are solved.
+    Error,
+    /// Ask which one to keep.
+    Interactive,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MergeSide {
+    Left,
+    Right,
+}
+
+/// Applies a conflict policy over several merges and keeps track of the conflicts.
+pub struct MergeConflicts {
+    policy: ConflictPolicy,
+    /// Conflicts refused by the `error` policy
+    pub unresolved: Vec<String>,
+    /// Resolutions picked by the user to solve conflicts, by requested path
+    pub answered: ResolutionDB,
+}
+
+impl MergeConflicts {
+    pub fn new(policy: ConflictPolicy) -> Self {
+        MergeConflicts {
+            policy,
+            unresolved: Vec::new(),
+            answered: ResolutionDB::new(),
+        }
+    }
+
+    /// `winner` is the side picked by priorities.
+    fn resolve(
+        &mut self,
+        requested_path: &str,
+        left: &Resolution,
+        right: &Resolution,
+        winner: MergeSide,
+    ) -> MergeSide {
+        let report = format!(
+            "{}: `{}` from {} conflicts with `{}` from {}",
+            requested_path,
+            left.describe(),
+            left.source(),
+            right.describe(),
+            right.source()
+        );
+        match self.policy {
+            ConflictPolicy::Override => {}
+            ConflictPolicy::Report => warn!(
+                "{}, keeping the one from {}",
+                report,
+                match winner {
+                    MergeSide::Left => left.source(),
+                    MergeSide::Right => right.source(),
+                }
+            ),
+            ConflictPolicy::Error => self.unresolved.push(report),
+            ConflictPolicy::Interactive => {
+                let choices = [left, right].map(|resolution| {
+                    format!("{} (from {})", resolution.describe(), resolution.source())
+                });
+                if let Some(choice) = interactive::prompt_merge_conflict(requested_path, &choices) {
+                    let (side, picked) = if choice == 0 {
+                        (MergeSide::Left, left)
+                    } else {
+                        (MergeSide::Right, right)
+                    };
+                    self.answered.insert(requested_path.to_string(), picked.clone());
+                    return side;
+                }
+            }
+        }
+
+        winner
+    }
+}
+
+/// Unify two set of resolutions, the highest `priority` wins for a path in both,
+/// right taking priority over left on ties.
 pub fn merge_resolution_db(left: ResolutionDB, right: ResolutionDB) -> ResolutionDB {
-    left.into_iter().chain(right).collect()
+    merge_resolution_db_with(left, right, &mut MergeConflicts::new(ConflictPolicy::Override))
+}
+
+/// Same as `merge_resolution_db`, conflicting decisions go through `conflicts`.
+pub fn merge_resolution_db_with(
+    mut left: ResolutionDB,
+    right: ResolutionDB,
+    conflicts: &mut MergeConflicts,
+) -> ResolutionDB {
+    for (requested_path, resolution) in right {
+        if let Some(existing) = left.get(&requested_path) {
+            if resolution.is_tombstone() {
+                debug!(
+                    "{}: the resolution from {} is disabled by {}",
+                    requested_path,
+                    existing.source(),
+                    resolution.source()
+                );
+                left.insert(requested_path, resolution);
+                continue;
+            }
+            let winner = if existing.priority() > resolution.priority() {
+                MergeSide::Left
+            } else {
+                MergeSide::Right
+            };
+            let winner = if existing.agrees_with(&resolution) {
+                winner
+            } else {
+                conflicts.resolve(&requested_path, existing, &resolution, winner)
+            };
+            if winner == MergeSide::Left {
+                debug!(
+                    "{}: keeping the resolution from {} (priority {}) over {} (priority {})",
+                    requested_path,
+                    existing.source(),
+                    existing.priority(),
+                    resolution.source(),
+                    resolution.priority()
+                );
+                continue;
+            }
+            debug!(
+                "{}: the resolution from {} (priority {}) overrides {} (priority {})",
+                requested_path,
+                resolution.source(),
+                resolution.priority(),
+                existing.source(),
+                existing.priority()
+            );
+        }
+        left.insert(requested_path, resolution);
+    }
+
+    left
+}
+
+/// Drop the tombstones once every file is merged, the paths they disabled are searched again.
+pub fn drop_tombstones(mut db: ResolutionDB) -> ResolutionDB {
+    db.retain(|_, resolution| !resolution.is_tombstone());
+    db
+}
+
+/// Remember where these resolutions were loaded from.
+pub fn with_source(mut db: ResolutionDB, source: &str) -> ResolutionDB {
+    for resolution in db.values_mut() {
+        for data in resolution.all_data_mut() {
+            data.source = Some(source.to_string());
+        }
+    }
+
+    db
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pattern_resolutions_are_consulted_by_priority() {
+        let db = read_resolution_db(
+            r#"
+["include/python3.*"]
+resolution = "pattern"
+decision = "ignore"
+
+["include/python3\\.11"]
+resolution = "pattern"
+syntax = "regex"
+priority = 10
+decision = "ignore"
+"#,
+        )
+        .unwrap();
+        let patterns = PatternResolutions::compile(&db, false);
+
+        assert_eq!(patterns.find("include/python3.11"), Some("include/python3\\.11"));
+        assert_eq!(patterns.find("include/python3.10"), Some("include/python3.*"));
+        assert_eq!(patterns.find("include/python3.10/Python.h"), None);
+    }
+
+    #[test]
+    fn test_older_schemas_are_migrated_and_newer_ones_rejected() {
+        let unversioned = read_resolution_db(
+            r#"
+["include/sys/endian.h"]
+decision = "ignore"
+"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            unversioned["include/sys/endian.h"],
+            Resolution::ConstantResolution(_)
+        ));
+        assert_eq!(
+            db_to_human_toml(&unversioned)["schema_version"].as_integer(),
+            Some(SCHEMA_VERSION)
+        );
+
+        let future = toml::from_str(&format!("schema_version = {}", SCHEMA_VERSION + 1)).unwrap();
+        assert!(matches!(
+            Resolution::from_toml(future),
+            Err(ParseResolutionError::UnsupportedSchemaVersion(..))
+        ));
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let db = read_resolution_db(
+            r#"
+["include/sys/endian.h"]
+resolution = "constant"
+decision = "ignore"
+priority = 5
+
+["include/python3.*"]
+resolution = "pattern"
+decision = "host-fallback"
+host_path = "/usr/include/python3.11"
+"#,
+        )
+        .unwrap();
+
+        let json = serialize_resolution_db(&db, DbFormat::Json, None);
+        assert_eq!(deserialize_resolution_db(&json, DbFormat::Json, "test"), Some(db));
+
+        // Unsupported versions are refused in both formats.
+        for schema_version in [-1, SCHEMA_VERSION + 1] {
+            let json = format!(r#"{{"schema_version": {}, "resolutions": {{}}}}"#, schema_version);
+            assert_eq!(deserialize_resolution_db(&json, DbFormat::Json, "test"), None);
+            let toml = format!("schema_version = {}", schema_version);
+            assert_eq!(deserialize_resolution_db(&toml, DbFormat::Toml, "test"), None);
+        }
+    }
+
+    #[test]
+    fn test_stub_contents_are_optional() {
+        let db = read_resolution_db(
+            r#"
+["lib/.keep"]
+decision = "stub"
+
+["share/config.site"]
+decision = "stub"
+contents = "prefix=@prefix@\n"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            db["lib/.keep"].data_for_phase(None).unwrap().decision,
+            Decision::Stub { contents: String::new() }
+        );
+        assert_eq!(
+            read_resolution_db(&toml::to_string(&db_to_human_toml(&db)).unwrap()),
+            Some(db)
+        );
+    }
+
+    #[test]
+    fn test_flake_installables_are_kept_with_their_store_path() {
+        let db = read_resolution_db(
+            r#"
+["bin/mytool"]
+decision = "provide"
+file_entry_name = "/bin/mytool"
+kind = "regular-file"
+flake = "github:acme/tools#mytool"
+
+["bin/mytool".store_path]
+hash = "zy4xhzc6x6ldrfzqzfa4x3cw8ds1mgcn"
+name = "mytool-1.0"
+store_dir = "/nix/store"
+
+["bin/mytool".store_path.origin]
+attr = "github:acme/tools#mytool"
+output = "out"
+toplevel = true
+"#,
+        )
+        .unwrap();
+
+        match &db["bin/mytool"].data_for_phase(None).unwrap().decision {
+            Decision::Provide(data) => {
+                assert_eq!(data.flake.as_deref(), Some("github:acme/tools#mytool"))
+            }
+            decision => panic!("unexpected decision {}", decision),
+        }
+        assert_eq!(
+            read_resolution_db(&toml::to_string(&db_to_human_toml(&db)).unwrap()),
+            Some(db)
+        );
+    }
+
+    #[test]
+    fn test_provenance_is_kept_in_human_toml() {
+        assert_eq!(format_utc(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        assert_eq!(
+            format_utc(UNIX_EPOCH + std::time::Duration::from_secs(1709210096)),
+            "2024-02-29T12:34:56Z"
+        );
+
+        let db = read_resolution_db(
+            r#"
+["bin/yacc"]
+decision = "ignore"
+
+["bin/yacc".provenance]
+recorded_at = "2024-02-29T12:34:56Z"
+user = "alice"
+comment = "bison's yacc breaks the build, byacc is provided through the shell"
+"#,
+        )
+        .unwrap();
+        let provenance = &db["bin/yacc"].data_for_phase(None).unwrap().provenance;
+        assert_eq!(provenance.user.as_deref(), Some("alice"));
+        assert_eq!(provenance.buildxyz_version, None);
+
+        assert_eq!(
+            read_resolution_db(&toml::to_string(&db_to_human_toml(&db)).unwrap()),
+            Some(db)
+        );
+    }
+
+    #[test]
+    fn test_interleaved_writers_keep_each_other_resolutions() {
+        let dir = tempfile::tempdir().unwrap();
+        let filepath = dir.path().join("buildxyz.toml");
+
+        // Each writer only knows its own resolutions, like two sessions recording in the same file.
+        let writers: Vec<_> = ["gcc", "clang"]
+            .into_iter()
+            .map(|writer| {
+                let filepath = filepath.clone();
+                std::thread::spawn(move || {
+                    let mut db = ResolutionDB::new();
+                    for k in 0..10 {
+                        db.append(
+                            &mut read_resolution_db(&format!(
+                                "[\"bin/{}-{}\"]\nresolution = \"constant\"\ndecision = \"ignore\"\n",
+                                writer, k
+                            ))
+                            .unwrap(),
+                        );
+                        write_resolution_db(&filepath, &db, None).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let written = read_resolution_db(&fs::read_to_string(&filepath).unwrap()).unwrap();
+        assert_eq!(written.len(), 20);
+        assert!(written.contains_key("bin/gcc-9") && written.contains_key("bin/clang-0"));
+
+        // Edits see the resolutions written meanwhile, and can remove them.
+        update_resolution_db(&filepath, None, |db| {
+            db.retain(|requested_path, _| requested_path.starts_with("bin/gcc-"));
+            Ok(())
+        })
+        .unwrap();
+        let written = read_resolution_db(&fs::read_to_string(&filepath).unwrap()).unwrap();
+        assert_eq!(written.len(), 10);
+
+        fs::write(&filepath, "not = [valid").unwrap();
+        assert!(write_resolution_db(&filepath, &ResolutionDB::new(), None).is_err());
+        assert_eq!(fs::read_to_string(&filepath).unwrap(), "not = [valid");
+    }
+
+    #[test]
+    fn test_comments_survive_a_rewrite() {
+        let existing = r#"# Resolutions of the project, see the README
+schema_version = 1
+
+# bison's yacc breaks the build
+["bin/yacc"]
+resolution = "constant"
+decision = "ignore" # byacc is provided through the shell
+
+["include/foo.h"]
+resolution = "constant"
+decision = "ignore"
+"#;
+        let mut db = read_resolution_db(existing).unwrap();
+        db.remove("include/foo.h");
+        let mut lex = db["bin/yacc"].clone();
+        if let Resolution::ConstantResolution(data) = &mut lex {
+            data.requested_path = "bin/lex".into();
+            data.decision = Decision::Defer;
+        }
+        db.insert("bin/lex".into(), lex);
+
+        let updated =
+            update_human_toml(existing, &serialize_resolution_db(&db, DbFormat::Toml, None))
+                .unwrap();
+        assert_eq!(
+            updated,
+            r#"# Resolutions of the project, see the README
+schema_version = 1
+
+# bison's yacc breaks the build
+["bin/yacc"]
+resolution = "constant"
+decision = "ignore" # byacc is provided through the shell
+
+["bin/lex"]
+decision = "defer"
+resolution = "constant"
+"#
+        );
+        assert_eq!(read_resolution_db(&updated), Some(db));
+    }
+
+    #[test]
+    fn test_urls_are_kept_whole_in_resolution_paths() {
+        assert_eq!(
+            split_resolution_path(
+                "/etc/buildxyz:https://example.org:8443/core.toml::http://example.org/extra.toml:rel"
+            ),
+            vec![
+                "/etc/buildxyz",
+                "https://example.org:8443/core.toml",
+                "http://example.org/extra.toml",
+                "rel"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pins_are_kept_apart_from_resolutions() {
+        let db = read_resolution_db(
+            r#"
+["include/sys/endian.h"]
+decision = "ignore"
+"#,
+        )
+        .unwrap();
+        let pin = IndexPin {
+            nixpkgs_rev: Some("8a3e1cf40a6eaeb122c8321b97a0518cfa6ed779".into()),
+            index: "fnv1a64:cbf29ce484222325-0".into(),
+        };
+
+        for format in [DbFormat::Toml, DbFormat::Json] {
+            let serialized = serialize_resolution_db(&db, format, Some(&pin));
+            assert_eq!(deserialize_resolution_db(&serialized, format, "test"), Some(db.clone()));
+            assert_eq!(deserialize_pin(&serialized, format), Some(pin.clone()));
+        }
+        assert_eq!(
+            deserialize_pin(&serialize_resolution_db(&db, DbFormat::Toml, None), DbFormat::Toml),
+            None
+        );
+    }
+
+    #[test]
+    fn test_merges_keep_the_highest_priority() {
+        let core = with_source(
+            read_resolution_db(
+                r#"
+["include/sys/endian.h"]
+resolution = "constant"
+decision = "ignore"
+priority = 5
+"#,
+            )
+            .unwrap(),
+            "core",
+        );
+        let project = with_source(
+            read_resolution_db(
+                r#"
+["include/sys/endian.h"]
+resolution = "constant"
+decision = "host-fallback"
+host_path = "/usr/include/sys/endian.h"
+"#,
+            )
+            .unwrap(),
+            "project",
+        );
+
+        let merged = merge_resolution_db(core.clone(), project.clone());
+        assert_eq!(merged["include/sys/endian.h"].source(), "core");
+        let merged = merge_resolution_db(project, core);
+        assert_eq!(merged["include/sys/endian.h"].source(), "core");
+    }
+
+    #[test]
+    fn test_disabled_core_resolutions_are_dropped() {
+        let core = read_resolution_db(
+            r#"
+["bin/yacc"]
+resolution = "constant"
+decision = "ignore"
+priority = 5
+
+["bin/lex"]
+resolution = "constant"
+decision = "ignore"
+"#,
+        )
+        .unwrap();
+        let project = read_resolution_db(
+            r#"
+disable = ["bin/yacc"]
+"#,
+        )
+        .unwrap();
+        assert!(project["bin/yacc"].is_tombstone());
+        assert_eq!(
+            toml::to_string(&db_to_human_toml(&project)).unwrap(),
+            "disable = [\"bin/yacc\"]\nschema_version = 1\n"
+        );
+
+        let merged = drop_tombstones(merge_resolution_db(core, project));
+        assert_eq!(merged.keys().collect::<Vec<_>>(), vec!["bin/lex"]);
+    }
+
+    #[test]
+    fn test_invalid_resolutions_are_diagnosed_and_skipped() {
+        let (db, diagnostics) = diagnose_resolution_db(
+            r#"schema_version = 1
+
+["bin/yacc"]
+resolution = "constant"
+decision = "ignore"
+
+["include/zlib.h"]
+resolution = "constant"
+decision = "provide"
+file_entry_name = "/include/zlib.h"
+kind = "regular-file"
+"#,
+            DbFormat::Toml,
+        );
+        assert_eq!(db.unwrap().keys().collect::<Vec<_>>(), vec!["bin/yacc"]);
+        assert_eq!(
+            diagnostics,
+            vec![Diagnostic {
+                line: Some(7),
+                requested_path: Some("include/zlib.h".into()),
+                message: "missing field `store_path`".into(),
+            }]
+        );
+
+        let (db, diagnostics) = diagnose_resolution_db("[\"bin/yacc\"\n", DbFormat::Toml);
+        assert_eq!(db, None);
+        assert_eq!(diagnostics[0].line, Some(1));
+    }
+
+    #[test]
+    fn test_resolution_files_are_discovered_in_merge_order() {
+        let search_path = tempfile::tempdir().unwrap();
+        let files = [
+            "buildxyz.toml",
+            "b.resolutions.toml",
+            "a.resolutions.json",
+            "unrelated.toml",
+            "resolutions/zlib.toml",
+            "resolutions/nested/bison.json",
+            "resolutions/README.md",
+        ];
+        fs::create_dir_all(search_path.path().join("resolutions/nested")).unwrap();
+        for (n, file) in files.iter().enumerate() {
+            let db = read_resolution_db(&format!("[\"bin/tool{}\"]\ndecision = \"ignore\"\n", n)).unwrap();
+            let format = DbFormat::from_path(Path::new(file));
+            fs::write(search_path.path().join(file), serialize_resolution_db(&db, format, None)).unwrap();
+        }
+
+        let located = locate_resolution_db(search_path.path().to_owned());
+        assert_eq!(
+            located,
+            [
+                "buildxyz.toml",
+                "a.resolutions.json",
+                "b.resolutions.toml",
+                "resolutions/nested/bison.json",
+                "resolutions/zlib.toml",
+            ]
+            .map(|file| search_path.path().join(file))
+        );
+        // A search path can be a resolution file itself.
+        assert_eq!(locate_resolution_db(located[1].clone()), vec![located[1].clone()]);
+        assert!(locate_resolution_db(search_path.path().join("missing")).is_empty());
+
+        let mut conflicts = MergeConflicts::new(ConflictPolicy::Override);
+        let mut pins = PinCheck::new(false);
+        let signatures = SignatureCheck::new(Vec::new());
+        let db = load_resolution_db(search_path.path().to_owned(), &mut conflicts, &mut pins, &signatures)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            db.keys().collect::<Vec<_>>(),
+            vec!["bin/tool0", "bin/tool1", "bin/tool2", "bin/tool4", "bin/tool5"]
+        );
+
+        // A file which cannot be loaded is not silently skipped.
+        fs::write(search_path.path().join("resolutions/broken.toml"), "[\"bin/yacc\"\n").unwrap();
+        assert!(load_resolution_db(search_path.path().to_owned(), &mut conflicts, &mut pins, &signatures).is_err());
+        assert_eq!(
+            load_resolution_db(search_path.path().join("missing"), &mut conflicts, &mut pins, &signatures).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_reconfirm_is_kept_in_every_format() {
+        let db = read_resolution_db(
+            r#"
+["bin/cc"]
+decision = "redirect"
+target = "/usr/bin/cc"
+reconfirm = true
+
+["bin/yacc"]
+decision = "ignore"
+"#,
+        )
+        .unwrap();
+        assert!(db["bin/cc"].data_for_phase(None).unwrap().reconfirm);
+        assert!(!db["bin/yacc"].data_for_phase(None).unwrap().reconfirm);
+
+        for format in [DbFormat::Toml, DbFormat::Json] {
+            let serialized = serialize_resolution_db(&db, format, None);
+            assert_eq!(
+                deserialize_resolution_db(&serialized, format, "test"),
+                Some(db.clone())
+            );
+        }
+    }
 }
@@ -0,0 +1,316 @@
+use log::{debug, warn};
+use serde_json::{json, Value};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::frontend::{prompt_json, AnswerError, Frontend, JsonAnswer, PendingPrompts};
+use crate::fs::FsEventMessage;
+use crate::interactive::UserRequest;
+
+/// JSON-RPC 2.0 error codes.
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// The prompt was answered elsewhere or its lookup went away.
+const NOT_PENDING: i64 = -32000;
+
+/// Clients not reading their notifications anymore are dropped after this,
+/// the notifications are written with the state locked.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Requests are a few bytes of JSON, larger messages are refused rather than read.
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// Serves the prompts over JSON-RPC 2.0 on a Unix socket, framed with `Content-Length` headers like LSP
+/// so that editors can reuse their language client transport.
+///
+/// Notifications sent to every client:
+/// - `prompt` with a prompt, as `GET /api/prompts` of `--remote-listen` lists them, also when it is asked again
+/// - `cancel` with `{"id"}` once a prompt does not wait for an answer anymore
+/// - `decided` with `{"id", "path", "description"}` for every recorded decision
+///
+/// Methods:
+/// - `prompts` returns the pending prompts
+/// - `answer` with `{"id", ...}` answers a prompt like `POST /api/prompts/<id>`, see `JsonAnswer`
+/// - `undo` with `{"id"}` undoes a decision
+/// - `phase` with `{"phase"}` switches the session to this build phase, `null` for none, e.g. from a phase hook
+pub struct RpcFrontend {
+    pub socket_path: PathBuf,
+}
+
+#[derive(Default)]
+struct State {
+    prompts: PendingPrompts,
+    clients: Vec<UnixStream>,
+}
+
+impl State {
+    fn notify(&mut self, method: &str, params: Value) {
+        let message = json!({ "jsonrpc": "2.0", "method": method, "params": params });
+        self.clients
+            .retain_mut(|client| write_message(client, &message).is_ok());
+    }
+}
+
+fn write_message(stream: &mut UnixStream, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(stream, "Content-Length: {}\r\n\r\n{}", body.len(), body)
+}
+
+/// The body of the next message, `None` once the client is gone.
+fn read_message(reader: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        if line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+
+    let length = length.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length header")
+    })?;
+    if length > MAX_MESSAGE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("message of {} bytes, {} at most", length, MAX_MESSAGE_SIZE),
+        ));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+
+    Ok(Some(body))
+}
+
+fn request_id(params: &Value) -> Result<u64, (i64, String)> {
+    params["id"]
+        .as_u64()
+        .ok_or((INVALID_PARAMS, "`id` of the request expected".to_string()))
+}
+
+fn call(
+    method: &str,
+    params: Value,
+    state: &Mutex<State>,
+    reply_fs: &Sender<FsEventMessage>,
+) -> Result<Value, (i64, String)> {
+    match method {
+        "prompts" => Ok(state.lock().unwrap().prompts.to_json()),
+        "answer" => {
+            let request_id = request_id(&params)?;
+            let answer: JsonAnswer =
+                serde_json::from_value(params).map_err(|err| (INVALID_PARAMS, err.to_string()))?;
+            let mut state = state.lock().unwrap();
+            state
+                .prompts
+                .answer(request_id, answer, reply_fs)
+                .map_err(|err| match err {
+                    AnswerError::NotPending(_) => (NOT_PENDING, err.to_string()),
+                    AnswerError::Invalid => (INVALID_PARAMS, err.to_string()),
+                })?;
+            state.notify("cancel", json!({ "id": request_id }));
+            Ok(Value::Null)
+        }
+        "undo" => {
+            let request_id = request_id(&params)?;
+            reply_fs
+                .send(FsEventMessage::Undo(request_id))
+                .expect("Failed to send message to FS thread");
+            Ok(Value::Null)
+        }
+        "phase" => {
+            let phase = match &params["phase"] {
+                Value::Null => None,
+                Value::String(phase) => Some(phase.clone()),
+                _ => return Err((INVALID_PARAMS, "`phase` name or null expected".to_string())),
+            };
+            reply_fs
+                .send(FsEventMessage::SetPhase(phase))
+                .expect("Failed to send message to FS thread");
+            Ok(Value::Null)
+        }
+        method => Err((METHOD_NOT_FOUND, format!("unknown method `{}`", method))),
+    }
+}
+
+fn serve(
+    stream: UnixStream,
+    state: &Mutex<State>,
+    reply_fs: &Sender<FsEventMessage>,
+) -> io::Result<()> {
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    state.lock().unwrap().clients.push(stream.try_clone()?);
+    let mut stream = stream;
+
+    while let Some(body) = read_message(&mut reader)? {
+        let response = match serde_json::from_slice::<Value>(&body) {
+            Ok(message) => {
+                let method = message["method"].as_str().unwrap_or_default().to_string();
+                let outcome = call(&method, message["params"].clone(), state, reply_fs);
+                // Notifications are not answered.
+                let id = match message.get("id") {
+                    Some(id) => id.clone(),
+                    None => continue,
+                };
+                match outcome {
+                    Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                    Err((code, message)) => json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "error": { "code": code, "message": message },
+                    }),
+                }
+            }
+            Err(err) => json!({
+                "jsonrpc": "2.0",
+                "id": Value::Null,
+                "error": { "code": PARSE_ERROR, "message": err.to_string() },
+            }),
+        };
+        // Not interleaved with the notifications.
+        let _state = state.lock().unwrap();
+        write_message(&mut stream, &response)?;
+    }
+
+    Ok(())
+}
+
+impl Frontend for RpcFrontend {
+    fn spawn(
+        self: Box<Self>,
+        reply_fs: Sender<FsEventMessage>,
+    ) -> io::Result<(Option<JoinHandle<()>>, Sender<UserRequest>)> {
+        // Left behind by a previous run.
+        if self.socket_path.exists() {
+            std::fs::remove_file(&self.socket_path)?;
+        }
+        let listener = UnixListener::bind(&self.socket_path)?;
+        let (send_request, recv_request) = channel();
+        let state = Arc::new(Mutex::new(State::default()));
+        debug!(
+            "serving the prompts over JSON-RPC on {}",
+            self.socket_path.display()
+        );
+
+        let notified = state.clone();
+        let socket_path = self.socket_path.clone();
+        thread::spawn(move || {
+            for request in recv_request {
+                let mut state = notified.lock().unwrap();
+                state.prompts.update(&request);
+                match &request {
+                    UserRequest::Quit => break,
+                    UserRequest::Cancel(request_id) => {
+                        state.notify("cancel", json!({ "id": request_id }))
+                    }
+                    UserRequest::Decided(request_id, requested_path, description) => state.notify(
+                        "decided",
+                        json!({
+                            "id": request_id,
+                            "path": requested_path.display().to_string(),
+                            "description": description,
+                        }),
+                    ),
+                    UserRequest::Realizing(progress) => state.notify(
+                        "realizing",
+                        json!({
+                            "total": progress.total,
+                            "done": progress.done,
+                            "failed": progress.failed,
+                            "running": progress
+                                .running
+                                .iter()
+                                .map(|(store_path, copied, expected)| json!({
+                                    "store_path": store_path,
+                                    "copied": copied,
+                                    "expected": expected,
+                                }))
+                                .collect::<Vec<_>>(),
+                        }),
+                    ),
+                    request => {
+                        if let Some(prompt) = prompt_json(request) {
+                            state.notify("prompt", prompt);
+                        }
+                    }
+                }
+            }
+            let _ = std::fs::remove_file(socket_path);
+        });
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        warn!("Failed to accept a JSON-RPC connection: {}", err);
+                        continue;
+                    }
+                };
+                let (state, reply_fs) = (state.clone(), reply_fs.clone());
+                thread::spawn(move || {
+                    if let Err(err) = serve(stream, &state, &reply_fs) {
+                        debug!("JSON-RPC connection failed: {}", err);
+                    }
+                });
+            }
+        });
+
+        Ok((None, send_request))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_messages_are_framed_like_lsp() {
+        let mut input: &[u8] =
+            b"Content-Length: 17\r\n\r\n{\"method\":\"foo\"}\ncontent-length: 2\r\nX-Other: 1\r\n\r\n{}";
+        assert_eq!(
+            read_message(&mut input).unwrap().unwrap(),
+            b"{\"method\":\"foo\"}\n"
+        );
+        assert_eq!(read_message(&mut input).unwrap().unwrap(), b"{}");
+        assert!(read_message(&mut input).unwrap().is_none());
+        // Not allocated.
+        let mut input: &[u8] = b"Content-Length: 18446744073709551615\r\n\r\n{}";
+        assert_eq!(
+            read_message(&mut input).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn test_phase_method() {
+        let (reply_fs, recv_fs) = channel();
+        let state = Mutex::new(State::default());
+
+        call("phase", json!({ "phase": "configure" }), &state, &reply_fs).unwrap();
+        call("phase", json!({ "phase": null }), &state, &reply_fs).unwrap();
+        assert!(matches!(
+            recv_fs.try_recv(),
+            Ok(FsEventMessage::SetPhase(Some(phase))) if phase == "configure"
+        ));
+        assert!(matches!(recv_fs.try_recv(), Ok(FsEventMessage::SetPhase(None))));
+        assert_eq!(
+            call("phase", json!({ "phase": 42 }), &state, &reply_fs).unwrap_err().0,
+            INVALID_PARAMS
+        );
+    }
+}
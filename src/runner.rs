@@ -1,11 +1,13 @@
 use log::{debug, error, info};
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::io::{BufRead, BufReader, Read};
+use std::process::{Command, Stdio};
 use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::Arc;
 use std::thread;
 use std::{collections::HashMap, sync::mpsc::Sender};
 
+use crate::interpreter;
 use crate::EventMessage;
 
 fn append_search_path(env: &mut HashMap<String, String>, key: &str, value: PathBuf, insert: bool) {
@@ -67,6 +69,52 @@ fn append_search_paths(env: &mut HashMap<String, String>,
         });
 }
 
+/// Environment of the command inside a development shell, see `--develop`:
+/// the variables of the shell win over ours, its `PATH` comes before ours like with `nix develop`.
+/// Our search paths come after all of them, so only what the shell lacks is looked up.
+pub fn layer_dev_env(
+    mut env: HashMap<String, String>,
+    dev_env: HashMap<String, String>,
+) -> HashMap<String, String> {
+    for (key, value) in dev_env {
+        match (key.as_str(), env.get("PATH")) {
+            ("PATH", Some(host_path)) => {
+                let path = format!("{}:{}", value, host_path);
+                env.insert(key, path);
+            }
+            _ => {
+                env.insert(key, value);
+            }
+        }
+    }
+
+    env
+}
+
+/// Forward the lines of a child output, e.g. to the log pane of the TUI.
+fn forward_lines<R: Read + Send + 'static>(output: R, send_line: Sender<String>) {
+    thread::spawn(move || {
+        for line in BufReader::new(output).lines().map_while(Result::ok) {
+            if send_line.send(line).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// Where the child looks things up and where its output goes.
+pub struct RunnerOptions<'a> {
+    /// The FUSE mount, searched last
+    pub mountpoint: &'a Path,
+    /// The fast working tree, searched before the mount
+    pub fast_working_root: &'a Path,
+    /// Run scripts whose interpreter is missing through the one served by the mount
+    pub interpreter_shims: bool,
+    /// The child output, line by line, ours otherwise
+    pub send_output: Option<Sender<String>>,
+}
+
+/// Run the command with our search paths, again if it fails while `should_retry` is set.
 pub fn spawn_instrumented_program(
     cmd: String,
     args: Vec<String>,
@@ -74,24 +122,47 @@ pub fn spawn_instrumented_program(
     current_child_pid: Arc<AtomicU32>,
     should_retry: Arc<AtomicBool>,
     send_to_main: Sender<EventMessage>,
-    mountpoint: &Path,
-    fast_working_root: &Path
+    options: RunnerOptions<'_>,
 ) -> thread::JoinHandle<Option<i32>> {
+    let RunnerOptions {
+        mountpoint,
+        fast_working_root,
+        interpreter_shims,
+        send_output,
+    } = options;
 
     // Fast working tree
     append_search_paths(&mut env, fast_working_root);
     // FUSE
     append_search_paths(&mut env, mountpoint);
 
+    // Scripts whose interpreter is missing, e.g. `/usr/bin/env` in a sandbox,
+    // are run through the interpreter served by the FUSE `bin/`.
+    // Scripts they spawn themselves still rely on PATH, where our `bin/` comes last.
+    let (cmd, args) = match interpreter_shims
+        .then(|| interpreter::shim_invocation(&cmd, &args, env.get("PATH").map(|p| p.as_str()), mountpoint))
+        .flatten()
+    {
+        Some((shim, shim_args)) => {
+            info!("`{}` interpreter is missing, running it through `{}`", cmd, shim);
+            (shim, shim_args)
+        }
+        None => (cmd, args),
+    };
+
     thread::spawn(move || {
         loop {
             debug!("Spawning a child `{}`...", cmd);
-            let mut child = Command::new(&cmd)
-                .args(&args)
-                .env_clear()
-                .envs(&env)
-                .spawn()
-                .expect("Command failed to start");
+            let mut command = Command::new(&cmd);
+            command.args(&args).env_clear().envs(&env);
+            if send_output.is_some() {
+                command.stdout(Stdio::piped()).stderr(Stdio::piped());
+            }
+            let mut child = command.spawn().expect("Command failed to start");
+            if let Some(send_output) = &send_output {
+                forward_lines(child.stdout.take().expect("Piped stdout"), send_output.clone());
+                forward_lines(child.stderr.take().expect("Piped stderr"), send_output.clone());
+            }
 
             // Send our PID so we can get killed if needed.
             current_child_pid.store(child.id(), Ordering::SeqCst);
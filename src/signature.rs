@@ -0,0 +1,80 @@
+use minisign_verify::{PublicKey, Signature};
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+/// Detached signature of a resolution file, next to it as minisign writes it.
+pub fn signature_location(location: &str) -> String {
+    format!("{}.minisig", location)
+}
+
+/// Public key files as minisign writes them, or base64 keys.
+fn public_key(key: &str) -> Result<PublicKey, minisign_verify::Error> {
+    if Path::new(key).exists() {
+        PublicKey::from_file(key)
+    } else {
+        PublicKey::from_base64(key)
+    }
+}
+
+/// Sign a resolution file with minisign, `secret_key` defaults to the minisign one.
+/// minisign asks for the password of the key on the terminal.
+pub fn sign(filepath: &Path, secret_key: Option<&Path>) -> io::Result<()> {
+    let mut command = Command::new("minisign");
+    command.arg("-S").arg("-m").arg(filepath);
+    if let Some(secret_key) = secret_key {
+        command.arg("-s").arg(secret_key);
+    }
+
+    if command.status()?.success() {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("Failed to sign {}", filepath.display()),
+        ))
+    }
+}
+
+/// Verifies loaded resolution files against trusted minisign public keys, see `--require-signed`.
+/// A malicious resolution can provide `bin/cc` from any store path.
+#[derive(Default)]
+pub struct SignatureCheck {
+    /// Public keys, as files or base64, empty when signatures are not required
+    pub trusted_keys: Vec<String>,
+}
+
+impl SignatureCheck {
+    pub fn new(trusted_keys: Vec<String>) -> Self {
+        SignatureCheck { trusted_keys }
+    }
+
+    pub fn required(&self) -> bool {
+        !self.trusted_keys.is_empty()
+    }
+
+    /// Whether any trusted key signed `data`.
+    /// Verified in process, minisign itself is only needed to sign.
+    pub fn verify(&self, data: &[u8], signature: &[u8]) -> io::Result<()> {
+        let signature = Signature::decode(&String::from_utf8_lossy(signature)).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid signature: {}", err),
+            )
+        })?;
+
+        let mut failures = Vec::new();
+        for key in &self.trusted_keys {
+            // Legacy signatures, not prehashed, are accepted as `minisign -V` does.
+            match public_key(key).and_then(|public_key| public_key.verify(data, &signature, true)) {
+                Ok(()) => return Ok(()),
+                Err(err) => failures.push(format!("{}: {}", key, err)),
+            }
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("no trusted key signed it ({})", failures.join(", ")),
+        ))
+    }
+}
@@ -0,0 +1,199 @@
+use log::{debug, warn};
+use lru::LruCache;
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+
+use crate::cache::StorePath;
+use crate::nix::is_valid_path;
+
+/// Store paths whose availability is remembered.
+const KNOWN_PATHS: usize = 4096;
+/// Seconds given to the substituters to answer, they are asked before prompting.
+const TIMEOUT: &str = "5";
+
+/// Whether a store path can be had without building it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Availability {
+    Local,
+    Substitutable,
+    /// Every substituter answered it does not have it
+    MustBuild,
+    /// Some substituter could not tell, e.g. it is not reachable or is not a binary cache over HTTP
+    Unknown,
+}
+
+/// Availability of a store path from the HTTP status of its narinfo on each substituter, `None` if not asked.
+fn availability_of(statuses: &[Option<u16>]) -> Availability {
+    if statuses.contains(&Some(200)) {
+        Availability::Substitutable
+    } else if !statuses.is_empty() && statuses.iter().all(|status| *status == Some(404)) {
+        Availability::MustBuild
+    } else {
+        Availability::Unknown
+    }
+}
+
+/// HTTP status of each URL in the `%{http_code} %{url_effective}` lines written by curl.
+fn parse_statuses(stdout: &str) -> HashMap<&str, u16> {
+    stdout
+        .lines()
+        .filter_map(|line| {
+            let (status, url) = line.split_once(' ')?;
+            Some((url, status.parse().ok()?))
+        })
+        .collect()
+}
+
+/// Tells candidates the substituters have from those which would be built locally,
+/// asking for their `.narinfo` before they are ranked.
+pub struct SubstituterCheck {
+    /// Nothing is checked if empty, see `--no-substituter-check`
+    substituters: Vec<String>,
+    /// Store path -> its availability
+    known: Mutex<LruCache<String, Availability>>,
+}
+
+impl Default for SubstituterCheck {
+    fn default() -> Self {
+        SubstituterCheck::new(Vec::new())
+    }
+}
+
+impl SubstituterCheck {
+    pub fn new(substituters: Vec<String>) -> Self {
+        SubstituterCheck {
+            substituters,
+            known: Mutex::new(LruCache::new(NonZeroUsize::new(KNOWN_PATHS).unwrap())),
+        }
+    }
+
+    /// Find out whether these store paths are available, those already known are not asked again.
+    /// The narinfos are asked to all the substituters at once.
+    pub fn check<'a>(&self, store_paths: impl IntoIterator<Item = &'a StorePath>) {
+        if self.substituters.is_empty() {
+            return;
+        }
+        let mut unknown: Vec<&StorePath> = {
+            let known = self.known.lock().unwrap();
+            store_paths
+                .into_iter()
+                .filter(|store_path| !known.contains(store_path.as_str().as_ref()))
+                .collect()
+        };
+        unknown.dedup_by_key(|store_path| store_path.hash());
+        let (local, remote): (Vec<&StorePath>, Vec<&StorePath>) = unknown
+            .into_iter()
+            .partition(|store_path| is_valid_path(&store_path.as_str()));
+
+        let narinfo_urls = |store_path: &StorePath| -> Vec<Option<String>> {
+            self.substituters
+                .iter()
+                .map(|substituter| {
+                    (substituter.starts_with("https://") || substituter.starts_with("http://"))
+                        .then(|| {
+                            format!(
+                                "{}/{}.narinfo",
+                                substituter.trim_end_matches('/'),
+                                store_path.hash()
+                            )
+                        })
+                })
+                .collect()
+        };
+        let urls: Vec<String> = remote
+            .iter()
+            .flat_map(|store_path| narinfo_urls(store_path))
+            .flatten()
+            .collect();
+        let stdout = if urls.is_empty() {
+            String::new()
+        } else {
+            let mut command = Command::new("curl");
+            command
+                .args(["--silent", "--head", "--parallel", "--max-time", TIMEOUT])
+                .args(["--write-out", "%{http_code} %{url_effective}\\n"])
+                .stdin(Stdio::null());
+            for url in &urls {
+                command.args(["--output", "/dev/null"]).arg(url);
+            }
+            match command.output() {
+                Ok(output) => String::from_utf8_lossy(&output.stdout).into_owned(),
+                Err(err) => {
+                    warn!("Failed to ask the substituters for the candidates: {}", err);
+                    String::new()
+                }
+            }
+        };
+        let statuses = parse_statuses(&stdout);
+
+        let mut known = self.known.lock().unwrap();
+        for store_path in local {
+            known.put(store_path.as_str().into_owned(), Availability::Local);
+        }
+        for store_path in remote {
+            let asked: Vec<Option<u16>> = narinfo_urls(store_path)
+                .into_iter()
+                .map(|url| statuses.get(url?.as_str()).copied())
+                .collect();
+            let availability = availability_of(&asked);
+            debug!("{} is {:?}", store_path.as_str(), availability);
+            known.put(store_path.as_str().into_owned(), availability);
+        }
+    }
+
+    /// Whether no substituter has the store path and it is not in the local store, as checked before.
+    pub fn must_build(&self, store_path: &StorePath) -> bool {
+        self.known
+            .lock()
+            .unwrap()
+            .peek(store_path.as_str().as_ref())
+            == Some(&Availability::MustBuild)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_paths_no_substituter_has_must_be_built() {
+        let statuses = parse_statuses(
+            "200 https://cache.nixos.org/zy4xhzc6x6ldrfzqzfa4x3cw8ds1mgcn.narinfo\n\
+             404 https://cache.nixos.org/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa.narinfo\n\
+             000 https://example.cachix.org/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa.narinfo\n",
+        );
+        let status = |url: &str| statuses.get(url).copied();
+
+        assert_eq!(
+            availability_of(&[status(
+                "https://cache.nixos.org/zy4xhzc6x6ldrfzqzfa4x3cw8ds1mgcn.narinfo"
+            )]),
+            Availability::Substitutable
+        );
+        assert_eq!(
+            availability_of(&[status(
+                "https://cache.nixos.org/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa.narinfo"
+            )]),
+            Availability::MustBuild
+        );
+        // An unreachable substituter may have it.
+        assert_eq!(
+            availability_of(&[
+                status("https://cache.nixos.org/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa.narinfo"),
+                status("https://example.cachix.org/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa.narinfo"),
+            ]),
+            Availability::Unknown
+        );
+        // So may a substituter which is not asked, e.g. `ssh://`.
+        assert_eq!(
+            availability_of(&[
+                status("https://cache.nixos.org/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa.narinfo"),
+                None
+            ]),
+            Availability::Unknown
+        );
+        assert_eq!(availability_of(&[]), Availability::Unknown);
+    }
+}
@@ -0,0 +1,90 @@
+use serde::Serialize;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, LineWriter, Write};
+use std::path::Path;
+use std::time::{Instant, SystemTime};
+
+use crate::process::ProcessInfo;
+
+/// How a lookup was answered.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AccessOutcome {
+    GlobalDirectory,
+    Blocked,
+    FilteredProcess,
+    RecordedEnoent,
+    FastWorkingTree,
+    SynthesizedDirectory,
+    /// Answered by an existing resolution
+    ResolvedProvide,
+    ResolvedIgnore,
+    ResolvedHostFallback,
+    ResolvedRedirect,
+    ResolvedStub,
+    /// Answered by the user or the automatic mode
+    Provided,
+    Ignored,
+    Interrupted,
+    /// Not in the index but present on the host
+    HostFallback,
+    NotFound,
+}
+
+impl fmt::Display for AccessOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // As named in the trace.
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::String(name)) => f.write_str(&name),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct AccessEvent<'a> {
+    /// Seconds since the UNIX epoch
+    timestamp: f64,
+    /// Milliseconds since the beginning of the trace
+    elapsed_ms: u128,
+    path: &'a str,
+    outcome: AccessOutcome,
+    requested_by: Option<&'a ProcessInfo>,
+}
+
+/// Ordered record of every lookup reaching the filesystem, as JSON lines.
+pub struct AccessTrace {
+    start: Instant,
+    writer: LineWriter<File>,
+}
+
+impl AccessTrace {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(AccessTrace {
+            start: Instant::now(),
+            writer: LineWriter::new(File::create(path)?),
+        })
+    }
+
+    pub fn record(
+        &mut self,
+        path: &Path,
+        outcome: AccessOutcome,
+        requested_by: Option<&ProcessInfo>,
+    ) -> io::Result<()> {
+        let event = AccessEvent {
+            timestamp: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+            elapsed_ms: self.start.elapsed().as_millis(),
+            path: &path.to_string_lossy(),
+            outcome,
+            requested_by,
+        };
+
+        serde_json::to_writer(&mut self.writer, &event)?;
+        self.writer.write_all(b"\n")
+    }
+}
@@ -0,0 +1,1145 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, Stdout};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::execute;
+use crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use log::{debug, error, info, Log, Metadata, Record};
+use tui::backend::CrosstermBackend;
+use tui::layout::{Constraint, Direction, Layout, Rect};
+use tui::style::{Color, Modifier, Style};
+use tui::text::{Span, Spans};
+use tui::widgets::{Block, Borders, Cell, Paragraph, Row, Table, TableState, Wrap};
+use tui::{Frame, Terminal};
+
+use crate::audit::{AuditEntry, AuditTrail};
+use crate::cache::{FileNode, FileTreeEntry, StorePath};
+use crate::compare::{candidate_details, comparison, CandidateDetails};
+use crate::explain::Explanation;
+use crate::frontend::Frontend;
+use crate::fs::FsEventMessage;
+use crate::fuzzy;
+use crate::interactive::{
+    answer_automatically, human_size, prompt_title, send_answer, PromptAnswer, TimeoutDecision,
+    UserRequest, CLOSURE_SIZE_WORKERS,
+};
+use crate::nix::closure_size;
+use crate::outputs;
+use crate::policy::AutomaticPolicy;
+use crate::realize::RealizationProgress;
+use crate::EventMessage;
+
+/// How often the screen is redrawn when nothing happens, e.g. to count down timeouts.
+const TICK: Duration = Duration::from_millis(100);
+
+/// Lines of build log kept for scrolling back.
+const LOG_CAPACITY: usize = 5000;
+
+/// Frames shown in place of closure sizes being computed.
+const SPINNER: [&str; 8] = ["⣾", "⣽", "⣻", "⢿", "⡿", "⣟", "⣯", "⣷"];
+
+/// A candidate of a prompt and its matched file.
+type Candidate = (StorePath, FileTreeEntry);
+
+/// Where the logs go while the TUI owns the terminal.
+static LOG_PANE: Mutex<Option<Sender<String>>> = Mutex::new(None);
+
+/// Writes logs like `stderrlog` does, unless the TUI owns the terminal:
+/// they are shown in its log pane then.
+struct PaneLogger;
+
+impl Log for PaneLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let line = format!("{} - {}", record.level(), record.args());
+        match &*LOG_PANE.lock().unwrap() {
+            Some(pane) if pane.send(line.clone()).is_ok() => {}
+            _ => eprintln!("{}", line),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Install the logger which hands the logs over to the TUI while it runs.
+pub fn init_logger() {
+    log::set_logger(&PaneLogger).expect("Failed to set the logger");
+    log::set_max_level(log::LevelFilter::Trace);
+}
+
+/// Gives the terminal back when the TUI goes away, even on panics.
+struct TerminalGuard;
+
+impl TerminalGuard {
+    fn acquire(
+        log_pane: Sender<String>,
+    ) -> io::Result<(Self, Terminal<CrosstermBackend<Stdout>>)> {
+        enable_raw_mode()?;
+        let guard = TerminalGuard;
+        execute!(io::stdout(), EnterAlternateScreen)?;
+        *LOG_PANE.lock().unwrap() = Some(log_pane);
+
+        Ok((guard, Terminal::new(CrosstermBackend::new(io::stdout()))?))
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        *LOG_PANE.lock().unwrap() = None;
+        let _ = execute!(io::stdout(), LeaveAlternateScreen);
+        let _ = disable_raw_mode();
+    }
+}
+
+/// Computes closure sizes in the background, `nix path-info` takes a while for remote paths.
+fn spawn_closure_sizes() -> (Sender<String>, Receiver<(String, Option<usize>)>) {
+    let (send_path, recv_path) = channel::<String>();
+    let (send_size, recv_size) = channel();
+    let recv_path = Arc::new(Mutex::new(recv_path));
+
+    for _ in 0..CLOSURE_SIZE_WORKERS {
+        let (recv_path, send_size) = (recv_path.clone(), send_size.clone());
+        thread::spawn(move || loop {
+            let store_path = match recv_path.lock().unwrap().recv() {
+                Ok(store_path) => store_path,
+                Err(_) => break,
+            };
+            let size = closure_size(&store_path);
+            if send_size.send((store_path, size)).is_err() {
+                break;
+            }
+        });
+    }
+
+    (send_path, recv_size)
+}
+
+/// Gathers the details of the candidates being compared in the background, see `compare::candidate_details`.
+fn spawn_candidate_details() -> (Sender<Candidate>, Receiver<(String, CandidateDetails)>) {
+    let (send_candidate, recv_candidate) = channel::<Candidate>();
+    let (send_details, recv_details) = channel();
+
+    thread::spawn(move || {
+        for (store_path, entry) in recv_candidate {
+            let key = store_path.join_entry(entry.clone()).into_owned();
+            if send_details
+                .send((key, candidate_details(&store_path, &entry)))
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+
+    (send_candidate, recv_details)
+}
+
+fn describe_entry(store_path: &StorePath, entry: &FileTreeEntry) -> Vec<Spans<'static>> {
+    let kind = match &entry.node {
+        FileNode::Regular { size, executable } => format!(
+            "{}file of {}",
+            if *executable { "executable " } else { "" },
+            human_size(*size as usize)
+        ),
+        FileNode::Symlink { target } => {
+            format!("symlink to {}", String::from_utf8_lossy(target))
+        }
+        FileNode::Directory { size, .. } => format!("directory of {} entries", size),
+    };
+
+    vec![
+        Spans::from(vec![
+            Span::styled("path: ", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(store_path.join_entry(entry.clone()).into_owned().as_str().to_string()),
+        ]),
+        Spans::from(vec![
+            Span::styled("kind: ", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(kind),
+        ]),
+        Spans::from(vec![
+            Span::styled("package: ", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(format!(
+                "{} ({})",
+                outputs::label(&store_path.origin()),
+                store_path.name()
+            )),
+        ]),
+    ]
+}
+
+/// State of the TUI between two frames.
+struct App {
+    /// Prompts waiting for the user, the first one is shown
+    prompts: VecDeque<UserRequest>,
+    /// Since when the first prompt is shown
+    shown_since: Instant,
+    selection: TableState,
+    /// Typed to filter the candidates of the shown prompt
+    filter: String,
+    /// Request whose manual search results are awaited, shown first when they come
+    searching: Option<u64>,
+    log: VecDeque<String>,
+    /// Lines scrolled back from the end of the log
+    log_scroll: usize,
+    /// Store path -> closure size, if known
+    closure_sizes: HashMap<String, Option<usize>>,
+    requested_sizes: HashSet<String>,
+    send_closure_size: Sender<String>,
+    /// Candidates of the shown prompt marked for comparison, at most two
+    compared: Vec<usize>,
+    /// Matched file -> details of its candidate, once gathered
+    details: HashMap<String, CandidateDetails>,
+    requested_details: HashSet<String>,
+    send_details: Sender<Candidate>,
+    /// Why the shown prompt is requested, once asked with Ctrl-E: its request, then what the filesystem tells
+    explanation: Option<(u64, Option<Explanation>)>,
+    /// Whether suggestions are accepted automatically, their decisions get a panel of their own
+    automatic: bool,
+    audit: AuditTrail,
+    /// Decisions taken automatically, oldest first
+    automatic_decisions: VecDeque<AuditEntry>,
+    /// Decisions of this session which can be undone, see `UserRequest::Decided`
+    decisions: Vec<(u64, PathBuf, String)>,
+    /// Where the realizations of store paths stand, until they are all finished
+    realizing: Option<RealizationProgress>,
+    /// Whether the decisions are shown instead of the prompts
+    reviewing: bool,
+    review_selection: TableState,
+}
+
+impl App {
+    fn next_prompt(&mut self) {
+        self.prompts.pop_front();
+        self.prompt_changed();
+    }
+
+    /// Show another pending prompt, the shown one waits in the queue:
+    /// the next one if `forward`, the last one otherwise.
+    fn rotate_prompts(&mut self, forward: bool) {
+        if self.prompts.len() < 2 {
+            return;
+        }
+        if forward {
+            self.prompts.rotate_left(1);
+        } else {
+            self.prompts.rotate_right(1);
+        }
+        self.prompt_changed();
+    }
+
+    fn prompt_changed(&mut self) {
+        self.shown_since = Instant::now();
+        self.selection.select(Some(0));
+        self.filter.clear();
+        self.compared.clear();
+        self.explanation = None;
+    }
+
+    /// Ask the filesystem why the path of the shown prompt is requested, or stop showing it.
+    fn toggle_explanation(&mut self, reply_fs: &Sender<FsEventMessage>) {
+        if self.explanation.take().is_some() {
+            return;
+        }
+        if let Some(request_id) = self.prompts.front().and_then(UserRequest::prompt_id) {
+            let _ = reply_fs.send(FsEventMessage::Explain(request_id));
+            self.explanation = Some((request_id, None));
+        }
+    }
+
+    /// Rows of the area below the prompt, it grows to show a comparison or an explanation.
+    fn preview_height(&self) -> u16 {
+        match &self.explanation {
+            Some((_, Some(explanation))) => explanation.lines().len() as u16 + 2,
+            Some((_, None)) => 3,
+            // Room for every row of the comparison.
+            None if self.compared_candidates().is_some() => 9,
+            None => 5,
+        }
+    }
+
+    /// Indices of the candidates of the shown prompt matching the filter, best first.
+    fn visible_candidates(&self) -> Vec<usize> {
+        let candidates = match self.prompts.front() {
+            Some(UserRequest::InteractiveSearch(_, candidates, ..)) => candidates,
+            _ => return Vec::new(),
+        };
+        // By attribute and version, e.g. `python311.out python3-3.11.4`.
+        let mut scored: Vec<(i64, usize)> = candidates
+            .iter()
+            .enumerate()
+            .filter_map(|(index, (store_path, _))| {
+                let text = format!(
+                    "{} {}",
+                    outputs::label(&store_path.origin()),
+                    store_path.name()
+                );
+                fuzzy::filter_score(&self.filter, &text).map(|score| (score, index))
+            })
+            .collect();
+        if !self.filter.is_empty() {
+            scored.sort_by_key(|(score, index)| (-score, *index));
+        }
+
+        scored.into_iter().map(|(_, index)| index).collect()
+    }
+
+    fn choices(&self) -> usize {
+        match self.prompts.front() {
+            Some(UserRequest::InteractiveSearch(..)) => self.visible_candidates().len(),
+            Some(UserRequest::Reconfirm(..)) => 1,
+            _ => 0,
+        }
+    }
+
+    /// The answer to the shown prompt with the selected choice, if any is left by the filter.
+    fn selected_answer(&self) -> Option<PromptAnswer> {
+        let selected = self.selection.selected().unwrap_or(0);
+        match self.prompts.front()? {
+            UserRequest::InteractiveSearch(..) => match self.visible_candidates().get(selected) {
+                Some(index) => Some(PromptAnswer::Choice(*index)),
+                // Nothing listed matches, look it up instead.
+                None => self.search_answer(),
+            },
+            _ => Some(PromptAnswer::Choice(0)),
+        }
+    }
+
+    /// Look up what was typed rather than picking a candidate.
+    fn search_answer(&self) -> Option<PromptAnswer> {
+        match self.prompts.front()? {
+            UserRequest::InteractiveSearch(..) if !self.filter.trim().is_empty() => {
+                Some(PromptAnswer::Search(self.filter.trim().to_string()))
+            }
+            _ => None,
+        }
+    }
+
+    fn edit_filter(&mut self, edit: impl FnOnce(&mut String)) {
+        if let Some(UserRequest::InteractiveSearch(..)) = self.prompts.front() {
+            edit(&mut self.filter);
+            self.selection.select(Some(0));
+        }
+    }
+
+    fn move_selection(&mut self, offset: isize) {
+        let choices = self.choices();
+        if choices == 0 {
+            return;
+        }
+        let selected = self.selection.selected().unwrap_or(0) as isize + offset;
+        self.selection
+            .select(Some(selected.clamp(0, choices as isize - 1) as usize));
+    }
+
+    /// Ask the closure sizes of the shown candidates, once.
+    fn request_closure_sizes(&mut self) {
+        if let Some(UserRequest::InteractiveSearch(_, candidates, ..)) = self.prompts.front() {
+            for (store_path, _) in candidates {
+                let store_path = store_path.as_str().to_string();
+                if self.requested_sizes.insert(store_path.clone()) {
+                    let _ = self.send_closure_size.send(store_path);
+                }
+            }
+        }
+    }
+
+    /// Mark the selected candidate for comparison, or unmark it.
+    /// Marking a third one forgets the oldest one.
+    fn toggle_compared(&mut self) {
+        let selected = self.selection.selected().unwrap_or(0);
+        let index = match self.visible_candidates().get(selected) {
+            Some(index) => *index,
+            None => return,
+        };
+        if let Some(position) = self.compared.iter().position(|compared| *compared == index) {
+            self.compared.remove(position);
+            return;
+        }
+        self.compared.push(index);
+        if self.compared.len() > 2 {
+            self.compared.remove(0);
+        }
+    }
+
+    /// The two candidates marked for comparison, if so.
+    fn compared_candidates(&self) -> Option<[&Candidate; 2]> {
+        match (self.prompts.front(), self.compared.as_slice()) {
+            (Some(UserRequest::InteractiveSearch(_, candidates, ..)), [left, right]) => {
+                Some([&candidates[*left], &candidates[*right]])
+            }
+            _ => None,
+        }
+    }
+
+    /// Ask the details of the compared candidates, once.
+    fn request_details(&mut self) {
+        let candidates: Vec<Candidate> = match self.compared_candidates() {
+            Some(candidates) => candidates.into_iter().cloned().collect(),
+            None => return,
+        };
+        for (store_path, entry) in candidates {
+            if self
+                .requested_details
+                .insert(store_path.join_entry(entry.clone()).into_owned())
+            {
+                let _ = self.send_details.send((store_path, entry));
+            }
+        }
+    }
+
+    fn move_review_selection(&mut self, offset: isize) {
+        if self.decisions.is_empty() {
+            return;
+        }
+        let selected = self.review_selection.selected().unwrap_or(0) as isize + offset;
+        self.review_selection
+            .select(Some(selected.clamp(0, self.decisions.len() as isize - 1) as usize));
+    }
+
+    /// Undo the selected decision, it is asked again on the next access.
+    fn undo_selected(&mut self, reply_fs: &Sender<FsEventMessage>) {
+        let selected = self.review_selection.selected().unwrap_or(0);
+        if selected >= self.decisions.len() {
+            return;
+        }
+        let (request_id, requested_path, description) = self.decisions.remove(selected);
+        info!("Undoing {}: {}", requested_path.display(), description);
+        reply_fs
+            .send(FsEventMessage::Undo(request_id))
+            .expect("Failed to send message to FS thread");
+        self.move_review_selection(0);
+    }
+
+    fn push_automatic_decision(&mut self, entry: AuditEntry) {
+        self.automatic_decisions.push_back(entry);
+        if self.automatic_decisions.len() > LOG_CAPACITY {
+            self.automatic_decisions.pop_front();
+        }
+    }
+
+    fn push_log(&mut self, line: String) {
+        self.log.push_back(line);
+        if self.log.len() > LOG_CAPACITY {
+            self.log.pop_front();
+        }
+        // Keep looking at the same lines while scrolled back.
+        if self.log_scroll > 0 {
+            self.log_scroll = (self.log_scroll + 1).min(self.log.len());
+        }
+    }
+}
+
+fn draw_prompt<B: tui::backend::Backend>(f: &mut Frame<B>, app: &mut App, prompt: Rect, preview: Rect) {
+    let block = |title: String| Block::default().borders(Borders::ALL).title(title);
+    let highlight = Style::default()
+        .bg(Color::Blue)
+        .fg(Color::White)
+        .add_modifier(Modifier::BOLD);
+    // Parallel builds queue up prompts, they can be answered in any order.
+    let prompt = if app.prompts.len() > 1 {
+        let areas = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(75), Constraint::Percentage(25)])
+            .split(prompt);
+        draw_queue(f, app, areas[1]);
+        areas[0]
+    } else {
+        prompt
+    };
+
+    let visible = app.visible_candidates();
+    match app.prompts.front() {
+        Some(request @ UserRequest::InteractiveSearch(_, candidates, rankings, _, _, fuzzy, _, subtree, _)) => {
+            let header = Row::new(vec!["Package", "Closure", "Ranked by", "Matched file"])
+                .style(Style::default().add_modifier(Modifier::BOLD));
+            let rows: Vec<Row> = visible
+                .iter()
+                .map(|index| (index, &candidates[*index], &rankings[*index]))
+                .map(|(index, (store_path, entry), ranking)| {
+                    let closure_size = match app.closure_sizes.get(store_path.as_str().as_ref()) {
+                        Some(Some(size)) => human_size(*size),
+                        Some(None) => "?".into(),
+                        None => SPINNER[(app.shown_since.elapsed().as_millis() / TICK.as_millis()) as usize
+                            % SPINNER.len()]
+                            .into(),
+                    };
+                    let marker = if app.compared.contains(index) { "* " } else { "" };
+                    Row::new(vec![
+                        Cell::from(format!("{}{}", marker, outputs::label(&store_path.origin()))),
+                        Cell::from(closure_size),
+                        Cell::from(ranking.to_string()),
+                        Cell::from(String::from_utf8_lossy(&entry.path).to_string()),
+                    ])
+                })
+                .collect();
+            let widths = [
+                Constraint::Percentage(25),
+                Constraint::Length(10),
+                Constraint::Percentage(40),
+                Constraint::Percentage(35),
+            ];
+            let mut title = if *fuzzy {
+                format!("{} (fuzzy matches)", prompt_title(request))
+            } else {
+                prompt_title(request)
+            };
+            if let Some(subtree) = subtree {
+                title.push_str(&format!(" | Ctrl-A provides all of {}/", subtree.display()));
+            }
+            if !app.filter.is_empty() {
+                title.push_str(&format!(
+                    " | filter: {} ({} of {})",
+                    app.filter,
+                    visible.len(),
+                    candidates.len()
+                ));
+            }
+            let table = Table::new(rows)
+                .header(header)
+                .block(block(title))
+                .widths(&widths)
+                .highlight_style(highlight)
+                .highlight_symbol("> ");
+            f.render_stateful_widget(table, prompt, &mut app.selection);
+
+            let selected = app.selection.selected().unwrap_or(0);
+            if let Some((_, explanation)) = &app.explanation {
+                draw_explanation(f, app, explanation.as_ref(), preview);
+            } else if let Some(compared) = app.compared_candidates() {
+                draw_comparison(f, app, compared, preview);
+            } else if let Some((store_path, entry)) = visible.get(selected).map(|index| &candidates[*index]) {
+                f.render_widget(
+                    Paragraph::new(describe_entry(store_path, entry))
+                        .block(block("Candidate".into()))
+                        .wrap(Wrap { trim: false }),
+                    preview,
+                );
+            }
+        }
+        Some(request @ UserRequest::Reconfirm(_, requested_path, description, _)) => {
+            let table = Table::new(vec![Row::new(vec![description.clone()])])
+                .block(block(prompt_title(request)))
+                .widths(&[Constraint::Percentage(100)])
+                .highlight_style(highlight)
+                .highlight_symbol("> ");
+            f.render_stateful_widget(table, prompt, &mut app.selection);
+            if let Some((_, explanation)) = &app.explanation {
+                draw_explanation(f, app, explanation.as_ref(), preview);
+                return;
+            }
+            f.render_widget(
+                Paragraph::new(format!("{}: {}", requested_path.display(), description))
+                    .block(block("Resolution".into()))
+                    .wrap(Wrap { trim: false }),
+                preview,
+            );
+        }
+        _ => {}
+    }
+}
+
+/// Paths of the pending prompts, the shown one first.
+fn draw_queue<B: tui::backend::Backend>(f: &mut Frame<B>, app: &App, area: Rect) {
+    let lines: Vec<Spans> = app
+        .prompts
+        .iter()
+        .enumerate()
+        .map(|(position, request)| {
+            let path = request
+                .requested_path()
+                .map_or(String::new(), |path| path.display().to_string());
+            match request {
+                _ if position == 0 => Spans::from(Span::styled(
+                    format!("> {}", path),
+                    Style::default().add_modifier(Modifier::BOLD),
+                )),
+                UserRequest::Reconfirm(..) => Spans::from(format!("  {} (to confirm)", path)),
+                _ => Spans::from(format!("  {}", path)),
+            }
+        })
+        .collect();
+    f.render_widget(
+        Paragraph::new(lines).block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(format!("Queue ({}), Ctrl-N/Ctrl-P switch", app.prompts.len())),
+        ),
+        area,
+    );
+}
+
+/// Side by side details of the two candidates marked with Ctrl-X.
+fn draw_comparison<B: tui::backend::Backend>(
+    f: &mut Frame<B>,
+    app: &App,
+    [left, right]: [&Candidate; 2],
+    preview: Rect,
+) {
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .title("Comparison, Ctrl-X unmarks the selected candidate");
+    let details = |(store_path, entry): &Candidate| {
+        app.details
+            .get(store_path.join_entry(entry.clone()).as_ref())
+            .cloned()
+    };
+    let rows: Vec<Row> = match (details(left), details(right)) {
+        (Some(left), Some(right)) => comparison(&left, &right)
+            .into_iter()
+            .map(|[label, left, right]| {
+                Row::new(vec![
+                    Cell::from(label).style(Style::default().add_modifier(Modifier::BOLD)),
+                    Cell::from(left),
+                    Cell::from(right),
+                ])
+            })
+            .collect(),
+        _ => vec![Row::new(vec![
+            Cell::from(
+                SPINNER[(app.shown_since.elapsed().as_millis() / TICK.as_millis()) as usize % SPINNER.len()],
+            ),
+            Cell::from("Evaluating nixpkgs and asking the store, this may take a while"),
+        ])],
+    };
+    let table = Table::new(rows).block(block).widths(&[
+        Constraint::Length(16),
+        Constraint::Percentage(42),
+        Constraint::Percentage(42),
+    ]);
+    f.render_widget(table, preview);
+}
+
+/// Why the path of the shown prompt is requested, asked with Ctrl-E.
+fn draw_explanation<B: tui::backend::Backend>(
+    f: &mut Frame<B>,
+    app: &App,
+    explanation: Option<&Explanation>,
+    preview: Rect,
+) {
+    let lines: Vec<Spans> = match explanation {
+        Some(explanation) => explanation.lines().into_iter().map(Spans::from).collect(),
+        None => vec![Spans::from(format!(
+            "{} Asking the filesystem",
+            SPINNER[(app.shown_since.elapsed().as_millis() / TICK.as_millis()) as usize % SPINNER.len()]
+        ))],
+    };
+    f.render_widget(
+        Paragraph::new(lines)
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title("Why is it requested? Ctrl-E goes back"),
+            )
+            .wrap(Wrap { trim: false }),
+        preview,
+    );
+}
+
+fn draw_decisions<B: tui::backend::Backend>(f: &mut Frame<B>, app: &mut App, decisions: Rect, preview: Rect) {
+    let block = |title: String| Block::default().borders(Borders::ALL).title(title);
+    let rows: Vec<Row> = app
+        .decisions
+        .iter()
+        .map(|(_, requested_path, description)| {
+            Row::new(vec![
+                Cell::from(requested_path.display().to_string()),
+                Cell::from(description.clone()),
+            ])
+        })
+        .collect();
+    let table = Table::new(rows)
+        .header(Row::new(vec!["Path", "Decision"]).style(Style::default().add_modifier(Modifier::BOLD)))
+        .block(block(format!(
+            "{} decisions made during this session, Enter or Delete undoes one, Esc goes back",
+            app.decisions.len()
+        )))
+        .widths(&[Constraint::Percentage(40), Constraint::Percentage(60)])
+        .highlight_style(
+            Style::default()
+                .bg(Color::Blue)
+                .fg(Color::White)
+                .add_modifier(Modifier::BOLD),
+        )
+        .highlight_symbol("> ");
+    f.render_stateful_widget(table, decisions, &mut app.review_selection);
+
+    let selected = app.review_selection.selected().unwrap_or(0);
+    if let Some((_, requested_path, description)) = app.decisions.get(selected) {
+        f.render_widget(
+            Paragraph::new(format!(
+                "{}: {}\nUndoing it forgets the decision, the path is asked again on its next lookup.",
+                requested_path.display(),
+                description
+            ))
+            .block(block("Decision".into()))
+            .wrap(Wrap { trim: false }),
+            preview,
+        );
+    }
+}
+
+/// The latest decisions of `--automatic`, as they are recorded, to stop the build when one looks wrong.
+fn draw_automatic_decisions<B: tui::backend::Backend>(f: &mut Frame<B>, app: &App, area: Rect) {
+    let visible = area.height.saturating_sub(2) as usize;
+    let skipped = app.automatic_decisions.len().saturating_sub(visible);
+    let lines: Vec<Spans> = app
+        .automatic_decisions
+        .iter()
+        .skip(skipped)
+        .map(|entry| {
+            Spans::from(vec![
+                Span::raw(format!("{} → ", entry.path.display())),
+                Span::styled(entry.installable.clone(), Style::default().add_modifier(Modifier::BOLD)),
+                Span::styled(
+                    format!(" ({}{})", if entry.fuzzy { "fuzzy, " } else { "" }, entry.ranking),
+                    Style::default().fg(if entry.fuzzy { Color::Yellow } else { Color::DarkGray }),
+                ),
+            ])
+        })
+        .collect();
+    f.render_widget(
+        Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title(format!(
+            "Automatic decisions ({}), Ctrl-R undoes them, Ctrl-C stops the build",
+            app.automatic_decisions.len()
+        ))),
+        area,
+    );
+}
+
+fn draw<B: tui::backend::Backend>(f: &mut Frame<B>, app: &mut App, prompt_timeout: Option<Duration>) {
+    let prompting = !app.prompts.is_empty() || app.reviewing;
+    let areas = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints(if prompting {
+            vec![
+                Constraint::Percentage(40),
+                Constraint::Length(app.preview_height()),
+                Constraint::Min(3),
+                Constraint::Length(1),
+            ]
+        } else {
+            vec![Constraint::Min(3), Constraint::Length(1)]
+        })
+        .split(f.size());
+    let (log_area, status_area) = if app.reviewing {
+        draw_decisions(f, app, areas[0], areas[1]);
+        (areas[2], areas[3])
+    } else if prompting {
+        draw_prompt(f, app, areas[0], areas[1]);
+        (areas[2], areas[3])
+    } else {
+        (areas[0], areas[1])
+    };
+    let log_area = if app.automatic {
+        let areas = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Percentage(35), Constraint::Min(3)])
+            .split(log_area);
+        draw_automatic_decisions(f, app, areas[0]);
+        areas[1]
+    } else {
+        log_area
+    };
+
+    // Only the lines which fit, from the end of the log or where it was scrolled back to.
+    let visible = log_area.height.saturating_sub(2) as usize;
+    let end = app.log.len() - app.log_scroll.min(app.log.len());
+    let lines: Vec<Spans> = app
+        .log
+        .range(end.saturating_sub(visible)..end)
+        .map(|line| {
+            let style = if line.starts_with("ERROR") {
+                Style::default().fg(Color::Red)
+            } else if line.starts_with("WARN") {
+                Style::default().fg(Color::Yellow)
+            } else {
+                Style::default()
+            };
+            Spans::from(Span::styled(line.clone(), style))
+        })
+        .collect();
+    let log_title = if app.log_scroll > 0 {
+        format!("Build log ({} lines back)", app.log_scroll)
+    } else {
+        "Build log".to_string()
+    };
+    f.render_widget(
+        Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title(log_title)),
+        log_area,
+    );
+
+    let mut status = match app.prompts.len() {
+        0 => "No pending prompt".to_string(),
+        1 => "1 pending prompt".to_string(),
+        count => format!("{} pending prompts", count),
+    };
+    if let Some(progress) = &app.realizing {
+        status.push_str(&format!(" | {}", progress));
+    }
+    if app.reviewing {
+        status.push_str(" | ↑↓ select, Enter undo, Esc back to the prompts");
+    } else if prompting {
+        status.push_str(
+            " | type to filter, Tab look it up, ↑↓ select, Ctrl-X compare, Ctrl-E explain, Enter accept, Esc skip, Ctrl-S skip for now",
+        );
+        if let Some(timeout) = prompt_timeout {
+            status.push_str(&format!(
+                ", default decision in {}s",
+                timeout.saturating_sub(app.shown_since.elapsed()).as_secs()
+            ));
+        }
+    }
+    status.push_str(" | Ctrl-R review decisions, PgUp/PgDn scroll the log, Ctrl-C stops the build");
+    f.render_widget(
+        Paragraph::new(status).style(Style::default().add_modifier(Modifier::REVERSED)),
+        status_area,
+    );
+}
+
+/// Prompts in a full screen TUI, see `spawn_tui`.
+pub struct TuiFrontend {
+    pub send_to_main: Sender<EventMessage>,
+    pub log_pane: (Sender<String>, Receiver<String>),
+    /// Policy accepting the suggestions, `None` unless `--automatic`
+    pub automatic: Option<AutomaticPolicy>,
+    /// Follows the accepted suggestions, see `--audit-log`
+    pub audit: AuditTrail,
+    pub prompt_timeout: Option<Duration>,
+    pub timeout_decision: TimeoutDecision,
+}
+
+impl Frontend for TuiFrontend {
+    fn spawn(
+        self: Box<Self>,
+        reply_fs: Sender<FsEventMessage>,
+    ) -> io::Result<(Option<JoinHandle<()>>, Sender<UserRequest>)> {
+        let (join_handle, send) = spawn_tui(
+            reply_fs,
+            self.send_to_main,
+            self.log_pane,
+            self.automatic,
+            self.audit,
+            self.prompt_timeout,
+            self.timeout_decision,
+        );
+
+        Ok((Some(join_handle), send))
+    }
+}
+
+/// Spawn the UI thread with a full screen TUI, see `interactive::spawn_ui` for the line based one.
+/// `log_pane` receives the logs and the build output, `send_to_main` is told about Ctrl-C
+/// as the terminal does not turn it into a signal anymore.
+pub fn spawn_tui(
+    reply_fs: Sender<FsEventMessage>,
+    send_to_main: Sender<EventMessage>,
+    (send_log, recv_log): (Sender<String>, Receiver<String>),
+    automatic: Option<AutomaticPolicy>,
+    audit: AuditTrail,
+    prompt_timeout: Option<Duration>,
+    timeout_decision: TimeoutDecision,
+) -> (JoinHandle<()>, Sender<UserRequest>) {
+    let (send, recv) = channel();
+
+    let join_handle = thread::spawn(move || {
+        let (_guard, mut terminal) = match TerminalGuard::acquire(send_log) {
+            Ok(terminal) => terminal,
+            Err(err) => {
+                error!("Failed to set up the terminal for the TUI: {}", err);
+                return;
+            }
+        };
+        info!("UI thread spawned and listening for events");
+
+        let (send_closure_size, recv_closure_size) = spawn_closure_sizes();
+        let (send_details, recv_details) = spawn_candidate_details();
+        let mut app = App {
+            prompts: VecDeque::new(),
+            shown_since: Instant::now(),
+            selection: TableState::default(),
+            filter: String::new(),
+            searching: None,
+            log: VecDeque::new(),
+            log_scroll: 0,
+            closure_sizes: HashMap::new(),
+            requested_sizes: HashSet::new(),
+            send_closure_size,
+            compared: Vec::new(),
+            details: HashMap::new(),
+            explanation: None,
+            requested_details: HashSet::new(),
+            send_details,
+            automatic: automatic.is_some(),
+            audit,
+            automatic_decisions: VecDeque::new(),
+            decisions: Vec::new(),
+            realizing: None,
+            reviewing: false,
+            review_selection: TableState::default(),
+        };
+        app.selection.select(Some(0));
+        app.review_selection.select(Some(0));
+
+        'ui: loop {
+            loop {
+                let request = match recv.try_recv() {
+                    Ok(request) => request,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => break 'ui,
+                };
+                if let Some(entry) = app.audit.update(&request) {
+                    app.push_automatic_decision(entry);
+                }
+                match request {
+                    UserRequest::Quit => break 'ui,
+                    UserRequest::Cancel(request_id) => {
+                        if app.prompts.front().and_then(UserRequest::prompt_id) == Some(request_id) {
+                            info!("The request was interrupted, dropping the prompt");
+                            app.next_prompt();
+                        } else {
+                            debug!("request {} was cancelled before being prompted", request_id);
+                            app.prompts.retain(|request| request.prompt_id() != Some(request_id));
+                        }
+                    }
+                    UserRequest::Decided(request_id, requested_path, description) => {
+                        app.decisions.push((request_id, requested_path, description));
+                    }
+                    UserRequest::Explanation(request_id, explanation) => {
+                        if let Some((asked, shown @ None)) = &mut app.explanation {
+                            if *asked == request_id {
+                                *shown = Some(explanation);
+                            }
+                        }
+                    }
+                    // Failures are logged by the realizer already.
+                    UserRequest::Realizing(progress) => {
+                        app.realizing = (!progress.is_complete()).then_some(progress);
+                    }
+                    request if answer_automatically(&reply_fs, &request, automatic.as_ref(), &mut app.audit) => {}
+                    request if request.prompt_id().is_some() && request.prompt_id() == app.searching => {
+                        app.searching = None;
+                        app.prompts.push_front(request);
+                        app.prompt_changed();
+                    }
+                    request => {
+                        if app.prompts.is_empty() {
+                            app.shown_since = Instant::now();
+                        }
+                        app.prompts.push_back(request);
+                    }
+                }
+            }
+            while let Ok(line) = recv_log.try_recv() {
+                app.push_log(line);
+            }
+            while let Ok((store_path, size)) = recv_closure_size.try_recv() {
+                app.closure_sizes.insert(store_path, size);
+            }
+            app.request_closure_sizes();
+            while let Ok((key, details)) = recv_details.try_recv() {
+                app.details.insert(key, details);
+            }
+            app.request_details();
+
+            let timed_out = prompt_timeout.map_or(false, |timeout| app.shown_since.elapsed() >= timeout);
+            if let Some(request) = app.prompts.front().filter(|_| timed_out) {
+                send_answer(&reply_fs, request, PromptAnswer::TimedOut, prompt_timeout, timeout_decision);
+                app.next_prompt();
+            }
+
+            if let Err(err) = terminal.draw(|f| draw(f, &mut app, prompt_timeout)) {
+                error!("Failed to draw the TUI: {}", err);
+                break;
+            }
+
+            if !event::poll(TICK).unwrap_or(false) {
+                continue;
+            }
+            let key = match event::read() {
+                Ok(Event::Key(key @ KeyEvent { kind: KeyEventKind::Press, .. })) => key,
+                _ => continue,
+            };
+            let answer = match (key.code, key.modifiers) {
+                (KeyCode::Char('c'), KeyModifiers::CONTROL) => {
+                    let _ = send_to_main.send(EventMessage::Stop);
+                    None
+                }
+                (KeyCode::Char('r'), KeyModifiers::CONTROL) => {
+                    app.reviewing = !app.reviewing;
+                    None
+                }
+                (KeyCode::Up, _) if app.reviewing => {
+                    app.move_review_selection(-1);
+                    None
+                }
+                (KeyCode::Down, _) if app.reviewing => {
+                    app.move_review_selection(1);
+                    None
+                }
+                (KeyCode::Enter | KeyCode::Delete, _) if app.reviewing => {
+                    app.undo_selected(&reply_fs);
+                    None
+                }
+                (KeyCode::Esc, _) if app.reviewing => {
+                    app.reviewing = false;
+                    None
+                }
+                (KeyCode::Up, _) => {
+                    app.move_selection(-1);
+                    None
+                }
+                (KeyCode::Down, _) => {
+                    app.move_selection(1);
+                    None
+                }
+                (KeyCode::PageUp, _) => {
+                    app.log_scroll = (app.log_scroll + 10).min(app.log.len());
+                    None
+                }
+                (KeyCode::PageDown, _) => {
+                    app.log_scroll = app.log_scroll.saturating_sub(10);
+                    None
+                }
+                // The prompts are hidden while reviewing.
+                _ if app.reviewing => None,
+                (KeyCode::Enter, _) => app.selected_answer(),
+                (KeyCode::Char('a'), KeyModifiers::CONTROL) => match app.selected_answer() {
+                    Some(PromptAnswer::Choice(index)) => Some(PromptAnswer::SubtreeChoice(index)),
+                    answer => answer,
+                },
+                (KeyCode::Tab, _) => app.search_answer(),
+                (KeyCode::Char('x'), KeyModifiers::CONTROL) => {
+                    app.toggle_compared();
+                    None
+                }
+                (KeyCode::Char('e'), KeyModifiers::CONTROL) => {
+                    app.toggle_explanation(&reply_fs);
+                    None
+                }
+                (KeyCode::Char('n'), KeyModifiers::CONTROL) => {
+                    app.rotate_prompts(true);
+                    None
+                }
+                (KeyCode::Char('p'), KeyModifiers::CONTROL) => {
+                    app.rotate_prompts(false);
+                    None
+                }
+                (KeyCode::Char('s'), KeyModifiers::CONTROL) => Some(PromptAnswer::SkipForNow),
+                // The first Esc clears the filter.
+                (KeyCode::Esc, _) if !app.filter.is_empty() => {
+                    app.edit_filter(String::clear);
+                    None
+                }
+                (KeyCode::Esc, _) => Some(PromptAnswer::Skip),
+                (KeyCode::Backspace, _) => {
+                    app.edit_filter(|filter| {
+                        filter.pop();
+                    });
+                    None
+                }
+                (KeyCode::Char(c), KeyModifiers::NONE | KeyModifiers::SHIFT) => {
+                    app.edit_filter(|filter| filter.push(c));
+                    None
+                }
+                _ => None,
+            };
+            if let (Some(answer), Some(request)) = (answer, app.prompts.front()) {
+                if let PromptAnswer::Search(query) = &answer {
+                    info!("Looking up `{}`, this may take a while", query);
+                    app.searching = request.prompt_id();
+                }
+                send_answer(&reply_fs, request, answer, prompt_timeout, timeout_decision);
+                app.next_prompt();
+            }
+        }
+    });
+
+    (join_handle, send)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::database::candidate;
+    use crate::process::ProcessInfo;
+
+    fn app(prompts: Vec<UserRequest>) -> App {
+        let mut app = App {
+            prompts: prompts.into(),
+            shown_since: Instant::now(),
+            selection: TableState::default(),
+            filter: String::new(),
+            searching: None,
+            log: VecDeque::new(),
+            log_scroll: 0,
+            closure_sizes: HashMap::new(),
+            requested_sizes: HashSet::new(),
+            send_closure_size: channel().0,
+            compared: Vec::new(),
+            details: HashMap::new(),
+            explanation: None,
+            requested_details: HashSet::new(),
+            send_details: channel().0,
+            automatic: false,
+            audit: AuditTrail::default(),
+            automatic_decisions: VecDeque::new(),
+            decisions: Vec::new(),
+            realizing: None,
+            reviewing: false,
+            review_selection: TableState::default(),
+        };
+        app.selection.select(Some(0));
+
+        app
+    }
+
+    #[test]
+    fn test_the_filter_picks_among_the_candidates() {
+        let candidates: Vec<Candidate> = [
+            ("zlib", "/nix/store/8vvhlnnbjb7sdmgdcb9ixyw4k7nqp56w-zlib-1.3-dev"),
+            ("zlib-ng", "/nix/store/2zmbsy7nfyd8q7ss8vc8nlmq7q9ygnl4-zlib-ng-2.1-dev"),
+            ("minizip", "/nix/store/fx5bd3gqch6asghpv36yyqf6lvq8chpv-minizip-1.3-dev"),
+        ]
+        .into_iter()
+        .map(|(attr, store_path)| candidate(attr, "dev", true, store_path, "/include/zlib.h"))
+        .collect();
+        let prompt = UserRequest::InteractiveSearch(
+            1,
+            candidates.clone(),
+            Vec::new(),
+            candidates[0].clone(),
+            ProcessInfo::from_pid(std::process::id()),
+            false,
+            false,
+            None,
+            PathBuf::from("include/zlib.h"),
+        );
+        let mut app = app(vec![prompt, UserRequest::Cancel(2)]);
+        assert_eq!(app.choices(), 3);
+        app.move_selection(5);
+        assert!(matches!(app.selected_answer(), Some(PromptAnswer::Choice(2))));
+
+        // The selection is among the candidates left by the filter, answers are their indices.
+        app.edit_filter(|filter| filter.push_str("ng"));
+        assert_eq!(app.visible_candidates(), vec![1]);
+        assert!(matches!(app.selected_answer(), Some(PromptAnswer::Choice(1))));
+        // Nothing left, what was typed is looked up.
+        app.edit_filter(|filter| filter.push_str("xyz"));
+        assert_eq!(app.choices(), 0);
+        assert!(matches!(app.selected_answer(), Some(PromptAnswer::Search(search)) if search == "ngxyz"));
+
+        app.next_prompt();
+        assert!(app.filter.is_empty());
+        assert_eq!(app.choices(), 0);
+    }
+}
@@ -0,0 +1,176 @@
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::resolution::ResolutionDB;
+
+/// How often a resolution was used, in seconds since the UNIX epoch.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Usage {
+    pub hits: u64,
+    /// First run which loaded this resolution
+    pub first_seen: u64,
+    #[serde(default)]
+    pub last_used: Option<u64>,
+}
+
+impl Usage {
+    /// Whether it was not used since `cutoff`, counting from its first run if it never was.
+    pub fn unused_since(&self, cutoff: u64) -> bool {
+        self.last_used.unwrap_or(self.first_seen) < cutoff
+    }
+}
+
+/// Usage of the resolutions of every resolution file, by canonical file path then requested path.
+pub type UsageStats = BTreeMap<PathBuf, BTreeMap<String, Usage>>;
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+/// Seconds since the UNIX epoch, `days` ago.
+pub fn days_ago(days: u64) -> u64 {
+    now().saturating_sub(Duration::from_secs(days * 24 * 60 * 60).as_secs())
+}
+
+pub fn default_stats_filepath() -> PathBuf {
+    xdg::BaseDirectories::with_prefix("buildxyz")
+        .expect("Failed to find the XDG directories")
+        .place_data_file("usage.json")
+        .expect("Failed to create the buildxyz data directory")
+}
+
+pub fn read_stats(filepath: &Path) -> io::Result<UsageStats> {
+    match fs::read(filepath) {
+        Ok(data) => serde_json::from_slice(&data)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Default::default()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Update the stats file under an exclusive lock, concurrent runs may persist at the same time.
+pub fn update_stats(filepath: &Path, update: impl FnOnce(&mut UsageStats)) -> io::Result<()> {
+    let mut lock_path = filepath.as_os_str().to_owned();
+    lock_path.push(".lock");
+    let lock_file = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(&lock_path)?;
+    nix::fcntl::flock(lock_file.as_raw_fd(), nix::fcntl::FlockArg::LockExclusive)?;
+
+    let mut stats = read_stats(filepath)?;
+    update(&mut stats);
+    let serialized = serde_json::to_vec(&stats).expect("Failed to serialize the usage stats");
+    let directory = match filepath.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let mut tmp_file = tempfile::NamedTempFile::new_in(directory)?;
+    io::Write::write_all(&mut tmp_file, &serialized)?;
+    tmp_file.persist(filepath).map_err(|err| err.error)?;
+
+    Ok(())
+}
+
+/// Hits of the resolutions during this run, by source then requested path.
+#[derive(Default)]
+pub struct UsageCounter {
+    hits: HashMap<(String, String), u64>,
+}
+
+impl UsageCounter {
+    pub fn hit(&mut self, source: &str, requested_path: &str) {
+        *self
+            .hits
+            .entry((source.to_string(), requested_path.to_string()))
+            .or_default() += 1;
+    }
+
+    /// Merge the hits of this run into the stats file.
+    /// Every resolution loaded from a file is registered, so that unused ones can be found later.
+    pub fn persist(&self, filepath: &Path, db: &ResolutionDB) {
+        let now = now();
+        // Sources are file paths as they were loaded, the stats are keyed by canonical path.
+        let mut canonical_sources: HashMap<&str, Option<PathBuf>> = HashMap::new();
+
+        let mut used: Vec<(PathBuf, &String, u64)> = Vec::new();
+        for (requested_path, resolution) in db {
+            let source = resolution.source();
+            // Core resolutions and those of this session do not come from a file.
+            let filepath = match canonical_sources
+                .entry(source)
+                .or_insert_with(|| fs::canonicalize(source).ok())
+            {
+                Some(filepath) => filepath.clone(),
+                None => continue,
+            };
+            let hits = self
+                .hits
+                .get(&(source.to_string(), requested_path.clone()))
+                .copied()
+                .unwrap_or(0);
+            used.push((filepath, requested_path, hits));
+        }
+        if used.is_empty() {
+            return;
+        }
+
+        let result = update_stats(filepath, |stats| {
+            for (filepath, requested_path, hits) in used {
+                let usage = stats
+                    .entry(filepath)
+                    .or_default()
+                    .entry(requested_path.clone())
+                    .or_insert(Usage {
+                        hits: 0,
+                        first_seen: now,
+                        last_used: None,
+                    });
+                if hits > 0 {
+                    usage.hits += hits;
+                    usage.last_used = Some(now);
+                }
+            }
+        });
+        match result {
+            Ok(()) => debug!("usage stats written to {}", filepath.display()),
+            Err(err) => warn!(
+                "Failed to write the usage stats to {}: {}",
+                filepath.display(),
+                err
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_never_used_resolutions_age_from_their_first_run() {
+        let never_used = Usage {
+            hits: 0,
+            first_seen: 100,
+            last_used: None,
+        };
+        assert!(never_used.unused_since(200));
+        assert!(!never_used.unused_since(50));
+
+        let used = Usage {
+            hits: 3,
+            first_seen: 100,
+            last_used: Some(300),
+        };
+        assert!(!used.unused_since(200));
+    }
+}
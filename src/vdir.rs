@@ -0,0 +1,38 @@
+use fuser::FileType;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+/// Directories synthesized from the paths we provided so far.
+///
+/// Providing `include/foo/bar.h` makes `include/foo` a directory listing `bar.h`,
+/// so that looking it up does not need another index search.
+#[derive(Default, Debug)]
+pub struct VirtualDirectories {
+    /// directory path -> name -> kind
+    children: HashMap<String, BTreeMap<String, FileType>>,
+}
+
+impl VirtualDirectories {
+    /// Register a provided path (relative to the mountpoint) and all its parents.
+    pub fn insert(&mut self, path: &Path, kind: FileType) {
+        let mut kind = kind;
+        let mut current = path;
+
+        while let (Some(parent), Some(name)) = (current.parent(), current.file_name()) {
+            self.children
+                .entry(parent.to_string_lossy().to_string())
+                .or_default()
+                .insert(name.to_string_lossy().to_string(), kind);
+            kind = FileType::Directory;
+            current = parent;
+        }
+    }
+
+    pub fn is_dir(&self, path: &str) -> bool {
+        self.children.contains_key(path)
+    }
+
+    pub fn children(&self, path: &str) -> Option<&BTreeMap<String, FileType>> {
+        self.children.get(path)
+    }
+}
@@ -0,0 +1,273 @@
+use log::{debug, error, warn};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+
+use crate::nix::{path_info, substituters, trusted_public_keys};
+use crate::resolution::{Decision, ResolutionDB, ResolutionData};
+
+/// What happens to the store paths of loaded resolution files which are not valid signed paths,
+/// see `--unverified-store-paths`.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UnverifiedPaths {
+    /// Serve them without checking anything
+    Allow,
+    /// Serve them with a warning
+    #[default]
+    Warn,
+    /// Drop their resolutions, the paths they provide are asked again
+    Refuse,
+}
+
+/// What the stores tell about a store path, from the least to the most trusted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Verdict {
+    /// Neither in the local store nor on any substituter
+    Missing,
+    /// Valid but not signed by any trusted key
+    Unsigned,
+    /// Signed by a trusted key, or built on this machine
+    Signed,
+}
+
+/// The part of `nix path-info --json` telling whether a store path can be trusted.
+#[derive(Deserialize, Debug)]
+struct PathInfo {
+    #[serde(default)]
+    path: Option<String>,
+    /// Only reported for invalid paths by older versions of Nix
+    #[serde(default = "valid_by_default")]
+    valid: bool,
+    #[serde(default)]
+    signatures: Vec<String>,
+    /// Built on this machine
+    #[serde(default)]
+    ultimate: bool,
+}
+
+fn valid_by_default() -> bool {
+    true
+}
+
+/// Valid store paths of `nix path-info --json`, as a list or as an object by store path depending on the Nix version.
+fn parse_path_info(stdout: &[u8]) -> HashMap<String, PathInfo> {
+    let parse = |value: serde_json::Value| serde_json::from_value::<PathInfo>(value).ok();
+
+    match serde_json::from_slice(stdout) {
+        Ok(serde_json::Value::Array(infos)) => infos
+            .into_iter()
+            .filter_map(parse)
+            .filter(|info| info.valid)
+            .filter_map(|info| Some((info.path.clone()?, info)))
+            .collect(),
+        Ok(serde_json::Value::Object(infos)) => infos
+            .into_iter()
+            .filter(|(_, info)| !info.is_null())
+            .filter_map(|(path, info)| Some((path, parse(info)?)))
+            .collect(),
+        _ => HashMap::new(),
+    }
+}
+
+/// Whether a store path is signed by one of the trusted keys, by name, e.g. `cache.nixos.org-1`.
+fn verdict(info: Option<&PathInfo>, trusted_keys: &[String]) -> Verdict {
+    let is_trusted = |signature: &String| {
+        signature.split_once(':').map_or(false, |(name, _)| {
+            trusted_keys.iter().any(|key| key == name)
+        })
+    };
+
+    match info {
+        None => Verdict::Missing,
+        Some(info) if info.ultimate || info.signatures.iter().any(is_trusted) => Verdict::Signed,
+        Some(_) => Verdict::Unsigned,
+    }
+}
+
+/// Store path to verify before serving it, those of the embedded core resolutions and of flake installables aside.
+fn store_path_to_verify(data: &ResolutionData) -> Option<String> {
+    let Decision::Provide(provide_data) = &data.decision else {
+        return None;
+    };
+    if provide_data.flake.is_some()
+        || data
+            .source
+            .as_deref()
+            .map_or(false, |source| source.starts_with("core:"))
+    {
+        return None;
+    }
+
+    Some(provide_data.store_path.as_str().into_owned())
+}
+
+/// Checks that the store paths of loaded resolution files are valid and signed by a trusted key
+/// before they are realized and served: a shared resolution file could point to anything.
+pub struct PathVerification {
+    policy: UnverifiedPaths,
+    /// Names of the `trusted-public-keys`
+    trusted_keys: Vec<String>,
+    /// Store path -> what the stores tell about it
+    verdicts: HashMap<String, Verdict>,
+    /// Store paths already reported, namespaces share most of their resolutions
+    reported: HashSet<String>,
+}
+
+impl PathVerification {
+    pub fn new(policy: UnverifiedPaths) -> Self {
+        let trusted_keys = if policy == UnverifiedPaths::Allow {
+            Vec::new()
+        } else {
+            trusted_public_keys()
+                .iter()
+                .filter_map(|key| key.split_once(':').map(|(name, _)| name.to_string()))
+                .collect()
+        };
+
+        PathVerification {
+            policy,
+            trusted_keys,
+            verdicts: HashMap::new(),
+            reported: HashSet::new(),
+        }
+    }
+
+    /// Ask the local store, then the substituters, about the store paths not verified yet.
+    fn verify(&mut self, store_paths: Vec<String>) {
+        let mut pending: Vec<String> = store_paths
+            .into_iter()
+            .filter(|store_path| !self.verdicts.contains_key(store_path))
+            .collect();
+        pending.sort();
+        pending.dedup();
+        if pending.is_empty() {
+            return;
+        }
+
+        // The store the `nix` commands use, then each substituter.
+        let stores = std::iter::once(None).chain(substituters().into_iter().map(Some));
+        for store in stores {
+            if pending.is_empty() {
+                break;
+            }
+            let infos = parse_path_info(&path_info(&pending, store.as_deref()));
+            pending.retain(|store_path| {
+                let verdict = verdict(infos.get(store_path), &self.trusted_keys);
+                debug!(
+                    "{} is {:?} on {}",
+                    store_path,
+                    verdict,
+                    store.as_deref().unwrap_or("the store")
+                );
+                // Unsigned paths may still be signed on a substituter.
+                let best = self
+                    .verdicts
+                    .entry(store_path.clone())
+                    .or_insert(Verdict::Missing);
+                *best = (*best).max(verdict);
+                verdict != Verdict::Signed
+            });
+        }
+    }
+
+    /// Verify the store paths this database provides, warning about or dropping the resolutions of the others.
+    pub fn apply(&mut self, mut db: ResolutionDB) -> ResolutionDB {
+        if self.policy == UnverifiedPaths::Allow {
+            return db;
+        }
+        let store_paths = db
+            .values()
+            .flat_map(|resolution| resolution.all_data())
+            .filter_map(store_path_to_verify)
+            .collect();
+        self.verify(store_paths);
+
+        let is_untrusted = |data: &ResolutionData| {
+            store_path_to_verify(data).map_or(false, |store_path| {
+                self.verdicts.get(&store_path) != Some(&Verdict::Signed)
+            })
+        };
+        for data in db.values().flat_map(|resolution| resolution.all_data()) {
+            if !is_untrusted(data) {
+                continue;
+            }
+            let store_path = store_path_to_verify(data).unwrap();
+            if !self.reported.insert(store_path.clone()) {
+                continue;
+            }
+            let why = match self.verdicts[&store_path] {
+                Verdict::Missing => "is neither in the store nor on any substituter",
+                _ => "is not signed by a trusted key",
+            };
+            let source = data.source.as_deref().unwrap_or("this session");
+            match self.policy {
+                UnverifiedPaths::Refuse => error!(
+                    "Refusing to provide {} from {}: {} {}",
+                    data.requested_path, source, store_path, why
+                ),
+                _ => warn!(
+                    "{} from {} is provided by {}, which {}",
+                    data.requested_path, source, store_path, why
+                ),
+            }
+        }
+        if self.policy == UnverifiedPaths::Refuse {
+            db.retain(|_, resolution| !resolution.all_data().into_iter().any(is_untrusted));
+        }
+
+        db
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_signed_paths_are_trusted() {
+        let trusted_keys = vec!["cache.nixos.org-1".to_string()];
+
+        // Nix 2.19 and later
+        let infos = parse_path_info(
+            br#"{
+                "/nix/store/zy4xhzc6x6ldrfzqzfa4x3cw8ds1mgcn-zlib-1.3": {"signatures": ["cache.nixos.org-1:AAAA"], "ultimate": false},
+                "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-evil-1.0": {"signatures": ["evil.example.org-1:BBBB"], "ultimate": false},
+                "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-mine-1.0": {"signatures": [], "ultimate": true},
+                "/nix/store/cccccccccccccccccccccccccccccccc-gone-1.0": null
+            }"#,
+        );
+        let verdict_of = |path: &str| verdict(infos.get(path), &trusted_keys);
+        assert_eq!(
+            verdict_of("/nix/store/zy4xhzc6x6ldrfzqzfa4x3cw8ds1mgcn-zlib-1.3"),
+            Verdict::Signed
+        );
+        assert_eq!(
+            verdict_of("/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-evil-1.0"),
+            Verdict::Unsigned
+        );
+        assert_eq!(
+            verdict_of("/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-mine-1.0"),
+            Verdict::Signed
+        );
+        assert_eq!(
+            verdict_of("/nix/store/cccccccccccccccccccccccccccccccc-gone-1.0"),
+            Verdict::Missing
+        );
+
+        // Earlier versions
+        let infos = parse_path_info(
+            br#"[
+                {"path": "/nix/store/zy4xhzc6x6ldrfzqzfa4x3cw8ds1mgcn-zlib-1.3", "signatures": ["cache.nixos.org-1:AAAA"]},
+                {"path": "/nix/store/cccccccccccccccccccccccccccccccc-gone-1.0", "valid": false}
+            ]"#,
+        );
+        assert_eq!(infos.len(), 1);
+        assert_eq!(
+            verdict(
+                infos.get("/nix/store/zy4xhzc6x6ldrfzqzfa4x3cw8ds1mgcn-zlib-1.3"),
+                &trusted_keys
+            ),
+            Verdict::Signed
+        );
+        assert!(parse_path_info(b"").is_empty());
+    }
+}